@@ -0,0 +1,64 @@
+//! Rows per second of generated transactions processed one by one with `process_transaction`
+//! and in chunks with `process_chunk`.
+//!
+//! Run with `cargo bench --bench chunks`.
+
+use payment_engine::{simulate::TransactionGenerator, types::{ClientId, Transaction}, PaymentEngine};
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+/// Generated rows in the benchmark, about a sixth of them disputes, resolves and chargebacks.
+const ROWS: usize = 1_000_000;
+
+/// Clients the rows are spread over, few enough for each to have several rows in a chunk.
+const CLIENTS: ClientId = 100;
+
+/// Rows in each chunk given to `process_chunk`.
+const CHUNK_SIZE: usize = 1024;
+
+/// Times each way of processing the rows is run, the best run being reported.
+const RUNS: usize = 5;
+
+fn main() -> io::Result<()> {
+    let rows: Vec<Transaction> =
+        TransactionGenerator::new(0x2545_f491_4f6c_dd1d, CLIENTS).take(ROWS).collect();
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+
+    let mut row_engine = PaymentEngine::new();
+    let one_by_one = best_of(|| {
+        row_engine = PaymentEngine::new();
+        runtime.block_on(async {
+            for txn in rows.iter().cloned() {
+                let _ = row_engine.process_transaction(txn).await;
+            }
+        });
+    });
+    let mut chunk_engine = PaymentEngine::new();
+    let chunked = best_of(|| {
+        chunk_engine = PaymentEngine::new();
+        runtime.block_on(async {
+            for chunk in rows.chunks(CHUNK_SIZE) {
+                chunk_engine.process_chunk(&mut chunk.to_vec()).await;
+            }
+        });
+    });
+    assert!(row_engine.clients == chunk_engine.clients, "both ways give the same accounts");
+
+    let per_second = |elapsed: Duration| rows.len() as f64 / elapsed.as_secs_f64();
+    println!("{} rows over {} clients", rows.len(), CLIENTS);
+    println!("process_transaction: {:>12.0} rows/s", per_second(one_by_one));
+    println!("process_chunk:       {:>12.0} rows/s", per_second(chunked));
+    Ok(())
+}
+
+fn best_of(mut run: impl FnMut()) -> Duration {
+    let mut best = Duration::MAX;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        run();
+        best = best.min(start.elapsed());
+    }
+    best
+}
@@ -0,0 +1,78 @@
+//! Rows per second of the client states report, written the way it was before amounts skipped
+//! `f64` (one unbuffered `write!` per row) and by `write_report`.
+//!
+//! Run with `cargo bench --bench report`.
+
+use payment_engine::{
+    amount::Amount,
+    report::{write_report, ReportOptions},
+    types::{Client, ClientId},
+    PaymentEngine,
+};
+use std::{
+    fs::File,
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+/// Clients in the benchmarked report, as many as client ids allow up to a million.
+const CLIENTS: u64 = 1_000_000;
+
+/// Times each way of writing the report is run, the best run being reported.
+const RUNS: usize = 5;
+
+fn main() -> io::Result<()> {
+    let mut engine = PaymentEngine::new();
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    for id in 1..=CLIENTS.min(ClientId::MAX.into()) {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        let mut client = Client::new();
+        client.available = Amount::from_units((state >> 30) as i64).unwrap();
+        client.held = Amount::from_units((state % 1_000_000) as i64).unwrap();
+        client.total = client.available.checked_add(client.held).unwrap();
+        engine.clients.insert(id as ClientId, client);
+    }
+    let rows = engine.clients.len();
+    let path = std::env::temp_dir().join(format!("payment-engine-bench-{}.csv", std::process::id()));
+    let options = ReportOptions::default();
+
+    let naive = best_of(|| {
+        let mut w = File::create(&path)?;
+        writeln!(w, "client,available,held,total,locked")?;
+        for id in engine.client_ids(options.order) {
+            let client = &engine.clients[&id];
+            let amount = |amount: Amount| options.output.format_csv(amount.to_f64());
+            writeln!(
+                w,
+                "{},{},{},{},{}",
+                id,
+                amount(client.available),
+                amount(client.held),
+                amount(client.total),
+                client.locked
+            )?;
+        }
+        Ok(())
+    })?;
+    let naive_report = std::fs::read(&path)?;
+    let fast = best_of(|| write_report(&engine, File::create(&path)?, &options))?;
+    let fast_report = std::fs::read(&path)?;
+    std::fs::remove_file(&path)?;
+    assert!(naive_report == fast_report, "both ways give the same report");
+
+    let per_second = |elapsed: Duration| rows as f64 / elapsed.as_secs_f64();
+    println!("{} rows", rows);
+    println!("before:       {:>12.0} rows/s", per_second(naive));
+    println!("write_report: {:>12.0} rows/s", per_second(fast));
+    Ok(())
+}
+
+fn best_of(mut run: impl FnMut() -> io::Result<()>) -> io::Result<Duration> {
+    let mut best = Duration::MAX;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        run()?;
+        best = best.min(start.elapsed());
+    }
+    Ok(best)
+}
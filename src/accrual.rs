@@ -0,0 +1,44 @@
+//! Periodic interest accruals over every account, e.g. a monthly interest payment.
+
+use crate::{amount::Amount, types::ClientId};
+use std::io::{self, Write};
+
+/// Header line of the accrual entries report.
+pub const ACCRUALS_HEADER: &str = "sequence,client,rate,available_before,interest";
+
+/// The interest an accrual paid to one account, as kept in `PaymentEngine::accruals`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccrualEntry {
+    /// Position of the entry among the engine's accrual entries, starting at 1.
+    pub sequence: u64,
+    pub client: ClientId,
+    /// The rate of the accrual, e.g. `0.01` for 1%.
+    pub rate: f64,
+    /// The available balance the interest was computed on.
+    pub available_before: Amount,
+    /// The interest credited to available and total, rounded with `EngineConfig::rounding`.
+    pub interest: Amount,
+}
+
+/// What `PaymentEngine::apply_accrual` did.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccrualReport {
+    pub rate: f64,
+    /// One entry per account the accrual changed, in ascending client id order.
+    pub entries: Vec<AccrualEntry>,
+    /// The interest paid across the entries, negative for a negative rate.
+    pub total_interest: Amount,
+}
+
+/// Writes accrual entries as a CSV of `ACCRUALS_HEADER`, one row per entry in the given order.
+pub fn write_entries<W: Write>(entries: &[AccrualEntry], mut w: W) -> io::Result<()> {
+    writeln!(w, "{}", ACCRUALS_HEADER)?;
+    for entry in entries {
+        writeln!(
+            w,
+            "{},{},{},{},{}",
+            entry.sequence, entry.client, entry.rate, entry.available_before, entry.interest
+        )?;
+    }
+    w.flush()
+}
@@ -0,0 +1,291 @@
+//! Fixed-point amounts, so balances are exact to `SCALE` decimal places instead of collecting
+//! floating point noise.
+
+use crate::{errors::PaymentError, report::Rounding};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, ops::Neg};
+
+/// Decimal places amounts are kept with.
+pub const SCALE: u32 = 4;
+
+/// Number of units in `1.0`.
+const UNIT: i64 = 10i64.pow(SCALE);
+
+/// Decimal places a floating point amount is first cut to, before being rounded to `SCALE`.
+/// Like the report's rendering, this decides ties on the decimal amount the input meant
+/// (`0.00005` is a tie, although the closest `f64` is slightly above it).
+const EXACT_DECIMALS: u32 = 8;
+
+/// An amount of money, as a whole number of `10^-SCALE` units.
+///
+/// There are no arithmetic operators: sums and differences go through `checked_add` and
+/// `checked_sub`, which fail with `PaymentError::AmountOutOfRange` rather than wrap. The range
+/// is symmetric, so negating an amount can't overflow.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Amount(i64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+    /// The largest amount, about `9.2e14`.
+    pub const MAX: Amount = Amount(i64::MAX);
+    /// The smallest amount, `-MAX`.
+    pub const MIN: Amount = Amount(-i64::MAX);
+
+    /// Returns the amount of `units` ten-thousandths, `None` if it is out of range.
+    pub fn from_units(units: i64) -> Option<Self> {
+        (units != i64::MIN).then_some(Amount(units))
+    }
+
+    /// Returns the amount as a number of ten-thousandths.
+    pub fn units(self) -> i64 {
+        self.0
+    }
+
+    /// Converts a floating point amount, rounding it to `SCALE` decimal places with `rounding`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::AmountOutOfRange` if `value` isn't a finite number between
+    /// `MIN` and `MAX`.
+    pub fn from_f64(value: f64, rounding: Rounding) -> Result<Self, PaymentError> {
+        let exact = (value * 10f64.powi(EXACT_DECIMALS as i32)).round();
+        let limit = i64::MAX as f64 * 10f64.powi((EXACT_DECIMALS - SCALE) as i32);
+        if !exact.is_finite() || exact.abs() >= limit {
+            return Err(PaymentError::AmountOutOfRange(format!("{} is not a valid amount", value)));
+        }
+        let divisor = 10i128.pow(EXACT_DECIMALS - SCALE);
+        let exact = exact as i128;
+        let (quotient, remainder) = (exact / divisor, (exact % divisor).abs());
+        let away_from_zero = match rounding {
+            Rounding::Truncate => false,
+            Rounding::HalfUp => remainder * 2 >= divisor,
+            Rounding::HalfEven => remainder * 2 > divisor || (remainder * 2 == divisor && quotient % 2 != 0),
+        };
+        let units = if away_from_zero { quotient + exact.signum() } else { quotient };
+        i64::try_from(units)
+            .ok()
+            .and_then(Amount::from_units)
+            .ok_or_else(|| PaymentError::AmountOutOfRange(format!("{} is not a valid amount", value)))
+    }
+
+    /// Converts the amount to floating point, for statistics and reports. The conversion is
+    /// exact up to about `9e11`.
+    pub fn to_f64(self) -> f64 {
+        self.0 as f64 / UNIT as f64
+    }
+
+    /// Returns `self + rhs`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::AmountOutOfRange` if the sum is out of range.
+    pub fn checked_add(self, rhs: Amount) -> Result<Self, PaymentError> {
+        self.0
+            .checked_add(rhs.0)
+            .and_then(Amount::from_units)
+            .ok_or_else(|| PaymentError::AmountOutOfRange(format!("{} + {} overflows", self, rhs)))
+    }
+
+    /// Returns `self - rhs`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::AmountOutOfRange` if the difference is out of range.
+    pub fn checked_sub(self, rhs: Amount) -> Result<Self, PaymentError> {
+        self.0
+            .checked_sub(rhs.0)
+            .and_then(Amount::from_units)
+            .ok_or_else(|| PaymentError::AmountOutOfRange(format!("{} - {} overflows", self, rhs)))
+    }
+
+    /// Returns the amount multiplied by `factor`, e.g. a percentage fee or an exchange rate,
+    /// rounded to `SCALE` decimal places with `rounding`. Multiplying by `x` then by `y` is
+    /// within one unit of multiplying by `x * y` when both factors are at most `1` in size.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::AmountOutOfRange` if the product is out of range or `factor`
+    /// isn't a finite number.
+    pub fn checked_mul(self, factor: f64, rounding: Rounding) -> Result<Self, PaymentError> {
+        Amount::from_f64(self.to_f64() * factor, rounding)
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+
+    pub fn is_positive(self) -> bool {
+        self.0 > 0
+    }
+}
+
+impl Neg for Amount {
+    type Output = Amount;
+
+    fn neg(self) -> Amount {
+        Amount(-self.0)
+    }
+}
+
+impl PartialEq<f64> for Amount {
+    fn eq(&self, other: &f64) -> bool {
+        self.to_f64() == *other
+    }
+}
+
+/// Writes the amount with its `SCALE` decimal places, like the default report (`-1.5000`).
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.is_negative() { "-" } else { "" };
+        let units = self.0.unsigned_abs();
+        let unit = UNIT as u64;
+        write!(f, "{}{}.{:0width$}", sign, units / unit, units % unit, width = SCALE as usize)
+    }
+}
+
+impl Amount {
+    /// Appends the amount as `Display` writes it, without going through `fmt`, for reports of
+    /// millions of rows.
+    pub(crate) fn write_to(self, out: &mut Vec<u8>) {
+        if self.is_negative() {
+            out.push(b'-');
+        }
+        let units = self.0.unsigned_abs();
+        let unit = UNIT as u64;
+        push_digits(out, units / unit);
+        out.push(b'.');
+        let fraction = units % unit;
+        let mut divisor = unit / 10;
+        while divisor > 0 {
+            out.push(b'0' + (fraction / divisor % 10) as u8);
+            divisor /= 10;
+        }
+    }
+}
+
+/// Appends the decimal digits of `n`.
+pub(crate) fn push_digits(out: &mut Vec<u8>, mut n: u64) {
+    let mut digits = [0u8; 20];
+    let mut start = digits.len();
+    loop {
+        start -= 1;
+        digits[start] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            break;
+        }
+    }
+    out.extend_from_slice(&digits[start..]);
+}
+
+/// Serialized as a string with `SCALE` decimal places, like the default report.
+impl Serialize for Amount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let amount = String::deserialize(deserializer)?;
+        amount
+            .parse()
+            .ok()
+            .and_then(|value| Amount::from_f64(value, Rounding::HalfEven).ok())
+            .ok_or_else(|| D::Error::custom(format!("invalid amount '{}'", amount)))
+    }
+}
+
+/// Converts a literal of a test, which must be a valid amount.
+#[cfg(test)]
+pub(crate) fn amount(value: f64) -> Amount {
+    Amount::from_f64(value, Rounding::HalfEven).expect("a valid amount")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        amount::Amount,
+        errors::PaymentError,
+        report::Rounding,
+    };
+
+    /// Returns `count` amounts spread over a wide range, from a fixed seed.
+    fn sample_amounts(count: usize) -> Vec<Amount> {
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        (0..count)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                let units = (state >> 33) as i64 - (1 << 30);
+                Amount::from_units(units * (state % 1000) as i64).unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn floats_are_rounded_to_four_places() -> Result<(), PaymentError> {
+        let amount = |value, rounding| Amount::from_f64(value, rounding).map(Amount::units);
+        assert_eq!(amount(1.5, Rounding::HalfEven)?, 15000);
+        assert_eq!(amount(0.00005, Rounding::HalfEven)?, 0);
+        assert_eq!(amount(0.00015, Rounding::HalfEven)?, 2);
+        assert_eq!(amount(0.00005, Rounding::HalfUp)?, 1);
+        assert_eq!(amount(-0.00005, Rounding::HalfUp)?, -1);
+        assert_eq!(amount(2.67559, Rounding::Truncate)?, 26755);
+        assert_eq!(amount(0.1 + 0.2, Rounding::HalfEven)?, 3000);
+
+        assert!(Amount::from_f64(f64::NAN, Rounding::HalfEven).is_err());
+        assert!(Amount::from_f64(1e300, Rounding::HalfEven).is_err());
+        assert_eq!(Amount::from_f64(-1.25, Rounding::HalfEven)?.to_string(), "-1.2500");
+        Ok(())
+    }
+
+    #[test]
+    fn written_amounts_match_their_display() {
+        let edges = [0, 1, -1, 9999, -9999, 10000, -10000, i64::MAX, -i64::MAX];
+        let edges = edges.into_iter().map(|units| Amount::from_units(units).unwrap());
+        for amount in edges.chain(sample_amounts(1000)) {
+            let mut out = Vec::new();
+            amount.write_to(&mut out);
+            assert_eq!(String::from_utf8(out).unwrap(), amount.to_string());
+        }
+    }
+
+    #[test]
+    fn overflow_is_an_error() {
+        assert!(Amount::MAX.checked_add(Amount::from_units(1).unwrap()).is_err());
+        assert!(Amount::MIN.checked_sub(Amount::from_units(1).unwrap()).is_err());
+        assert!(Amount::MAX.checked_mul(2.0, Rounding::HalfEven).is_err());
+        assert_eq!(-Amount::MIN, Amount::MAX);
+        assert_eq!(Amount::from_units(i64::MIN), None);
+    }
+
+    #[test]
+    fn sums_are_associative() -> Result<(), PaymentError> {
+        let amounts = sample_amounts(300);
+        for abc in amounts.chunks_exact(3) {
+            let (a, b, c) = (abc[0], abc[1], abc[2]);
+            assert_eq!(a.checked_add(b)?.checked_add(c)?, a.checked_add(b.checked_add(c)?)?);
+            assert_eq!(a.checked_add(b)?.checked_sub(b)?, a);
+            assert_eq!(a.checked_sub(b)?, a.checked_add(-b)?);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn products_are_associative_within_one_unit() -> Result<(), PaymentError> {
+        let factors = [0.015, -0.25, 0.5, 0.7351, 0.99995, 1.0];
+        for rounding in [Rounding::HalfUp, Rounding::HalfEven, Rounding::Truncate] {
+            for amount in sample_amounts(100) {
+                for x in factors {
+                    for y in factors {
+                        let stepwise = amount.checked_mul(x, rounding)?.checked_mul(y, rounding)?;
+                        let at_once = amount.checked_mul(x * y, rounding)?;
+                        let drift = (stepwise.units() - at_once.units()).abs();
+                        assert!(drift <= 1, "{} * {} * {}: {} != {}", amount, x, y, stepwise, at_once);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
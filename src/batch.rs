@@ -0,0 +1,76 @@
+use crate::{
+    errors::PaymentError,
+    parser::ParsedRecord,
+    rejection::RejectionRecord,
+    types::{SourceRef, Transaction},
+};
+
+/// A row of a batch: its transaction, or why it couldn't be parsed, and where it was read from
+/// when known. The line and file of a row end up in the record of its rejection.
+#[derive(Debug)]
+pub struct BatchRow {
+    pub transaction: Result<Transaction, PaymentError>,
+    pub source: Option<SourceRef>,
+}
+
+impl From<Result<Transaction, PaymentError>> for BatchRow {
+    fn from(transaction: Result<Transaction, PaymentError>) -> Self {
+        BatchRow {
+            transaction,
+            source: None,
+        }
+    }
+}
+
+impl From<ParsedRecord> for BatchRow {
+    fn from(record: ParsedRecord) -> Self {
+        BatchRow {
+            source: Some(record.source_ref()),
+            transaction: record.transaction,
+        }
+    }
+}
+
+/// A transaction the engine ignored while processing a batch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rejection {
+    /// Position of the row in the batch, starting at 1.
+    pub row: usize,
+    pub record: RejectionRecord,
+}
+
+/// What `PaymentEngine::process_all` or `process_stream` did with a batch of rows.
+#[derive(Debug, Default)]
+pub struct BatchResult {
+    /// Transactions that changed the engine state.
+    pub applied: usize,
+    /// Transactions the engine ignored, each listed in `rejections`.
+    pub ignored: usize,
+    /// Rows that failed to parse.
+    pub errors: usize,
+    /// The ignored transactions, in batch order.
+    pub rejections: Vec<Rejection>,
+    /// The error that stopped the batch early: a transaction store failure, the first row that
+    /// failed to parse under `EngineConfig::fail_fast`, or the first ignored transaction under
+    /// `EngineConfig::fail_on_ignore`. A row that stopped the batch this way is still counted
+    /// in `errors` or `ignored`.
+    pub error: Option<PaymentError>,
+    /// Set when the batch was cancelled, or timed out, before its stream ended. The rows
+    /// received until then were processed in full.
+    pub cancelled: bool,
+}
+
+impl BatchResult {
+    /// Returns the number of rows the batch went through.
+    pub fn rows(&self) -> usize {
+        self.applied + self.ignored + self.errors
+    }
+
+    /// Returns the result, or the error that stopped the batch early.
+    pub fn into_result(mut self) -> Result<Self, PaymentError> {
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => Ok(self),
+        }
+    }
+}
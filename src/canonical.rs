@@ -0,0 +1,223 @@
+//! The canonical encoding of client states and transactions: the one byte sequence each has
+//! whatever the platform, build or settings, for everything that hashes or keeps them to be
+//! compared later, starting with `PaymentEngine::state_digest`.
+//!
+//! Both are CSV rows, with their fields in a fixed order, separated by `,` and ending in `\n`:
+//!
+//! * client ids, tx ids and timestamps are written in decimal, whatever the width of `ClientId`;
+//! * amounts are written from their fixed-point units, with `amount::SCALE` decimal places, `.`
+//!   as separator and a `-` in front of negative ones, never through floating point formatting.
+//!   The amount of a transaction is first rounded half to even to `SCALE` places;
+//! * transaction types and account statuses are written as their snake_case name;
+//! * a missing optional field is empty, and a text field is quoted if it contains a `,`, `"`,
+//!   `\r` or `\n`, its quotes being doubled.
+//!
+//! An encoding doesn't change within a version: another field, order or format bumps its version
+//! constant, so that what was encoded by two versions is never taken for the same.
+
+use crate::{
+    amount::{self, Amount},
+    errors::PaymentError,
+    report::{csv_field, Rounding},
+    types::{ClientView, Transaction},
+};
+
+/// Version of the encoding of `encode_client`.
+pub const CLIENT_ENCODING_VERSION: u32 = 1;
+
+/// Version of the encoding of `encode_transaction`.
+pub const TRANSACTION_ENCODING_VERSION: u32 = 1;
+
+/// The fields of `encode_client`, as a header line without its `\n`. Unlike the client states
+/// report, which only tells locked accounts apart, it keeps the status of the account.
+pub const CLIENT_HEADER: &str = "client,available,held,total,status";
+
+/// The fields of `encode_transaction`, as a header line without its `\n`. It is a valid input
+/// header, so that encoded transactions can be read back as input.
+pub const TRANSACTION_HEADER: &str = "type,client,tx,amount,reason,merchant,timestamp,memo,idempotency_key";
+
+/// Appends the canonical row of a client's state, `client,available,held,total,status`, the
+/// status being `active`, `frozen` or `locked`.
+pub fn encode_client(view: ClientView, out: &mut Vec<u8>) {
+    amount::push_digits(out, view.client.into());
+    for balance in [view.available, view.held, view.total] {
+        out.push(b',');
+        balance.write_to(out);
+    }
+    out.push(b',');
+    out.extend_from_slice(view.status.as_str().as_bytes());
+    out.push(b'\n');
+}
+
+/// Appends the canonical row of a transaction, with the fields of `TRANSACTION_HEADER`.
+///
+/// # Errors
+///
+/// Returns a `PaymentError::AmountOutOfRange`, appending nothing, if the transaction's amount
+/// isn't a valid `Amount`.
+pub fn encode_transaction(txn: &Transaction, out: &mut Vec<u8>) -> Result<(), PaymentError> {
+    let amount = txn.amount.map(|amount| Amount::from_f64(amount, Rounding::HalfEven)).transpose()?;
+    out.extend_from_slice(txn.r#type.as_str().as_bytes());
+    out.push(b',');
+    amount::push_digits(out, txn.client.into());
+    out.push(b',');
+    amount::push_digits(out, txn.tx.into());
+    out.push(b',');
+    if let Some(amount) = amount {
+        amount.write_to(out);
+    }
+    for text in [txn.reason.as_deref(), txn.merchant.as_deref()] {
+        out.push(b',');
+        push_text(out, text);
+    }
+    out.push(b',');
+    if let Some(timestamp) = txn.timestamp {
+        amount::push_digits(out, timestamp);
+    }
+    for text in [&txn.memo, &txn.idempotency_key] {
+        out.push(b',');
+        push_text(out, text.as_deref());
+    }
+    out.push(b'\n');
+    Ok(())
+}
+
+fn push_text(out: &mut Vec<u8>, text: Option<&str>) {
+    if let Some(text) = text {
+        out.extend_from_slice(csv_field(text).as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        amount::Amount,
+        canonical::{encode_client, encode_transaction, TRANSACTION_HEADER},
+        errors::PaymentError,
+        parser::parse_transactions,
+        payment_engine::PaymentEngine,
+        types::{AccountStatus, Client, ClientId, ClientView, Transaction},
+    };
+
+    fn client(client: ClientId, units: [i64; 3], status: AccountStatus) -> ClientView {
+        let [available, held, total] = units.map(|units| Amount::from_units(units).expect("in range"));
+        ClientView {
+            client,
+            available,
+            held,
+            total,
+            status,
+        }
+    }
+
+    fn encoded_client(view: ClientView) -> String {
+        let mut out = Vec::new();
+        encode_client(view, &mut out);
+        String::from_utf8(out).expect("the encoding is text")
+    }
+
+    fn encoded_transaction(txn: &Transaction) -> Result<String, PaymentError> {
+        let mut out = Vec::new();
+        encode_transaction(txn, &mut out)?;
+        Ok(String::from_utf8(out).expect("the encoding is text"))
+    }
+
+    #[test]
+    fn client_states_encode_to_pinned_bytes() {
+        let cases = [
+            (client(0, [0, 0, 0], AccountStatus::Active), "0,0.0000,0.0000,0.0000,active\n"),
+            (client(1, [15_000, 2_500, 17_500], AccountStatus::Active), "1,1.5000,0.2500,1.7500,active\n"),
+            (client(2, [-1, 1, 0], AccountStatus::Frozen), "2,-0.0001,0.0001,0.0000,frozen\n"),
+            (
+                client(3, [-12_345_678, 0, -12_345_678], AccountStatus::Locked),
+                "3,-1234.5678,0.0000,-1234.5678,locked\n",
+            ),
+            (
+                client(65535, [i64::MAX, 0, -i64::MAX], AccountStatus::Active),
+                "65535,922337203685477.5807,0.0000,-922337203685477.5807,active\n",
+            ),
+        ];
+        for (view, expected) in cases {
+            assert_eq!(encoded_client(view), expected);
+        }
+    }
+
+    #[test]
+    fn transactions_encode_to_pinned_bytes() -> Result<(), PaymentError> {
+        let mut deposit = Transaction::deposit(7, 4_294_967_295, 1.5)
+            .with_merchant("acme, inc")
+            .with_idempotency_key("k-1");
+        deposit.timestamp = Some(1_700_000_000);
+        deposit.memo = Some("said \"hi\"\nthen left".to_owned());
+        let mut chargeback = Transaction::chargeback(7, 3);
+        chargeback.reason = Some("fraud".to_owned());
+        let cases = [
+            (
+                deposit,
+                "deposit,7,4294967295,1.5000,,\"acme, inc\",1700000000,\"said \"\"hi\"\"\nthen left\",k-1\n",
+            ),
+            (chargeback, "chargeback,7,3,,fraud,,,,\n"),
+            // ties are rounded half to even, on the decimal amount the input meant
+            (Transaction::withdrawal(1, 1, 0.00005), "withdrawal,1,1,0.0000,,,,,\n"),
+            (Transaction::withdrawal(1, 2, 0.00015), "withdrawal,1,2,0.0002,,,,,\n"),
+            (Transaction::withdrawal(1, 3, -2.5), "withdrawal,1,3,-2.5000,,,,,\n"),
+        ];
+        for (txn, expected) in cases {
+            assert_eq!(encoded_transaction(&txn)?, expected);
+        }
+
+        let mut out = b"kept".to_vec();
+        let invalid = encode_transaction(&Transaction::deposit(1, 1, f64::NAN), &mut out);
+        assert!(matches!(invalid, Err(PaymentError::AmountOutOfRange(_))));
+        assert_eq!(out, b"kept");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn encoded_transactions_read_back_as_input() -> Result<(), PaymentError> {
+        let mut memo = Transaction::deposit(1, 1, 12.3456).with_merchant("acme");
+        memo.memo = Some("a, \"b\"".to_owned());
+        let transactions = [memo, Transaction::dispute(1, 1), Transaction::withdrawal(2, 2, 0.5)];
+        let mut csv = format!("{}\n", TRANSACTION_HEADER).into_bytes();
+        for txn in &transactions {
+            encode_transaction(txn, &mut csv)?;
+        }
+        let parsed = parse_transactions(Box::new(std::io::Cursor::new(csv))).await?.collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(parsed, transactions);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn encodings_depend_on_the_values_only() -> Result<(), PaymentError> {
+        // amounts are written from their units with integer arithmetic only
+        for units in [0, 1, 9, 10, 9_999, 10_000, 10_001, 123_456_789, i64::MAX] {
+            for units in [units, -units] {
+                let view = client(1, [units, 0, units], AccountStatus::Active);
+                let sign = if units < 0 { "-" } else { "" };
+                let (whole, fraction) = (units.unsigned_abs() / 10_000, units.unsigned_abs() % 10_000);
+                let amount = format!("{}{}.{:04}", sign, whole, fraction);
+                assert_eq!(encoded_client(view), format!("1,{},0.0000,{},active\n", amount, amount));
+            }
+        }
+
+        // and the accounts are taken in client id order, whatever the order of the engine's map
+        let accounts: Vec<(ClientId, Client)> = (1..=500)
+            .map(|id| {
+                let mut account = Client::new();
+                account.available = Amount::from_units(i64::from(id) * 7_919).expect("in range");
+                account.total = account.available;
+                (id, account)
+            })
+            .collect();
+        let mut forward = PaymentEngine::new();
+        let mut backward = PaymentEngine::new();
+        for (id, account) in &accounts {
+            forward.clients.insert(*id, *account);
+        }
+        for (id, account) in accounts.iter().rev() {
+            backward.clients.insert(*id, *account);
+        }
+        assert_eq!(forward.state_digest(), backward.state_digest());
+        Ok(())
+    }
+}
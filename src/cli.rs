@@ -0,0 +1,1903 @@
+use payment_engine::{
+    config::{
+        ClientCreationPolicy, DisputeAmountPolicy, DisputeShortfallPolicy, DisputeSupport, DuplicateAction,
+        DuplicateDetection, DustPolicy, DEFAULT_MAX_IDEMPOTENCY_KEYS, DEFAULT_MAX_MEMO_LEN,
+    },
+    diagnostics::ErrorFormat,
+    external_sort::{InputOrder, DEFAULT_SORT_CHUNK_ROWS},
+    errors::PaymentError,
+    filter::ClientFilter,
+    inspect::InspectQuery,
+    journal::DEFAULT_SYNC_EVERY,
+    parser::{AmountUnit, InputFormat, InputLimits, STDIN_PATH},
+    report::{OutputOptions, OutputOrder, ReportFormat, ReportOptions, Rounding, MAX_PRECISION},
+    shared_engine::StreamOptions,
+    simulate::SimulationConfig,
+    statements::UtcOffset,
+    types::{AsOfTx, ClientId, LockedDepositPolicy},
+};
+use std::time::Duration;
+
+/// Default interval between two rewrites of the report in `--follow` mode.
+const DEFAULT_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What the binary was asked to do.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Process a transactions file and output the account states (the default, `process` may
+    /// also be given explicitly).
+    Process(Box<CliOptions>),
+    /// Compare two account states reports.
+    Diff(DiffOptions),
+    /// Recompute the account states from a transactions file and check them against a claimed state.
+    Verify(VerifyOptions),
+    /// Propose the resolves and chargebacks missing from a transactions file to reach a claimed state.
+    Reconcile(VerifyOptions),
+    /// Write the transactions backing out a batch applied by mistake.
+    Invert(InvertOptions),
+    /// Answer a query about the accounts of a clients dump, without any transaction.
+    Inspect(InspectOptions),
+    /// Check the references of a disputes file against a deposit index, without any account.
+    Validate(ValidateOptions),
+    /// Run a seeded simulation, checking the engine's invariants along the way.
+    Simulate(SimulateOptions),
+    /// Process a transactions file and write the daily statements of some clients.
+    Statements(StatementsOptions),
+    /// Read transactions and commands interactively from stdin.
+    Repl,
+}
+
+impl Command {
+    /// Parses the command line arguments, excluding the program name.
+    pub fn parse<I: Iterator<Item = String>>(args: I) -> Result<Self, PaymentError> {
+        let mut args = args.peekable();
+        match args.peek().map(String::as_str) {
+            Some("process") => CliOptions::parse(args.skip(1)).map(Box::new).map(Command::Process),
+            Some("diff") => DiffOptions::parse(args.skip(1)).map(Command::Diff),
+            Some("verify") => VerifyOptions::parse("verify", args.skip(1)).map(Command::Verify),
+            Some("reconcile") => VerifyOptions::parse("reconcile", args.skip(1)).map(Command::Reconcile),
+            Some("invert") => InvertOptions::parse(args.skip(1)).map(Command::Invert),
+            Some("inspect") => InspectOptions::parse(args.skip(1)).map(Command::Inspect),
+            Some("validate") => ValidateOptions::parse(args.skip(1)).map(Command::Validate),
+            Some("simulate") => SimulateOptions::parse(args.skip(1)).map(Command::Simulate),
+            Some("statements") => StatementsOptions::parse(args.skip(1)).map(Command::Statements),
+            Some("repl") => match args.nth(1) {
+                None => Ok(Command::Repl),
+                Some(arg) => Err(PaymentError::InvalidCliArgument(format!(
+                    "repl takes no argument, got '{}'",
+                    arg
+                ))),
+            },
+            _ => CliOptions::parse(args).map(Box::new).map(Command::Process),
+        }
+    }
+
+    /// Returns the error format asked for with `--errors`, looked up before the arguments are
+    /// parsed so that invalid arguments are reported in it too.
+    pub fn error_format(args: &[String]) -> ErrorFormat {
+        args.windows(2)
+            .rev()
+            .find(|pair| pair[0] == "--errors")
+            .and_then(|pair| ErrorFormat::parse(&pair[1]).ok())
+            .unwrap_or_default()
+    }
+}
+
+/// Options of the `diff` subcommand: `diff <before.csv> <after.csv> [--epsilon <e>]`.
+#[derive(Debug, PartialEq)]
+pub struct DiffOptions {
+    pub before: String,
+    pub after: String,
+    /// Amounts differing by no more than this are considered equal.
+    pub epsilon: f64,
+}
+
+impl DiffOptions {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, PaymentError> {
+        let mut files = Vec::new();
+        let mut epsilon = 0.0;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--epsilon" => {
+                    let value = flag_value(&arg, args.next())?;
+                    epsilon = value.parse::<f64>().ok().filter(|e| *e >= 0.0).ok_or_else(|| {
+                        PaymentError::InvalidCliArgument(format!(
+                            "{} expects a non-negative number, got '{}'",
+                            arg, value
+                        ))
+                    })?;
+                }
+                flag if flag.starts_with("--") => {
+                    return Err(PaymentError::InvalidCliArgument(format!(
+                        "unknown option '{}'",
+                        flag
+                    )))
+                }
+                _ => files.push(arg),
+            }
+        }
+
+        match <[String; 2]>::try_from(files) {
+            Ok([before, after]) => Ok(DiffOptions {
+                before,
+                after,
+                epsilon,
+            }),
+            Err(_) => Err(PaymentError::InvalidCliArgument(
+                "diff expects exactly two account states files".to_owned(),
+            )),
+        }
+    }
+}
+
+/// Options of the `verify` subcommand: `verify <transactions.csv> --against <accounts.csv>`, also
+/// taken by `reconcile`.
+#[derive(Debug, PartialEq)]
+pub struct VerifyOptions {
+    pub transactions: String,
+    pub against: String,
+}
+
+impl VerifyOptions {
+    fn parse<I: Iterator<Item = String>>(command: &str, mut args: I) -> Result<Self, PaymentError> {
+        let mut transactions = None;
+        let mut against = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--against" => against = Some(flag_value(&arg, args.next())?),
+                flag if flag.starts_with("--") => {
+                    return Err(PaymentError::InvalidCliArgument(format!(
+                        "unknown option '{}'",
+                        flag
+                    )))
+                }
+                _ if transactions.is_none() => transactions = Some(arg),
+                _ => {
+                    return Err(PaymentError::InvalidCliArgument(format!(
+                        "unexpected argument '{}'",
+                        arg
+                    )))
+                }
+            }
+        }
+
+        match (transactions, against) {
+            (Some(transactions), Some(against)) => Ok(VerifyOptions {
+                transactions,
+                against,
+            }),
+            _ => Err(PaymentError::InvalidCliArgument(format!(
+                "{} expects a transactions file and --against <accounts.csv>",
+                command
+            ))),
+        }
+    }
+}
+
+/// Options of the `invert` subcommand:
+/// `invert <batch.csv> [--initial-state <clients.json>] [--report <non_invertible.csv>]`.
+#[derive(Debug, PartialEq)]
+pub struct InvertOptions {
+    pub batch: String,
+    /// Clients dump of the accounts before the batch was applied, empty accounts if not given.
+    pub initial_state: Option<String>,
+    /// Write the rows that can't be backed out to this CSV file rather than stderr.
+    pub report: Option<String>,
+}
+
+impl InvertOptions {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, PaymentError> {
+        let mut batch = None;
+        let mut initial_state = None;
+        let mut report = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--initial-state" => initial_state = Some(flag_value(&arg, args.next())?),
+                "--report" => report = Some(flag_value(&arg, args.next())?),
+                flag if flag.starts_with("--") => {
+                    return Err(PaymentError::InvalidCliArgument(format!(
+                        "unknown option '{}'",
+                        flag
+                    )))
+                }
+                _ if batch.is_none() => batch = Some(arg),
+                _ => {
+                    return Err(PaymentError::InvalidCliArgument(format!(
+                        "unexpected argument '{}'",
+                        arg
+                    )))
+                }
+            }
+        }
+
+        match batch {
+            Some(batch) => Ok(InvertOptions {
+                batch,
+                initial_state,
+                report,
+            }),
+            None => Err(PaymentError::InvalidCliArgument(
+                "invert expects the transactions file of a batch".to_owned(),
+            )),
+        }
+    }
+}
+
+/// Options of the `inspect` subcommand: `inspect <clients.json> [--client <ids> | --summary |
+/// --open-disputes | --locked] [--format csv|json|table]`.
+#[derive(Debug, PartialEq)]
+pub struct InspectOptions {
+    /// Clients dump to read, as written with `--dump-clients-json`.
+    pub dump: String,
+    /// What to report, every account if no query is given.
+    pub query: InspectQuery,
+    pub format: ReportFormat,
+}
+
+impl InspectOptions {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, PaymentError> {
+        let mut dump = None;
+        let mut queries = Vec::new();
+        let mut format = ReportFormat::default();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--client" => queries.push(InspectQuery::Accounts(Some(ClientFilter::parse(&flag_value(
+                    &arg,
+                    args.next(),
+                )?)?))),
+                "--summary" => queries.push(InspectQuery::Summary),
+                "--open-disputes" => queries.push(InspectQuery::OpenDisputes),
+                "--locked" => queries.push(InspectQuery::Locked),
+                "--format" => format = ReportFormat::parse(&flag_value(&arg, args.next())?)?,
+                flag if flag.starts_with("--") => {
+                    return Err(PaymentError::InvalidCliArgument(format!(
+                        "unknown option '{}'",
+                        flag
+                    )))
+                }
+                _ if dump.is_none() => dump = Some(arg),
+                _ => {
+                    return Err(PaymentError::InvalidCliArgument(format!(
+                        "unexpected argument '{}'",
+                        arg
+                    )))
+                }
+            }
+        }
+
+        if queries.len() > 1 {
+            return Err(PaymentError::InvalidCliArgument(
+                "inspect answers one of --client, --summary, --open-disputes and --locked at a time".to_owned(),
+            ));
+        }
+        match dump {
+            Some(dump) => Ok(InspectOptions {
+                dump,
+                query: queries.pop().unwrap_or(InspectQuery::Accounts(None)),
+                format,
+            }),
+            None => Err(PaymentError::InvalidCliArgument(
+                "inspect expects a clients dump".to_owned(),
+            )),
+        }
+    }
+}
+
+/// Options of the `validate` subcommand: `validate <disputes.csv> --deposit-index <index>`.
+#[derive(Debug, PartialEq)]
+pub struct ValidateOptions {
+    /// Disputes file to check.
+    pub disputes: String,
+    /// Deposit index to check it against, as written with `--emit-deposit-index`.
+    pub deposit_index: String,
+}
+
+impl ValidateOptions {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, PaymentError> {
+        let mut disputes = None;
+        let mut deposit_index = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--deposit-index" => deposit_index = Some(flag_value(&arg, args.next())?),
+                flag if flag.starts_with("--") => {
+                    return Err(PaymentError::InvalidCliArgument(format!(
+                        "unknown option '{}'",
+                        flag
+                    )))
+                }
+                _ if disputes.is_none() => disputes = Some(arg),
+                _ => {
+                    return Err(PaymentError::InvalidCliArgument(format!(
+                        "unexpected argument '{}'",
+                        arg
+                    )))
+                }
+            }
+        }
+
+        match (disputes, deposit_index) {
+            (Some(disputes), Some(deposit_index)) => Ok(ValidateOptions { disputes, deposit_index }),
+            (None, _) => Err(PaymentError::InvalidCliArgument(
+                "validate expects a disputes file".to_owned(),
+            )),
+            (_, None) => Err(PaymentError::InvalidCliArgument(
+                "validate expects --deposit-index <path>".to_owned(),
+            )),
+        }
+    }
+}
+
+/// Options of the `simulate` subcommand: `simulate [--seed <n>] [--rows <n>] [--clients <n>]
+/// [--check-every <n>] [--repro <failure.json>]`.
+#[derive(Debug, PartialEq)]
+pub struct SimulateOptions {
+    pub config: SimulationConfig,
+    /// Where to write a failure's reproduction, `simulation-<seed>-failure.json` if not given.
+    pub repro: Option<String>,
+}
+
+impl SimulateOptions {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, PaymentError> {
+        let mut config = SimulationConfig::default();
+        let mut repro = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--seed" => {
+                    let value = flag_value(&arg, args.next())?;
+                    config.seed = value.parse().map_err(|_| {
+                        PaymentError::InvalidCliArgument(format!(
+                            "--seed expects an unsigned integer, got '{}'",
+                            value
+                        ))
+                    })?;
+                }
+                "--rows" => config.rows = positive_integer(&arg, flag_value(&arg, args.next())?)?,
+                "--clients" => {
+                    let clients = positive_integer(&arg, flag_value(&arg, args.next())?)?;
+                    config.clients = ClientId::try_from(clients).map_err(|_| {
+                        PaymentError::InvalidCliArgument(format!(
+                            "--clients can be at most {} in this build, got {} (client ids wider \
+                             than 16 bits need the wide-client-ids feature)",
+                            ClientId::MAX,
+                            clients
+                        ))
+                    })?;
+                }
+                "--check-every" => {
+                    config.check_every = positive_integer(&arg, flag_value(&arg, args.next())?)?
+                }
+                "--repro" => repro = Some(flag_value(&arg, args.next())?),
+                flag if flag.starts_with("--") => {
+                    return Err(PaymentError::InvalidCliArgument(format!(
+                        "unknown option '{}'",
+                        flag
+                    )))
+                }
+                _ => {
+                    return Err(PaymentError::InvalidCliArgument(format!(
+                        "unexpected argument '{}'",
+                        arg
+                    )))
+                }
+            }
+        }
+        Ok(SimulateOptions { config, repro })
+    }
+}
+
+/// Options of the `statements` subcommand:
+/// `statements <transactions.csv> (--client <id> | --all) [--utc-offset <+hh:mm>]`.
+#[derive(Debug, PartialEq)]
+pub struct StatementsOptions {
+    pub transactions: String,
+    /// The clients to write statements for, every client for `--all`.
+    pub clients: Option<ClientFilter>,
+    /// Offset from UTC of the statements' days.
+    pub utc_offset: UtcOffset,
+}
+
+impl StatementsOptions {
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, PaymentError> {
+        let mut transactions = None;
+        let mut clients = None;
+        let mut all = false;
+        let mut utc_offset = UtcOffset::UTC;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--client" => clients = Some(ClientFilter::parse(&flag_value(&arg, args.next())?)?),
+                "--all" => all = true,
+                "--utc-offset" => utc_offset = UtcOffset::parse(&flag_value(&arg, args.next())?)?,
+                flag if flag.starts_with("--") => {
+                    return Err(PaymentError::InvalidCliArgument(format!(
+                        "unknown option '{}'",
+                        flag
+                    )))
+                }
+                _ if transactions.is_none() => transactions = Some(arg),
+                _ => {
+                    return Err(PaymentError::InvalidCliArgument(format!(
+                        "unexpected argument '{}'",
+                        arg
+                    )))
+                }
+            }
+        }
+
+        match (transactions, clients.is_some() != all) {
+            (Some(transactions), true) => Ok(StatementsOptions {
+                transactions,
+                clients,
+                utc_offset,
+            }),
+            _ => Err(PaymentError::InvalidCliArgument(
+                "statements expects a transactions file and either --client <id> or --all".to_owned(),
+            )),
+        }
+    }
+}
+
+/// Options accepted on the command line.
+#[derive(Debug, PartialEq)]
+pub struct CliOptions {
+    /// Path of the transactions CSV file, `-` for stdin.
+    pub file_path: String,
+    /// Further transactions CSV files, processed after `file_path` in the same run.
+    pub extra_files: Vec<String>,
+    /// Process every file with its own engine at once and merge the engines, for files that
+    /// share no client.
+    pub parallel_files: bool,
+    /// Under `--parallel-files`, write the clients of each file to stdout as soon as the file
+    /// is processed, instead of the report of the merged engines. Ignored otherwise.
+    pub stream_report: bool,
+    /// Stream the rows of each file ordered by client id.
+    pub sort_within_shard: bool,
+    /// Append a `shard` column to the streamed rows, the index of their file.
+    pub tag_shard: bool,
+    /// Keep at most this many stored transactions in memory and spill the rest to disk.
+    pub max_transactions_in_memory: Option<usize>,
+    /// Also write the run statistics as JSON to this path.
+    pub stats_json: Option<String>,
+    /// Print a SHA-256 digest of the final account states as the last stderr line.
+    pub checksum: bool,
+    /// Print periodic progress lines to stderr (only when stderr is a terminal).
+    pub progress: bool,
+    /// How errors and warnings are written to stderr.
+    pub errors: ErrorFormat,
+    /// Exit with a dedicated code when any transaction was rejected.
+    pub fail_on_reject: bool,
+    /// Exit with a dedicated code when no transaction was parsed.
+    pub fail_on_empty: bool,
+    /// Abort the run at the first transaction the engine ignores.
+    pub strict_engine: bool,
+    /// Skip rows that fail to parse instead of aborting the run.
+    pub lenient: bool,
+    /// Append rows that fail to parse or are rejected by the engine to this CSV file.
+    pub quarantine: Option<String>,
+    /// Write a record of every rejected row to this file, as CSV or, for `.jsonl` paths, JSON
+    /// lines.
+    pub rejections_report: Option<String>,
+    /// Only report these clients.
+    pub clients: Option<ClientFilter>,
+    /// Skip transactions of clients outside `clients` entirely.
+    pub filter_input: bool,
+    /// Write one `client_<id>.csv` file per client into this directory instead of stdout.
+    pub output_dir: Option<String>,
+    /// Allow writing into a non-empty output directory.
+    pub force: bool,
+    /// Write the account states to this file, atomically, instead of stdout.
+    pub report: Option<String>,
+    /// Keep following the transactions file as it grows, rewriting `report` periodically.
+    pub follow: bool,
+    /// In `--follow` mode, rewrite the report at least this often when rows were applied.
+    pub refresh_interval: Duration,
+    /// In `--follow` mode, also rewrite the report once this many rows were applied.
+    pub refresh_rows: Option<u64>,
+    /// Stop processing at the first transaction with this tx id, in stream order.
+    pub as_of: Option<AsOfTx>,
+    /// Format of the account states report.
+    pub format: ReportFormat,
+    /// Append the dispute counters of each client to the report.
+    pub extended_output: bool,
+    /// Write the disputes still open at the end of the run to this CSV file.
+    pub disputes_report: Option<String>,
+    /// Write the locked accounts and the chargebacks that locked them to this CSV file.
+    pub locked_report: Option<String>,
+    /// Write the deposit, dispute and chargeback statistics of each merchant to this CSV file.
+    pub merchant_report: Option<String>,
+    /// Write the clients owing money to this CSV file.
+    pub debtors_report: Option<String>,
+    /// Write the losses written off by chargebacks to this CSV file.
+    pub losses_report: Option<String>,
+    /// Write the interest each account was paid by accruals to this CSV file.
+    pub accruals_report: Option<String>,
+    /// Write the index of the deposits the engine kept to this file, for `validate`.
+    pub emit_deposit_index: Option<String>,
+    /// Write every transaction the engine retained, with its state, to this CSV file.
+    pub export_transactions: Option<String>,
+    /// Write the client accounts to this JSON file, after the run.
+    pub dump_clients_json: Option<String>,
+    /// Load the client accounts from this JSON file, as written by `--dump-clients-json`,
+    /// before the run.
+    pub initial_state: Option<String>,
+    /// Print how the run changes the accounts instead of writing `dump_clients_json`.
+    pub dry_run: bool,
+    /// Reject the deposits and withdrawals journaled in this file by earlier runs, and journal
+    /// those applied.
+    pub journal: Option<String>,
+    /// Write and fsync the journal every this many applied deposits and withdrawals.
+    pub journal_sync_every: usize,
+    /// Show at most this many clients in the table report.
+    pub max_rows: Option<usize>,
+    /// Order of the clients in the report.
+    pub order: OutputOrder,
+    /// Precision, rounding and separators of the amounts in the report.
+    pub output: OutputOptions,
+    /// What happens to deposits to locked accounts.
+    pub locked_deposits: LockedDepositPolicy,
+    /// File of client ids whose deposits and withdrawals are rejected.
+    pub blocklist: Option<String>,
+    /// Which clients may transact.
+    pub client_creation: ClientCreationPolicy,
+    /// File of onboarded client ids, who may transact without an account in the initial state.
+    pub allowlist: Option<String>,
+    /// Largest total a client may reach through deposits.
+    pub max_client_balance: Option<f64>,
+    /// Fee debited from the client on every chargeback.
+    pub chargeback_fee: Option<f64>,
+    /// Interest rate paid on every unlocked account's available balance once the input is
+    /// processed.
+    pub accrue: Option<f64>,
+    /// What a dispute holds when the client's available funds don't cover it.
+    pub dispute_shortfall: DisputeShortfallPolicy,
+    /// Let resolves and chargebacks release only the held balance when it holds less than the
+    /// dispute, instead of rejecting them.
+    pub clamp_inconsistent_held: bool,
+    /// What the amounts of dispute, resolve and chargeback rows are used for.
+    pub dispute_amounts: DisputeAmountPolicy,
+    /// Whether deposits and withdrawals are stored for later disputes.
+    pub dispute_support: DisputeSupport,
+    /// Smallest amount of a deposit or withdrawal not taken for dust.
+    pub min_amount: Option<f64>,
+    /// What happens to deposits and withdrawals below `min_amount`.
+    pub dust: DustPolicy,
+    /// Largest amount of a single deposit or withdrawal.
+    pub max_transaction_amount: Option<f64>,
+    /// Skip up to this many transactions whose processing panics, rather than aborting.
+    pub max_panics: Option<usize>,
+    /// Suspected duplicate deposits detection, when a window is given.
+    pub duplicate_deposits: Option<DuplicateDetection>,
+    /// Seconds after which an open escrow hold expires, by the input's timestamps.
+    pub hold_ttl_secs: Option<u64>,
+    /// Write the deposits flagged as suspected duplicates to this CSV file.
+    pub duplicates_report: Option<String>,
+    /// Longest memo kept with a transaction, in characters.
+    pub max_memo_len: usize,
+    /// Most idempotency keys remembered to reject repeated transactions, without a journal.
+    pub max_idempotency_keys: usize,
+    /// Stop the run when a deposit would create more client accounts than this.
+    pub max_clients: Option<usize>,
+    /// Stop the run when a deposit or withdrawal would store more transactions than this.
+    pub max_transactions: Option<usize>,
+    /// Reject disputes of a client that already has this many open.
+    pub max_open_disputes_per_client: Option<usize>,
+    /// Reject disputes once this many are open over all clients.
+    pub max_open_disputes: Option<usize>,
+    /// Added to the tx id of every row, for merging feeds whose tx ids collide: one offset for
+    /// every input, or one per input in the order they are given. See `tx_offset`.
+    pub tx_offsets: Vec<u32>,
+    /// Encoding of the transactions inputs.
+    pub input_format: InputFormat,
+    /// Unit of the amount column of the transactions inputs.
+    pub amount_unit: AmountUnit,
+    /// Order in which the input rows are processed.
+    pub input_order: InputOrder,
+    /// Rows sorted in memory at once when ordering the input by timestamp.
+    pub sort_chunk_rows: usize,
+    /// Rows given to the engine at once, grouped by client, 1 to process them one by one.
+    pub chunk_size: usize,
+    /// Bounds on the fields, records and rows of the transactions inputs.
+    pub input_limits: InputLimits,
+    /// Warn about deposits and withdrawals whose tx id is lower than one seen before them.
+    pub check_tx_order: bool,
+    /// Abort the run on the first out of order tx id instead of warning.
+    pub strict_ordering: bool,
+    /// Write the out of order tx ids to this CSV file.
+    pub tx_order_report: Option<String>,
+    /// Validate the whole input first and apply none of it if any row is invalid.
+    pub two_pass: bool,
+    /// Write the findings of the validation pass to this CSV file.
+    pub findings_report: Option<String>,
+}
+
+impl CliOptions {
+    /// Parses the command line arguments, excluding the program name.
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Self, PaymentError> {
+        let mut file_path = None;
+        let mut extra_files = Vec::new();
+        let mut parallel_files = false;
+        let mut stream_report = false;
+        let mut sort_within_shard = false;
+        let mut tag_shard = false;
+        let mut journal = None;
+        let mut journal_sync_every = None;
+        let mut max_transactions_in_memory = None;
+        let mut stats_json = None;
+        let mut checksum = false;
+        let mut progress = false;
+        let mut errors = ErrorFormat::default();
+        let mut fail_on_reject = false;
+        let mut fail_on_empty = false;
+        let mut strict_engine = false;
+        let mut lenient = false;
+        let mut quarantine = None;
+        let mut rejections_report = None;
+        let mut clients = None;
+        let mut filter_input = false;
+        let mut output_dir = None;
+        let mut force = false;
+        let mut report = None;
+        let mut follow = false;
+        let mut refresh_interval = None;
+        let mut refresh_rows = None;
+        let mut as_of_tx = None;
+        let mut as_of_exclusive = false;
+        let mut format = ReportFormat::default();
+        let mut extended_output = false;
+        let mut disputes_report = None;
+        let mut locked_report = None;
+        let mut merchant_report = None;
+        let mut emit_deposit_index = None;
+        let mut export_transactions = None;
+        let mut debtors_report = None;
+        let mut losses_report = None;
+        let mut accruals_report = None;
+        let mut dump_clients_json = None;
+        let mut initial_state = None;
+        let mut dry_run = false;
+        let mut max_rows = None;
+        let mut order = OutputOrder::default();
+        let mut output = OutputOptions::default();
+        let mut locked_deposits = LockedDepositPolicy::default();
+        let mut blocklist = None;
+        let mut client_creation = ClientCreationPolicy::default();
+        let mut allowlist = None;
+        let mut max_client_balance = None;
+        let mut chargeback_fee = None;
+        let mut accrue = None;
+        let mut dispute_shortfall = DisputeShortfallPolicy::default();
+        let mut clamp_inconsistent_held = false;
+        let mut dispute_amounts = DisputeAmountPolicy::default();
+        let mut dispute_support = DisputeSupport::default();
+        let mut min_amount = None;
+        let mut max_transaction_amount = None;
+        let mut dust = DustPolicy::default();
+        let mut max_panics = None;
+        let mut duplicate_window = None;
+        let mut reject_duplicates = false;
+        let mut duplicates_report = None;
+        let mut hold_ttl_secs = None;
+        let mut max_memo_len = None;
+        let mut max_idempotency_keys = None;
+        let mut max_clients = None;
+        let mut max_transactions = None;
+        let mut max_open_disputes_per_client = None;
+        let mut max_open_disputes = None;
+        let mut tx_offsets = Vec::new();
+        let mut input_format = InputFormat::default();
+        let mut amount_unit = AmountUnit::default();
+        let mut input_order = InputOrder::default();
+        let mut sort_chunk_rows = None;
+        let mut chunk_size = None;
+        let mut input_limits = InputLimits::default();
+        let mut check_tx_order = false;
+        let mut strict_ordering = false;
+        let mut tx_order_report = None;
+        let mut two_pass = false;
+        let mut findings_report = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--max-transactions-in-memory" => {
+                    let value = flag_value(&arg, args.next())?;
+                    let n = value.parse::<usize>().ok().filter(|n| *n > 0).ok_or_else(|| {
+                        PaymentError::InvalidCliArgument(format!(
+                            "{} expects a positive integer, got '{}'",
+                            arg, value
+                        ))
+                    })?;
+                    max_transactions_in_memory = Some(n);
+                }
+                "--stats-json" => stats_json = Some(flag_value(&arg, args.next())?),
+                "--checksum" => checksum = true,
+                "--progress" => progress = true,
+                "--errors" => errors = ErrorFormat::parse(&flag_value(&arg, args.next())?)?,
+                "--fail-on-reject" => fail_on_reject = true,
+                "--fail-on-empty" => fail_on_empty = true,
+                "--strict-engine" => strict_engine = true,
+                "--lenient" => lenient = true,
+                "--quarantine" => quarantine = Some(flag_value(&arg, args.next())?),
+                "--rejections-report" => rejections_report = Some(flag_value(&arg, args.next())?),
+                "--clients" => {
+                    clients = Some(ClientFilter::parse(&flag_value(&arg, args.next())?)?)
+                }
+                "--filter-input" => filter_input = true,
+                "--output-dir" => output_dir = Some(flag_value(&arg, args.next())?),
+                "--force" => force = true,
+                "--report" => report = Some(flag_value(&arg, args.next())?),
+                "--follow" => follow = true,
+                "--refresh-secs" => {
+                    let secs = positive_integer(&arg, flag_value(&arg, args.next())?)?;
+                    refresh_interval = Some(Duration::from_secs(secs));
+                }
+                "--refresh-rows" => {
+                    refresh_rows = Some(positive_integer(&arg, flag_value(&arg, args.next())?)?)
+                }
+                "--as-of-tx" => {
+                    let value = flag_value(&arg, args.next())?;
+                    as_of_tx = Some(value.parse::<u32>().map_err(|_| {
+                        PaymentError::InvalidCliArgument(format!(
+                            "{} expects a transaction id, got '{}'",
+                            arg, value
+                        ))
+                    })?);
+                }
+                "--as-of-exclusive" => as_of_exclusive = true,
+                "--format" => format = ReportFormat::parse(&flag_value(&arg, args.next())?)?,
+                "--extended-output" => extended_output = true,
+                "--disputes-report" => disputes_report = Some(flag_value(&arg, args.next())?),
+                "--locked-report" => locked_report = Some(flag_value(&arg, args.next())?),
+                "--merchant-report" => merchant_report = Some(flag_value(&arg, args.next())?),
+                "--emit-deposit-index" => emit_deposit_index = Some(flag_value(&arg, args.next())?),
+                "--export-transactions" => export_transactions = Some(flag_value(&arg, args.next())?),
+                "--debtors-report" => debtors_report = Some(flag_value(&arg, args.next())?),
+                "--losses-report" => losses_report = Some(flag_value(&arg, args.next())?),
+                "--accruals-report" => accruals_report = Some(flag_value(&arg, args.next())?),
+                "--dump-clients-json" => dump_clients_json = Some(flag_value(&arg, args.next())?),
+                "--initial-state" => initial_state = Some(flag_value(&arg, args.next())?),
+                "--dry-run" => dry_run = true,
+                "--journal" => journal = Some(flag_value(&arg, args.next())?),
+                "--journal-sync-every" => {
+                    let value = flag_value(&arg, args.next())?;
+                    journal_sync_every = Some(positive_integer(&arg, value)? as usize)
+                }
+                "--max-rows" => {
+                    max_rows = Some(positive_integer(&arg, flag_value(&arg, args.next())?)? as usize)
+                }
+                "--order" => order = OutputOrder::parse(&flag_value(&arg, args.next())?)?,
+                "--two-pass" => two_pass = true,
+                "--parallel-files" => parallel_files = true,
+                "--stream-report" => stream_report = true,
+                "--sort-within-shard" => sort_within_shard = true,
+                "--tag-shard" => tag_shard = true,
+                "--findings-report" => findings_report = Some(flag_value(&arg, args.next())?),
+                "--check-tx-order" => check_tx_order = true,
+                "--strict-ordering" => strict_ordering = true,
+                "--tx-order-report" => tx_order_report = Some(flag_value(&arg, args.next())?),
+                "--input-format" => input_format = InputFormat::parse(&flag_value(&arg, args.next())?)?,
+                "--amount-unit" => amount_unit = AmountUnit::parse(&flag_value(&arg, args.next())?)?,
+                "--order-by" => input_order = InputOrder::parse(&flag_value(&arg, args.next())?)?,
+                "--sort-chunk-rows" => {
+                    sort_chunk_rows = Some(positive_integer(&arg, flag_value(&arg, args.next())?)? as usize)
+                }
+                "--chunk-size" => {
+                    chunk_size = Some(positive_integer(&arg, flag_value(&arg, args.next())?)? as usize)
+                }
+                "--max-field-bytes" => {
+                    input_limits.max_field_bytes = positive_integer(&arg, flag_value(&arg, args.next())?)?
+                }
+                "--max-record-bytes" => {
+                    input_limits.max_record_bytes = positive_integer(&arg, flag_value(&arg, args.next())?)?
+                }
+                "--max-records" => {
+                    input_limits.max_records = positive_integer(&arg, flag_value(&arg, args.next())?)?
+                }
+                "--precision" => {
+                    let value = flag_value(&arg, args.next())?;
+                    output.precision = value.parse::<u8>().ok().filter(|n| *n <= MAX_PRECISION).ok_or_else(|| {
+                        PaymentError::InvalidCliArgument(format!(
+                            "{} expects a number of decimal places between 0 and {}, got '{}'",
+                            arg, MAX_PRECISION, value
+                        ))
+                    })?;
+                }
+                "--rounding" => output.rounding = Rounding::parse(&flag_value(&arg, args.next())?)?,
+                "--decimal-separator" => {
+                    output.decimal_separator = single_char(&arg, flag_value(&arg, args.next())?)?
+                }
+                "--delimiter" => output.delimiter = single_char(&arg, flag_value(&arg, args.next())?)?,
+                "--blocklist" => blocklist = Some(flag_value(&arg, args.next())?),
+                "--client-creation" => {
+                    client_creation = ClientCreationPolicy::parse(&flag_value(&arg, args.next())?)?
+                }
+                "--allowlist" => allowlist = Some(flag_value(&arg, args.next())?),
+                "--duplicate-window" => {
+                    duplicate_window = Some(positive_integer(&arg, flag_value(&arg, args.next())?)?)
+                }
+                "--reject-duplicates" => reject_duplicates = true,
+                "--duplicates-report" => duplicates_report = Some(flag_value(&arg, args.next())?),
+                "--hold-ttl" => hold_ttl_secs = Some(positive_integer(&arg, flag_value(&arg, args.next())?)?),
+                "--max-client-balance" => {
+                    max_client_balance = Some(amount(&arg, flag_value(&arg, args.next())?)?)
+                }
+                "--max-memo-len" => {
+                    max_memo_len = Some(positive_integer(&arg, flag_value(&arg, args.next())?)? as usize)
+                }
+                "--max-idempotency-keys" => {
+                    max_idempotency_keys = Some(positive_integer(&arg, flag_value(&arg, args.next())?)? as usize)
+                }
+                "--max-clients" => {
+                    max_clients = Some(positive_integer(&arg, flag_value(&arg, args.next())?)? as usize)
+                }
+                "--max-transactions" => {
+                    max_transactions = Some(positive_integer(&arg, flag_value(&arg, args.next())?)? as usize)
+                }
+                "--max-open-disputes-per-client" => {
+                    max_open_disputes_per_client =
+                        Some(positive_integer(&arg, flag_value(&arg, args.next())?)? as usize)
+                }
+                "--max-open-disputes" => {
+                    max_open_disputes = Some(positive_integer(&arg, flag_value(&arg, args.next())?)? as usize)
+                }
+                "--tx-offset" => tx_offsets = offsets(&arg, flag_value(&arg, args.next())?)?,
+                "--chargeback-fee" => {
+                    chargeback_fee = Some(amount(&arg, flag_value(&arg, args.next())?)?)
+                }
+                "--accrue" => accrue = Some(rate(&arg, flag_value(&arg, args.next())?)?),
+                "--dispute-shortfall" => {
+                    dispute_shortfall = DisputeShortfallPolicy::parse(&flag_value(&arg, args.next())?)?
+                }
+                "--clamp-inconsistent-held" => clamp_inconsistent_held = true,
+                "--dispute-amounts" => {
+                    dispute_amounts = DisputeAmountPolicy::parse(&flag_value(&arg, args.next())?)?
+                }
+                "--dispute-support" => {
+                    dispute_support = DisputeSupport::parse(&flag_value(&arg, args.next())?)?
+                }
+                "--min-amount" => min_amount = Some(amount(&arg, flag_value(&arg, args.next())?)?),
+                "--max-transaction-amount" => {
+                    max_transaction_amount = Some(amount(&arg, flag_value(&arg, args.next())?)?)
+                }
+                "--dust" => dust = DustPolicy::parse(&flag_value(&arg, args.next())?)?,
+                "--max-panics" => {
+                    max_panics = Some(positive_integer(&arg, flag_value(&arg, args.next())?)? as usize)
+                }
+                "--locked-deposits" => {
+                    locked_deposits = LockedDepositPolicy::parse(&flag_value(&arg, args.next())?)?
+                }
+                flag if flag.starts_with("--") => {
+                    return Err(PaymentError::InvalidCliArgument(format!(
+                        "unknown option '{}'",
+                        flag
+                    )))
+                }
+                _ if file_path.is_none() => file_path = Some(arg),
+                _ => extra_files.push(arg),
+            }
+        }
+
+        if filter_input && clients.is_none() {
+            return Err(PaymentError::InvalidCliArgument(
+                "--filter-input requires --clients".to_owned(),
+            ));
+        }
+
+        if force && output_dir.is_none() {
+            return Err(PaymentError::InvalidCliArgument(
+                "--force requires --output-dir".to_owned(),
+            ));
+        }
+
+        if report.is_some() && output_dir.is_some() {
+            return Err(PaymentError::InvalidCliArgument(
+                "--report and --output-dir can't be used together".to_owned(),
+            ));
+        }
+
+        if follow && report.is_none() {
+            return Err(PaymentError::InvalidCliArgument(
+                "--follow requires --report".to_owned(),
+            ));
+        }
+
+        if !follow && (refresh_interval.is_some() || refresh_rows.is_some()) {
+            return Err(PaymentError::InvalidCliArgument(
+                "--refresh-secs and --refresh-rows require --follow".to_owned(),
+            ));
+        }
+
+        if max_rows.is_some() && format != ReportFormat::Table {
+            return Err(PaymentError::InvalidCliArgument(
+                "--max-rows requires --format table".to_owned(),
+            ));
+        }
+
+        output.validate()?;
+
+        if duplicate_window.is_none() && (reject_duplicates || duplicates_report.is_some()) {
+            return Err(PaymentError::InvalidCliArgument(
+                "--reject-duplicates and --duplicates-report require --duplicate-window".to_owned(),
+            ));
+        }
+
+        if as_of_exclusive && as_of_tx.is_none() {
+            return Err(PaymentError::InvalidCliArgument(
+                "--as-of-exclusive requires --as-of-tx".to_owned(),
+            ));
+        }
+
+        if follow && !extra_files.is_empty() {
+            return Err(PaymentError::InvalidCliArgument(
+                "--follow takes a single transactions file".to_owned(),
+            ));
+        }
+
+        if tx_offsets.len() > 1 && tx_offsets.len() != extra_files.len() + 1 {
+            return Err(PaymentError::InvalidCliArgument(format!(
+                "--tx-offset gives {} offsets for {} transactions files",
+                tx_offsets.len(),
+                extra_files.len() + 1
+            )));
+        }
+
+        if !extra_files.is_empty() && input_order != InputOrder::File {
+            return Err(PaymentError::InvalidCliArgument(
+                "--order-by timestamp takes a single transactions file".to_owned(),
+            ));
+        }
+
+        if follow && input_order != InputOrder::File {
+            return Err(PaymentError::InvalidCliArgument(
+                "--order-by can't be used with --follow".to_owned(),
+            ));
+        }
+
+        if input_format != InputFormat::Csv && (follow || input_order != InputOrder::File) {
+            return Err(PaymentError::InvalidCliArgument(
+                "only CSV inputs can be used with --follow or --order-by timestamp".to_owned(),
+            ));
+        }
+
+        if amount_unit != AmountUnit::Major && (input_format != InputFormat::Csv || follow) {
+            return Err(PaymentError::InvalidCliArgument(
+                "--amount-unit only applies to CSV inputs and can't be used with --follow".to_owned(),
+            ));
+        }
+
+        if sort_chunk_rows.is_some() && input_order != InputOrder::Timestamp {
+            return Err(PaymentError::InvalidCliArgument(
+                "--sort-chunk-rows requires --order-by timestamp".to_owned(),
+            ));
+        }
+
+        if tx_order_report.is_some() && !check_tx_order && !strict_ordering {
+            return Err(PaymentError::InvalidCliArgument(
+                "--tx-order-report requires --check-tx-order".to_owned(),
+            ));
+        }
+
+        if follow && two_pass {
+            return Err(PaymentError::InvalidCliArgument(
+                "--two-pass can't be used with --follow".to_owned(),
+            ));
+        }
+
+        // stdin is read once, by the sequential run
+        let stdin_inputs = file_path.iter().chain(&extra_files).filter(|path| *path == STDIN_PATH).count();
+        if stdin_inputs > 1 || (stdin_inputs == 1 && (follow || parallel_files)) {
+            return Err(PaymentError::InvalidCliArgument(
+                "stdin ('-') can be given once, and not with --follow or --parallel-files".to_owned(),
+            ));
+        }
+
+        if findings_report.is_some() && !two_pass {
+            return Err(PaymentError::InvalidCliArgument(
+                "--findings-report requires --two-pass".to_owned(),
+            ));
+        }
+
+        if follow && as_of_tx.is_some() {
+            return Err(PaymentError::InvalidCliArgument(
+                "--as-of-tx can't be used with --follow".to_owned(),
+            ));
+        }
+
+        if max_idempotency_keys.is_some() && journal.is_some() {
+            return Err(PaymentError::InvalidCliArgument(
+                "--max-idempotency-keys can't be used with --journal, which keeps every key".to_owned(),
+            ));
+        }
+
+        if journal_sync_every.is_some() && journal.is_none() {
+            return Err(PaymentError::InvalidCliArgument(
+                "--journal-sync-every requires --journal".to_owned(),
+            ));
+        }
+
+        if allowlist.is_some() && client_creation != ClientCreationPolicy::FromInitialStateOnly {
+            return Err(PaymentError::InvalidCliArgument(
+                "--allowlist requires --client-creation initial-state-only".to_owned(),
+            ));
+        }
+
+        // a dry run would journal rows whose changes it doesn't save
+        if journal.is_some() && dry_run {
+            return Err(PaymentError::InvalidCliArgument(
+                "--journal can't be used with --dry-run".to_owned(),
+            ));
+        }
+
+        if (sort_within_shard || tag_shard) && !stream_report {
+            return Err(PaymentError::InvalidCliArgument(
+                "--sort-within-shard and --tag-shard require --stream-report".to_owned(),
+            ));
+        }
+
+        // the streamed rows are the CSV rows of each file's clients, as they are final
+        if parallel_files
+            && stream_report
+            && (format != ReportFormat::Csv
+                || order != OutputOrder::default()
+                || max_rows.is_some()
+                || clients.is_some()
+                || output_dir.is_some()
+                || report.is_some()
+                || accrue.is_some())
+        {
+            return Err(PaymentError::InvalidCliArgument(
+                "--stream-report can't be used with --format, --order, --max-rows, --clients, \
+                 --output-dir, --report or --accrue"
+                    .to_owned(),
+            ));
+        }
+
+        // the files are processed by the library, which knows nothing of what the run does to
+        // each row
+        if parallel_files
+            && (follow
+                || two_pass
+                || as_of_tx.is_some()
+                || quarantine.is_some()
+                || rejections_report.is_some()
+                || filter_input
+                || !tx_offsets.is_empty()
+                || check_tx_order
+                || strict_ordering
+                || input_order != InputOrder::File
+                || input_format != InputFormat::Csv
+                || amount_unit != AmountUnit::Major
+                || input_limits != InputLimits::default()
+                || max_transactions_in_memory.is_some()
+                || initial_state.is_some()
+                || journal.is_some())
+        {
+            return Err(PaymentError::InvalidCliArgument(
+                "--parallel-files can't be used with --follow, --two-pass, --as-of-tx, \
+                 --quarantine, --rejections-report, --filter-input, --tx-offset, \
+                 --check-tx-order, --strict-ordering, --order-by, --input-format, \
+                 --amount-unit, --max-field-bytes, --max-record-bytes, --max-records, \
+                 --max-transactions-in-memory, --initial-state or --journal"
+                    .to_owned(),
+            ));
+        }
+
+        Ok(CliOptions {
+            file_path: file_path.ok_or_else(|| {
+                PaymentError::InvalidCliArgument("CSV filename missing in cli argument".to_owned())
+            })?,
+            extra_files,
+            parallel_files,
+            stream_report,
+            sort_within_shard,
+            tag_shard,
+            max_transactions_in_memory,
+            stats_json,
+            checksum,
+            progress,
+            errors,
+            fail_on_reject,
+            fail_on_empty,
+            strict_engine,
+            lenient,
+            quarantine,
+            rejections_report,
+            clients,
+            filter_input,
+            output_dir,
+            force,
+            report,
+            follow,
+            refresh_interval: refresh_interval.unwrap_or(DEFAULT_REFRESH_INTERVAL),
+            refresh_rows,
+            as_of: as_of_tx.map(|tx| AsOfTx {
+                tx,
+                inclusive: !as_of_exclusive,
+            }),
+            format,
+            extended_output,
+            disputes_report,
+            locked_report,
+            merchant_report,
+            emit_deposit_index,
+            export_transactions,
+            debtors_report,
+            losses_report,
+            accruals_report,
+            dump_clients_json,
+            initial_state,
+            dry_run,
+            journal,
+            journal_sync_every: journal_sync_every.unwrap_or(DEFAULT_SYNC_EVERY),
+            max_rows,
+            order,
+            output,
+            locked_deposits,
+            blocklist,
+            client_creation,
+            allowlist,
+            max_client_balance,
+            chargeback_fee,
+            accrue,
+            dispute_shortfall,
+            clamp_inconsistent_held,
+            dispute_amounts,
+            dispute_support,
+            min_amount,
+            dust,
+            max_transaction_amount,
+            max_panics,
+            duplicate_deposits: duplicate_window.map(|window_secs| DuplicateDetection {
+                window_secs,
+                action: if reject_duplicates {
+                    DuplicateAction::Reject
+                } else {
+                    DuplicateAction::Flag
+                },
+            }),
+            duplicates_report,
+            hold_ttl_secs,
+            max_memo_len: max_memo_len.unwrap_or(DEFAULT_MAX_MEMO_LEN),
+            max_idempotency_keys: max_idempotency_keys.unwrap_or(DEFAULT_MAX_IDEMPOTENCY_KEYS),
+            max_clients,
+            max_transactions,
+            max_open_disputes_per_client,
+            max_open_disputes,
+            tx_offsets,
+            input_format,
+            amount_unit,
+            input_order,
+            sort_chunk_rows: sort_chunk_rows.unwrap_or(DEFAULT_SORT_CHUNK_ROWS),
+            chunk_size: chunk_size.unwrap_or(1),
+            input_limits,
+            // aborting on out of order tx ids implies checking them
+            check_tx_order: check_tx_order || strict_ordering,
+            strict_ordering,
+            tx_order_report,
+            two_pass,
+            findings_report,
+        })
+    }
+
+    /// Returns how the account states report should be written.
+    pub fn report_options(&self) -> ReportOptions<'_> {
+        ReportOptions {
+            format: self.format,
+            order: self.order,
+            output: self.output,
+            extended: self.extended_output,
+            clients: self.clients.as_ref(),
+            max_rows: self.max_rows,
+        }
+    }
+
+    /// Returns how `--stream-report` renders the rows of each file's clients.
+    pub fn stream_options(&self) -> StreamOptions {
+        StreamOptions {
+            sort_within_shard: self.sort_within_shard,
+            tag_shard: self.tag_shard,
+            extended: self.extended_output,
+            output: self.output,
+        }
+    }
+
+    /// Returns the offset added to the tx ids of the input file at `index`, counted from 0 in
+    /// the order the files are given, or `None` without `--tx-offset`.
+    pub fn tx_offset(&self, index: usize) -> Option<u32> {
+        match self.tx_offsets[..] {
+            [] => None,
+            [offset] => Some(offset),
+            ref offsets => offsets.get(index).copied(),
+        }
+    }
+}
+
+/// Parses the tx id offsets of `--tx-offset`, one or several separated by commas.
+fn offsets(flag: &str, value: String) -> Result<Vec<u32>, PaymentError> {
+    value
+        .split(',')
+        .map(|offset| {
+            offset.trim().parse::<u32>().map_err(|_| {
+                PaymentError::InvalidCliArgument(format!(
+                    "{} expects offsets from 0 to {}, got '{}'",
+                    flag,
+                    u32::MAX,
+                    value
+                ))
+            })
+        })
+        .collect()
+}
+
+fn positive_integer(flag: &str, value: String) -> Result<u64, PaymentError> {
+    value.parse::<u64>().ok().filter(|n| *n > 0).ok_or_else(|| {
+        PaymentError::InvalidCliArgument(format!(
+            "{} expects a positive integer, got '{}'",
+            flag, value
+        ))
+    })
+}
+
+fn amount(flag: &str, value: String) -> Result<f64, PaymentError> {
+    value.parse::<f64>().ok().filter(|amount| *amount >= 0.0).ok_or_else(|| {
+        PaymentError::InvalidCliArgument(format!(
+            "{} expects a non-negative amount, got '{}'",
+            flag, value
+        ))
+    })
+}
+
+fn rate(flag: &str, value: String) -> Result<f64, PaymentError> {
+    value.parse::<f64>().ok().filter(|rate| rate.is_finite() && *rate >= -1.0).ok_or_else(|| {
+        PaymentError::InvalidCliArgument(format!("{} expects a rate of at least -1, got '{}'", flag, value))
+    })
+}
+
+fn single_char(flag: &str, value: String) -> Result<char, PaymentError> {
+    let mut chars = value.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c),
+        _ => Err(PaymentError::InvalidCliArgument(format!(
+            "{} expects a single character, got '{}'",
+            flag, value
+        ))),
+    }
+}
+
+fn flag_value(flag: &str, value: Option<String>) -> Result<String, PaymentError> {
+    value.ok_or_else(|| PaymentError::InvalidCliArgument(format!("{} expects a value", flag)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::{
+        CliOptions, Command, DiffOptions, InspectOptions, InvertOptions, SimulateOptions, StatementsOptions,
+        ValidateOptions, VerifyOptions,
+    };
+    use payment_engine::{
+        config::{
+        ClientCreationPolicy, DisputeAmountPolicy, DisputeShortfallPolicy, DisputeSupport, DuplicateAction,
+        DuplicateDetection, DustPolicy, DEFAULT_MAX_IDEMPOTENCY_KEYS, DEFAULT_MAX_MEMO_LEN,
+    },
+    diagnostics::ErrorFormat,
+    external_sort::{InputOrder, DEFAULT_SORT_CHUNK_ROWS},
+        filter::ClientFilter,
+        inspect::InspectQuery,
+        journal::DEFAULT_SYNC_EVERY,
+        parser::{AmountUnit, InputFormat, InputLimits},
+        report::{OutputOptions, OutputOrder, ReportFormat, Rounding},
+        simulate::SimulationConfig,
+        statements::UtcOffset,
+        types::{AsOfTx, LockedDepositPolicy},
+    };
+    use std::time::Duration;
+
+    fn args(list: &[&str]) -> impl Iterator<Item = String> {
+        list.iter()
+            .map(|arg| arg.to_string())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn can_parse_file_path_only() {
+        let options = CliOptions::parse(args(&["transactions.csv"])).unwrap();
+        assert_eq!(options.file_path, "transactions.csv");
+        assert!(options.extra_files.is_empty());
+        assert_eq!(options.max_transactions_in_memory, None);
+        assert_eq!(options.stats_json, None);
+        assert!(!options.checksum);
+        assert!(!options.progress);
+        assert_eq!(options.errors, ErrorFormat::Human);
+        assert!(!options.fail_on_reject);
+        assert!(!options.fail_on_empty);
+        assert!(!options.strict_engine);
+        assert!(!options.lenient);
+        assert_eq!(options.quarantine, None);
+        assert_eq!(options.rejections_report, None);
+        assert_eq!(options.clients, None);
+        assert!(!options.filter_input);
+        assert_eq!(options.output_dir, None);
+        assert!(!options.force);
+        assert_eq!(options.report, None);
+        assert!(!options.follow);
+        assert_eq!(options.refresh_interval, Duration::from_secs(5));
+        assert_eq!(options.refresh_rows, None);
+        assert_eq!(options.as_of, None);
+        assert_eq!(options.format, ReportFormat::Csv);
+        assert!(!options.extended_output);
+        assert_eq!(options.disputes_report, None);
+        assert_eq!(options.locked_report, None);
+        assert_eq!(options.merchant_report, None);
+        assert_eq!(options.emit_deposit_index, None);
+        assert_eq!(options.export_transactions, None);
+        assert_eq!(options.debtors_report, None);
+        assert_eq!(options.losses_report, None);
+        assert_eq!(options.accruals_report, None);
+        assert_eq!(options.dump_clients_json, None);
+        assert_eq!(options.initial_state, None);
+        assert!(!options.dry_run);
+        assert_eq!(options.journal, None);
+        assert_eq!(options.journal_sync_every, DEFAULT_SYNC_EVERY);
+        assert_eq!(options.max_rows, None);
+        assert_eq!(options.order, OutputOrder::ClientId);
+        assert_eq!(options.output, OutputOptions::default());
+        assert_eq!(options.locked_deposits, LockedDepositPolicy::Reject);
+        assert_eq!(options.blocklist, None);
+        assert_eq!(options.client_creation, ClientCreationPolicy::Auto);
+        assert_eq!(options.allowlist, None);
+        assert_eq!(options.max_client_balance, None);
+        assert_eq!(options.chargeback_fee, None);
+        assert_eq!(options.accrue, None);
+        assert_eq!(options.dispute_shortfall, DisputeShortfallPolicy::Allow);
+        assert!(!options.clamp_inconsistent_held);
+        assert_eq!(options.dispute_amounts, DisputeAmountPolicy::Ignore);
+        assert_eq!(options.dispute_support, DisputeSupport::Full);
+        assert_eq!(options.min_amount, None);
+        assert_eq!(options.max_transaction_amount, None);
+        assert_eq!(options.max_panics, None);
+        assert_eq!(options.dust, DustPolicy::Reject);
+        assert_eq!(options.duplicate_deposits, None);
+        assert_eq!(options.hold_ttl_secs, None);
+        assert_eq!(options.duplicates_report, None);
+        assert_eq!(options.max_memo_len, DEFAULT_MAX_MEMO_LEN);
+        assert_eq!(options.max_idempotency_keys, DEFAULT_MAX_IDEMPOTENCY_KEYS);
+        assert_eq!(options.max_clients, None);
+        assert_eq!(options.max_transactions, None);
+        assert_eq!(options.max_open_disputes_per_client, None);
+        assert_eq!(options.max_open_disputes, None);
+        assert!(options.tx_offsets.is_empty());
+        assert_eq!(options.input_format, InputFormat::Csv);
+        assert_eq!(options.amount_unit, AmountUnit::Major);
+        assert_eq!(options.input_order, InputOrder::File);
+        assert_eq!(options.sort_chunk_rows, DEFAULT_SORT_CHUNK_ROWS);
+        assert_eq!(options.chunk_size, 1);
+        assert_eq!(options.input_limits, InputLimits::default());
+        assert!(!options.check_tx_order);
+        assert!(!options.strict_ordering);
+        assert_eq!(options.tx_order_report, None);
+        assert!(!options.two_pass);
+        assert_eq!(options.findings_report, None);
+        assert!(!options.parallel_files);
+    }
+
+    #[test]
+    fn can_parse_options() {
+        let options = CliOptions::parse(args(&[
+            "transactions.csv",
+            "--max-transactions-in-memory",
+            "1000",
+            "--stats-json",
+            "stats.json",
+            "--checksum",
+            "--progress",
+            "--errors",
+            "json",
+            "--fail-on-reject",
+            "--fail-on-empty",
+            "--strict-engine",
+            "--lenient",
+            "--quarantine",
+            "poison.csv",
+            "--rejections-report",
+            "rejections.jsonl",
+            "--clients",
+            "1,3-4",
+            "--filter-input",
+            "--output-dir",
+            "out",
+            "--force",
+            "--format",
+            "json",
+            "--extended-output",
+            "--disputes-report",
+            "disputes.csv",
+            "--locked-report",
+            "locked.csv",
+            "--merchant-report",
+            "merchants.csv",
+            "--emit-deposit-index",
+            "deposits.idx",
+            "--export-transactions",
+            "transactions.csv",
+            "--debtors-report",
+            "debtors.csv",
+            "--losses-report",
+            "losses.csv",
+            "--accruals-report",
+            "accruals.csv",
+            "--dump-clients-json",
+            "clients.json",
+            "--initial-state",
+            "state.json",
+            "--dry-run",
+            "--blocklist",
+            "blocked.txt",
+            "--client-creation",
+            "initial-state-only",
+            "--allowlist",
+            "onboarded.txt",
+            "--max-client-balance",
+            "10000",
+            "--chargeback-fee",
+            "15",
+            "--accrue",
+            "0.01",
+            "--dispute-shortfall",
+            "freeze",
+            "--clamp-inconsistent-held",
+            "--dispute-amounts",
+            "verify",
+            "--dispute-support",
+            "none",
+            "--min-amount",
+            "0.01",
+            "--max-transaction-amount",
+            "1000000",
+            "--dust",
+            "apply-but-dont-store",
+            "--max-panics",
+            "5",
+            "--max-memo-len",
+            "64",
+            "--max-idempotency-keys",
+            "500",
+            "--max-clients",
+            "100",
+            "--max-transactions",
+            "5000000",
+            "--max-open-disputes-per-client",
+            "3",
+            "--max-open-disputes",
+            "10000",
+            "--tx-offset",
+            "1000000",
+            "--input-format",
+            "csv",
+            "--amount-unit",
+            "minor:4",
+            "--order-by",
+            "timestamp",
+            "--sort-chunk-rows",
+            "5000",
+            "--chunk-size",
+            "1024",
+            "--max-field-bytes",
+            "256",
+            "--max-record-bytes",
+            "1024",
+            "--max-records",
+            "1000000",
+            "--check-tx-order",
+            "--tx-order-report",
+            "order.csv",
+            "--two-pass",
+            "--findings-report",
+            "findings.csv",
+        ]))
+        .unwrap();
+        assert_eq!(options.max_transactions_in_memory, Some(1000));
+        assert_eq!(options.stats_json.as_deref(), Some("stats.json"));
+        assert!(options.checksum);
+        assert!(options.progress);
+        assert_eq!(options.errors, ErrorFormat::Json);
+        assert!(options.fail_on_reject);
+        assert!(options.fail_on_empty);
+        assert!(options.strict_engine);
+        assert!(options.lenient);
+        assert_eq!(options.quarantine.as_deref(), Some("poison.csv"));
+        assert_eq!(options.rejections_report.as_deref(), Some("rejections.jsonl"));
+        assert_eq!(options.clients, Some(ClientFilter::parse("1,3-4").unwrap()));
+        assert!(options.filter_input);
+        assert_eq!(options.output_dir.as_deref(), Some("out"));
+        assert!(options.force);
+        assert_eq!(options.format, ReportFormat::Json);
+        assert!(options.extended_output);
+        assert_eq!(options.disputes_report.as_deref(), Some("disputes.csv"));
+        assert_eq!(options.locked_report.as_deref(), Some("locked.csv"));
+        assert_eq!(options.merchant_report.as_deref(), Some("merchants.csv"));
+        assert_eq!(options.emit_deposit_index.as_deref(), Some("deposits.idx"));
+        assert_eq!(options.export_transactions.as_deref(), Some("transactions.csv"));
+        assert_eq!(options.debtors_report.as_deref(), Some("debtors.csv"));
+        assert_eq!(options.losses_report.as_deref(), Some("losses.csv"));
+        assert_eq!(options.accruals_report.as_deref(), Some("accruals.csv"));
+        assert_eq!(options.dump_clients_json.as_deref(), Some("clients.json"));
+        assert_eq!(options.initial_state.as_deref(), Some("state.json"));
+        assert!(options.dry_run);
+        assert_eq!(options.blocklist.as_deref(), Some("blocked.txt"));
+        assert_eq!(options.client_creation, ClientCreationPolicy::FromInitialStateOnly);
+        assert_eq!(options.allowlist.as_deref(), Some("onboarded.txt"));
+        assert_eq!(options.max_client_balance, Some(10000.0));
+        assert_eq!(options.chargeback_fee, Some(15.0));
+        assert_eq!(options.accrue, Some(0.01));
+        assert_eq!(options.dispute_shortfall, DisputeShortfallPolicy::Freeze);
+        assert!(options.clamp_inconsistent_held);
+        assert_eq!(options.dispute_amounts, DisputeAmountPolicy::Verify);
+        assert_eq!(options.dispute_support, DisputeSupport::None);
+        assert_eq!(options.min_amount, Some(0.01));
+        assert_eq!(options.max_transaction_amount, Some(1_000_000.0));
+        assert_eq!(options.dust, DustPolicy::ApplyButDontStore);
+        assert_eq!(options.max_panics, Some(5));
+        assert_eq!(options.max_memo_len, 64);
+        assert_eq!(options.max_idempotency_keys, 500);
+        assert_eq!(options.max_clients, Some(100));
+        assert_eq!(options.max_transactions, Some(5000000));
+        assert_eq!(options.max_open_disputes_per_client, Some(3));
+        assert_eq!(options.max_open_disputes, Some(10000));
+        assert_eq!(options.tx_offsets, [1_000_000]);
+        assert_eq!(options.input_format, InputFormat::Csv);
+        assert_eq!(options.amount_unit, AmountUnit::MinorUnits(4));
+        assert_eq!(options.input_order, InputOrder::Timestamp);
+        assert_eq!(options.sort_chunk_rows, 5000);
+        assert_eq!(options.chunk_size, 1024);
+        assert_eq!(
+            options.input_limits,
+            InputLimits {
+                max_field_bytes: 256,
+                max_record_bytes: 1024,
+                max_records: 1_000_000,
+            }
+        );
+        assert!(options.check_tx_order);
+        assert!(!options.strict_ordering);
+        assert_eq!(options.tx_order_report.as_deref(), Some("order.csv"));
+        assert!(options.two_pass);
+        assert_eq!(options.findings_report.as_deref(), Some("findings.csv"));
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        assert!(CliOptions::parse(args(&[])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--max-transactions-in-memory"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--max-transactions-in-memory", "0"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--unknown"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--stats-json"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--rejections-report"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--emit-deposit-index"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--accrue", "-1.5"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--accrue", "1%"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--clients", "x"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--filter-input"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--force"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--follow"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--refresh-rows", "10"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--report", "r.csv", "--output-dir", "out"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--as-of-tx", "-1"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--as-of-exclusive"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--format", "xml"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--order", "random"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--order-by", "amount"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--input-format", "xml"])).is_err());
+        // protobuf inputs are only read by builds with the proto feature
+        let proto = CliOptions::parse(args(&["a.bin", "--input-format", "proto"]));
+        assert_eq!(proto.map(|options| options.input_format).ok(), cfg!(feature = "proto").then_some(InputFormat::Proto));
+        assert!(CliOptions::parse(args(&["a.bin", "--input-format", "proto", "--order-by", "timestamp"])).is_err());
+        let msgpack = CliOptions::parse(args(&["a.bin", "--input-format", "msgpack", "--format", "msgpack"]));
+        let formats = msgpack.map(|options| (options.input_format, options.format));
+        assert_eq!(formats.ok(), cfg!(feature = "msgpack").then_some((InputFormat::Msgpack, ReportFormat::Msgpack)));
+        assert!(CliOptions::parse(args(&["a.bin", "--input-format", "msgpack", "--follow"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--amount-unit", "cents"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--amount-unit", "minor:19"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--amount-unit", "minor:2", "--follow", "--report", "r.csv"])).is_err());
+        let options =
+            CliOptions::parse(args(&["a.csv", "--amount-unit", "major", "--follow", "--report", "r.csv"])).unwrap();
+        assert_eq!(options.amount_unit, AmountUnit::Major);
+        let options = CliOptions::parse(args(&["a.csv", "b.csv", "c.csv"])).unwrap();
+        assert_eq!(options.extra_files, ["b.csv", "c.csv"]);
+        let options = CliOptions::parse(args(&["--parallel-files", "a.csv", "b.csv"])).unwrap();
+        assert!(options.parallel_files);
+        assert_eq!((options.file_path.as_str(), &options.extra_files[..]), ("a.csv", &["b.csv".to_owned()][..]));
+        assert!(CliOptions::parse(args(&["--parallel-files", "a.csv", "b.csv", "--two-pass"])).is_err());
+        assert!(CliOptions::parse(args(&["--parallel-files", "a.csv", "-"])).is_err());
+        assert!(CliOptions::parse(args(&["-", "a.csv", "-"])).is_err());
+        assert!(CliOptions::parse(args(&["-", "--follow"])).is_err());
+        assert!(CliOptions::parse(args(&["--parallel-files", "a.csv", "--quarantine", "q.csv"])).is_err());
+        let options =
+            CliOptions::parse(args(&["--parallel-files", "a.csv", "--stream-report", "--tag-shard"])).unwrap();
+        assert!(options.stream_report && options.tag_shard && !options.sort_within_shard);
+        assert!(options.stream_options().tag_shard);
+        // a single-threaded run ignores it
+        assert!(CliOptions::parse(args(&["a.csv", "--stream-report", "--format", "json"])).is_ok());
+        let json = ["--parallel-files", "a.csv", "--stream-report", "--format", "json"];
+        assert!(CliOptions::parse(args(&json)).is_err());
+        assert!(CliOptions::parse(args(&["--parallel-files", "a.csv", "--sort-within-shard"])).is_err());
+        assert!(CliOptions::parse(args(&["--parallel-files", "a.csv", "--max-records", "10"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--max-field-bytes", "0"])).is_err());
+        let options =
+            CliOptions::parse(args(&["a.csv", "--journal", "applied.journal", "--journal-sync-every", "500"])).unwrap();
+        assert_eq!((options.journal.as_deref(), options.journal_sync_every), (Some("applied.journal"), 500));
+        assert!(CliOptions::parse(args(&["a.csv", "--journal-sync-every", "10"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--journal", "j", "--dry-run"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--journal", "j", "--max-idempotency-keys", "10"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--max-idempotency-keys", "0"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--journal", "j", "--journal-sync-every", "0"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "b.csv", "--follow", "--report", "r.csv"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "b.csv", "--order-by", "timestamp"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--sort-chunk-rows", "10"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--chunk-size", "0"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--tx-order-report", "o.csv"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--findings-report", "f.csv"])).is_err());
+        let options = CliOptions::parse(args(&["a.csv", "--strict-ordering"])).unwrap();
+        assert!(options.check_tx_order && options.strict_ordering);
+        assert!(CliOptions::parse(args(&["a.csv", "--precision", "9"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--rounding", "up"])).is_err());
+        let options = CliOptions::parse(args(&["a.csv", "--precision", "0", "--rounding", "truncate"])).unwrap();
+        assert_eq!((options.output.precision, options.output.rounding), (0, Rounding::Truncate));
+        assert!(CliOptions::parse(args(&["a.csv", "--decimal-separator", ","])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--delimiter", ";;"])).is_err());
+        let options =
+            CliOptions::parse(args(&["a.csv", "--decimal-separator", ",", "--delimiter", ";"])).unwrap();
+        assert_eq!((options.output.decimal_separator, options.output.delimiter), (',', ';'));
+        assert!(CliOptions::parse(args(&["a.csv", "--reject-duplicates"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--duplicates-report", "d.csv"])).is_err());
+        let options =
+            CliOptions::parse(args(&["a.csv", "--duplicate-window", "5", "--reject-duplicates"])).unwrap();
+        assert_eq!(
+            options.duplicate_deposits,
+            Some(DuplicateDetection {
+                window_secs: 5,
+                action: DuplicateAction::Reject
+            })
+        );
+        assert!(CliOptions::parse(args(&["a.csv", "--hold-ttl", "0"])).is_err());
+        assert_eq!(CliOptions::parse(args(&["a.csv", "--hold-ttl", "86400"])).unwrap().hold_ttl_secs, Some(86400));
+        assert!(CliOptions::parse(args(&["a.csv", "--locked-deposits", "keep"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--dispute-shortfall", "partial"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--dispute-support", "off"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--dust", "store"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--client-creation", "never"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--allowlist", "onboarded.txt"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--min-amount", "-1"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--max-transaction-amount", "lots"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--max-panics", "0"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--dispute-amounts", "check"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--errors", "yaml"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--max-clients", "0"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--max-transactions", "many"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--max-open-disputes-per-client", "0"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--max-open-disputes"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "--tx-offset", "4294967296"])).is_err());
+        // one offset per file
+        let options = CliOptions::parse(args(&["a.csv", "b.csv", "--tx-offset", "0,1000"])).unwrap();
+        assert_eq!((options.tx_offset(0), options.tx_offset(1)), (Some(0), Some(1000)));
+        let options = CliOptions::parse(args(&["a.csv", "b.csv", "--tx-offset", "1000"])).unwrap();
+        assert_eq!((options.tx_offset(0), options.tx_offset(1)), (Some(1000), Some(1000)));
+        assert!(CliOptions::parse(args(&["a.csv", "b.csv", "--tx-offset", "0,1,2"])).is_err());
+        assert!(CliOptions::parse(args(&["a.csv", "b.csv", "--tx-offset", "0,"])).is_err());
+        assert_eq!(
+            CliOptions::parse(args(&["a.csv", "--locked-deposits", "hold"]))
+                .unwrap()
+                .locked_deposits,
+            LockedDepositPolicy::Hold
+        );
+        assert!(CliOptions::parse(args(&["a.csv", "--max-rows", "10"])).is_err());
+        assert_eq!(
+            CliOptions::parse(args(&["a.csv", "--format", "table", "--max-rows", "10"]))
+                .unwrap()
+                .max_rows,
+            Some(10)
+        );
+    }
+
+    #[test]
+    fn can_parse_as_of_options() {
+        let options = CliOptions::parse(args(&["a.csv", "--as-of-tx", "123456"])).unwrap();
+        assert_eq!(options.as_of, Some(AsOfTx { tx: 123456, inclusive: true }));
+
+        let command = Command::parse(args(&["process", "a.csv", "--as-of-tx", "7", "--as-of-exclusive"]));
+        match command.unwrap() {
+            Command::Process(options) => {
+                assert_eq!(options.file_path, "a.csv");
+                assert_eq!(options.as_of, Some(AsOfTx { tx: 7, inclusive: false }));
+            }
+            other => panic!("unexpected command {:?}", other),
+        }
+    }
+
+    #[test]
+    fn can_parse_follow_options() {
+        let options = CliOptions::parse(args(&[
+            "transactions.csv",
+            "--follow",
+            "--report",
+            "accounts.csv",
+            "--refresh-secs",
+            "30",
+            "--refresh-rows",
+            "1000",
+        ]))
+        .unwrap();
+        assert!(options.follow);
+        assert_eq!(options.report.as_deref(), Some("accounts.csv"));
+        assert_eq!(options.refresh_interval, Duration::from_secs(30));
+        assert_eq!(options.refresh_rows, Some(1000));
+        assert!(CliOptions::parse(args(&["a.csv", "--follow", "--report", "r.csv", "--refresh-secs", "0"])).is_err());
+    }
+
+    #[test]
+    fn can_parse_diff_subcommand() {
+        assert_eq!(
+            Command::parse(args(&["diff", "a.csv", "b.csv", "--epsilon", "0.0001"])).unwrap(),
+            Command::Diff(DiffOptions {
+                before: "a.csv".to_owned(),
+                after: "b.csv".to_owned(),
+                epsilon: 0.0001,
+            })
+        );
+        assert!(matches!(
+            Command::parse(args(&["transactions.csv"])).unwrap(),
+            Command::Process(_)
+        ));
+        assert!(Command::parse(args(&["diff", "a.csv"])).is_err());
+        assert!(Command::parse(args(&["diff", "a.csv", "b.csv", "--epsilon", "-1"])).is_err());
+    }
+
+    #[test]
+    fn can_parse_verify_subcommand() {
+        assert_eq!(
+            Command::parse(args(&["verify", "tx.csv", "--against", "accounts.csv"])).unwrap(),
+            Command::Verify(VerifyOptions {
+                transactions: "tx.csv".to_owned(),
+                against: "accounts.csv".to_owned(),
+            })
+        );
+        assert!(Command::parse(args(&["verify", "tx.csv"])).is_err());
+        assert!(Command::parse(args(&["verify", "--against", "accounts.csv"])).is_err());
+        assert_eq!(
+            Command::parse(args(&["reconcile", "tx.csv", "--against", "accounts.csv"])).unwrap(),
+            Command::Reconcile(VerifyOptions {
+                transactions: "tx.csv".to_owned(),
+                against: "accounts.csv".to_owned(),
+            })
+        );
+        let err = Command::parse(args(&["reconcile", "tx.csv"])).unwrap_err();
+        assert!(err.to_string().contains("reconcile expects"), "{}", err);
+        assert_eq!(Command::parse(args(&["repl"])).unwrap(), Command::Repl);
+        assert!(Command::parse(args(&["repl", "x"])).is_err());
+    }
+
+    #[test]
+    fn can_parse_invert_subcommand() {
+        assert_eq!(
+            Command::parse(args(&["invert", "batch.csv", "--initial-state", "clients.json"])).unwrap(),
+            Command::Invert(InvertOptions {
+                batch: "batch.csv".to_owned(),
+                initial_state: Some("clients.json".to_owned()),
+                report: None,
+            })
+        );
+        assert_eq!(
+            Command::parse(args(&["invert", "batch.csv", "--report", "left.csv"])).unwrap(),
+            Command::Invert(InvertOptions {
+                batch: "batch.csv".to_owned(),
+                initial_state: None,
+                report: Some("left.csv".to_owned()),
+            })
+        );
+        assert!(Command::parse(args(&["invert"])).is_err());
+        assert!(Command::parse(args(&["invert", "a.csv", "b.csv"])).is_err());
+    }
+
+    #[test]
+    fn can_parse_inspect_subcommand() {
+        assert_eq!(
+            Command::parse(args(&["inspect", "clients.json"])).unwrap(),
+            Command::Inspect(InspectOptions {
+                dump: "clients.json".to_owned(),
+                query: InspectQuery::Accounts(None),
+                format: ReportFormat::Csv,
+            })
+        );
+        assert_eq!(
+            Command::parse(args(&["inspect", "clients.json", "--client", "42", "--format", "json"])).unwrap(),
+            Command::Inspect(InspectOptions {
+                dump: "clients.json".to_owned(),
+                query: InspectQuery::Accounts(Some(ClientFilter::parse("42").unwrap())),
+                format: ReportFormat::Json,
+            })
+        );
+        for (flag, query) in [
+            ("--summary", InspectQuery::Summary),
+            ("--open-disputes", InspectQuery::OpenDisputes),
+            ("--locked", InspectQuery::Locked),
+        ] {
+            let command = Command::parse(args(&["inspect", flag, "clients.json"])).unwrap();
+            assert!(matches!(command, Command::Inspect(options) if options.query == query));
+        }
+        assert!(Command::parse(args(&["inspect"])).is_err());
+        assert!(Command::parse(args(&["inspect", "clients.json", "--locked", "--summary"])).is_err());
+        assert!(Command::parse(args(&["inspect", "clients.json", "--client", "x"])).is_err());
+    }
+
+    #[test]
+    fn can_parse_validate_subcommand() {
+        assert_eq!(
+            Command::parse(args(&["validate", "disputes.csv", "--deposit-index", "deposits.idx"])).unwrap(),
+            Command::Validate(ValidateOptions {
+                disputes: "disputes.csv".to_owned(),
+                deposit_index: "deposits.idx".to_owned(),
+            })
+        );
+        assert!(Command::parse(args(&["validate", "disputes.csv"])).is_err());
+        assert!(Command::parse(args(&["validate", "--deposit-index", "deposits.idx"])).is_err());
+        assert!(Command::parse(args(&["validate", "a.csv", "b.csv", "--deposit-index", "deposits.idx"])).is_err());
+    }
+
+    #[test]
+    fn can_parse_simulate_subcommand() {
+        assert_eq!(
+            Command::parse(args(&["simulate", "--seed", "42", "--rows", "10000", "--clients", "500"])).unwrap(),
+            Command::Simulate(SimulateOptions {
+                config: SimulationConfig {
+                    seed: 42,
+                    rows: 10_000,
+                    clients: 500,
+                    ..Default::default()
+                },
+                repro: None,
+            })
+        );
+        assert_eq!(
+            Command::parse(args(&["simulate", "--check-every", "100", "--repro", "f.json"])).unwrap(),
+            Command::Simulate(SimulateOptions {
+                config: SimulationConfig {
+                    check_every: 100,
+                    ..Default::default()
+                },
+                repro: Some("f.json".to_owned()),
+            })
+        );
+        assert!(Command::parse(args(&["simulate", "--rows", "0"])).is_err());
+        assert!(Command::parse(args(&["simulate", "--seed", "-1"])).is_err());
+        assert!(Command::parse(args(&["simulate", "--clients", "4294967296"])).is_err());
+        assert!(Command::parse(args(&["simulate", "a.csv"])).is_err());
+    }
+
+    #[test]
+    fn can_parse_statements_subcommand() {
+        assert_eq!(
+            Command::parse(args(&["statements", "a.csv", "--client", "7", "--utc-offset", "-05:00"])).unwrap(),
+            Command::Statements(StatementsOptions {
+                transactions: "a.csv".to_owned(),
+                clients: Some(ClientFilter::parse("7").unwrap()),
+                utc_offset: UtcOffset::parse("-05:00").unwrap(),
+            })
+        );
+        assert_eq!(
+            Command::parse(args(&["statements", "--all", "a.csv"])).unwrap(),
+            Command::Statements(StatementsOptions {
+                transactions: "a.csv".to_owned(),
+                clients: None,
+                utc_offset: UtcOffset::UTC,
+            })
+        );
+        assert!(Command::parse(args(&["statements", "a.csv"])).is_err());
+        assert!(Command::parse(args(&["statements", "a.csv", "--client", "1", "--all"])).is_err());
+        assert!(Command::parse(args(&["statements", "a.csv", "--all", "--utc-offset", "2"])).is_err());
+    }
+}
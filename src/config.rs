@@ -0,0 +1,439 @@
+use crate::{
+    errors::PaymentError,
+    report::Rounding,
+    types::{ClientId, LockedDepositPolicy},
+};
+use csv::{ReaderBuilder, Trim};
+use std::{
+    collections::{HashMap, HashSet},
+    io::Read,
+};
+
+/// Default of `EngineConfig::max_memo_len`.
+pub const DEFAULT_MAX_MEMO_LEN: usize = 256;
+
+/// Default of `EngineConfig::max_idempotency_keys`.
+pub const DEFAULT_MAX_IDEMPOTENCY_KEYS: usize = 100_000;
+
+/// Appended to memos cut at `EngineConfig::max_memo_len`.
+pub const MEMO_TRUNCATION_MARKER: &str = "[...]";
+
+/// Business rules of a `PaymentEngine`, set with `PaymentEngine::with_config`.
+#[derive(Debug, Clone)]
+pub struct EngineConfig {
+    /// What happens to deposits to locked accounts.
+    pub locked_deposits: LockedDepositPolicy,
+    /// Clients whose deposits and withdrawals are rejected as `IgnoreReason::Blocklisted`, with
+    /// the reason they were listed for, if any. Disputes, resolves and chargebacks of their
+    /// existing transactions still go through.
+    pub blocked_clients: HashMap<ClientId, Option<String>>,
+    /// Largest `total` a client may reach through deposits. Deposits going over it are
+    /// rejected as `IgnoreReason::BalanceCapExceeded`; disputes and resolves only move existing
+    /// money and are exempt.
+    pub max_client_balance: Option<f64>,
+    /// Per client caps overriding `max_client_balance`, `None` exempting the client.
+    pub client_balance_caps: HashMap<ClientId, Option<f64>>,
+    /// Fee debited from available and total on every chargeback, which may take the balance
+    /// below zero.
+    pub chargeback_fee: Option<f64>,
+    /// Flags or rejects deposits repeating the client's previous one, off by default.
+    pub duplicate_deposits: Option<DuplicateDetection>,
+    /// Seconds after which an open escrow hold expires, its funds going back to available as
+    /// if released, measured with the input's `timestamp` column: a hold expires once a row
+    /// stamped more than this after the hold row is processed. Holds without a timestamp never
+    /// expire. Off by default.
+    pub hold_ttl_secs: Option<u64>,
+    /// Longest memo kept with a transaction, in characters. Longer memos are cut and end with
+    /// `MEMO_TRUNCATION_MARKER`, so a file of long memos doesn't fill the transaction store.
+    pub max_memo_len: usize,
+    /// What a dispute holds when the client's available funds don't cover the disputed amount.
+    pub dispute_shortfall: DisputeShortfallPolicy,
+    /// What the amounts some upstreams fill in on dispute, resolve and chargeback rows are
+    /// used for.
+    pub dispute_amounts: DisputeAmountPolicy,
+    /// Makes `PaymentEngine::process_all` stop at the first transaction the engine ignores,
+    /// for reconciliation runs where an ignored withdrawal hides a real problem.
+    pub fail_on_ignore: bool,
+    /// Makes `PaymentEngine::process_all` stop at the first row that failed to parse, instead
+    /// of counting it and going on.
+    pub fail_fast: bool,
+    /// What `PaymentEngine::merge` does with clients that have an account on both sides.
+    pub client_merge: ClientMergePolicy,
+    /// Most client accounts the engine creates. A deposit for one more client stops processing
+    /// with `PaymentError::LimitExceeded`, so a corrupt file can't exhaust the memory.
+    pub max_clients: Option<usize>,
+    /// Most transactions the engine stores. Once the store is full, the next deposit or
+    /// withdrawal stops processing with `PaymentError::LimitExceeded`.
+    pub max_stored_transactions: Option<usize>,
+    /// Most disputes a client may have open at once. Disputes beyond it are rejected as
+    /// `IgnoreReason::TooManyOpenDisputes`; resolves and chargebacks of the open ones go through.
+    pub max_open_disputes_per_client: Option<usize>,
+    /// Most disputes open at once over all clients, rejected the same way. The engine warns
+    /// with `EngineWarning::OpenDisputesNearCap` as the count approaches it.
+    pub max_open_disputes: Option<usize>,
+    /// Whether deposits and withdrawals are stored so they can be disputed later.
+    pub dispute_support: DisputeSupport,
+    /// How amounts with more than `amount::SCALE` decimal places are rounded as they enter the
+    /// engine, and how `Amount::checked_mul` results are rounded. Balances are kept exact to
+    /// `SCALE` places.
+    pub rounding: Rounding,
+    /// Lets `PaymentEngine::apply_correction` set client balances directly. Off by default, and
+    /// the command line never turns it on.
+    pub allow_corrections: bool,
+    /// Lets a resolve or chargeback release only the client's held balance when it holds less
+    /// than the dispute, e.g. after a manual edit of the state, to recover from it. Off by
+    /// default: such resolves and chargebacks are rejected as
+    /// `IgnoreReason::HeldBalanceInconsistent`. Either way the engine warns with an
+    /// `EngineWarning::InvariantViolated`.
+    pub clamp_inconsistent_held: bool,
+    /// Smallest amount of a deposit or withdrawal not taken for dust, e.g. `0.0001` to catch
+    /// exact-zero rows. Dust is handled per `dust`, and counted by
+    /// `PaymentEngine::dust_transactions` whatever the policy.
+    pub min_amount: Option<f64>,
+    /// What happens to deposits and withdrawals below `min_amount`.
+    pub dust: DustPolicy,
+    /// Largest amount of a single deposit or withdrawal, e.g. `1000000.0` to catch feed errors
+    /// before they reach the balances. Larger ones are rejected as
+    /// `IgnoreReason::AmountAboveLimit`, and so are larger escrow holds and pending withdrawals;
+    /// disputes of transactions applied before the limit was set still go through.
+    pub max_transaction_amount: Option<f64>,
+    /// Catches a panic while processing a transaction, restores what it changed and ignores it
+    /// as `IgnoreReason::InternalError`, for up to this many transactions; the one after fails
+    /// with `PaymentError::InternalError`. Off (`None`) by default: a panic unwinds out of the
+    /// engine. Catching costs an extra store lookup per deposit and withdrawal, like the undo
+    /// history, and once any deposit named a merchant, per dispute, chargeback and
+    /// representment.
+    pub max_panics: Option<usize>,
+    /// Which clients may transact: any, their account opened by their first deposit, or only
+    /// the onboarded ones.
+    pub client_creation: ClientCreationPolicy,
+    /// Clients onboarded without an account yet, who may transact under
+    /// `ClientCreationPolicy::FromInitialStateOnly` like the clients with a loaded account.
+    pub onboarded_clients: HashSet<ClientId>,
+    /// Most idempotency keys remembered to reject repeats as
+    /// `IgnoreReason::DuplicateIdempotencyKey`, the least recently seen one being forgotten
+    /// first. Engines with a journal journal their keys instead and forget none.
+    pub max_idempotency_keys: usize,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        EngineConfig {
+            locked_deposits: LockedDepositPolicy::default(),
+            blocked_clients: HashMap::new(),
+            max_client_balance: None,
+            client_balance_caps: HashMap::new(),
+            chargeback_fee: None,
+            duplicate_deposits: None,
+            hold_ttl_secs: None,
+            max_memo_len: DEFAULT_MAX_MEMO_LEN,
+            dispute_shortfall: DisputeShortfallPolicy::default(),
+            dispute_amounts: DisputeAmountPolicy::default(),
+            fail_on_ignore: false,
+            fail_fast: false,
+            client_merge: ClientMergePolicy::default(),
+            max_clients: None,
+            max_stored_transactions: None,
+            max_open_disputes_per_client: None,
+            max_open_disputes: None,
+            dispute_support: DisputeSupport::default(),
+            rounding: Rounding::default(),
+            allow_corrections: false,
+            clamp_inconsistent_held: false,
+            min_amount: None,
+            dust: DustPolicy::default(),
+            max_transaction_amount: None,
+            max_panics: None,
+            client_creation: ClientCreationPolicy::default(),
+            onboarded_clients: HashSet::new(),
+            max_idempotency_keys: DEFAULT_MAX_IDEMPOTENCY_KEYS,
+        }
+    }
+}
+
+/// Settings of the duplicate deposit heuristic, which needs the input's `timestamp` column.
+///
+/// A deposit is suspected to be a duplicate when it has the same amount as the client's
+/// previous timestamped deposit, made at most `window_secs` earlier.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DuplicateDetection {
+    pub window_secs: u64,
+    pub action: DuplicateAction,
+}
+
+/// What happens to a suspected duplicate deposit.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DuplicateAction {
+    /// Apply it, but list it in `PaymentEngine::suspected_duplicates`.
+    #[default]
+    Flag,
+    /// Ignore it as `IgnoreReason::SuspectedDuplicate`.
+    Reject,
+}
+
+/// What a dispute does when the client's available funds are less than the disputed amount,
+/// e.g. because part of the deposit was already withdrawn. The part not covered is the shortfall.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DisputeShortfallPolicy {
+    /// Hold the whole amount anyway, taking available below zero. A chargeback then zeroes
+    /// the account, but for funds held by escrow or other disputes.
+    #[default]
+    Allow,
+    /// Hold only what is available and track the shortfall. A resolve releases what was held,
+    /// a chargeback takes it and writes the shortfall off.
+    HoldPartial,
+    /// Hold everything available and freeze the client: withdrawals are refused and deposits
+    /// go to the dispute's hold until the shortfall is collected. A chargeback turns what is
+    /// still missing into debt.
+    Freeze,
+}
+
+impl DisputeShortfallPolicy {
+    /// Parses a policy name as given on the command line (`allow`, `hold-partial` or `freeze`).
+    pub fn parse(name: &str) -> Result<Self, PaymentError> {
+        match name {
+            "allow" => Ok(DisputeShortfallPolicy::Allow),
+            "hold-partial" => Ok(DisputeShortfallPolicy::HoldPartial),
+            "freeze" => Ok(DisputeShortfallPolicy::Freeze),
+            _ => Err(PaymentError::InvalidCliArgument(format!(
+                "unknown dispute shortfall policy '{}', expected allow, hold-partial or freeze",
+                name
+            ))),
+        }
+    }
+}
+
+/// What the amount of a dispute, resolve or chargeback row is used for. Rows without an
+/// amount are processed the same under every policy.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DisputeAmountPolicy {
+    /// Disregard it: a dispute holds the whole disputed transaction.
+    #[default]
+    Ignore,
+    /// Cross-check it: a dispute's amount must equal the disputed transaction's, and a
+    /// resolve's or chargeback's what the dispute holds. Rows that disagree are rejected as
+    /// `IgnoreReason::AmountMismatch`.
+    Verify,
+    /// Dispute only that much of the transaction, more than zero and at most its amount. A
+    /// resolve or chargeback settles the whole dispute, so its amount is cross-checked as under
+    /// `Verify`.
+    UseAsPartial,
+}
+
+impl DisputeAmountPolicy {
+    /// Parses a policy name as given on the command line (`ignore`, `verify` or `partial`).
+    pub fn parse(name: &str) -> Result<Self, PaymentError> {
+        match name {
+            "ignore" => Ok(DisputeAmountPolicy::Ignore),
+            "verify" => Ok(DisputeAmountPolicy::Verify),
+            "partial" => Ok(DisputeAmountPolicy::UseAsPartial),
+            _ => Err(PaymentError::InvalidCliArgument(format!(
+                "unknown dispute amount policy '{}', expected ignore, verify or partial",
+                name
+            ))),
+        }
+    }
+}
+
+/// Whether the engine keeps what disputes need, see `EngineConfig::dispute_support`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DisputeSupport {
+    /// Store every deposit and withdrawal, so disputes, resolves and chargebacks can refer to them.
+    #[default]
+    Full,
+    /// Store nothing, for feeds known to hold only deposits and withdrawals: the engine keeps
+    /// just the client accounts. Disputes, resolves, chargebacks and representments are
+    /// rejected as `IgnoreReason::DisputesDisabled`.
+    None,
+}
+
+impl DisputeSupport {
+    /// Parses a name as given on the command line (`full` or `none`).
+    pub fn parse(name: &str) -> Result<Self, PaymentError> {
+        match name {
+            "full" => Ok(DisputeSupport::Full),
+            "none" => Ok(DisputeSupport::None),
+            _ => Err(PaymentError::InvalidCliArgument(format!(
+                "unknown dispute support '{}', expected full or none",
+                name
+            ))),
+        }
+    }
+}
+
+/// What happens to a deposit or withdrawal below `EngineConfig::min_amount`, e.g. a penny test
+/// deposit.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DustPolicy {
+    /// Ignore it as `IgnoreReason::DustAmount`.
+    #[default]
+    Reject,
+    /// Apply it without storing it, so it takes no room in the store and can't be disputed:
+    /// disputes of it are rejected as `IgnoreReason::UnknownTransaction`.
+    ApplyButDontStore,
+    /// Apply and store it like any other transaction.
+    Apply,
+}
+
+impl DustPolicy {
+    /// Parses a policy name as given on the command line (`reject`, `apply-but-dont-store` or
+    /// `apply`).
+    pub fn parse(name: &str) -> Result<Self, PaymentError> {
+        match name {
+            "reject" => Ok(DustPolicy::Reject),
+            "apply-but-dont-store" => Ok(DustPolicy::ApplyButDontStore),
+            "apply" => Ok(DustPolicy::Apply),
+            _ => Err(PaymentError::InvalidCliArgument(format!(
+                "unknown dust policy '{}', expected reject, apply-but-dont-store or apply",
+                name
+            ))),
+        }
+    }
+}
+
+/// What `PaymentEngine::merge` does with a client that has an account in both engine states.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ClientMergePolicy {
+    /// Fail the merge: states are expected to partition the clients, e.g. one per region.
+    #[default]
+    Reject,
+    /// Sum the two accounts' balances and counters, locking the account if either side is.
+    Sum,
+}
+
+/// Which clients may transact, see `EngineConfig::client_creation`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ClientCreationPolicy {
+    /// Any client: a deposit opens the account of a client seen for the first time. Other
+    /// transactions of a client without an account are rejected as `IgnoreReason::UnknownClient`.
+    #[default]
+    Auto,
+    /// Only onboarded clients, with an account loaded before the first transaction (e.g. with
+    /// `PaymentEngine::load_clients_json`) or listed in `EngineConfig::onboarded_clients`. Every
+    /// transaction of another client, deposits included, is rejected as
+    /// `IgnoreReason::UnknownClient`.
+    FromInitialStateOnly,
+}
+
+impl ClientCreationPolicy {
+    /// Parses a policy name as given on the command line (`auto` or `initial-state-only`).
+    pub fn parse(name: &str) -> Result<Self, PaymentError> {
+        match name {
+            "auto" => Ok(ClientCreationPolicy::Auto),
+            "initial-state-only" => Ok(ClientCreationPolicy::FromInitialStateOnly),
+            _ => Err(PaymentError::InvalidCliArgument(format!(
+                "unknown client creation policy '{}', expected auto or initial-state-only",
+                name
+            ))),
+        }
+    }
+}
+
+impl EngineConfig {
+    /// Returns the balance cap that applies to a client, if any.
+    pub fn balance_cap(&self, client: ClientId) -> Option<f64> {
+        match self.client_balance_caps.get(&client) {
+            Some(cap) => *cap,
+            None => self.max_client_balance,
+        }
+    }
+
+    /// Cuts a memo longer than `max_memo_len` characters, marking it as truncated.
+    pub fn cap_memo(&self, memo: &mut String) {
+        if let Some((end, _)) = memo.char_indices().nth(self.max_memo_len) {
+            memo.truncate(end);
+            memo.push_str(MEMO_TRUNCATION_MARKER);
+        }
+    }
+}
+
+/// Reads a blocklist: one client id per line, optionally followed by a reason column
+/// (`7,chargeback fraud`). A leading `client,reason` header line is skipped.
+///
+/// # Errors
+///
+/// Returns a `PaymentError::CsvParseError` naming the line of the first id that isn't a valid
+/// client id.
+pub fn read_blocklist<R: Read>(r: R) -> Result<HashMap<ClientId, Option<String>>, PaymentError> {
+    read_client_list(r, "blocklist")
+}
+
+/// Reads an allowlist of onboarded clients, in the format of a blocklist. Reasons are ignored.
+///
+/// # Errors
+///
+/// Returns a `PaymentError::CsvParseError` naming the line of the first id that isn't a valid
+/// client id.
+pub fn read_allowlist<R: Read>(r: R) -> Result<HashSet<ClientId>, PaymentError> {
+    Ok(read_client_list(r, "allowlist")?.into_keys().collect())
+}
+
+/// Reads a list of client ids with an optional reason each, `what` naming the list in errors.
+fn read_client_list<R: Read>(r: R, what: &str) -> Result<HashMap<ClientId, Option<String>>, PaymentError> {
+    let mut reader = ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(Trim::All)
+        .from_reader(r);
+
+    let mut listed = HashMap::new();
+    for (index, record) in reader.records().enumerate() {
+        let record = record.map_err(|err| PaymentError::CsvParseError(err.to_string()))?;
+        let id = record.get(0).unwrap_or_default();
+        if index == 0 && id == "client" {
+            continue;
+        }
+        let id = id.parse::<ClientId>().map_err(|_| {
+            let line = record.position().map_or(0, |pos| pos.line());
+            PaymentError::CsvParseError(format!("{} line {}: invalid client id '{}'", what, line, id))
+        })?;
+        let reason = record.get(1).filter(|reason| !reason.is_empty());
+        listed.insert(id, reason.map(str::to_owned));
+    }
+    Ok(listed)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{read_allowlist, read_blocklist, EngineConfig, MEMO_TRUNCATION_MARKER};
+
+    #[test]
+    fn can_read_blocklist() {
+        let blocked = read_blocklist("7\n42, chargeback fraud\n\n1000,\n".as_bytes()).unwrap();
+        assert_eq!(blocked.len(), 3);
+        assert_eq!(blocked[&7], None);
+        assert_eq!(blocked[&42].as_deref(), Some("chargeback fraud"));
+        assert_eq!(blocked[&1000], None);
+
+        let blocked = read_blocklist("client,reason\n3,mule account\n".as_bytes()).unwrap();
+        assert_eq!(blocked[&3].as_deref(), Some("mule account"));
+
+        let err = read_blocklist("7\nseven\n".as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("line 2"), "{}", err);
+    }
+
+    #[test]
+    fn can_read_allowlist() {
+        let onboarded = read_allowlist("client,reason\n7\n42, kyc passed\n".as_bytes()).unwrap();
+        assert_eq!(onboarded, [7, 42].into_iter().collect());
+
+        let err = read_allowlist("7\nseven\n".as_bytes()).unwrap_err();
+        assert_eq!(err.to_string(), "CSV parse error: allowlist line 2: invalid client id 'seven'");
+    }
+
+    #[test]
+    fn memos_are_cut_at_the_cap() {
+        let config = EngineConfig {
+            max_memo_len: 4,
+            ..Default::default()
+        };
+        let mut memo = "refund".to_owned();
+        config.cap_memo(&mut memo);
+        assert_eq!(memo, format!("refu{}", MEMO_TRUNCATION_MARKER));
+
+        // the cap counts characters, not bytes
+        let mut memo = "café".to_owned();
+        config.cap_memo(&mut memo);
+        assert_eq!(memo, "café");
+    }
+}
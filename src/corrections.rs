@@ -0,0 +1,26 @@
+//! Direct corrections of client balances, for the historical bugs no transaction can fix.
+
+use crate::{amount::Amount, types::ClientId};
+
+/// A correction applied by `PaymentEngine::apply_correction`, as kept in
+/// `PaymentEngine::corrections`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorrectionReceipt {
+    /// Position of the correction among the engine's corrections, starting at 1.
+    pub sequence: u64,
+    pub client: ClientId,
+    /// The balances before the correction.
+    pub old: Balances,
+    /// The balances set by the correction, `total` being recomputed from the other two.
+    pub new: Balances,
+    /// Why the balances were corrected.
+    pub reason: String,
+}
+
+/// The balances of an account, as seen by a correction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Balances {
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+}
@@ -0,0 +1,313 @@
+//! An index of the deposits of a processing run, from tx id to client and amount, saved apart
+//! from the accounts so that a later file of disputes can be checked against it without
+//! replaying last month's transactions.
+//!
+//! The file is a 4-byte magic, the format version and the number of deposits as little-endian
+//! u32s, then three columns of that many entries: the tx ids in ascending order (4 bytes each),
+//! the client of each (4 bytes, whatever the width of `ClientId`) and its amount in units of
+//! `amount::SCALE` decimal places (8 bytes).
+
+use crate::{
+    amount::Amount,
+    errors::PaymentError,
+    payment_engine::PaymentEngine,
+    report::Rounding,
+    store::TransactionStore,
+    types::{ClientId, Transaction, TransactionType},
+    validate::Finding,
+};
+use std::{collections::BTreeMap, fmt, path::Path};
+
+/// Version of the index format written by `DepositIndex::to_bytes`, the only one read back.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// First bytes of every deposit index file.
+const MAGIC: &[u8; 4] = b"PEDI";
+
+/// Bytes of the magic, version and count.
+const HEADER_BYTES: usize = 12;
+
+/// Bytes of each deposit: its tx id, client and amount.
+const ENTRY_BYTES: usize = 16;
+
+/// A deposit of the index.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IndexedDeposit {
+    pub client: ClientId,
+    pub amount: Amount,
+}
+
+/// The deposits of a run by tx id, in a sorted table searched by bisection.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DepositIndex {
+    tx_ids: Vec<u32>,
+    deposits: Vec<IndexedDeposit>,
+}
+
+impl DepositIndex {
+    /// Indexes the deposits among `transactions`. A tx id given twice keeps its last deposit.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::AmountOutOfRange` for a deposit whose amount isn't a valid
+    /// `Amount`.
+    pub fn of_deposits<'a>(transactions: impl IntoIterator<Item = &'a Transaction>) -> Result<Self, PaymentError> {
+        let mut deposits = BTreeMap::new();
+        for txn in transactions {
+            index_deposit(&mut deposits, txn)?;
+        }
+        Ok(Self::of_map(deposits))
+    }
+
+    /// Indexes the deposits an engine kept: those of its transaction store, and those it holds
+    /// as disputed or charged back. An engine without dispute support keeps none.
+    ///
+    /// # Errors
+    ///
+    /// Returns the store's error if a transaction can't be read back, or a
+    /// `PaymentError::AmountOutOfRange` as `of_deposits` does.
+    pub async fn of_engine<S: TransactionStore>(engine: &mut PaymentEngine<S>) -> Result<Self, PaymentError> {
+        let mut deposits = BTreeMap::new();
+        for txn in engine.disputed_transactions.values().chain(engine.charged_back_transactions.values()) {
+            index_deposit(&mut deposits, txn)?;
+        }
+        // read back one at a time, so a tiered store isn't brought into memory all at once
+        for tx in engine.transactions.tx_ids() {
+            if let Some(txn) = engine.transactions.get(tx).await? {
+                index_deposit(&mut deposits, &txn)?;
+            }
+        }
+        Ok(Self::of_map(deposits))
+    }
+
+    fn of_map(deposits: BTreeMap<u32, IndexedDeposit>) -> Self {
+        DepositIndex {
+            tx_ids: deposits.keys().copied().collect(),
+            deposits: deposits.into_values().collect(),
+        }
+    }
+
+    /// Returns the number of deposits indexed.
+    pub fn len(&self) -> usize {
+        self.tx_ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tx_ids.is_empty()
+    }
+
+    /// Returns the deposit with tx id `tx`, if it was indexed.
+    pub fn get(&self, tx: u32) -> Option<IndexedDeposit> {
+        self.tx_ids.binary_search(&tx).ok().map(|i| self.deposits[i])
+    }
+
+    /// Checks a row of a disputes file, found at `line`, against the index, returning what is
+    /// wrong with it, if anything: a row that doesn't parse, a row that doesn't refer to a
+    /// deposit (anything but a dispute, resolve, chargeback or representment), a reference to a
+    /// tx id the index doesn't have, or one from another client than the deposit's.
+    pub fn check(&self, row: &Result<Transaction, PaymentError>, line: impl fmt::Display) -> Option<Finding> {
+        let problem = match row {
+            Ok(txn) => self.problem(txn)?,
+            Err(err) => err.to_string(),
+        };
+        Some(Finding {
+            line: line.to_string(),
+            problem,
+        })
+    }
+
+    fn problem(&self, txn: &Transaction) -> Option<String> {
+        let name = txn.r#type.as_str();
+        match txn.r#type {
+            TransactionType::Dispute
+            | TransactionType::Resolve
+            | TransactionType::Chargeback
+            | TransactionType::Representment => match self.get(txn.tx) {
+                None => Some(format!("{} of unknown tx {}", name, txn.tx)),
+                Some(deposit) if deposit.client != txn.client => Some(format!(
+                    "{} of tx {} by client {}, deposited by client {}",
+                    name, txn.tx, txn.client, deposit.client
+                )),
+                Some(_) => None,
+            },
+            _ => Some(format!("{} tx {} doesn't refer to a deposit", name, txn.tx)),
+        }
+    }
+
+    /// Returns the index in its file format.
+    // client ids are already `u32` with wide-client-ids
+    #[allow(clippy::useless_conversion)]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_BYTES + self.len() * ENTRY_BYTES);
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(self.len() as u32).to_le_bytes());
+        bytes.extend(self.tx_ids.iter().flat_map(|tx| tx.to_le_bytes()));
+        bytes.extend(self.deposits.iter().flat_map(|deposit| u32::from(deposit.client).to_le_bytes()));
+        bytes.extend(self.deposits.iter().flat_map(|deposit| deposit.amount.units().to_le_bytes()));
+        bytes
+    }
+
+    /// Reads an index back from its file format.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::StorageError` if the bytes aren't a deposit index, are of
+    /// another format version, are cut short, or hold a client id too wide for `ClientId` or
+    /// tx ids out of order.
+    #[allow(clippy::useless_conversion)]
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, PaymentError> {
+        let invalid = |what: String| PaymentError::StorageError(format!("invalid deposit index: {}", what));
+        let word = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().expect("4 bytes"));
+        if bytes.len() < HEADER_BYTES || &bytes[..4] != MAGIC {
+            return Err(invalid("not a deposit index".to_owned()));
+        }
+        let version = word(4);
+        if version != FORMAT_VERSION {
+            return Err(invalid(format!(
+                "format version {}, this build reads version {}",
+                version, FORMAT_VERSION
+            )));
+        }
+        let count = word(8) as usize;
+        if bytes.len() != HEADER_BYTES + count * ENTRY_BYTES {
+            return Err(invalid(format!("{} bytes for {} deposits", bytes.len(), count)));
+        }
+
+        let clients_at = HEADER_BYTES + count * 4;
+        let amounts_at = clients_at + count * 4;
+        let tx_ids: Vec<u32> = (0..count).map(|i| word(HEADER_BYTES + i * 4)).collect();
+        if tx_ids.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(invalid("tx ids out of order".to_owned()));
+        }
+        let deposits = (0..count)
+            .map(|i| {
+                let client = word(clients_at + i * 4);
+                let client = ClientId::try_from(client).map_err(|_| {
+                    invalid(format!(
+                        "client id {} is out of range, the largest supported is {}",
+                        client,
+                        ClientId::MAX
+                    ))
+                })?;
+                let at = amounts_at + i * 8;
+                let units = i64::from_le_bytes(bytes[at..at + 8].try_into().expect("8 bytes"));
+                let amount = Amount::from_units(units).ok_or_else(|| invalid(format!("amount of {} units", units)))?;
+                Ok(IndexedDeposit { client, amount })
+            })
+            .collect::<Result<_, PaymentError>>()?;
+        Ok(DepositIndex { tx_ids, deposits })
+    }
+
+    /// Writes the index to `path`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::FileError` if the file can't be written.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), PaymentError> {
+        std::fs::write(&path, self.to_bytes()).map_err(|err| PaymentError::file(path.as_ref(), err))
+    }
+
+    /// Reads the index saved at `path`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::FileError` if the file can't be read, or the errors of
+    /// `from_bytes`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, PaymentError> {
+        let bytes = std::fs::read(&path).map_err(|err| PaymentError::file(path.as_ref(), err))?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+/// Adds `txn` to the deposits being indexed if it is a deposit, replacing any earlier one with
+/// its tx id.
+fn index_deposit(deposits: &mut BTreeMap<u32, IndexedDeposit>, txn: &Transaction) -> Result<(), PaymentError> {
+    if txn.r#type == TransactionType::Deposit {
+        let amount = Amount::from_f64(txn.amount.unwrap_or_default(), Rounding::HalfEven)?;
+        deposits.insert(txn.tx, IndexedDeposit { client: txn.client, amount });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        amount::Amount,
+        deposit_index::{DepositIndex, IndexedDeposit},
+        errors::PaymentError,
+        parser::{parse_records, parse_transactions},
+        payment_engine::PaymentEngine,
+    };
+
+    async fn index() -> Result<DepositIndex, PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 10.0
+        deposit, 2, 2, 2.5
+        withdrawal, 1, 3, 4.0
+        deposit, 1, 4, 3.0
+        dispute, 1, 4,
+        deposit, 3, 5, 7.25
+        dispute, 3, 5,
+        chargeback, 3, 5,";
+        let mut engine = PaymentEngine::new();
+        let str_buf = stringreader::StringReader::new(csv);
+        engine.process_all(parse_transactions(Box::new(str_buf)).await?).await.into_result()?;
+        DepositIndex::of_engine(&mut engine).await
+    }
+
+    #[tokio::test]
+    async fn indexes_the_deposits_of_a_run() -> Result<(), PaymentError> {
+        let index = index().await?;
+        assert_eq!(index.len(), 4);
+        let deposit = |client, units| Some(IndexedDeposit { client, amount: Amount::from_units(units).unwrap() });
+        assert_eq!(index.get(1), deposit(1, 100_000));
+        assert_eq!(index.get(3), None);
+        assert_eq!(index.get(4), deposit(1, 30_000));
+        assert_eq!(index.get(5), deposit(3, 72_500));
+        assert_eq!(DepositIndex::from_bytes(&index.to_bytes())?, index);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn finds_unknown_references_and_client_mismatches() -> Result<(), PaymentError> {
+        let index = index().await?;
+        let csv = "type, client, tx, amount
+        dispute, 1, 1,
+        dispute, 2, 1,
+        resolve, 1, 9,
+        chargeback, 1, 3,
+        deposit, 1, 6, 1.0
+        dispute, x, 1,
+        representment, 3, 5,";
+        let str_buf = stringreader::StringReader::new(csv);
+        let (_, records) = parse_records(Box::new(str_buf)).await?;
+        let findings: Vec<String> = records
+            .filter_map(|record| index.check(&record.transaction, record.line))
+            .map(|finding| finding.to_string())
+            .collect();
+        assert_eq!(findings.len(), 5);
+        assert_eq!(findings[0], "line 3: dispute of tx 1 by client 2, deposited by client 1");
+        assert_eq!(findings[1], "line 4: resolve of unknown tx 9");
+        assert_eq!(findings[2], "line 5: chargeback of unknown tx 3");
+        assert_eq!(findings[3], "line 6: deposit tx 6 doesn't refer to a deposit");
+        assert!(findings[4].starts_with("line 7: CSV parse error"), "{}", findings[4]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejects_what_is_not_an_index_of_this_version() -> Result<(), PaymentError> {
+        let bytes = index().await?.to_bytes();
+        let error = |bytes: &[u8]| DepositIndex::from_bytes(bytes).unwrap_err().to_string();
+
+        assert!(error(b"PEJ1\x01\0\0\0\0\0\0\0").contains("not a deposit index"));
+        let mut newer = bytes.clone();
+        newer[4] = 2;
+        assert!(error(&newer).contains("format version 2, this build reads version 1"));
+        assert!(error(&bytes[..bytes.len() - 1]).contains("bytes for 4 deposits"));
+        let mut unordered = bytes.clone();
+        unordered[12..16].copy_from_slice(&9u32.to_le_bytes());
+        assert!(error(&unordered).contains("tx ids out of order"));
+        Ok(())
+    }
+}
@@ -0,0 +1,262 @@
+//! Errors and warnings as JSON lines on stderr, for `--errors json`, so orchestrators can parse
+//! them rather than scrape the human-readable text.
+
+use crate::{
+    errors::PaymentError,
+    invariants::{Ledger, TotalsDrift, ViolationKind},
+    ordering::OutOfOrderTx,
+    panics::CaughtPanic,
+    parser::SuspectedMinorUnits,
+    rejection::RejectionReason,
+    types::{ClientId, IgnoreReason},
+    validate::Finding,
+    warnings::EngineWarning,
+};
+use serde::{Deserialize, Serialize};
+
+/// Version of the diagnostic schema, the `schema` field of every diagnostic. It only changes
+/// when a field is removed or changes meaning: new kinds and reasons may appear within a version.
+pub const DIAGNOSTIC_SCHEMA_VERSION: u32 = 1;
+
+/// How errors and warnings are written to stderr.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ErrorFormat {
+    /// As text, `Error: ...` and `warning: ...`.
+    #[default]
+    Human,
+    /// As one `Diagnostic` JSON object per line.
+    Json,
+}
+
+impl ErrorFormat {
+    /// Parses a name as given on the command line (`human` or `json`).
+    pub fn parse(name: &str) -> Result<Self, PaymentError> {
+        match name {
+            "human" => Ok(ErrorFormat::Human),
+            "json" => Ok(ErrorFormat::Json),
+            _ => Err(PaymentError::InvalidCliArgument(format!(
+                "unknown error format '{}', expected human or json",
+                name
+            ))),
+        }
+    }
+}
+
+/// Whether a diagnostic stopped the run.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    /// The run stopped on it.
+    Error,
+    /// The run went on, e.g. without the row.
+    Warning,
+}
+
+/// An error or a warning, as written under `--errors json`. Every field is always present,
+/// `null` when it doesn't apply.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    /// `DIAGNOSTIC_SCHEMA_VERSION`.
+    pub schema: u32,
+    pub level: Level,
+    /// The `PaymentError` variant, e.g. `CsvParseError` for a row that failed to parse or
+    /// `IgnoredTransaction` for a transaction the engine ignored (the error either stops a
+    /// strict run with), or the warning's own kind (`InvariantViolated`, `OpenDisputesNearCap`,
+    /// `InvalidRow`, `ValidationFailed`, `SuspectedMinorUnits`, `InternalError`).
+    pub kind: String,
+    /// Why the row was rejected, for `CsvParseError` and `IgnoredTransaction` warnings.
+    pub reason: Option<RejectionReason>,
+    /// Input file of the row, in multi-file runs.
+    pub source: Option<String>,
+    /// Line of the row in its input (the header is line 1).
+    pub line: Option<u64>,
+    pub client: Option<ClientId>,
+    pub tx: Option<u32>,
+    /// The human-readable text of the error or warning.
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(level: Level, kind: &str, message: String) -> Self {
+        Diagnostic {
+            schema: DIAGNOSTIC_SCHEMA_VERSION,
+            level,
+            kind: kind.to_owned(),
+            reason: None,
+            source: None,
+            line: None,
+            client: None,
+            tx: None,
+            message,
+        }
+    }
+
+    /// Returns the diagnostic of a warning the run went on after.
+    pub fn warning(kind: &str, message: String) -> Self {
+        Diagnostic::new(Level::Warning, kind, message)
+    }
+
+    /// Returns the diagnostic of the error a run stopped with, with the row's line and file
+    /// when it stopped on a row.
+    pub fn error(err: &PaymentError) -> Self {
+        match err {
+            PaymentError::AtRow { line, file, error } => Diagnostic {
+                source: file.clone(),
+                line: Some(*line),
+                ..Diagnostic::error(error)
+            },
+            _ => Diagnostic::new(Level::Error, err.kind(), err.to_string()),
+        }
+    }
+
+    /// Sets where the row was found, as `line` or `path:line`.
+    fn at(mut self, location: &str) -> Self {
+        match location.rsplit_once(':') {
+            Some((source, line)) => {
+                self.source = Some(source.to_owned());
+                self.line = line.parse().ok();
+            }
+            None => self.line = location.parse().ok(),
+        }
+        self
+    }
+
+    /// Returns the diagnostic as a line of JSON, without the line break.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("diagnostics serialize to JSON")
+    }
+}
+
+impl From<&EngineWarning> for Diagnostic {
+    fn from(warning: &EngineWarning) -> Self {
+        match warning {
+            EngineWarning::Rejected(rejection) => {
+                let kind = match rejection.reason {
+                    RejectionReason::ParseError => "CsvParseError",
+                    RejectionReason::Ignored(_) => "IgnoredTransaction",
+                };
+                Diagnostic {
+                    reason: Some(rejection.reason),
+                    source: rejection.source.clone(),
+                    line: rejection.line,
+                    client: rejection.client,
+                    tx: rejection.tx,
+                    ..Diagnostic::warning(kind, warning.to_string())
+                }
+            }
+            EngineWarning::InvariantViolated(violation) => Diagnostic {
+                client: Some(violation.client),
+                tx: match violation.kind {
+                    ViolationKind::HeldBelowDispute { tx, .. } => Some(tx),
+                    _ => None,
+                },
+                ..Diagnostic::warning("InvariantViolated", warning.to_string())
+            },
+            EngineWarning::OpenDisputesNearCap { .. } => {
+                Diagnostic::warning("OpenDisputesNearCap", warning.to_string())
+            }
+        }
+    }
+}
+
+impl From<&TotalsDrift> for Diagnostic {
+    fn from(drift: &TotalsDrift) -> Self {
+        Diagnostic::warning("InvariantViolated", format!("invariant violated: {}", drift))
+    }
+}
+
+impl From<&Ledger> for Diagnostic {
+    fn from(ledger: &Ledger) -> Self {
+        Diagnostic::warning(
+            "InvariantViolated",
+            format!("invariant violated: ledger doesn't balance: {}", ledger),
+        )
+    }
+}
+
+impl From<&OutOfOrderTx> for Diagnostic {
+    /// The warning `--check-tx-order` gives where `--strict-ordering` would stop the run.
+    fn from(out_of_order: &OutOfOrderTx) -> Self {
+        Diagnostic {
+            tx: Some(out_of_order.tx),
+            ..Diagnostic::warning("OrderingError", out_of_order.to_string())
+        }
+        .at(&out_of_order.line)
+    }
+}
+
+impl From<&SuspectedMinorUnits> for Diagnostic {
+    fn from(suspected: &SuspectedMinorUnits) -> Self {
+        Diagnostic {
+            line: Some(suspected.line),
+            ..Diagnostic::warning("SuspectedMinorUnits", suspected.to_string())
+        }
+    }
+}
+
+impl From<&CaughtPanic> for Diagnostic {
+    /// The error of a transaction that panicked and was caught, the run having gone on without it.
+    fn from(panic: &CaughtPanic) -> Self {
+        Diagnostic {
+            reason: Some(RejectionReason::Ignored(IgnoreReason::InternalError)),
+            source: panic.source.as_ref().and_then(|source| source.file.as_deref().map(str::to_owned)),
+            line: panic.source.as_ref().map(|source| source.line),
+            client: Some(panic.client),
+            tx: Some(panic.tx),
+            ..Diagnostic::new(Level::Error, "InternalError", format!("internal error: {}", panic))
+        }
+    }
+}
+
+impl From<&Finding> for Diagnostic {
+    fn from(finding: &Finding) -> Self {
+        Diagnostic::warning("InvalidRow", finding.to_string()).at(&finding.line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        diagnostics::{Diagnostic, Level, DIAGNOSTIC_SCHEMA_VERSION},
+        errors::PaymentError,
+        rejection::{RejectionReason, RejectionRecord},
+        types::{IgnoreReason, Transaction},
+        validate::Finding,
+        warnings::EngineWarning,
+    };
+
+    #[test]
+    fn diagnostics_keep_the_kind_and_location_of_what_they_report() {
+        let err = PaymentError::CsvParseError("unknown transaction type".to_owned());
+        let skipped = EngineWarning::Rejected(RejectionRecord::unparseable(3, &err).with_source("b.csv"));
+        let diagnostic = Diagnostic::from(&skipped);
+        assert_eq!(
+            diagnostic.to_json(),
+            format!(
+                "{{\"schema\":{},\"level\":\"warning\",\"kind\":\"CsvParseError\",\
+                 \"reason\":\"parse_error\",\"source\":\"b.csv\",\"line\":3,\"client\":null,\
+                 \"tx\":null,\"message\":\"{}\"}}",
+                DIAGNOSTIC_SCHEMA_VERSION, skipped
+            )
+        );
+
+        let txn = Transaction::withdrawal(2, 5, 3.0);
+        let detail = "insufficient funds".to_owned();
+        let ignored = RejectionRecord::ignored(&txn, IgnoreReason::InsufficientFunds, detail).with_line(Some(6));
+        let diagnostic = Diagnostic::from(&EngineWarning::Rejected(ignored));
+        assert_eq!(diagnostic.kind, "IgnoredTransaction");
+        assert_eq!(diagnostic.reason, Some(RejectionReason::Ignored(IgnoreReason::InsufficientFunds)));
+        assert_eq!((diagnostic.line, diagnostic.client, diagnostic.tx), (Some(6), Some(2), Some(5)));
+
+        let finding = Finding {
+            line: "a.csv:7".to_owned(),
+            problem: "amount has more than 4 decimal places".to_owned(),
+        };
+        let diagnostic = Diagnostic::from(&finding);
+        assert_eq!((diagnostic.source.as_deref(), diagnostic.line), (Some("a.csv"), Some(7)));
+
+        let diagnostic = Diagnostic::error(&PaymentError::JsonError("expected value".to_owned()));
+        assert_eq!((diagnostic.level, diagnostic.kind.as_str()), (Level::Error, "JsonError"));
+        assert_eq!(diagnostic.message, "JSON error: expected value");
+    }
+}
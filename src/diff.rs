@@ -0,0 +1,263 @@
+use crate::{
+    errors::PaymentError,
+    state::{read_account_records, AccountRecord},
+    types::ClientId,
+};
+use std::{collections::BTreeMap, fmt, io::Read};
+
+/// A difference between two account states reports for one client.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientDiff {
+    pub client: ClientId,
+    pub kind: DiffKind,
+}
+
+/// How a client's state differs between the two reports.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiffKind {
+    /// The client is only present in the second report.
+    Added(AccountRecord),
+    /// The client is only present in the first report.
+    Removed(AccountRecord),
+    /// The client is present in both reports with different values.
+    Changed(Vec<FieldChange>),
+}
+
+/// A field whose value differs between the two reports.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldChange {
+    Available { before: f64, after: f64 },
+    Held { before: f64, after: f64 },
+    Total { before: f64, after: f64 },
+    Locked { before: bool, after: bool },
+}
+
+/// Compares two account states reports exactly, see `diff_states_with_epsilon`.
+pub fn diff_states(a: impl Read, b: impl Read) -> Result<Vec<ClientDiff>, PaymentError> {
+    diff_states_with_epsilon(a, b, 0.0)
+}
+
+/// Compares two account states reports and returns the differences sorted by client id.
+///
+/// Clients are matched by id, in whatever order they appear. Amounts are considered equal when
+/// they differ by no more than `epsilon`, which absorbs floating point noise between runs.
+///
+/// # Errors
+///
+/// Returns a `PaymentError::CsvParseError` if either report can't be read or lists a client
+/// twice, as it couldn't be matched.
+pub fn diff_states_with_epsilon(
+    a: impl Read,
+    b: impl Read,
+    epsilon: f64,
+) -> Result<Vec<ClientDiff>, PaymentError> {
+    let before = by_client(read_account_records(a)?)?;
+    let mut after = by_client(read_account_records(b)?)?;
+
+    let mut diffs = Vec::new();
+    for (client, old) in before {
+        match after.remove(&client) {
+            Some(new) => {
+                let changes = field_changes(&old, &new, epsilon);
+                if !changes.is_empty() {
+                    diffs.push(ClientDiff {
+                        client,
+                        kind: DiffKind::Changed(changes),
+                    });
+                }
+            }
+            None => diffs.push(ClientDiff {
+                client,
+                kind: DiffKind::Removed(old),
+            }),
+        }
+    }
+    diffs.extend(after.into_iter().map(|(client, new)| ClientDiff {
+        client,
+        kind: DiffKind::Added(new),
+    }));
+    diffs.sort_by_key(|diff| diff.client);
+    Ok(diffs)
+}
+
+fn by_client(records: Vec<AccountRecord>) -> Result<BTreeMap<ClientId, AccountRecord>, PaymentError> {
+    let mut clients = BTreeMap::new();
+    for record in records {
+        let client = record.client;
+        if clients.insert(client, record).is_some() {
+            return Err(PaymentError::CsvParseError(format!("client {} is listed twice", client)));
+        }
+    }
+    Ok(clients)
+}
+
+fn field_changes(old: &AccountRecord, new: &AccountRecord, epsilon: f64) -> Vec<FieldChange> {
+    let differs = |before: f64, after: f64| (before - after).abs() > epsilon;
+    let mut changes = Vec::new();
+    if differs(old.available, new.available) {
+        changes.push(FieldChange::Available {
+            before: old.available,
+            after: new.available,
+        });
+    }
+    if differs(old.held, new.held) {
+        changes.push(FieldChange::Held {
+            before: old.held,
+            after: new.held,
+        });
+    }
+    if differs(old.total, new.total) {
+        changes.push(FieldChange::Total {
+            before: old.total,
+            after: new.total,
+        });
+    }
+    if old.locked != new.locked {
+        changes.push(FieldChange::Locked {
+            before: old.locked,
+            after: new.locked,
+        });
+    }
+    changes
+}
+
+impl fmt::Display for FieldChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (name, before, after) = match self {
+            FieldChange::Available { before, after } => ("available", before, after),
+            FieldChange::Held { before, after } => ("held", before, after),
+            FieldChange::Total { before, after } => ("total", before, after),
+            FieldChange::Locked { before, after } => {
+                return write!(f, "locked {} -> {}", before, after)
+            }
+        };
+        write!(f, "{} {:.4} -> {:.4} ({:+.4})", name, before, after, after - before)
+    }
+}
+
+impl fmt::Display for ClientDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            DiffKind::Added(record) => write!(
+                f,
+                "+ client {}: {:.4},{:.4},{:.4},{}",
+                self.client, record.available, record.held, record.total, record.locked
+            ),
+            DiffKind::Removed(record) => write!(
+                f,
+                "- client {}: {:.4},{:.4},{:.4},{}",
+                self.client, record.available, record.held, record.total, record.locked
+            ),
+            DiffKind::Changed(changes) => {
+                write!(f, "~ client {}:", self.client)?;
+                for (i, change) in changes.iter().enumerate() {
+                    write!(f, "{}{}", if i == 0 { " " } else { ", " }, change)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::diff::{diff_states, diff_states_with_epsilon, DiffKind, FieldChange};
+
+    const BEFORE: &str = "client,available,held,total,locked
+1,1.5000,0.0000,1.5000,false
+2,2.0000,0.0000,2.0000,false
+3,3.0000,0.0000,3.0000,false
+";
+
+    #[test]
+    fn identical_reports_have_no_differences() {
+        let reordered = "client,available,held,total,locked
+3,3.0000,0.0000,3.0000,false
+1,1.5000,0.0000,1.5000,false
+2,2.0000,0.0000,2.0000,false
+";
+        assert!(diff_states(BEFORE.as_bytes(), reordered.as_bytes())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn can_report_changed_balance_and_newly_locked_account() {
+        let after = "client,available,held,total,locked
+1,1.0000,0.0000,1.0000,false
+2,0.0000,0.0000,0.0000,true
+3,3.0000,0.0000,3.0000,false
+";
+        let diffs = diff_states(BEFORE.as_bytes(), after.as_bytes()).unwrap();
+        assert_eq!(diffs.len(), 2);
+        assert_eq!(diffs[0].client, 1);
+        assert_eq!(
+            diffs[0].to_string(),
+            "~ client 1: available 1.5000 -> 1.0000 (-0.5000), total 1.5000 -> 1.0000 (-0.5000)"
+        );
+        assert_eq!(diffs[1].client, 2);
+        match &diffs[1].kind {
+            DiffKind::Changed(changes) => assert!(changes.contains(&FieldChange::Locked {
+                before: false,
+                after: true
+            })),
+            other => panic!("unexpected diff {:?}", other),
+        }
+    }
+
+    #[test]
+    fn can_report_clients_present_in_one_report_only() {
+        let after = "client,available,held,total,locked
+1,1.5000,0.0000,1.5000,false
+2,2.0000,0.0000,2.0000,false
+4,4.0000,0.0000,4.0000,false
+";
+        let diffs = diff_states(BEFORE.as_bytes(), after.as_bytes()).unwrap();
+        assert_eq!(diffs.len(), 2);
+        assert!(matches!(diffs[0].kind, DiffKind::Removed(_)));
+        assert_eq!(diffs[0].to_string(), "- client 3: 3.0000,0.0000,3.0000,false");
+        assert!(matches!(diffs[1].kind, DiffKind::Added(_)));
+        assert_eq!(diffs[1].client, 4);
+    }
+
+    #[test]
+    fn reports_listing_a_client_twice_are_rejected() {
+        let twice = "client,available,held,total,locked
+1,1.5000,0.0000,1.5000,false
+2,2.0000,0.0000,2.0000,false
+1,0.0000,0.0000,0.0000,true
+";
+        for (a, b) in [(BEFORE, twice), (twice, BEFORE)] {
+            let err = diff_states(a.as_bytes(), b.as_bytes()).unwrap_err();
+            assert_eq!(err.to_string(), "CSV parse error: client 1 is listed twice");
+        }
+    }
+
+    #[test]
+    fn legacy_locked_flags_compare_by_value() {
+        let legacy = "client,available,held,total,locked
+1,1.5000,0.0000,1.5000,N
+2,2.0000,0.0000,2.0000,0
+3,3.0000,0.0000,3.0000,Y
+";
+        let diffs = diff_states(BEFORE.as_bytes(), legacy.as_bytes()).unwrap();
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(
+            diffs[0].kind,
+            DiffKind::Changed(vec![FieldChange::Locked {
+                before: false,
+                after: true
+            }])
+        );
+        assert_eq!(diffs[0].to_string(), "~ client 3: locked false -> true");
+    }
+
+    #[test]
+    fn epsilon_absorbs_small_differences() {
+        let after = BEFORE.replace("1,1.5000", "1,1.50001");
+        assert_eq!(diff_states(BEFORE.as_bytes(), after.as_bytes()).unwrap().len(), 1);
+        assert!(diff_states_with_epsilon(BEFORE.as_bytes(), after.as_bytes(), 0.0001)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -1,4 +1,9 @@
-use std::{error::Error, fmt};
+use crate::types::ClientId;
+use std::{
+    error::Error,
+    fmt, io,
+    path::{Path, PathBuf},
+};
 
 /// Represents the various errors that can occur in the payment engine.
 #[derive(Debug)]
@@ -7,8 +12,86 @@ pub enum PaymentError {
     InvalidCliArgument(String),
     /// Indicates error in csv parsing.
     CsvParseError(String),
-    /// Indicates error in opening or reading the csv file.
-    FileError(String),
+    /// Indicates a protobuf message of the input that can't be decoded, or isn't a valid
+    /// transaction, with its index in the stream (from 1).
+    DecodeError { index: u64, reason: String },
+    /// Indicates a file that can't be opened, read or written.
+    FileError { path: PathBuf, source: io::Error },
+    /// Indicates a failure to write a report or a row somewhere other than a file.
+    IoError(String),
+    /// Indicates a failure to write the output of a command to a file or a stream, e.g.
+    /// `stdout` piped into a command that exited.
+    OutputError { path_or_stream: String, source: io::Error },
+    /// Indicates a failure in the transaction store (e.g. reading or writing spilled records).
+    StorageError(String),
+    /// Indicates a deposit or withdrawal out of tx id order under `--strict-ordering`.
+    OrderingError(String),
+    /// Indicates a transaction the engine ignored under `EngineConfig::fail_on_ignore`.
+    IgnoredTransaction(String),
+    /// Indicates an invalid JSON clients dump.
+    JsonError(String),
+    /// Indicates a transaction that would take the engine over one of its size limits.
+    LimitExceeded { which: Limit, limit: usize },
+    /// Indicates a balance correction the engine refused to apply.
+    CorrectionRefused(String),
+    /// Indicates an amount that isn't a finite number, or a balance change that would take an
+    /// amount out of the range of `Amount`.
+    AmountOutOfRange(String),
+    /// Indicates an input going over one of the limits of `parser::InputLimits`, at the given
+    /// line (the index of the message or element for binary inputs).
+    InputLimitExceeded { which: InputLimit, limit: u64, at_line: u64 },
+    /// Indicates an input of `parallel::process_files_parallel` whose engine can't be merged
+    /// with those of the inputs before it, e.g. as they share a client.
+    MergeFailed { path: PathBuf, source: Box<MergeError> },
+    /// Indicates a transaction whose processing panicked beyond `EngineConfig::max_panics`, or
+    /// left a state that couldn't be restored.
+    InternalError(String),
+    /// Indicates the error a row of the input failed with, with the line of the row and, in
+    /// multi-file runs, its file. It is displayed and named like the error itself.
+    AtRow {
+        line: u64,
+        file: Option<String>,
+        error: Box<PaymentError>,
+    },
+}
+
+/// The size limits of an engine, set in its `EngineConfig`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    /// `EngineConfig::max_clients`.
+    Clients,
+    /// `EngineConfig::max_stored_transactions`.
+    StoredTransactions,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Limit::Clients => write!(f, "clients"),
+            Limit::StoredTransactions => write!(f, "stored transactions"),
+        }
+    }
+}
+
+/// The size limits of an input, set in its `parser::InputLimits`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputLimit {
+    /// `InputLimits::max_field_bytes`.
+    FieldBytes,
+    /// `InputLimits::max_record_bytes`.
+    RecordBytes,
+    /// `InputLimits::max_records`.
+    Records,
+}
+
+impl fmt::Display for InputLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InputLimit::FieldBytes => write!(f, "bytes in a field"),
+            InputLimit::RecordBytes => write!(f, "bytes in a record"),
+            InputLimit::Records => write!(f, "records"),
+        }
+    }
 }
 
 impl fmt::Display for PaymentError {
@@ -16,9 +99,132 @@ impl fmt::Display for PaymentError {
         match self {
             PaymentError::InvalidCliArgument(msg) => write!(f, "Invalid cli argument: {}", msg),
             PaymentError::CsvParseError(msg) => write!(f, "CSV parse error: {}", msg),
-            PaymentError::FileError(msg) => write!(f, "File error: {}", msg),
+            PaymentError::DecodeError { index, reason } => {
+                write!(f, "Decode error: message {}: {}", index, reason)
+            }
+            PaymentError::FileError { path, source } => {
+                write!(f, "File error: {}: {}", path.display(), source)?;
+                if source.kind() == io::ErrorKind::PermissionDenied {
+                    write!(f, " (check the permissions of the file and of its directories)")?;
+                }
+                Ok(())
+            }
+            PaymentError::IoError(msg) => write!(f, "I/O error: {}", msg),
+            PaymentError::OutputError { path_or_stream, source } => {
+                write!(f, "Output error: {}: {}", path_or_stream, source)
+            }
+            PaymentError::StorageError(msg) => write!(f, "Storage error: {}", msg),
+            PaymentError::OrderingError(msg) => write!(f, "Ordering error: {}", msg),
+            PaymentError::IgnoredTransaction(msg) => write!(f, "Ignored transaction: {}", msg),
+            PaymentError::JsonError(msg) => write!(f, "JSON error: {}", msg),
+            PaymentError::LimitExceeded { which, limit } => {
+                write!(f, "Limit exceeded: more than {} {}", limit, which)
+            }
+            PaymentError::CorrectionRefused(msg) => write!(f, "Correction refused: {}", msg),
+            PaymentError::AmountOutOfRange(msg) => write!(f, "Amount out of range: {}", msg),
+            PaymentError::InputLimitExceeded { which, limit, at_line } => {
+                write!(f, "Input limit exceeded: more than {} {} at line {}", limit, which, at_line)
+            }
+            PaymentError::MergeFailed { path, source } => write!(
+                f,
+                "Merge failed: {} can't be merged with the files before it: {}",
+                path.display(),
+                source
+            ),
+            PaymentError::InternalError(msg) => write!(f, "Internal error: {}", msg),
+            PaymentError::AtRow { error, .. } => write!(f, "{}", error),
+        }
+    }
+}
+
+impl PaymentError {
+    /// Returns a `PaymentError::FileError` for an I/O error on `path`.
+    pub fn file(path: impl AsRef<Path>, source: io::Error) -> Self {
+        PaymentError::FileError {
+            path: path.as_ref().to_owned(),
+            source,
+        }
+    }
+
+    /// Returns a `PaymentError::OutputError` for an I/O error writing to `path_or_stream`.
+    pub fn output(path_or_stream: impl Into<String>, source: io::Error) -> Self {
+        PaymentError::OutputError {
+            path_or_stream: path_or_stream.into(),
+            source,
+        }
+    }
+
+    /// Returns whether the error is a write to an output whose reader went away, e.g. `stdout`
+    /// piped into `head`, which command line filters end on quietly.
+    pub fn is_broken_pipe(&self) -> bool {
+        matches!(self, PaymentError::OutputError { source, .. } if source.kind() == io::ErrorKind::BrokenPipe)
+    }
+
+    /// Returns the name of the variant, the `kind` of the error under `--errors json`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            PaymentError::InvalidCliArgument(_) => "InvalidCliArgument",
+            PaymentError::CsvParseError(_) => "CsvParseError",
+            PaymentError::DecodeError { .. } => "DecodeError",
+            PaymentError::FileError { .. } => "FileError",
+            PaymentError::IoError(_) => "IoError",
+            PaymentError::OutputError { .. } => "OutputError",
+            PaymentError::StorageError(_) => "StorageError",
+            PaymentError::OrderingError(_) => "OrderingError",
+            PaymentError::IgnoredTransaction(_) => "IgnoredTransaction",
+            PaymentError::JsonError(_) => "JsonError",
+            PaymentError::LimitExceeded { .. } => "LimitExceeded",
+            PaymentError::CorrectionRefused(_) => "CorrectionRefused",
+            PaymentError::AmountOutOfRange(_) => "AmountOutOfRange",
+            PaymentError::InputLimitExceeded { .. } => "InputLimitExceeded",
+            PaymentError::MergeFailed { .. } => "MergeFailed",
+            PaymentError::InternalError(_) => "InternalError",
+            PaymentError::AtRow { error, .. } => error.kind(),
+        }
+    }
+}
+
+impl Error for PaymentError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PaymentError::FileError { source, .. } => Some(source),
+            PaymentError::OutputError { source, .. } => Some(source),
+            PaymentError::MergeFailed { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Represents the reasons two engine states can't be merged with `PaymentEngine::merge`.
+#[derive(Debug)]
+pub enum MergeError {
+    /// Clients with an account on both sides, under `ClientMergePolicy::Reject`, in ascending
+    /// id order.
+    SharedClients(Vec<ClientId>),
+    /// Tx ids used on both sides, in ascending order.
+    DuplicateTransactions(Vec<u32>),
+    /// Indicates a failure of either side's transaction store, or merged balances out of range.
+    Storage(PaymentError),
+}
+
+impl fmt::Display for MergeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MergeError::SharedClients(clients) => write!(f, "clients on both sides: {}", join(clients)),
+            MergeError::DuplicateTransactions(txs) => write!(f, "tx ids on both sides: {}", join(txs)),
+            MergeError::Storage(err) => write!(f, "{}", err),
         }
     }
 }
 
-impl Error for PaymentError {}
+impl Error for MergeError {}
+
+impl From<PaymentError> for MergeError {
+    fn from(err: PaymentError) -> Self {
+        MergeError::Storage(err)
+    }
+}
+
+fn join<T: fmt::Display>(ids: &[T]) -> String {
+    ids.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")
+}
@@ -0,0 +1,407 @@
+//! Reordering of the input by the `timestamp` column, for merged feeds whose file order isn't
+//! chronological.
+//!
+//! The input is read in chunks of at most `chunk_rows` rows, each chunk is sorted and appended
+//! to a single spill file in the temp directory, and the chunks are merged back into a single
+//! stream as the engine consumes it, all read through the spill file's one handle. Memory stays
+//! bounded by the chunk size whatever the size of the input, and a single file is open however
+//! many chunks there are; an input fitting in a single chunk is sorted in memory without
+//! touching the disk.
+
+use crate::{
+    errors::PaymentError,
+    parser::{read_error, LimitedReader, ParseOptions, ParsedRecord, RowParser},
+};
+use csv::{ByteRecord, Position, Reader, ReaderBuilder, StringRecord, Writer, WriterBuilder};
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    fs::File,
+    io::{self, BufWriter, Read, Seek, SeekFrom},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+static CHUNK_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Timestamp then line of a row.
+type SortKey = (Option<u64>, u64);
+
+/// Default number of rows sorted in memory at once.
+pub const DEFAULT_SORT_CHUNK_ROWS: usize = 100_000;
+
+/// Order in which the rows of the input are processed.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum InputOrder {
+    /// The order of the rows in the file.
+    #[default]
+    File,
+    /// Ascending `timestamp` column, ties in file order.
+    Timestamp,
+}
+
+impl InputOrder {
+    /// Parses an order name as given on the command line (`file` or `timestamp`).
+    pub fn parse(name: &str) -> Result<Self, PaymentError> {
+        match name {
+            "file" => Ok(InputOrder::File),
+            "timestamp" => Ok(InputOrder::Timestamp),
+            _ => Err(PaymentError::InvalidCliArgument(format!(
+                "unknown input order '{}', expected file or timestamp",
+                name
+            ))),
+        }
+    }
+}
+
+/// Parses transactions from a CSV reader like `parser::parse_records`, but yields the rows
+/// ordered by their `timestamp` column, sorting at most `chunk_rows` rows in memory at once.
+///
+/// Rows with the same timestamp keep their file order. Rows without a valid timestamp, including
+/// rows that can't be read at all, come first so they are reported before anything is applied.
+/// Each row keeps its original line number.
+///
+/// # Errors
+///
+/// Returns a `PaymentError::CsvParseError` if the header can't be read or has no `timestamp`
+/// column, a `PaymentError::StorageError` if a chunk can't be spilled, or a
+/// `PaymentError::InputLimitExceeded` if the input goes over the default `InputLimits`.
+pub async fn parse_records_by_timestamp(
+    br: Box<dyn Read>,
+    chunk_rows: usize,
+) -> Result<(StringRecord, Box<dyn Iterator<Item = ParsedRecord>>), PaymentError> {
+    parse_records_by_timestamp_with(br, chunk_rows, &ParseOptions::default()).await
+}
+
+/// Parses transactions like `parse_records_by_timestamp`, reading the amounts in the unit of
+/// `options` and within its limits.
+pub async fn parse_records_by_timestamp_with(
+    br: Box<dyn Read>,
+    chunk_rows: usize,
+    options: &ParseOptions,
+) -> Result<(StringRecord, Box<dyn Iterator<Item = ParsedRecord>>), PaymentError> {
+    let options = *options;
+    let limited = LimitedReader::new(br, options.limits);
+    let mut rdr = ReaderBuilder::new().flexible(true).from_reader(limited);
+
+    let raw_headers = rdr.headers().map_err(read_error)?.clone();
+    let mut headers = raw_headers.clone();
+    headers.trim();
+    let column = headers
+        .iter()
+        .position(|header| header == "timestamp")
+        .ok_or_else(|| {
+            PaymentError::CsvParseError("ordering by timestamp needs a timestamp column".to_owned())
+        })?;
+
+    let chunk_rows = chunk_rows.max(1);
+    // only created once a chunk is full, an input fitting in one chunk never touches the disk
+    let mut spill = None;
+    let mut chunks = Vec::new();
+    let mut rows = Vec::new();
+    // read as bytes, so that a row that isn't valid UTF-8 can still be quarantined as it was
+    for result in rdr.into_byte_records() {
+        // the rows can't be sorted without the rest of the input
+        let result = match result {
+            Err(err) if err.is_io_error() => return Err(read_error(err)),
+            result => result,
+        };
+        rows.push(SortRow::read(result, column));
+        if rows.len() == chunk_rows {
+            let spill = match &spill {
+                Some(spill) => spill,
+                None => spill.insert(Arc::new(SpillFile::create()?)),
+            };
+            chunks.push(SpilledChunk::write(spill, &mut rows)?);
+        }
+    }
+
+    let sorted: Box<dyn Iterator<Item = SortRow>> = match &spill {
+        None => {
+            rows.sort_by_key(SortRow::key);
+            Box::new(rows.into_iter())
+        }
+        Some(spill) => {
+            if !rows.is_empty() {
+                chunks.push(SpilledChunk::write(spill, &mut rows)?);
+            }
+            Box::new(ChunkMerge::new(chunks))
+        }
+    };
+    let parser = RowParser::new(headers, options);
+    let records = sorted.map(move |row| row.into_parsed(&parser));
+    Ok((raw_headers, Box::new(records)))
+}
+
+/// A raw row with its sort key and the position it had in the input.
+struct SortRow {
+    timestamp: Option<u64>,
+    position: Position,
+    raw: ByteRecord,
+    /// Why the row couldn't be read, for rows the CSV reader rejected.
+    error: Option<String>,
+}
+
+impl SortRow {
+    fn read(result: Result<ByteRecord, csv::Error>, column: usize) -> Self {
+        match result {
+            Ok(raw) => SortRow {
+                timestamp: raw
+                    .get(column)
+                    .and_then(|field| std::str::from_utf8(field).ok())
+                    .and_then(|field| field.trim().parse().ok()),
+                position: raw.position().cloned().unwrap_or_else(Position::new),
+                raw,
+                error: None,
+            },
+            Err(err) => SortRow {
+                timestamp: None,
+                position: err.position().cloned().unwrap_or_else(Position::new),
+                raw: ByteRecord::new(),
+                error: Some(err.to_string()),
+            },
+        }
+    }
+
+    fn key(&self) -> SortKey {
+        (self.timestamp, self.position.line())
+    }
+
+    fn into_parsed(mut self, parser: &RowParser) -> ParsedRecord {
+        match self.error {
+            Some(err) => {
+                let raw = self.raw.iter().map(String::from_utf8_lossy).collect();
+                ParsedRecord {
+                    undecoded: Some(self.raw).filter(|raw| std::str::from_utf8(raw.as_slice()).is_err()),
+                    source: None,
+                    ..ParsedRecord::failed(self.position.line(), raw, PaymentError::CsvParseError(err))
+                }
+            }
+            None => {
+                self.raw.set_position(Some(self.position));
+                parser.parse_bytes(self.raw)
+            }
+        }
+    }
+
+    /// Spilled rows start with the position, the key and the error, followed by the raw fields.
+    fn spill<W: std::io::Write>(&self, w: &mut Writer<W>) -> csv::Result<()> {
+        let timestamp = self.timestamp.map(|timestamp| timestamp.to_string()).unwrap_or_default();
+        let prefix = [
+            self.position.byte().to_string(),
+            self.position.line().to_string(),
+            self.position.record().to_string(),
+            timestamp,
+            self.error.clone().unwrap_or_default(),
+        ];
+        w.write_record(prefix.iter().map(String::as_bytes).chain(self.raw.iter()))
+    }
+
+    fn unspill(record: &ByteRecord) -> Option<Self> {
+        let text = |index: usize| std::str::from_utf8(record.get(index)?).ok();
+        let number = |index: usize| text(index)?.parse::<u64>().ok();
+        let mut position = Position::new();
+        position.set_byte(number(0)?).set_line(number(1)?).set_record(number(2)?);
+        let error = text(4)?;
+        Some(SortRow {
+            timestamp: number(3),
+            position,
+            raw: record.iter().skip(5).collect(),
+            error: (!error.is_empty()).then(|| error.to_owned()),
+        })
+    }
+}
+
+/// The file in the temp directory the sorted chunks are spilled to, one after the other. It is
+/// opened once, its chunks are all read through the same handle, and removed when dropped.
+struct SpillFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl SpillFile {
+    fn create() -> Result<Self, PaymentError> {
+        let path = std::env::temp_dir().join(format!(
+            "payment-engine-{}-{}.sort",
+            std::process::id(),
+            CHUNK_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|err| PaymentError::StorageError(err.to_string()))?;
+        Ok(SpillFile { path, file })
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// The bytes of one chunk of the spill file. Every read seeks to where the chunk's last read
+/// ended, as the other chunks move the shared handle in between.
+struct ChunkReader {
+    spill: Arc<SpillFile>,
+    offset: u64,
+    end: u64,
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(usize::try_from(self.end - self.offset).unwrap_or(usize::MAX));
+        if len == 0 {
+            return Ok(0);
+        }
+        let mut file = &self.spill.file;
+        file.seek(SeekFrom::Start(self.offset))?;
+        let read = file.read(&mut buf[..len])?;
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+/// A sorted chunk of rows in the spill file.
+struct SpilledChunk {
+    reader: Reader<ChunkReader>,
+    failed: bool,
+}
+
+impl SpilledChunk {
+    /// Sorts the rows and moves them to a new chunk at the end of the spill file.
+    fn write(spill: &Arc<SpillFile>, rows: &mut Vec<SortRow>) -> Result<Self, PaymentError> {
+        let storage_error = |err: &dyn std::fmt::Display| PaymentError::StorageError(err.to_string());
+        rows.sort_by_key(SortRow::key);
+        let mut file = &spill.file;
+        let start = file.seek(SeekFrom::End(0)).map_err(|err| storage_error(&err))?;
+        let mut writer = WriterBuilder::new()
+            .flexible(true)
+            .has_headers(false)
+            .from_writer(BufWriter::new(file));
+        for row in rows.drain(..) {
+            row.spill(&mut writer).map_err(|err| storage_error(&err))?;
+        }
+        writer.flush().map_err(|err| storage_error(&err))?;
+        drop(writer);
+        let end = file.stream_position().map_err(|err| storage_error(&err))?;
+
+        let reader = ReaderBuilder::new().flexible(true).has_headers(false).from_reader(ChunkReader {
+            spill: spill.clone(),
+            offset: start,
+            end,
+        });
+        Ok(SpilledChunk { reader, failed: false })
+    }
+
+    /// Reads the chunk's next row. A row that can't be read back ends the chunk with an error
+    /// row, which the run then reports like any unreadable input row.
+    fn next_row(&mut self) -> Option<SortRow> {
+        if self.failed {
+            return None;
+        }
+        let mut record = ByteRecord::new();
+        let (err, raw) = match self.reader.read_byte_record(&mut record) {
+            Ok(false) => return None,
+            Ok(true) => match SortRow::unspill(&record) {
+                Some(row) => return Some(row),
+                // what follows the prefix is still the input row
+                None => (format!("corrupt sort chunk record: {:?}", record), record.iter().skip(5).collect()),
+            },
+            Err(err) => (err.to_string(), ByteRecord::new()),
+        };
+        self.failed = true;
+        Some(SortRow {
+            timestamp: None,
+            position: Position::new(),
+            raw,
+            error: Some(PaymentError::StorageError(err).to_string()),
+        })
+    }
+}
+
+/// K-way merge of sorted chunks, holding the next row of each chunk in memory.
+struct ChunkMerge {
+    chunks: Vec<SpilledChunk>,
+    heads: Vec<Option<SortRow>>,
+    queue: BinaryHeap<Reverse<(SortKey, usize)>>,
+}
+
+impl ChunkMerge {
+    fn new(mut chunks: Vec<SpilledChunk>) -> Self {
+        let heads: Vec<Option<SortRow>> = chunks.iter_mut().map(SpilledChunk::next_row).collect();
+        let queue = heads
+            .iter()
+            .enumerate()
+            .filter_map(|(index, head)| head.as_ref().map(|row| Reverse((row.key(), index))))
+            .collect();
+        ChunkMerge { chunks, heads, queue }
+    }
+}
+
+impl Iterator for ChunkMerge {
+    type Item = SortRow;
+
+    fn next(&mut self) -> Option<SortRow> {
+        let Reverse((_, index)) = self.queue.pop()?;
+        let row = self.heads[index].take();
+        if let Some(next) = self.chunks[index].next_row() {
+            self.queue.push(Reverse((next.key(), index)));
+            self.heads[index] = Some(next);
+        }
+        row
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{errors::PaymentError, external_sort::parse_records_by_timestamp};
+
+    #[tokio::test]
+    async fn spilled_chunks_merge_in_timestamp_then_file_order() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount, timestamp
+withdrawal, 1, 4, 1.0, 300
+deposit, 1, 1, 5.0, 100
+deposit, 2, 3, 2.0, 200
+deposit, 1, 2, 1.0, 200
+dispute, 1, 9, , abc
+deposit, 2, 5, 1.0, 300";
+        let spill_files = || {
+            let prefix = format!("payment-engine-{}-", std::process::id());
+            std::fs::read_dir(std::env::temp_dir())
+                .unwrap()
+                .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                .filter(|name| name.starts_with(&prefix) && name.ends_with(".sort"))
+                .count()
+        };
+        for (chunk_rows, files) in [(1, 1), (2, 1), (100, 0)] {
+            let str_buf = stringreader::StringReader::new(csv);
+            let (_, records) = parse_records_by_timestamp(Box::new(str_buf), chunk_rows).await?;
+            // however many chunks, they share a single file
+            assert_eq!(spill_files(), files, "chunks of {} rows", chunk_rows);
+            let order: Vec<(u64, Option<u32>)> = records
+                .map(|record| (record.line, record.transaction.ok().map(|txn| txn.tx)))
+                .collect();
+            // the row whose timestamp doesn't parse comes first, then fails to deserialize
+            assert_eq!(
+                order,
+                [(6, None), (3, Some(1)), (4, Some(3)), (5, Some(2)), (2, Some(4)), (7, Some(5))],
+                "chunks of {} rows",
+                chunk_rows
+            );
+            assert_eq!(spill_files(), 0);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn needs_a_timestamp_column() {
+        let str_buf = stringreader::StringReader::new("type, client, tx, amount\ndeposit, 1, 1, 1.0");
+        assert!(parse_records_by_timestamp(Box::new(str_buf), 10).await.is_err());
+    }
+}
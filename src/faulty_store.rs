@@ -0,0 +1,271 @@
+//! A transaction store that misbehaves on cue, to check how the engine copes with a failing
+//! storage backend before trusting one in production. Built for the crate's own tests, and for
+//! other crates with the `testing` feature.
+//!
+//! The engine handles store failures as follows:
+//!
+//! * a failed lookup or removal is tried once more, as both can be repeated safely. When the
+//!   retry succeeds the transaction goes on, and the failure counts in
+//!   `PaymentEngine::storage_retries`, reported in the run summary as a degraded run;
+//! * a failed insert is not retried, as the store took the transaction with it. Deposits,
+//!   withdrawals, captures and settles are stored before their account is changed, so the
+//!   transaction fails leaving the engine as it was;
+//! * a failure that remains fails the transaction with a `PaymentError::StorageError`, rather
+//!   than ignoring it for a reason the store made up, such as `unknown_transaction`.
+
+use crate::{
+    errors::PaymentError,
+    store::{InMemoryTransactionStore, TransactionStore},
+    types::Transaction,
+};
+use std::{collections::HashMap, future::Future, time::Duration};
+
+/// What a `FaultyStore` does instead of, or on top of, one of its operations.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// The operation fails with a `PaymentError::StorageError`, leaving the store as it was.
+    Fail,
+    /// The operation takes that much longer.
+    Delay(Duration),
+    /// A lookup returns the transaction as it was before its latest insert, or nothing if it
+    /// was inserted once, like a replica lagging behind. Other operations run as usual.
+    StaleRead,
+}
+
+/// Wraps a store, scripting faults on some of its operations. Inserts, lookups and removals are
+/// numbered from 1 as they run, `len` and `tx_ids` aren't counted.
+pub struct FaultyStore<S: TransactionStore = InMemoryTransactionStore> {
+    inner: S,
+    faults: HashMap<u64, Fault>,
+    operations: u64,
+    /// The transaction each insert replaced, `None` for a first insert, served by stale reads.
+    replaced: HashMap<u32, Option<Transaction>>,
+}
+
+impl FaultyStore {
+    /// Creates an empty in-memory store without faults.
+    pub fn new() -> Self {
+        FaultyStore::wrap(InMemoryTransactionStore::new())
+    }
+}
+
+impl Default for FaultyStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: TransactionStore + Send> FaultyStore<S> {
+    /// Wraps `inner`, without faults.
+    pub fn wrap(inner: S) -> Self {
+        FaultyStore {
+            inner,
+            faults: HashMap::new(),
+            operations: 0,
+            replaced: HashMap::new(),
+        }
+    }
+
+    /// Scripts `fault` on the `n`th operation, from 1, replacing the one scripted there if any.
+    pub fn with_fault(mut self, n: u64, fault: Fault) -> Self {
+        self.faults.insert(n, fault);
+        self
+    }
+
+    /// Returns the number of operations run so far, failed ones included.
+    pub fn operations(&self) -> u64 {
+        self.operations
+    }
+
+    /// Returns the wrapped store.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Numbers the next operation, returning its fault and the error it fails with, if any.
+    fn next_operation(&mut self, what: &str) -> (Option<Fault>, Result<(), PaymentError>) {
+        self.operations += 1;
+        let fault = self.faults.get(&self.operations).copied();
+        let result = match fault {
+            Some(Fault::Fail) => Err(PaymentError::StorageError(format!(
+                "injected failure of operation {} ({})",
+                self.operations, what
+            ))),
+            _ => Ok(()),
+        };
+        (fault, result)
+    }
+}
+
+/// Sleeps for the delay of `fault`, if it is one.
+async fn delay(fault: Option<Fault>) {
+    if let Some(Fault::Delay(delay)) = fault {
+        tokio::time::sleep(delay).await;
+    }
+}
+
+impl<S: TransactionStore + Send> TransactionStore for FaultyStore<S> {
+    fn insert(
+        &mut self,
+        txn: Transaction,
+    ) -> impl Future<Output = Result<(), PaymentError>> + Send {
+        let (fault, result) = self.next_operation("insert");
+        async move {
+            delay(fault).await;
+            result?;
+            let tx = txn.tx;
+            let previous = self.inner.get(tx).await?;
+            self.inner.insert(txn).await?;
+            self.replaced.insert(tx, previous);
+            Ok(())
+        }
+    }
+
+    fn get(
+        &mut self,
+        tx: u32,
+    ) -> impl Future<Output = Result<Option<Transaction>, PaymentError>> + Send {
+        let (fault, result) = self.next_operation("get");
+        async move {
+            delay(fault).await;
+            result?;
+            match (fault, self.replaced.get(&tx)) {
+                (Some(Fault::StaleRead), Some(previous)) => Ok(previous.clone()),
+                _ => self.inner.get(tx).await,
+            }
+        }
+    }
+
+    fn remove(&mut self, tx: u32) -> impl Future<Output = Result<(), PaymentError>> + Send {
+        let (fault, result) = self.next_operation("remove");
+        async move {
+            delay(fault).await;
+            result?;
+            self.replaced.remove(&tx);
+            self.inner.remove(tx).await
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    fn tx_ids(&self) -> Vec<u32> {
+        self.inner.tx_ids()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        errors::PaymentError,
+        faulty_store::{Fault, FaultyStore},
+        payment_engine::PaymentEngine,
+        report::OutputOrder,
+        stats::RunStats,
+        store::TransactionStore,
+        types::{IgnoreReason, ProcessOutcome, Transaction},
+    };
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn a_failed_insert_leaves_the_account_as_it_was() -> Result<(), PaymentError> {
+        // a deposit or withdrawal is a single insert
+        let store = FaultyStore::new()
+            .with_fault(2, Fault::Fail)
+            .with_fault(4, Fault::Fail)
+            .with_fault(5, Fault::Fail);
+        let mut engine = PaymentEngine::with_store(store);
+        engine.process_transaction(Transaction::deposit(1, 1, 1.0)).await?;
+
+        let failed = engine.process_transaction(Transaction::deposit(1, 2, 2.0).with_merchant("acme")).await;
+        assert_eq!(
+            failed.map_err(|err| err.to_string()),
+            Err("Storage error: injected failure of operation 2 (insert)".to_owned())
+        );
+        let client = engine.clients[&1];
+        assert_eq!((client.available.to_f64(), client.total.to_f64()), (1.0, 1.0));
+        assert_eq!(engine.transactions.len(), 1);
+        assert!(engine.merchants.get("acme").is_none());
+        assert_eq!(engine.check_totals(), None);
+
+        // the resent deposit goes through, and a withdrawal failing the same way changes nothing
+        engine.process_transaction(Transaction::deposit(1, 2, 2.0)).await?;
+        let failed = engine.process_transaction(Transaction::withdrawal(1, 3, 0.5)).await;
+        assert!(matches!(failed, Err(PaymentError::StorageError(_))));
+        let client = engine.clients[&1];
+        assert_eq!((client.available.to_f64(), client.total.to_f64()), (3.0, 3.0));
+        assert_eq!(engine.check_totals(), None);
+
+        // nor does it open the account of a new client
+        let failed = engine.process_transaction(Transaction::deposit(2, 4, 1.0)).await;
+        assert!(matches!(failed, Err(PaymentError::StorageError(_))));
+        assert!(!engine.clients.contains_key(&2));
+        assert_eq!(engine.client_ids(OutputOrder::FirstSeen), [1]);
+        assert_eq!(engine.storage_retries(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn a_failed_lookup_fails_the_dispute_instead_of_ignoring_it() -> Result<(), PaymentError> {
+        // the dispute's lookup and its retry both fail
+        let store = FaultyStore::new().with_fault(2, Fault::Fail).with_fault(3, Fault::Fail);
+        let mut engine = PaymentEngine::with_store(store);
+        engine.process_transaction(Transaction::deposit(1, 1, 1.0)).await?;
+
+        let failed = engine.process_transaction(Transaction::dispute(1, 1)).await;
+        assert!(matches!(failed, Err(PaymentError::StorageError(_))));
+        assert_eq!(engine.clients[&1].held, 0.0);
+        assert!(engine.disputed_transactions.is_empty());
+
+        let outcome = engine.process_transaction(Transaction::dispute(1, 1)).await?;
+        assert_eq!(outcome, ProcessOutcome::Applied);
+        assert_eq!(engine.clients[&1].held, 1.0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn a_lookup_failing_once_is_retried_and_degrades_the_run() -> Result<(), PaymentError> {
+        let mut engine = PaymentEngine::with_store(FaultyStore::new().with_fault(2, Fault::Fail));
+        engine.process_transaction(Transaction::deposit(1, 1, 1.0)).await?;
+        let outcome = engine.process_transaction(Transaction::dispute(1, 1)).await?;
+        assert_eq!(outcome, ProcessOutcome::Applied);
+        assert_eq!(engine.transactions.operations(), 3);
+        assert_eq!(engine.storage_retries(), 1);
+
+        let mut stats = RunStats::start();
+        stats.storage_retries = engine.storage_retries();
+        let mut summary = Vec::new();
+        stats.write_summary(&mut summary).expect("writing to memory");
+        let summary = String::from_utf8(summary).expect("the summary is text");
+        assert!(summary.contains("degraded: 1 storage operations failed and succeeded on retry"));
+
+        // without a fault, a missing transaction is still unknown
+        let outcome = engine.process_transaction(Transaction::dispute(1, 9)).await?;
+        assert_eq!(outcome, ProcessOutcome::Ignored(IgnoreReason::UnknownTransaction));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delays_and_stale_reads_are_served_as_scripted() -> Result<(), PaymentError> {
+        let mut store = FaultyStore::new()
+            .with_fault(4, Fault::StaleRead)
+            .with_fault(5, Fault::StaleRead)
+            .with_fault(7, Fault::Delay(Duration::from_secs(2)));
+        store.insert(Transaction::deposit(1, 1, 1.0)).await?;
+        store.insert(Transaction::deposit(1, 1, 2.0)).await?;
+        store.insert(Transaction::deposit(1, 2, 3.0)).await?;
+
+        // the stale read of tx 1 misses its second insert, that of tx 2 misses it altogether
+        let amount = store.get(1).await?.and_then(|txn| txn.amount);
+        assert_eq!(amount, Some(1.0));
+        assert_eq!(store.get(2).await?, None);
+        assert_eq!(store.get(2).await?, Some(Transaction::deposit(1, 2, 3.0)));
+
+        let started = tokio::time::Instant::now();
+        store.remove(1).await?;
+        assert!(started.elapsed() >= Duration::from_secs(2));
+        assert_eq!((store.len(), store.operations()), (1, 7));
+        Ok(())
+    }
+}
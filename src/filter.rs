@@ -0,0 +1,90 @@
+use crate::{errors::PaymentError, types::ClientId};
+use std::{collections::HashSet, ops::RangeInclusive};
+
+/// A set of client ids, written as a comma separated list of ids and inclusive ranges,
+/// e.g. `7,42,1000-1010`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientFilter {
+    ranges: Vec<RangeInclusive<ClientId>>,
+    /// Single ids given by `from_ids`, looked up without going through every range.
+    ids: HashSet<ClientId>,
+}
+
+impl ClientFilter {
+    /// Parses a client list such as `7,42,1000-1010`. Whitespace around items is ignored.
+    pub fn parse(spec: &str) -> Result<Self, PaymentError> {
+        let invalid = |item: &str| {
+            PaymentError::InvalidCliArgument(format!("invalid client id or range '{}'", item))
+        };
+        let parse_id = |item: &str, id: &str| id.trim().parse::<ClientId>().map_err(|_| invalid(item));
+
+        let mut ranges = Vec::new();
+        for item in spec.split(',').map(str::trim) {
+            let range = match item.split_once('-') {
+                Some((start, end)) => parse_id(item, start)?..=parse_id(item, end)?,
+                None => {
+                    let id = parse_id(item, item)?;
+                    id..=id
+                }
+            };
+            if range.is_empty() {
+                return Err(invalid(item));
+            }
+            ranges.push(range);
+        }
+        Ok(ClientFilter {
+            ranges,
+            ids: HashSet::new(),
+        })
+    }
+
+    /// Returns a filter of exactly these clients.
+    pub fn from_ids(ids: impl IntoIterator<Item = ClientId>) -> Self {
+        ClientFilter {
+            ranges: Vec::new(),
+            ids: ids.into_iter().collect(),
+        }
+    }
+
+    /// Returns `true` if the client id is part of the filter.
+    pub fn contains(&self, client: ClientId) -> bool {
+        self.ids.contains(&client) || self.ranges.iter().any(|range| range.contains(&client))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::filter::ClientFilter;
+
+    #[test]
+    fn can_parse_ids_and_ranges() {
+        let filter = ClientFilter::parse("7, 42,1000-1010").unwrap();
+        assert!(filter.contains(7));
+        assert!(filter.contains(42));
+        assert!(filter.contains(1000));
+        assert!(filter.contains(1005));
+        assert!(filter.contains(1010));
+        assert!(!filter.contains(8));
+        assert!(!filter.contains(1011));
+
+        let single = ClientFilter::parse("5-5").unwrap();
+        assert!(single.contains(5));
+        assert_eq!(single, ClientFilter::parse("5").unwrap());
+    }
+
+    #[test]
+    fn can_build_from_ids() {
+        let filter = ClientFilter::from_ids([7, 42, 7]);
+        assert!(filter.contains(7));
+        assert!(filter.contains(42));
+        assert!(!filter.contains(8));
+        assert!(!ClientFilter::from_ids([]).contains(7));
+    }
+
+    #[test]
+    fn rejects_invalid_specs() {
+        for spec in ["", "1,", "a", "1-", "-3", "10-2", "5000000000", "1-2-3"] {
+            assert!(ClientFilter::parse(spec).is_err(), "'{}' should be rejected", spec);
+        }
+    }
+}
@@ -0,0 +1,357 @@
+use crate::{
+    errors::PaymentError,
+    parser::{parse_records, ParsedRecord},
+    payment_engine::PaymentEngine,
+    report::{write_report, ReportOptions},
+    store::TransactionStore,
+};
+use csv::StringRecord;
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Cursor, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver},
+    thread,
+};
+
+/// Most bytes read from a followed stream at once.
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Follows an append-only transactions CSV, handing out the rows appended since the last poll.
+///
+/// Only complete lines are parsed: a trailing line without its newline yet is kept back until
+/// the rest of it arrives. Rows are split on line breaks before being parsed, so quoted fields
+/// spanning several lines aren't supported in this mode.
+///
+/// The file is expected to only ever grow. If it shrinks or, on unix, the path starts pointing
+/// to another file (log rotation), polling fails instead of misparsing whatever is there now.
+///
+/// Named pipes and other inputs that can't seek are drained by a reader thread instead, and
+/// each poll takes whatever it has read since.
+pub struct FileFollower {
+    path: PathBuf,
+    source: Source,
+    /// Number of bytes of the file consumed so far, including the pending partial line.
+    offset: u64,
+    /// Start of a line whose newline hasn't been written yet.
+    pending: Vec<u8>,
+    /// The header line, once it has been read.
+    header: Option<String>,
+    raw_headers: Option<StringRecord>,
+    /// Number of lines consumed so far, including the header.
+    lines: u64,
+}
+
+/// Where the followed bytes come from.
+enum Source {
+    /// A regular file, read from the offset reached so far at each poll.
+    File(File),
+    /// A pipe or other stream, whose chunks are sent by a reader thread as they arrive.
+    Stream(Receiver<io::Result<Vec<u8>>>),
+}
+
+impl FileFollower {
+    /// Opens the file to follow, starting from its beginning. Opening a named pipe waits for
+    /// its writer.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, PaymentError> {
+        let path = path.as_ref().to_path_buf();
+        let file = File::open(&path).map_err(|err| path_error(&path, err))?;
+        let metadata = file.metadata().map_err(|err| path_error(&path, err))?;
+        if !metadata.is_file() {
+            return Ok(FileFollower::from_reader(path, file));
+        }
+        Ok(FileFollower::with_source(path, Source::File(file)))
+    }
+
+    /// Follows a stream that can't seek, such as a pipe, until its writer closes it. `name`
+    /// stands for the stream in errors.
+    pub fn from_reader(name: impl AsRef<Path>, mut reader: impl Read + Send + 'static) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = vec![0; STREAM_CHUNK_SIZE];
+            loop {
+                let chunk = match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => Ok(buf[..n].to_vec()),
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => Err(err),
+                };
+                let failed = chunk.is_err();
+                if sender.send(chunk).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        FileFollower::with_source(name.as_ref().to_path_buf(), Source::Stream(receiver))
+    }
+
+    fn with_source(path: PathBuf, source: Source) -> Self {
+        FileFollower {
+            path,
+            source,
+            offset: 0,
+            pending: Vec::new(),
+            header: None,
+            raw_headers: None,
+            lines: 0,
+        }
+    }
+
+    /// Returns the untrimmed header record, once the header line has been read.
+    pub fn raw_headers(&self) -> Option<&StringRecord> {
+        self.raw_headers.as_ref()
+    }
+
+    /// Reads the bytes appended since the last poll and parses the complete lines among them.
+    ///
+    /// Line numbers of the returned records refer to the whole file, as with `parse_records`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::FileError` if the file can't be read, was truncated or was
+    /// replaced by another file, and a `PaymentError::CsvParseError` if the header is invalid.
+    pub async fn poll(&mut self) -> Result<Vec<ParsedRecord>, PaymentError> {
+        let appended = match &self.source {
+            Source::File(file) => self.read_appended(file)?,
+            Source::Stream(receiver) => {
+                let mut appended = Vec::new();
+                while let Ok(chunk) = receiver.try_recv() {
+                    appended.extend(chunk.map_err(|err| path_error(&self.path, err))?);
+                }
+                appended
+            }
+        };
+        self.offset += appended.len() as u64;
+        self.pending.extend_from_slice(&appended);
+
+        let Some(end) = self.pending.iter().rposition(|b| *b == b'\n') else {
+            return Ok(Vec::new()); // no complete line yet
+        };
+        let complete: Vec<u8> = self.pending.drain(..=end).collect();
+        let mut complete = String::from_utf8(complete).map_err(|err| {
+            PaymentError::CsvParseError(format!("{}: {}", self.path.display(), err))
+        })?;
+        let line_count = complete.matches('\n').count() as u64;
+
+        let header = match &self.header {
+            Some(header) => header.clone(),
+            None => {
+                let header_end = complete.find('\n').unwrap_or(complete.len()) + 1;
+                let header: String = complete.drain(..header_end).collect();
+                self.header = Some(header.clone());
+                header
+            }
+        };
+        // Lines already consumed before this batch, the parser numbers the batch's rows from 2
+        let base = self.lines.saturating_sub(1);
+        self.lines += line_count;
+
+        let (raw_headers, records) = parse_records(Box::new(Cursor::new(header + &complete))).await?;
+        self.raw_headers.get_or_insert(raw_headers);
+        Ok(records
+            .map(|mut record| {
+                record.line += base;
+                record
+            })
+            .collect())
+    }
+
+    /// Reads the bytes appended to a regular file since the last poll.
+    fn read_appended(&self, mut file: &File) -> Result<Vec<u8>, PaymentError> {
+        self.check_same_file(file)?;
+        let len = file.metadata().map_err(|err| path_error(&self.path, err))?.len();
+        if len < self.offset {
+            return Err(path_error(
+                &self.path,
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("file was truncated from {} to {} bytes while being followed", self.offset, len),
+                ),
+            ));
+        }
+
+        let mut appended = Vec::new();
+        file.seek(SeekFrom::Start(self.offset))
+            .and_then(|_| file.take(len - self.offset).read_to_end(&mut appended))
+            .map_err(|err| path_error(&self.path, err))?;
+        Ok(appended)
+    }
+
+    #[cfg(unix)]
+    fn check_same_file(&self, file: &File) -> Result<(), PaymentError> {
+        use std::os::unix::fs::MetadataExt;
+
+        let current = fs::metadata(&self.path).map_err(|err| path_error(&self.path, err))?;
+        let followed = file.metadata().map_err(|err| path_error(&self.path, err))?;
+        if (current.dev(), current.ino()) != (followed.dev(), followed.ino()) {
+            return Err(path_error(
+                &self.path,
+                io::Error::other("file was replaced while being followed"),
+            ));
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn check_same_file(&self, _file: &File) -> Result<(), PaymentError> {
+        Ok(())
+    }
+}
+
+/// Writes the account states report to `path` atomically.
+///
+/// The report is written to a temporary file next to `path` which is then renamed over it, so
+/// readers of `path` see either the previous report or the new one, never a partial write.
+pub fn write_report_atomically<S: TransactionStore>(
+    engine: &PaymentEngine<S>,
+    path: &Path,
+    options: &ReportOptions,
+) -> Result<(), PaymentError> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let file = File::create(&tmp_path).map_err(|err| path_error(&tmp_path, err))?;
+    let mut w = BufWriter::new(file);
+    write_report(engine, &mut w, options)
+        .and_then(|_| w.flush())
+        .map_err(|err| path_error(&tmp_path, err))?;
+    fs::rename(&tmp_path, path).map_err(|err| path_error(path, err))
+}
+
+fn path_error(path: &Path, err: io::Error) -> PaymentError {
+    PaymentError::file(path, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        errors::PaymentError,
+        follow::{write_report_atomically, FileFollower},
+        parser::ParsedRecord,
+        payment_engine::PaymentEngine,
+        report::ReportOptions,
+    };
+    use std::{
+        fs,
+        io::{self, Write},
+        path::PathBuf,
+        time::Duration,
+    };
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("payment-engine-{}-{}", std::process::id(), name))
+    }
+
+    fn append(path: &PathBuf, text: &str) {
+        let mut file = fs::OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(text.as_bytes()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn report_follows_appended_transactions() -> Result<(), PaymentError> {
+        let input = temp_path("follow.csv");
+        let report = temp_path("follow-report.csv");
+        fs::write(&input, "type, client, tx, amount\ndeposit, 1, 1, 1.0\n").unwrap();
+
+        let mut follower = FileFollower::open(&input)?;
+        let mut engine = PaymentEngine::new();
+        let mut apply = async |follower: &mut FileFollower| -> Result<Vec<u64>, PaymentError> {
+            let mut lines = Vec::new();
+            for record in follower.poll().await? {
+                lines.push(record.line);
+                engine.process_transaction(record.transaction?).await?;
+            }
+            write_report_atomically(&engine, &report, &ReportOptions::default())?;
+            Ok(lines)
+        };
+
+        assert_eq!(apply(&mut follower).await?, vec![2]);
+        assert_eq!(
+            fs::read_to_string(&report).unwrap(),
+            "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n"
+        );
+
+        // the second row isn't complete yet and must wait for its newline
+        append(&input, "deposit, 1, 2, 2.0\ndeposit, 1, 3, 4");
+        assert_eq!(apply(&mut follower).await?, vec![3]);
+        assert!(fs::read_to_string(&report).unwrap().contains("1,3.0000,0.0000,3.0000,false"));
+
+        append(&input, ".0\n");
+        assert_eq!(apply(&mut follower).await?, vec![4]);
+        assert!(fs::read_to_string(&report).unwrap().contains("1,7.0000,0.0000,7.0000,false"));
+
+        assert!(apply(&mut follower).await?.is_empty());
+
+        fs::remove_file(&input).unwrap();
+        fs::remove_file(&report).unwrap();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_detect_truncation_and_rotation() -> Result<(), PaymentError> {
+        let input = temp_path("rotate.csv");
+        fs::write(&input, "type, client, tx, amount\ndeposit, 1, 1, 1.0\n").unwrap();
+
+        let mut follower = FileFollower::open(&input)?;
+        assert_eq!(follower.poll().await?.len(), 1);
+
+        fs::write(&input, "type, client, tx, amount\n").unwrap();
+        let err = follower.poll().await.err().unwrap();
+        assert!(err.to_string().contains("truncated"));
+
+        if cfg!(unix) {
+            let mut follower = FileFollower::open(&input)?;
+            let rotated = temp_path("rotate.csv.1");
+            fs::rename(&input, &rotated).unwrap();
+            fs::write(&input, "type, client, tx, amount\n").unwrap();
+            let err = follower.poll().await.err().unwrap();
+            assert!(err.to_string().contains("replaced"));
+            fs::remove_file(&rotated).unwrap();
+        }
+
+        fs::remove_file(&input).unwrap();
+        Ok(())
+    }
+
+    /// Polls a stream follower until `rows` records came in, as its reader thread may lag.
+    async fn poll_rows(follower: &mut FileFollower, rows: usize) -> Result<Vec<ParsedRecord>, PaymentError> {
+        let mut records = Vec::new();
+        for _ in 0..500 {
+            records.extend(follower.poll().await?);
+            if records.len() >= rows {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        Ok(records)
+    }
+
+    #[tokio::test]
+    async fn pipes_are_followed_across_write_boundaries() -> Result<(), PaymentError> {
+        let (reader, mut writer) = io::pipe().unwrap();
+        let mut follower = FileFollower::from_reader("pipe", reader);
+        let mut engine = PaymentEngine::new();
+
+        // the dispute is cut in the middle of its last field
+        writer.write_all(b"type, client, tx, amount\ndeposit, 1, 1, 5.0\ndispute, 1,").unwrap();
+        let records = poll_rows(&mut follower, 1).await?;
+        assert_eq!(records.iter().map(|record| record.line).collect::<Vec<_>>(), vec![2]);
+        for record in records {
+            engine.process_transaction(record.transaction?).await?;
+        }
+
+        writer.write_all(b" 1\n").unwrap();
+        let records = poll_rows(&mut follower, 1).await?;
+        assert_eq!(records.iter().map(|record| record.line).collect::<Vec<_>>(), vec![3]);
+        for record in records {
+            engine.process_transaction(record.transaction?).await?;
+        }
+        let client = &engine.clients[&1];
+        assert_eq!((client.available.to_f64(), client.held.to_f64()), (0.0, 5.0));
+
+        drop(writer);
+        assert!(poll_rows(&mut follower, 0).await?.is_empty());
+        Ok(())
+    }
+}
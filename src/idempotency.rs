@@ -0,0 +1,101 @@
+//! The idempotency keys of recently applied transactions, which catch a transaction resent by an
+//! upstream retry under a new tx id.
+//!
+//! Without a journal, the engine remembers the keys of the last
+//! `EngineConfig::max_idempotency_keys` transactions it applied, the least recently seen key
+//! being forgotten first. With a journal, keys are journaled along with the tx ids and never
+//! forgotten, see `journal`.
+
+use std::collections::{BTreeMap, HashMap};
+
+/// A bounded set of idempotency keys, evicting the least recently seen one when full.
+#[derive(Debug, Default, Clone)]
+pub struct RecentKeys {
+    /// When each key was last seen, on `clock`.
+    seen: HashMap<String, u64>,
+    /// The keys by when they were last seen, oldest first.
+    by_age: BTreeMap<u64, String>,
+    clock: u64,
+}
+
+impl RecentKeys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns whether `key` is remembered, counting this as the key being seen again.
+    pub fn check(&mut self, key: &str) -> bool {
+        let Some(stamp) = self.seen.get_mut(key) else {
+            return false;
+        };
+        self.clock += 1;
+        let key = self.by_age.remove(stamp).expect("every key has an age");
+        *stamp = self.clock;
+        self.by_age.insert(self.clock, key);
+        true
+    }
+
+    /// Remembers `key`, forgetting the least recently seen keys beyond `capacity`.
+    pub fn insert(&mut self, key: String, capacity: usize) {
+        self.clock += 1;
+        if let Some(stamp) = self.seen.insert(key.clone(), self.clock) {
+            self.by_age.remove(&stamp);
+        }
+        self.by_age.insert(self.clock, key);
+        while self.seen.len() > capacity {
+            let Some((_, oldest)) = self.by_age.pop_first() else {
+                break;
+            };
+            self.seen.remove(&oldest);
+        }
+    }
+
+    /// Forgets `key`, e.g. when the transaction it came with is undone.
+    pub fn remove(&mut self, key: &str) {
+        if let Some(stamp) = self.seen.remove(key) {
+            self.by_age.remove(&stamp);
+        }
+    }
+
+    /// Remembers the keys of `other` as well, as seen after every key of this set.
+    pub fn extend(&mut self, other: RecentKeys, capacity: usize) {
+        for key in other.by_age.into_values() {
+            self.insert(key, capacity);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::idempotency::RecentKeys;
+
+    #[test]
+    fn the_least_recently_seen_key_is_forgotten_first() {
+        let mut keys = RecentKeys::new();
+        for key in ["a", "b", "c"] {
+            keys.insert(key.to_owned(), 3);
+        }
+        // seeing "a" again makes "b" the oldest
+        assert!(keys.check("a"));
+        keys.insert("d".to_owned(), 3);
+        assert_eq!(keys.len(), 3);
+        assert!(!keys.check("b"));
+        assert!(keys.check("a") && keys.check("c") && keys.check("d"));
+
+        keys.remove("c");
+        assert!(!keys.check("c"));
+        let mut more = RecentKeys::new();
+        more.insert("e".to_owned(), 3);
+        keys.extend(more, 2);
+        assert_eq!(keys.len(), 2);
+        assert!(keys.check("d") && keys.check("e"));
+    }
+}
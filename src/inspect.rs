@@ -0,0 +1,188 @@
+//! Read-only queries over a clients dump, as written with `--dump-clients-json`, answered from
+//! the accounts alone without replaying any transaction.
+
+use crate::{
+    errors::PaymentError,
+    filter::ClientFilter,
+    payment_engine::PaymentEngine,
+    report::{self, OutputOrder, ReportFormat, ReportOptions},
+    types::Client,
+};
+use std::io::{self, Write};
+
+/// A question asked of a clients dump.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InspectQuery {
+    /// The accounts of these clients, or of every client.
+    Accounts(Option<ClientFilter>),
+    /// The number of accounts and the funds across them.
+    Summary,
+    /// The accounts with an open dispute.
+    OpenDisputes,
+    /// The locked accounts.
+    Locked,
+}
+
+/// Loads the accounts of a clients dump into an engine of their own.
+///
+/// # Errors
+///
+/// Returns a `PaymentError::JsonError` if the JSON isn't a valid clients dump.
+pub fn load(json: &str) -> Result<PaymentEngine, PaymentError> {
+    let mut engine = PaymentEngine::new();
+    engine.load_clients_json(json)?;
+    Ok(engine)
+}
+
+/// Writes the answer to `query`: the summary as one figure per line, or else the extended
+/// report of the accounts asked for in `format`, in ascending client id order.
+pub fn inspect<W: Write>(
+    engine: &PaymentEngine,
+    query: &InspectQuery,
+    format: ReportFormat,
+    w: W,
+) -> io::Result<()> {
+    let clients = match query {
+        InspectQuery::Summary => return write_summary(engine, w),
+        InspectQuery::Accounts(clients) => clients.clone(),
+        InspectQuery::OpenDisputes => Some(matching(engine, |client| client.open_disputes > 0)),
+        InspectQuery::Locked => Some(matching(engine, |client| client.locked)),
+    };
+    let options = ReportOptions {
+        format,
+        order: OutputOrder::ClientId,
+        extended: true,
+        clients: clients.as_ref(),
+        ..Default::default()
+    };
+    report::write_report(engine, w, &options)
+}
+
+/// Returns the clients whose account matches `predicate`.
+fn matching(engine: &PaymentEngine, predicate: impl Fn(&Client) -> bool) -> ClientFilter {
+    ClientFilter::from_ids(
+        engine
+            .clients
+            .iter()
+            .filter(|(_, client)| predicate(client))
+            .map(|(id, _)| *id),
+    )
+}
+
+/// Writes the number of accounts, the funds across them and the accounts locked, frozen or
+/// with open disputes. Open disputes are counted from the accounts' counters, as the dump holds
+/// no transaction.
+fn write_summary<W: Write>(engine: &PaymentEngine, mut w: W) -> io::Result<()> {
+    let clients = engine.clients.values();
+    let frozen = clients.clone().filter(|client| client.frozen).count();
+    let open_disputes: u64 = clients.clone().map(|client| u64::from(client.open_disputes)).sum();
+    // folded from 0.0, as an empty f64 sum is -0.0
+    let open_dispute_held = clients.fold(0.0, |held, client| held + client.open_dispute_held.to_f64());
+
+    writeln!(w, "clients: {}", engine.clients.len())?;
+    writeln!(
+        w,
+        "client funds: {:.4} ({:.4} available, {:.4} held)",
+        engine.total_funds(),
+        engine.total_available(),
+        engine.total_held()
+    )?;
+    writeln!(w, "locked accounts: {}", engine.locked_count())?;
+    writeln!(w, "frozen accounts: {}", frozen)?;
+    writeln!(w, "open disputes: {} ({:.4} held)", open_disputes, open_dispute_held)?;
+    w.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        errors::PaymentError,
+        filter::ClientFilter,
+        inspect::{inspect, load, InspectQuery},
+        parser::parse_transactions,
+        payment_engine::PaymentEngine,
+        report::ReportFormat,
+    };
+
+    const HEADER: &str =
+        "client,available,held,total,locked,open_disputes,disputes,chargebacks,open_dispute_held,status_reason\n";
+
+    async fn dump() -> Result<String, PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 10.0
+        deposit, 1, 2, 5.0
+        dispute, 1, 2
+        chargeback, 1, 2
+        deposit, 2, 3, 20.0
+        dispute, 2, 3
+        deposit, 3, 4, 30.0
+        withdrawal, 3, 5, 7.5";
+        let mut engine = PaymentEngine::new();
+        let str_buf = stringreader::StringReader::new(csv);
+        engine.process_all(parse_transactions(Box::new(str_buf)).await?).await;
+        engine.clients_json()
+    }
+
+    fn answer(engine: &PaymentEngine, query: InspectQuery) -> String {
+        let mut out = Vec::new();
+        inspect(engine, &query, ReportFormat::Csv, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[tokio::test]
+    async fn can_answer_every_query_from_a_dump() -> Result<(), PaymentError> {
+        let engine = load(&dump().await?)?;
+        assert_eq!(
+            answer(&engine, InspectQuery::Accounts(None)),
+            format!(
+                "{}1,10.0000,0.0000,10.0000,true,0,1,1,0.0000,chargeback of tx 2\n\
+                 2,0.0000,20.0000,20.0000,false,1,1,0,20.0000,\n\
+                 3,22.5000,0.0000,22.5000,false,0,0,0,0.0000,\n",
+                HEADER
+            )
+        );
+        assert_eq!(
+            answer(&engine, InspectQuery::Accounts(Some(ClientFilter::parse("3").unwrap()))),
+            format!("{}3,22.5000,0.0000,22.5000,false,0,0,0,0.0000,\n", HEADER)
+        );
+        assert_eq!(
+            answer(&engine, InspectQuery::OpenDisputes),
+            format!("{}2,0.0000,20.0000,20.0000,false,1,1,0,20.0000,\n", HEADER)
+        );
+        assert_eq!(
+            answer(&engine, InspectQuery::Locked),
+            format!("{}1,10.0000,0.0000,10.0000,true,0,1,1,0.0000,chargeback of tx 2\n", HEADER)
+        );
+        assert_eq!(
+            answer(&engine, InspectQuery::Summary),
+            "clients: 3
+client funds: 52.5000 (32.5000 available, 20.0000 held)
+locked accounts: 1
+frozen accounts: 0
+open disputes: 1 (20.0000 held)
+"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn summarizes_an_empty_dump() -> Result<(), PaymentError> {
+        let engine = load(&PaymentEngine::new().clients_json()?)?;
+        assert_eq!(
+            answer(&engine, InspectQuery::Summary),
+            "clients: 0
+client funds: 0.0000 (0.0000 available, 0.0000 held)
+locked accounts: 0
+frozen accounts: 0
+open disputes: 0 (0.0000 held)
+"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_what_is_not_a_dump() {
+        assert!(matches!(load("[1, 2]"), Err(PaymentError::JsonError(_))));
+        assert!(matches!(load("{\"1\": {\"available\": true}}"), Err(PaymentError::JsonError(_))));
+    }
+}
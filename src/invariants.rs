@@ -0,0 +1,289 @@
+use crate::{
+    amount::{self, Amount},
+    types::{Client, ClientId},
+};
+use std::{collections::HashMap, fmt};
+
+/// Tolerance used when comparing sums of balances, which are floating point.
+pub const BALANCE_EPSILON: f64 = 1e-9;
+
+/// A client account whose balances contradict each other.
+#[derive(Debug, Clone, PartialEq)]
+pub struct InvariantViolation {
+    pub client: ClientId,
+    pub kind: ViolationKind,
+}
+
+/// The invariant a client account failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ViolationKind {
+    /// `total` differs from `available + held`.
+    TotalMismatch { available: Amount, held: Amount, total: Amount },
+    /// `held` went below zero.
+    NegativeHeld { held: Amount },
+    /// `held` is less than what the dispute `tx` holds, found by its resolve or chargeback.
+    HeldBelowDispute { tx: u32, held: Amount, disputed: Amount },
+}
+
+impl fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            ViolationKind::TotalMismatch {
+                available,
+                held,
+                total,
+            } => write!(
+                f,
+                "client {}: total {:.4} != available {:.4} + held {:.4}",
+                self.client, total, available, held
+            ),
+            ViolationKind::NegativeHeld { held } => {
+                write!(f, "client {}: negative held {:.4}", self.client, held)
+            }
+            ViolationKind::HeldBelowDispute { tx, held, disputed } => write!(
+                f,
+                "client {}: held {:.4} < {:.4} held by the dispute of tx {}",
+                self.client, held, disputed, tx
+            ),
+        }
+    }
+}
+
+/// Checks the invariants of a single client account.
+pub fn check_client(id: ClientId, client: &Client) -> Vec<InvariantViolation> {
+    let mut violations = Vec::new();
+    if client.available.checked_add(client.held).map_or(true, |sum| sum != client.total) {
+        violations.push(InvariantViolation {
+            client: id,
+            kind: ViolationKind::TotalMismatch {
+                available: client.available,
+                held: client.held,
+                total: client.total,
+            },
+        });
+    }
+    if client.held.is_negative() {
+        violations.push(InvariantViolation {
+            client: id,
+            kind: ViolationKind::NegativeHeld { held: client.held },
+        });
+    }
+    violations
+}
+
+/// Balances summed over a set of client accounts.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Totals {
+    pub available: f64,
+    pub held: f64,
+    pub total: f64,
+    /// Number of locked accounts.
+    pub locked: u64,
+}
+
+impl Totals {
+    /// Sums the balances of the given accounts.
+    pub fn of<'a>(clients: impl IntoIterator<Item = &'a Client>) -> Self {
+        let mut totals = Totals::default();
+        for client in clients {
+            totals.add(client);
+        }
+        totals
+    }
+
+    /// Adds an account to the sums.
+    pub(crate) fn add(&mut self, client: &Client) {
+        self.available += client.available.to_f64();
+        self.held += client.held.to_f64();
+        self.total += client.total.to_f64();
+        self.locked += u64::from(client.locked);
+    }
+
+    /// Takes an account, as added earlier, back out of the sums.
+    pub(crate) fn remove(&mut self, client: &Client) {
+        self.available -= client.available.to_f64();
+        self.held -= client.held.to_f64();
+        self.total -= client.total.to_f64();
+        self.locked = self.locked.saturating_sub(u64::from(client.locked));
+    }
+
+    fn matches(&self, other: &Totals) -> bool {
+        // running sums collect rounding errors, allow for one per account
+        let tolerance = BALANCE_EPSILON * 1e3;
+        (self.available - other.available).abs() <= tolerance
+            && (self.held - other.held).abs() <= tolerance
+            && (self.total - other.total).abs() <= tolerance
+            && self.locked == other.locked
+    }
+}
+
+impl fmt::Display for Totals {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "available {:.4}, held {:.4}, total {:.4}, {} locked",
+            self.available, self.held, self.total, self.locked
+        )
+    }
+}
+
+/// Running totals of an engine that disagree with the sums of its accounts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TotalsDrift {
+    pub tracked: Totals,
+    pub actual: Totals,
+}
+
+impl fmt::Display for TotalsDrift {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "running totals ({}) != account sums ({})", self.tracked, self.actual)
+    }
+}
+
+/// Checks running totals against a full recomputation from the accounts.
+pub fn check_totals(tracked: &Totals, clients: &HashMap<ClientId, Client>) -> Option<TotalsDrift> {
+    let actual = Totals::of(clients.values());
+    (!tracked.matches(&actual)).then_some(TotalsDrift {
+        tracked: *tracked,
+        actual,
+    })
+}
+
+/// The money the applied transactions brought into and took out of the accounts, summed
+/// exactly as they are applied, in units of `amount::SCALE` decimal places.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MoneyFlows {
+    /// Totals of the accounts loaded from an earlier state, or merged from another engine's.
+    pub opening: i128,
+    pub deposits: i128,
+    /// Withdrawals, captured escrow holds and settled pending withdrawals.
+    pub withdrawals: i128,
+    /// Taken back by chargebacks, shortfalls still owed included, less what representments
+    /// gave back.
+    pub charged_back: i128,
+    pub chargeback_fees: i128,
+    /// Balance corrections, interest accruals and balances written off by chargebacks, signed.
+    pub adjustments: i128,
+}
+
+impl MoneyFlows {
+    /// Returns the total the accounts should hold, in units.
+    pub fn expected_total(&self) -> i128 {
+        self.opening + self.deposits - self.withdrawals - self.charged_back - self.chargeback_fees + self.adjustments
+    }
+
+    /// Adds the flows of another engine, merged into this one.
+    pub(crate) fn merge(&mut self, other: &MoneyFlows) {
+        self.opening += other.opening;
+        self.deposits += other.deposits;
+        self.withdrawals += other.withdrawals;
+        self.charged_back += other.charged_back;
+        self.chargeback_fees += other.chargeback_fees;
+        self.adjustments += other.adjustments;
+    }
+}
+
+/// Converts an amount to the units `MoneyFlows` are summed in.
+pub(crate) fn units(amount: Amount) -> i128 {
+    amount.units().into()
+}
+
+/// The total the money flows account for, next to the total the accounts hold. Both are exact,
+/// so the ledger balances only if they are equal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ledger {
+    pub flows: MoneyFlows,
+    /// The sum of the accounts' totals, in units.
+    pub accounts: i128,
+}
+
+impl Ledger {
+    /// Returns by how much the accounts hold more than the flows account for, in units.
+    pub fn delta(&self) -> i128 {
+        self.accounts - self.flows.expected_total()
+    }
+
+    pub fn balances(&self) -> bool {
+        self.delta() == 0
+    }
+
+    /// Returns both sides of the ledger and their delta as a JSON object, for `--stats-json`.
+    pub fn to_json(&self) -> String {
+        let flows = &self.flows;
+        format!(
+            "{{\"deposits\":{},\"withdrawals\":{},\"chargebacks\":{},\"chargeback_fees\":{},\"adjustments\":{},\
+             \"opening\":{},\"expected_total\":{},\"accounts_total\":{},\"delta\":{}}}",
+            Units(flows.deposits),
+            Units(flows.withdrawals),
+            Units(flows.charged_back),
+            Units(flows.chargeback_fees),
+            Units(flows.adjustments),
+            Units(flows.opening),
+            Units(flows.expected_total()),
+            Units(self.accounts),
+            Units(self.delta())
+        )
+    }
+}
+
+impl fmt::Display for Ledger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flows = &self.flows;
+        write!(
+            f,
+            "deposits {} - withdrawals {} - chargebacks {} - fees {} + adjustments {} + opening {} = {}, \
+             accounts total {}, delta {}",
+            Units(flows.deposits),
+            Units(flows.withdrawals),
+            Units(flows.charged_back),
+            Units(flows.chargeback_fees),
+            Units(flows.adjustments),
+            Units(flows.opening),
+            Units(flows.expected_total()),
+            Units(self.accounts),
+            Units(self.delta())
+        )
+    }
+}
+
+/// Writes a number of units with `amount::SCALE` decimal places, as `Amount` does.
+struct Units(i128);
+
+impl fmt::Display for Units {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let unit = 10u128.pow(amount::SCALE);
+        let units = self.0.unsigned_abs();
+        write!(f, "{}{}.{:0width$}", sign, units / unit, units % unit, width = amount::SCALE as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        amount::amount,
+        invariants::{check_client, ViolationKind},
+        types::Client,
+    };
+
+    #[test]
+    fn can_detect_inconsistent_balances() {
+        let mut client = Client::new();
+        client.available = amount(1.0);
+        client.total = amount(1.0);
+        assert!(check_client(1, &client).is_empty());
+
+        client.held = amount(-2.0);
+        client.total = amount(-1.0);
+        let violations = check_client(1, &client);
+        assert_eq!(violations.len(), 1);
+        assert_eq!(violations[0].kind, ViolationKind::NegativeHeld { held: amount(-2.0) });
+
+        client.total = amount(5.0);
+        assert_eq!(check_client(1, &client).len(), 2);
+        assert_eq!(
+            check_client(1, &client)[0].to_string(),
+            "client 1: total 5.0000 != available 1.0000 + held -2.0000"
+        );
+    }
+}
@@ -0,0 +1,211 @@
+//! Corrective batches backing out a batch of transactions applied by mistake.
+
+use crate::{
+    errors::PaymentError,
+    parser::parse_records,
+    payment_engine::PaymentEngine,
+    report::csv_field,
+    store::TransactionStore,
+    types::{ProcessOutcome, Transaction, TransactionType},
+};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+};
+
+/// Header of the report of the rows an inverse can't back out.
+pub const NON_INVERTIBLE_HEADER: &str = "line,type,client,tx,reason";
+
+/// An applied row of a batch that its inverse doesn't back out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NonInvertible {
+    /// Line of the row in the batch (the header is line 1).
+    pub line: u64,
+    pub transaction: Transaction,
+    pub reason: String,
+}
+
+impl NonInvertible {
+    /// Writes the row as a row of the report, under `NON_INVERTIBLE_HEADER`.
+    pub fn write_row<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let txn = &self.transaction;
+        writeln!(
+            w,
+            "{},{},{},{},{}",
+            self.line,
+            txn.r#type.as_str(),
+            txn.client,
+            txn.tx,
+            csv_field(&self.reason)
+        )
+    }
+}
+
+impl fmt::Display for NonInvertible {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let txn = &self.transaction;
+        write!(
+            f,
+            "line {}: {} of tx {} by client {}: {}",
+            self.line,
+            txn.r#type.as_str(),
+            txn.tx,
+            txn.client,
+            self.reason
+        )
+    }
+}
+
+/// Applies a batch to `engine`, holding the accounts as they were before the batch, and returns
+/// the transactions that back it out, in the order to apply them, with the rows they don't back
+/// out, in batch order.
+///
+/// Only the rows the engine applies are inverted: deposits become withdrawals and withdrawals
+/// deposits, in reverse order so that every account can cover them, with tx ids following the
+/// highest one of the batch. Disputes, resolves, chargebacks, representments and escrow
+/// operations are never inverted. The inverse is replayed on the engine, and a row whose
+/// inverse would be rejected, e.g. a deposit since disputed, is reported instead of inverted.
+pub async fn invert<S: TransactionStore>(
+    mut engine: PaymentEngine<S>,
+    batch: Box<dyn Read>,
+) -> Result<(Vec<Transaction>, Vec<NonInvertible>), PaymentError> {
+    let (_, records) = parse_records(batch).await?;
+    let mut applied = Vec::new();
+    let mut non_invertible = Vec::new();
+    let mut last_tx = 0;
+    for record in records {
+        // a batch that can't be read in full can't be backed out safely
+        let txn = record.transaction?;
+        last_tx = last_tx.max(txn.tx);
+        if engine.process_transaction(txn.clone()).await? != ProcessOutcome::Applied {
+            continue;
+        }
+        match not_invertible(txn.r#type) {
+            None => applied.push((record.line, txn)),
+            Some(reason) => non_invertible.push(NonInvertible {
+                line: record.line,
+                transaction: txn,
+                reason: reason.to_owned(),
+            }),
+        }
+    }
+
+    let mut inverse = Vec::new();
+    for (line, txn) in applied.into_iter().rev() {
+        let tx = last_tx.checked_add(1).ok_or_else(|| {
+            PaymentError::CsvParseError(format!("no tx id left after {} for the inverse", last_tx))
+        })?;
+        let amount = txn.amount.unwrap_or_default();
+        let reversal = match txn.r#type {
+            TransactionType::Deposit => Transaction::withdrawal(txn.client, tx, amount),
+            _ => Transaction::deposit(txn.client, tx, amount),
+        };
+        match engine.process_transaction(reversal.clone()).await? {
+            ProcessOutcome::Applied => {
+                last_tx = tx;
+                inverse.push(reversal);
+            }
+            ProcessOutcome::Ignored(reason) => non_invertible.push(NonInvertible {
+                line,
+                transaction: txn,
+                reason: format!("its reversal would be rejected as {}", reason.as_str()),
+            }),
+        }
+    }
+    non_invertible.sort_by_key(|row| row.line);
+    Ok((inverse, non_invertible))
+}
+
+/// Returns why transactions of a type are never inverted, `None` for deposits and withdrawals.
+fn not_invertible(r#type: TransactionType) -> Option<&'static str> {
+    match r#type {
+        TransactionType::Deposit | TransactionType::Withdrawal => None,
+        TransactionType::Dispute => Some("a dispute is settled by a resolve or a chargeback, not reversed"),
+        TransactionType::Resolve => Some("a resolve closes its dispute for good"),
+        TransactionType::Chargeback => Some("a chargeback is only reversed by a representment"),
+        TransactionType::Representment => Some("a representment closes its chargeback for good"),
+        TransactionType::Hold | TransactionType::Release | TransactionType::Capture => {
+            Some("escrow operations are settled by the escrow, not reversed")
+        }
+        TransactionType::WithdrawalPending
+        | TransactionType::WithdrawalSettle
+        | TransactionType::WithdrawalCancel => Some("a pending withdrawal is settled or cancelled, not reversed"),
+        TransactionType::Accrue => Some("an accrual is paid on every account, not reversed"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        errors::PaymentError,
+        invert::invert,
+        parser::parse_transactions,
+        payment_engine::PaymentEngine,
+        types::{Transaction, TransactionType},
+    };
+
+    const BATCH: &str = "type, client, tx, amount
+        deposit, 1, 1, 10.0
+        deposit, 2, 2, 4.0
+        withdrawal, 1, 3, 2.5
+        withdrawal, 2, 4, 9.0
+        deposit, 1, 5, 1.0";
+
+    #[tokio::test]
+    async fn applying_the_inverse_restores_the_balances() -> Result<(), PaymentError> {
+        let mut prior = PaymentEngine::new();
+        prior.process_transaction(Transaction::deposit(2, 100, 3.0)).await?;
+        let str_buf = stringreader::StringReader::new(BATCH);
+        let (inverse, non_invertible) = invert(prior, Box::new(str_buf)).await?;
+
+        // the rejected withdrawal of tx 4 has nothing to back out
+        assert!(non_invertible.is_empty());
+        assert_eq!(
+            inverse,
+            [
+                Transaction::withdrawal(1, 6, 1.0),
+                Transaction::deposit(1, 7, 2.5),
+                Transaction::withdrawal(2, 8, 4.0),
+                Transaction::withdrawal(1, 9, 10.0),
+            ]
+        );
+
+        let mut engine = PaymentEngine::new();
+        engine.process_transaction(Transaction::deposit(2, 100, 3.0)).await?;
+        let str_buf = stringreader::StringReader::new(BATCH);
+        engine.process_all(parse_transactions(Box::new(str_buf)).await?).await.into_result()?;
+        for txn in inverse {
+            engine.process_transaction(txn).await?;
+        }
+        let balances = |client| {
+            let account = engine.clients.get(&client)?;
+            Some((account.available.to_f64(), account.total.to_f64()))
+        };
+        assert_eq!(balances(1), Some((0.0, 0.0)));
+        assert_eq!(balances(2), Some((3.0, 3.0)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn disputes_and_what_they_hold_are_reported() -> Result<(), PaymentError> {
+        let batch = "type, client, tx, amount
+            deposit, 1, 1, 10.0
+            deposit, 1, 2, 5.0
+            dispute, 1, 1";
+        let str_buf = stringreader::StringReader::new(batch);
+        let (inverse, non_invertible) = invert(PaymentEngine::new(), Box::new(str_buf)).await?;
+
+        // the deposit of tx 2 comes out, the held one can't
+        assert_eq!(inverse, [Transaction::withdrawal(1, 3, 5.0)]);
+        let rows: Vec<_> = non_invertible.iter().map(|row| row.to_string()).collect();
+        assert_eq!(
+            rows,
+            [
+                "line 2: deposit of tx 1 by client 1: its reversal would be rejected as insufficient_funds",
+                "line 4: dispute of tx 1 by client 1: a dispute is settled by a resolve or a chargeback, not reversed",
+            ]
+        );
+        assert_eq!(non_invertible[1].transaction.r#type, TransactionType::Dispute);
+        Ok(())
+    }
+}
@@ -0,0 +1,397 @@
+//! A journal of the deposits and withdrawals already applied, kept on disk across runs so that a
+//! file submitted twice isn't applied twice.
+//!
+//! The journal is a file of its own, apart from any dump of the accounts: a 4-byte magic
+//! followed by the applied tx ids, four little-endian bytes each, in the order they were applied.
+//!
+//! Ids are journaled as they are applied, but only written and fsynced every `sync_every` ids and
+//! by `TxJournal::sync`, which callers run before saving the accounts. A crash before a sync loses
+//! the ids along with the accounts they changed, so the next run applies them once more, from
+//! the accounts saved before. A crash between a sync and the saving of the accounts leaves ids
+//! journaled whose changes were lost: the next run rejects them again as
+//! `IgnoreReason::AlreadyProcessed`. Ids are never applied twice.
+//!
+//! The idempotency keys of the transactions applied are journaled the same way, in a second file
+//! next to the journal, named after it with a `.keys` suffix: a 4-byte magic followed by the
+//! keys, each one a little-endian `u16` length and the key's bytes.
+
+use crate::errors::PaymentError;
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+/// Ids journaled between two syncs, by default.
+pub const DEFAULT_SYNC_EVERY: usize = 10_000;
+
+/// First bytes of every journal file.
+const MAGIC: &[u8; 4] = b"PEJ1";
+
+/// First bytes of every file of journaled idempotency keys.
+const KEYS_MAGIC: &[u8; 4] = b"PEK1";
+
+/// The tx ids of the deposits and withdrawals already applied, as read from a journal file and
+/// appended to it.
+#[derive(Debug)]
+pub struct TxJournal {
+    path: PathBuf,
+    file: File,
+    ids: HashSet<u32>,
+    /// Ids journaled since the last sync, not yet written.
+    pending: Vec<u32>,
+    keys_path: PathBuf,
+    /// The file of journaled idempotency keys, opened with the first key journaled if it
+    /// doesn't exist yet.
+    keys_file: Option<File>,
+    keys: HashSet<String>,
+    /// Keys journaled since the last sync, not yet written.
+    pending_keys: Vec<String>,
+    sync_every: usize,
+}
+
+impl TxJournal {
+    /// Opens the journal at `path`, creating it if it doesn't exist, to be synced every
+    /// `sync_every` journaled ids.
+    ///
+    /// An id cut short at the end of the file, by a crash in the middle of a sync, is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::FileError` if the file can't be opened, read or written, or a
+    /// `PaymentError::StorageError` if it isn't a journal.
+    pub fn open(path: impl AsRef<Path>, sync_every: usize) -> Result<Self, PaymentError> {
+        let path = path.as_ref();
+        let file_error = |err| PaymentError::file(path, err);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(file_error)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes).map_err(file_error)?;
+
+        if bytes.is_empty() {
+            file.write_all(MAGIC).map_err(file_error)?;
+            file.sync_data().map_err(file_error)?;
+        } else if !bytes.starts_with(MAGIC) {
+            return Err(PaymentError::StorageError(format!(
+                "{} isn't a tx journal",
+                path.display()
+            )));
+        }
+        let entries = bytes.get(MAGIC.len()..).unwrap_or_default();
+        let whole = entries.len() - entries.len() % 4;
+        if whole < entries.len() {
+            file.set_len((MAGIC.len() + whole) as u64).map_err(file_error)?;
+        }
+        let ids = entries[..whole]
+            .chunks_exact(4)
+            .map(|id| u32::from_le_bytes([id[0], id[1], id[2], id[3]]))
+            .collect();
+        let mut keys_path = path.as_os_str().to_owned();
+        keys_path.push(".keys");
+        let keys_path = PathBuf::from(keys_path);
+        let (keys_file, keys) = match keys_path.exists() {
+            true => {
+                let (file, keys) = open_keys(&keys_path)?;
+                (Some(file), keys)
+            }
+            false => (None, HashSet::new()),
+        };
+        Ok(TxJournal {
+            path: path.to_owned(),
+            file,
+            ids,
+            pending: Vec::new(),
+            keys_path,
+            keys_file,
+            keys,
+            pending_keys: Vec::new(),
+            sync_every: sync_every.max(1),
+        })
+    }
+
+    /// Returns whether the transaction `tx` was already applied.
+    pub fn contains(&self, tx: u32) -> bool {
+        self.ids.contains(&tx)
+    }
+
+    /// Returns whether a transaction with the idempotency key `key` was already applied.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.keys.contains(key)
+    }
+
+    /// Returns the number of ids journaled, synced or not.
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Journals the transaction `tx` as applied, syncing the journal once `sync_every` ids are
+    /// waiting.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `sync`.
+    pub fn record(&mut self, tx: u32) -> Result<(), PaymentError> {
+        if self.ids.insert(tx) {
+            self.pending.push(tx);
+        }
+        if self.pending.len() >= self.sync_every {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Journals the idempotency key of an applied transaction, syncing the journal once
+    /// `sync_every` keys are waiting.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::StorageError` if the key is longer than `u16::MAX` bytes, or the
+    /// errors of `sync`.
+    pub fn record_key(&mut self, key: String) -> Result<(), PaymentError> {
+        if key.len() > usize::from(u16::MAX) {
+            return Err(PaymentError::StorageError(format!(
+                "idempotency key of {} bytes can't be journaled",
+                key.len()
+            )));
+        }
+        if self.keys.insert(key.clone()) {
+            self.pending_keys.push(key);
+        }
+        if self.pending_keys.len() >= self.sync_every {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Appends the ids and keys journaled since the last sync to their files and fsyncs them.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::FileError` if a file can't be written. The ids or keys are then
+    /// still waiting for the next sync.
+    pub fn sync(&mut self) -> Result<(), PaymentError> {
+        if !self.pending.is_empty() {
+            let bytes: Vec<u8> = self.pending.iter().flat_map(|id| id.to_le_bytes()).collect();
+            let file_error = |err| PaymentError::file(&self.path, err);
+            self.file.write_all(&bytes).map_err(file_error)?;
+            self.file.sync_data().map_err(file_error)?;
+            self.pending.clear();
+        }
+        if !self.pending_keys.is_empty() {
+            let file_error = |err| PaymentError::file(&self.keys_path, err);
+            let file = match &mut self.keys_file {
+                Some(file) => file,
+                None => self.keys_file.insert(open_keys(&self.keys_path)?.0),
+            };
+            let mut bytes = Vec::new();
+            for key in &self.pending_keys {
+                // keys are checked to fit when journaled
+                bytes.extend((key.len() as u16).to_le_bytes());
+                bytes.extend(key.as_bytes());
+            }
+            file.write_all(&bytes).map_err(file_error)?;
+            file.sync_data().map_err(file_error)?;
+            self.pending_keys.clear();
+        }
+        Ok(())
+    }
+}
+
+/// Opens the file of journaled idempotency keys at `path`, creating it if it doesn't exist, and
+/// reads its keys. A key cut short at the end of the file is dropped.
+fn open_keys(path: &Path) -> Result<(File, HashSet<String>), PaymentError> {
+    let file_error = |err| PaymentError::file(path, err);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)
+        .map_err(file_error)?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes).map_err(file_error)?;
+
+    if bytes.is_empty() {
+        file.write_all(KEYS_MAGIC).map_err(file_error)?;
+        file.sync_data().map_err(file_error)?;
+    } else if !bytes.starts_with(KEYS_MAGIC) {
+        return Err(PaymentError::StorageError(format!(
+            "{} isn't a file of idempotency keys",
+            path.display()
+        )));
+    }
+    let mut keys = HashSet::new();
+    let mut rest = bytes.get(KEYS_MAGIC.len()..).unwrap_or_default();
+    while let [low, high, tail @ ..] = rest {
+        let len = usize::from(u16::from_le_bytes([*low, *high]));
+        let Some(key) = tail.get(..len) else {
+            break;
+        };
+        keys.insert(String::from_utf8_lossy(key).into_owned());
+        rest = &tail[len..];
+    }
+    if !rest.is_empty() {
+        file.set_len((bytes.len() - rest.len()) as u64).map_err(file_error)?;
+    }
+    Ok((file, keys))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::EngineConfig,
+        errors::PaymentError,
+        journal::{TxJournal, DEFAULT_SYNC_EVERY},
+        parser::parse_transactions,
+        payment_engine::PaymentEngine,
+        rejection::RejectionReason,
+        types::{IgnoreReason, ProcessOutcome, Transaction},
+    };
+    use std::{fs, path::PathBuf};
+
+    const DAILY_FILE: &str = "type,client,tx,amount
+deposit,1,1,10.0
+deposit,2,2,5.0
+withdrawal,1,3,2.5
+dispute,2,2,
+";
+
+    fn journal_path(name: &str) -> PathBuf {
+        let name = format!("payment-engine-{}-{}.journal", std::process::id(), name);
+        std::env::temp_dir().join(name)
+    }
+
+    async fn daily_file(
+    ) -> Result<impl Iterator<Item = Result<Transaction, PaymentError>>, PaymentError> {
+        parse_transactions(Box::new(stringreader::StringReader::new(DAILY_FILE))).await
+    }
+
+    #[tokio::test]
+    async fn a_file_submitted_twice_is_applied_once() -> Result<(), PaymentError> {
+        let mut once = PaymentEngine::new();
+        once.process_all(daily_file().await?).await.into_result()?;
+
+        let path = journal_path("twice");
+        let journal = TxJournal::open(&path, DEFAULT_SYNC_EVERY)?;
+        let mut engine = PaymentEngine::new().with_journal(journal);
+        engine.process_all(daily_file().await?).await.into_result()?;
+        engine.sync_journal()?;
+        let again = engine.process_all(daily_file().await?).await;
+        assert_eq!(again.applied, 0);
+        let reasons: Vec<_> =
+            again.rejections.iter().map(|rejection| rejection.record.reason).collect();
+        assert_eq!(reasons[..3], [RejectionReason::Ignored(IgnoreReason::AlreadyProcessed); 3]);
+        assert_eq!(engine.all_client_views(), once.all_client_views());
+
+        // the next run starts from the saved accounts and the journal
+        let saved = engine.clients_json()?;
+        let journal = TxJournal::open(&path, DEFAULT_SYNC_EVERY)?;
+        let mut next_run = PaymentEngine::new().with_journal(journal);
+        next_run.load_clients_json(&saved)?;
+        let batch = next_run.process_all(daily_file().await?).await;
+        fs::remove_file(&path).expect("the journal was written");
+        assert_eq!(batch.applied, 0);
+        assert_eq!(next_run.all_client_views(), once.all_client_views());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn a_crash_before_the_accounts_are_saved_at_worst_rejects_rows_again(
+    ) -> Result<(), PaymentError> {
+        let path = journal_path("crash");
+        let mut engine = PaymentEngine::new().with_journal(TxJournal::open(&path, 2)?);
+        engine.process_all(daily_file().await?).await.into_result()?;
+        // the run crashes after syncing tx 1 and 2, before syncing tx 3 and saving the accounts
+        drop(engine);
+
+        let mut rerun = PaymentEngine::new().with_journal(TxJournal::open(&path, 2)?);
+        let batch = rerun.process_all(daily_file().await?).await;
+        fs::remove_file(&path).expect("the journal was written");
+        // the synced deposits are rejected although their changes were lost, so the withdrawal
+        // finds no account: rows are missed, none is applied twice
+        let reasons: Vec<_> =
+            batch.rejections.iter().map(|rejection| rejection.record.reason).collect();
+        assert_eq!(
+            reasons[..3],
+            [
+                RejectionReason::Ignored(IgnoreReason::AlreadyProcessed),
+                RejectionReason::Ignored(IgnoreReason::AlreadyProcessed),
+                RejectionReason::Ignored(IgnoreReason::UnknownClient)
+            ]
+        );
+        assert_eq!(batch.applied, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn journaled_idempotency_keys_are_never_forgotten() -> Result<(), PaymentError> {
+        let path = journal_path("keys");
+        let config = EngineConfig {
+            max_idempotency_keys: 1,
+            ..Default::default()
+        };
+        let mut engine = PaymentEngine::new().with_config(config.clone()).with_journal(TxJournal::open(&path, 2)?);
+        for (tx, key) in [(1, "k1"), (2, "k2"), (3, "k3")] {
+            engine.process_transaction(Transaction::deposit(1, tx, 1.0).with_idempotency_key(key)).await?;
+        }
+        // k1 is still rejected although only one key is kept in memory
+        let retry = Transaction::deposit(1, 4, 1.0).with_idempotency_key("k1");
+        assert_eq!(
+            engine.process_transaction(retry.clone()).await?,
+            ProcessOutcome::Ignored(IgnoreReason::DuplicateIdempotencyKey)
+        );
+        engine.sync_journal()?;
+
+        // and so it is by the next run
+        let mut next_run = PaymentEngine::new().with_config(config).with_journal(TxJournal::open(&path, 2)?);
+        let outcome = next_run.process_transaction(retry).await?;
+        let mut keys_path = path.clone().into_os_string();
+        keys_path.push(".keys");
+        fs::remove_file(&path).expect("the journal was written");
+        fs::remove_file(&keys_path).expect("the keys were journaled");
+        assert_eq!(outcome, ProcessOutcome::Ignored(IgnoreReason::DuplicateIdempotencyKey));
+        Ok(())
+    }
+
+    #[test]
+    fn journals_keep_the_ids_synced_before_they_were_dropped() -> Result<(), PaymentError> {
+        let path = std::env::temp_dir()
+            .join(format!("payment-engine-{}-journal.bin", std::process::id()));
+        let mut journal = TxJournal::open(&path, 2)?;
+        assert!(journal.is_empty());
+        for tx in [1, 2, 2, 3] {
+            journal.record(tx)?;
+        }
+        assert!(journal.contains(3));
+        // dropped like in a crash, without syncing tx 3
+        drop(journal);
+
+        let mut journal = TxJournal::open(&path, 2)?;
+        assert_eq!((journal.len(), journal.contains(2), journal.contains(3)), (2, true, false));
+        journal.record(u32::MAX)?;
+        journal.sync()?;
+        // a sync cut short after two bytes of an id
+        let mut bytes = fs::read(&path).expect("the journal was written");
+        bytes.extend([7, 0]);
+        fs::write(&path, &bytes).expect("the journal is writable");
+
+        let journal = TxJournal::open(&path, 2)?;
+        assert_eq!(journal.len(), 3);
+        assert!(journal.contains(u32::MAX));
+        assert_eq!(fs::metadata(&path).map(|metadata| metadata.len()).ok(), Some(4 + 3 * 4));
+
+        fs::write(&path, "type,client,tx,amount\n").expect("the journal is writable");
+        let not_a_journal = TxJournal::open(&path, 2);
+        fs::remove_file(&path).expect("the journal was written");
+        assert!(matches!(not_a_journal, Err(PaymentError::StorageError(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,54 @@
+pub mod accrual;
+pub mod amount;
+pub mod batch;
+pub mod canonical;
+pub mod config;
+pub mod corrections;
+pub mod deposit_index;
+pub mod diagnostics;
+pub mod diff;
+pub mod errors;
+pub mod external_sort;
+#[cfg(any(test, feature = "testing"))]
+pub mod faulty_store;
+pub mod filter;
+pub mod follow;
+pub mod idempotency;
+pub mod inspect;
+pub mod invert;
+pub mod invariants;
+pub mod journal;
+pub mod merchants;
+#[cfg(feature = "msgpack")]
+pub mod msgpack;
+pub mod ordering;
+pub mod output_dir;
+pub mod panics;
+pub mod parallel;
+pub mod parser;
+pub mod payment_engine;
+pub mod pipeline;
+pub mod progress;
+#[cfg(feature = "proto")]
+pub mod proto;
+pub mod quarantine;
+pub mod reconcile;
+pub mod rejection;
+pub mod repl;
+pub mod report;
+pub mod shared_engine;
+pub mod simulate;
+pub mod state;
+pub mod statements;
+pub mod stats;
+pub mod store;
+pub mod tiered_store;
+pub mod types;
+mod undo;
+pub mod validate;
+pub mod verify;
+pub mod warnings;
+pub mod watchable;
+
+pub use payment_engine::PaymentEngine;
+pub use shared_engine::SharedPaymentEngine;
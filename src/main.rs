@@ -1,41 +1,1491 @@
-mod errors;
-mod parser;
-mod payment_engine;
-mod types;
+mod cli;
 
-use std::{fs::File, io::BufReader};
+use std::{
+    fmt,
+    fs::File,
+    io::{BufReader, BufWriter, IsTerminal, Read, Seek, Write},
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use errors::PaymentError;
-use payment_engine::PaymentEngine;
+use cli::{
+    CliOptions, Command, DiffOptions, InspectOptions, InvertOptions, SimulateOptions, StatementsOptions,
+    ValidateOptions, VerifyOptions,
+};
+use payment_engine::{
+    batch::BatchRow,
+    config::{self, EngineConfig},
+    deposit_index::DepositIndex,
+    diagnostics::{Diagnostic, ErrorFormat, Level},
+    diff,
+    errors::PaymentError,
+    external_sort::{self, InputOrder},
+    follow, inspect,
+    invariants::Ledger,
+    invert::{self, NON_INVERTIBLE_HEADER},
+    journal::TxJournal,
+    ordering::{TxOrderCheck, OUT_OF_ORDER_HEADER},
+    output_dir, parallel,
+    parser::{
+        self, AmountUnit, EmptyInput, MinorUnitsCheck, ParseOptions, ParsedRecord, SourceId, SpooledInput,
+    },
+    pipeline::TxOffset,
+    progress::{ByteCounter, CountingReader, ProgressReporter},
+    quarantine::QuarantineWriter,
+    reconcile,
+    rejection::{RejectionReason, RejectionRecord, RejectionWriter},
+    repl, report, shared_engine, simulate, statements,
+    stats::RunStats,
+    store::TransactionStore,
+    tiered_store::TieredTransactionStore,
+    types::{IgnoreReason, ProcessOutcome, SourceRef, Transaction, TransactionType, Until},
+    validate::{Validator, FINDINGS_HEADER},
+    verify,
+    warnings::{EngineWarning, DEFAULT_WARNING_CHANNEL_CAPACITY},
+    PaymentEngine,
+};
+use csv::{ByteRecord, StringRecord};
+use tokio::sync::mpsc;
+
+/// The run completed, no transaction was rejected and every invariant holds.
+const EXIT_OK: i32 = 0;
+/// The run was aborted (invalid arguments, unreadable file, malformed row...).
+const EXIT_HARD_ERROR: i32 = 1;
+/// The run completed but some transactions were rejected and `--fail-on-reject` was given.
+const EXIT_REJECTED: i32 = 2;
+/// The run completed but some client accounts failed the invariant check, or `simulate` found
+/// a violation.
+const EXIT_INVARIANT_FAILED: i32 = 3;
+/// `diff` found differences between the two reports, `verify` found mismatching clients,
+/// `reconcile` found clients it can't reconcile, or `invert` rows it can't back out.
+const EXIT_DIFFERENCES: i32 = 2;
+/// `--two-pass` found invalid rows, so nothing was applied.
+const EXIT_VALIDATION_FAILED: i32 = 4;
+/// No transaction was parsed and `--fail-on-empty` was given.
+const EXIT_EMPTY_INPUT: i32 = 5;
+/// The run completed but some transactions panicked and were skipped under `--max-panics`.
+const EXIT_INTERNAL_ERROR: i32 = 6;
+
+/// Interval between two progress lines of `simulate`.
+const SIMULATE_PROGRESS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Rows of `--stream-report` waiting to be written to stdout before the files wait for it.
+const STREAMED_ROWS_CHANNEL_CAPACITY: usize = 1024;
+
+/// How often `--follow` checks the transactions file for appended rows.
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(250);
 
 #[tokio::main]
-async fn main() -> Result<(), PaymentError> {
-    // Get filename from the cli argument
-    let file_path = std::env::args().nth(1).ok_or_else(|| {
-        PaymentError::InvalidCliArgument("CSV filename missing in cli argument".to_owned())
-    })?;
-
-    let br = BufReader::new(
-        File::open(file_path).map_err(|err| PaymentError::FileError(err.to_string()))?,
-    );
-    // Parse the CSV file and get the iterator of transactions
-    let transactions = parser::parse_transactions(Box::new(br)).await?;
+async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let errors = Command::error_format(&args);
+    // process::exit doesn't run destructors, make sure the report is fully written first
+    let result = dispatch(args.into_iter())
+        .await
+        .and_then(|code| std::io::stdout().flush().map(|_| code).map_err(stdout_error));
+    let code = match result {
+        Ok(code) => code,
+        Err(err) => {
+            if !err.is_broken_pipe() {
+                print_diagnostic(errors, format_args!("Error: {}", err), Diagnostic::error(&err));
+            }
+            exit_code_of(&err)
+        }
+    };
+    std::process::exit(code);
+}
+
+/// Returns the exit code of a command that failed with `err`. A stdout closed by its reader,
+/// e.g. piped into `head`, ends the command quietly and successfully, like other filters.
+fn exit_code_of(err: &PaymentError) -> i32 {
+    if err.is_broken_pipe() {
+        EXIT_OK
+    } else {
+        EXIT_HARD_ERROR
+    }
+}
+
+/// Returns the error of a failed write to stdout.
+fn stdout_error(err: std::io::Error) -> PaymentError {
+    PaymentError::output("stdout", err)
+}
+
+/// Prints an error or a warning to stderr: its `text`, or its diagnostic under `--errors json`.
+fn print_diagnostic(errors: ErrorFormat, text: impl fmt::Display, diagnostic: Diagnostic) {
+    match errors {
+        ErrorFormat::Human => eprintln!("{}", text),
+        ErrorFormat::Json => eprintln!("{}", diagnostic.to_json()),
+    }
+}
+
+/// Runs the command given on the command line and returns the exit code.
+async fn dispatch<I: Iterator<Item = String>>(args: I) -> Result<i32, PaymentError> {
+    match Command::parse(args)? {
+        Command::Process(options) => process(*options).await,
+        Command::Diff(options) => diff(options),
+        Command::Verify(options) => verify(options).await,
+        Command::Reconcile(options) => reconcile(options).await,
+        Command::Invert(options) => invert(options).await,
+        Command::Inspect(options) => inspect(options),
+        Command::Validate(options) => validate(options).await,
+        Command::Simulate(options) => simulate(options).await,
+        Command::Statements(options) => statements(options).await,
+        Command::Repl => {
+            let prompt = std::io::stdin().is_terminal().then_some("> ");
+            repl::run(std::io::stdin().lock(), std::io::stdout(), prompt).await?;
+            Ok(EXIT_OK)
+        }
+    }
+}
+
+/// Compares two account states reports, printing one line per differing client.
+fn diff(options: DiffOptions) -> Result<i32, PaymentError> {
+    let diffs = diff::diff_states_with_epsilon(
+        parser::open_input(&options.before)?,
+        parser::open_input(&options.after)?,
+        options.epsilon,
+    )?;
+
+    let mut stdout = std::io::stdout().lock();
+    for client_diff in &diffs {
+        writeln!(stdout, "{}", client_diff).map_err(stdout_error)?;
+    }
+    Ok(if diffs.is_empty() {
+        EXIT_OK
+    } else {
+        EXIT_DIFFERENCES
+    })
+}
+
+/// Recomputes the account states from a transactions file and prints a reconciliation report
+/// against the claimed state.
+async fn verify(options: VerifyOptions) -> Result<i32, PaymentError> {
+    let report = verify::verify(
+        parser::open_input(&options.transactions)?,
+        parser::open_input(&options.against)?,
+    )
+    .await?;
 
-    // Create a new payment engine and process each transaction
+    write!(std::io::stdout().lock(), "{}", report).map_err(stdout_error)?;
+    Ok(if report.is_reconciled() {
+        EXIT_OK
+    } else {
+        EXIT_DIFFERENCES
+    })
+}
+
+/// Prints the resolves and chargebacks that would reconcile a transactions file with a claimed
+/// state as a transactions CSV, and the clients left unreconciled to stderr.
+async fn reconcile(options: VerifyOptions) -> Result<i32, PaymentError> {
+    let reconciliation = reconcile::reconcile(
+        parser::open_input(&options.transactions)?,
+        parser::open_input(&options.against)?,
+    )
+    .await?;
+
+    reconciliation.write_proposed(std::io::stdout().lock()).map_err(stdout_error)?;
+    if !reconciliation.is_reconciled() {
+        let mut stderr = std::io::stderr().lock();
+        let _ = writeln!(stderr, "unreconcilable clients, from the computed state to the claimed one:");
+        for residual in &reconciliation.unreconcilable {
+            let _ = writeln!(stderr, "{}", residual);
+        }
+    }
+    Ok(if reconciliation.is_reconciled() {
+        EXIT_OK
+    } else {
+        EXIT_DIFFERENCES
+    })
+}
+
+/// Writes the transactions backing out a batch to stdout, and the rows they don't back out to
+/// the report or stderr.
+async fn invert(options: InvertOptions) -> Result<i32, PaymentError> {
     let mut engine = PaymentEngine::new();
+    if let Some(path) = &options.initial_state {
+        let json = std::fs::read_to_string(path).map_err(|err| PaymentError::file(path, err))?;
+        engine.load_clients_json(&json)?;
+    }
+    let (inverse, non_invertible) = invert::invert(engine, parser::open_input(&options.batch)?).await?;
+
+    let mut writer = csv::Writer::from_writer(std::io::stdout().lock());
+    for txn in &inverse {
+        writer.serialize(txn).map_err(|err| stdout_error(err.into()))?;
+    }
+    writer.flush().map_err(stdout_error)?;
+    match &options.report {
+        Some(path) => {
+            let file_error = |err: std::io::Error| PaymentError::file(path, err);
+            let mut report = BufWriter::new(File::create(path).map_err(file_error)?);
+            writeln!(report, "{}", NON_INVERTIBLE_HEADER).map_err(file_error)?;
+            for row in &non_invertible {
+                row.write_row(&mut report).map_err(file_error)?;
+            }
+            report.flush().map_err(file_error)?;
+        }
+        None => {
+            let mut stderr = std::io::stderr().lock();
+            for row in &non_invertible {
+                let _ = writeln!(stderr, "not backed out: {}", row);
+            }
+        }
+    }
+    Ok(if non_invertible.is_empty() {
+        EXIT_OK
+    } else {
+        EXIT_DIFFERENCES
+    })
+}
+
+/// Answers a query about the accounts of a clients dump on stdout.
+fn inspect(options: InspectOptions) -> Result<i32, PaymentError> {
+    let path = &options.dump;
+    let json = std::fs::read_to_string(path).map_err(|err| PaymentError::file(path, err))?;
+    let engine = inspect::load(&json)?;
+    inspect::inspect(&engine, &options.query, options.format, std::io::stdout().lock()).map_err(stdout_error)?;
+    Ok(EXIT_OK)
+}
+
+/// Checks the references of a disputes file against a deposit index, printing the findings as
+/// a `line,finding` CSV and how many there were on stderr.
+async fn validate(options: ValidateOptions) -> Result<i32, PaymentError> {
+    let index = DepositIndex::load(&options.deposit_index)?;
+    let (_, records) = parser::parse_records(parser::open_input(&options.disputes)?).await?;
+
+    let mut stdout = std::io::stdout().lock();
+    writeln!(stdout, "{}", FINDINGS_HEADER).map_err(stdout_error)?;
+    let (mut rows, mut findings) = (0, 0);
+    for record in records {
+        rows += 1;
+        if let Some(finding) = index.check(&record.transaction, record.line) {
+            findings += 1;
+            finding.write_row(&mut stdout).map_err(stdout_error)?;
+        }
+    }
+    stdout.flush().map_err(stdout_error)?;
+    eprintln!(
+        "{} rows checked against {} deposits: {} findings",
+        rows,
+        index.len(),
+        findings
+    );
+    Ok(if findings == 0 { EXIT_OK } else { EXIT_VALIDATION_FAILED })
+}
+
+/// Runs a seeded simulation with progress lines on stderr, printing the final digest, and
+/// writes the reproduction of an invariant violation as JSON.
+async fn simulate(options: SimulateOptions) -> Result<i32, PaymentError> {
+    let config = &options.config;
+    let mut progress = ProgressReporter::new(None, None, SIMULATE_PROGRESS_INTERVAL);
+    let outcome = simulate::simulate(config, PaymentEngine::new, Some(&mut progress)).await?;
+
+    let mut stdout = std::io::stdout().lock();
+    writeln!(stdout, "seed: {}", config.seed)
+        .and_then(|_| writeln!(stdout, "rows: {} ({} applied)", outcome.rows, outcome.applied))
+        .and_then(|_| writeln!(stdout, "checksum: sha256:{}", outcome.digest))
+        .map_err(stdout_error)?;
+    let Some(failure) = outcome.failure else {
+        return Ok(EXIT_OK);
+    };
+    let path = options
+        .repro
+        .unwrap_or_else(|| format!("simulation-{}-failure.json", config.seed));
+    let json =
+        serde_json::to_string_pretty(&failure).map_err(|err| PaymentError::JsonError(err.to_string()))?;
+    std::fs::write(&path, json + "\n").map_err(|err| PaymentError::file(&path, err))?;
+    let mut stderr = std::io::stderr().lock();
+    for violation in &failure.violations {
+        let _ = writeln!(stderr, "invariant violated: {}", violation);
+    }
+    let _ = writeln!(
+        stderr,
+        "first seen after row {} of seed {}, reproduction written to {}",
+        failure.row, failure.seed, path
+    );
+    Ok(EXIT_INVARIANT_FAILED)
+}
+
+/// Writes the daily statements of the requested clients to stdout.
+async fn statements(options: StatementsOptions) -> Result<i32, PaymentError> {
+    let input = parser::open_input(&options.transactions)?;
+    let statements =
+        statements::statements(PaymentEngine::new(), input, options.clients.as_ref(), options.utc_offset).await?;
+    let mut stdout = BufWriter::new(std::io::stdout().lock());
+    for statement in &statements {
+        statement.write_to(&mut stdout).map_err(stdout_error)?;
+    }
+    stdout.flush().map_err(stdout_error)?;
+    Ok(EXIT_OK)
+}
+
+/// Runs the engine as configured by the command line and returns the exit code.
+async fn process(options: CliOptions) -> Result<i32, PaymentError> {
+    let mut stats = RunStats::start();
+
+    let mut files = Vec::new();
+    for path in std::iter::once(&options.file_path).chain(&options.extra_files) {
+        files.push(match path.as_str() {
+            parser::STDIN_PATH => parser::stdin_file()?,
+            _ => parser::open_input_file(path)?,
+        });
+    }
+
+    // Kept until the run is over, the copies are removed when dropped
+    let mut spooled = Vec::new();
+    if options.two_pass {
+        // Inputs are read once per pass, so pipes, stdin included, are copied to the temp
+        // directory first
+        for file in files.iter_mut() {
+            if !parser::is_seekable(file) {
+                let copy = SpooledInput::new(&*file)?;
+                *file = copy.file()?;
+                spooled.push(copy);
+            }
+        }
+        let findings = validate_inputs(&options, &files).await?;
+        if findings > 0 {
+            let message = format!("validation failed: {} invalid rows, nothing applied", findings);
+            print_diagnostic(
+                options.errors,
+                &message,
+                Diagnostic::new(Level::Error, "ValidationFailed", message.clone()),
+            );
+            return Ok(EXIT_VALIDATION_FAILED);
+        }
+    }
+
+    // Progress goes to stderr only, so there is no point in it when nobody is watching, nor
+    // amid JSON diagnostics
+    let (inputs, mut progress): (Vec<Box<dyn Read>>, Option<ProgressReporter>) =
+        if options.progress && options.errors == ErrorFormat::Human && std::io::stderr().is_terminal() {
+            // the total is only known when every input is a regular file
+            let total_len = files
+                .iter()
+                .map(|file| {
+                    file.metadata()
+                        .ok()
+                        .filter(|metadata| metadata.is_file())
+                        .map(|metadata| metadata.len())
+                })
+                .sum::<Option<u64>>();
+            let counter = ByteCounter::default();
+            let inputs = files
+                .into_iter()
+                .map(|file| {
+                    let reader = CountingReader::with_counter(file, counter.clone());
+                    Box::new(BufReader::new(reader)) as Box<dyn Read>
+                })
+                .collect();
+            let progress = ProgressReporter::new(Some(counter), total_len, Duration::from_secs(1));
+            (inputs, Some(progress))
+        } else {
+            let inputs = files
+                .into_iter()
+                .map(|file| Box::new(BufReader::new(file)) as Box<dyn Read>)
+                .collect();
+            (inputs, None)
+        };
+
+    let config = EngineConfig {
+        locked_deposits: options.locked_deposits,
+        blocked_clients: match &options.blocklist {
+            Some(path) => config::read_blocklist(parser::open_input(path)?)?,
+            None => Default::default(),
+        },
+        client_creation: options.client_creation,
+        onboarded_clients: match &options.allowlist {
+            Some(path) => config::read_allowlist(parser::open_input(path)?)?,
+            None => Default::default(),
+        },
+        max_client_balance: options.max_client_balance,
+        chargeback_fee: options.chargeback_fee,
+        dispute_shortfall: options.dispute_shortfall,
+        clamp_inconsistent_held: options.clamp_inconsistent_held,
+        dispute_amounts: options.dispute_amounts,
+        duplicate_deposits: options.duplicate_deposits,
+        hold_ttl_secs: options.hold_ttl_secs,
+        max_memo_len: options.max_memo_len,
+        max_idempotency_keys: options.max_idempotency_keys,
+        max_clients: options.max_clients,
+        max_stored_transactions: options.max_transactions,
+        max_open_disputes_per_client: options.max_open_disputes_per_client,
+        max_open_disputes: options.max_open_disputes,
+        dispute_support: options.dispute_support,
+        min_amount: options.min_amount,
+        dust: options.dust,
+        max_transaction_amount: options.max_transaction_amount,
+        max_panics: options.max_panics,
+        fail_on_ignore: options.strict_engine,
+        ..Default::default()
+    };
+
+    let journal = match &options.journal {
+        Some(path) => Some(TxJournal::open(path, options.journal_sync_every)?),
+        None => None,
+    };
+
+    let initial_state = match &options.initial_state {
+        Some(path) => Some(
+            std::fs::read_to_string(path).map_err(|err| PaymentError::file(path, err))?,
+        ),
+        None => None,
+    };
+
+    let ran: Result<(), PaymentError> = async {
+        if options.parallel_files {
+            run_parallel(config, &options, &mut stats).await?;
+        } else {
+            match options.max_transactions_in_memory {
+                Some(capacity) => {
+                    let mut engine =
+                        PaymentEngine::with_store(TieredTransactionStore::new(capacity)?).with_config(config);
+                    if let Some(journal) = journal {
+                        engine = engine.with_journal(journal);
+                    }
+                    if let Some(json) = &initial_state {
+                        engine.load_clients_json(json)?;
+                    }
+                    let engine = if options.follow {
+                        follow(engine, &options, &mut stats).await?
+                    } else {
+                        run(engine, inputs, &options, &mut stats, progress.as_mut()).await?
+                    };
+                    stats.tiered_store = Some(engine.transactions.stats());
+                }
+                None => {
+                    let mut engine = PaymentEngine::new().with_config(config);
+                    if let Some(journal) = journal {
+                        engine = engine.with_journal(journal);
+                    }
+                    if let Some(json) = &initial_state {
+                        engine.load_clients_json(json)?;
+                    }
+                    if options.follow {
+                        follow(engine, &options, &mut stats).await?;
+                    } else {
+                        run(
+                            engine,
+                            inputs,
+                            &options,
+                            &mut stats,
+                            progress.as_mut(),
+                        )
+                        .await?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    .await;
+    // a run stopped early wrote its reports, and its statistics go with them
+    let stopped = match ran {
+        Err(err) if stops_the_run(&err) => Some(err),
+        ran => ran.map(|_| None)?,
+    };
+
+    stats.finish();
+    if options.errors == ErrorFormat::Human {
+        let _ = stats.write_summary(std::io::stderr().lock());
+    }
+    if let Some(path) = &options.stats_json {
+        std::fs::write(path, stats.to_json() + "\n").map_err(|err| PaymentError::file(path, err))?;
+    }
+    if let Some(err) = stopped {
+        return Err(err);
+    }
+
+    let empty = options.fail_on_empty && stats.rows_parsed == 0;
+    if empty {
+        let message = match stats.empty_input {
+            Some(empty) => format!("--fail-on-empty: no transactions processed, {}", empty),
+            None => "--fail-on-empty: no transactions processed".to_owned(),
+        };
+        print_diagnostic(
+            options.errors,
+            &message,
+            Diagnostic::new(Level::Error, "EmptyInput", message.clone()),
+        );
+    }
+
+    Ok(run_exit_code(&stats, empty, options.fail_on_reject))
+}
+
+/// Returns whether `err` stopped the run on a row, with the rows before it still reported: a
+/// limit exceeded, or a rejection under `--strict-engine`.
+fn stops_the_run(err: &PaymentError) -> bool {
+    matches!(err, PaymentError::LimitExceeded { .. } | PaymentError::IgnoredTransaction(_))
+}
+
+/// Returns the exit code of a completed run, `empty` if `--fail-on-empty` found no transaction.
+fn run_exit_code(stats: &RunStats, empty: bool, fail_on_reject: bool) -> i32 {
+    if stats.caught_panics > 0 {
+        EXIT_INTERNAL_ERROR
+    } else if stats.invariant_violations > 0 {
+        EXIT_INVARIANT_FAILED
+    } else if empty {
+        EXIT_EMPTY_INPUT
+    } else if fail_on_reject && stats.rows_rejected + stats.parse_errors > 0 {
+        EXIT_REJECTED
+    } else {
+        EXIT_OK
+    }
+}
+
+/// Processes every transaction of the CSV inputs, one file after the other, and outputs the
+/// final account states.
+async fn run<S: TransactionStore>(
+    mut engine: PaymentEngine<S>,
+    inputs: Vec<Box<dyn Read>>,
+    options: &CliOptions,
+    stats: &mut RunStats,
+    mut progress: Option<&mut ProgressReporter>,
+) -> Result<PaymentEngine<S>, PaymentError> {
+    // Rows of multi-file runs are attributed to their file
+    let sources = (inputs.len() > 1).then(|| input_sources(options));
+    let mut order = TxOrderWatch::new(options)?;
+    let mut rejected = RejectedRows::new(options)?;
+    let mut chunk = RowChunk::new(options);
+    let mut stopped = None;
+    // under --errors json rejections are printed as they happen, like in --follow mode
+    let mut warnings = None;
+    if options.errors == ErrorFormat::Json {
+        let (sender, receiver) = mpsc::channel(DEFAULT_WARNING_CHANNEL_CAPACITY);
+        engine = engine.with_warning_sink(sender);
+        warnings = Some(receiver);
+    }
+
+    // set apart for the summary when no input has a row
+    let mut seen_rows = false;
+    let mut empty = None;
+    'inputs: for (index, input) in inputs.into_iter().enumerate() {
+        if index > 0 {
+            // the earlier files' rows are in the store before their tx ids are looked up there
+            match process_chunk(&mut engine, &mut chunk, stats, &mut rejected).await {
+                Err(err) if stops_the_run(&err) => {
+                    stopped = Some(err);
+                    break 'inputs;
+                }
+                processed => processed?,
+            }
+        }
+        // Parse the CSV file and get the iterator of records, raw rows are kept for the quarantine
+        let (headers, records) = parse_input(input, options).await?;
+        let records = match sources.as_ref() {
+            Some(sources) => parser::attribute(records, sources[index].clone()),
+            None => records,
+        };
+        rejected.open_quarantine(options, &headers)?;
+        let mut unit_check = (options.amount_unit == AmountUnit::Major
+            && options.input_format == parser::InputFormat::Csv)
+            .then(|| MinorUnitsCheck::new(&headers));
+
+        let mut has_rows = false;
+        for record in records {
+            seen_rows = true;
+            has_rows = true;
+            if let Some(suspected) = unit_check.as_mut().and_then(|check| check.check(&record.raw, record.line)) {
+                // multi-file runs name the file, the others may be fine
+                let source = record.source.as_ref().map(|source| source.path.to_string());
+                let text = match &source {
+                    Some(source) => format!("{}: {}", source, suspected),
+                    None => suspected.to_string(),
+                };
+                let diagnostic = Diagnostic {
+                    source,
+                    ..Diagnostic::from(&suspected)
+                };
+                print_diagnostic(options.errors, format_args!("warning: {} (see --amount-unit)", text), diagnostic);
+            }
+            let until = match (&options.as_of, &record.transaction) {
+                (Some(as_of), Ok(txn)) => as_of.until(txn),
+                _ => Until::Continue,
+            };
+            if until == Until::StopBefore {
+                break 'inputs;
+            }
+            let applied = apply_record(
+                &mut engine,
+                record,
+                options,
+                stats,
+                &mut rejected,
+                &mut chunk,
+                order.as_mut(),
+            )
+            .await;
+            // printed before the run can stop on the row
+            if let Some(warnings) = warnings.as_mut() {
+                while let Ok(warning) = warnings.try_recv() {
+                    print_diagnostic(options.errors, &warning, Diagnostic::from(&warning));
+                }
+            }
+            match applied {
+                // the rows applied so far are still reported
+                Err(err) if stops_the_run(&err) => {
+                    stopped = Some(err);
+                    break 'inputs;
+                }
+                applied => applied?,
+            }
+            if let Some(progress) = progress.as_mut() {
+                progress.tick();
+            }
+            if until == Until::StopAfter {
+                break 'inputs;
+            }
+        }
+        if !has_rows {
+            // a header in any of the inputs makes them header-only
+            empty = match (empty, EmptyInput::of(&headers)) {
+                (Some(EmptyInput::HeaderOnly), _) | (_, EmptyInput::HeaderOnly) => Some(EmptyInput::HeaderOnly),
+                _ => Some(EmptyInput::NoBytes),
+            };
+        }
+    }
+    if stopped.is_none() {
+        // the rows still in the chunk, of the last input or before an early stop
+        let processed = process_chunk(&mut engine, &mut chunk, stats, &mut rejected).await;
+        if let Some(warnings) = warnings.as_mut() {
+            while let Ok(warning) = warnings.try_recv() {
+                print_diagnostic(options.errors, &warning, Diagnostic::from(&warning));
+            }
+        }
+        match processed {
+            Err(err) if stops_the_run(&err) => stopped = Some(err),
+            processed => processed?,
+        }
+    }
+    if !seen_rows {
+        stats.empty_input = empty;
+    }
+    rejected.flush()?;
+    if let Some(order) = order.as_mut() {
+        order.flush()?;
+    }
+    stats.dropped_warnings = engine.dropped_warnings();
+    engine.close_warnings();
+
+    // journaled before the accounts are saved, see `journal`
+    accrue(&mut engine, options)?;
+    engine.sync_journal()?;
+    write_accounts(&engine, options)?;
+    emit_deposit_index(&mut engine, options).await?;
+    export_transactions(&mut engine, options).await?;
+    finish_run(&engine, options, stats)?;
+    match stopped {
+        Some(err) => Err(err),
+        None => Ok(engine),
+    }
+}
+
+/// Outputs the final account states to stdout (CSV format), a report file or one file per
+/// client.
+fn write_accounts<S: TransactionStore>(
+    engine: &PaymentEngine<S>,
+    options: &CliOptions,
+) -> Result<(), PaymentError> {
+    let report_options = options.report_options();
+    match (&options.output_dir, &options.report) {
+        (Some(dir), _) => {
+            output_dir::write_client_files(engine, Path::new(dir), options.force, &report_options)?;
+        }
+        (None, Some(path)) => {
+            follow::write_report_atomically(engine, Path::new(path), &report_options)?
+        }
+        (None, None) => {
+            report::write_report(engine, std::io::stdout().lock(), &report_options).map_err(stdout_error)?;
+        }
+    }
+    Ok(())
+}
+
+/// Processes the inputs of `--parallel-files`, each with its own engine, and outputs the
+/// accounts of the merged engines, or with `--stream-report` those of each engine as soon as
+/// its file is processed.
+async fn run_parallel(
+    config: EngineConfig,
+    options: &CliOptions,
+    stats: &mut RunStats,
+) -> Result<(), PaymentError> {
+    let paths: Vec<&String> = std::iter::once(&options.file_path).chain(&options.extra_files).collect();
+    let config = EngineConfig {
+        fail_fast: !options.lenient,
+        ..config
+    };
+    let (mut engine, batches) = if options.stream_report {
+        let (sender, receiver) = mpsc::channel(STREAMED_ROWS_CHANNEL_CAPACITY);
+        let (processed, written) = tokio::join!(
+            parallel::process_files_parallel_streamed(&paths, &config, sender, options.stream_options()),
+            shared_engine::write_streamed_report(receiver, std::io::stdout(), options.stream_options()),
+        );
+        // a closed stdout stops the files too, report it rather than their failed sends
+        written.map_err(stdout_error)?;
+        processed?
+    } else {
+        parallel::process_files_parallel_batches(&paths, &config).await?
+    };
+    for batch in &batches {
+        stats.rows_parsed += (batch.applied + batch.ignored) as u64;
+        stats.parse_errors += batch.errors as u64;
+        stats.rows_applied += batch.applied as u64;
+        for rejection in &batch.rejections {
+            let record = &rejection.record;
+            if let (Some(r#type), RejectionReason::Ignored(reason)) = (record.txn_type, record.reason) {
+                stats.record_outcome(r#type, &ProcessOutcome::Ignored(reason));
+            }
+        }
+    }
+    accrue(&mut engine, options)?;
+    if !options.stream_report {
+        write_accounts(&engine, options)?;
+    }
+    emit_deposit_index(&mut engine, options).await?;
+    export_transactions(&mut engine, options).await?;
+    finish_run(&engine, options, stats)
+}
+
+/// Parses one of the inputs, in the order the rows are to be processed.
+async fn parse_input(
+    input: Box<dyn Read>,
+    options: &CliOptions,
+) -> Result<(StringRecord, Box<dyn Iterator<Item = ParsedRecord>>), PaymentError> {
+    let parse_options = ParseOptions {
+        amount_unit: options.amount_unit,
+        limits: options.input_limits,
+        ..ParseOptions::default()
+    };
+    match (options.input_format, options.input_order) {
+        #[cfg(feature = "proto")]
+        (parser::InputFormat::Proto, _) => {
+            let (header, records) = payment_engine::proto::parse_records_proto(input);
+            Ok((header, parser::limit_records(records, options.input_limits)))
+        }
+        #[cfg(feature = "msgpack")]
+        (parser::InputFormat::Msgpack, _) => {
+            let (header, records) = payment_engine::msgpack::parse_records_msgpack(input);
+            Ok((header, parser::limit_records(records, options.input_limits)))
+        }
+        (_, InputOrder::File) => parser::parse_records_with(input, &parse_options).await,
+        (_, InputOrder::Timestamp) => {
+            external_sort::parse_records_by_timestamp_with(input, options.sort_chunk_rows, &parse_options)
+                .await
+        }
+    }
+}
+
+/// Runs the validation pass of `--two-pass` over every input, in processing order, and returns
+/// the number of invalid rows. The findings are written to the findings report if asked, or
+/// else to stderr. Each input is rewound afterwards, ready for the second pass.
+async fn validate_inputs(options: &CliOptions, files: &[File]) -> Result<u64, PaymentError> {
+    let mut report = match &options.findings_report {
+        Some(path) => {
+            let file = File::create(path).map_err(|err| PaymentError::file(path, err))?;
+            let mut report = BufWriter::new(file);
+            writeln!(report, "{}", FINDINGS_HEADER).map_err(|err| PaymentError::IoError(err.to_string()))?;
+            Some(report)
+        }
+        None => None,
+    };
+
+    let paths: Vec<&String> = std::iter::once(&options.file_path).chain(&options.extra_files).collect();
+    let mut validator = Validator::new();
+    let mut findings = 0;
+    for (path, mut file) in paths.iter().zip(files) {
+        let input = file.try_clone().map_err(|err| PaymentError::file(path, err))?;
+        let (_, records) = parse_input(Box::new(BufReader::new(input)), options).await?;
+        for record in records {
+            let finding = if paths.len() > 1 {
+                validator.check(&record.transaction, format!("{}:{}", path, record.line))
+            } else {
+                validator.check(&record.transaction, record.line)
+            };
+            let Some(finding) = finding else {
+                continue;
+            };
+            findings += 1;
+            match report.as_mut() {
+                Some(report) => finding
+                    .write_row(report)
+                    .map_err(|err| PaymentError::IoError(err.to_string()))?,
+                None => print_diagnostic(
+                    options.errors,
+                    format_args!("invalid row: {}", finding),
+                    Diagnostic::from(&finding),
+                ),
+            }
+        }
+        file.rewind().map_err(|err| PaymentError::file(path, err))?;
+    }
+    if let Some(report) = report.as_mut() {
+        report.flush().map_err(|err| PaymentError::IoError(err.to_string()))?;
+    }
+    Ok(findings)
+}
+
+/// Follows the transactions file as it grows, rewriting the report file as configured until
+/// interrupted with Ctrl-C. Warnings are printed to stderr as they happen.
+async fn follow<S: TransactionStore>(
+    engine: PaymentEngine<S>,
+    options: &CliOptions,
+    stats: &mut RunStats,
+) -> Result<PaymentEngine<S>, PaymentError> {
+    let (sender, mut receiver) = mpsc::channel(DEFAULT_WARNING_CHANNEL_CAPACITY);
+    let mut engine = engine.with_warning_sink(sender);
+    let errors = options.errors;
+    let warnings = tokio::spawn(async move {
+        while let Some(warning) = receiver.recv().await {
+            print_diagnostic(errors, format_args!("warning: {}", warning), Diagnostic::from(&warning));
+        }
+    });
+
+    // --follow requires --report, checked when parsing the options
+    let report = Path::new(options.report.as_deref().unwrap_or_default());
+    let mut follower = follow::FileFollower::open(&options.file_path)?;
+    let mut order = TxOrderWatch::new(options)?;
+    let mut rejected = RejectedRows::new(options)?;
+    let mut chunk = RowChunk::new(options);
 
-    for txn in transactions {
-        engine.process_transaction(txn?).await;
+    let interrupted = tokio::signal::ctrl_c();
+    tokio::pin!(interrupted);
+    let mut rows_since_report = 0;
+    let mut last_report: Option<Instant> = None;
+    loop {
+        let records = follower.poll().await?;
+        if let Some(headers) = follower.raw_headers() {
+            rejected.open_quarantine(options, headers)?;
+        }
+        rows_since_report += records.len() as u64;
+        for record in records {
+            apply_record(&mut engine, record, options, stats, &mut rejected, &mut chunk, order.as_mut()).await?;
+        }
+        process_chunk(&mut engine, &mut chunk, stats, &mut rejected).await?;
+        rejected.flush()?;
+        if let Some(order) = order.as_mut() {
+            order.flush()?;
+        }
+
+        let refresh_due = last_report.is_none_or(|at| at.elapsed() >= options.refresh_interval)
+            || options
+                .refresh_rows
+                .is_some_and(|rows| rows_since_report >= rows);
+        if refresh_due && (rows_since_report > 0 || last_report.is_none()) {
+            engine.sync_journal()?;
+            follow::write_report_atomically(&engine, report, &options.report_options())?;
+            rows_since_report = 0;
+            last_report = Some(Instant::now());
+        }
+
+        tokio::select! {
+            _ = &mut interrupted => break,
+            _ = tokio::time::sleep(FOLLOW_POLL_INTERVAL) => {}
+        }
+    }
+
+    stats.dropped_warnings = engine.dropped_warnings();
+    engine.close_warnings();
+    let _ = warnings.await;
+
+    accrue(&mut engine, options)?;
+    engine.sync_journal()?;
+    follow::write_report_atomically(&engine, report, &options.report_options())?;
+    emit_deposit_index(&mut engine, options).await?;
+    export_transactions(&mut engine, options).await?;
+    finish_run(&engine, options, stats)?;
+    Ok(engine)
+}
+
+/// Returns the input files of a multi-file run, in the order they are processed.
+fn input_sources(options: &CliOptions) -> Vec<SourceId> {
+    std::iter::once(&options.file_path)
+        .chain(&options.extra_files)
+        .enumerate()
+        .map(|(index, path)| SourceId {
+            index,
+            path: Arc::from(path.as_str()),
+        })
+        .collect()
+}
+
+/// Returns where a deposit or withdrawal of a multi-file run was first read from, if its tx id
+/// was already used by a transaction the engine keeps from an earlier file. Looking the tx id
+/// up in the engine's store bounds the check by the store rather than by every tx id ever read.
+async fn earlier_use<S: TransactionStore>(
+    engine: &mut PaymentEngine<S>,
+    txn: &Transaction,
+    source: Option<&SourceId>,
+) -> Result<Option<SourceRef>, PaymentError> {
+    let Some(source) = source else {
+        return Ok(None);
+    };
+    if !matches!(txn.r#type, TransactionType::Deposit | TransactionType::Withdrawal) {
+        return Ok(None);
+    }
+    let earlier = engine.source_of(txn.tx).await?;
+    Ok(earlier.filter(|earlier| earlier.file.as_ref().is_some_and(|file| *file != source.path)))
+}
+
+/// The tx id order check of a run, with its report.
+struct TxOrderWatch {
+    check: TxOrderCheck,
+    strict: bool,
+    errors: ErrorFormat,
+    report: Option<BufWriter<File>>,
+}
+
+impl TxOrderWatch {
+    /// Returns the check asked for on the command line, if any, creating its report.
+    fn new(options: &CliOptions) -> Result<Option<Self>, PaymentError> {
+        if !options.check_tx_order {
+            return Ok(None);
+        }
+        let report = match &options.tx_order_report {
+            Some(path) => {
+                let file_error = |err: std::io::Error| PaymentError::file(path, err);
+                let mut report = BufWriter::new(File::create(path).map_err(file_error)?);
+                writeln!(report, "{}", OUT_OF_ORDER_HEADER).map_err(file_error)?;
+                Some(report)
+            }
+            None => None,
+        };
+        Ok(Some(TxOrderWatch {
+            check: TxOrderCheck::new(),
+            strict: options.strict_ordering,
+            errors: options.errors,
+            report,
+        }))
+    }
+
+    /// Checks the order of a transaction found at `line`, warning on stderr and in the report
+    /// when it is out of order, or failing under `--strict-ordering`.
+    fn check(&mut self, txn: &Transaction, line: &str, stats: &mut RunStats) -> Result<(), PaymentError> {
+        let Some(out_of_order) = self.check.check(txn, line) else {
+            return Ok(());
+        };
+        stats.out_of_order_tx += 1;
+        if self.strict {
+            return Err(PaymentError::OrderingError(out_of_order.to_string()));
+        }
+        print_diagnostic(
+            self.errors,
+            format_args!("warning: {}", out_of_order),
+            Diagnostic::from(&out_of_order),
+        );
+        if let Some(report) = self.report.as_mut() {
+            out_of_order
+                .write_row(report)
+                .map_err(|err| PaymentError::IoError(err.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the report rows written so far.
+    fn flush(&mut self) -> Result<(), PaymentError> {
+        match self.report.as_mut() {
+            Some(report) => report.flush().map_err(|err| PaymentError::IoError(err.to_string())),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Where rejected rows are reported: the quarantine, which keeps the raw rows for
+/// reprocessing, and the rejections report.
+struct RejectedRows {
+    quarantine: Option<QuarantineWriter<BufWriter<File>>>,
+    report: Option<RejectionWriter<BufWriter<File>>>,
+}
+
+impl RejectedRows {
+    /// Creates the rejections report if asked. The quarantine waits for the input's header.
+    fn new(options: &CliOptions) -> Result<Self, PaymentError> {
+        let report = match &options.rejections_report {
+            Some(path) => Some(RejectionWriter::create(path)?),
+            None => None,
+        };
+        Ok(RejectedRows {
+            quarantine: None,
+            report,
+        })
+    }
+
+    /// Creates the quarantine if asked and not created yet, under the input's raw header, or
+    /// has the rows of a following input go under its own header.
+    fn open_quarantine(&mut self, options: &CliOptions, headers: &StringRecord) -> Result<(), PaymentError> {
+        match (self.quarantine.as_mut(), &options.quarantine) {
+            (Some(quarantine), _) => quarantine.start_source(headers)?,
+            (None, Some(path)) => self.quarantine = Some(QuarantineWriter::create(path, headers)?),
+            (None, None) => {}
+        }
+        Ok(())
+    }
+
+    /// Reports a rejected row, `raw` being the row as it appeared in the input.
+    fn report(&mut self, raw: &ByteRecord, rejection: &RejectionRecord) -> Result<(), PaymentError> {
+        if let Some(quarantine) = self.quarantine.as_mut() {
+            quarantine.quarantine(raw, rejection.location(), &rejection.detail)?;
+        }
+        if let Some(report) = self.report.as_mut() {
+            report.write(rejection)?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the rows reported so far.
+    fn flush(&mut self) -> Result<(), PaymentError> {
+        if let Some(quarantine) = self.quarantine.as_mut() {
+            quarantine.flush()?;
+        }
+        if let Some(report) = self.report.as_mut() {
+            report.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// The rows on their way to the engine, processed together once there are `size` of them.
+struct RowChunk {
+    size: usize,
+    transactions: Vec<BatchRow>,
+    rows: Vec<ChunkRow>,
+}
+
+/// What the outcome of a row of a `RowChunk` is reported with.
+struct ChunkRow {
+    r#type: TransactionType,
+    raw: StringRecord,
+}
+
+impl RowChunk {
+    /// Returns a chunk of `--chunk-size` rows. Rows go one by one under `--strict-engine`,
+    /// `--max-clients` and `--max-transactions`, so that the run stops right on the row that
+    /// stops it.
+    fn new(options: &CliOptions) -> Self {
+        let stops_on_a_row =
+            options.strict_engine || options.max_clients.is_some() || options.max_transactions.is_some();
+        let size = if stops_on_a_row { 1 } else { options.chunk_size };
+        RowChunk {
+            size,
+            transactions: Vec::with_capacity(size),
+            rows: Vec::with_capacity(size),
+        }
+    }
+}
+
+/// Processes the rows of the chunk as a batch, keeping the statistics and the rejected rows up
+/// to date in the rows' order. Under `--strict-engine` the first rejected transaction stops the
+/// run, once reported.
+async fn process_chunk<S: TransactionStore>(
+    engine: &mut PaymentEngine<S>,
+    chunk: &mut RowChunk,
+    stats: &mut RunStats,
+    rejected: &mut RejectedRows,
+) -> Result<(), PaymentError> {
+    if chunk.rows.is_empty() {
+        return Ok(());
+    }
+    let batch = engine.process_all_chunked(chunk.transactions.drain(..), chunk.size).await;
+    let mut rejections = batch.rejections.into_iter().peekable();
+    // the rows after one that stopped the batch weren't counted
+    for (index, row) in chunk.rows.drain(..).enumerate().take(batch.applied + batch.ignored) {
+        let Some(rejection) = rejections.next_if(|rejection| rejection.row == index + 1) else {
+            stats.record_outcome(row.r#type, &ProcessOutcome::Applied);
+            continue;
+        };
+        if let RejectionReason::Ignored(reason) = rejection.record.reason {
+            stats.record_outcome(row.r#type, &ProcessOutcome::Ignored(reason));
+        }
+        rejected.report(row.raw.as_byte_record(), &rejection.record)?;
+    }
+    match batch.error {
+        Some(err) => {
+            rejected.flush()?;
+            Err(err)
+        }
+        None => Ok(()),
+    }
+}
+
+/// Applies one parsed row to the engine, keeping the statistics and the rejected rows up to
+/// date. Transactions go through the chunk, processed once full; rows reported right away
+/// wait for the rows before them in the chunk to be processed.
+///
+/// In multi-file runs, where records are attributed to their file, deposits and withdrawals
+/// reusing a tx id from an earlier file are rejected, and rejected rows and parse errors name
+/// the file of the row. Rows are checked for tx id order, filtered out or not, when asked.
+async fn apply_record<S: TransactionStore>(
+    engine: &mut PaymentEngine<S>,
+    mut record: ParsedRecord,
+    options: &CliOptions,
+    stats: &mut RunStats,
+    rejected: &mut RejectedRows,
+    chunk: &mut RowChunk,
+    order: Option<&mut TxOrderWatch>,
+) -> Result<(), PaymentError> {
+    let location = match &record.source {
+        Some(source) => source.location(record.line),
+        None => record.line.to_string(),
+    };
+    let source = record.source.as_ref().map(|source| source.path.to_string());
+    let source_ref = record.source_ref();
+    let locate = |rejection: RejectionRecord| match &source {
+        Some(path) => rejection.with_source(path),
+        None => rejection,
+    };
+    if let Some(offset) = options.tx_offset(record.source.as_ref().map_or(0, |source| source.index)) {
+        record.transaction = record.transaction.and_then(|txn| TxOffset(offset).remap(txn));
+    }
+    stats.record_parsed(&record.transaction);
+    match record.transaction {
+        Ok(txn) => {
+            if let Some(Err(err)) = order.map(|order| order.check(&txn, &location, stats)) {
+                process_chunk(engine, chunk, stats, rejected).await?;
+                rejected.flush()?;
+                return Err(err);
+            }
+            if options.filter_input
+                && options
+                    .clients
+                    .as_ref()
+                    .is_some_and(|clients| !clients.contains(txn.client))
+            {
+                stats.rows_filtered += 1;
+                return Ok(());
+            }
+            if let Some(original) = earlier_use(engine, &txn, record.source.as_ref()).await? {
+                process_chunk(engine, chunk, stats, rejected).await?;
+                let reason = IgnoreReason::DuplicateTransaction;
+                let detail = format!("{}: tx {} already at {}", reason.as_str(), txn.tx, original);
+                let rejection = locate(RejectionRecord::ignored(&txn, reason, detail).with_line(Some(record.line)));
+                stats.record_outcome(txn.r#type, &ProcessOutcome::Ignored(reason));
+                rejected.report(record.raw.as_byte_record(), &rejection)?;
+                engine.warn(EngineWarning::Rejected(rejection.clone()));
+                if engine.config().fail_on_ignore {
+                    rejected.flush()?;
+                    return Err(rejection.to_error(format!("line {}", location)));
+                }
+                return Ok(());
+            }
+            chunk.rows.push(ChunkRow {
+                r#type: txn.r#type,
+                raw: record.raw,
+            });
+            chunk.transactions.push(BatchRow {
+                transaction: Ok(txn),
+                source: Some(source_ref),
+            });
+            if chunk.rows.len() >= chunk.size {
+                process_chunk(engine, chunk, stats, rejected).await?;
+            }
+        }
+        Err(err) => {
+            process_chunk(engine, chunk, stats, rejected).await?;
+            let err = match (err, &source) {
+                (PaymentError::CsvParseError(msg), Some(path)) => {
+                    PaymentError::CsvParseError(format!("{}: {}", path, msg))
+                }
+                (err, _) => err,
+            };
+            let rejection = locate(RejectionRecord::unparseable(record.line, &err));
+            let raw = record.undecoded.as_ref().unwrap_or(record.raw.as_byte_record());
+            rejected.report(raw, &rejection)?;
+            // an input over its limits is never read further, even with --lenient
+            if options.lenient && !matches!(err, PaymentError::InputLimitExceeded { .. }) {
+                engine.warn(EngineWarning::Rejected(rejection));
+            } else {
+                rejected.flush()?;
+                return Err(PaymentError::AtRow {
+                    line: record.line,
+                    file: source,
+                    error: Box::new(err),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks the engine's final state: reports every caught panic and failed invariant on stderr,
+/// the ledger included, records the open disputes, suspected duplicates and chargeback fees in
+/// the statistics and writes the disputes, locked accounts, debtors, losses, merchant and
+/// duplicates reports and the clients JSON dump if asked.
+fn finish_run<S: TransactionStore>(
+    engine: &PaymentEngine<S>,
+    options: &CliOptions,
+    stats: &mut RunStats,
+) -> Result<(), PaymentError> {
+    for panic in engine.caught_panics() {
+        print_diagnostic(options.errors, format_args!("internal error: {}", panic), Diagnostic::from(panic));
+        stats.caught_panics += 1;
+    }
+    for violation in engine.check_invariants() {
+        let warning = EngineWarning::InvariantViolated(violation);
+        print_diagnostic(options.errors, &warning, Diagnostic::from(&warning));
+        stats.invariant_violations += 1;
+    }
+    if let Some(drift) = engine.check_totals() {
+        print_diagnostic(
+            options.errors,
+            format_args!("invariant violated: {}", drift),
+            Diagnostic::from(&drift),
+        );
+        stats.invariant_violations += 1;
+    }
+    record_ledger(engine.check_ledger(), options.errors, stats);
+    stats.total_available = engine.total_available();
+    stats.total_held = engine.total_held();
+    stats.total_funds = engine.total_funds();
+    stats.locked_accounts = engine.locked_count();
+    if options.checksum {
+        stats.checksum = Some(engine.state_digest());
+    }
+
+    stats.open_disputes = engine.disputed_transactions.len() as u64;
+    stats.open_dispute_held = engine.open_dispute_held()?.to_f64();
+    if let Some(path) = &options.disputes_report {
+        let file_error = |err: std::io::Error| PaymentError::file(path, err);
+        let file = File::create(path).map_err(file_error)?;
+        engine
+            .write_open_disputes(BufWriter::new(file))
+            .map_err(file_error)?;
+    }
+    if let Some(path) = &options.locked_report {
+        let file_error = |err: std::io::Error| PaymentError::file(path, err);
+        let file = File::create(path).map_err(file_error)?;
+        engine
+            .write_locked_accounts(BufWriter::new(file))
+            .map_err(file_error)?;
+    }
+    stats.suspected_duplicates = engine.suspected_duplicates.len() as u64;
+    stats.collected_chargeback_fees = engine.collected_chargeback_fees()?.to_f64();
+    stats.interest_paid = engine.interest_paid()?.to_f64();
+    stats.storage_retries = engine.storage_retries();
+    stats.rows_not_retained = engine.unretained_transactions();
+    stats.dust_transactions = engine.dust_transactions();
+    if let Some(path) = &options.duplicates_report {
+        let file_error = |err: std::io::Error| PaymentError::file(path, err);
+        let file = File::create(path).map_err(file_error)?;
+        engine
+            .write_suspected_duplicates(BufWriter::new(file))
+            .map_err(file_error)?;
     }
+    if let Some(path) = &options.debtors_report {
+        let file_error = |err: std::io::Error| PaymentError::file(path, err);
+        let file = File::create(path).map_err(file_error)?;
+        engine
+            .write_debtors_report(BufWriter::new(file))
+            .map_err(file_error)?;
+    }
+    if let Some(path) = &options.losses_report {
+        let file_error = |err: std::io::Error| PaymentError::file(path, err);
+        let file = File::create(path).map_err(file_error)?;
+        engine
+            .write_losses_report(BufWriter::new(file))
+            .map_err(file_error)?;
+    }
+    if let Some(path) = &options.merchant_report {
+        let file_error = |err: std::io::Error| PaymentError::file(path, err);
+        let file = File::create(path).map_err(file_error)?;
+        engine
+            .write_merchant_stats(BufWriter::new(file))
+            .map_err(file_error)?;
+    }
+    if let Some(path) = &options.accruals_report {
+        let file_error = |err: std::io::Error| PaymentError::file(path, err);
+        let file = File::create(path).map_err(file_error)?;
+        engine
+            .write_accruals(BufWriter::new(file))
+            .map_err(file_error)?;
+    }
+    match &options.dump_clients_json {
+        Some(path) if options.dry_run => eprintln!("dry run: {} left as it was", path),
+        Some(path) => std::fs::write(path, engine.clients_json()? + "\n")
+            .map_err(|err| PaymentError::file(path, err))?,
+        None => {}
+    }
+    if options.dry_run {
+        write_dry_run_diff(engine, options, std::io::stderr().lock())?;
+    }
+    Ok(())
+}
 
-    // Output the final account states to stdout (CSV format)
-    engine.output_client_states().await;
+/// Records the ledger of the run in the statistics, reporting it on stderr and counting it as
+/// an invariant violation if it doesn't balance.
+fn record_ledger(ledger: Ledger, errors: ErrorFormat, stats: &mut RunStats) {
+    if !ledger.balances() {
+        print_diagnostic(
+            errors,
+            format_args!("invariant violated: ledger doesn't balance: {}", ledger),
+            Diagnostic::from(&ledger),
+        );
+        stats.invariant_violations += 1;
+    }
+    stats.ledger = Some(ledger);
+}
+
+/// Pays the interest of `--accrue`, once the inputs are processed and before the accounts are
+/// written.
+fn accrue<S: TransactionStore>(engine: &mut PaymentEngine<S>, options: &CliOptions) -> Result<(), PaymentError> {
+    if let Some(rate) = options.accrue {
+        engine.apply_accrual(rate)?;
+    }
     Ok(())
 }
 
+/// Writes the index of the deposits the engine kept, if asked with `--emit-deposit-index`.
+async fn emit_deposit_index<S: TransactionStore>(
+    engine: &mut PaymentEngine<S>,
+    options: &CliOptions,
+) -> Result<(), PaymentError> {
+    match &options.emit_deposit_index {
+        Some(path) => DepositIndex::of_engine(engine).await?.save(path),
+        None => Ok(()),
+    }
+}
+
+/// Writes the transactions the engine retained, with their states, if asked with
+/// `--export-transactions`.
+async fn export_transactions<S: TransactionStore>(
+    engine: &mut PaymentEngine<S>,
+    options: &CliOptions,
+) -> Result<(), PaymentError> {
+    let Some(path) = &options.export_transactions else {
+        return Ok(());
+    };
+    let file = File::create(path).map_err(|err| PaymentError::file(path, err))?;
+    engine.export_transactions(BufWriter::new(file)).await
+}
+
+/// Writes how the run changed the accounts loaded with `--initial-state` (no accounts without
+/// it), in the format of the `diff` command.
+fn write_dry_run_diff<S: TransactionStore, W: Write>(
+    engine: &PaymentEngine<S>,
+    options: &CliOptions,
+    mut w: W,
+) -> Result<(), PaymentError> {
+    let mut current = PaymentEngine::new();
+    if let Some(path) = &options.initial_state {
+        let json = std::fs::read_to_string(path).map_err(|err| PaymentError::file(path, err))?;
+        current.load_clients_json(&json)?;
+    }
+    let diffs = diff::diff_states(
+        canonical_report(&current)?.as_slice(),
+        canonical_report(engine)?.as_slice(),
+    )?;
+    let io_error = |err: std::io::Error| PaymentError::IoError(err.to_string());
+    writeln!(w, "dry run: accounts of {} clients would change", diffs.len()).map_err(io_error)?;
+    for client_diff in &diffs {
+        writeln!(w, "{}", client_diff).map_err(io_error)?;
+    }
+    Ok(())
+}
+
+/// Returns the accounts as the default CSV report.
+fn canonical_report<S: TransactionStore>(engine: &PaymentEngine<S>) -> Result<Vec<u8>, PaymentError> {
+    let mut csv = Vec::new();
+    report::write_report(engine, &mut csv, &report::ReportOptions::default())
+        .map_err(|err| PaymentError::IoError(err.to_string()))?;
+    Ok(csv)
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::{errors::PaymentError, parser::parse_transactions, payment_engine::PaymentEngine};
+    use super::{
+        exit_code_of, record_ledger, run_exit_code, stdout_error, EXIT_HARD_ERROR, EXIT_INVARIANT_FAILED, EXIT_OK,
+    };
+    use payment_engine::{
+        amount::Amount,
+        diagnostics::ErrorFormat,
+        errors::PaymentError,
+        parser::parse_transactions,
+        report::{write_report, ReportOptions},
+        stats::RunStats,
+        PaymentEngine,
+    };
+    use std::io::{self, Write};
+
+    /// A writer taking `room` bytes before failing every write with `kind`.
+    struct FailingWriter {
+        room: usize,
+        kind: io::ErrorKind,
+    }
+
+    impl Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.room == 0 {
+                return Err(io::Error::new(self.kind, "writer closed"));
+            }
+            let taken = buf.len().min(self.room);
+            self.room -= taken;
+            Ok(taken)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    async fn report_into(writer: FailingWriter) -> Result<(), PaymentError> {
+        let csv = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,2,2,2.0\ndeposit,3,3,3.0";
+        let transactions = parse_transactions(Box::new(stringreader::StringReader::new(csv))).await?;
+        let mut engine = PaymentEngine::new();
+        engine.process_all(transactions).await.into_result()?;
+        write_report(&engine, writer, &ReportOptions::default()).map_err(stdout_error)
+    }
+
+    #[tokio::test]
+    async fn failed_output_writes_are_hard_errors() {
+        let err = report_into(FailingWriter { room: 40, kind: io::ErrorKind::StorageFull })
+            .await
+            .unwrap_err();
+        assert!(matches!(err, PaymentError::OutputError { ref path_or_stream, .. } if path_or_stream == "stdout"));
+        assert_eq!(err.to_string(), "Output error: stdout: writer closed");
+        assert!(!err.is_broken_pipe());
+        assert_eq!(exit_code_of(&err), EXIT_HARD_ERROR);
+    }
+
+    #[tokio::test]
+    async fn an_unbalanced_ledger_fails_the_run() -> Result<(), PaymentError> {
+        let csv = "type,client,tx,amount\ndeposit,1,1,5.0\nwithdrawal,1,2,1.5\ndeposit,2,3,2.0";
+        let transactions = parse_transactions(Box::new(stringreader::StringReader::new(csv))).await?;
+        let mut engine = PaymentEngine::new();
+        engine.process_all(transactions).await.into_result()?;
+        let mut stats = RunStats::start();
+        record_ledger(engine.check_ledger(), ErrorFormat::Json, &mut stats);
+        assert_eq!((stats.invariant_violations, run_exit_code(&stats, false, false)), (0, EXIT_OK));
+        assert!(stats.to_json().contains("\"delta\":0.0000}"));
+
+        // every deposit credited a unit it didn't bring in
+        let transactions = parse_transactions(Box::new(stringreader::StringReader::new(csv))).await?;
+        let mut engine = PaymentEngine::new().with_deposit_skew(Amount::from_units(1).unwrap());
+        engine.process_all(transactions).await.into_result()?;
+        let mut stats = RunStats::start();
+        record_ledger(engine.check_ledger(), ErrorFormat::Json, &mut stats);
+        assert_eq!(stats.invariant_violations, 1);
+        assert_eq!(run_exit_code(&stats, false, false), EXIT_INVARIANT_FAILED);
+        let mut summary = Vec::new();
+        stats.write_summary(&mut summary).expect("writing to memory");
+        let summary = String::from_utf8(summary).expect("the summary is text");
+        assert!(summary.contains(
+            "ledger: deposits 7.0000 - withdrawals 1.5000 - chargebacks 0.0000 - fees 0.0000 + adjustments 0.0000 + \
+             opening 0.0000 = 5.5000, accounts total 5.5002, delta 0.0002\n"
+        ));
+        assert!(stats.to_json().contains(
+            "\"ledger\":{\"deposits\":7.0000,\"withdrawals\":1.5000,\"chargebacks\":0.0000,\
+             \"chargeback_fees\":0.0000,\"adjustments\":0.0000,\"opening\":0.0000,\"expected_total\":5.5000,\
+             \"accounts_total\":5.5002,\"delta\":0.0002}"
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn broken_pipes_exit_cleanly() {
+        let err = report_into(FailingWriter { room: 0, kind: io::ErrorKind::BrokenPipe })
+            .await
+            .unwrap_err();
+        assert!(err.is_broken_pipe());
+        assert_eq!(exit_code_of(&err), EXIT_OK);
+    }
 
     #[tokio::test]
     async fn can_parse_process_print_account_balances_correctly() -> Result<(), PaymentError> {
@@ -49,11 +1499,9 @@ mod tests {
         let transactions = parse_transactions(Box::new(str_buf)).await?;
         let mut engine = PaymentEngine::new();
 
-        for txn in transactions {
-            engine.process_transaction(txn?).await;
-        }
+        engine.process_all(transactions).await.into_result()?;
 
-        engine.output_client_states().await;
+        engine.output_client_states().await?;
 
         Ok(())
     }
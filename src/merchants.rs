@@ -0,0 +1,164 @@
+use crate::{report::csv_field, types::TransactionType};
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    sync::Arc,
+};
+
+/// Header line of the merchant statistics report.
+pub const MERCHANT_STATS_HEADER: &str =
+    "merchant,deposits,deposit_volume,disputes,chargebacks,chargeback_volume,chargeback_rate";
+
+/// Activity of the deposits made through one merchant.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MerchantStats {
+    pub deposits: u64,
+    pub deposit_volume: f64,
+    /// Disputes opened on the merchant's deposits, whatever their outcome.
+    pub disputes: u64,
+    pub chargebacks: u64,
+    pub chargeback_volume: f64,
+}
+
+impl MerchantStats {
+    /// Returns the share of the merchant's deposits that were charged back, `0` without
+    /// deposits.
+    pub fn chargeback_rate(&self) -> f64 {
+        if self.deposits == 0 {
+            0.0
+        } else {
+            self.chargebacks as f64 / self.deposits as f64
+        }
+    }
+}
+
+/// The statistics of one merchant of a `MerchantTable` as they were, by id, with the number of
+/// merchants then known.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct MerchantSnapshot {
+    merchants: usize,
+    stats: Option<(u32, MerchantStats)>,
+}
+
+/// Per merchant statistics, keyed by an interned id so each merchant name is kept only once
+/// however many rows carry it. Stored deposits share the table's copy of their merchant's name.
+#[derive(Debug, Clone, Default)]
+pub struct MerchantTable {
+    ids: HashMap<Arc<str>, u32>,
+    names: Vec<Arc<str>>,
+    stats: Vec<MerchantStats>,
+}
+
+impl MerchantTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the id of a merchant, assigning the next one the first time it is seen.
+    pub fn intern(&mut self, merchant: &Arc<str>) -> u32 {
+        if let Some(id) = self.ids.get(merchant) {
+            return *id;
+        }
+        let id = self.names.len() as u32;
+        self.ids.insert(merchant.clone(), id);
+        self.names.push(merchant.clone());
+        self.stats.push(MerchantStats::default());
+        id
+    }
+
+    /// Returns the table's copy of a merchant's name if it was seen, or else `merchant` itself,
+    /// which `intern` then keeps, so that every stored deposit of a merchant shares one name.
+    pub(crate) fn shared(&self, merchant: Arc<str>) -> Arc<str> {
+        match self.ids.get(&merchant) {
+            Some(id) => self.names[*id as usize].clone(),
+            None => merchant,
+        }
+    }
+
+    /// Returns `true` if no merchant was ever seen.
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    /// Returns the statistics of a merchant, if it was ever seen.
+    pub fn get(&self, merchant: &str) -> Option<&MerchantStats> {
+        self.ids.get(merchant).map(|id| &self.stats[*id as usize])
+    }
+
+    /// Counts an applied deposit, dispute or chargeback of `amount` on a merchant's deposit,
+    /// or takes it back out when `revert` is set (the transaction was undone). Other
+    /// transaction types are ignored.
+    pub fn record(&mut self, merchant: &Arc<str>, r#type: TransactionType, amount: f64, revert: bool) {
+        let id = self.intern(merchant);
+        let stats = &mut self.stats[id as usize];
+        let (step, volume) = if revert { (-1, -amount) } else { (1, amount) };
+        match r#type {
+            TransactionType::Deposit => {
+                stats.deposits = stats.deposits.saturating_add_signed(step);
+                stats.deposit_volume += volume;
+            }
+            TransactionType::Dispute => stats.disputes = stats.disputes.saturating_add_signed(step),
+            TransactionType::Chargeback => {
+                stats.chargebacks = stats.chargebacks.saturating_add_signed(step);
+                stats.chargeback_volume += volume;
+            }
+            _ => {}
+        }
+    }
+
+    /// Takes the statistics of one merchant as they are, so that `restore` can undo whatever is
+    /// recorded for it next, interning it included.
+    pub(crate) fn snapshot(&self, merchant: &str) -> MerchantSnapshot {
+        MerchantSnapshot {
+            merchants: self.names.len(),
+            stats: self.ids.get(merchant).map(|id| (*id, self.stats[*id as usize])),
+        }
+    }
+
+    /// Puts back the statistics taken by `snapshot`, forgetting the merchants seen since.
+    pub(crate) fn restore(&mut self, snapshot: MerchantSnapshot) {
+        if let Some((id, stats)) = snapshot.stats {
+            self.stats[id as usize] = stats;
+        }
+        for name in self.names.drain(snapshot.merchants..) {
+            self.ids.remove(&name);
+        }
+        self.stats.truncate(snapshot.merchants);
+    }
+
+    /// Adds the statistics of another table to this one, merchant by merchant.
+    pub fn merge(&mut self, other: MerchantTable) {
+        for (name, stats) in other.names.into_iter().zip(other.stats) {
+            let id = self.intern(&name);
+            let merged = &mut self.stats[id as usize];
+            merged.deposits += stats.deposits;
+            merged.deposit_volume += stats.deposit_volume;
+            merged.disputes += stats.disputes;
+            merged.chargebacks += stats.chargebacks;
+            merged.chargeback_volume += stats.chargeback_volume;
+        }
+    }
+
+    /// Writes the statistics of every merchant as a CSV ordered by merchant name.
+    pub fn write<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let mut ids: Vec<usize> = (0..self.names.len()).collect();
+        ids.sort_by(|a, b| self.names[*a].cmp(&self.names[*b]));
+
+        writeln!(w, "{}", MERCHANT_STATS_HEADER)?;
+        for id in ids {
+            let stats = &self.stats[id];
+            writeln!(
+                w,
+                "{},{},{:.4},{},{},{:.4},{:.4}",
+                csv_field(&self.names[id]),
+                stats.deposits,
+                stats.deposit_volume,
+                stats.disputes,
+                stats.chargebacks,
+                stats.chargeback_volume,
+                stats.chargeback_rate()
+            )?;
+        }
+        w.flush()
+    }
+}
@@ -0,0 +1,754 @@
+//! Batches of transactions and account states as MessagePack, for `--input-format msgpack` and
+//! `--format msgpack`.
+//!
+//! An input is a top-level array of maps keyed like the CSV columns, read one element at a time
+//! and deserialized like a CSV row; a report is an array of maps keyed like the report's columns.
+//! Amounts are strings both ways, so no precision is lost to floats.
+
+use crate::{
+    amount::Amount,
+    errors::PaymentError,
+    parser::ParsedRecord,
+    payment_engine::PaymentEngine,
+    report::{self, ReportFormat, ReportOptions},
+    store::TransactionStore,
+    types::{Client, ClientId, Transaction},
+};
+use csv::StringRecord;
+use std::io::{self, BufReader, Read, Write};
+
+/// Keys of the elements of MessagePack inputs, which are also the columns of their raw records.
+/// Other keys are ignored.
+pub const MSGPACK_COLUMNS: &str = "type,client,tx,amount,reason,merchant,timestamp,memo";
+
+/// Keys every element must have.
+const REQUIRED_KEYS: [&str; 3] = ["type", "client", "tx"];
+
+/// Longest string read. A longer one is taken for a corrupted length rather than allocated.
+pub const MAX_STRING_BYTES: u64 = 64 * 1024;
+
+/// Deepest nesting of the values skipped under unknown keys.
+const MAX_DEPTH: usize = 32;
+
+/// Largest element whose bytes are kept to be quarantined if it can't be decoded.
+const MAX_KEPT_BYTES: usize = 64 * 1024;
+
+/// Reads the elements of a top-level MessagePack array, yielding them with their index (from 1)
+/// as raw records under `MSGPACK_COLUMNS`. An element that isn't a map of scalars, or lacks a
+/// required key, is an error followed by the next element, but bytes that aren't MessagePack end
+/// the stream, as the elements after them can't be found.
+pub struct MsgpackReader<R> {
+    decoder: Decoder<R>,
+    /// Elements left in the array, `None` until its header is read.
+    remaining: Option<u64>,
+    index: u64,
+    done: bool,
+}
+
+impl<R: Read> MsgpackReader<R> {
+    pub fn new(reader: R) -> Self {
+        MsgpackReader {
+            decoder: Decoder::new(reader),
+            remaining: None,
+            index: 0,
+            done: false,
+        }
+    }
+
+    /// Reads the next element, `None` after the last one. The outer error is of the encoding and
+    /// ends the stream, the inner one of the element.
+    fn next_element(&mut self) -> Option<Result<Result<StringRecord, String>, String>> {
+        let remaining = match self.remaining {
+            Some(remaining) => remaining,
+            None => match self.decoder.array_len() {
+                Ok(len) => len,
+                Err(reason) => return Some(Err(reason)),
+            },
+        };
+        if remaining == 0 {
+            return None;
+        }
+        self.remaining = Some(remaining - 1);
+        Some(self.decoder.element())
+    }
+
+    /// Takes the bytes of the last element read, which failed to decode if it was an error.
+    /// Empty if the element was too large to keep.
+    pub fn undecoded(&mut self) -> Vec<u8> {
+        self.decoder.element.take().unwrap_or_default()
+    }
+}
+
+impl<R: Read> Iterator for MsgpackReader<R> {
+    type Item = (u64, Result<StringRecord, PaymentError>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let element = self.next_element()?;
+        self.index += 1;
+        let index = self.index;
+        let element = match element {
+            Ok(element) => element,
+            Err(reason) => {
+                self.done = true;
+                Err(reason)
+            }
+        };
+        Some((index, element.map_err(|reason| PaymentError::DecodeError { index, reason })))
+    }
+}
+
+/// Parses the transactions of a MessagePack array from a reader, one element at a time like
+/// `parser::parse_transactions` reads rows. Invalid elements are `PaymentError::DecodeError`s
+/// with their index in the array.
+pub fn parse_transactions_msgpack<R: Read>(
+    reader: R,
+) -> impl Iterator<Item = Result<Transaction, PaymentError>> {
+    let header = columns();
+    MsgpackReader::new(reader)
+        .map(move |(index, record)| record.and_then(|record| deserialized(index, &record, &header)))
+}
+
+/// Parses the transactions of a MessagePack array like `parser::parse_records` parses CSV rows:
+/// the records' line is the index of their element, and their raw fields are under
+/// `MSGPACK_COLUMNS`, returned as the header.
+pub fn parse_records_msgpack(
+    reader: Box<dyn Read>,
+) -> (StringRecord, Box<dyn Iterator<Item = ParsedRecord>>) {
+    let header = columns();
+    let parsing_header = header.clone();
+    let mut elements = MsgpackReader::new(reader);
+    let records = std::iter::from_fn(move || {
+        let (index, record) = elements.next()?;
+        Some(match record {
+            Ok(raw) => ParsedRecord {
+                line: index,
+                transaction: deserialized(index, &raw, &parsing_header),
+                raw,
+                undecoded: None,
+                source: None,
+            },
+            Err(err) => ParsedRecord::undecodable_message(index, &elements.undecoded(), err),
+        })
+    });
+    (header, Box::new(records))
+}
+
+/// Writes the account states of every client as a MessagePack array, with the default report
+/// options.
+pub fn write_client_states_msgpack<S: TransactionStore, W: Write>(
+    engine: &PaymentEngine<S>,
+    w: W,
+) -> io::Result<()> {
+    let options = ReportOptions {
+        format: ReportFormat::Msgpack,
+        ..Default::default()
+    };
+    report::write_report(engine, w, &options)
+}
+
+/// Writes transactions as a MessagePack array that `parse_transactions_msgpack` reads back, e.g.
+/// to turn a CSV input into a batch. Keys without a value are left out.
+pub fn write_transactions_msgpack<W: Write>(
+    mut w: W,
+    transactions: &[Transaction],
+) -> io::Result<()> {
+    let mut out = Vec::new();
+    push_array_len(&mut out, transactions.len());
+    for txn in transactions {
+        let optional = [
+            ("amount", txn.amount.map(|amount| amount.to_string())),
+            ("reason", txn.reason.clone()),
+            ("merchant", txn.merchant.as_deref().map(str::to_owned)),
+            ("memo", txn.memo.clone()),
+        ];
+        let present = optional.iter().filter(|(_, value)| value.is_some()).count();
+        push_map_len(&mut out, 3 + present + usize::from(txn.timestamp.is_some()));
+        push_str(&mut out, "type");
+        push_str(&mut out, txn.r#type.as_str());
+        push_str(&mut out, "client");
+        push_uint(&mut out, txn.client.into());
+        push_str(&mut out, "tx");
+        push_uint(&mut out, txn.tx.into());
+        for (key, value) in &optional {
+            if let Some(value) = value {
+                push_str(&mut out, key);
+                push_str(&mut out, value);
+            }
+        }
+        if let Some(timestamp) = txn.timestamp {
+            push_str(&mut out, "timestamp");
+            push_uint(&mut out, timestamp);
+        }
+    }
+    w.write_all(&out)
+}
+
+/// Appends one client's map of the account states report, its amounts formatted like the JSON
+/// report's but as strings, from their fixed-point units.
+pub(crate) fn push_client(
+    out: &mut Vec<u8>,
+    client_id: ClientId,
+    client: &Client,
+    options: &ReportOptions,
+) {
+    let amount = |out: &mut Vec<u8>, key: &str, amount: Amount| {
+        push_str(out, key);
+        push_str(out, &options.output.format_amount(amount));
+    };
+    push_map_len(out, if options.extended { 10 } else { 5 });
+    push_str(out, "client");
+    push_uint(out, client_id.into());
+    amount(out, "available", client.available);
+    amount(out, "held", client.held);
+    amount(out, "total", client.total);
+    push_str(out, "locked");
+    out.push(if client.locked { TRUE } else { FALSE });
+    if options.extended {
+        for (key, count) in [
+            ("open_disputes", client.open_disputes),
+            ("disputes", client.disputes),
+            ("chargebacks", client.chargebacks),
+        ] {
+            push_str(out, key);
+            push_uint(out, count.into());
+        }
+        amount(out, "open_dispute_held", client.open_dispute_held);
+        push_str(out, "status_reason");
+        match client.status_reason {
+            Some(reason) => push_str(out, reason.as_str()),
+            None => out.push(NIL),
+        }
+    }
+}
+
+pub(crate) fn push_array_len(out: &mut Vec<u8>, len: usize) {
+    push_len(out, len, 0x90, 0xdc);
+}
+
+fn push_map_len(out: &mut Vec<u8>, len: usize) {
+    push_len(out, len, 0x80, 0xde);
+}
+
+/// Appends the header of an array or map: `fix | len` for up to 15 elements, else `marker` and
+/// a 16-bit length or `marker + 1` and a 32-bit one.
+fn push_len(out: &mut Vec<u8>, len: usize, fix: u8, marker: u8) {
+    if len < 16 {
+        out.push(fix | len as u8);
+    } else if let Ok(len) = u16::try_from(len) {
+        out.push(marker);
+        out.extend_from_slice(&len.to_be_bytes());
+    } else {
+        out.push(marker + 1);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+fn push_str(out: &mut Vec<u8>, value: &str) {
+    let len = value.len();
+    if len < 32 {
+        out.push(0xa0 | len as u8);
+    } else if let Ok(len) = u8::try_from(len) {
+        out.extend_from_slice(&[0xd9, len]);
+    } else if let Ok(len) = u16::try_from(len) {
+        out.push(0xda);
+        out.extend_from_slice(&len.to_be_bytes());
+    } else {
+        out.push(0xdb);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+    out.extend_from_slice(value.as_bytes());
+}
+
+/// Appends an unsigned integer in the smallest encoding that holds it.
+fn push_uint(out: &mut Vec<u8>, value: u64) {
+    if value < 0x80 {
+        out.push(value as u8);
+    } else if let Ok(value) = u8::try_from(value) {
+        out.extend_from_slice(&[0xcc, value]);
+    } else if let Ok(value) = u16::try_from(value) {
+        out.push(0xcd);
+        out.extend_from_slice(&value.to_be_bytes());
+    } else if let Ok(value) = u32::try_from(value) {
+        out.push(0xce);
+        out.extend_from_slice(&value.to_be_bytes());
+    } else {
+        out.push(0xcf);
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+const NIL: u8 = 0xc0;
+const FALSE: u8 = 0xc2;
+const TRUE: u8 = 0xc3;
+
+fn columns() -> StringRecord {
+    StringRecord::from(MSGPACK_COLUMNS.split(',').collect::<Vec<_>>())
+}
+
+/// Deserializes an element's raw record like a CSV row, naming the key of a field that doesn't.
+fn deserialized(
+    index: u64,
+    record: &StringRecord,
+    header: &StringRecord,
+) -> Result<Transaction, PaymentError> {
+    record.deserialize(Some(header)).map_err(|err| {
+        let reason = match err.kind() {
+            csv::ErrorKind::Deserialize { err, .. } => {
+                let key = err.field().and_then(|field| header.get(field as usize));
+                match key {
+                    Some(key) => format!("key `{}`: {}", key, err.kind()),
+                    None => err.kind().to_string(),
+                }
+            }
+            _ => err.to_string(),
+        };
+        PaymentError::DecodeError { index, reason }
+    })
+}
+
+/// A scalar read in place of a string field.
+enum Scalar {
+    Text(String),
+    /// A nested value, which can't be a field.
+    Nested(&'static str),
+}
+
+/// Reads MessagePack values, counting the bytes read to tell where the encoding goes wrong.
+struct Decoder<R> {
+    reader: BufReader<R>,
+    offset: u64,
+    /// The bytes of the element being read, `None` once over `MAX_KEPT_BYTES`.
+    element: Option<Vec<u8>>,
+}
+
+impl<R: Read> Decoder<R> {
+    fn new(reader: R) -> Self {
+        Decoder {
+            reader: BufReader::new(reader),
+            offset: 0,
+            element: None,
+        }
+    }
+
+    /// Keeps bytes read as part of the current element.
+    fn keep(&mut self, bytes: &[u8]) {
+        if let Some(element) = &mut self.element {
+            match element.len() + bytes.len() > MAX_KEPT_BYTES {
+                true => self.element = None,
+                false => element.extend_from_slice(bytes),
+            }
+        }
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        let mut bytes = [0; N];
+        self.reader.read_exact(&mut bytes).map_err(|err| match err.kind() {
+            io::ErrorKind::UnexpectedEof => {
+                format!("the input ends within a value, after byte {}", self.offset)
+            }
+            _ => format!("unreadable input at byte {}: {}", self.offset, err),
+        })?;
+        self.offset += N as u64;
+        self.keep(&bytes);
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    /// Reads the big-endian length of `size` bytes following a marker.
+    fn len(&mut self, size: u32) -> Result<u64, String> {
+        Ok(match size {
+            1 => self.byte()?.into(),
+            2 => u16::from_be_bytes(self.bytes()?).into(),
+            _ => u32::from_be_bytes(self.bytes()?).into(),
+        })
+    }
+
+    /// Reads the header of the top-level array.
+    fn array_len(&mut self) -> Result<u64, String> {
+        let at = self.offset;
+        match self.byte()? {
+            marker @ 0x90..=0x9f => Ok(u64::from(marker & 0x0f)),
+            0xdc => self.len(2),
+            0xdd => self.len(4),
+            marker => Err(format!(
+                "the input isn't an array: marker {:#04x} at byte {}",
+                marker, at
+            )),
+        }
+    }
+
+    /// Reads an element, which should be a map, into a raw record under `MSGPACK_COLUMNS`.
+    fn element(&mut self) -> Result<Result<StringRecord, String>, String> {
+        self.element = Some(Vec::new());
+        let at = self.offset;
+        let marker = self.byte()?;
+        let pairs = match marker {
+            0x80..=0x8f => u64::from(marker & 0x0f),
+            0xde => self.len(2)?,
+            0xdf => self.len(4)?,
+            marker => {
+                let kind = self.skip_after(marker, at, 0)?;
+                return Ok(Err(format!("the element is {}, not a map", kind)));
+            }
+        };
+
+        let columns: Vec<&str> = MSGPACK_COLUMNS.split(',').collect();
+        let mut fields: Vec<Option<String>> = vec![None; columns.len()];
+        let mut invalid = None;
+        for _ in 0..pairs {
+            let at = self.offset;
+            let key = match self.scalar()? {
+                Scalar::Text(key) => key,
+                Scalar::Nested(kind) => {
+                    invalid.get_or_insert(format!("a key at byte {} is {}", at, kind));
+                    self.skip(0)?;
+                    continue;
+                }
+            };
+            let Some(column) = columns.iter().position(|column| *column == key) else {
+                self.skip(0)?;
+                continue;
+            };
+            let at = self.offset;
+            match self.scalar()? {
+                Scalar::Text(value) => {
+                    fields[column] = Some(value).filter(|value| !value.is_empty());
+                }
+                Scalar::Nested(kind) => {
+                    invalid.get_or_insert(format!("key `{}` at byte {} is {}", key, at, kind));
+                }
+            }
+        }
+        if let Some(invalid) = invalid {
+            return Ok(Err(invalid));
+        }
+        if let Some(missing) = REQUIRED_KEYS.iter().find(|key| {
+            let column = columns.iter().position(|column| column == *key);
+            column.is_some_and(|column| fields[column].is_none())
+        }) {
+            return Ok(Err(format!("missing key `{}`", missing)));
+        }
+        Ok(Ok(fields.into_iter().map(Option::unwrap_or_default).collect()))
+    }
+
+    /// Reads a value as the text of a field: strings as they are, numbers and booleans as
+    /// written in a CSV row, nil as an empty field. Nested values are skipped.
+    fn scalar(&mut self) -> Result<Scalar, String> {
+        let at = self.offset;
+        let marker = self.byte()?;
+        let text = match marker {
+            0x00..=0x7f => marker.to_string(),
+            0xe0..=0xff => (marker as i8).to_string(),
+            NIL => String::new(),
+            FALSE => "false".to_owned(),
+            TRUE => "true".to_owned(),
+            0xa0..=0xbf => self.string(u64::from(marker & 0x1f), at)?,
+            0xd9 => {
+                let len = self.len(1)?;
+                self.string(len, at)?
+            }
+            0xda => {
+                let len = self.len(2)?;
+                self.string(len, at)?
+            }
+            0xdb => {
+                let len = self.len(4)?;
+                self.string(len, at)?
+            }
+            0xca => f32::from_be_bytes(self.bytes()?).to_string(),
+            0xcb => f64::from_be_bytes(self.bytes()?).to_string(),
+            0xcc => self.byte()?.to_string(),
+            0xcd => u16::from_be_bytes(self.bytes()?).to_string(),
+            0xce => u32::from_be_bytes(self.bytes()?).to_string(),
+            0xcf => u64::from_be_bytes(self.bytes()?).to_string(),
+            0xd0 => i8::from_be_bytes(self.bytes()?).to_string(),
+            0xd1 => i16::from_be_bytes(self.bytes()?).to_string(),
+            0xd2 => i32::from_be_bytes(self.bytes()?).to_string(),
+            0xd3 => i64::from_be_bytes(self.bytes()?).to_string(),
+            marker => return self.skip_after(marker, at, 0).map(Scalar::Nested),
+        };
+        Ok(Scalar::Text(text))
+    }
+
+    fn string(&mut self, len: u64, at: u64) -> Result<String, String> {
+        if len > MAX_STRING_BYTES {
+            return Err(format!(
+                "corrupted string length at byte {}: {} bytes is over the {} bytes of the \
+                 longest string",
+                at, len, MAX_STRING_BYTES
+            ));
+        }
+        let mut bytes = vec![0; len as usize];
+        self.reader.read_exact(&mut bytes).map_err(|_| {
+            format!("the input ends within a string of {} bytes at byte {}", len, at)
+        })?;
+        self.offset += len;
+        self.keep(&bytes);
+        String::from_utf8(bytes).map_err(|_| format!("the string at byte {} isn't UTF-8", at))
+    }
+
+    /// Skips the next value.
+    fn skip(&mut self, depth: usize) -> Result<(), String> {
+        let at = self.offset;
+        let marker = self.byte()?;
+        self.skip_after(marker, at, depth).map(|_| ())
+    }
+
+    /// Skips the rest of the value starting with `marker`, read at byte `at`, and returns what
+    /// kind of value it was.
+    fn skip_after(&mut self, marker: u8, at: u64, depth: usize) -> Result<&'static str, String> {
+        if depth > MAX_DEPTH {
+            return Err(format!("values nested over {} deep at byte {}", MAX_DEPTH, at));
+        }
+        let (kind, bytes, elements) = match marker {
+            0x00..=0x7f | 0xe0..=0xff | 0xcc..=0xd3 | 0xca | 0xcb | NIL | FALSE | TRUE => {
+                let size = match marker {
+                    0xcc | 0xd0 => 1,
+                    0xcd | 0xd1 => 2,
+                    0xce | 0xd2 | 0xca => 4,
+                    0xcf | 0xd3 | 0xcb => 8,
+                    _ => 0,
+                };
+                ("a scalar", size, 0)
+            }
+            0xa0..=0xbf => ("a string", u64::from(marker & 0x1f), 0),
+            0xd9..=0xdb => ("a string", self.len(1 << (marker - 0xd9))?, 0),
+            0xc4..=0xc6 => ("binary data", self.len(1 << (marker - 0xc4))?, 0),
+            0xd4..=0xd8 => ("an extension", 1 + (1 << (marker - 0xd4)), 0),
+            0xc7..=0xc9 => ("an extension", self.len(1 << (marker - 0xc7))? + 1, 0),
+            0x90..=0x9f => ("an array", 0, u64::from(marker & 0x0f)),
+            0xdc | 0xdd => ("an array", 0, self.len(2 << (marker - 0xdc))?),
+            0x80..=0x8f => ("a map", 0, 2 * u64::from(marker & 0x0f)),
+            0xde | 0xdf => ("a map", 0, 2 * self.len(2 << (marker - 0xde))?),
+            _ => return Err(format!("invalid marker {:#04x} at byte {}", marker, at)),
+        };
+        let mut skipped = 0;
+        let mut chunk = [0; 8 * 1024];
+        while skipped < bytes {
+            let want = chunk.len().min((bytes - skipped) as usize);
+            let read = match self.reader.read(&mut chunk[..want]) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(format!("unreadable input at byte {}: {}", self.offset, err)),
+            };
+            self.keep(&chunk[..read]);
+            self.offset += read as u64;
+            skipped += read as u64;
+        }
+        if skipped < bytes {
+            return Err(format!("the input ends within a value, after byte {}", self.offset));
+        }
+        for _ in 0..elements {
+            self.skip(depth + 1)?;
+        }
+        Ok(kind)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        amount::{amount, Amount},
+        errors::{InputLimit, PaymentError},
+        msgpack::{
+            parse_records_msgpack, parse_transactions_msgpack, push_array_len, push_client,
+            write_client_states_msgpack, write_transactions_msgpack, Decoder, Scalar,
+        },
+        parser::{limit_records, InputLimits},
+        payment_engine::PaymentEngine,
+        report::ReportOptions,
+        types::{Client, Transaction},
+    };
+    use std::collections::HashMap;
+
+    fn batch(transactions: &[Transaction]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_transactions_msgpack(&mut bytes, transactions).expect("writes to a Vec");
+        bytes
+    }
+
+    #[tokio::test]
+    async fn transactions_and_account_states_round_trip() -> Result<(), PaymentError> {
+        let mut deposit = Transaction::deposit(1, 1, 10.1234);
+        deposit.timestamp = Some(1_700_000_000);
+        deposit.memo = Some("first deposit".to_owned());
+        let transactions: Vec<Transaction> = [deposit]
+            .into_iter()
+            .chain((2..40).map(|tx| Transaction::deposit(2, tx, 0.5)))
+            .chain([Transaction::withdrawal(1, 40, 0.1234), Transaction::dispute(2, 2)])
+            .collect();
+        let bytes = batch(&transactions);
+        let parsed: Vec<Transaction> =
+            parse_transactions_msgpack(bytes.as_slice()).collect::<Result<_, _>>()?;
+        assert_eq!(parsed, transactions);
+
+        let mut engine = PaymentEngine::new();
+        engine.process_all(Box::new(parse_transactions_msgpack(bytes.as_slice()))).await.into_result()?;
+        let mut report = Vec::new();
+        write_client_states_msgpack(&engine, &mut report).expect("writes to a Vec");
+
+        let mut decoder = Decoder::new(report.as_slice());
+        assert_eq!(decoder.array_len(), Ok(2));
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let pairs = decoder.byte().map(|marker| marker & 0x0f);
+            let client: HashMap<String, String> = (0..pairs.unwrap_or(0))
+                .map(|_| match (decoder.scalar(), decoder.scalar()) {
+                    (Ok(Scalar::Text(key)), Ok(Scalar::Text(value))) => (key, value),
+                    _ => panic!("the report is a map of scalars"),
+                })
+                .collect();
+            clients.push(client);
+        }
+        assert_eq!(clients[0]["client"], "1");
+        assert_eq!((clients[0]["available"].as_str(), clients[0]["total"].as_str()), ("10.0000", "10.0000"));
+        assert_eq!((clients[1]["available"].as_str(), clients[1]["held"].as_str()), ("18.5000", "0.5000"));
+        assert_eq!(clients[1]["locked"], "false");
+        let client = engine.client_view(2).expect("client 2 has an account");
+        assert_eq!(client.total, amount(19.0));
+
+        // amounts are written from their units, past what an f64 holds to the unit too
+        let mut large = Client::new();
+        large.total = Amount::from_units(9_000_000_000_000_000_001).unwrap();
+        let mut map = Vec::new();
+        push_client(&mut map, 3, &large, &ReportOptions::default());
+        let mut decoder = Decoder::new(map.as_slice());
+        decoder.byte().expect("the map's header");
+        let total = (0..5).find_map(|_| match (decoder.scalar(), decoder.scalar()) {
+            (Ok(Scalar::Text(key)), Ok(Scalar::Text(value))) if key == "total" => Some(value),
+            _ => None,
+        });
+        assert_eq!(total.as_deref(), Some("900000000000000.0001"));
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_keys_are_ignored_and_missing_ones_reported() {
+        let mut bytes = Vec::new();
+        push_array_len(&mut bytes, 4);
+        // {"type": "deposit", "client": 1, "tx": 1, "amount": 1.5, "extra": [1, {"a": nil}]}
+        bytes.extend([0x85, 0xa4]);
+        bytes.extend(b"type");
+        bytes.push(0xa7);
+        bytes.extend(b"deposit");
+        bytes.extend([0xa6]);
+        bytes.extend(b"client");
+        bytes.extend([0x01, 0xa2]);
+        bytes.extend(b"tx");
+        bytes.extend([0x01, 0xa6]);
+        bytes.extend(b"amount");
+        bytes.push(0xcb);
+        bytes.extend(1.5f64.to_be_bytes());
+        bytes.push(0xa5);
+        bytes.extend(b"extra");
+        bytes.extend([0x92, 0x01, 0x81, 0xa1, b'a', 0xc0]);
+        // {"type": "deposit", "client": 1}
+        bytes.extend([0x82, 0xa4]);
+        bytes.extend(b"type");
+        bytes.push(0xa7);
+        bytes.extend(b"deposit");
+        bytes.extend([0xa6]);
+        bytes.extend(b"client");
+        bytes.push(0x01);
+        // {"type": "deposit", "client": -1, "tx": 3}
+        bytes.extend([0x83, 0xa4]);
+        bytes.extend(b"type");
+        bytes.push(0xa7);
+        bytes.extend(b"deposit");
+        bytes.extend([0xa6]);
+        bytes.extend(b"client");
+        bytes.extend([0xff, 0xa2]);
+        bytes.extend(b"tx");
+        bytes.push(0x03);
+        // "deposit"
+        bytes.push(0xa7);
+        bytes.extend(b"deposit");
+
+        let results: Vec<String> = parse_transactions_msgpack(bytes.as_slice())
+            .map(|result| result.map_or_else(|err| err.to_string(), |txn| format!("{:?}", txn.amount)))
+            .collect();
+        assert_eq!(results[0], "Some(1.5)");
+        assert_eq!(results[1], "Decode error: message 2: missing key `tx`");
+        assert!(results[2].starts_with("Decode error: message 3: key `client`: "), "{}", results[2]);
+        assert_eq!(results[3], "Decode error: message 4: the element is a string, not a map");
+        assert_eq!(results.len(), 4);
+
+        let (header, records) = parse_records_msgpack(Box::new(std::io::Cursor::new(bytes)));
+        assert_eq!(header.len(), 8);
+        let first = records.into_iter().next().expect("a first element");
+        assert_eq!(first.raw.iter().take(4).collect::<Vec<_>>(), ["deposit", "1", "1", "1.5"]);
+    }
+
+    #[test]
+    fn messages_over_the_input_limits_end_the_stream() {
+        let mut transactions: Vec<Transaction> =
+            (1..=5).map(|tx| Transaction::deposit(1, tx, 1.0)).collect();
+        transactions[1].memo = Some("a memo of 20 bytes..".to_owned());
+        let limits = InputLimits {
+            max_field_bytes: 10,
+            ..InputLimits::default()
+        };
+        let (_, records) = parse_records_msgpack(Box::new(std::io::Cursor::new(batch(&transactions))));
+        let records: Vec<_> = limit_records(records, limits).collect();
+        assert_eq!(records.len(), 2);
+        assert!(matches!(
+            records[1].transaction,
+            Err(PaymentError::InputLimitExceeded { which: InputLimit::FieldBytes, limit: 10, at_line: 2 })
+        ));
+        // the message over the limit is still quarantined as it was read
+        assert_eq!(records[1].raw.get(7), Some("a memo of 20 bytes.."));
+
+        let limits = InputLimits {
+            max_records: 3,
+            ..InputLimits::default()
+        };
+        let (_, records) = parse_records_msgpack(Box::new(std::io::Cursor::new(batch(&transactions))));
+        let results: Vec<_> = limit_records(records, limits).map(|record| record.transaction).collect();
+        assert_eq!(results.len(), 4);
+        assert!(results[..3].iter().all(Result::is_ok));
+        assert!(matches!(results[3], Err(PaymentError::InputLimitExceeded { which: InputLimit::Records, .. })));
+    }
+
+    #[test]
+    fn corrupted_streams_end_with_the_byte_they_go_wrong_at() {
+        let transactions = [Transaction::deposit(1, 1, 1.0), Transaction::deposit(1, 2, 2.0)];
+        let mut bytes = batch(&transactions);
+        // make the marker of the second element's first key invalid
+        let second = bytes.len() / 2 + 1;
+        assert_eq!(bytes[second], 0x84);
+        bytes[second + 1] = 0xc1;
+        let results: Vec<_> = parse_transactions_msgpack(bytes.as_slice()).collect();
+        assert_eq!(results.len(), 2);
+        assert!(results[0].is_ok());
+        let err = results[1].as_ref().map(|_| ()).expect_err("the second element is corrupted");
+        assert_eq!(
+            err.to_string(),
+            format!("Decode error: message 2: invalid marker 0xc1 at byte {}", second + 1)
+        );
+        // the bytes read of the corrupted element are its raw field, in hexadecimal
+        let (_, records) = parse_records_msgpack(Box::new(std::io::Cursor::new(bytes.clone())));
+        let corrupted = records.last().expect("a record for the corrupted element");
+        assert_eq!(corrupted.raw.iter().collect::<Vec<_>>(), ["84c1"]);
+
+        // cut within the first element
+        let bytes = batch(&transactions);
+        let results: Vec<_> = parse_transactions_msgpack(&bytes[..10]).collect();
+        assert!(matches!(
+            &results[..],
+            [Err(PaymentError::DecodeError { index: 1, reason })]
+                if reason == "the input ends within a string of 7 bytes at byte 7"
+        ));
+        let not_an_array: Vec<_> = parse_transactions_msgpack(&b"\xa1x"[..]).collect();
+        assert_eq!(
+            not_an_array[0].as_ref().map_err(ToString::to_string).err().as_deref(),
+            Some("Decode error: message 1: the input isn't an array: marker 0xa1 at byte 0")
+        );
+    }
+}
@@ -0,0 +1,96 @@
+use crate::{
+    report::csv_field,
+    types::{Transaction, TransactionType},
+};
+use std::{
+    fmt,
+    io::{self, Write},
+};
+
+/// Header line of the out of order tx ids report.
+pub const OUT_OF_ORDER_HEADER: &str = "line,tx,max_tx";
+
+/// A deposit or withdrawal whose tx id is lower than one seen earlier in the input.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutOfOrderTx {
+    /// Line of the row, as `path:line` in multi-file runs.
+    pub line: String,
+    pub tx: u32,
+    /// Highest deposit or withdrawal tx id seen before the row.
+    pub max_tx: u32,
+}
+
+impl OutOfOrderTx {
+    /// Writes the out of order transaction as a row of the report, under `OUT_OF_ORDER_HEADER`.
+    pub fn write_row<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "{},{},{}", csv_field(&self.line), self.tx, self.max_tx)
+    }
+}
+
+impl fmt::Display for OutOfOrderTx {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: tx {} comes after tx {}", self.line, self.tx, self.max_tx)
+    }
+}
+
+/// Checks that deposit and withdrawal tx ids only increase through the input, which upstream
+/// guarantees: a decrease usually means a corrupted or mis-ordered file.
+///
+/// Disputes, resolves and chargebacks refer to earlier tx ids by design and aren't checked,
+/// nor are escrow holds, whose ids are their own.
+#[derive(Debug, Default)]
+pub struct TxOrderCheck {
+    max_tx: Option<u32>,
+}
+
+impl TxOrderCheck {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checks the next transaction of the input, found at `line`, returning the out of order
+    /// deposit or withdrawal it is, if so.
+    pub fn check(&mut self, txn: &Transaction, line: impl fmt::Display) -> Option<OutOfOrderTx> {
+        if !matches!(txn.r#type, TransactionType::Deposit | TransactionType::Withdrawal) {
+            return None;
+        }
+        match self.max_tx {
+            Some(max_tx) if txn.tx < max_tx => Some(OutOfOrderTx {
+                line: line.to_string(),
+                tx: txn.tx,
+                max_tx,
+            }),
+            _ => {
+                self.max_tx = Some(txn.tx);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{errors::PaymentError, ordering::TxOrderCheck, parser::parse_records};
+
+    #[tokio::test]
+    async fn flags_funds_moving_transactions_only() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 5, 10.0
+        dispute, 1, 5
+        deposit, 1, 7, 1.0
+        chargeback, 1, 5
+        withdrawal, 1, 6, 1.0
+        withdrawal, 1, 8, 1.0";
+        let str_buf = stringreader::StringReader::new(csv);
+        let (_, records) = parse_records(Box::new(str_buf)).await?;
+
+        let mut check = TxOrderCheck::new();
+        let mut flagged = Vec::new();
+        for record in records {
+            flagged.extend(check.check(&record.transaction?, record.line));
+        }
+        assert_eq!(flagged.len(), 1);
+        assert_eq!(flagged[0].to_string(), "line 6: tx 6 comes after tx 7");
+        Ok(())
+    }
+}
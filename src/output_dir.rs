@@ -0,0 +1,110 @@
+use crate::{
+    errors::PaymentError,
+    payment_engine::PaymentEngine,
+    report::{write_client_report, ReportOptions},
+    store::TransactionStore,
+    types::ClientId,
+};
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+/// Writes one `client_<id>.csv` file per client into `dir`, each holding the header of the
+/// client states CSV and that client's row (or `client_<id>.json` with the client's object
+/// for the JSON format).
+///
+/// The directory is created if needed. To avoid mixing the files of several runs, a non-empty
+/// directory is refused unless `force` is set, in which case existing files with the same
+/// names are overwritten. Returns the number of files written.
+///
+/// # Errors
+///
+/// Returns a `PaymentError::FileError` naming the failing path if the directory can't be
+/// prepared or any file can't be written; files written before the failure are left in place.
+pub fn write_client_files<S: TransactionStore>(
+    engine: &PaymentEngine<S>,
+    dir: &Path,
+    force: bool,
+    options: &ReportOptions,
+) -> Result<usize, PaymentError> {
+    let path_error = |path: &Path, err: io::Error| PaymentError::file(path, err);
+
+    fs::create_dir_all(dir).map_err(|err| path_error(dir, err))?;
+    let non_empty = fs::read_dir(dir)
+        .map_err(|err| path_error(dir, err))?
+        .next()
+        .is_some();
+    if non_empty && !force {
+        return Err(path_error(
+            dir,
+            io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "output directory is not empty, use --force to write into it anyway",
+            ),
+        ));
+    }
+
+    let mut ids: Vec<&ClientId> = engine
+        .clients
+        .keys()
+        .filter(|id| options.clients.is_none_or(|filter| filter.contains(**id)))
+        .collect();
+    ids.sort();
+
+    for id in &ids {
+        let path = dir.join(format!("client_{}.{}", id, options.format.extension()));
+        let file = File::create(&path).map_err(|err| path_error(&path, err))?;
+        let mut w = BufWriter::new(file);
+        write_client_report(&mut w, **id, &engine.clients[*id], options)
+            .and_then(|_| w.flush())
+            .map_err(|err| path_error(&path, err))?;
+    }
+    Ok(ids.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        errors::PaymentError, output_dir::write_client_files, parser::parse_transactions,
+        payment_engine::PaymentEngine, report::ReportOptions,
+    };
+    use std::fs;
+
+    #[tokio::test]
+    async fn can_write_one_file_per_client() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 1.0
+        deposit, 2, 2, 2.0
+        deposit, 3, 3, 3.0
+        dispute, 2, 2";
+        let str_buf = stringreader::StringReader::new(csv);
+        let transactions = parse_transactions(Box::new(str_buf)).await?;
+        let mut engine = PaymentEngine::new();
+        engine.process_all(transactions).await.into_result()?;
+
+        let dir = std::env::temp_dir().join(format!("payment-engine-out-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(write_client_files(&engine, &dir, false, &ReportOptions::default())?, 3);
+
+        let mut names: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["client_1.csv", "client_2.csv", "client_3.csv"]);
+        assert_eq!(
+            fs::read_to_string(dir.join("client_2.csv")).unwrap(),
+            "client,available,held,total,locked\n2,0.0000,2.0000,2.0000,false\n"
+        );
+
+        // a second run must not silently mix its files with the first one's
+        let err = write_client_files(&engine, &dir, false, &ReportOptions::default()).unwrap_err();
+        assert!(err.to_string().contains("not empty"));
+        assert_eq!(write_client_files(&engine, &dir, true, &ReportOptions::default())?, 3);
+
+        fs::remove_dir_all(&dir).unwrap();
+        Ok(())
+    }
+}
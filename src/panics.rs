@@ -0,0 +1,227 @@
+//! Panics caught while processing single transactions, under `EngineConfig::max_panics`, so a
+//! bug hit by one row of a batch doesn't lose the rest of it.
+
+use crate::{
+    invariants::Totals,
+    merchants::MerchantSnapshot,
+    types::{ClientId, SourceRef, TransactionType},
+    undo::UndoEntry,
+};
+use std::{
+    any::Any,
+    fmt,
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    task::Poll,
+};
+
+/// A transaction whose processing panicked. The engine's state is as it was before it, and the
+/// transaction was ignored as `IgnoreReason::InternalError`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaughtPanic {
+    pub tx: u32,
+    pub client: ClientId,
+    pub r#type: TransactionType,
+    /// Where the transaction was read from, if it was processed with a source.
+    pub source: Option<SourceRef>,
+    /// The panic's message, or a placeholder for a payload that isn't a string.
+    pub message: String,
+}
+
+impl fmt::Display for CaughtPanic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} tx {} of client {}", self.r#type.as_str(), self.tx, self.client)?;
+        if let Some(source) = &self.source {
+            write!(f, " at {}", source)?;
+        }
+        write!(f, " panicked: {}", self.message)
+    }
+}
+
+/// Everything a transaction may change, taken before it is processed so a panic can be undone:
+/// the undo entry of its footprint, and the engine-wide counters and tables it may touch.
+pub(crate) struct Checkpoint {
+    pub entry: UndoEntry,
+    pub totals: Totals,
+    pub unretained: u64,
+    pub suspected_duplicates: usize,
+    pub amount_mismatch: Option<(u32, String)>,
+    /// The statistics of the merchant the transaction may count for, if any.
+    pub merchant: Option<MerchantSnapshot>,
+}
+
+/// Awaits `future`, catching a panic in any of its polls as the panic's message.
+///
+/// The future is polled through `AssertUnwindSafe`: its caller restores what it may have left
+/// half changed.
+pub(crate) async fn catch_unwind<F: Future>(future: F) -> Result<F::Output, String> {
+    let mut future = std::pin::pin!(future);
+    std::future::poll_fn(|cx| {
+        match panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(cx))) {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => Poll::Ready(Err(message(payload.as_ref()))),
+        }
+    })
+    .await
+}
+
+/// Returns the message of a panic's payload, as given to `panic!`.
+fn message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        (*message).to_owned()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "(no message)".to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::EngineConfig,
+        errors::PaymentError,
+        parser::parse_transactions,
+        payment_engine::PaymentEngine,
+        report::OutputOrder,
+        store::{InMemoryTransactionStore, TransactionStore},
+        types::{IgnoreReason, ProcessOutcome, SourceRef, Transaction, TransactionType},
+    };
+    use std::future::Future;
+
+    /// A store with a bug: it panics when asked to keep one of the `broken` tx ids.
+    struct PanickingStore {
+        inner: InMemoryTransactionStore,
+        broken: Vec<u32>,
+    }
+
+    impl TransactionStore for PanickingStore {
+        fn insert(
+            &mut self,
+            txn: Transaction,
+        ) -> impl Future<Output = Result<(), PaymentError>> + Send {
+            if self.broken.contains(&txn.tx) {
+                panic!("store bug on tx {}", txn.tx);
+            }
+            self.inner.insert(txn)
+        }
+
+        fn get(
+            &mut self,
+            tx: u32,
+        ) -> impl Future<Output = Result<Option<Transaction>, PaymentError>> + Send {
+            self.inner.get(tx)
+        }
+
+        fn remove(&mut self, tx: u32) -> impl Future<Output = Result<(), PaymentError>> + Send {
+            self.inner.remove(tx)
+        }
+
+        fn len(&self) -> usize {
+            self.inner.len()
+        }
+
+        fn tx_ids(&self) -> Vec<u32> {
+            self.inner.tx_ids()
+        }
+    }
+
+    fn engine(broken: &[u32], max_panics: Option<usize>) -> PaymentEngine<PanickingStore> {
+        PaymentEngine::with_store(PanickingStore {
+            inner: InMemoryTransactionStore::new(),
+            broken: broken.to_vec(),
+        })
+        .with_config(EngineConfig {
+            max_panics,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn a_panicking_transaction_is_undone_and_the_batch_goes_on() -> Result<(), PaymentError> {
+        // the store panics on tx 2 once its deposit was credited and counted for its merchant
+        let csv = "type, client, tx, amount, merchant
+        deposit, 1, 1, 1.0, acme
+        deposit, 1, 2, 2.0, acme
+        deposit, 2, 3, 3.0,
+        dispute, 1, 2,,
+        dispute, 1, 1,,
+        deposit, 2, 4, 4.0, globex";
+        let mut engine = engine(&[2, 4], Some(2));
+        let str_buf = stringreader::StringReader::new(csv);
+        let batch = engine.process_all(parse_transactions(Box::new(str_buf)).await?).await;
+        assert!(batch.error.is_none());
+        assert_eq!((batch.applied, batch.ignored), (3, 3));
+        let details: Vec<&str> =
+            batch.rejections.iter().map(|rejection| rejection.record.detail.as_str()).collect();
+        assert_eq!(
+            details,
+            [
+                "internal_error: panicked: store bug on tx 2",
+                "unknown_transaction",
+                "internal_error: panicked: store bug on tx 4"
+            ]
+        );
+
+        let client = engine.clients[&1];
+        assert_eq!(client.available, 0.0);
+        assert_eq!(client.held, 1.0);
+        assert_eq!(client.total, 1.0);
+        assert_eq!(engine.clients[&2].total, 3.0);
+        assert_eq!(engine.check_totals(), None);
+        assert!(engine.check_invariants().is_empty());
+        let acme = engine.merchants.get("acme").copied().unwrap_or_default();
+        assert_eq!((acme.deposits, acme.disputes), (1, 1));
+        // the merchant first seen on the panicking deposit is forgotten
+        assert!(engine.merchants.get("globex").is_none());
+        let mut report = Vec::new();
+        engine.merchants.write(&mut report).expect("writing to memory");
+        assert_eq!(String::from_utf8(report).expect("the report is text").lines().count(), 2);
+        assert_eq!(engine.caught_panics().len(), 2);
+        assert_eq!(engine.caught_panics()[0].message, "store bug on tx 2");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn a_panic_opening_an_account_leaves_no_account() -> Result<(), PaymentError> {
+        let mut engine = engine(&[1], Some(1));
+        let outcome = engine
+            .process_transaction_from(Transaction::deposit(7, 1, 5.0), Some(SourceRef::line(2)))
+            .await?;
+        assert_eq!(outcome, ProcessOutcome::Ignored(IgnoreReason::InternalError));
+        assert!(engine.clients.is_empty());
+        assert!(engine.client_ids(OutputOrder::FirstSeen).is_empty());
+        let panic = &engine.caught_panics()[0];
+        assert_eq!((panic.tx, panic.r#type), (1, TransactionType::Deposit));
+        assert_eq!(panic.to_string(), "deposit tx 1 of client 7 at line 2 panicked: store bug on tx 1");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn panics_beyond_the_cap_fail_the_batch() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 1.0
+        deposit, 1, 2, 2.0
+        deposit, 1, 3, 3.0
+        deposit, 1, 4, 4.0";
+        let mut engine = engine(&[1, 3], Some(1));
+        let str_buf = stringreader::StringReader::new(csv);
+        let batch = engine.process_all(parse_transactions(Box::new(str_buf)).await?).await;
+        assert_eq!((batch.applied, batch.ignored), (1, 1));
+        let error = batch.error.as_ref().map(PaymentError::to_string);
+        assert_eq!(
+            error.as_deref(),
+            Some("Internal error: deposit tx 3 of client 1 panicked: store bug on tx 3, more than 1 panics")
+        );
+        assert_eq!(engine.clients[&1].total, 2.0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "store bug on tx 1")]
+    async fn panics_unwind_unless_caught() {
+        let mut engine = engine(&[1], None);
+        let _ = engine.process_transaction(Transaction::deposit(1, 1, 1.0)).await;
+    }
+}
@@ -0,0 +1,217 @@
+//! Inputs that share no client, e.g. one file per region, processed each by its own engine on
+//! its own thread and merged into one engine at the end.
+
+use crate::{
+    batch::BatchResult,
+    config::EngineConfig,
+    errors::PaymentError,
+    parser::{self, parse_transactions},
+    payment_engine::PaymentEngine,
+    shared_engine::{self, StreamOptions},
+};
+use std::{
+    panic,
+    path::{Path, PathBuf},
+    thread,
+};
+use tokio::sync::mpsc;
+
+/// Processes every file with its own engine, all at once, and merges the engines in the order
+/// of `paths`. The accounts are those of processing the files one after the other, as long as
+/// no client and no tx id is in two files.
+///
+/// # Errors
+///
+/// Returns the first error of the files, in the order of `paths`, e.g. a file that can't be
+/// opened or a row that fails to parse under `EngineConfig::fail_fast`. A file sharing a
+/// client or a tx id with a file before it is a `PaymentError::MergeFailed` naming it.
+pub async fn process_files_parallel<P: AsRef<Path>>(
+    paths: &[P],
+    config: &EngineConfig,
+) -> Result<PaymentEngine, PaymentError> {
+    Ok(process_files_parallel_batches(paths, config).await?.0)
+}
+
+/// Like `process_files_parallel`, also returning what each file's engine did with its rows, in
+/// the order of `paths`.
+///
+/// # Errors
+///
+/// Returns the errors of `process_files_parallel`.
+pub async fn process_files_parallel_batches<P: AsRef<Path>>(
+    paths: &[P],
+    config: &EngineConfig,
+) -> Result<(PaymentEngine, Vec<BatchResult>), PaymentError> {
+    process_files(paths, config, None).await
+}
+
+/// Like `process_files_parallel_batches`, also sending the report rows of each file's clients
+/// to `rows` as soon as the file is processed, without waiting for the other files. The rows
+/// are those of `shared_engine::write_streamed_report`, the shard of a row being the index of
+/// its file in `paths`.
+///
+/// The rows of the files processed before a failure are sent all the same, so a run that fails
+/// may have streamed part of its report.
+///
+/// # Errors
+///
+/// Returns the errors of `process_files_parallel`, or a `PaymentError::IoError` if the
+/// receiving end of `rows` was dropped.
+pub async fn process_files_parallel_streamed<P: AsRef<Path>>(
+    paths: &[P],
+    config: &EngineConfig,
+    rows: mpsc::Sender<String>,
+    options: StreamOptions,
+) -> Result<(PaymentEngine, Vec<BatchResult>), PaymentError> {
+    process_files(paths, config, Some((rows, options))).await
+}
+
+async fn process_files<P: AsRef<Path>>(
+    paths: &[P],
+    config: &EngineConfig,
+    stream: Option<(mpsc::Sender<String>, StreamOptions)>,
+) -> Result<(PaymentEngine, Vec<BatchResult>), PaymentError> {
+    let paths: Vec<PathBuf> = paths.iter().map(|path| path.as_ref().to_path_buf()).collect();
+    let (worker_paths, worker_config) = (paths.clone(), config.clone());
+    // each file gets a thread of its own, as the parsers' readers can't move between threads
+    let processed = tokio::task::spawn_blocking(move || {
+        thread::scope(|scope| {
+            let workers: Vec<_> = worker_paths
+                .iter()
+                .enumerate()
+                .map(|(index, path)| {
+                    let config = worker_config.clone();
+                    let stream = stream.as_ref().map(|(rows, options)| (index, rows.clone(), *options));
+                    scope.spawn(move || process_file(path, config, stream))
+                })
+                .collect();
+            // only the workers keep a sender, so the stream ends with the last of them
+            drop(stream);
+            workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap_or_else(|err| panic::resume_unwind(err)))
+                .collect::<Vec<_>>()
+        })
+    })
+    .await
+    .unwrap_or_else(|err| panic::resume_unwind(err.into_panic()));
+
+    let mut merged: Option<PaymentEngine> = None;
+    let mut batches = Vec::with_capacity(paths.len());
+    for (path, processed) in paths.into_iter().zip(processed) {
+        let (engine, batch) = processed?;
+        batches.push(batch);
+        merged = Some(match merged {
+            Some(merged) => merged.merge(engine).await.map_err(|source| {
+                PaymentError::MergeFailed {
+                    path,
+                    source: Box::new(source),
+                }
+            })?,
+            None => engine,
+        });
+    }
+    let engine = merged.unwrap_or_else(|| PaymentEngine::new().with_config(config.clone()));
+    Ok((engine, batches))
+}
+
+/// Processes a file with a new engine, on a runtime of the calling thread, then sends the
+/// report rows of its clients as shard `index` if asked.
+fn process_file(
+    path: &Path,
+    config: EngineConfig,
+    stream: Option<(usize, mpsc::Sender<String>, StreamOptions)>,
+) -> Result<(PaymentEngine, BatchResult), PaymentError> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .map_err(|err| PaymentError::IoError(err.to_string()))?;
+    runtime.block_on(async {
+        let transactions = parse_transactions(parser::open_input(path)?).await?;
+        let mut engine = PaymentEngine::new().with_config(config);
+        let batch = engine.process_all(transactions).await.into_result().map_err(|err| match err {
+            PaymentError::CsvParseError(msg) => {
+                PaymentError::CsvParseError(format!("{}: {}", path.display(), msg))
+            }
+            err => err,
+        })?;
+        if let Some((index, rows, options)) = stream {
+            let clients = engine.clients.iter().map(|(id, client)| (*id, *client)).collect();
+            shared_engine::send_rows(index, clients, &rows, options).await?;
+        }
+        Ok((engine, batch))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        config::EngineConfig,
+        errors::{MergeError, PaymentError},
+        parallel::process_files_parallel,
+        parser::parse_transactions,
+        payment_engine::PaymentEngine,
+    };
+    use std::{fs, path::PathBuf};
+
+    fn write_inputs(name: &str, inputs: &[&str]) -> Vec<PathBuf> {
+        inputs
+            .iter()
+            .enumerate()
+            .map(|(i, csv)| {
+                let path = std::env::temp_dir()
+                    .join(format!("payment-engine-{}-{}-{}.csv", std::process::id(), name, i));
+                fs::write(&path, csv).expect("the temp directory is writable");
+                path
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn disjoint_files_merge_into_their_sequential_result() -> Result<(), PaymentError> {
+        let inputs = [
+            "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,5.0\ndispute,1,1,\n",
+            "type,client,tx,amount\ndeposit,3,3,7.5\nwithdrawal,3,4,2.5\ndeposit,4,5,1.0\n",
+            "type,client,tx,amount\ndeposit,5,6,3.0\ndispute,5,6,\nchargeback,5,6,\n",
+        ];
+        let paths = write_inputs("disjoint", &inputs);
+        let merged = process_files_parallel(&paths, &EngineConfig::default()).await;
+
+        let mut sequential = PaymentEngine::new();
+        for input in inputs {
+            let transactions =
+                parse_transactions(Box::new(stringreader::StringReader::new(input))).await?;
+            sequential.process_all(transactions).await.into_result()?;
+        }
+        for path in &paths {
+            fs::remove_file(path).expect("the input was written");
+        }
+        let merged = merged?;
+        assert_eq!(merged.all_client_views(), sequential.all_client_views());
+        assert_eq!(merged.state_digest(), sequential.state_digest());
+        assert_eq!(merged.disputed_transactions.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn a_client_in_two_files_fails_the_merge() {
+        let paths = write_inputs(
+            "overlapping",
+            &[
+                "type,client,tx,amount\ndeposit,1,1,1.0\n",
+                "type,client,tx,amount\ndeposit,2,2,1.0\n",
+                "type,client,tx,amount\ndeposit,3,3,1.0\ndeposit,1,4,1.0\n",
+            ],
+        );
+        let merged = process_files_parallel(&paths, &EngineConfig::default()).await;
+        for path in &paths {
+            fs::remove_file(path).expect("the input was written");
+        }
+        match merged {
+            Err(PaymentError::MergeFailed { path, source }) => {
+                assert_eq!(path, paths[2]);
+                assert!(matches!(*source, MergeError::SharedClients(ref clients) if clients == &[1]));
+            }
+            other => panic!("expected a failed merge, got {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -1,6 +1,254 @@
-use crate::{errors::PaymentError, types::Transaction};
-use csv::{ReaderBuilder, Trim};
-use std::io::Read;
+use crate::{
+    errors::{InputLimit, PaymentError},
+    types::{ClientId, SourceRef, Transaction, TransactionType},
+};
+use csv::{ByteRecord, Position, ReaderBuilder, StringRecord};
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, BufReader, Read, Seek},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+static SPOOL_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// The transactions path that stands for stdin.
+pub const STDIN_PATH: &str = "-";
+
+/// Encoding of the transactions inputs.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum InputFormat {
+    /// CSV with a `type,client,tx,amount` header.
+    #[default]
+    Csv,
+    /// Length-delimited protobuf messages, see `proto`. Needs the `proto` feature.
+    Proto,
+    /// A MessagePack array of maps, see `msgpack`. Needs the `msgpack` feature.
+    Msgpack,
+}
+
+impl InputFormat {
+    /// Parses a format name as given on the command line (`csv`, `proto` or `msgpack`).
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::InvalidCliArgument` for an unknown name, or for a format whose
+    /// feature the build lacks.
+    pub fn parse(name: &str) -> Result<Self, PaymentError> {
+        match name {
+            "csv" => Ok(InputFormat::Csv),
+            "proto" if cfg!(feature = "proto") => Ok(InputFormat::Proto),
+            "proto" => Err(PaymentError::InvalidCliArgument(
+                "protobuf inputs need the engine built with --features proto".to_owned(),
+            )),
+            "msgpack" if cfg!(feature = "msgpack") => Ok(InputFormat::Msgpack),
+            "msgpack" => Err(PaymentError::InvalidCliArgument(
+                "MessagePack inputs need the engine built with --features msgpack".to_owned(),
+            )),
+            _ => Err(PaymentError::InvalidCliArgument(format!(
+                "unknown input format '{}', expected csv, proto or msgpack",
+                name
+            ))),
+        }
+    }
+}
+
+/// Largest scale of `AmountUnit::MinorUnits` accepted on the command line.
+pub const MAX_AMOUNT_SCALE: u32 = 18;
+
+/// Unit of the `amount` column of CSV inputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AmountUnit {
+    /// Decimal amounts, e.g. `15.0` or `15`.
+    #[default]
+    Major,
+    /// Whole numbers of minor units with the given number of decimal places, e.g. `150000` for
+    /// `15.0000` with a scale of 4.
+    MinorUnits(u32),
+}
+
+impl AmountUnit {
+    /// Parses a unit as given on the command line: `major`, or `minor:<scale>`.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::InvalidCliArgument` for an unknown unit or a scale above
+    /// `MAX_AMOUNT_SCALE`.
+    pub fn parse(name: &str) -> Result<Self, PaymentError> {
+        let scale = name.strip_prefix("minor:").map(str::parse::<u32>);
+        match (name, scale) {
+            ("major", _) => Ok(AmountUnit::Major),
+            (_, Some(Ok(scale))) if scale <= MAX_AMOUNT_SCALE => Ok(AmountUnit::MinorUnits(scale)),
+            _ => Err(PaymentError::InvalidCliArgument(format!(
+                "unknown amount unit '{}', expected major or minor:<scale> with a scale up to {}",
+                name, MAX_AMOUNT_SCALE
+            ))),
+        }
+    }
+}
+
+/// Default of `InputLimits::max_field_bytes`.
+pub const DEFAULT_MAX_FIELD_BYTES: u64 = 1024 * 1024;
+
+/// Default of `InputLimits::max_record_bytes`.
+pub const DEFAULT_MAX_RECORD_BYTES: u64 = 4 * 1024 * 1024;
+
+/// Default of `InputLimits::max_records`.
+pub const DEFAULT_MAX_RECORDS: u64 = 10_000_000_000;
+
+/// Bounds on the shape of an input, checked as it is read so that a hostile file, e.g. a
+/// single field of gigabytes or billions of empty rows, fails before it can exhaust the
+/// memory. Going over one is a `PaymentError::InputLimitExceeded`, which ends the input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InputLimits {
+    /// Longest field, quotes included.
+    pub max_field_bytes: u64,
+    /// Longest record, separators and quotes included but not the line break. A record of
+    /// one-byte fields takes several times this in memory, the bounds of each field included.
+    pub max_record_bytes: u64,
+    /// Most records after the header. Empty CSV lines count, although they are skipped.
+    pub max_records: u64,
+}
+
+impl Default for InputLimits {
+    fn default() -> Self {
+        InputLimits {
+            max_field_bytes: DEFAULT_MAX_FIELD_BYTES,
+            max_record_bytes: DEFAULT_MAX_RECORD_BYTES,
+            max_records: DEFAULT_MAX_RECORDS,
+        }
+    }
+}
+
+/// How the fields of CSV rows are turned into transactions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParserEngine {
+    /// Deserializes every row with serde.
+    Serde,
+    /// Reads the fields of rows in decimal amounts by hand, falling back to serde for any row
+    /// it can't read, so it accepts the same rows as `Serde`, with the same transactions, and
+    /// fails the others with the same errors.
+    #[default]
+    Fast,
+}
+
+/// How the rows of inputs are read, for `parse_records_with` and `parse_transactions_with`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ParseOptions {
+    /// Unit of the amounts of CSV inputs.
+    pub amount_unit: AmountUnit,
+    pub limits: InputLimits,
+    pub engine: ParserEngine,
+}
+
+/// Opens an input file for buffered reading.
+///
+/// # Errors
+///
+/// Returns a `PaymentError::FileError` holding the canonicalized path and the underlying
+/// `io::Error`, see `open_input_file`.
+pub fn open_input(path: impl AsRef<Path>) -> Result<Box<dyn Read>, PaymentError> {
+    Ok(Box::new(BufReader::new(open_input_file(path)?)))
+}
+
+/// Opens an input file, for callers that need the `File` itself (e.g. its length).
+///
+/// # Errors
+///
+/// Returns a `PaymentError::FileError` if the file doesn't exist, is a directory or can't be
+/// opened. The error names the canonicalized path when it can be resolved, and keeps the
+/// `io::Error` as its `source()`.
+pub fn open_input_file(path: impl AsRef<Path>) -> Result<File, PaymentError> {
+    let path = resolve(path.as_ref());
+    let metadata = fs::metadata(&path).map_err(|err| PaymentError::file(&path, err))?;
+    if metadata.is_dir() {
+        return Err(PaymentError::file(
+            &path,
+            io::Error::new(io::ErrorKind::IsADirectory, "is a directory, expected a CSV file"),
+        ));
+    }
+    File::open(&path).map_err(|err| PaymentError::file(&path, err))
+}
+
+/// Returns stdin as a `File`, for callers that treat it like any other input. Stdin is usually a
+/// pipe, see `is_seekable`.
+///
+/// # Errors
+///
+/// Returns a `PaymentError::FileError` if stdin can't be duplicated.
+pub fn stdin_file() -> Result<File, PaymentError> {
+    let stdin = io::stdin();
+    #[cfg(unix)]
+    let handle = std::os::fd::AsFd::as_fd(&stdin).try_clone_to_owned();
+    #[cfg(windows)]
+    let handle = std::os::windows::io::AsHandle::as_handle(&stdin).try_clone_to_owned();
+    #[cfg(not(any(unix, windows)))]
+    let handle: io::Result<File> = Err(io::Error::new(io::ErrorKind::Unsupported, "not supported on this platform"));
+    handle.map(File::from).map_err(|err| PaymentError::file("stdin", err))
+}
+
+/// Returns whether `file` is a regular file, which can be read again and has a known length,
+/// rather than a named pipe, socket or terminal.
+pub fn is_seekable(file: &File) -> bool {
+    file.metadata().is_ok_and(|metadata| metadata.is_file())
+}
+
+/// A copy of an input that can't be read twice, such as a named pipe, in the temp directory.
+/// The copy is removed when dropped.
+pub struct SpooledInput {
+    path: PathBuf,
+    file: File,
+}
+
+impl SpooledInput {
+    /// Reads `input` to its end into a new temp file.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::FileError` if the input can't be read or the copy can't be
+    /// written.
+    pub fn new(mut input: impl Read) -> Result<Self, PaymentError> {
+        let path = std::env::temp_dir().join(format!(
+            "payment-engine-{}-{}.spool",
+            std::process::id(),
+            SPOOL_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|err| PaymentError::file(&path, err))?;
+        // built first so that a failed copy is removed as well
+        let spooled = SpooledInput { path, file };
+        io::copy(&mut input, &mut &spooled.file)
+            .and_then(|_| (&spooled.file).rewind())
+            .map_err(|err| PaymentError::file(&spooled.path, err))?;
+        Ok(spooled)
+    }
+
+    /// Returns a handle on the copy, positioned at its start when the copy was just made.
+    pub fn file(&self) -> Result<File, PaymentError> {
+        self.file.try_clone().map_err(|err| PaymentError::file(&self.path, err))
+    }
+}
+
+impl Drop for SpooledInput {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Returns the canonical form of `path`, or its absolute form if it doesn't exist.
+fn resolve(path: &Path) -> PathBuf {
+    fs::canonicalize(path)
+        .or_else(|_| std::path::absolute(path))
+        .unwrap_or_else(|_| path.to_owned())
+}
 
 /// Parses transactions from a CSV reader asynchronously.
 ///
@@ -11,7 +259,7 @@ use std::io::Read;
 /// # Arguments
 ///
 /// * `br` - A boxed reader that implements the `Read` trait. This can be a file, stream,
-///          or any other readable source.
+///   or any other readable source.
 ///
 /// # Returns
 ///
@@ -21,21 +269,795 @@ use std::io::Read;
 pub async fn parse_transactions(
     br: Box<dyn Read>,
 ) -> Result<Box<dyn Iterator<Item = Result<Transaction, PaymentError>>>, PaymentError> {
-    let rdr = ReaderBuilder::new()
-        .flexible(true)
-        .trim(Trim::All)
-        .from_reader(br);
+    parse_transactions_with(br, &ParseOptions::default()).await
+}
+
+/// Parses transactions from a CSV reader like `parse_transactions`, reading the amounts in the
+/// unit of `options` with its parser engine.
+pub async fn parse_transactions_with(
+    br: Box<dyn Read>,
+    options: &ParseOptions,
+) -> Result<Box<dyn Iterator<Item = Result<Transaction, PaymentError>>>, PaymentError> {
+    if options.engine == ParserEngine::Serde {
+        let (_, records) = parse_records_with(br, options).await?;
+        return Ok(Box::new(records.map(|record| record.transaction)));
+    }
+    let options = *options;
+    let limited = LimitedReader::new(br, options.limits);
+    let mut rdr = ReaderBuilder::new().flexible(true).from_reader(limited);
+    let mut headers = rdr.headers().map_err(read_error)?.clone();
+    headers.trim();
+    let parser = RowParser::new(headers, options);
+
+    // rows are read into the same record, only cloned for the serde fallback
+    let mut raw = StringRecord::new();
+    let mut ended = false;
+    Ok(Box::new(std::iter::from_fn(move || {
+        if ended {
+            return None;
+        }
+        match rdr.read_record(&mut raw) {
+            Ok(true) => Some(parser.transaction(&raw)),
+            Ok(false) => {
+                ended = true;
+                None
+            }
+            Err(err) => {
+                let err = read_error(err);
+                ended = matches!(err, PaymentError::InputLimitExceeded { .. });
+                Some(Err(err))
+            }
+        }
+    })))
+}
+
+/// Parses transactions from a CSV reader like `parse_transactions`, with the
+/// `ParserEngine::Fast` engine whatever the default.
+pub async fn parse_transactions_fast(
+    br: Box<dyn Read>,
+) -> Result<Box<dyn Iterator<Item = Result<Transaction, PaymentError>>>, PaymentError> {
+    let options = ParseOptions {
+        engine: ParserEngine::Fast,
+        ..ParseOptions::default()
+    };
+    parse_transactions_with(br, &options).await
+}
+
+/// How an input without a single row was empty.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmptyInput {
+    /// Zero bytes, not even a header.
+    NoBytes,
+    /// A header and no row.
+    HeaderOnly,
+}
+
+impl EmptyInput {
+    /// Returns how an input without rows was empty, given the header it was parsed with.
+    pub fn of(header: &StringRecord) -> Self {
+        if header.is_empty() {
+            EmptyInput::NoBytes
+        } else {
+            EmptyInput::HeaderOnly
+        }
+    }
+
+    /// Returns the snake_case name used for this shape in the statistics JSON.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EmptyInput::NoBytes => "no_bytes",
+            EmptyInput::HeaderOnly => "header_only",
+        }
+    }
+}
+
+impl fmt::Display for EmptyInput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmptyInput::NoBytes => write!(f, "input is empty (0 bytes)"),
+            EmptyInput::HeaderOnly => write!(f, "input has a header but no rows"),
+        }
+    }
+}
+
+/// One of the input files of a multi-file run: its position among the inputs and its path.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceId {
+    pub index: usize,
+    pub path: Arc<str>,
+}
+
+impl SourceId {
+    /// Returns where a row of this file is, as `path:line`.
+    pub fn location(&self, line: u64) -> String {
+        format!("{}:{}", self.path, line)
+    }
+
+    /// Returns the source of the row at `line` of this file.
+    pub fn source_ref(&self, line: u64) -> SourceRef {
+        SourceRef {
+            file: Some(self.path.clone()),
+            line,
+        }
+    }
+}
+
+/// A row of the CSV input, kept verbatim alongside its deserialized transaction.
+pub struct ParsedRecord {
+    /// Line number of the row in the input (the header is line 1).
+    pub line: u64,
+    /// The row's fields exactly as they appeared in the input, untrimmed.
+    pub raw: StringRecord,
+    /// The row's fields as read, for a row that isn't valid UTF-8 and whose `raw` fields are
+    /// thus decoded lossily.
+    pub undecoded: Option<ByteRecord>,
+    /// The input file of the row in a multi-file run, set by `attribute`.
+    pub source: Option<SourceId>,
+    /// The deserialized transaction, or why the row couldn't be parsed.
+    pub transaction: Result<Transaction, PaymentError>,
+}
+
+impl ParsedRecord {
+    /// Returns a record failing with `err`, with the row's fields as `raw`.
+    pub(crate) fn failed(line: u64, raw: StringRecord, err: PaymentError) -> Self {
+        ParsedRecord {
+            line,
+            raw,
+            undecoded: None,
+            source: None,
+            transaction: Err(err),
+        }
+    }
+
+    /// Returns the record of a row that isn't valid UTF-8, failing with a
+    /// `PaymentError::CsvParseError` that says where.
+    pub(crate) fn undecodable(err: csv::FromUtf8Error) -> Self {
+        let reason = err.utf8_error().clone();
+        let bytes = err.into_byte_record();
+        let position = bytes.position().cloned().unwrap_or_else(Position::new);
+        let mut raw: StringRecord = bytes.iter().map(String::from_utf8_lossy).collect();
+        raw.set_position(Some(position.clone()));
+        let err = invalid_utf8(&position, &reason);
+        ParsedRecord {
+            undecoded: Some(bytes),
+            source: None,
+            ..ParsedRecord::failed(position.line(), raw, err)
+        }
+    }
+
+    /// Returns the record of a MessagePack element or protobuf message that can't be decoded,
+    /// failing with `err`. Its bytes, if kept, are its only raw field, in hexadecimal.
+    #[cfg(any(feature = "proto", feature = "msgpack"))]
+    pub(crate) fn undecodable_message(index: u64, bytes: &[u8], err: PaymentError) -> Self {
+        let raw = match bytes.is_empty() {
+            true => StringRecord::new(),
+            false => StringRecord::from(vec![bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<String>()]),
+        };
+        ParsedRecord::failed(index, raw, err)
+    }
+
+    /// Returns the row's fields as they were in the input, e.g. to quarantine the row.
+    pub fn raw_bytes(&self) -> &ByteRecord {
+        self.undecoded.as_ref().unwrap_or(self.raw.as_byte_record())
+    }
+
+    /// Returns where the row was read from: its file, if attributed to one, and its line.
+    pub fn source_ref(&self) -> SourceRef {
+        match &self.source {
+            Some(source) => source.source_ref(self.line),
+            None => SourceRef::line(self.line),
+        }
+    }
+}
+
+/// Attributes every record of `records` to the input file `source`, for multi-file runs.
+pub fn attribute(
+    records: Box<dyn Iterator<Item = ParsedRecord>>,
+    source: SourceId,
+) -> Box<dyn Iterator<Item = ParsedRecord>> {
+    Box::new(records.map(move |record| ParsedRecord {
+        source: Some(source.clone()),
+        ..record
+    }))
+}
+
+/// Parses transactions from a CSV reader, keeping each raw record and its line number.
+///
+/// Works like `parse_transactions`, fields are trimmed before deserialization, but the
+/// untrimmed header and rows are kept so they can be reported verbatim (e.g. quarantined).
+///
+/// # Returns
+///
+/// Returns a `Result` containing:
+/// - On success: The raw header record and a boxed iterator over the parsed rows.
+/// - On failure: A `PaymentError` if the header can't be read.
+///
+/// The input is read within the default `InputLimits`.
+pub async fn parse_records(
+    br: Box<dyn Read>,
+) -> Result<(StringRecord, Box<dyn Iterator<Item = ParsedRecord>>), PaymentError> {
+    parse_records_with(br, &ParseOptions::default()).await
+}
+
+/// Parses transactions from a CSV reader like `parse_records`, reading the amounts in the unit
+/// of `options`. The raw rows keep the amounts as they were in the input. An input going over
+/// the limits of `options` ends with a row failing with `PaymentError::InputLimitExceeded`.
+pub async fn parse_records_with(
+    br: Box<dyn Read>,
+    options: &ParseOptions,
+) -> Result<(StringRecord, Box<dyn Iterator<Item = ParsedRecord>>), PaymentError> {
+    let options = *options;
+    let limited = LimitedReader::new(br, options.limits);
+    let mut rdr = ReaderBuilder::new().flexible(true).from_reader(limited);
+
+    let raw_headers = rdr.headers().map_err(read_error)?.clone();
+    let mut headers = raw_headers.clone();
+    headers.trim();
+    let parser = RowParser::new(headers, options);
+
+    let mut exceeded = false;
+    // read as bytes, so that a row that isn't valid UTF-8 can still be quarantined as it was
+    let records = rdr.into_byte_records().map_while(move |result| {
+        if exceeded {
+            return None;
+        }
+        Some(match result {
+            Ok(bytes) => parser.parse_bytes(bytes),
+            Err(err) => {
+                let line = err.position().map_or(0, |pos| pos.line());
+                let err = read_error(err);
+                exceeded = matches!(err, PaymentError::InputLimitExceeded { .. });
+                ParsedRecord::failed(line, StringRecord::new(), err)
+            }
+        })
+    });
+    Ok((raw_headers, Box::new(records)))
+}
+
+/// Returns the error of a CSV input that can't be read: the limit a `LimitedReader` stopped it
+/// at, or else a `PaymentError::CsvParseError`.
+pub(crate) fn read_error(err: csv::Error) -> PaymentError {
+    if let csv::ErrorKind::Io(io_err) = err.kind() {
+        let exceeded = io_err.get_ref().and_then(|source| source.downcast_ref::<LimitExceeded>());
+        if let Some(LimitExceeded { which, limit, at_line }) = exceeded {
+            return PaymentError::InputLimitExceeded {
+                which: *which,
+                limit: *limit,
+                at_line: *at_line,
+            };
+        }
+    }
+    if let csv::ErrorKind::Utf8 { pos: Some(pos), err } = err.kind() {
+        return invalid_utf8(pos, err);
+    }
+    PaymentError::CsvParseError(err.to_string())
+}
+
+/// Returns the error of a row that isn't valid UTF-8, whether it was read as a string or as
+/// bytes.
+fn invalid_utf8(position: &Position, err: &csv::Utf8Error) -> PaymentError {
+    PaymentError::CsvParseError(format!(
+        "CSV parse error: record {} (line {}, field: {}, byte: {}): {}",
+        position.record(),
+        position.line(),
+        err.field(),
+        position.byte(),
+        err
+    ))
+}
+
+/// A limit a `LimitedReader` stopped at, carried to `read_error` by the CSV reader's
+/// `io::Error`.
+#[derive(Debug)]
+struct LimitExceeded {
+    which: InputLimit,
+    limit: u64,
+    at_line: u64,
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "more than {} {} at line {}", self.limit, self.which, self.at_line)
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// Where a `LimitedReader` is within the CSV syntax.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CsvState {
+    StartOfField,
+    InField,
+    InQuotes,
+    /// After a quote within quotes, which either escapes a quote or ends the quotes.
+    QuoteInQuotes,
+}
+
+/// Reads a CSV input while following its fields and records, failing the read that takes it
+/// over its `InputLimits`. The CSV reader only buffers what it was given, so its memory stays
+/// bounded by the limits whatever the input.
+///
+/// Line breaks outside quotes are passed on as `\n`, whether they are `\n`, `\r\n` or a bare
+/// `\r`, so records, limits and line numbers are counted the same whatever the input uses.
+pub(crate) struct LimitedReader<R> {
+    inner: R,
+    limits: InputLimits,
+    state: CsvState,
+    /// Line of the byte being read, from 1.
+    line: u64,
+    /// Records ended by a line break so far, the header included.
+    records_ended: u64,
+    /// Whether the last byte was a `\r` passed on as `\n`, so a `\n` right after it is dropped.
+    after_cr: bool,
+    field_bytes: u64,
+    record_bytes: u64,
+    /// The limit the input went over, returned again by every later read.
+    exceeded: Option<(InputLimit, u64, u64)>,
+}
+
+impl<R: Read> LimitedReader<R> {
+    pub(crate) fn new(inner: R, limits: InputLimits) -> Self {
+        LimitedReader {
+            inner,
+            limits,
+            state: CsvState::StartOfField,
+            line: 1,
+            records_ended: 0,
+            after_cr: false,
+            field_bytes: 0,
+            record_bytes: 0,
+            exceeded: None,
+        }
+    }
+
+    /// Follows one byte of the input, returning the limit it goes over.
+    fn follow(&mut self, byte: u8) -> Option<(InputLimit, u64)> {
+        if self.records_ended > self.limits.max_records {
+            return Some((InputLimit::Records, self.limits.max_records));
+        }
+        let ends_record = byte == b'\n' && self.state != CsvState::InQuotes;
+        let ends_field = byte == b',' && self.state != CsvState::InQuotes;
+        self.state = match (self.state, byte) {
+            (CsvState::InQuotes, b'"') => CsvState::QuoteInQuotes,
+            (CsvState::InQuotes, _) => CsvState::InQuotes,
+            (CsvState::StartOfField, b'"') | (CsvState::QuoteInQuotes, b'"') => CsvState::InQuotes,
+            _ if ends_record || ends_field => CsvState::StartOfField,
+            _ => CsvState::InField,
+        };
+        if byte == b'\n' {
+            self.line += 1;
+        }
+        if ends_record {
+            self.records_ended += 1;
+            self.field_bytes = 0;
+            self.record_bytes = 0;
+            return None;
+        }
+        self.field_bytes = if ends_field { 0 } else { self.field_bytes + 1 };
+        self.record_bytes += 1;
+        if self.field_bytes > self.limits.max_field_bytes {
+            return Some((InputLimit::FieldBytes, self.limits.max_field_bytes));
+        }
+        (self.record_bytes > self.limits.max_record_bytes)
+            .then_some((InputLimit::RecordBytes, self.limits.max_record_bytes))
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some((which, limit, at_line)) = self.exceeded {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                LimitExceeded { which, limit, at_line },
+            ));
+        }
+        loop {
+            let read = self.inner.read(buf)?;
+            // bytes are passed on in place, as many or fewer than were read
+            let mut kept = 0;
+            for i in 0..read {
+                let byte = match buf[i] {
+                    b'\n' if self.after_cr => {
+                        self.after_cr = false;
+                        continue;
+                    }
+                    b'\r' if self.state != CsvState::InQuotes => {
+                        self.after_cr = true;
+                        b'\n'
+                    }
+                    byte => {
+                        self.after_cr = false;
+                        byte
+                    }
+                };
+                if let Some((which, limit)) = self.follow(byte) {
+                    self.exceeded = Some((which, limit, self.line));
+                    // the bytes before the limit go through first, for the rows they end
+                    return if kept > 0 { Ok(kept) } else { self.read(buf) };
+                }
+                buf[kept] = byte;
+                kept += 1;
+            }
+            // a read of nothing but the `\n` of a `\r\n` isn't the end of the input
+            if kept > 0 || read == 0 {
+                return Ok(kept);
+            }
+        }
+    }
+}
+
+/// Ends `records` with a `PaymentError::InputLimitExceeded` at the first record going over
+/// `limits`, for inputs read by another reader than the CSV one, e.g. protobuf or MessagePack.
+/// These readers bound the size of a message on their own, so its fields and length are
+/// checked once it is read.
+pub fn limit_records(
+    records: Box<dyn Iterator<Item = ParsedRecord>>,
+    limits: InputLimits,
+) -> Box<dyn Iterator<Item = ParsedRecord>> {
+    let mut seen = 0u64;
+    let mut exceeded = false;
+    Box::new(records.map_while(move |record| {
+        if exceeded {
+            return None;
+        }
+        seen += 1;
+        let longest_field = record.raw.iter().map(str::len).max().unwrap_or_default() as u64;
+        let over = if seen > limits.max_records {
+            Some((InputLimit::Records, limits.max_records))
+        } else if longest_field > limits.max_field_bytes {
+            Some((InputLimit::FieldBytes, limits.max_field_bytes))
+        } else if record.raw.as_slice().len() as u64 > limits.max_record_bytes {
+            Some((InputLimit::RecordBytes, limits.max_record_bytes))
+        } else {
+            None
+        };
+        let Some((which, limit)) = over else {
+            return Some(record);
+        };
+        exceeded = true;
+        let err = PaymentError::InputLimitExceeded {
+            which,
+            limit,
+            at_line: record.line,
+        };
+        Some(ParsedRecord {
+            transaction: Err(err),
+            ..record
+        })
+    }))
+}
+
+/// Deserializes a raw row against the trimmed headers, taking its line from the row's position.
+/// An amount in minor units is first turned into its decimal form.
+fn parse_raw(
+    raw: StringRecord,
+    headers: &StringRecord,
+    options: &ParseOptions,
+) -> ParsedRecord {
+    let mut trimmed = raw.clone();
+    trimmed.trim();
+    let transaction = match options.amount_unit {
+        AmountUnit::Major => Ok(trimmed),
+        AmountUnit::MinorUnits(scale) => {
+            let column = headers.iter().position(|header| header == "amount");
+            trimmed
+                .iter()
+                .enumerate()
+                .map(|(i, field)| match Some(i) == column {
+                    true => minor_units_to_decimal(field, scale),
+                    false => Ok(field.to_owned()),
+                })
+                .collect::<Result<StringRecord, _>>()
+        }
+    }
+    .and_then(|mut trimmed| {
+        trimmed.set_position(raw.position().cloned());
+        trimmed
+            .deserialize(Some(headers))
+            .map_err(|err| PaymentError::CsvParseError(err.to_string()))
+    });
+    ParsedRecord {
+        line: raw.position().map_or(0, |pos| pos.line()),
+        transaction,
+        raw,
+        undecoded: None,
+        source: None,
+    }
+}
+
+/// A column of the CSV input, as told by its header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Column {
+    Type,
+    Client,
+    Tx,
+    Amount,
+    Reason,
+    Merchant,
+    Timestamp,
+    Memo,
+    IdempotencyKey,
+    /// A column the transactions don't have, skipped.
+    Other,
+}
+
+impl Column {
+    fn of(header: &str) -> Self {
+        match header {
+            "type" => Column::Type,
+            "client" => Column::Client,
+            "tx" => Column::Tx,
+            "amount" => Column::Amount,
+            "reason" => Column::Reason,
+            "merchant" => Column::Merchant,
+            "timestamp" => Column::Timestamp,
+            "memo" => Column::Memo,
+            "idempotency_key" => Column::IdempotencyKey,
+            _ => Column::Other,
+        }
+    }
+
+    /// Whether serde reads the column as absent when a row has no field for it.
+    fn is_optional(self) -> bool {
+        !matches!(self, Column::Type | Column::Client | Column::Tx | Column::Other)
+    }
+}
+
+/// Powers of ten up to the largest one an `f64` holds exactly.
+const POWERS_OF_TEN: [f64; 23] = [
+    1e0, 1e1, 1e2, 1e3, 1e4, 1e5, 1e6, 1e7, 1e8, 1e9, 1e10, 1e11, 1e12, 1e13, 1e14, 1e15, 1e16, 1e17, 1e18,
+    1e19, 1e20, 1e21, 1e22,
+];
+
+/// Parses the rows of a CSV input against its trimmed headers, with the engine of its
+/// `ParseOptions`.
+pub(crate) struct RowParser {
+    headers: StringRecord,
+    options: ParseOptions,
+    /// The column of each header, if the fast engine reads these rows: the headers name the
+    /// type, client and tx columns once each, and the amounts are decimal.
+    columns: Option<Vec<Column>>,
+}
+
+impl RowParser {
+    pub(crate) fn new(headers: StringRecord, options: ParseOptions) -> Self {
+        let columns: Vec<Column> = headers.iter().map(Column::of).collect();
+        let named_once = |column| columns.iter().filter(|named| **named == column).count() == 1;
+        let known = [Column::Type, Column::Client, Column::Tx];
+        let readable = options.engine == ParserEngine::Fast
+            && options.amount_unit == AmountUnit::Major
+            && known.into_iter().all(named_once)
+            && columns.iter().all(|column| *column == Column::Other || named_once(*column));
+        RowParser {
+            headers,
+            options,
+            columns: readable.then_some(columns),
+        }
+    }
+
+    /// Parses a raw row, taking its line from the row's position.
+    pub(crate) fn parse(&self, raw: StringRecord) -> ParsedRecord {
+        match self.read(&raw) {
+            Some(transaction) => ParsedRecord {
+                line: raw.position().map_or(0, |pos| pos.line()),
+                raw,
+                undecoded: None,
+                source: None,
+                transaction: Ok(transaction),
+            },
+            None => parse_raw(raw, &self.headers, &self.options),
+        }
+    }
+
+    /// Parses a row read as bytes like `parse`. A row that isn't valid UTF-8 fails, keeping its
+    /// bytes.
+    pub(crate) fn parse_bytes(&self, bytes: ByteRecord) -> ParsedRecord {
+        match StringRecord::from_byte_record(bytes) {
+            Ok(raw) => self.parse(raw),
+            Err(err) => ParsedRecord::undecodable(err),
+        }
+    }
+
+    /// Parses a raw row into its transaction alone.
+    pub(crate) fn transaction(&self, raw: &StringRecord) -> Result<Transaction, PaymentError> {
+        match self.read(raw) {
+            Some(transaction) => Ok(transaction),
+            None => parse_raw(raw.clone(), &self.headers, &self.options).transaction,
+        }
+    }
+
+    /// Reads the transaction of a raw row by hand, or returns `None` for serde to parse it: a
+    /// row serde would reject, or one in a form this doesn't read, e.g. a hexadecimal tx id or an
+    /// amount with an exponent. Fields are trimmed like `StringRecord::trim` does, and a row
+    /// short of fields has no value in the optional columns it lacks, as serde reads it.
+    fn read(&self, raw: &StringRecord) -> Option<Transaction> {
+        let columns = self.columns.as_ref()?;
+        let (mut r#type, mut client, mut tx) = (None, None, None);
+        let mut txn = Transaction::new(TransactionType::Deposit, 0, 0, None);
+        for (i, column) in columns.iter().enumerate() {
+            let Some(field) = raw.get(i) else {
+                if column.is_optional() {
+                    continue;
+                }
+                return None;
+            };
+            let field = field.trim();
+            let text = || (!field.is_empty()).then(|| field.to_owned());
+            match column {
+                Column::Type => r#type = Some(TransactionType::from_name(field)?),
+                Column::Client => client = Some(ClientId::try_from(read_decimal(field)?).ok()?),
+                Column::Tx => tx = Some(u32::try_from(read_decimal(field)?).ok()?),
+                Column::Amount if field.is_empty() => {}
+                Column::Amount => txn.amount = Some(read_amount(field).or_else(|| field.parse().ok())?),
+                Column::Reason => txn.reason = text(),
+                Column::Merchant => txn.merchant = text().map(Arc::from),
+                Column::Timestamp if field.is_empty() => {}
+                Column::Timestamp => txn.timestamp = Some(read_decimal(field)?),
+                Column::Memo => txn.memo = text(),
+                Column::IdempotencyKey => txn.idempotency_key = text(),
+                Column::Other => {}
+            }
+        }
+        txn.r#type = r#type?;
+        txn.client = client?;
+        txn.tx = tx?;
+        Some(txn)
+    }
+}
+
+/// Reads a whole number written in decimal digits alone, as `str::parse` would.
+fn read_decimal(field: &str) -> Option<u64> {
+    if field.is_empty() {
+        return None;
+    }
+    field.bytes().try_fold(0u64, |number, byte| {
+        let digit = byte.wrapping_sub(b'0');
+        (digit < 10).then_some(())?;
+        number.checked_mul(10)?.checked_add(u64::from(digit))
+    })
+}
+
+/// Reads an amount written as digits with an optional sign and decimal point, e.g. `-12.5`, as
+/// a fixed-point number of units. Returns the `f64` `str::parse` would, as both round the exact
+/// quotient of the units by a power of ten to the nearest `f64`, or `None` for an amount with
+/// too many digits for the quotient to be exact.
+fn read_amount(field: &str) -> Option<f64> {
+    let (negative, digits) = match field.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, field),
+    };
+    let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    if whole.is_empty() && fraction.is_empty() {
+        return None;
+    }
+    let mut units = 0u64;
+    for byte in whole.bytes().chain(fraction.bytes()) {
+        let digit = byte.wrapping_sub(b'0');
+        if digit >= 10 {
+            return None;
+        }
+        units = units.checked_mul(10)?.checked_add(u64::from(digit))?;
+    }
+    if units > 1 << f64::MANTISSA_DIGITS {
+        return None;
+    }
+    let amount = units as f64 / POWERS_OF_TEN.get(fraction.len())?;
+    Some(if negative { -amount } else { amount })
+}
+
+/// Writes a whole number of minor units as the decimal it stands for with `scale` decimal
+/// places, moving the decimal point rather than dividing so that the amount parses exactly like
+/// its decimal form would (`150000` at scale 4 is `15.0000`). An empty field stays empty.
+fn minor_units_to_decimal(units: &str, scale: u32) -> Result<String, PaymentError> {
+    if units.is_empty() {
+        return Ok(String::new());
+    }
+    let (sign, digits) = match units.strip_prefix('-') {
+        Some(digits) => ("-", digits),
+        None => ("", units),
+    };
+    if digits.is_empty() || !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        return Err(PaymentError::CsvParseError(format!(
+            "amount '{}' isn't a whole number of minor units",
+            units
+        )));
+    }
+    let scale = scale as usize;
+    let digits = format!("{:0>width$}", digits, width = scale + 1);
+    let (whole, fraction) = digits.split_at(digits.len() - scale);
+    Ok(match scale {
+        0 => format!("{}{}", sign, whole),
+        _ => format!("{}{}.{}", sign, whole, fraction),
+    })
+}
+
+/// Whole amounts from which `MinorUnitsCheck` suspects minor units: 1.0000 at a scale of 4.
+const LARGE_WHOLE_AMOUNT: u64 = 10_000;
+
+/// Large whole amounts `MinorUnitsCheck` waits for before warning.
+const SUSPICIOUS_AMOUNTS: u64 = 10;
 
-    let transactions_iter = rdr
-        .into_deserialize()
-        .map(|result| result.map_err(|err| PaymentError::CsvParseError(err.to_string())));
-    // let mut transactions = Vec::new();
-    Ok(Box::new(transactions_iter))
+/// Looks for amounts in minor units in an input read as `AmountUnit::Major`: whole numbers of
+/// 10000 or more, making up at least half the amounts, are unlikely to be decimal amounts.
+#[derive(Debug)]
+pub struct MinorUnitsCheck {
+    column: Option<usize>,
+    amounts: u64,
+    large_whole: u64,
+    warned: bool,
+}
+
+/// The warning of `MinorUnitsCheck`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SuspectedMinorUnits {
+    /// Line of the row the check warned at.
+    pub line: u64,
+    /// Amounts read up to that row.
+    pub amounts: u64,
+    /// How many of them were whole numbers of 10000 or more.
+    pub large_whole: u64,
+}
+
+impl fmt::Display for SuspectedMinorUnits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} of the {} amounts up to line {} are whole numbers of {} or more, the amount \
+             column may be in minor units",
+            self.large_whole, self.amounts, self.line, LARGE_WHOLE_AMOUNT
+        )
+    }
+}
+
+impl MinorUnitsCheck {
+    /// Creates the check of an input with the raw `headers`.
+    pub fn new(headers: &StringRecord) -> Self {
+        MinorUnitsCheck {
+            column: headers.iter().position(|header| header.trim() == "amount"),
+            amounts: 0,
+            large_whole: 0,
+            warned: false,
+        }
+    }
+
+    /// Counts the amount of a raw row found at `line`, returning the warning the first time the
+    /// input's amounts look like minor units.
+    pub fn check(&mut self, raw: &StringRecord, line: u64) -> Option<SuspectedMinorUnits> {
+        let amount = self.column.and_then(|column| raw.get(column)).map(str::trim);
+        let amount = amount.filter(|amount| !amount.is_empty())?;
+        self.amounts += 1;
+        if amount.bytes().all(|byte| byte.is_ascii_digit())
+            && amount.parse::<u64>().map_or(true, |amount| amount >= LARGE_WHOLE_AMOUNT)
+        {
+            self.large_whole += 1;
+        }
+        if self.warned || self.large_whole < SUSPICIOUS_AMOUNTS || self.large_whole * 2 < self.amounts {
+            return None;
+        }
+        self.warned = true;
+        Some(SuspectedMinorUnits {
+            line,
+            amounts: self.amounts,
+            large_whole: self.large_whole,
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{errors::PaymentError, parser::parse_transactions, types::TransactionType};
+    use crate::{
+        errors::{InputLimit, PaymentError},
+        parser::{
+            minor_units_to_decimal, open_input, parse_records, parse_records_with, parse_transactions,
+            parse_transactions_fast, parse_transactions_with, read_amount, AmountUnit, InputLimits,
+            MinorUnitsCheck, ParseOptions, ParserEngine, RowParser,
+        },
+        payment_engine::PaymentEngine,
+        simulate::TransactionGenerator,
+        types::{ClientId, Transaction, TransactionType},
+    };
+    use csv::StringRecord;
+    use std::{error::Error, fs, io};
 
     #[tokio::test]
     async fn can_parse_csv_stream_and_return_all_transactions() -> Result<(), PaymentError> {
@@ -68,6 +1090,460 @@ mod tests {
         assert_eq!(fist_transaction.client, 1);
         assert_eq!(fist_transaction.tx, 1);
         assert_eq!(fist_transaction.amount, Some(1.0));
+        assert_eq!(fist_transaction.memo, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_keep_raw_records_and_line_numbers() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+deposit,  1, 1,   1.0
+deposit, 1, 2, abc";
+        let str_buf = stringreader::StringReader::new(csv);
+        let (headers, records) = parse_records(Box::new(str_buf)).await?;
+        let records: Vec<_> = records.collect();
+
+        assert_eq!(headers.iter().collect::<Vec<_>>(), vec!["type", " client", " tx", " amount"]);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].line, 2);
+        assert_eq!(records[0].raw.iter().collect::<Vec<_>>(), vec!["deposit", "  1", " 1", "   1.0"]);
+        assert_eq!(records[0].transaction.as_ref().map(|txn| txn.amount).ok(), Some(Some(1.0)));
+        assert_eq!(records[1].line, 3);
+        assert!(records[1].transaction.is_err());
         Ok(())
     }
+
+    #[tokio::test]
+    async fn client_ids_wider_than_the_build_are_rejected() -> Result<(), PaymentError> {
+        let too_wide = u64::from(ClientId::MAX) + 1;
+        let csv = format!("type, client, tx, amount\ndeposit, {}, 1, 1.0", too_wide);
+        let (_, mut records) = parse_records(Box::new(std::io::Cursor::new(csv))).await?;
+
+        let err = records.next().unwrap().transaction.unwrap_err();
+        let expected = format!("client id {} is out of range, the largest supported is {}", too_wide, ClientId::MAX);
+        assert!(err.to_string().contains(&expected), "{}", err);
+        Ok(())
+    }
+
+    #[cfg(feature = "wide-client-ids")]
+    #[tokio::test]
+    async fn wide_client_ids_go_beyond_u16() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+deposit, 70000, 1, 2.5
+withdrawal, 70000, 2, 1.0";
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = crate::payment_engine::PaymentEngine::new();
+        engine.process_all(parse_transactions(Box::new(str_buf)).await?).await.into_result()?;
+
+        assert_eq!(engine.clients[&70000].available, 1.5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn transactions_built_in_code_round_trip_through_csv() -> Result<(), PaymentError> {
+        let transactions = vec![
+            Transaction::deposit(1, 1, 2.5)
+                .with_merchant("Acme, Inc")
+                .with_timestamp(1700000000),
+            Transaction::withdrawal(1, 2, 0.5).with_memo("atm \"cash\"").with_idempotency_key("retry-7"),
+            Transaction::dispute(1, 1).with_reason("fraud"),
+            Transaction::resolve(1, 1),
+            Transaction::chargeback(2, 3),
+            Transaction::hold(2, 4, 1.0),
+            Transaction::capture(2, 4),
+        ];
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        for txn in &transactions {
+            writer.serialize(txn).unwrap();
+        }
+        let csv = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert!(csv.starts_with(
+            "type,client,tx,amount,reason,merchant,timestamp,memo,idempotency_key\n\
+             deposit,1,1,2.5,,\"Acme, Inc\",1700000000,,\n"
+        ));
+        assert!(csv.contains("\nwithdrawal,1,2,0.5,,,,\"atm \"\"cash\"\"\",retry-7\n"));
+        assert!(csv.contains("\ndispute,1,1,,fraud,,,,\n"));
+
+        let parsed: Vec<Transaction> = parse_transactions(Box::new(std::io::Cursor::new(csv)))
+            .await?
+            .collect::<Result<_, _>>()?;
+        assert_eq!(parsed, transactions);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn amounts_in_minor_units_process_like_their_decimal_form() -> Result<(), PaymentError> {
+        let major = "type, client, tx, amount
+        deposit, 1, 1, 15.0
+        deposit, 2, 2, 0.0001
+        withdrawal, 1, 3, 2.5
+        dispute, 2, 2,
+        deposit, 3, 4, 1234567.8912";
+        let minor = "type, client, tx, amount
+        deposit, 1, 1, 150000
+        deposit, 2, 2, 1
+        withdrawal, 1, 3, 25000
+        dispute, 2, 2,
+        deposit, 3, 4, 12345678912";
+        let options = ParseOptions {
+            amount_unit: AmountUnit::MinorUnits(4),
+            ..ParseOptions::default()
+        };
+        let mut engines = Vec::new();
+        for (csv, options) in [(major, ParseOptions::default()), (minor, options)] {
+            let transactions = parse_transactions_with(Box::new(std::io::Cursor::new(csv)), &options).await?;
+            let mut engine = PaymentEngine::new();
+            engine.process_all(transactions).await.into_result()?;
+            engines.push(engine);
+        }
+        assert_eq!(engines[0].all_client_views(), engines[1].all_client_views());
+        assert_eq!(engines[0].state_digest(), engines[1].state_digest());
+        assert_eq!(engines[1].clients[&1].available, 12.5);
+
+        let csv = "type, client, tx, amount\ndeposit, 1, 1, 1.5\ndeposit, 1, 2, 1e4";
+        let (_, records) = parse_records_with(Box::new(std::io::Cursor::new(csv)), &options).await?;
+        for record in records {
+            let err = record.transaction.unwrap_err();
+            assert!(err.to_string().contains("isn't a whole number of minor units"), "{}", err);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn minor_units_move_the_decimal_point_exactly() {
+        for (units, decimal) in [
+            ("0", "0.0000"),
+            ("1", "0.0001"),
+            ("9999", "0.9999"),
+            ("10000", "1.0000"),
+            ("00012", "0.0012"),
+            ("-5", "-0.0005"),
+            ("18446744073709551615", "1844674407370955.1615"),
+            ("99999999999999999999999", "9999999999999999999.9999"),
+            ("", ""),
+        ] {
+            assert_eq!(minor_units_to_decimal(units, 4).ok().as_deref(), Some(decimal), "{}", units);
+        }
+        assert_eq!(minor_units_to_decimal("150", 0).ok().as_deref(), Some("150"));
+        assert_eq!(minor_units_to_decimal("150", 2).ok().as_deref(), Some("1.50"));
+        for invalid in ["-", "1.0", "+1", "1 000", "0x10"] {
+            assert!(minor_units_to_decimal(invalid, 4).is_err(), "{}", invalid);
+        }
+        // the scale-4 amounts parse to the same float as their decimal form
+        assert_eq!(minor_units_to_decimal("71234", 4).unwrap().parse::<f64>(), "7.1234".parse::<f64>());
+
+        assert_eq!(AmountUnit::parse("minor:4").ok(), Some(AmountUnit::MinorUnits(4)));
+        assert_eq!(AmountUnit::parse("major").ok(), Some(AmountUnit::Major));
+        assert!(AmountUnit::parse("minor:-1").is_err());
+    }
+
+    #[test]
+    fn large_whole_amounts_are_suspected_minor_units_once() {
+        let headers = StringRecord::from(vec!["type", " client", " tx", " amount"]);
+        let row = |amount: &str| StringRecord::from(vec!["deposit", "1", "1", amount]);
+        let mut check = MinorUnitsCheck::new(&headers);
+        for line in 2..11 {
+            assert_eq!(check.check(&row(" 150000"), line), None);
+        }
+        assert_eq!(check.check(&row(""), 11), None);
+        let suspected = check.check(&row("20000"), 12).expect("ten large whole amounts");
+        assert_eq!((suspected.line, suspected.amounts, suspected.large_whole), (12, 10, 10));
+        assert_eq!(check.check(&row("30000"), 13), None);
+
+        // decimal amounts, however large, and small whole ones are left alone
+        let mut check = MinorUnitsCheck::new(&headers);
+        let amounts = (0..20).map(|i| match i % 2 {
+            0 => "150000.0",
+            _ => "15",
+        });
+        assert!(amounts.enumerate().all(|(line, amount)| check.check(&row(amount), line as u64).is_none()));
+    }
+
+    /// Parses `csv` with `engine`, as records and as transactions, describing every row, errors
+    /// included.
+    async fn parse_with(csv: &[u8], options: ParseOptions, engine: ParserEngine) -> Vec<String> {
+        let options = ParseOptions { engine, ..options };
+        let input = || -> Box<dyn io::Read> { Box::new(io::Cursor::new(csv.to_vec())) };
+        let mut rows = Vec::new();
+        match parse_records_with(input(), &options).await {
+            Ok((header, records)) => {
+                rows.push(format!("{:?}", header));
+                rows.extend(records.map(|record| {
+                    format!("{} {:?} {:?}", record.line, record.raw, record.transaction)
+                }));
+            }
+            Err(err) => rows.push(format!("{:?}", err)),
+        }
+        match parse_transactions_with(input(), &options).await {
+            Ok(transactions) => rows.extend(transactions.map(|txn| format!("{:?}", txn))),
+            Err(err) => rows.push(format!("{:?}", err)),
+        }
+        rows
+    }
+
+    /// Checks that both engines parse `csv` alike, row for row.
+    async fn assert_engines_agree(csv: impl AsRef<[u8]>, options: ParseOptions) {
+        let csv = csv.as_ref();
+        let serde = parse_with(csv, options, ParserEngine::Serde).await;
+        let fast = parse_with(csv, options, ParserEngine::Fast).await;
+        let csv = String::from_utf8_lossy(csv);
+        assert_eq!(serde.len(), fast.len(), "{}", csv);
+        for (serde, fast) in serde.iter().zip(&fast) {
+            assert_eq!(serde, fast, "{}", csv);
+        }
+    }
+
+    fn write_csv(rows: &[Vec<String>]) -> String {
+        let mut w = csv::WriterBuilder::new().flexible(true).from_writer(Vec::new());
+        for row in rows {
+            w.write_record(row).unwrap();
+        }
+        String::from_utf8(w.into_inner().unwrap()).unwrap()
+    }
+
+    /// Field values that are valid in some columns and not in others, or that only some parsers
+    /// would read the same way.
+    const ADVERSARIAL_FIELDS: &[&str] = &[
+        "", " ", "0", "-0", "00012", "-1.5", "+7", "0x1F", "1e3", "1E-2", ".5", "5.", ".", "-", "--1", "- 1",
+        "inf", "NaN", "-infinity", "18446744073709551615", "18446744073709551616", "4294967295", "4294967296",
+        "65535", "65536", "99999999999999999999.9999", "0.00000000000000000000001", "9007199254740993",
+        "900719925474099.3", "0.1", "2.0000", "1.2.3", "1,5", "\"7\"", " 12 ", "\t12\t", "\u{a0}12\u{a0}",
+        "\u{661}\u{662}", "Deposit", " deposit ", "withdrawal", "chargeback", "representment", "rep\u{e9}sentment",
+        "x",
+    ];
+
+    #[tokio::test]
+    async fn the_fast_engine_parses_generated_rows_like_serde() -> Result<(), PaymentError> {
+        let mut w = csv::Writer::from_writer(Vec::new());
+        for (i, txn) in TransactionGenerator::new(11, 200).take(20_000).enumerate() {
+            let txn = match i % 7 {
+                0 => txn.with_reason("fraud").with_timestamp(1_700_000_000 + i as u64),
+                3 => txn.with_merchant("acme").with_memo(format!("row {}, \"quoted\"", i)),
+                _ => txn,
+            };
+            w.serialize(txn).unwrap();
+        }
+        let csv = String::from_utf8(w.into_inner().unwrap()).unwrap();
+        assert_engines_agree(&csv, ParseOptions::default()).await;
+
+        // every generated row is read by hand, none is left to serde
+        let mut rdr = csv::Reader::from_reader(csv.as_bytes());
+        let parser = RowParser::new(rdr.headers().unwrap().clone(), ParseOptions::default());
+        for raw in rdr.records() {
+            assert!(parser.read(&raw.unwrap()).is_some());
+        }
+
+        // the same rows with a field made adversarial here and there
+        let mut state = 11u64;
+        // splitting on commas breaks up the quoted memos too, for more adversarial rows
+        let mut rows: Vec<Vec<String>> =
+            csv.lines().map(|line| line.split(',').map(str::to_owned).collect()).collect();
+        for row in rows.iter_mut().skip(1) {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            if state >> 62 == 0 {
+                let field = (state >> 8) as usize % row.len();
+                row[field] = ADVERSARIAL_FIELDS[(state >> 20) as usize % ADVERSARIAL_FIELDS.len()].to_owned();
+            }
+        }
+        assert_engines_agree(&write_csv(&rows), ParseOptions::default()).await;
+
+        let transactions = parse_transactions_fast(Box::new(io::Cursor::new(csv))).await?;
+        assert_eq!(transactions.count(), 20_000);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn the_fast_engine_parses_adversarial_rows_like_serde() {
+        let headers = [
+            "type,client,tx,amount",
+            " type , client,tx ,amount ",
+            "tx,amount,client,type",
+            "type,client,tx",
+            "type,client,amount",
+            "type,client,tx,amount,amount",
+            "type,client,tx,tx,amount",
+            "type,client,tx,amount,extra",
+            "extra,type,client,tx,amount",
+            "type,client,tx,amount,reason,merchant,timestamp,memo,idempotency_key",
+            "idempotency_key,memo,timestamp,merchant,reason,amount,tx,client,type",
+            "type,client,tx,amount,idempotency_key,idempotency_key",
+            "Type,client,tx,amount",
+            "",
+        ];
+        let valid = |header: &str| match header {
+            "type" => "deposit",
+            "client" => "1",
+            "tx" => "2",
+            "amount" => "1.5",
+            "reason" => "fraud",
+            "merchant" => "acme",
+            "timestamp" => "1700000000",
+            "memo" => "note",
+            "idempotency_key" => "retry-7",
+            _ => "x",
+        };
+        for header in headers {
+            let names: Vec<&str> = header.split(',').map(str::trim).collect();
+            let valid_row: Vec<String> = names.iter().map(|name| valid(name).to_owned()).collect();
+            let mut rows = vec![header.split(',').map(str::to_owned).collect::<Vec<_>>()];
+            for column in 0..names.len() {
+                for field in ADVERSARIAL_FIELDS {
+                    let mut row = valid_row.clone();
+                    row[column] = (*field).to_owned();
+                    rows.push(row);
+                }
+            }
+            // rows short of fields, or with more than the header
+            for len in 0..names.len() {
+                rows.push(valid_row[..len].to_vec());
+                rows.push(valid_row[..len].iter().cloned().chain([String::new()]).collect());
+            }
+            rows.push(valid_row.iter().cloned().chain(["extra".to_owned(), "1".to_owned()]).collect());
+            let csv = write_csv(&rows);
+            assert_engines_agree(&csv, ParseOptions::default()).await;
+            let limits = InputLimits {
+                max_field_bytes: 8,
+                max_record_bytes: 64,
+                max_records: 100,
+            };
+            assert_engines_agree(&csv, ParseOptions { limits, ..ParseOptions::default() }).await;
+            let minor_units = ParseOptions {
+                amount_unit: AmountUnit::MinorUnits(4),
+                ..ParseOptions::default()
+            };
+            assert_engines_agree(&csv, minor_units).await;
+        }
+
+        // rows that aren't UTF-8, or not CSV at all
+        let csv = b"type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,\xff,2,1.0\n\"unterminated,1,3,1.0\n";
+        assert_engines_agree(csv, ParseOptions::default()).await;
+    }
+
+    #[test]
+    fn fixed_point_amounts_are_the_floats_str_parse_gives() {
+        let mut state = 5u64;
+        for _ in 0..100_000 {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let scale = (state >> 59) as usize % 9;
+            let units = (state >> 11) % 10u64.pow(12);
+            let digits = format!("{:0>width$}", units, width = scale + 1);
+            let (whole, fraction) = digits.split_at(digits.len() - scale);
+            let amount = format!("{}.{}", whole, fraction);
+            assert_eq!(read_amount(&amount), Some(amount.parse::<f64>().unwrap()), "{}", amount);
+            let negative = format!("-{}", amount);
+            assert_eq!(read_amount(&negative).map(f64::to_bits), negative.parse::<f64>().ok().map(f64::to_bits));
+        }
+        assert_eq!(read_amount("9007199254740993"), None);
+        assert_eq!(read_amount("1e3"), None);
+        assert_eq!(read_amount("."), None);
+    }
+
+    #[tokio::test]
+    async fn inputs_over_their_limits_end_with_the_limit_they_exceed() -> Result<(), PaymentError> {
+        let limits = InputLimits {
+            max_field_bytes: 10,
+            max_record_bytes: 30,
+            max_records: 3,
+        };
+        let cases = [
+            // a quoted line break neither ends the record nor restarts the field
+            (
+                "type,client,tx,amount,memo\ndeposit,1,1,1.0,\"a,b\nc\"\ndeposit,1,2,12345678901\n",
+                InputLimit::FieldBytes,
+                10,
+                4,
+            ),
+            (
+                "type,client,tx,amount,memo\ndeposit,1,1,1.0,abcdefghij,abcde\n",
+                InputLimit::RecordBytes,
+                30,
+                2,
+            ),
+            ("type,client,tx,amount\ndeposit,1,1,1.0\n\n\ndeposit,1,2,1.0\n", InputLimit::Records, 3, 5),
+            // a bare `\r` ends a line too
+            ("type,client,tx,amount\rdeposit,1,1,1.0\r\r\rdeposit,1,2,1.0\r", InputLimit::Records, 3, 5),
+        ];
+        for (csv, which, limit, at_line) in cases {
+            let options = ParseOptions {
+                limits,
+                ..ParseOptions::default()
+            };
+            let (_, records) = parse_records_with(Box::new(std::io::Cursor::new(csv)), &options).await?;
+            let last = records.last().map(|record| record.transaction);
+            assert!(
+                matches!(
+                    last,
+                    Some(Err(PaymentError::InputLimitExceeded { which: w, limit: l, at_line: a }))
+                        if (w, l, a) == (which, limit, at_line)
+                ),
+                "{:?}",
+                last
+            );
+        }
+
+        // inputs within the limits are read whole, a final line break included
+        let csv = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,1.0\ndeposit,1,3,1.0\n";
+        let options = ParseOptions {
+            limits,
+            ..ParseOptions::default()
+        };
+        let transactions = parse_transactions_with(Box::new(std::io::Cursor::new(csv)), &options).await?;
+        assert_eq!(transactions.collect::<Result<Vec<_>, _>>()?.len(), 3);
+        // `\r\n` is one line break, not two
+        let crlf = csv.replace('\n', "\r\n");
+        let transactions = parse_transactions_with(Box::new(std::io::Cursor::new(crlf)), &options).await?;
+        assert_eq!(transactions.collect::<Result<Vec<_>, _>>()?.len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn every_line_break_ends_a_line() -> Result<(), PaymentError> {
+        // bare `\r`, `\r\n` and `\n` mixed, and a `\r` kept within quotes
+        let csv = "type,client,tx,amount,memo\rdeposit,1,1,1.0,\"a\rb\"\rdeposit,1,2,x\r\n\
+                   deposit,1,3,2.0\ndeposit,1,4,3.0";
+        let (_, records) = parse_records(Box::new(std::io::Cursor::new(csv))).await?;
+        let records: Vec<_> = records.collect();
+        assert_eq!(records.iter().map(|record| record.line).collect::<Vec<_>>(), [2, 3, 4, 5]);
+        assert_eq!(records[0].transaction.as_ref().unwrap().memo.as_deref(), Some("a\rb"));
+        assert!(records[1].transaction.is_err());
+        assert_eq!(records[3].transaction.as_ref().unwrap().tx, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn missing_inputs_name_the_path() {
+        let path = std::env::temp_dir().join(format!("payment-engine-{}-missing.csv", std::process::id()));
+        let err = open_input(&path).err().expect("the file doesn't exist");
+        let source = err.source().and_then(|source| source.downcast_ref::<io::Error>());
+        assert_eq!(source.map(io::Error::kind), Some(io::ErrorKind::NotFound));
+        assert!(err.to_string().contains("missing.csv"), "{}", err);
+    }
+
+    #[test]
+    fn directories_are_rejected() {
+        let dir = std::env::temp_dir().canonicalize().unwrap();
+        let err = open_input(&dir).err().expect("a directory isn't an input");
+        let source = err.source().and_then(|source| source.downcast_ref::<io::Error>());
+        assert_eq!(source.map(io::Error::kind), Some(io::ErrorKind::IsADirectory));
+        assert!(err.to_string().contains(&*dir.to_string_lossy()), "{}", err);
+        assert!(err.to_string().contains("is a directory"), "{}", err);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unreadable_inputs_get_a_permission_hint() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("payment-engine-{}-unreadable.csv", std::process::id()));
+        fs::write(&path, "type,client,tx,amount\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o000)).unwrap();
+        let result = open_input(&path);
+        fs::remove_file(&path).unwrap();
+        // root reads the file anyway, there is no permission error to check then
+        if let Err(err) = result {
+            let source = err.source().and_then(|source| source.downcast_ref::<io::Error>());
+            assert_eq!(source.map(io::Error::kind), Some(io::ErrorKind::PermissionDenied));
+            assert!(err.to_string().contains("check the permissions"), "{}", err);
+        }
+    }
 }
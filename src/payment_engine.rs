@@ -1,27 +1,348 @@
-use crate::types::{Client, Transaction, TransactionType};
-use std::collections::HashMap;
+use crate::{
+    accrual::{self, AccrualEntry, AccrualReport},
+    amount::Amount,
+    batch::{BatchResult, BatchRow, Rejection},
+    canonical,
+    config::{
+        ClientCreationPolicy, ClientMergePolicy, DisputeAmountPolicy, DisputeShortfallPolicy, DisputeSupport,
+        DuplicateAction, DustPolicy, EngineConfig,
+    },
+    corrections::{Balances, CorrectionReceipt},
+    errors::{Limit, MergeError, PaymentError},
+    filter::ClientFilter,
+    idempotency::RecentKeys,
+    invariants::{self, InvariantViolation, Ledger, MoneyFlows, Totals, TotalsDrift, ViolationKind},
+    journal::TxJournal,
+    merchants::MerchantTable,
+    panics::{self, CaughtPanic, Checkpoint},
+    rejection::RejectionRecord,
+    report::{self, csv_field, OutputOrder, ReportOptions},
+    store::{ClientStore, InMemoryClientStore, InMemoryTransactionStore, TransactionStore},
+    types::{
+        Client, ClientId, ClientView, DisputeView, IgnoreReason, LastDeposit, LockCause,
+        LockedDepositPolicy, ProcessOutcome, SourceRef, StatusReason, SuspectedDuplicate,
+        Transaction, TransactionState, TransactionType, Until,
+    },
+    undo::{UndoEntry, UndoHistory},
+    warnings::{EngineWarning, WarningSink},
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::{self, Write},
+    ops::ControlFlow,
+    time::Duration,
+};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
 
-pub struct PaymentEngine {
-    pub clients: HashMap<u16, Client>,
-    pub transactions: HashMap<u32, Transaction>,
+pub struct PaymentEngine<S: TransactionStore = InMemoryTransactionStore, C: ClientStore = InMemoryClientStore> {
+    /// The accounts of the run: those opened by its transactions, loaded from `client_store` or
+    /// from an initial state.
+    pub clients: HashMap<ClientId, Client>,
+    /// Where accounts are loaded from and written back to, see `ClientStore`.
+    client_store: C,
+    pub transactions: S,
+    /// The transactions currently under dispute, by tx id, with the dispute's reason. Entries
+    /// are removed once the dispute is resolved or charged back.
     pub disputed_transactions: HashMap<u32, Transaction>,
+    /// The transactions charged back and not won back by a representment, by tx id.
+    pub charged_back_transactions: HashMap<u32, Transaction>,
+    /// The transactions whose dispute was resolved, or whose chargeback a representment won
+    /// back, by tx id. They stay in the set when disputed again, and leave it with their stored
+    /// transaction.
+    resolved_transactions: HashSet<u32>,
+    /// Disputed amounts not held for lack of available funds, by tx id, under
+    /// `DisputeShortfallPolicy::HoldPartial` or `Freeze`. Entries are removed once the dispute
+    /// is settled, except for shortfalls written off by a chargeback.
+    pub dispute_shortfalls: HashMap<u32, Amount>,
+    /// The negative balance each chargeback zeroed under `DisputeShortfallPolicy::Allow`, by tx
+    /// id, at most what the chargeback took. A representment doesn't give it back.
+    chargeback_write_offs: HashMap<u32, Amount>,
+    /// Reason of each chargeback, by tx id: the chargeback's own reason, or else its dispute's.
+    pub chargeback_reasons: HashMap<u32, String>,
+    /// Deposits, disputes and chargebacks of the deposits that name a merchant.
+    pub merchants: MerchantTable,
+    /// Deposits flagged by duplicate detection, in input order.
+    pub suspected_duplicates: Vec<SuspectedDuplicate>,
+    /// The open escrow holds, by tx id. Entries are removed once the hold is released or
+    /// captured.
+    pub escrow_holds: HashMap<u32, Transaction>,
+    /// The withdrawals awaiting settlement, by tx id. Entries are removed once the withdrawal is
+    /// settled or cancelled.
+    pub pending_withdrawals: HashMap<u32, Transaction>,
+    /// Tx id and source of the last transaction a store lookup found with a source, for the
+    /// rejection's detail of the row that looked it up.
+    referenced: Option<(u32, SourceRef)>,
+    /// Client ids in the order their accounts were created.
+    first_seen: Vec<ClientId>,
+    /// Balances summed over every account, kept up to date as accounts change.
+    totals: Totals,
+    /// The balance corrections applied so far, in order.
+    corrections: Vec<CorrectionReceipt>,
+    /// The interest paid by accruals so far, one entry per account and accrual, in order.
+    accruals: Vec<AccrualEntry>,
+    /// Deposits and withdrawals applied without being stored, as dispute support is off.
+    unretained: u64,
+    /// Deposits and withdrawals below `EngineConfig::min_amount`, applied or rejected.
+    dust: u64,
+    /// Thresholds of `EngineConfig::max_open_disputes` the open disputes had reached when last
+    /// counted, see `track_open_disputes`.
+    open_disputes_reached: usize,
+    /// Tx id of the last row rejected as `IgnoreReason::AmountMismatch`, with the amount it
+    /// should have had, for the rejection's detail.
+    amount_mismatch: Option<(u32, String)>,
+    /// The transactions whose processing panicked, under `EngineConfig::max_panics`.
+    panics: Vec<CaughtPanic>,
+    /// Store lookups and removals that failed and succeeded when tried again.
+    storage_retries: u64,
+    /// Escrow holds released on expiry, under `EngineConfig::hold_ttl_secs`.
+    expired_holds: u64,
+    /// The money the applied transactions moved, checked against the accounts by
+    /// `check_ledger`.
+    flows: MoneyFlows,
+    /// Credited on top of every deposit without being recorded in `flows`, see
+    /// `with_deposit_skew`.
+    #[cfg(any(test, feature = "testing"))]
+    deposit_skew: Amount,
+    history: UndoHistory,
+    config: EngineConfig,
+    warnings: Option<WarningSink>,
+    /// Deposits and withdrawals applied by earlier runs, rejected as already processed.
+    journal: Option<TxJournal>,
+    /// Idempotency keys of the transactions applied lately, when there is no journal to keep
+    /// them.
+    recent_keys: RecentKeys,
+    /// The latest timestamp of the rows processed so far, applied or not, which the age of the
+    /// open disputes is measured against.
+    latest_timestamp: Option<u64>,
 }
 
 impl PaymentEngine {
     pub fn new() -> Self {
+        PaymentEngine::with_store(InMemoryTransactionStore::new())
+    }
+}
+
+impl Default for PaymentEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: TransactionStore> PaymentEngine<S> {
+    /// Creates an engine that keeps its transactions in the given store.
+    pub fn with_store(store: S) -> Self {
+        PaymentEngine::with_stores(store, InMemoryClientStore)
+    }
+}
+
+impl<S: TransactionStore, C: ClientStore> PaymentEngine<S, C> {
+    /// Creates an engine that keeps its transactions in `store` and loads and writes back the
+    /// accounts through `client_store`.
+    pub fn with_stores(store: S, client_store: C) -> Self {
         PaymentEngine {
             clients: HashMap::new(),
-            transactions: HashMap::new(),
+            client_store,
+            transactions: store,
             disputed_transactions: HashMap::new(),
+            escrow_holds: HashMap::new(),
+            pending_withdrawals: HashMap::new(),
+            referenced: None,
+            charged_back_transactions: HashMap::new(),
+            resolved_transactions: HashSet::new(),
+            dispute_shortfalls: HashMap::new(),
+            chargeback_write_offs: HashMap::new(),
+            chargeback_reasons: HashMap::new(),
+            merchants: MerchantTable::new(),
+            suspected_duplicates: Vec::new(),
+            first_seen: Vec::new(),
+            totals: Totals::default(),
+            corrections: Vec::new(),
+            accruals: Vec::new(),
+            unretained: 0,
+            dust: 0,
+            open_disputes_reached: 0,
+            amount_mismatch: None,
+            panics: Vec::new(),
+            storage_retries: 0,
+            expired_holds: 0,
+            flows: MoneyFlows::default(),
+            #[cfg(any(test, feature = "testing"))]
+            deposit_skew: Amount::ZERO,
+            history: UndoHistory::new(0),
+            config: EngineConfig::default(),
+            warnings: None,
+            journal: None,
+            recent_keys: RecentKeys::new(),
+            latest_timestamp: None,
+        }
+    }
+
+    /// Applies the given business rules instead of the defaults.
+    pub fn with_config(mut self, config: EngineConfig) -> Self {
+        self.config = config;
+        self.mark_blocklisted();
+        self
+    }
+
+    /// Credits every deposit `skew` more than its amount, without recording it as money that
+    /// came in: a deliberate bug, to check that `check_ledger` catches a handler going wrong.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn with_deposit_skew(mut self, skew: Amount) -> Self {
+        self.deposit_skew = skew;
+        self
+    }
+
+    /// Gives the accounts of blocklisted clients the blocklist's reason, unless they already
+    /// have one.
+    fn mark_blocklisted(&mut self) {
+        for (id, listed_for) in &self.config.blocked_clients {
+            let Some(client) = self.clients.get_mut(id) else {
+                continue;
+            };
+            if client.status_reason.is_none() {
+                let reason = match listed_for {
+                    Some(listed_for) => format!("blocklisted: {}", listed_for),
+                    None => "blocklisted".to_owned(),
+                };
+                client.status_reason = Some(StatusReason::new(&reason));
+            }
+        }
+    }
+
+    /// Rejects the deposits and withdrawals whose tx id is in `journal` as
+    /// `IgnoreReason::AlreadyProcessed`, and journals those the engine applies. See `journal`
+    /// for when they reach the disk.
+    pub fn with_journal(mut self, journal: TxJournal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    /// Writes the ids journaled since the last sync to the journal, if the engine has one. Run it
+    /// before saving the accounts, so that no saved change can be applied again.
+    ///
+    /// # Errors
+    ///
+    /// Returns the errors of `TxJournal::sync`.
+    pub fn sync_journal(&mut self) -> Result<(), PaymentError> {
+        self.journal.as_mut().map_or(Ok(()), TxJournal::sync)
+    }
+
+    /// Sets what happens to deposits to locked accounts, rejected by default.
+    pub fn with_locked_deposit_policy(mut self, policy: LockedDepositPolicy) -> Self {
+        self.config.locked_deposits = policy;
+        self
+    }
+
+    /// Returns the business rules the engine applies.
+    pub fn config(&self) -> &EngineConfig {
+        &self.config
+    }
+
+    /// Describes why a transaction of `client` was ignored, for reports: the reason's name,
+    /// followed by the reason the client was blocklisted for, or by how much the client can
+    /// still deposit under its balance cap.
+    pub fn describe_ignored(&self, client: ClientId, reason: IgnoreReason) -> String {
+        match reason {
+            IgnoreReason::Blocklisted => match self.config.blocked_clients.get(&client) {
+                Some(Some(listed_for)) => format!("{}: {}", reason.as_str(), listed_for),
+                _ => reason.as_str().to_owned(),
+            },
+            IgnoreReason::BalanceCapExceeded => {
+                let total = self.clients.get(&client).map_or(Amount::ZERO, |client| client.total);
+                let cap = self.config.balance_cap(client);
+                let cap = cap.and_then(|cap| Amount::from_f64(cap, self.config.rounding).ok());
+                match cap.and_then(|cap| cap.checked_sub(total).ok()) {
+                    Some(headroom) => format!("{}: headroom {}", reason.as_str(), headroom.max(Amount::ZERO)),
+                    None => reason.as_str().to_owned(),
+                }
+            }
+            IgnoreReason::UnknownClient if !self.may_transact(client) => {
+                format!("{}: not onboarded", reason.as_str())
+            }
+            _ => reason.as_str().to_owned(),
+        }
+    }
+
+    /// Returns the record of `txn`, ignored for `reason`, with the detail of `describe_ignored`,
+    /// or for an amount mismatch the row's amount and the one expected. When the row referred to
+    /// an applied transaction with a known source, e.g. the deposit a dispute refers to, the
+    /// detail ends with where it was read from.
+    pub fn rejection(&self, txn: &Transaction, reason: IgnoreReason) -> RejectionRecord {
+        let detail = match &self.amount_mismatch {
+            Some((tx, expected)) if reason == IgnoreReason::AmountMismatch && *tx == txn.tx => format!(
+                "{}: row says {:.4}, expected {}",
+                reason.as_str(),
+                txn.amount.unwrap_or_default(),
+                expected
+            ),
+            _ if reason == IgnoreReason::AmountAboveLimit => format!(
+                "{}: {:.4} is above the limit of {:.4}",
+                reason.as_str(),
+                txn.amount.unwrap_or_default(),
+                self.config.max_transaction_amount.unwrap_or_default()
+            ),
+            _ if reason == IgnoreReason::InternalError => match self.panics.last() {
+                Some(panic) if panic.tx == txn.tx => format!("{}: panicked: {}", reason.as_str(), panic.message),
+                _ => reason.as_str().to_owned(),
+            },
+            _ => self.describe_ignored(txn.client, reason),
+        };
+        // ignored rows are never recorded, so a source found is the earlier transaction's
+        let referenced = match &self.referenced {
+            Some((tx, source)) if *tx == txn.tx => Some(source),
+            _ => None,
+        };
+        let detail = match referenced.or_else(|| self.open_source(txn.tx)) {
+            Some(source) => format!("{} (tx {} at {})", detail, txn.tx, source),
+            None => detail,
+        };
+        RejectionRecord::ignored(txn, reason, detail)
+    }
+
+    /// Keeps what the last `depth` applied transactions changed, so `undo_last` can revert them.
+    ///
+    /// Recording costs an extra store lookup per deposit and withdrawal, so it is off (`0`) by
+    /// default.
+    pub fn with_undo_history(mut self, depth: usize) -> Self {
+        self.history = UndoHistory::new(depth);
+        self
+    }
+
+    /// Emits a warning through `sender` for every ignored transaction and every invariant an
+    /// account fails after a transaction, as they happen rather than at the end of the run.
+    ///
+    /// Warnings are sent without waiting: those that don't fit in the channel are dropped and
+    /// counted in `dropped_warnings`.
+    pub fn with_warning_sink(mut self, sender: mpsc::Sender<EngineWarning>) -> Self {
+        self.warnings = Some(WarningSink::new(sender));
+        self
+    }
+
+    /// Emits a warning through the warning sink, if any, e.g. for a row the caller skipped.
+    pub fn warn(&mut self, warning: EngineWarning) {
+        if let Some(warnings) = self.warnings.as_mut() {
+            warnings.send(warning);
         }
     }
 
+    /// Returns the number of warnings dropped because the warning channel was full.
+    pub fn dropped_warnings(&self) -> u64 {
+        self.warnings.as_ref().map_or(0, WarningSink::dropped)
+    }
+
+    /// Removes the warning sink, closing the channel once every warning sent is received.
+    pub fn close_warnings(&mut self) {
+        self.warnings = None;
+    }
+
     /// Asynchronously processes a given transaction and updates the client’s account state.
     ///
     /// # Arguments
     ///
     /// * `txn` - A `Transaction` object representing the incoming transaction to be processed.
-    ///           This contains the type of transaction and associated metadata (e.g., client ID, amount).
+    ///   This contains the type of transaction and associated metadata (e.g., client ID, amount).
     ///
     /// # Transaction Types
     ///
@@ -30,234 +351,5642 @@ impl PaymentEngine {
     /// * `Dispute`: Temporarily moves funds from available to held, pending a resolution.
     /// * `Resolve`: Moves held funds back to available, resolving the dispute.
     /// * `Chargeback`: Finalizes a dispute, removing held funds and locking the client’s account.
-    pub async fn process_transaction(&mut self, txn: Transaction) {
-        match txn.r#type {
-            TransactionType::Deposit => self.process_deposit(txn),
-            TransactionType::Withdrawal => self.process_withdrawal(txn),
-            TransactionType::Dispute => self.process_dispute(txn),
-            TransactionType::Resolve => self.process_resolve(txn),
-            TransactionType::Chargeback => self.process_chargeback(txn),
+    /// * `Hold`: Moves funds from available to held in escrow, if sufficient funds are present.
+    /// * `Release`: Moves the escrowed funds back to available.
+    /// * `Capture`: Withdraws the escrowed funds, removing them from held and total.
+    /// * `Representment`: Reverses a chargeback, crediting the funds back and unlocking the
+    ///   account unless other chargebacks keep it locked.
+    /// * `WithdrawalPending`: Moves funds from available to held as pending out, if sufficient
+    ///   funds are present.
+    /// * `WithdrawalSettle`: Withdraws the pending funds, removing them from held and total.
+    /// * `WithdrawalCancel`: Moves the pending funds back to available.
+    ///
+    /// # Returns
+    ///
+    /// `ProcessOutcome::Applied` if the transaction changed the engine state, otherwise
+    /// `ProcessOutcome::Ignored` with the reason it was skipped.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::StorageError` if the transaction store fails, or a
+    /// `PaymentError::AmountOutOfRange`, leaving the account as it was, if the transaction's
+    /// amount isn't a finite number or would take a balance out of the range of `Amount`.
+    pub async fn process_transaction(
+        &mut self,
+        txn: Transaction,
+    ) -> Result<ProcessOutcome, PaymentError> {
+        self.process_transaction_at(txn, None).await
+    }
+
+    /// Processes a transaction like `process_transaction`, given the line of the input row it
+    /// was parsed from. The line is recorded on the account if the transaction locks it.
+    pub async fn process_transaction_at(
+        &mut self,
+        txn: Transaction,
+        line: Option<u64>,
+    ) -> Result<ProcessOutcome, PaymentError> {
+        self.process_transaction_from(txn, line.map(SourceRef::line)).await
+    }
+
+    /// Processes a transaction like `process_transaction_at`, given the file and line of the
+    /// input row it was parsed from. The source of an applied deposit, withdrawal, escrow hold or
+    /// pending withdrawal is kept with it for `source_of`, the disputes and locked accounts
+    /// reports and the details of the rows later rejected about it.
+    ///
+    /// Under `EngineConfig::max_panics`, a panic while processing the transaction is caught: what
+    /// it changed is restored and it is ignored as `IgnoreReason::InternalError`, see
+    /// `caught_panics`. Once more transactions than allowed panicked, the last one fails with a
+    /// `PaymentError::InternalError` instead.
+    pub async fn process_transaction_from(
+        &mut self,
+        txn: Transaction,
+        source: Option<SourceRef>,
+    ) -> Result<ProcessOutcome, PaymentError> {
+        self.load_client(txn.client).await?;
+        self.expire_holds(txn.timestamp).await?;
+        match self.config.max_panics {
+            Some(max_panics) => self.process_guarded(txn, source, max_panics).await,
+            None => self.process_unguarded(txn, source).await,
         }
     }
 
-    fn process_deposit(&mut self, txn: Transaction) {
-        let client = self.clients.entry(txn.client).or_insert(Client::new());
+    /// Loads the account of `id` from the client store, unless the engine has it already. A
+    /// loaded account counts as money the run opened with, like an initial state.
+    async fn load_client(&mut self, id: ClientId) -> Result<(), PaymentError> {
+        if !C::KEEPS_ACCOUNTS || self.clients.contains_key(&id) {
+            return Ok(());
+        }
+        let Some(client) = self.client_store.get(id).await? else {
+            return Ok(());
+        };
+        self.flows.opening += invariants::units(client.total);
+        self.totals.add(&client);
+        self.first_seen.push(id);
+        self.clients.insert(id, client);
+        Ok(())
+    }
 
-        if !client.locked {// don't process if account is locked
-            if let Some(amount) = txn.amount {
-                client.available += amount;
-                client.total += amount;
-            }
-            self.transactions.insert(txn.tx, txn);
+    /// Writes the account of `id` back to the client store, or removes it from the store if the
+    /// engine has no account for it, when it changed from `before`.
+    async fn write_client(&mut self, id: ClientId, before: Option<Client>) -> Result<(), PaymentError> {
+        if !C::KEEPS_ACCOUNTS {
+            return Ok(());
+        }
+        match self.clients.get(&id).copied() {
+            Some(client) if before != Some(client) => self.client_store.insert(id, client).await,
+            None if before.is_some() => self.client_store.remove(id).await,
+            _ => Ok(()),
         }
     }
 
-    fn process_withdrawal(&mut self, txn: Transaction) {
-        let client = self.clients.get_mut(&txn.client);
-        if let Some(client) = client {
-            if !client.locked { // don't process if account is locked
-                if let Some(amount) = txn.amount {
-                    if client.available >= amount {
-                        client.available -= amount;
-                        client.total -= amount;
+    /// Writes every account of the engine to the client store, e.g. after loading an initial
+    /// state or correcting a balance. Accounts changed by transactions are written as they
+    /// change.
+    ///
+    /// # Errors
+    ///
+    /// Returns the client store's error.
+    pub async fn save_clients(&mut self) -> Result<(), PaymentError> {
+        if !C::KEEPS_ACCOUNTS {
+            return Ok(());
+        }
+        let mut ids: Vec<ClientId> = self.clients.keys().copied().collect();
+        ids.sort_unstable();
+        for id in ids {
+            self.client_store.insert(id, self.clients[&id]).await?;
+        }
+        Ok(())
+    }
 
-                        self.transactions.insert(txn.tx, txn);
-                    }
-                }
+    /// Processes a transaction like `process_transaction_from`, catching a panic.
+    async fn process_guarded(
+        &mut self,
+        txn: Transaction,
+        source: Option<SourceRef>,
+        max_panics: usize,
+    ) -> Result<ProcessOutcome, PaymentError> {
+        let (tx, client, r#type) = (txn.tx, txn.client, txn.r#type);
+        let rejected = txn.clone();
+        let mut checkpoint = None;
+        let caught = panics::catch_unwind(async {
+            checkpoint = Some(self.checkpoint(&txn).await?);
+            self.process_unguarded(txn, source.clone()).await
+        })
+        .await;
+        let message = match caught {
+            Ok(result) => return result,
+            Err(message) => message,
+        };
+        let panic = CaughtPanic {
+            tx,
+            client,
+            r#type,
+            source,
+            message,
+        };
+        // without a checkpoint, the panic came before anything was changed
+        if let Some(checkpoint) = checkpoint {
+            let restored = match panics::catch_unwind(self.restore(checkpoint)).await {
+                Ok(restored) => restored.map_err(|err| err.to_string()),
+                Err(message) => Err(format!("panicked: {}", message)),
+            };
+            if let Err(message) = restored {
+                return Err(PaymentError::InternalError(format!(
+                    "{}, and restoring the state it changed failed: {}",
+                    panic, message
+                )));
             }
         }
+        if self.panics.len() >= max_panics {
+            return Err(PaymentError::InternalError(format!(
+                "{}, more than {} panics",
+                panic, max_panics
+            )));
+        }
+        let source = panic.source.clone();
+        self.panics.push(panic);
+        if self.warnings.is_some() {
+            self.warn_about(&rejected, source.as_ref(), ProcessOutcome::Ignored(IgnoreReason::InternalError));
+        }
+        Ok(ProcessOutcome::Ignored(IgnoreReason::InternalError))
     }
 
-    fn process_dispute(&mut self, txn: Transaction) {
-        if let Some(original_txn) = self.transactions.get(&txn.tx) {
-            if original_txn.client == txn.client { // both transaction should refer to same client
-                let client = self.clients.get_mut(&original_txn.client);
-                if let Some(client) = client {
-                    if let Some(amount) = original_txn.amount {
-                        client.available -= amount;
-                        client.held += amount;
-                    }
-                }
-                self.disputed_transactions.insert(txn.tx, txn);
+    /// Returns the transactions whose processing panicked and was caught, in input order.
+    pub fn caught_panics(&self) -> &[CaughtPanic] {
+        &self.panics
+    }
+
+    /// Returns the number of escrow holds released on expiry, see `EngineConfig::hold_ttl_secs`.
+    pub fn expired_holds(&self) -> u64 {
+        self.expired_holds
+    }
+
+    /// Returns the number of store lookups and removals that failed and succeeded when tried
+    /// again. A run with any is degraded: its results are right, but the store is flaky.
+    pub fn storage_retries(&self) -> u64 {
+        self.storage_retries
+    }
+
+    /// Looks a transaction up in the store, trying once more if the lookup fails.
+    async fn stored(&mut self, tx: u32) -> Result<Option<Transaction>, PaymentError> {
+        let found = match self.transactions.get(tx).await {
+            Ok(found) => found,
+            Err(_) => {
+                let found = self.transactions.get(tx).await?;
+                self.storage_retries += 1;
+                found
             }
+        };
+        if let Some(source) = found.as_ref().and_then(|txn| txn.source.clone()) {
+            self.referenced = Some((tx, source));
         }
+        Ok(found)
     }
 
-    fn process_resolve(&mut self, txn: Transaction) {
-        if self.disputed_transactions.contains_key(&txn.tx) { // resolve only if disputed transaction reference is present
-            if let Some(original_txn) = self.transactions.get(&txn.tx) {
-                if original_txn.client == txn.client { // both transaction should refer to same client
-                    let client = self.clients.get_mut(&original_txn.client);
-                    if let Some(client) = client {
-                        if let Some(amount) = original_txn.amount {
-                            client.available += amount;
-                            client.held -= amount;
-                        }
-                    }
-                }
+    /// Removes a transaction from the store, trying once more if the removal fails, and forgets
+    /// that its dispute was resolved. Inserts aren't retried: the failed one took the transaction.
+    async fn unstore(&mut self, tx: u32) -> Result<(), PaymentError> {
+        if self.transactions.remove(tx).await.is_err() {
+            self.transactions.remove(tx).await?;
+            self.storage_retries += 1;
+        }
+        self.resolved_transactions.remove(&tx);
+        Ok(())
+    }
+
+    /// Takes what `txn` may change, so a panic while processing it can be undone by `restore`.
+    async fn checkpoint(&mut self, txn: &Transaction) -> Result<Checkpoint, PaymentError> {
+        // a deposit counts for its own merchant, the others for their deposit's
+        let merchant = match txn.r#type {
+            TransactionType::Deposit => txn.merchant.clone(),
+            TransactionType::Dispute | TransactionType::Chargeback | TransactionType::Representment
+                if !self.merchants.is_empty() =>
+            {
+                self.stored(txn.tx).await?.and_then(|deposit| deposit.merchant)
             }
+            _ => None,
+        };
+        Ok(Checkpoint {
+            entry: self.undo_entry(txn).await?,
+            totals: self.totals,
+            unretained: self.unretained,
+            suspected_duplicates: self.suspected_duplicates.len(),
+            amount_mismatch: self.amount_mismatch.clone(),
+            merchant: merchant.map(|merchant| self.merchants.snapshot(&merchant)),
+        })
+    }
+
+    /// Puts back what was taken by `checkpoint`, whatever the transaction changed of it.
+    async fn restore(&mut self, checkpoint: Checkpoint) -> Result<(), PaymentError> {
+        self.revert(checkpoint.entry).await?;
+        self.totals = checkpoint.totals;
+        self.unretained = checkpoint.unretained;
+        self.suspected_duplicates.truncate(checkpoint.suspected_duplicates);
+        self.amount_mismatch = checkpoint.amount_mismatch;
+        if let Some(merchant) = checkpoint.merchant {
+            self.merchants.restore(merchant);
         }
+        Ok(())
     }
 
-    fn process_chargeback(&mut self, txn: Transaction) {
-        if self.disputed_transactions.contains_key(&txn.tx) { // chargeback only if disputed transaction reference is present
-            if let Some(original_txn) = self.transactions.get(&txn.tx) {
-                if original_txn.client == txn.client { // both transaction should refer to same client
-                    let client = self.clients.get_mut(&original_txn.client);
-                    if let Some(client) = client {
-                        if let Some(amount) = original_txn.amount {
-                            client.total -= amount;
-                            if client.available < 0.0 || client.total < 0.0 {
-                                client.total = 0.0;
-                                client.available = 0.0;
-                            }
-                            client.held -= amount;
-                            client.locked = true;
-                        }
-                    }
-                }
+    /// Processes a transaction like `process_transaction_from`, letting a panic unwind.
+    async fn process_unguarded(
+        &mut self,
+        txn: Transaction,
+        source: Option<SourceRef>,
+    ) -> Result<ProcessOutcome, PaymentError> {
+        let client = txn.client;
+        let before = self.clients.get(&client).copied();
+        let outcome = self.process_untotaled(txn, source).await?;
+        self.retotal(client, before);
+        Ok(outcome)
+    }
+
+    /// Processes a transaction like `process_unguarded`, leaving the running totals to the
+    /// caller.
+    async fn process_untotaled(
+        &mut self,
+        mut txn: Transaction,
+        source: Option<SourceRef>,
+    ) -> Result<ProcessOutcome, PaymentError> {
+        let line = source.as_ref().map(|source| source.line);
+        self.referenced = None;
+        if let Some(timestamp) = txn.timestamp {
+            self.latest_timestamp = self.latest_timestamp.max(Some(timestamp));
+        }
+        // kept for the warning the transaction may end up in
+        let warned = self.warnings.is_some().then(|| (txn.clone(), source.clone()));
+        // kept with the transaction, so it goes wherever the transaction is stored
+        if matches!(
+            txn.r#type,
+            TransactionType::Deposit
+                | TransactionType::Withdrawal
+                | TransactionType::Hold
+                | TransactionType::WithdrawalPending
+        ) {
+            txn.source = source;
+        }
+        let client = txn.client;
+        let unwritten = C::KEEPS_ACCOUNTS.then(|| self.clients.get(&client).copied());
+        let journaled = matches!(txn.r#type, TransactionType::Deposit | TransactionType::Withdrawal)
+            .then_some(txn.tx);
+        let key = txn.idempotency_key.clone();
+        let outcome = if journaled.is_some_and(|tx| self.already_processed(tx)) {
+            ProcessOutcome::Ignored(IgnoreReason::AlreadyProcessed)
+        } else if key.as_deref().is_some_and(|key| self.seen_key(key)) {
+            ProcessOutcome::Ignored(IgnoreReason::DuplicateIdempotencyKey)
+        } else if self.history.is_enabled() && txn.r#type != TransactionType::Accrue {
+            // an accrual can't be undone, it clears the history instead
+            let entry = self.undo_entry(&txn).await?;
+            let outcome = self.apply_transaction(txn, line).await?;
+            if outcome == ProcessOutcome::Applied { // ignored transactions change nothing worth reverting
+                self.history.push(entry);
             }
+            outcome
+        } else {
+            self.apply_transaction(txn, line).await?
+        };
+        if let (Some(tx), Some(journal)) = (journaled, self.journal.as_mut()) {
+            if outcome == ProcessOutcome::Applied {
+                journal.record(tx)?;
+            }
+        }
+        if let (Some(key), ProcessOutcome::Applied) = (key, outcome) {
+            match self.journal.as_mut() {
+                Some(journal) => journal.record_key(key)?,
+                None => self.recent_keys.insert(key, self.config.max_idempotency_keys),
+            }
+        }
+        if let Some(before) = unwritten {
+            self.write_client(client, before).await?;
         }
+        if let Some((txn, source)) = warned {
+            self.warn_about(&txn, source.as_ref(), outcome);
+        }
+        Ok(outcome)
     }
 
-    /// This asynchronous function prints the state of each client in a CSV format, including the
-    /// available funds, held funds, total balance, and account lock status.
-    ///
-    /// The function outputs the state of each client as follows:
+    /// Returns where the applied deposit, withdrawal, escrow hold or pending withdrawal `tx` was
+    /// read from, if it was processed with a source. The source is kept with the transaction, so
+    /// it is found as long as the transaction is.
     ///
-    /// ```text
-    /// client,available,held,total,locked
-    /// 1,100.0000,0.0000,100.0000,false
-    /// 2,200.0000,0.0000,200.0000,false
-    /// ```
+    /// # Errors
     ///
-    /// The available, held, and total values are displayed with four decimal places.
-    pub async fn output_client_states(&self) {
-        println!("client,available,held,total,locked");
-        for (client_id, client) in &self.clients {
-            println!(
-                "{},{:.4},{:.4},{:.4},{}",
-                client_id, client.available, client.held, client.total, client.locked
-            );
+    /// Returns a `PaymentError::StorageError` if the transaction store fails.
+    pub async fn source_of(&mut self, tx: u32) -> Result<Option<SourceRef>, PaymentError> {
+        if let Some(source) = self.open_source(tx) {
+            return Ok(Some(source.clone()));
         }
+        Ok(self.stored(tx).await?.and_then(|txn| txn.source))
     }
-}
 
-// Test trasaction processor
-#[cfg(test)]
-mod tests {
-    use crate::{errors::PaymentError, parser::parse_transactions, payment_engine::PaymentEngine};
+    /// Returns where the transaction `tx` was read from, if it is disputed, charged back, an open
+    /// escrow hold or a pending withdrawal and was processed with a source.
+    fn open_source(&self, tx: u32) -> Option<&SourceRef> {
+        [
+            &self.disputed_transactions,
+            &self.charged_back_transactions,
+            &self.escrow_holds,
+            &self.pending_withdrawals,
+        ]
+        .into_iter()
+        .find_map(|open| open.get(&tx))
+        .and_then(|txn| txn.source.as_ref())
+    }
 
-    #[tokio::test]
-    async fn can_process_simple_transactions() -> Result<(), PaymentError> {
-        let csv = "type, client, tx, amount 
-        deposit, 1, 1, 1.0 
-        deposit, 2, 2, 2.0 
-        deposit, 1, 3, 2.0 
-        withdrawal, 1, 4, 1.5 
-        withdrawal, 2, 5, 3.0";
-        let str_buf = stringreader::StringReader::new(csv);
-        let transactions = parse_transactions(Box::new(str_buf)).await?;
-        let mut engine = PaymentEngine::new();
+    /// Returns whether the deposit or withdrawal `tx` is in the journal, as applied by an
+    /// earlier run.
+    fn already_processed(&self, tx: u32) -> bool {
+        self.journal.as_ref().is_some_and(|journal| journal.contains(tx))
+    }
 
-        for txn in transactions {
-            engine.process_transaction(txn?).await;
+    /// Returns whether a transaction with the idempotency key `key` was applied, as found in the
+    /// journal or else among the keys seen lately.
+    fn seen_key(&mut self, key: &str) -> bool {
+        match &self.journal {
+            Some(journal) => journal.contains_key(key),
+            None => self.recent_keys.check(key),
         }
+    }
 
-        if let Some(client) = engine.clients.get(&1) {
-            assert_eq!(client.total, 1.5);
-            assert_eq!(client.available, 1.5);
-            assert!(!client.locked);
-            assert_eq!(client.held, 0.0);
+    /// Brings the running totals up to date after a change to one account, given the account
+    /// as it was before.
+    fn retotal(&mut self, id: ClientId, before: Option<Client>) {
+        if let Some(before) = before {
+            self.totals.remove(&before);
+        }
+        if let Some(after) = self.clients.get(&id) {
+            self.totals.add(after);
         }
-
-        Ok(())
     }
 
-    #[tokio::test]
-    async fn can_process_chargeback_transactions() -> Result<(), PaymentError> {
-        let csv = "type, client, tx, amount
-        deposit, 1, 1, 1.0
-        deposit, 2, 2, 2.0
-        deposit, 1, 3, 2.0
-        withdrawal, 1, 4, 1.5
-        withdrawal, 2, 5, 3.0
-        dispute, 1, 3
-        resolve, 1, 3
-        dispute, 2, 2
-        chargeback, 2, 2";
-        let str_buf = stringreader::StringReader::new(csv);
-        let transactions = parse_transactions(Box::new(str_buf)).await?;
-        let mut engine = PaymentEngine::new();
+    /// Emits the warnings about a processed transaction: why it was ignored, or the invariants
+    /// its client's account fails now that it was applied.
+    fn warn_about(&mut self, txn: &Transaction, source: Option<&SourceRef>, outcome: ProcessOutcome) {
+        match outcome {
+            ProcessOutcome::Ignored(reason) => {
+                let mut rejection = self.rejection(txn, reason).with_line(source.map(|source| source.line));
+                if let Some(file) = source.and_then(|source| source.file.as_deref()) {
+                    rejection = rejection.with_source(file);
+                }
+                self.warn(EngineWarning::Rejected(rejection));
+            }
+            ProcessOutcome::Applied => {
+                let violations = self
+                    .clients
+                    .get(&txn.client)
+                    .map(|account| invariants::check_client(txn.client, account))
+                    .unwrap_or_default();
+                for violation in violations {
+                    self.warn(EngineWarning::InvariantViolated(violation));
+                }
+            }
+        }
+    }
 
+    /// Processes transactions from the stream until `until` says to stop, returning the number
+    /// of transactions processed (applied or ignored).
+    ///
+    /// The stop decision is made on each transaction in stream order, before processing it. The
+    /// rest of the stream is left unconsumed, except for a `Until::StopBefore` transaction which
+    /// is taken from it but not processed. `AsOfTx::until` stops at a given tx id.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first parse error of the stream, or a `PaymentError::StorageError` if the
+    /// transaction store fails.
+    pub async fn process_until<I, P>(&mut self, transactions: I, mut until: P) -> Result<usize, PaymentError>
+    where
+        I: IntoIterator<Item = Result<Transaction, PaymentError>>,
+        P: FnMut(&Transaction) -> Until,
+    {
+        let mut processed = 0;
         for txn in transactions {
-            engine.process_transaction(txn?).await;
+            let txn = txn?;
+            let decision = until(&txn);
+            if decision == Until::StopBefore {
+                break;
+            }
+            self.process_transaction(txn).await?;
+            processed += 1;
+            if decision == Until::StopAfter {
+                break;
+            }
         }
+        Ok(processed)
+    }
 
-        if let Some(client) = engine.clients.get(&2) {
-            assert_eq!(client.total, 0.0);
-            assert_eq!(client.available, 0.0);
-            assert!(client.locked); // should be locked due to chargeback
-            assert_eq!(client.held, 0.0);
-        }
+    /// Processes a chunk of transactions and returns the outcome of each, in the chunk's order,
+    /// leaving the chunk empty for the next one.
+    ///
+    /// The rows of each client with an account run one after the other, the running totals
+    /// being brought up to date once per client rather than once per row. Each row still looks
+    /// up its client's account. Rows of clients without an account yet run last, in chunk
+    /// order, so accounts are opened in the same order as row by row. The outcomes and the
+    /// accounts are those of processing every row in order with `process_transaction`, errors
+    /// included, since rows of different clients don't affect each other. Chunks where they could
+    /// run row by row: a tx id used by two clients in the chunk, a row with an idempotency key,
+    /// an `accrue` row, or an engine with a journal, an undo history, or `EngineConfig::max_clients`,
+    /// `max_stored_transactions`, `max_open_disputes`, `duplicate_deposits`, `hold_ttl_secs` or
+    /// `max_panics` set.
+    /// Warnings come in the order the rows ran.
+    pub async fn process_chunk(
+        &mut self,
+        chunk: &mut Vec<Transaction>,
+    ) -> Vec<Result<ProcessOutcome, PaymentError>> {
+        let mut rows = chunk.drain(..).map(|txn| (txn, None)).collect();
+        self.process_chunk_with(&mut rows, |_, _, _| {}).await
+    }
 
-        Ok(())
+    /// Processes a chunk like `process_chunk`, each row with the source it was read from as
+    /// with `process_transaction_from`. Returns the outcome of each row, in the chunk's order,
+    /// with the record of its rejection if it was ignored.
+    pub async fn process_chunk_from(
+        &mut self,
+        chunk: &mut Vec<(Transaction, Option<SourceRef>)>,
+    ) -> Vec<Result<(ProcessOutcome, Option<RejectionRecord>), PaymentError>> {
+        let rejected: Vec<Transaction> = chunk.iter().map(|(txn, _)| txn.clone()).collect();
+        let mut records: Vec<Option<RejectionRecord>> = vec![None; chunk.len()];
+        let outcomes = self
+            .process_chunk_with(chunk, |engine, index, outcome| {
+                if let Ok(ProcessOutcome::Ignored(reason)) = outcome {
+                    records[index] = Some(engine.rejection(&rejected[index], *reason));
+                }
+            })
+            .await;
+        outcomes
+            .into_iter()
+            .zip(records)
+            .map(|(outcome, record)| outcome.map(|outcome| (outcome, record)))
+            .collect()
     }
 
-    #[tokio::test]
-    async fn can_process_disputed_transactions() -> Result<(), PaymentError> {
-        let csv = "type, client, tx, amount
-        deposit, 1, 1, 1.0
-        deposit, 2, 2, 2.0
-        deposit, 1, 3, 2.0
-        withdrawal, 1, 4, 1.5
-        withdrawal, 2, 5, 3.0
-        dispute, 2, 2";
+    /// Processes a chunk like `process_chunk_from`, calling `processed` with the index of every
+    /// row in the chunk and its outcome right after the row ran, while the engine can still tell
+    /// why it was ignored.
+    async fn process_chunk_with<F>(
+        &mut self,
+        chunk: &mut Vec<(Transaction, Option<SourceRef>)>,
+        mut processed: F,
+    ) -> Vec<Result<ProcessOutcome, PaymentError>>
+    where
+        F: FnMut(&Self, usize, &Result<ProcessOutcome, PaymentError>),
+    {
+        let groups = self.chunk_groups(chunk);
+        let mut rows: Vec<Option<(Transaction, Option<SourceRef>)>> = chunk.drain(..).map(Some).collect();
+        let mut outcomes: Vec<Option<Result<ProcessOutcome, PaymentError>>> = rows.iter().map(|_| None).collect();
+        let mut start = 0;
+        while start < groups.len() {
+            let client = groups[start].0;
+            let end = start + groups[start..].iter().take_while(|(group, _)| *group == client).count();
+            let before = client.and_then(|client| self.clients.get(&client).copied());
+            for &(_, index) in &groups[start..end] {
+                let Some((txn, source)) = rows[index].take() else {
+                    continue;
+                };
+                let outcome = match client {
+                    Some(_) => self.process_untotaled(txn, source).await,
+                    None => self.process_transaction_from(txn, source).await,
+                };
+                processed(self, index, &outcome);
+                outcomes[index] = Some(outcome);
+            }
+            if let Some(client) = client {
+                self.retotal(client, before);
+            }
+            start = end;
+        }
+        outcomes.into_iter().flatten().collect()
+    }
 
-        let str_buf = stringreader::StringReader::new(csv);
-        let transactions = parse_transactions(Box::new(str_buf)).await?;
-        let mut engine = PaymentEngine::new();
+    /// Returns the order the rows of a chunk run in, as row indices under the group they run
+    /// in: the rows of each client with an account, under the client's id, then the other rows,
+    /// under `None` as they are processed with their own totals update. A chunk that must run
+    /// row by row is a single group of the latter kind.
+    fn chunk_groups(&self, chunk: &[(Transaction, Option<SourceRef>)]) -> Vec<(Option<ClientId>, usize)> {
+        let row_by_row = || (0..chunk.len()).map(|index| (None, index)).collect();
+        // caps, histories and keys shared by all clients depend on the order across them
+        if chunk.len() < 2
+            || self.config.max_clients.is_some()
+            || self.config.max_stored_transactions.is_some()
+            || self.config.max_open_disputes.is_some()
+            || self.config.duplicate_deposits.is_some()
+            || self.config.hold_ttl_secs.is_some()
+            || self.config.max_panics.is_some()
+            || self.history.is_enabled()
+            || self.journal.is_some()
+        {
+            return row_by_row();
+        }
+        let mut owners: HashMap<u32, ClientId> = HashMap::with_capacity(chunk.len());
+        let mut rank_of: HashMap<ClientId, usize> = HashMap::new();
+        let mut ranked = Vec::with_capacity(chunk.len());
+        for (index, (txn, _)) in chunk.iter().enumerate() {
+            // an accrual pays every account, so it depends on the order across clients too
+            if txn.idempotency_key.is_some()
+                || txn.r#type == TransactionType::Accrue
+                || *owners.entry(txn.tx).or_insert(txn.client) != txn.client
+            {
+                return row_by_row();
+            }
+            let rank = match rank_of.get(&txn.client) {
+                Some(rank) => *rank,
+                None if self.clients.contains_key(&txn.client) => {
+                    rank_of.insert(txn.client, rank_of.len());
+                    rank_of.len() - 1
+                }
+                None => usize::MAX,
+            };
+            ranked.push((rank, index));
+        }
+        // a stable sort keeps each client's rows, and the new clients' rows, in chunk order
+        ranked.sort_by_key(|(rank, _)| *rank);
+        ranked
+            .into_iter()
+            .map(|(rank, index)| (Some(chunk[index].0.client).filter(|_| rank != usize::MAX), index))
+            .collect()
+    }
 
+    /// Processes a batch of parsed rows in order, such as the iterator of `parse_transactions`,
+    /// and returns what was applied, ignored or failed to parse. Rows given as a `ParsedRecord`,
+    /// or as a `BatchRow` with a source, are processed like with `process_transaction_from` and
+    /// their rejections carry their line and file.
+    ///
+    /// Rows that fail to parse are counted and skipped, unless `EngineConfig::fail_fast` is set
+    /// or the input went over its `parser::InputLimits`. The batch stops early at the first transaction store failure, and under
+    /// `EngineConfig::fail_on_ignore` at the first ignored transaction, with
+    /// `BatchResult::error` set. Rows after the failing one are left unprocessed.
+    pub async fn process_all<I>(&mut self, transactions: I) -> BatchResult
+    where
+        I: IntoIterator,
+        I::Item: Into<BatchRow>,
+    {
+        let mut batch = BatchResult::default();
         for txn in transactions {
-            engine.process_transaction(txn?).await;
+            if !self.process_batch_row(txn.into(), &mut batch).await {
+                break;
+            }
         }
+        batch
+    }
 
-        if let Some(client) = engine.clients.get(&2) {
-            assert_eq!(client.total, 2.0);
-            assert_eq!(client.available, 0.0); // available should be 0 due to dispute
-            assert!(!client.locked);
-            assert_eq!(client.held, 2.0);
+    /// Processes the rows received from a channel like `process_all`, until the channel is
+    /// closed or the batch stops early.
+    pub async fn process_stream<R: Into<BatchRow>>(&mut self, mut transactions: mpsc::Receiver<R>) -> BatchResult {
+        let mut batch = BatchResult::default();
+        while let Some(txn) = transactions.recv().await {
+            if !self.process_batch_row(txn.into(), &mut batch).await {
+                break;
+            }
         }
+        batch
+    }
 
-        Ok(())
+    /// Processes the rows received from a channel like `process_stream`, until `token` is
+    /// cancelled, for callers that need to abandon a batch.
+    ///
+    /// The token is checked between transactions, never during one, so the engine is left in
+    /// the state of processing exactly the rows counted in the result, which is then marked as
+    /// `cancelled`. Rows still in the channel stay there.
+    pub async fn process_stream_with_cancel<R: Into<BatchRow>>(
+        &mut self,
+        mut transactions: mpsc::Receiver<R>,
+        token: CancellationToken,
+    ) -> BatchResult {
+        let mut batch = BatchResult::default();
+        loop {
+            let txn = tokio::select! {
+                biased;
+                _ = token.cancelled() => {
+                    batch.cancelled = true;
+                    break;
+                }
+                txn = transactions.recv() => txn,
+            };
+            let Some(txn) = txn else {
+                break;
+            };
+            if !self.process_batch_row(txn.into(), &mut batch).await {
+                break;
+            }
+        }
+        batch
     }
 
-    #[tokio::test]
-    async fn can_process_resolved_transactions() -> Result<(), PaymentError> {
-        let csv = "type, client, tx, amount
-        deposit, 1, 1, 1.0
-        deposit, 2, 2, 2.0
-        deposit, 1, 3, 2.0
-        withdrawal, 1, 4, 1.5
-        withdrawal, 2, 5, 3.0
-        dispute, 2, 2,
-        resolve, 2, 2";
+    /// Processes the rows received from a channel like `process_stream_with_cancel`, cancelling
+    /// the batch once `timeout` has elapsed.
+    pub async fn process_stream_with_timeout<R: Into<BatchRow>>(
+        &mut self,
+        transactions: mpsc::Receiver<R>,
+        timeout: Duration,
+    ) -> BatchResult {
+        let token = CancellationToken::new();
+        let timer = tokio::spawn({
+            let token = token.clone();
+            async move {
+                tokio::time::sleep(timeout).await;
+                token.cancel();
+            }
+        });
+        let batch = self.process_stream_with_cancel(transactions, token).await;
+        timer.abort();
+        batch
+    }
 
-        let str_buf = stringreader::StringReader::new(csv);
-        let transactions = parse_transactions(Box::new(str_buf)).await?;
-        let mut engine = PaymentEngine::new();
+    /// Processes the rows received from a channel like `process_stream`, a chunk of up to
+    /// `chunk_size` rows at a time with `process_chunk`, for throughput. A chunk is made of the
+    /// rows already received, up to the next one that failed to parse, so rows are never held
+    /// back waiting for more.
+    ///
+    /// Under `EngineConfig::fail_on_ignore` rows are processed one at a time, so that the batch
+    /// stops right after the first ignored one. A transaction store failure stops the batch at
+    /// the end of its chunk: rows of the chunk after the failing one may have been processed,
+    /// and aren't counted.
+    pub async fn process_stream_chunked<R: Into<BatchRow>>(
+        &mut self,
+        mut transactions: mpsc::Receiver<R>,
+        chunk_size: usize,
+    ) -> BatchResult {
+        let chunk_size = if self.config.fail_on_ignore { 1 } else { chunk_size.max(1) };
+        let mut batch = BatchResult::default();
+        let mut received = Vec::with_capacity(chunk_size);
+        let mut chunk = Vec::with_capacity(chunk_size);
+        while transactions.recv_many(&mut received, chunk_size).await > 0 {
+            if !self.process_batch_rows(received.drain(..).map(Into::into), &mut chunk, &mut batch).await
+                || !self.process_batch_chunk(&mut chunk, &mut batch).await
+            {
+                break;
+            }
+        }
+        batch
+    }
 
-        for txn in transactions {
-            engine.process_transaction(txn?).await;
+    /// Processes a batch of rows like `process_all`, a chunk of up to `chunk_size` rows at a
+    /// time with `process_chunk_from`, like `process_stream_chunked` does for a stream. A chunk
+    /// ends early at a row that failed to parse.
+    pub async fn process_all_chunked<I>(&mut self, transactions: I, chunk_size: usize) -> BatchResult
+    where
+        I: IntoIterator,
+        I::Item: Into<BatchRow>,
+    {
+        let chunk_size = if self.config.fail_on_ignore { 1 } else { chunk_size.max(1) };
+        let mut batch = BatchResult::default();
+        let mut transactions = transactions.into_iter().map(Into::into).peekable();
+        let mut chunk = Vec::with_capacity(chunk_size);
+        while transactions.peek().is_some() {
+            if !self.process_batch_rows(transactions.by_ref().take(chunk_size), &mut chunk, &mut batch).await
+                || !self.process_batch_chunk(&mut chunk, &mut batch).await
+            {
+                break;
+            }
         }
+        batch
+    }
 
-        if let Some(client) = engine.clients.get(&2) {
-            assert_eq!(client.total, 2.0);
-            assert_eq!(client.available, 2.0);
-            assert!(!client.locked);
-            assert_eq!(client.held, 0.0); // held should be 0 as dispute is resolved
+    /// Adds the parsed rows of `rows` to the chunk of a batch, processing the chunk and then
+    /// the row on every row that failed to parse. Returns whether the batch goes on.
+    async fn process_batch_rows<I>(
+        &mut self,
+        rows: I,
+        chunk: &mut Vec<(Transaction, Option<SourceRef>)>,
+        batch: &mut BatchResult,
+    ) -> bool
+    where
+        I: Iterator<Item = BatchRow>,
+    {
+        for row in rows {
+            let goes_on = match row.transaction {
+                Ok(txn) => {
+                    chunk.push((txn, row.source));
+                    true
+                }
+                Err(err) => {
+                    self.process_batch_chunk(chunk, batch).await
+                        && self.process_batch_row(BatchRow::from(Err(err)), batch).await
+                }
+            };
+            if !goes_on {
+                return false;
+            }
         }
+        true
+    }
+
+    /// Processes the next chunk of a batch and records the outcomes of its rows in order,
+    /// returning whether the batch goes on.
+    async fn process_batch_chunk(
+        &mut self,
+        chunk: &mut Vec<(Transaction, Option<SourceRef>)>,
+        batch: &mut BatchResult,
+    ) -> bool {
+        let first_row = batch.rows() + 1;
+        let sources: Vec<Option<SourceRef>> = chunk.iter().map(|(_, source)| source.clone()).collect();
+        let outcomes = self.process_chunk_from(chunk).await;
+        for (index, (outcome, source)) in outcomes.into_iter().zip(sources).enumerate() {
+            // a record was made for every ignored row
+            let outcome = outcome.map(|(_, record)| record.map(|record| located(record, source.as_ref())));
+            if !self.record_batch_outcome(batch, first_row + index, outcome) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Records the outcome of the transaction at `row` of a batch, given as the record of its
+    /// rejection if it was ignored, returning whether the batch goes on. Under
+    /// `EngineConfig::fail_on_ignore` the error names the line of the row when it is known.
+    fn record_batch_outcome(
+        &self,
+        batch: &mut BatchResult,
+        row: usize,
+        outcome: Result<Option<RejectionRecord>, PaymentError>,
+    ) -> bool {
+        match outcome {
+            Ok(None) => batch.applied += 1,
+            Ok(Some(record)) => {
+                batch.ignored += 1;
+                if self.config.fail_on_ignore {
+                    batch.error = Some(match record.line {
+                        Some(_) => record.to_error(format!("line {}", record.location())),
+                        None => record.to_error(format!("row {}", row)),
+                    });
+                }
+                batch.rejections.push(Rejection { row, record });
+            }
+            Err(err) => batch.error = Some(err),
+        }
+        batch.error.is_none()
+    }
+
+    /// Processes the next row of a batch and records its outcome, returning whether the batch
+    /// goes on.
+    async fn process_batch_row(&mut self, row: BatchRow, batch: &mut BatchResult) -> bool {
+        let index = batch.rows() + 1;
+        let txn = match row.transaction {
+            Ok(txn) => txn,
+            Err(err) => {
+                batch.errors += 1;
+                if self.config.fail_fast || matches!(err, PaymentError::InputLimitExceeded { .. }) {
+                    batch.error = Some(err);
+                }
+                return batch.error.is_none();
+            }
+        };
+        let rejected = txn.clone();
+        let outcome = match self.process_transaction_from(txn, row.source.clone()).await {
+            Ok(ProcessOutcome::Applied) => Ok(None),
+            Ok(ProcessOutcome::Ignored(reason)) => {
+                Ok(Some(located(self.rejection(&rejected, reason), row.source.as_ref())))
+            }
+            Err(err) => Err(err),
+        };
+        self.record_batch_outcome(batch, index, outcome)
+    }
 
+    /// Reverts the most recently applied transaction still in the undo history and returns it,
+    /// or `None` once the history is exhausted (or was never enabled).
+    ///
+    /// Transactions are undone in reverse order, so a dispute, resolve or chargeback is always
+    /// reverted before the deposit it refers to: undoing a disputed deposit means undoing its
+    /// dispute first, never leaving a dispute that points to a missing transaction. Undoing a
+    /// chargeback unlocks the account and puts the funds back on hold.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::StorageError` if the transaction store fails.
+    pub async fn undo_last(&mut self) -> Result<Option<Transaction>, PaymentError> {
+        let Some(entry) = self.history.pop() else {
+            return Ok(None);
+        };
+        // the merchant of a dispute or chargeback is on the deposit, still stored at this point
+        let merchant_txn = match entry.txn.r#type {
+            TransactionType::Deposit => Some(entry.txn.clone()),
+            TransactionType::Dispute | TransactionType::Chargeback | TransactionType::Representment => {
+                self.stored(entry.txn.tx).await?
+            }
+            _ => None,
+        };
+        if let Some(txn) = merchant_txn {
+            match entry.txn.r#type {
+                TransactionType::Representment => self.record_merchant(&txn, TransactionType::Chargeback, false),
+                r#type => self.record_merchant(&txn, r#type, true),
+            }
+        }
+        if entry.txn.r#type == TransactionType::Deposit
+            && self.suspected_duplicates.last().is_some_and(|dup| dup.tx == entry.txn.tx)
+        {
+            self.suspected_duplicates.pop();
+        }
+        let txn = entry.txn.clone();
+        self.revert(entry).await?;
+        Ok(Some(txn))
+    }
+
+    /// Puts back the accounts, stored transactions, disputes, escrow holds, pending withdrawals,
+    /// chargebacks and their reasons, resolutions and shortfalls an undo entry kept.
+    async fn revert(&mut self, entry: UndoEntry) -> Result<(), PaymentError> {
+        let before = self.clients.get(&entry.txn.client).copied();
+        match entry.client {
+            Some(client) => self.clients.insert(entry.txn.client, client),
+            None => {
+                self.first_seen.retain(|id| *id != entry.txn.client);
+                self.clients.remove(&entry.txn.client)
+            }
+        };
+        self.retotal(entry.txn.client, before);
+        self.write_client(entry.txn.client, before).await?;
+        self.flows = entry.flows;
+        self.dust = entry.dust;
+        // journaled keys stay, like the journaled ids
+        if let Some(key) = &entry.txn.idempotency_key {
+            self.recent_keys.remove(key);
+        }
+        match entry.stored {
+            Some(Some(previous)) => self.transactions.insert(previous).await?,
+            Some(None) => self.unstore(entry.txn.tx).await?,
+            None => {}
+        }
+        match entry.disputed {
+            Some(Some(previous)) => self.disputed_transactions.insert(entry.txn.tx, previous),
+            Some(None) => self.disputed_transactions.remove(&entry.txn.tx),
+            None => None,
+        };
+        self.track_open_disputes();
+        match entry.escrowed {
+            Some(Some(previous)) => self.escrow_holds.insert(entry.txn.tx, previous),
+            Some(None) => self.escrow_holds.remove(&entry.txn.tx),
+            None => None,
+        };
+        match entry.pending {
+            Some(Some(previous)) => self.pending_withdrawals.insert(entry.txn.tx, previous),
+            Some(None) => self.pending_withdrawals.remove(&entry.txn.tx),
+            None => None,
+        };
+        match entry.charged_back {
+            Some(Some(previous)) => self.charged_back_transactions.insert(entry.txn.tx, previous),
+            Some(None) => self.charged_back_transactions.remove(&entry.txn.tx),
+            None => None,
+        };
+        match entry.resolved {
+            Some(true) => self.resolved_transactions.insert(entry.txn.tx),
+            Some(false) => self.resolved_transactions.remove(&entry.txn.tx),
+            None => false,
+        };
+        for (tx, shortfall) in entry.shortfalls {
+            match shortfall {
+                Some(shortfall) => self.dispute_shortfalls.insert(tx, shortfall),
+                None => self.dispute_shortfalls.remove(&tx),
+            };
+        }
+        match entry.written_off {
+            Some(Some(previous)) => self.chargeback_write_offs.insert(entry.txn.tx, previous),
+            Some(None) => self.chargeback_write_offs.remove(&entry.txn.tx),
+            None => None,
+        };
+        match entry.chargeback_reason {
+            Some(Some(previous)) => self.chargeback_reasons.insert(entry.txn.tx, previous),
+            Some(None) => self.chargeback_reasons.remove(&entry.txn.tx),
+            None => None,
+        };
+        Ok(())
+    }
+
+    /// Captures the state a transaction may change, before it is applied.
+    async fn undo_entry(&mut self, txn: &Transaction) -> Result<UndoEntry, PaymentError> {
+        let stored = match txn.r#type {
+            TransactionType::Deposit
+            | TransactionType::Withdrawal
+            | TransactionType::Capture
+            | TransactionType::WithdrawalSettle => Some(self.stored(txn.tx).await?),
+            _ => None,
+        };
+        let disputed = match txn.r#type {
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
+                Some(self.disputed_transactions.get(&txn.tx).cloned())
+            }
+            _ => None,
+        };
+        let escrowed = match txn.r#type {
+            TransactionType::Hold | TransactionType::Release | TransactionType::Capture => {
+                Some(self.escrow_holds.get(&txn.tx).cloned())
+            }
+            _ => None,
+        };
+        let pending = match txn.r#type {
+            TransactionType::WithdrawalPending
+            | TransactionType::WithdrawalSettle
+            | TransactionType::WithdrawalCancel => Some(self.pending_withdrawals.get(&txn.tx).cloned()),
+            _ => None,
+        };
+        let charged_back = match txn.r#type {
+            TransactionType::Chargeback | TransactionType::Representment => {
+                Some(self.charged_back_transactions.get(&txn.tx).cloned())
+            }
+            _ => None,
+        };
+        let resolved = match txn.r#type {
+            TransactionType::Resolve | TransactionType::Representment => {
+                Some(self.resolved_transactions.contains(&txn.tx))
+            }
+            _ => None,
+        };
+        let written_off = match txn.r#type {
+            TransactionType::Chargeback | TransactionType::Representment => {
+                Some(self.chargeback_write_offs.get(&txn.tx).copied())
+            }
+            _ => None,
+        };
+        let chargeback_reason = match txn.r#type {
+            TransactionType::Chargeback | TransactionType::Representment => {
+                Some(self.chargeback_reasons.get(&txn.tx).cloned())
+            }
+            _ => None,
+        };
+        let shortfalls = match txn.r#type {
+            TransactionType::Dispute
+            | TransactionType::Resolve
+            | TransactionType::Chargeback
+            | TransactionType::Representment => {
+                vec![(txn.tx, self.dispute_shortfalls.get(&txn.tx).copied())]
+            }
+            // a deposit to a frozen account collects the shortfalls of the client's disputes
+            TransactionType::Deposit if self.clients.get(&txn.client).is_some_and(|client| client.frozen) => self
+                .open_shortfalls(txn.client)
+                .into_iter()
+                .map(|(tx, shortfall)| (tx, Some(shortfall)))
+                .collect(),
+            _ => Vec::new(),
+        };
+        Ok(UndoEntry {
+            txn: txn.clone(),
+            client: self.clients.get(&txn.client).copied(),
+            stored,
+            disputed,
+            escrowed,
+            pending,
+            charged_back,
+            resolved,
+            shortfalls,
+            written_off,
+            chargeback_reason,
+            dust: self.dust,
+            flows: self.flows,
+        })
+    }
+
+    /// Returns the shortfalls of the client's open disputes, ordered by tx id.
+    fn open_shortfalls(&self, client_id: ClientId) -> Vec<(u32, Amount)> {
+        let mut shortfalls: Vec<(u32, Amount)> = self
+            .dispute_shortfalls
+            .iter()
+            .filter(|(tx, _)| {
+                self.disputed_transactions
+                    .get(tx)
+                    .is_some_and(|disputed| disputed.client == client_id)
+            })
+            .map(|(tx, shortfall)| (*tx, *shortfall))
+            .collect();
+        shortfalls.sort_by_key(|(tx, _)| *tx);
+        shortfalls
+    }
+
+    /// Returns the amount of a transaction the engine refers back to, rounded like amounts
+    /// entering the engine.
+    fn amount_of(&self, txn: &Transaction) -> Result<Option<Amount>, PaymentError> {
+        txn.amount
+            .map(|amount| Amount::from_f64(amount, self.config.rounding))
+            .transpose()
+    }
+
+    /// Applies `EngineConfig::dispute_amounts` to the amount of a dispute, resolve or chargeback
+    /// row, `held` being the disputed transaction's amount, or what its dispute holds. Returns
+    /// the amount the row disputes or settles, or `AmountMismatch` if the row's amount disagrees.
+    fn row_amount(
+        &mut self,
+        txn: &Transaction,
+        held: Option<Amount>,
+    ) -> Result<Result<Option<Amount>, IgnoreReason>, PaymentError> {
+        if self.config.dispute_amounts == DisputeAmountPolicy::Ignore { // whatever the column holds
+            return Ok(Ok(held));
+        }
+        let (Some(given), Some(held)) = (self.amount_of(txn)?, held) else {
+            return Ok(Ok(held));
+        };
+        let partial = self.config.dispute_amounts == DisputeAmountPolicy::UseAsPartial
+            && txn.r#type == TransactionType::Dispute;
+        let agrees = match partial {
+            true => given.is_positive() && given <= held,
+            _ => given == held,
+        };
+        if agrees {
+            return Ok(Ok(Some(given)));
+        }
+        let expected = match partial {
+            true => format!("at most {:.4}", held),
+            false => format!("{:.4}", held),
+        };
+        self.amount_mismatch = Some((txn.tx, expected));
+        Ok(Err(IgnoreReason::AmountMismatch))
+    }
+
+    /// Returns the amount the open dispute of `tx` holds, shortfall included.
+    fn disputed_amount(&self, tx: u32) -> Result<Option<Amount>, PaymentError> {
+        match self.disputed_transactions.get(&tx) {
+            Some(disputed) => self.amount_of(disputed),
+            None => Ok(None),
+        }
+    }
+
+    async fn apply_transaction(
+        &mut self,
+        mut txn: Transaction,
+        line: Option<u64>,
+    ) -> Result<ProcessOutcome, PaymentError> {
+        if let Some(memo) = txn.memo.as_mut() {
+            self.config.cap_memo(memo);
+        }
+        // an accrual is for every client, not for client 0
+        if txn.r#type != TransactionType::Accrue && !self.may_transact(txn.client) {
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::UnknownClient));
+        }
+        if self.config.dispute_support == DisputeSupport::None
+            && matches!(
+                txn.r#type,
+                TransactionType::Dispute
+                    | TransactionType::Resolve
+                    | TransactionType::Chargeback
+                    | TransactionType::Representment
+            )
+        {
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::DisputesDisabled));
+        }
+        match txn.r#type {
+            TransactionType::Deposit => self.process_deposit(txn).await,
+            TransactionType::Withdrawal => self.process_withdrawal(txn).await,
+            TransactionType::Dispute => self.process_dispute(txn).await,
+            TransactionType::Resolve => self.process_resolve(txn).await,
+            TransactionType::Chargeback => self.process_chargeback(txn, line).await,
+            TransactionType::Hold => self.process_hold(txn),
+            TransactionType::Release => self.process_release(txn),
+            TransactionType::Capture => self.process_capture(txn).await,
+            TransactionType::Representment => self.process_representment(txn),
+            TransactionType::WithdrawalPending => self.process_withdrawal_pending(txn),
+            TransactionType::WithdrawalSettle => self.process_withdrawal_settle(txn).await,
+            TransactionType::WithdrawalCancel => self.process_withdrawal_cancel(txn),
+            TransactionType::Accrue => self.process_accrual(txn).await,
+        }
+    }
+
+    /// Returns whether a client may transact under `EngineConfig::client_creation`.
+    fn may_transact(&self, client: ClientId) -> bool {
+        match self.config.client_creation {
+            ClientCreationPolicy::Auto => true,
+            ClientCreationPolicy::FromInitialStateOnly => {
+                self.clients.contains_key(&client) || self.config.onboarded_clients.contains(&client)
+            }
+        }
+    }
+
+    async fn process_deposit(&mut self, mut txn: Transaction) -> Result<ProcessOutcome, PaymentError> {
+        if self.config.blocked_clients.contains_key(&txn.client) { // not even an empty account
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::Blocklisted));
+        }
+        if self.is_above_limit(txn.amount) { // nor for a feed error
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::AmountAboveLimit));
+        }
+        let dust = self.is_dust(txn.amount);
+        if dust && self.config.dust == DustPolicy::Reject { // nor for dust
+            self.dust += 1;
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::DustAmount));
+        }
+        if let Some(limit) = self.config.max_clients {
+            if self.clients.len() >= limit && !self.clients.contains_key(&txn.client) {
+                return Err(PaymentError::LimitExceeded {
+                    which: Limit::Clients,
+                    limit,
+                });
+            }
+        }
+        let existing = self.clients.get(&txn.client).copied();
+        let shortfalls = match existing {
+            Some(client) if client.frozen => self.open_shortfalls(txn.client),
+            _ => Vec::new(),
+        };
+        // a new client's account is only opened once the deposit is applied
+        let client = existing.unwrap_or_default();
+
+        if client.locked && self.config.locked_deposits == LockedDepositPolicy::Reject { // don't process if account is locked
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::AccountLocked));
+        }
+        let Some(amount) = txn.amount else {
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::MissingAmount));
+        };
+        let amount = Amount::from_f64(amount, self.config.rounding)?;
+        let mut account = client; // changed on the side, so an overflow leaves the client as it was
+        account.total = account.total.checked_add(amount)?;
+        if let Some(cap) = self.config.balance_cap(txn.client) {
+            if account.total > Amount::from_f64(cap, self.config.rounding)? { // a deposit exactly to the cap is fine
+                return Ok(ProcessOutcome::Ignored(IgnoreReason::BalanceCapExceeded));
+            }
+        }
+        let mut credit = amount;
+        let mut collected_shortfalls = Vec::with_capacity(shortfalls.len());
+        for (tx, shortfall) in shortfalls { // a frozen account's deposits collect the shortfalls first
+            let collected = credit.min(shortfall);
+            collected_shortfalls.push((tx, shortfall.checked_sub(collected)?));
+            credit = credit.checked_sub(collected)?;
+        }
+        if account.frozen {
+            let collected = amount.checked_sub(credit)?;
+            account.held = account.held.checked_add(collected)?;
+            account.open_dispute_held = account.open_dispute_held.checked_add(collected)?;
+            account.release_shortfall(collected)?;
+        }
+        if account.locked { // pay down the debt, keep the rest on hold until the account is unlocked
+            let repayment = credit.min(account.debt);
+            let kept = credit.checked_sub(repayment)?;
+            account.available = account.available.checked_add(repayment)?;
+            account.held = account.held.checked_add(kept)?;
+            account.held_while_locked = account.held_while_locked.checked_add(kept)?;
+        } else {
+            account.available = account.available.checked_add(credit)?;
+        }
+        let repaid = account.settle_debt();
+        account.debt_repaid = account.debt_repaid.checked_add(repaid)?;
+        #[cfg(any(test, feature = "testing"))]
+        {
+            account.available = account.available.checked_add(self.deposit_skew)?;
+            account.total = account.total.checked_add(self.deposit_skew)?;
+        }
+        let mut duplicate = None;
+        if let (Some(detection), Some(timestamp)) = (self.config.duplicate_deposits, txn.timestamp) {
+            let duplicated = client.last_deposit.filter(|last| {
+                last.amount == amount && timestamp.abs_diff(last.timestamp) <= detection.window_secs
+            });
+            if let Some(last) = duplicated {
+                if detection.action == DuplicateAction::Reject {
+                    return Ok(ProcessOutcome::Ignored(IgnoreReason::SuspectedDuplicate));
+                }
+                duplicate = Some(SuspectedDuplicate {
+                    client: txn.client,
+                    original_tx: last.tx,
+                    tx: txn.tx,
+                    amount,
+                    delta_secs: timestamp.abs_diff(last.timestamp),
+                });
+            }
+            account.last_deposit = Some(LastDeposit {
+                tx: txn.tx,
+                amount,
+                timestamp,
+            });
+        }
+        if self.stores(&txn) { // only a deposit about to be stored can find the store full, unstored dust fits
+            self.check_store_limit()?;
+        }
+        txn.merchant = txn.merchant.map(|merchant| self.merchants.shared(merchant));
+        // stored first, so a failing store leaves the engine as it was
+        let (id, merchant) = (txn.client, txn.merchant.clone().zip(txn.amount));
+        self.retain(txn, dust).await?;
+        if existing.is_none() {
+            self.first_seen.push(id);
+        }
+        self.clients.insert(id, account);
+        self.flows.deposits += invariants::units(amount);
+        self.suspected_duplicates.extend(duplicate);
+        self.dispute_shortfalls.extend(collected_shortfalls);
+        if let Some((merchant, amount)) = merchant {
+            self.merchants.record(&merchant, TransactionType::Deposit, amount, false);
+        }
+        Ok(ProcessOutcome::Applied)
+    }
+
+    /// Stores an applied deposit or withdrawal so it can be disputed later, unless dispute
+    /// support is off or it is dust not to be stored. `dust` tells whether it is below
+    /// `EngineConfig::min_amount`.
+    async fn retain(&mut self, txn: Transaction, dust: bool) -> Result<(), PaymentError> {
+        match self.config.dispute_support {
+            DisputeSupport::Full if dust && self.config.dust == DustPolicy::ApplyButDontStore => {}
+            DisputeSupport::Full => self.transactions.insert(txn).await?,
+            DisputeSupport::None => self.unretained += 1,
+        }
+        if dust {
+            self.dust += 1;
+        }
+        Ok(())
+    }
+
+    /// Returns whether the amount of a deposit or withdrawal is below `EngineConfig::min_amount`,
+    /// once rounded like the balances.
+    fn is_dust(&self, amount: Option<f64>) -> bool {
+        let (Some(min), Some(amount)) = (self.config.min_amount, amount) else {
+            return false;
+        };
+        match (
+            Amount::from_f64(amount, self.config.rounding),
+            Amount::from_f64(min, self.config.rounding),
+        ) {
+            (Ok(amount), Ok(min)) => amount < min,
+            _ => false, // processing fails on the amount itself
+        }
+    }
+
+    /// Returns whether the amount of a deposit or withdrawal is above
+    /// `EngineConfig::max_transaction_amount`, once rounded like the balances.
+    fn is_above_limit(&self, amount: Option<f64>) -> bool {
+        let (Some(max), Some(amount)) = (self.config.max_transaction_amount, amount) else {
+            return false;
+        };
+        match (
+            Amount::from_f64(amount, self.config.rounding),
+            Amount::from_f64(max, self.config.rounding),
+        ) {
+            (Ok(amount), Ok(max)) => amount > max,
+            _ => amount > max, // an amount out of range is above any limit in range
+        }
+    }
+
+    /// Returns whether a deposit or withdrawal is stored once applied.
+    fn stores(&self, txn: &Transaction) -> bool {
+        self.config.dispute_support == DisputeSupport::Full
+            && !(self.config.dust == DustPolicy::ApplyButDontStore && self.is_dust(txn.amount))
+    }
+
+    /// Returns the number of deposits and withdrawals below `EngineConfig::min_amount`, rejected
+    /// or applied per `EngineConfig::dust`.
+    pub fn dust_transactions(&self) -> u64 {
+        self.dust
+    }
+
+    /// Returns the number of deposits and withdrawals applied without being stored, under
+    /// `DisputeSupport::None`.
+    pub fn unretained_transactions(&self) -> u64 {
+        self.unretained
+    }
+
+    /// Fails if the store already holds `EngineConfig::max_stored_transactions` transactions.
+    /// Checked before anything is changed, so the failing transaction leaves no trace.
+    fn check_store_limit(&self) -> Result<(), PaymentError> {
+        match self.config.max_stored_transactions {
+            Some(limit) if self.transactions.len() >= limit => Err(PaymentError::LimitExceeded {
+                which: Limit::StoredTransactions,
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts a deposit, or a dispute or chargeback of the deposit, in the statistics of the
+    /// deposit's merchant, if it names one.
+    fn record_merchant(&mut self, deposit: &Transaction, r#type: TransactionType, revert: bool) {
+        if let (TransactionType::Deposit, Some(merchant)) = (deposit.r#type, &deposit.merchant) {
+            let amount = deposit.amount.unwrap_or_default();
+            self.merchants.record(merchant, r#type, amount, revert);
+        }
+    }
+
+    async fn process_withdrawal(&mut self, txn: Transaction) -> Result<ProcessOutcome, PaymentError> {
+        if self.config.blocked_clients.contains_key(&txn.client) {
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::Blocklisted));
+        }
+        if self.is_above_limit(txn.amount) {
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::AmountAboveLimit));
+        }
+        let dust = self.is_dust(txn.amount);
+        let Some(client) = self.clients.get_mut(&txn.client) else {
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::UnknownClient));
+        };
+        if client.locked { // don't process if account is locked
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::AccountLocked));
+        }
+        if client.frozen {
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::AccountFrozen));
+        }
+        let Some(amount) = txn.amount else {
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::MissingAmount));
+        };
+        let amount = Amount::from_f64(amount, self.config.rounding)?;
+        if dust && self.config.dust == DustPolicy::Reject {
+            self.dust += 1;
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::DustAmount));
+        }
+        if client.available < amount {
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::InsufficientFunds));
+        }
+        let available = client.available.checked_sub(amount)?;
+        let total = client.total.checked_sub(amount)?;
+        if self.stores(&txn) { // a withdrawal ignored for lack of funds doesn't stop the run
+            self.check_store_limit()?;
+        }
+        let id = txn.client;
+        self.retain(txn, dust).await?; // stored first, so a failing store leaves the account as it was
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.total = total;
+            client.available = available;
+        }
+        self.flows.withdrawals += invariants::units(amount);
+        Ok(ProcessOutcome::Applied)
+    }
+
+    /// Looks up the transaction referenced by a dispute, resolve or chargeback and checks that
+    /// both refer to the same client.
+    async fn referenced_transaction(
+        &mut self,
+        txn: &Transaction,
+    ) -> Result<Result<Transaction, IgnoreReason>, PaymentError> {
+        let Some(original_txn) = self.stored(txn.tx).await? else {
+            return Ok(Err(IgnoreReason::UnknownTransaction));
+        };
+        if original_txn.client != txn.client { // both transaction should refer to same client
+            return Ok(Err(IgnoreReason::ClientMismatch));
+        }
+        Ok(Ok(original_txn))
+    }
+
+    async fn process_dispute(&mut self, txn: Transaction) -> Result<ProcessOutcome, PaymentError> {
+        if self.disputed_transactions.contains_key(&txn.tx) { // funds are already held for this one
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::AlreadyDisputed));
+        }
+        let original_txn = match self.referenced_transaction(&txn).await? {
+            Ok(original_txn) => original_txn,
+            Err(reason) => return Ok(ProcessOutcome::Ignored(reason)),
+        };
+        let client_open = self
+            .clients
+            .get(&original_txn.client)
+            .map_or(0, |client| client.open_disputes as usize);
+        if self.config.max_open_disputes_per_client.is_some_and(|cap| client_open >= cap)
+            || self
+                .config
+                .max_open_disputes
+                .is_some_and(|cap| self.disputed_transactions.len() >= cap)
+        {
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::TooManyOpenDisputes));
+        }
+        let full = self.amount_of(&original_txn)?;
+        let amount = match self.row_amount(&txn, full)? {
+            Ok(amount) => amount,
+            Err(reason) => return Ok(ProcessOutcome::Ignored(reason)),
+        };
+        if let Some(client) = self.clients.get_mut(&original_txn.client) {
+            let mut account = *client;
+            if let Some(amount) = amount {
+                let shortfall = match self.config.dispute_shortfall {
+                    DisputeShortfallPolicy::Allow => Amount::ZERO,
+                    _ => amount.checked_sub(account.available.max(Amount::ZERO))?.max(Amount::ZERO),
+                };
+                let hold = amount.checked_sub(shortfall)?;
+                account.available = account.available.checked_sub(hold)?;
+                account.held = account.held.checked_add(hold)?;
+                account.open_dispute_held = account.open_dispute_held.checked_add(hold)?;
+                account.settle_debt();
+                if shortfall.is_positive() {
+                    account.dispute_shortfall = account.dispute_shortfall.checked_add(shortfall)?;
+                    let freezes = self.config.dispute_shortfall == DisputeShortfallPolicy::Freeze;
+                    if freezes && !account.frozen && !account.locked {
+                        let reason = format!("shortfall of dispute tx {}", txn.tx);
+                        account.status_reason = Some(StatusReason::new(&reason));
+                    }
+                    account.frozen |= freezes;
+                    self.dispute_shortfalls.insert(txn.tx, shortfall);
+                }
+            }
+            account.open_disputes += 1;
+            account.disputes += 1;
+            *client = account;
+        }
+        self.record_merchant(&original_txn, TransactionType::Dispute, false);
+        let disputed = Transaction {
+            reason: txn.reason,
+            // the dispute's own time, for how long it has been open
+            timestamp: txn.timestamp,
+            // a partial dispute holds, and later settles, only its own amount
+            amount: match amount == full {
+                true => original_txn.amount,
+                false => txn.amount,
+            },
+            ..original_txn
+        };
+        self.disputed_transactions.insert(txn.tx, disputed);
+        self.track_open_disputes();
+        Ok(ProcessOutcome::Applied)
+    }
+
+    /// Warns with `EngineWarning::OpenDisputesNearCap` when the open disputes reach nine tenths
+    /// of `EngineConfig::max_open_disputes`, or the cap itself, having been below it when last
+    /// counted. Called whenever disputes open or settle, undone ones included, so a threshold
+    /// crossed by several disputes at once still warns, and warns again once crossed anew.
+    fn track_open_disputes(&mut self) {
+        let Some(cap) = self.config.max_open_disputes else {
+            return;
+        };
+        let open = self.disputed_transactions.len();
+        let reached = [cap - cap / 10, cap]
+            .into_iter()
+            .filter(|&threshold| threshold > 0 && open >= threshold)
+            .count();
+        if reached > self.open_disputes_reached {
+            self.warn(EngineWarning::OpenDisputesNearCap { open, cap });
+        }
+        self.open_disputes_reached = reached;
+    }
+
+    /// Returns what a resolve or chargeback of the dispute `tx` takes from the client's held
+    /// balance, given the `disputed` amount it holds, or the reason to reject it if the balance
+    /// is less. A short balance is reported as an invariant violation, and only released whole
+    /// under `EngineConfig::clamp_inconsistent_held`.
+    fn held_release(
+        &mut self,
+        client: ClientId,
+        tx: u32,
+        disputed: Option<Amount>,
+    ) -> Result<Option<Amount>, IgnoreReason> {
+        let Some(disputed) = disputed else {
+            return Ok(None);
+        };
+        let held = self.clients.get(&client).map_or(disputed, |account| account.held);
+        if held >= disputed {
+            return Ok(Some(disputed));
+        }
+        self.warn(EngineWarning::InvariantViolated(InvariantViolation {
+            client,
+            kind: ViolationKind::HeldBelowDispute { tx, held, disputed },
+        }));
+        if !self.config.clamp_inconsistent_held {
+            return Err(IgnoreReason::HeldBalanceInconsistent);
+        }
+        Ok(Some(held.max(Amount::ZERO)))
+    }
+
+    async fn process_resolve(&mut self, txn: Transaction) -> Result<ProcessOutcome, PaymentError> {
+        if !self.disputed_transactions.contains_key(&txn.tx) { // resolve only if disputed transaction reference is present
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::NotDisputed));
+        }
+        let original_txn = match self.referenced_transaction(&txn).await? {
+            Ok(original_txn) => original_txn,
+            Err(reason) => return Ok(ProcessOutcome::Ignored(reason)),
+        };
+        let held = self.disputed_amount(txn.tx)?;
+        let amount = match self.row_amount(&txn, held)? {
+            Ok(amount) => amount,
+            Err(reason) => return Ok(ProcessOutcome::Ignored(reason)),
+        };
+        let shortfall = self.dispute_shortfalls.get(&txn.tx).copied().unwrap_or_default(); // never collected, so never owed
+        let disputed = amount.map(|amount| amount.checked_sub(shortfall)).transpose()?;
+        let release = match self.held_release(original_txn.client, txn.tx, disputed) {
+            Ok(release) => release,
+            Err(reason) => return Ok(ProcessOutcome::Ignored(reason)),
+        };
+        if let Some(client) = self.clients.get_mut(&original_txn.client) {
+            let mut account = *client;
+            if let (Some(disputed), Some(release)) = (disputed, release) {
+                account.available = account.available.checked_add(release)?;
+                account.held = account.held.checked_sub(release)?;
+                account.open_dispute_held = account.open_dispute_held.checked_sub(disputed)?;
+                account.settle_debt(); // the disputed funds are back, not a repayment
+                account.release_shortfall(shortfall)?;
+            }
+            account.open_disputes -= 1;
+            *client = account;
+        }
+        self.dispute_shortfalls.remove(&txn.tx);
+        self.disputed_transactions.remove(&txn.tx); // the dispute is settled
+        self.track_open_disputes();
+        self.resolved_transactions.insert(txn.tx);
+        Ok(ProcessOutcome::Applied)
+    }
+
+    async fn process_chargeback(
+        &mut self,
+        txn: Transaction,
+        line: Option<u64>,
+    ) -> Result<ProcessOutcome, PaymentError> {
+        if !self.disputed_transactions.contains_key(&txn.tx) { // chargeback only if disputed transaction reference is present
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::NotDisputed));
+        }
+        let original_txn = match self.referenced_transaction(&txn).await? {
+            Ok(original_txn) => original_txn,
+            Err(reason) => return Ok(ProcessOutcome::Ignored(reason)),
+        };
+        let policy = self.config.dispute_shortfall;
+        let held = self.disputed_amount(txn.tx)?;
+        let amount = match self.row_amount(&txn, held)? {
+            Ok(amount) => amount,
+            Err(reason) => return Ok(ProcessOutcome::Ignored(reason)),
+        };
+        let fee = self
+            .config
+            .chargeback_fee
+            .map(|fee| Amount::from_f64(fee, self.config.rounding))
+            .transpose()?;
+        let shortfall = self.dispute_shortfalls.get(&txn.tx).copied().unwrap_or_default();
+        let disputed = amount.map(|amount| amount.checked_sub(shortfall)).transpose()?;
+        let release = match self.held_release(original_txn.client, txn.tx, disputed) {
+            Ok(release) => release,
+            Err(reason) => return Ok(ProcessOutcome::Ignored(reason)),
+        };
+        let dispute_reason = self.disputed_transactions.get(&txn.tx).and_then(|dispute| dispute.reason.as_ref());
+        let lock_reason = match txn.reason.as_ref().or(dispute_reason) {
+            Some(reason) => format!("chargeback of tx {}: {}", txn.tx, reason),
+            None => format!("chargeback of tx {}", txn.tx),
+        };
+        let mut written_off = None;
+        if let Some(client) = self.clients.get_mut(&original_txn.client) {
+            let mut account = *client;
+            let mut flows = self.flows; // recorded along with the account
+            if let (Some(amount), Some(disputed), Some(release)) = (amount, disputed, release) {
+                account.total = account.total.checked_sub(release)?;
+                account.held = account.held.checked_sub(release)?;
+                flows.charged_back += invariants::units(release);
+                if policy == DisputeShortfallPolicy::Allow && (account.available.is_negative() || account.total.is_negative()) {
+                    // zero what is left, funds held by escrow or other disputes stay held
+                    let zeroed = account.held.checked_sub(account.total)?;
+                    flows.adjustments += invariants::units(zeroed);
+                    account.available = Amount::ZERO;
+                    account.total = account.held;
+                    written_off = Some(zeroed.min(release));
+                }
+                account.open_dispute_held = account.open_dispute_held.checked_sub(disputed)?;
+                account.release_shortfall(shortfall)?;
+                match policy {
+                    DisputeShortfallPolicy::HoldPartial => {
+                        account.shortfall_written_off = account.shortfall_written_off.checked_add(shortfall)?
+                    }
+                    _ => { // still owed, to be repaid like any debt
+                        account.available = account.available.checked_sub(shortfall)?;
+                        account.total = account.total.checked_sub(shortfall)?;
+                        flows.charged_back += invariants::units(shortfall);
+                    }
+                }
+                if let Some(fee) = fee { // passed on even into the negative, the account is locked anyway
+                    account.available = account.available.checked_sub(fee)?;
+                    account.total = account.total.checked_sub(fee)?;
+                    account.chargeback_fees = account.chargeback_fees.checked_add(fee)?;
+                    flows.chargeback_fees += invariants::units(fee);
+                }
+                account.settle_debt();
+                account.locked = true;
+                if account.locked_by.is_none() { // keep the chargeback that locked it first
+                    account.locked_by = Some(LockCause {
+                        tx: txn.tx,
+                        amount,
+                        line,
+                    });
+                    account.status_reason = Some(StatusReason::new(&lock_reason));
+                }
+            }
+            account.open_disputes -= 1;
+            account.chargebacks += 1;
+            *client = account;
+            self.flows = flows;
+        }
+        if policy != DisputeShortfallPolicy::HoldPartial { // kept for a representment
+            self.dispute_shortfalls.remove(&txn.tx);
+        }
+        if let Some(written_off) = written_off {
+            self.chargeback_write_offs.insert(txn.tx, written_off);
+        }
+        self.record_merchant(&original_txn, TransactionType::Chargeback, false);
+        let dispute = self.disputed_transactions.remove(&txn.tx); // the dispute is settled
+        self.track_open_disputes();
+        let charged_back = Transaction {
+            amount: dispute.as_ref().and_then(|dispute| dispute.amount), // what a representment gives back
+            ..original_txn
+        };
+        self.charged_back_transactions.insert(txn.tx, charged_back);
+        if let Some(reason) = txn.reason.or(dispute.and_then(|dispute| dispute.reason)) {
+            self.chargeback_reasons.insert(txn.tx, reason);
+        }
+        Ok(ProcessOutcome::Applied)
+    }
+
+    /// Credits a charged back transaction back to its client. The chargeback fee, if any, isn't
+    /// refunded.
+    fn process_representment(&mut self, txn: Transaction) -> Result<ProcessOutcome, PaymentError> {
+        let Some(original_txn) = self.charged_back_transactions.get(&txn.tx) else {
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::NotChargedBack));
+        };
+        if original_txn.client != txn.client {
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::ClientMismatch));
+        }
+        let original_txn = original_txn.clone();
+        let amount = self.amount_of(&original_txn)?;
+        let written_off = self.dispute_shortfalls.get(&txn.tx).copied().unwrap_or_default(); // never taken from the client
+        let zeroed = self.chargeback_write_offs.get(&txn.tx).copied().unwrap_or_default(); // taken, then written off
+        if let Some(client) = self.clients.get_mut(&original_txn.client) {
+            let mut account = *client;
+            let mut flows = self.flows;
+            if let Some(amount) = amount {
+                let credit = amount.checked_sub(written_off)?.checked_sub(zeroed)?;
+                account.available = account.available.checked_add(credit)?;
+                account.total = account.total.checked_add(credit)?;
+                flows.charged_back -= invariants::units(credit);
+                account.shortfall_written_off = account.shortfall_written_off.checked_sub(written_off)?;
+                let repaid = account.settle_debt();
+                account.debt_repaid = account.debt_repaid.checked_add(repaid)?;
+            }
+            account.chargebacks -= 1;
+            if account.locked && account.chargebacks == 0 { // no other chargeback keeps it locked
+                account.unlock()?;
+            }
+            *client = account;
+            self.flows = flows;
+        }
+        self.dispute_shortfalls.remove(&txn.tx);
+        self.chargeback_write_offs.remove(&txn.tx);
+        self.record_merchant(&original_txn, TransactionType::Chargeback, true);
+        self.charged_back_transactions.remove(&txn.tx); // back to resolved
+        self.resolved_transactions.insert(txn.tx);
+        self.chargeback_reasons.remove(&txn.tx);
+        Ok(ProcessOutcome::Applied)
+    }
+
+    /// Unlocks a client's account, releasing to available the deposits held while it was
+    /// locked. Returns `false` if the client is unknown or its account wasn't locked.
+    ///
+    /// Unlocking isn't a transaction, so it isn't recorded in the undo history.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::AmountOutOfRange`, leaving the account locked, if releasing the
+    /// held deposits overflows its balances.
+    pub fn unlock(&mut self, client_id: ClientId) -> Result<bool, PaymentError> {
+        let Some(client) = self.clients.get_mut(&client_id) else {
+            return Ok(false);
+        };
+        if !client.locked {
+            return Ok(false);
+        }
+        let before = *client;
+        let mut account = before;
+        account.unlock()?;
+        *client = account;
+        self.retotal(client_id, Some(before));
+        Ok(true)
+    }
+
+    /// Sets a client's balances directly, for the historical bugs no transaction can fix.
+    /// `total` is recomputed as `new_available + new_held`; the lock and the counters of the
+    /// account are left as they are. The correction is kept in `corrections` with its reason.
+    ///
+    /// A correction isn't a transaction and can't be undone. The undo history is cleared, so
+    /// that undoing an earlier transaction can't silently revert it.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::CorrectionRefused`, changing nothing, unless
+    /// `EngineConfig::allow_corrections` is set, or if the reason is blank, the client has no
+    /// account, a balance isn't a finite number or `new_held` is negative.
+    pub fn apply_correction(
+        &mut self,
+        client_id: ClientId,
+        new_available: f64,
+        new_held: f64,
+        reason: &str,
+    ) -> Result<CorrectionReceipt, PaymentError> {
+        let refuse = |msg: String| Err(PaymentError::CorrectionRefused(msg));
+        if !self.config.allow_corrections {
+            return refuse("corrections are disabled, see EngineConfig::allow_corrections".to_owned());
+        }
+        if reason.trim().is_empty() {
+            return refuse(format!("client {}: a correction needs a reason", client_id));
+        }
+        let balances = Amount::from_f64(new_available, self.config.rounding).and_then(|available| {
+            let held = Amount::from_f64(new_held, self.config.rounding)?;
+            Ok((available, held, available.checked_add(held)?))
+        });
+        let Ok((new_available, new_held, new_total)) = balances else {
+            return refuse(format!("client {}: balances must be finite numbers in range", client_id));
+        };
+        if new_held.is_negative() {
+            return refuse(format!("client {}: held can't be negative, got {}", client_id, new_held));
+        }
+        let Some(before) = self.clients.get(&client_id).copied() else {
+            return refuse(format!("client {} has no account", client_id));
+        };
+
+        let client = self.clients.get_mut(&client_id).expect("the client was just found");
+        client.available = new_available;
+        client.held = new_held;
+        client.total = new_total;
+        self.flows.adjustments += invariants::units(new_total) - invariants::units(before.total);
+        let balances = |client: &Client| Balances {
+            available: client.available,
+            held: client.held,
+            total: client.total,
+        };
+        let receipt = CorrectionReceipt {
+            sequence: self.corrections.len() as u64 + 1,
+            client: client_id,
+            old: balances(&before),
+            new: balances(client),
+            reason: reason.to_owned(),
+        };
+        self.retotal(client_id, Some(before));
+        self.history.clear();
+        self.corrections.push(receipt.clone());
+        Ok(receipt)
+    }
+
+    /// Returns the balance corrections applied so far, in order.
+    pub fn corrections(&self) -> &[CorrectionReceipt] {
+        &self.corrections
+    }
+
+    /// Pays interest at `rate` on the available balance of every account that isn't locked,
+    /// crediting `available * rate`, rounded with `EngineConfig::rounding`, to available and
+    /// total. Accounts are visited in ascending client id order, so the entries kept in
+    /// `accruals` are the same from one run to the next. Overdrawn accounts are left as they
+    /// are, interest is only paid on funds, and accounts whose interest rounds to zero get no
+    /// entry.
+    ///
+    /// Like a correction, an accrual isn't a transaction and can't be undone: the undo history
+    /// is cleared.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::AmountOutOfRange`, changing nothing, if `rate` isn't a finite
+    /// number of at least `-1` or an account's interest would take its balances out of range.
+    pub fn apply_accrual(&mut self, rate: f64) -> Result<AccrualReport, PaymentError> {
+        if !rate.is_finite() || rate < -1.0 {
+            return Err(PaymentError::AmountOutOfRange(format!("{} is not a valid accrual rate", rate)));
+        }
+        let mut ids: Vec<ClientId> = self
+            .clients
+            .iter()
+            .filter(|(_, client)| !client.locked && client.available > Amount::ZERO)
+            .map(|(id, _)| *id)
+            .collect();
+        ids.sort_unstable();
+
+        // every account is worked out before any is changed, so a failure leaves them all alone
+        let mut accrued = Vec::new();
+        let mut total_interest = Amount::ZERO;
+        for id in ids {
+            let before = self.clients[&id];
+            let interest = before.available.checked_mul(rate, self.config.rounding)?;
+            if interest == Amount::ZERO {
+                continue;
+            }
+            let mut client = before;
+            client.available = client.available.checked_add(interest)?;
+            client.total = client.total.checked_add(interest)?;
+            total_interest = total_interest.checked_add(interest)?;
+            accrued.push((id, before, client, interest));
+        }
+
+        let mut entries = Vec::with_capacity(accrued.len());
+        for (id, before, client, interest) in accrued {
+            self.clients.insert(id, client);
+            self.flows.adjustments += invariants::units(interest);
+            self.retotal(id, Some(before));
+            entries.push(AccrualEntry {
+                sequence: self.accruals.len() as u64 + entries.len() as u64 + 1,
+                client: id,
+                rate,
+                available_before: before.available,
+                interest,
+            });
+        }
+        self.history.clear();
+        self.accruals.extend(entries.iter().cloned());
+        Ok(AccrualReport {
+            rate,
+            entries,
+            total_interest,
+        })
+    }
+
+    /// Applies an `accrue` row, paying interest at the rate given as its amount like
+    /// `apply_accrual`, and writes the credited accounts through to the client store.
+    async fn process_accrual(&mut self, txn: Transaction) -> Result<ProcessOutcome, PaymentError> {
+        let Some(rate) = txn.amount else {
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::MissingAmount));
+        };
+        let report = self.apply_accrual(rate)?;
+        for entry in report.entries {
+            self.write_client(entry.client, None).await?;
+        }
+        Ok(ProcessOutcome::Applied)
+    }
+
+    /// Writes the entries of every accrual so far as a CSV of `ACCRUALS_HEADER`, in the order
+    /// they were paid, for audits.
+    pub fn write_accruals<W: Write>(&self, w: W) -> io::Result<()> {
+        accrual::write_entries(&self.accruals, w)
+    }
+
+    /// Returns the interest paid by accruals so far, one entry per account and accrual, in order.
+    pub fn accruals(&self) -> &[AccrualEntry] {
+        &self.accruals
+    }
+
+    /// Returns the interest paid by accruals across all clients.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::AmountOutOfRange` if the sum is out of range.
+    pub fn interest_paid(&self) -> Result<Amount, PaymentError> {
+        self.accruals
+            .iter()
+            .try_fold(Amount::ZERO, |paid, entry| paid.checked_add(entry.interest))
+    }
+
+    /// Escrow holds are tracked apart from disputes: they don't refer to a stored transaction,
+    /// and their tx id is only matched against other holds. A stale hold stays open until
+    /// released or captured, unless it expires under `EngineConfig::hold_ttl_secs`.
+    fn process_hold(&mut self, txn: Transaction) -> Result<ProcessOutcome, PaymentError> {
+        // a capture would take the held money out, so blocklisted clients can't open holds
+        if self.config.blocked_clients.contains_key(&txn.client) {
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::Blocklisted));
+        }
+        if self.is_above_limit(txn.amount) {
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::AmountAboveLimit));
+        }
+        let Some(client) = self.clients.get_mut(&txn.client) else {
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::UnknownClient));
+        };
+        if client.locked { // no new holds on a locked account
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::AccountLocked));
+        }
+        if self.escrow_holds.contains_key(&txn.tx) {
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::AlreadyHeld));
+        }
+        let Some(amount) = txn.amount else {
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::MissingAmount));
+        };
+        let amount = Amount::from_f64(amount, self.config.rounding)?;
+        if client.available < amount {
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::InsufficientFunds));
+        }
+        let available = client.available.checked_sub(amount)?;
+        client.held = client.held.checked_add(amount)?;
+        client.available = available;
+        self.escrow_holds.insert(txn.tx, txn);
+        Ok(ProcessOutcome::Applied)
+    }
+
+    /// Looks up the open escrow hold referenced by a release or capture and checks that both
+    /// refer to the same client.
+    fn referenced_hold(&self, txn: &Transaction) -> Result<Transaction, IgnoreReason> {
+        let Some(hold) = self.escrow_holds.get(&txn.tx) else {
+            return Err(IgnoreReason::NotHeld);
+        };
+        if hold.client != txn.client {
+            return Err(IgnoreReason::ClientMismatch);
+        }
+        Ok(hold.clone())
+    }
+
+    /// Releases the escrow holds expired under `EngineConfig::hold_ttl_secs` at `now`, the
+    /// timestamp of the row about to be processed, in tx id order. Like an accrual, the expiry
+    /// changes accounts other than the row's, so it can't be undone and clears the undo history.
+    async fn expire_holds(&mut self, now: Option<u64>) -> Result<(), PaymentError> {
+        let (Some(ttl), Some(now)) = (self.config.hold_ttl_secs, now) else {
+            return Ok(());
+        };
+        let mut expired: Vec<u32> = self
+            .escrow_holds
+            .iter()
+            .filter(|(_, hold)| hold.timestamp.is_some_and(|stamped| now.saturating_sub(stamped) > ttl))
+            .map(|(tx, _)| *tx)
+            .collect();
+        if expired.is_empty() {
+            return Ok(());
+        }
+        expired.sort_unstable();
+        for tx in expired {
+            let Some(hold) = self.escrow_holds.remove(&tx) else {
+                continue;
+            };
+            let before = self.clients.get(&hold.client).copied();
+            let amount = self.amount_of(&hold)?;
+            if let (Some(client), Some(amount)) = (self.clients.get_mut(&hold.client), amount) {
+                let available = client.available.checked_add(amount)?;
+                client.held = client.held.checked_sub(amount)?;
+                client.available = available;
+            }
+            self.retotal(hold.client, before);
+            self.write_client(hold.client, before).await?;
+            self.expired_holds += 1;
+        }
+        self.history.clear();
+        Ok(())
+    }
+
+    /// Releases are allowed on locked accounts, the funds go back to available either way.
+    fn process_release(&mut self, txn: Transaction) -> Result<ProcessOutcome, PaymentError> {
+        let hold = match self.referenced_hold(&txn) {
+            Ok(hold) => hold,
+            Err(reason) => return Ok(ProcessOutcome::Ignored(reason)),
+        };
+        let amount = self.amount_of(&hold)?;
+        if let (Some(client), Some(amount)) = (self.clients.get_mut(&hold.client), amount) {
+            let available = client.available.checked_add(amount)?;
+            client.held = client.held.checked_sub(amount)?;
+            client.available = available;
+        }
+        self.escrow_holds.remove(&txn.tx); // the hold is settled
+        Ok(ProcessOutcome::Applied)
+    }
+
+    /// The captured hold is stored as a withdrawal under its tx id, so it can be disputed like
+    /// any other withdrawal.
+    async fn process_capture(&mut self, txn: Transaction) -> Result<ProcessOutcome, PaymentError> {
+        let hold = match self.referenced_hold(&txn) {
+            Ok(hold) => hold,
+            Err(reason) => return Ok(ProcessOutcome::Ignored(reason)),
+        };
+        let amount = self.amount_of(&hold)?;
+        let Some(client) = self.clients.get_mut(&hold.client) else {
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::UnknownClient));
+        };
+        if client.locked { // like withdrawals, captures wait for the account to be unlocked
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::AccountLocked));
+        }
+        let mut account = *client;
+        if let Some(amount) = amount {
+            let held = account.held.checked_sub(amount)?;
+            account.total = account.total.checked_sub(amount)?;
+            account.held = held;
+        }
+        let id = hold.client;
+        self.retain(
+            Transaction {
+                r#type: TransactionType::Withdrawal,
+                ..hold
+            },
+            false,
+        )
+        .await?; // stored first, so a failing store leaves the hold open
+        self.clients.insert(id, account);
+        if let Some(amount) = amount {
+            self.flows.withdrawals += invariants::units(amount);
+        }
+        self.escrow_holds.remove(&txn.tx); // the hold is settled
+        Ok(ProcessOutcome::Applied)
+    }
+
+    /// Pending withdrawals are held like escrow holds, the amount being counted in both `held`
+    /// and the client's `pending_out`, and their tx id is only matched against other pending
+    /// withdrawals.
+    fn process_withdrawal_pending(&mut self, txn: Transaction) -> Result<ProcessOutcome, PaymentError> {
+        // settling takes the money out, so a blocklisted client can't start one
+        if self.config.blocked_clients.contains_key(&txn.client) {
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::Blocklisted));
+        }
+        if self.is_above_limit(txn.amount) {
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::AmountAboveLimit));
+        }
+        let Some(client) = self.clients.get_mut(&txn.client) else {
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::UnknownClient));
+        };
+        if client.locked { // no new pending withdrawals on a locked account
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::AccountLocked));
+        }
+        if self.pending_withdrawals.contains_key(&txn.tx) {
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::AlreadyPending));
+        }
+        let Some(amount) = txn.amount else {
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::MissingAmount));
+        };
+        let amount = Amount::from_f64(amount, self.config.rounding)?;
+        if client.available < amount {
+            return Ok(ProcessOutcome::Ignored(IgnoreReason::InsufficientFunds));
+        }
+        let available = client.available.checked_sub(amount)?;
+        let held = client.held.checked_add(amount)?;
+        client.pending_out = client.pending_out.checked_add(amount)?;
+        client.held = held;
+        client.available = available;
+        self.pending_withdrawals.insert(txn.tx, txn);
+        Ok(ProcessOutcome::Applied)
+    }
+
+    /// Looks up the pending withdrawal referenced by a settle or cancel and checks that both
+    /// refer to the same client.
+    fn referenced_pending(&self, txn: &Transaction) -> Result<Transaction, IgnoreReason> {
+        let Some(pending) = self.pending_withdrawals.get(&txn.tx) else {
+            return Err(IgnoreReason::NotPending);
+        };
+        if pending.client != txn.client {
+            return Err(IgnoreReason::ClientMismatch);
+        }
+        Ok(pending.clone())
+    }
+
+    /// Unlike captures, settles go through on locked accounts: the funds already left available
+    /// when the withdrawal was requested. The settled withdrawal is stored under its tx id, so
+    /// it can be disputed like any other withdrawal.
+    async fn process_withdrawal_settle(&mut self, txn: Transaction) -> Result<ProcessOutcome, PaymentError> {
+        let pending = match self.referenced_pending(&txn) {
+            Ok(pending) => pending,
+            Err(reason) => return Ok(ProcessOutcome::Ignored(reason)),
+        };
+        let amount = self.amount_of(&pending)?;
+        let mut account = self.clients.get(&pending.client).copied();
+        if let (Some(account), Some(amount)) = (account.as_mut(), amount) {
+            let held = account.held.checked_sub(amount)?;
+            let pending_out = account.pending_out.checked_sub(amount)?;
+            account.total = account.total.checked_sub(amount)?;
+            account.pending_out = pending_out;
+            account.held = held;
+        }
+        let id = pending.client;
+        self.retain(
+            Transaction {
+                r#type: TransactionType::Withdrawal,
+                ..pending
+            },
+            false,
+        )
+        .await?; // stored first, so a failing store leaves the withdrawal pending
+        if let (Some(account), Some(amount)) = (account, amount) {
+            self.clients.insert(id, account);
+            self.flows.withdrawals += invariants::units(amount);
+        }
+        self.pending_withdrawals.remove(&txn.tx); // the withdrawal is settled
+        Ok(ProcessOutcome::Applied)
+    }
+
+    /// Cancels are allowed on locked accounts, the funds go back to available either way.
+    fn process_withdrawal_cancel(&mut self, txn: Transaction) -> Result<ProcessOutcome, PaymentError> {
+        let pending = match self.referenced_pending(&txn) {
+            Ok(pending) => pending,
+            Err(reason) => return Ok(ProcessOutcome::Ignored(reason)),
+        };
+        let amount = self.amount_of(&pending)?;
+        if let (Some(client), Some(amount)) = (self.clients.get_mut(&pending.client), amount) {
+            let available = client.available.checked_add(amount)?;
+            let held = client.held.checked_sub(amount)?;
+            client.pending_out = client.pending_out.checked_sub(amount)?;
+            client.held = held;
+            client.available = available;
+        }
+        self.pending_withdrawals.remove(&txn.tx); // the withdrawal is cancelled
+        Ok(ProcessOutcome::Applied)
+    }
+
+    /// Returns the chargeback fees debited from all clients.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::AmountOutOfRange` if the sum is out of range.
+    pub fn collected_chargeback_fees(&self) -> Result<Amount, PaymentError> {
+        self.clients
+            .values()
+            .try_fold(Amount::ZERO, |fees, client| fees.checked_add(client.chargeback_fees))
+    }
+
+    /// Returns the latest timestamp of the rows processed so far, `None` if none had one.
+    pub fn latest_timestamp(&self) -> Option<u64> {
+        self.latest_timestamp
+    }
+
+    /// Returns the amount held by the disputes still open, short of their disputed amount by
+    /// what the clients' available funds didn't cover.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::AmountOutOfRange` if the sum is out of range.
+    pub fn open_dispute_held(&self) -> Result<Amount, PaymentError> {
+        let mut held = Amount::ZERO;
+        for (tx, txn) in &self.disputed_transactions {
+            let amount = self.amount_of(txn)?.unwrap_or(Amount::ZERO);
+            let shortfall = self.dispute_shortfalls.get(tx).copied().unwrap_or(Amount::ZERO);
+            held = held.checked_add(amount.checked_sub(shortfall)?)?;
+        }
+        Ok(held)
+    }
+
+    /// Returns where a stored transaction is in its dispute lifecycle: `Disputed` while a
+    /// dispute is open, `ChargedBack` once charged back, `Resolved` once a dispute was resolved
+    /// or a chargeback won back, and `Settled` if it was never disputed.
+    pub fn transaction_state(&self, tx: u32) -> TransactionState {
+        if self.disputed_transactions.contains_key(&tx) {
+            TransactionState::Disputed
+        } else if self.charged_back_transactions.contains_key(&tx) {
+            TransactionState::ChargedBack
+        } else if self.resolved_transactions.contains(&tx) {
+            TransactionState::Resolved
+        } else {
+            TransactionState::Settled
+        }
+    }
+
+    /// Writes every transaction the store retained, sorted by tx id, for audits: a
+    /// `# retained: ...` line telling which transactions the engine's config retains, then a CSV
+    /// of `EXPORTED_TRANSACTIONS_HEADER` with the state of each and the source it was read from,
+    /// if processed with one. Captures and settles are retained as withdrawals.
+    ///
+    /// Past its first line, the export is a valid input: its `state` and `source` columns are
+    /// skipped.
+    ///
+    /// # Errors
+    ///
+    /// Returns the store's error if a transaction can't be read back, or a
+    /// `PaymentError::IoError` if writing fails.
+    pub async fn export_transactions<W: Write>(&mut self, mut w: W) -> Result<(), PaymentError> {
+        let io_error = |err: io::Error| PaymentError::IoError(err.to_string());
+        writeln!(w, "# retained: {}", self.retention()).map_err(io_error)?;
+        writeln!(w, "{}", EXPORTED_TRANSACTIONS_HEADER).map_err(io_error)?;
+        let mut txs = self.transactions.tx_ids();
+        txs.sort_unstable();
+        // read back one at a time, so a tiered store isn't brought into memory all at once
+        for tx in txs {
+            let Some(txn) = self.stored(tx).await? else {
+                continue;
+            };
+            let amount = self.amount_of(&txn)?.map(|amount| amount.to_string()).unwrap_or_default();
+            let location = txn.source.as_ref().map(SourceRef::location).unwrap_or_default();
+            writeln!(
+                w,
+                "{},{},{},{},{},{},{}",
+                txn.tx,
+                txn.client,
+                txn.r#type.as_str(),
+                amount,
+                self.transaction_state(txn.tx).as_str(),
+                csv_field(&location),
+                csv_field(txn.memo.as_deref().unwrap_or_default())
+            )
+            .map_err(io_error)?;
+        }
+        w.flush().map_err(io_error)
+    }
+
+    /// Describes which transactions the store retains under the engine's config.
+    fn retention(&self) -> String {
+        match (self.config.dispute_support, self.config.dust, self.config.min_amount) {
+            (DisputeSupport::None, _, _) => "nothing, dispute support is off".to_owned(),
+            (DisputeSupport::Full, DustPolicy::ApplyButDontStore, Some(min)) => {
+                format!("deposits and withdrawals of at least {:.4}", min)
+            }
+            (DisputeSupport::Full, _, _) => "deposits and withdrawals".to_owned(),
+        }
+    }
+
+    /// Returns a copy of every dispute still open, sorted by tx id.
+    pub fn open_disputes(&self) -> impl Iterator<Item = DisputeView> + '_ {
+        let mut txs: Vec<u32> = self.disputed_transactions.keys().copied().collect();
+        txs.sort_unstable();
+        txs.into_iter().filter_map(|tx| self.dispute(tx))
+    }
+
+    /// Returns a copy of the open dispute of the transaction `tx`, `None` if it isn't disputed
+    /// or its dispute was resolved or charged back.
+    pub fn dispute(&self, tx: u32) -> Option<DisputeView> {
+        let txn = self.disputed_transactions.get(&tx)?;
+        let amount = txn.amount.unwrap_or_default();
+        let shortfall = self.dispute_shortfalls.get(&tx).map_or(0.0, |shortfall| shortfall.to_f64());
+        Some(DisputeView {
+            tx,
+            client: txn.client,
+            amount,
+            held: amount - shortfall,
+            timestamp: txn.timestamp,
+            reason: txn.reason.clone(),
+            source: txn.source.clone(),
+        })
+    }
+
+    /// Writes the disputes still open, i.e. neither resolved nor charged back, as a CSV of the
+    /// disputed transaction's id, client and amount, the dispute's reason (empty when none was
+    /// given) and where the disputed transaction was read from (empty when unknown), ordered by
+    /// tx id. When the dispute row had a timestamp, `age_secs` is the time since, up to the
+    /// latest timestamp processed (see `latest_timestamp`); it is empty otherwise.
+    pub fn write_open_disputes<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "{}", OPEN_DISPUTES_HEADER)?;
+        for dispute in self.open_disputes() {
+            let reason = csv_field(dispute.reason.as_deref().unwrap_or_default());
+            let location = dispute.source.map(|source| source.location()).unwrap_or_default();
+            let source = csv_field(&location);
+            let age = match (dispute.timestamp, self.latest_timestamp) {
+                (Some(opened), Some(latest)) => latest.saturating_sub(opened).to_string(),
+                _ => String::new(),
+            };
+            writeln!(
+                w,
+                "{},{},{:.4},{},{},{}",
+                dispute.tx, dispute.client, dispute.amount, reason, source, age
+            )?;
+        }
+        w.flush()
+    }
+
+    /// Writes every locked account with the chargeback that locked it, as a CSV of the client,
+    /// the charged back tx id and amount, the chargeback's input line (empty when unknown), the
+    /// account's remaining total, the chargeback's reason (empty when none was given) and where
+    /// the charged back transaction was read from (empty when unknown), ordered by client id.
+    pub fn write_locked_accounts<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let mut locked: Vec<(&ClientId, &LockCause, Amount)> = self
+            .clients
+            .iter()
+            .filter_map(|(id, client)| client.locked_by.as_ref().map(|cause| (id, cause, client.total)))
+            .collect();
+        locked.sort_by_key(|(id, _, _)| **id);
+
+        writeln!(w, "{}", LOCKED_ACCOUNTS_HEADER)?;
+        for (id, cause, total) in locked {
+            let line = cause.line.map(|line| line.to_string()).unwrap_or_default();
+            let reason = csv_field(self.chargeback_reasons.get(&cause.tx).map_or("", String::as_str));
+            let location = self.open_source(cause.tx).map(SourceRef::location).unwrap_or_default();
+            let source = csv_field(&location);
+            writeln!(
+                w,
+                "{},{},{:.4},{},{:.4},{},{}",
+                id, cause.tx, cause.amount, line, total, reason, source
+            )?;
+        }
+        w.flush()
+    }
+
+    /// Writes every client owing money, as a CSV of the client, its outstanding debt, the
+    /// deposits that went to repaying it so far, its total and whether it is locked, ordered by
+    /// client id.
+    pub fn write_debtors_report<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let mut debtors: Vec<(&ClientId, &Client)> = self
+            .clients
+            .iter()
+            .filter(|(_, client)| client.debt.is_positive())
+            .collect();
+        debtors.sort_by_key(|(id, _)| **id);
+
+        writeln!(w, "{}", DEBTORS_HEADER)?;
+        for (id, client) in debtors {
+            writeln!(
+                w,
+                "{},{:.4},{:.4},{:.4},{}",
+                id, client.debt, client.debt_repaid, client.total, client.locked
+            )?;
+        }
+        w.flush()
+    }
+
+    /// Writes the losses of the chargebacks that couldn't take back the whole disputed amount,
+    /// as a CSV of the client, the tx id, the amount disputed and charged back, the part of it
+    /// recovered, and the shortfall written off, ordered by tx id. Under
+    /// `DisputeShortfallPolicy::HoldPartial` the shortfall is what the dispute couldn't hold;
+    /// under `Allow` it is the negative balance the chargeback zeroed. `Freeze` turns shortfalls
+    /// into debt rather than writing them off. A representment takes its chargeback's loss off
+    /// the report.
+    pub fn write_losses_report<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let mut losses: Vec<(u32, Amount)> = self
+            .charged_back_transactions
+            .keys()
+            .filter_map(|tx| {
+                let shortfall = self.dispute_shortfalls.get(tx).or(self.chargeback_write_offs.get(tx))?;
+                Some((*tx, *shortfall))
+            })
+            .collect();
+        losses.sort_by_key(|(tx, _)| *tx);
+
+        writeln!(w, "{}", LOSSES_HEADER)?;
+        for (tx, shortfall) in losses {
+            let charged_back = &self.charged_back_transactions[&tx];
+            let expected = self.amount_of(charged_back).map_err(io::Error::other)?.unwrap_or_default();
+            let recovered = expected.checked_sub(shortfall).map_err(io::Error::other)?;
+            writeln!(w, "{},{},{},{},{}", charged_back.client, tx, expected, recovered, shortfall)?;
+        }
+        w.flush()
+    }
+
+    /// Writes the deposits flagged by duplicate detection as a CSV of the client, the earlier
+    /// deposit's tx id, the suspected duplicate's tx id, the amount and the seconds between
+    /// them, in input order.
+    pub fn write_suspected_duplicates<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "{}", SUSPECTED_DUPLICATES_HEADER)?;
+        for dup in &self.suspected_duplicates {
+            writeln!(
+                w,
+                "{},{},{},{:.4},{}",
+                dup.client, dup.original_tx, dup.tx, dup.amount, dup.delta_secs
+            )?;
+        }
+        w.flush()
+    }
+
+    /// Writes the deposits, disputes and chargebacks of every merchant as a CSV of the merchant,
+    /// its deposit count and volume, dispute count, chargeback count and volume and chargeback
+    /// rate (chargebacks per deposit), ordered by merchant. Deposits without a merchant aren't
+    /// counted.
+    pub fn write_merchant_stats<W: Write>(&self, w: W) -> io::Result<()> {
+        self.merchants.write(w)
+    }
+
+    /// Returns the client accounts as a JSON object keyed by client id, in ascending id order,
+    /// for dashboards and external snapshots. Amounts are strings with four decimal places.
+    pub fn clients_json(&self) -> Result<String, PaymentError> {
+        let clients: BTreeMap<&ClientId, &Client> = self.clients.iter().collect();
+        serde_json::to_string(&clients).map_err(|err| PaymentError::JsonError(err.to_string()))
+    }
+
+    /// Returns the SHA-256 digest of the client accounts as 64 lowercase hex digits, to check
+    /// that two runs ended in the same state without comparing their reports.
+    ///
+    /// The digest is taken over the `canonical::CLIENT_HEADER` line, then the canonical row of
+    /// each account in ascending client id order, see `canonical::encode_client`, so a frozen
+    /// account doesn't digest like an active one.
+    pub fn state_digest(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(canonical::CLIENT_HEADER.as_bytes());
+        hasher.update(b"\n");
+        let mut ids: Vec<ClientId> = self.clients.keys().copied().collect();
+        ids.sort_unstable();
+        let mut row = Vec::new();
+        for id in ids {
+            row.clear();
+            canonical::encode_client(self.clients[&id].view(id), &mut row);
+            hasher.update(&row);
+        }
+        hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    /// Loads client accounts from a JSON object in the format of `clients_json`, replacing the
+    /// accounts with the same ids, so a run can start from an earlier run's state.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::JsonError` if the JSON isn't a valid clients dump, in which case
+    /// no account is loaded.
+    pub fn load_clients_json(&mut self, json: &str) -> Result<(), PaymentError> {
+        let clients: BTreeMap<ClientId, Client> =
+            serde_json::from_str(json).map_err(|err| PaymentError::JsonError(err.to_string()))?;
+        for (id, client) in clients {
+            self.flows.opening += invariants::units(client.total);
+            match self.clients.insert(id, client) {
+                Some(replaced) => self.flows.opening -= invariants::units(replaced.total),
+                None => self.first_seen.push(id),
+            }
+        }
+        self.mark_blocklisted();
+        self.totals = Totals::of(self.clients.values());
+        Ok(())
+    }
+
+    /// Merges the state of another engine into this one, e.g. the states of two regions
+    /// processed separately, keeping this engine's config and warning sink.
+    ///
+    /// Clients with an account on only one side carry over. A client with an account on both
+    /// sides fails the merge, unless `EngineConfig::client_merge` is `ClientMergePolicy::Sum`,
+    /// which sums the accounts and locks the result if either side is locked. The other side's
+    /// stored transactions, disputes, chargebacks and escrow holds move over with it, so every
+    /// open dispute still finds its transaction in the store, and its balance corrections follow
+    /// ours, renumbered. Undo history is cleared, as it can't revert a merge. The merged accounts
+    /// of `other` are written to this engine's client store; those `other` never loaded from
+    /// its own store don't move.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `MergeError::DuplicateTransactions` listing every tx id, stored, held in escrow
+    /// or pending, used on both sides, or a `MergeError::SharedClients` listing the clients on both
+    /// sides under `ClientMergePolicy::Reject`. Nothing is merged in either case. A
+    /// `MergeError::Storage` is returned if a store fails while transactions are moved.
+    pub async fn merge(mut self, mut other: PaymentEngine<S, C>) -> Result<PaymentEngine<S, C>, MergeError> {
+        let ours: HashSet<u32> = self
+            .transactions
+            .tx_ids()
+            .into_iter()
+            .chain(self.escrow_holds.keys().copied())
+            .chain(self.pending_withdrawals.keys().copied())
+            .collect();
+        let mut collisions: Vec<u32> = other
+            .transactions
+            .tx_ids()
+            .into_iter()
+            .chain(other.escrow_holds.keys().copied())
+            .chain(other.pending_withdrawals.keys().copied())
+            .filter(|tx| ours.contains(tx))
+            .collect();
+        if !collisions.is_empty() {
+            collisions.sort_unstable();
+            collisions.dedup();
+            return Err(MergeError::DuplicateTransactions(collisions));
+        }
+
+        if self.config.client_merge == ClientMergePolicy::Reject {
+            let mut shared: Vec<ClientId> = other
+                .clients
+                .keys()
+                .filter(|id| self.clients.contains_key(id))
+                .copied()
+                .collect();
+            if !shared.is_empty() {
+                shared.sort_unstable();
+                return Err(MergeError::SharedClients(shared));
+            }
+        }
+
+        for tx in other.transactions.tx_ids() {
+            if let Some(txn) = other.transactions.get(tx).await? {
+                self.transactions.insert(txn).await?;
+            }
+        }
+        self.disputed_transactions.extend(other.disputed_transactions);
+        self.track_open_disputes();
+        self.charged_back_transactions.extend(other.charged_back_transactions);
+        self.resolved_transactions.extend(other.resolved_transactions);
+        self.dispute_shortfalls.extend(other.dispute_shortfalls);
+        self.chargeback_write_offs.extend(other.chargeback_write_offs);
+        self.chargeback_reasons.extend(other.chargeback_reasons);
+        self.escrow_holds.extend(other.escrow_holds);
+        self.pending_withdrawals.extend(other.pending_withdrawals);
+        self.recent_keys.extend(other.recent_keys, self.config.max_idempotency_keys);
+        self.merchants.merge(other.merchants);
+        self.suspected_duplicates.extend(other.suspected_duplicates);
+        self.unretained += other.unretained;
+        self.dust += other.dust;
+        self.panics.extend(other.panics);
+        self.storage_retries += other.storage_retries;
+        self.flows.merge(&other.flows);
+        for mut correction in other.corrections {
+            correction.sequence = self.corrections.len() as u64 + 1;
+            self.corrections.push(correction);
+        }
+        for mut entry in other.accruals {
+            entry.sequence = self.accruals.len() as u64 + 1;
+            self.accruals.push(entry);
+        }
+
+        for id in other.first_seen {
+            if !self.clients.contains_key(&id) {
+                self.first_seen.push(id);
+            }
+        }
+        let mut merged_ids = Vec::with_capacity(other.clients.len());
+        for (id, client) in other.clients {
+            merged_ids.push(id);
+            match self.clients.get_mut(&id) {
+                Some(merged) => merged.absorb(client)?,
+                None => {
+                    self.clients.insert(id, client);
+                }
+            }
+        }
+        if C::KEEPS_ACCOUNTS {
+            merged_ids.sort_unstable();
+            for id in merged_ids {
+                self.client_store.insert(id, self.clients[&id]).await?;
+            }
+        }
+        self.totals = Totals::of(self.clients.values());
+        self.history.clear();
+        Ok(self)
+    }
+
+    /// Returns the available funds of all clients.
+    pub fn total_available(&self) -> f64 {
+        self.totals.available
+    }
+
+    /// Returns the funds held across all clients.
+    pub fn total_held(&self) -> f64 {
+        self.totals.held
+    }
+
+    /// Returns the total funds of all clients, available and held.
+    pub fn total_funds(&self) -> f64 {
+        self.totals.total
+    }
+
+    /// Returns the number of locked accounts.
+    pub fn locked_count(&self) -> u64 {
+        self.totals.locked
+    }
+
+    /// Sums the balances of the given clients, each counted once. Unknown clients add nothing.
+    pub fn sum_for_clients(&self, ids: &[ClientId]) -> Totals {
+        let ids: HashSet<&ClientId> = ids.iter().collect();
+        Totals::of(ids.into_iter().filter_map(|id| self.clients.get(id)))
+    }
+
+    /// Checks the running totals behind `total_funds` and the like against the sums of the
+    /// accounts, which only differ if `clients` was changed from outside the engine.
+    pub fn check_totals(&self) -> Option<TotalsDrift> {
+        invariants::check_totals(&self.totals, &self.clients)
+    }
+
+    /// Returns the money the applied transactions, corrections, accruals and loaded states
+    /// moved, summed exactly as they were applied.
+    pub fn money_flows(&self) -> MoneyFlows {
+        self.flows
+    }
+
+    /// Checks the money flows against the accounts: what came in less what went out should be
+    /// exactly what the accounts hold. A ledger that doesn't balance means a handler changed an
+    /// account without recording why, or `clients` was changed from outside the engine.
+    pub fn check_ledger(&self) -> Ledger {
+        Ledger {
+            flows: self.flows,
+            accounts: self.clients.values().map(|client| invariants::units(client.total)).sum(),
+        }
+    }
+
+    /// Checks every client account for inconsistent balances, returning the violations found
+    /// ordered by client id.
+    pub fn check_invariants(&self) -> Vec<InvariantViolation> {
+        let mut ids: Vec<&ClientId> = self.clients.keys().collect();
+        ids.sort();
+        ids.into_iter()
+            .flat_map(|id| invariants::check_client(*id, &self.clients[id]))
+            .collect()
+    }
+
+    /// Returns a copy of a client's balances and status, `None` if the client has no account.
+    pub fn client_view(&self, client: ClientId) -> Option<ClientView> {
+        self.clients.get(&client).map(|account| account.view(client))
+    }
+
+    /// Returns a copy of every client's balances and status, sorted by client id.
+    pub fn all_client_views(&self) -> Vec<ClientView> {
+        let mut views = Vec::with_capacity(self.clients_len());
+        let _ = self.for_each_client_state(|view| {
+            views.push(view);
+            ControlFlow::Continue(())
+        });
+        views
+    }
+
+    /// Calls `f` with a copy of every client's balances and status, sorted by client id, until it
+    /// returns `ControlFlow::Break`. Lets a host serialize the states its own way without
+    /// collecting them first; returns `Break` if `f` stopped early.
+    pub fn for_each_client_state<F: FnMut(ClientView) -> ControlFlow<()>>(
+        &self,
+        mut f: F,
+    ) -> ControlFlow<()> {
+        self.for_each_client(OutputOrder::ClientId, None, |id, client| f(client.view(id)))
+    }
+
+    /// Returns the number of client accounts.
+    pub fn clients_len(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Calls `f` with every client's id and account in `order`, restricted to `filter`, until it
+    /// breaks, and returns what it broke with. All the client states writers go through here.
+    pub(crate) fn for_each_client<'a, B>(
+        &'a self,
+        order: OutputOrder,
+        filter: Option<&ClientFilter>,
+        mut f: impl FnMut(ClientId, &'a Client) -> ControlFlow<B>,
+    ) -> ControlFlow<B> {
+        for id in self.client_ids(order) {
+            if filter.is_none_or(|filter| filter.contains(id)) {
+                if let ControlFlow::Break(value) = f(id, &self.clients[&id]) {
+                    return ControlFlow::Break(value);
+                }
+            }
+        }
+        ControlFlow::Continue(())
+    }
+
+    /// Returns the ids of every client, in the given order.
+    ///
+    /// For `OutputOrder::FirstSeen`, clients inserted into `clients` directly rather than by a
+    /// transaction come last, by id.
+    pub fn client_ids(&self, order: OutputOrder) -> Vec<ClientId> {
+        let mut ids: Vec<ClientId> = match order {
+            OutputOrder::FirstSeen => self.first_seen.clone(),
+            OutputOrder::ClientId | OutputOrder::Unordered => self.clients.keys().copied().collect(),
+        };
+        match order {
+            OutputOrder::ClientId => ids.sort(),
+            OutputOrder::FirstSeen if ids.len() < self.clients.len() => {
+                let tracked: HashSet<ClientId> = self.first_seen.iter().copied().collect();
+                let mut untracked: Vec<ClientId> =
+                    self.clients.keys().filter(|id| !tracked.contains(id)).copied().collect();
+                untracked.sort();
+                ids.extend(untracked);
+            }
+            OutputOrder::FirstSeen | OutputOrder::Unordered => {}
+        }
+        ids
+    }
+
+    /// This asynchronous function prints the state of each client in a CSV format, including the
+    /// available funds, held funds, total balance, and account lock status.
+    ///
+    /// The function outputs the state of each client as follows:
+    ///
+    /// ```text
+    /// client,available,held,total,locked
+    /// 1,100.0000,0.0000,100.0000,false
+    /// 2,200.0000,0.0000,200.0000,false
+    /// ```
+    ///
+    /// The available, held, and total values are displayed with four decimal places.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::OutputError` if stdout can't be written.
+    pub async fn output_client_states(&self) -> Result<(), PaymentError> {
+        let mut stdout = std::io::stdout().lock();
+        self.write_client_states(&mut stdout, None, OutputOrder::default())
+            .and_then(|_| stdout.flush())
+            .map_err(|err| PaymentError::output("stdout", err))
+    }
+
+    /// Writes the state of each client to `w` in the CSV format of `output_client_states`,
+    /// listed in `order` and restricted to the clients in `filter` when one is given.
+    pub fn write_client_states<W: Write>(
+        &self,
+        w: W,
+        filter: Option<&ClientFilter>,
+        order: OutputOrder,
+    ) -> io::Result<()> {
+        report::write_report(
+            self,
+            w,
+            &ReportOptions {
+                order,
+                clients: filter,
+                ..Default::default()
+            },
+        )
+    }
+}
+
+/// Header line of the open disputes report.
+pub const OPEN_DISPUTES_HEADER: &str = "tx,client,amount,reason,source,age_secs";
+
+/// Header of the retained transactions export, after its `# retained: ...` line.
+pub const EXPORTED_TRANSACTIONS_HEADER: &str = "tx,client,type,amount,state,source,memo";
+
+/// Header line of the locked accounts report.
+pub const LOCKED_ACCOUNTS_HEADER: &str = "client,tx,amount,line,total,reason,tx_source";
+
+/// Header line of the debtors report.
+pub const DEBTORS_HEADER: &str = "client,debt,repaid,total,locked";
+
+/// Header line of the losses report.
+pub const LOSSES_HEADER: &str = "client,tx,expected,recovered,shortfall";
+
+/// Header line of the suspected duplicate deposits report.
+pub const SUSPECTED_DUPLICATES_HEADER: &str = "client,original_tx,tx,amount,delta_secs";
+
+/// Header line of the client states CSV.
+pub const CLIENT_STATES_HEADER: &str = "client,available,held,total,locked";
+
+/// Writes one client's row of the client states CSV.
+pub fn write_client_row<W: Write>(w: W, client_id: ClientId, client: &Client) -> io::Result<()> {
+    report::write_csv_row(w, client_id, client, &ReportOptions::default())
+}
+
+/// Returns the record of a rejected batch row with the line and file it was read from, if known.
+fn located(record: RejectionRecord, source: Option<&SourceRef>) -> RejectionRecord {
+    let Some(source) = source else {
+        return record;
+    };
+    let record = record.with_line(Some(source.line));
+    match &source.file {
+        Some(file) => record.with_source(file.as_ref()),
+        None => record,
+    }
+}
+
+// Test trasaction processor
+#[cfg(test)]
+mod tests {
+    use crate::{
+        amount::{amount, Amount},
+        batch::BatchResult,
+        config::{
+            ClientCreationPolicy, ClientMergePolicy, DisputeAmountPolicy, DisputeShortfallPolicy, DisputeSupport,
+            DuplicateAction, DuplicateDetection, DustPolicy, EngineConfig,
+        },
+        corrections::Balances,
+        errors::{Limit, MergeError, PaymentError},
+        parser::{parse_records, parse_transactions},
+        payment_engine::{PaymentEngine, EXPORTED_TRANSACTIONS_HEADER, LOSSES_HEADER},
+        rejection::{RejectionReason, RejectionRecord},
+        report::{OutputOrder, Rounding},
+        simulate::TransactionGenerator,
+        store::TransactionStore,
+        types::{
+            AccountStatus, AsOfTx, ClientId, ClientView, DisputeView, IgnoreReason, LockCause,
+            LockedDepositPolicy, ProcessOutcome, SourceRef, StatusReason, Transaction, TransactionState,
+            TransactionType,
+        },
+        warnings::EngineWarning,
+    };
+    use std::{ops::ControlFlow, sync::Arc, time::Duration};
+    use tokio::sync::mpsc;
+    use tokio_util::sync::CancellationToken;
+
+    #[tokio::test]
+    async fn can_process_simple_transactions() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount 
+        deposit, 1, 1, 1.0 
+        deposit, 2, 2, 2.0 
+        deposit, 1, 3, 2.0 
+        withdrawal, 1, 4, 1.5 
+        withdrawal, 2, 5, 3.0";
+        let str_buf = stringreader::StringReader::new(csv);
+        let transactions = parse_transactions(Box::new(str_buf)).await?;
+        let mut engine = PaymentEngine::new();
+
+        engine.process_all(transactions).await.into_result()?;
+
+        let client = engine.client_view(1).expect("client 1 has an account");
+        assert_eq!(client.total, 1.5);
+        assert_eq!(client.available, 1.5);
+        assert!(!client.is_locked());
+        assert_eq!(client.held, 0.0);
+        assert_eq!(engine.client_view(3), None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_views_can_be_kept_while_processing_goes_on() -> Result<(), PaymentError> {
+        let mut engine = PaymentEngine::new();
+        engine.process_transaction(Transaction::deposit(2, 1, 5.0)).await?;
+        engine.process_transaction(Transaction::deposit(1, 2, 3.0)).await?;
+        let before = engine.client_view(1).expect("client 1 has an account");
+        let all_before = engine.all_client_views();
+
+        engine.process_transaction(Transaction::dispute(1, 2)).await?;
+        engine.process_transaction(Transaction::chargeback(1, 2)).await?;
+        engine.process_transaction(Transaction::withdrawal(2, 3, 1.0)).await?;
+
+        // the views still hold the balances they were taken with
+        assert_eq!(
+            (before.client, before.available, before.status),
+            (1, amount(3.0), AccountStatus::Active)
+        );
+        let ids: Vec<ClientId> = all_before.iter().map(|view| view.client).collect();
+        assert_eq!(ids, [1, 2]);
+        assert_eq!(all_before[1].available, amount(5.0));
+
+        let after = engine.client_view(1).expect("client 1 has an account");
+        assert_eq!((after.total, after.status), (amount(0.0), AccountStatus::Locked));
+        assert_eq!(engine.all_client_views()[1].available, amount(4.0));
+        assert_eq!(ClientView::empty(7).total, Amount::ZERO);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_process_chargeback_transactions() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 1.0
+        deposit, 2, 2, 2.0
+        deposit, 1, 3, 2.0
+        withdrawal, 1, 4, 1.5
+        withdrawal, 2, 5, 3.0
+        dispute, 1, 3
+        resolve, 1, 3
+        dispute, 2, 2
+        chargeback, 2, 2";
+        let str_buf = stringreader::StringReader::new(csv);
+        let transactions = parse_transactions(Box::new(str_buf)).await?;
+        let mut engine = PaymentEngine::new();
+
+        engine.process_all(transactions).await.into_result()?;
+
+        if let Some(client) = engine.clients.get(&2) {
+            assert_eq!(client.total, 0.0);
+            assert_eq!(client.available, 0.0);
+            assert!(client.locked); // should be locked due to chargeback
+            assert_eq!(client.held, 0.0);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_process_disputed_transactions() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 1.0
+        deposit, 2, 2, 2.0
+        deposit, 1, 3, 2.0
+        withdrawal, 1, 4, 1.5
+        withdrawal, 2, 5, 3.0
+        dispute, 2, 2";
+
+        let str_buf = stringreader::StringReader::new(csv);
+        let transactions = parse_transactions(Box::new(str_buf)).await?;
+        let mut engine = PaymentEngine::new();
+
+        engine.process_all(transactions).await.into_result()?;
+
+        if let Some(client) = engine.clients.get(&2) {
+            assert_eq!(client.total, 2.0);
+            assert_eq!(client.available, 0.0); // available should be 0 due to dispute
+            assert!(!client.locked);
+            assert_eq!(client.held, 2.0);
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_process_resolved_transactions() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 1.0
+        deposit, 2, 2, 2.0
+        deposit, 1, 3, 2.0
+        withdrawal, 1, 4, 1.5
+        withdrawal, 2, 5, 3.0
+        dispute, 2, 2,
+        resolve, 2, 2";
+
+        let str_buf = stringreader::StringReader::new(csv);
+        let transactions = parse_transactions(Box::new(str_buf)).await?;
+        let mut engine = PaymentEngine::new();
+
+        engine.process_all(transactions).await.into_result()?;
+
+        if let Some(client) = engine.clients.get(&2) {
+            assert_eq!(client.total, 2.0);
+            assert_eq!(client.available, 2.0);
+            assert!(!client.locked);
+            assert_eq!(client.held, 0.0); // held should be 0 as dispute is resolved
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_report_ignored_transactions() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        withdrawal, 3, 1, 1.0
+        deposit, 1, 2, 1.0
+        withdrawal, 1, 3, 5.0
+        dispute, 2, 2
+        dispute, 1, 9
+        resolve, 1, 2
+        dispute, 1, 2
+        chargeback, 1, 2
+        deposit, 1, 4, 1.0";
+
+        let str_buf = stringreader::StringReader::new(csv);
+        let transactions = parse_transactions(Box::new(str_buf)).await?;
+        let mut engine = PaymentEngine::new();
+        let mut outcomes = Vec::new();
+
+        for txn in transactions {
+            outcomes.push(engine.process_transaction(txn?).await?);
+        }
+
+        assert_eq!(
+            outcomes,
+            vec![
+                ProcessOutcome::Ignored(IgnoreReason::UnknownClient),
+                ProcessOutcome::Applied,
+                ProcessOutcome::Ignored(IgnoreReason::InsufficientFunds),
+                ProcessOutcome::Ignored(IgnoreReason::ClientMismatch),
+                ProcessOutcome::Ignored(IgnoreReason::UnknownTransaction),
+                ProcessOutcome::Ignored(IgnoreReason::NotDisputed),
+                ProcessOutcome::Applied,
+                ProcessOutcome::Applied,
+                ProcessOutcome::Ignored(IgnoreReason::AccountLocked),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_undo_back_to_a_fresh_engine() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 10.0
+        deposit, 2, 2, 5.0
+        withdrawal, 1, 3, 3.0
+        withdrawal, 2, 4, 50.0
+        dispute, 1, 1
+        chargeback, 1, 1";
+        let str_buf = stringreader::StringReader::new(csv);
+        let transactions = parse_transactions(Box::new(str_buf)).await?;
+        let mut engine = PaymentEngine::new().with_undo_history(10);
+        engine.process_all(transactions).await.into_result()?;
+        assert!(engine.clients[&1].locked);
+
+        // undoing the chargeback unlocks the account and restores the dispute
+        let undone = engine.undo_last().await?.unwrap();
+        assert_eq!((undone.r#type, undone.tx), (TransactionType::Chargeback, 1));
+        let client = engine.clients[&1];
+        assert!(!client.locked);
+        assert_eq!((client.available.to_f64(), client.held.to_f64(), client.total.to_f64()), (-3.0, 10.0, 7.0));
+        assert!(engine.disputed_transactions.contains_key(&1));
+
+        // the ignored withdrawal was never recorded
+        let undone: Vec<u32> = [
+            engine.undo_last().await?,
+            engine.undo_last().await?,
+            engine.undo_last().await?,
+            engine.undo_last().await?,
+        ]
+        .into_iter()
+        .map(|txn| txn.unwrap().tx)
+        .collect();
+        assert_eq!(undone, vec![1, 3, 2, 1]);
+        assert!(engine.undo_last().await?.is_none());
+
+        assert!(engine.clients.is_empty());
+        assert!(engine.disputed_transactions.is_empty());
+        assert!(engine.transactions.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn undo_history_is_bounded() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 1.0
+        deposit, 1, 2, 2.0
+        deposit, 1, 3, 4.0";
+        let str_buf = stringreader::StringReader::new(csv);
+        let transactions = parse_transactions(Box::new(str_buf)).await?;
+        let mut engine = PaymentEngine::new().with_undo_history(2);
+        engine.process_all(transactions).await.into_result()?;
+
+        assert_eq!(engine.undo_last().await?.map(|txn| txn.tx), Some(3));
+        assert_eq!(engine.undo_last().await?.map(|txn| txn.tx), Some(2));
+        assert!(engine.undo_last().await?.is_none());
+        assert_eq!(engine.clients[&1].total, 1.0);
+
+        // without a history nothing can be undone
+        let mut engine = PaymentEngine::new();
+        let str_buf = stringreader::StringReader::new("type,client,tx,amount\ndeposit,1,1,1.0");
+        engine.process_all(parse_transactions(Box::new(str_buf)).await?).await.into_result()?;
+        assert!(engine.undo_last().await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_process_until_tx_in_stream_order() -> Result<(), PaymentError> {
+        // tx ids are out of order: stopping at tx 3 must still have applied tx 10
+        let csv = "type, client, tx, amount
+        deposit, 1, 10, 1.0
+        deposit, 1, 3, 2.0
+        deposit, 1, 7, 4.0";
+
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = PaymentEngine::new();
+        let as_of = AsOfTx { tx: 3, inclusive: true };
+        let processed = engine
+            .process_until(parse_transactions(Box::new(str_buf)).await?, |txn| as_of.until(txn))
+            .await?;
+        assert_eq!(processed, 2);
+        assert_eq!(engine.clients[&1].total, 3.0);
+
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = PaymentEngine::new();
+        let as_of = AsOfTx { tx: 3, inclusive: false };
+        let processed = engine
+            .process_until(parse_transactions(Box::new(str_buf)).await?, |txn| as_of.until(txn))
+            .await?;
+        assert_eq!(processed, 1);
+        assert_eq!(engine.clients[&1].total, 1.0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dispute_is_settled_only_once() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 1.0
+        dispute, 1, 1
+        dispute, 1, 1
+        resolve, 1, 1
+        resolve, 1, 1
+        chargeback, 1, 1";
+
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = PaymentEngine::new();
+        let mut outcomes = Vec::new();
+        for txn in parse_transactions(Box::new(str_buf)).await? {
+            outcomes.push(engine.process_transaction(txn?).await?);
+        }
+
+        assert_eq!(
+            outcomes,
+            vec![
+                ProcessOutcome::Applied,
+                ProcessOutcome::Applied,
+                ProcessOutcome::Ignored(IgnoreReason::AlreadyDisputed),
+                ProcessOutcome::Applied,
+                ProcessOutcome::Ignored(IgnoreReason::NotDisputed),
+                ProcessOutcome::Ignored(IgnoreReason::NotDisputed),
+            ]
+        );
+        let client = engine.clients[&1];
+        assert_eq!((client.available.to_f64(), client.held.to_f64(), client.locked), (1.0, 0.0, false));
+        assert_eq!((client.open_disputes, client.disputes), (0, 1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn open_disputes_are_capped_per_client() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 1.0
+        deposit, 1, 2, 1.0
+        deposit, 1, 3, 1.0
+        deposit, 2, 4, 1.0
+        dispute, 1, 1
+        dispute, 1, 2
+        dispute, 1, 3
+        dispute, 2, 4
+        chargeback, 1, 2
+        dispute, 1, 3";
+
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = PaymentEngine::new().with_config(EngineConfig {
+            max_open_disputes_per_client: Some(2),
+            ..Default::default()
+        });
+        let mut outcomes = Vec::new();
+        for txn in parse_transactions(Box::new(str_buf)).await? {
+            outcomes.push(engine.process_transaction(txn?).await?);
+        }
+
+        // the third dispute of client 1 is over the cap, client 2 has its own
+        assert_eq!(outcomes[6], ProcessOutcome::Ignored(IgnoreReason::TooManyOpenDisputes));
+        assert_eq!(outcomes[7], ProcessOutcome::Applied);
+        // settling an open dispute frees its slot
+        assert_eq!(outcomes[8], ProcessOutcome::Applied);
+        assert_eq!(outcomes[9], ProcessOutcome::Applied);
+        assert_eq!(engine.clients[&1].open_disputes, 2);
+        assert_eq!(engine.clients[&1].held, 2.0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn engine_without_dispute_support_stores_nothing() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 10.0
+        deposit, 2, 2, 20.0
+        withdrawal, 1, 3, 4.0
+        withdrawal, 2, 4, 25.0
+        hold, 2, 5, 5.0
+        capture, 2, 5
+        deposit, 1, 6, 0.5";
+
+        let mut full = PaymentEngine::new();
+        full.process_all(parse_transactions(Box::new(stringreader::StringReader::new(csv))).await?)
+            .await
+            .into_result()?;
+        let mut lean = PaymentEngine::new().with_config(EngineConfig {
+            dispute_support: DisputeSupport::None,
+            ..Default::default()
+        });
+        lean.process_all(parse_transactions(Box::new(stringreader::StringReader::new(csv))).await?)
+            .await
+            .into_result()?;
+
+        assert_eq!(lean.clients, full.clients);
+        assert_eq!(lean.transactions.len(), 0);
+        assert_eq!(lean.unretained_transactions(), full.transactions.len() as u64);
+
+        for txn in [Transaction::dispute(1, 1), Transaction::resolve(1, 1), Transaction::chargeback(1, 1)] {
+            let outcome = lean.process_transaction(txn).await?;
+            assert_eq!(outcome, ProcessOutcome::Ignored(IgnoreReason::DisputesDisabled));
+        }
+        assert_eq!(lean.clients, full.clients);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dust_is_handled_per_policy() -> Result<(), PaymentError> {
+        let transactions = [
+            Transaction::deposit(1, 1, 10.0),
+            Transaction::deposit(1, 2, 0.0001),
+            Transaction::deposit(1, 3, 0.0),
+            Transaction::withdrawal(1, 4, 0.00005), // rounds to zero
+            Transaction::withdrawal(1, 5, 0.01),
+        ];
+        let mut outcomes = Vec::new();
+        for dust in [DustPolicy::Reject, DustPolicy::ApplyButDontStore, DustPolicy::Apply] {
+            let mut engine = PaymentEngine::new().with_config(EngineConfig {
+                min_amount: Some(0.01),
+                dust,
+                ..Default::default()
+            });
+            let mut applied = Vec::new();
+            for txn in transactions.clone() {
+                applied.push(engine.process_transaction(txn).await? == ProcessOutcome::Applied);
+            }
+            assert_eq!(engine.dust_transactions(), 3, "{:?}", dust);
+            let dispute = engine.process_transaction(Transaction::dispute(1, 2)).await?;
+            outcomes.push((dust, applied, engine.transactions.len(), engine.clients[&1].total, dispute));
+        }
+
+        assert_eq!(
+            outcomes,
+            [
+                (
+                    DustPolicy::Reject,
+                    vec![true, false, false, false, true],
+                    2,
+                    amount(9.99),
+                    ProcessOutcome::Ignored(IgnoreReason::UnknownTransaction)
+                ),
+                (
+                    DustPolicy::ApplyButDontStore,
+                    vec![true; 5],
+                    2,
+                    amount(9.9901),
+                    ProcessOutcome::Ignored(IgnoreReason::UnknownTransaction)
+                ),
+                (DustPolicy::Apply, vec![true; 5], 5, amount(9.9901), ProcessOutcome::Applied),
+            ]
+        );
+
+        // an undone dust deposit is no longer counted
+        let mut engine = PaymentEngine::new()
+            .with_config(EngineConfig {
+                min_amount: Some(0.01),
+                dust: DustPolicy::Apply,
+                ..Default::default()
+            })
+            .with_undo_history(1);
+        engine.process_transaction(Transaction::deposit(1, 1, 0.0001)).await?;
+        assert_eq!(engine.dust_transactions(), 1);
+        engine.undo_last().await?;
+        assert_eq!(engine.dust_transactions(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rejected_dust_names_its_reason_and_creates_no_account() -> Result<(), PaymentError> {
+        let mut engine = PaymentEngine::new().with_config(EngineConfig {
+            min_amount: Some(0.0001),
+            ..Default::default()
+        });
+        let zero = Transaction::deposit(7, 1, 0.0);
+        let outcome = engine.process_transaction(zero.clone()).await?;
+        assert_eq!(outcome, ProcessOutcome::Ignored(IgnoreReason::DustAmount));
+        assert_eq!(engine.rejection(&zero, IgnoreReason::DustAmount).detail, "dust_amount");
+        assert!(engine.clients.is_empty());
+        // exactly the minimum isn't dust
+        let penny = engine.process_transaction(Transaction::deposit(7, 2, 0.0001)).await?;
+        assert_eq!(penny, ProcessOutcome::Applied);
+        assert_eq!(engine.dust_transactions(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn amounts_above_the_limit_are_rejected_before_the_balances() -> Result<(), PaymentError> {
+        let limited = EngineConfig {
+            max_transaction_amount: Some(1_000_000.0),
+            ..Default::default()
+        };
+        let mut engine = PaymentEngine::new().with_config(limited.clone());
+        let above = Transaction::deposit(1, 1, 1_000_000.000_1);
+        for (txn, outcome) in [
+            (above.clone(), ProcessOutcome::Ignored(IgnoreReason::AmountAboveLimit)),
+            (Transaction::deposit(1, 2, 999_999.999_9), ProcessOutcome::Applied),
+            (Transaction::deposit(1, 3, 1_000_000.0), ProcessOutcome::Applied),
+            (Transaction::withdrawal(1, 4, 1_500_000.0), ProcessOutcome::Ignored(IgnoreReason::AmountAboveLimit)),
+            (Transaction::withdrawal(1, 5, 1_000_000.0), ProcessOutcome::Applied),
+            // past the range of balances, rejected rather than failing the run
+            (Transaction::deposit(2, 6, 1e20), ProcessOutcome::Ignored(IgnoreReason::AmountAboveLimit)),
+        ] {
+            assert_eq!(engine.process_transaction(txn.clone()).await?, outcome, "{:?}", txn);
+        }
+        assert_eq!(engine.clients[&1].total, 999_999.999_9);
+        assert!(!engine.clients.contains_key(&2));
+        assert_eq!(
+            engine.rejection(&above, IgnoreReason::AmountAboveLimit).detail,
+            "amount_above_limit: 1000000.0001 is above the limit of 1000000.0000"
+        );
+
+        // a deposit applied before the limit was set can still be disputed and charged back
+        let mut engine = PaymentEngine::new();
+        engine.process_transaction(Transaction::deposit(1, 1, 2_000_000.0)).await?;
+        let mut engine = engine.with_config(limited);
+        for txn in [Transaction::dispute(1, 1), Transaction::chargeback(1, 1)] {
+            assert_eq!(engine.process_transaction(txn).await?, ProcessOutcome::Applied);
+        }
+        assert_eq!(engine.clients[&1].total, 0.0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn holds_and_pending_withdrawals_above_the_limit_are_rejected() -> Result<(), PaymentError> {
+        let limited = EngineConfig {
+            max_transaction_amount: Some(100.0),
+            ..Default::default()
+        };
+        let mut engine = PaymentEngine::new().with_config(limited);
+        for tx in 1..=3 {
+            engine.process_transaction(Transaction::deposit(1, tx, 100.0)).await?;
+        }
+        for (txn, outcome) in [
+            (Transaction::hold(1, 4, 100.000_1), ProcessOutcome::Ignored(IgnoreReason::AmountAboveLimit)),
+            (Transaction::hold(1, 5, 99.999_9), ProcessOutcome::Applied),
+            (Transaction::hold(1, 6, 100.0), ProcessOutcome::Applied),
+            (
+                Transaction::withdrawal_pending(1, 7, 100.000_1),
+                ProcessOutcome::Ignored(IgnoreReason::AmountAboveLimit),
+            ),
+            (Transaction::withdrawal_pending(1, 8, 99.999_9), ProcessOutcome::Applied),
+            (Transaction::withdrawal_pending(1, 9, 100.0), ProcessOutcome::Ignored(IgnoreReason::InsufficientFunds)),
+        ] {
+            assert_eq!(engine.process_transaction(txn.clone()).await?, outcome, "{:?}", txn);
+        }
+        assert_eq!(engine.escrow_holds.len(), 2);
+        assert_eq!(engine.pending_withdrawals.len(), 1);
+        assert_eq!(engine.clients[&1].held, 299.999_8);
+
+        // at the limit, a pending withdrawal the funds cover is applied
+        engine.process_transaction(Transaction::deposit(1, 10, 100.0)).await?;
+        let at_limit = Transaction::withdrawal_pending(1, 11, 100.0);
+        assert_eq!(engine.process_transaction(at_limit).await?, ProcessOutcome::Applied);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn warns_as_open_disputes_approach_the_global_cap() -> Result<(), PaymentError> {
+        let (sender, mut receiver) = mpsc::channel(16);
+        let mut engine = PaymentEngine::new()
+            .with_config(EngineConfig {
+                max_open_disputes: Some(10),
+                ..Default::default()
+            })
+            .with_undo_history(4)
+            .with_warning_sink(sender);
+        for tx in 1..=11 {
+            engine.process_transaction(Transaction::deposit(tx as ClientId, tx, 1.0)).await?;
+        }
+        let mut outcomes = Vec::new();
+        for tx in 1..=11 {
+            outcomes.push(engine.process_transaction(Transaction::dispute(tx as ClientId, tx)).await?);
+        }
+        assert_eq!(outcomes[9], ProcessOutcome::Applied);
+        assert_eq!(outcomes[10], ProcessOutcome::Ignored(IgnoreReason::TooManyOpenDisputes));
+        // resolves of open disputes aren't capped
+        assert_eq!(engine.process_transaction(Transaction::resolve(1, 1)).await?, ProcessOutcome::Applied);
+        // back at the cap after dropping below it, by a dispute and by an undone resolve
+        engine.process_transaction(Transaction::dispute(1, 1)).await?;
+        engine.process_transaction(Transaction::resolve(2, 2)).await?;
+        engine.undo_last().await?;
+
+        engine.close_warnings();
+        let mut warnings = Vec::new();
+        while let Some(warning) = receiver.recv().await {
+            if let EngineWarning::OpenDisputesNearCap { open, cap } = warning {
+                warnings.push((open, cap));
+            }
+        }
+        assert_eq!(warnings, [(9, 10), (10, 10), (10, 10), (10, 10)]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_write_open_disputes() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 1.0
+        deposit, 2, 2, 2.0
+        deposit, 1, 3, 4.0
+        deposit, 2, 4, 8.0
+        dispute, 2, 4
+        dispute, 1, 1
+        dispute, 1, 3
+        resolve, 1, 3
+        dispute, 2, 2
+        chargeback, 2, 2";
+
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = PaymentEngine::new();
+        engine.process_all(parse_transactions(Box::new(str_buf)).await?).await.into_result()?;
+
+        let mut out = Vec::new();
+        engine.write_open_disputes(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "tx,client,amount,reason,source,age_secs\n1,1,1.0000,,,\n4,2,8.0000,,,\n"
+        );
+        assert_eq!(engine.open_dispute_held()?, amount(9.0));
+        // no dispute holds nothing, not -0.0
+        assert_eq!(format!("{:.4}", PaymentEngine::new().open_dispute_held()?.to_f64()), "0.0000");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_view_open_disputes() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount, reason, timestamp
+        deposit, 1, 1, 100.0, , 1000
+        deposit, 2, 2, 5.0, , 1001
+        withdrawal, 1, 3, 90.0, , 1002
+        dispute, 2, 2, , fraud, 1003
+        dispute, 1, 1, , , 1004
+        resolve, 2, 2, , , 1005";
+        let mut engine = PaymentEngine::new().with_config(EngineConfig {
+            dispute_shortfall: DisputeShortfallPolicy::HoldPartial,
+            ..Default::default()
+        });
+        let mut transactions = parse_transactions(Box::new(stringreader::StringReader::new(csv))).await?;
+        for txn in transactions.by_ref().take(5) {
+            engine.process_transaction(txn?).await?;
+        }
+
+        let fraud = DisputeView {
+            tx: 2,
+            client: 2,
+            amount: 5.0,
+            held: 5.0,
+            timestamp: Some(1003),
+            reason: Some("fraud".to_string()),
+            source: None,
+        };
+        // only the 10.0 left after the withdrawal is held for tx 1
+        let short = DisputeView {
+            tx: 1,
+            client: 1,
+            amount: 100.0,
+            held: 10.0,
+            timestamp: Some(1004),
+            reason: None,
+            source: None,
+        };
+        assert_eq!(engine.open_disputes().collect::<Vec<_>>(), [short.clone(), fraud.clone()]);
+        assert_eq!(engine.dispute(2), Some(fraud));
+        assert_eq!(engine.dispute(3), None);
+        assert_eq!(engine.latest_timestamp(), Some(1004));
+
+        engine.process_transaction(transactions.next().expect("the resolve row")?).await?;
+        assert_eq!(engine.dispute(2), None);
+        assert_eq!(engine.open_disputes().collect::<Vec<_>>(), [short]);
+        // the dispute is aged up to the resolve row's timestamp
+        let mut out = Vec::new();
+        engine.write_open_disputes(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "tx,client,amount,reason,source,age_secs\n1,1,100.0000,,,1\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_write_locked_accounts() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 1.0
+        deposit, 2, 2, 2.0
+        deposit, 1, 3, 2.0
+        withdrawal, 1, 4, 1.5
+        withdrawal, 2, 5, 3.0
+        dispute, 1, 3
+        resolve, 1, 3
+        dispute, 2, 2
+        chargeback, 2, 2";
+        let str_buf = stringreader::StringReader::new(csv);
+        let (_, records) = parse_records(Box::new(str_buf)).await?;
+        let mut engine = PaymentEngine::new();
+        for record in records {
+            engine
+                .process_transaction_at(record.transaction?, Some(record.line))
+                .await?;
+        }
+
+        assert_eq!(
+            engine.clients[&2].locked_by,
+            Some(LockCause {
+                tx: 2,
+                amount: amount(2.0),
+                line: Some(10)
+            })
+        );
+        let mut out = Vec::new();
+        engine.write_locked_accounts(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,tx,amount,line,total,reason,tx_source\n2,2,2.0000,10,0.0000,,3\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_trace_disputes_to_the_rows_of_their_transactions() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 10.0
+        deposit, 2, 2, 5.0
+        dispute, 2, 2
+        dispute, 2, 2
+        deposit, 2, 3, 1.0";
+        let (_, records) = parse_records(Box::new(stringreader::StringReader::new(csv))).await?;
+        let file: Arc<str> = Arc::from("day1.csv");
+        let mut engine = PaymentEngine::new().with_undo_history(1);
+        let mut rejections = Vec::new();
+        for record in records {
+            let txn = record.transaction?;
+            let source = SourceRef {
+                file: Some(file.clone()),
+                line: record.line,
+            };
+            if let ProcessOutcome::Ignored(reason) = engine.process_transaction_from(txn.clone(), Some(source)).await? {
+                rejections.push(engine.rejection(&txn, reason).detail);
+            }
+        }
+
+        let deposit = SourceRef {
+            file: Some(file),
+            line: 3,
+        };
+        assert_eq!(engine.source_of(2).await?, Some(deposit.clone()));
+        assert_eq!(engine.dispute(2).and_then(|dispute| dispute.source), Some(deposit));
+        assert_eq!(rejections, ["already_disputed (tx 2 at day1.csv:3)"]);
+        let mut out = Vec::new();
+        engine.write_open_disputes(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "tx,client,amount,reason,source,age_secs\n2,2,5.0000,,day1.csv:3,\n"
+        );
+
+        // undoing the last deposit forgets where it was read from
+        let source = engine.source_of(3).await?;
+        assert_eq!(source.as_ref().map(SourceRef::location), Some("day1.csv:6".to_string()));
+        engine.undo_last().await?;
+        assert_eq!(engine.source_of(3).await?, None);
+        engine.process_transaction(Transaction::deposit(2, 3, 1.0)).await?;
+        assert_eq!(engine.source_of(3).await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_visit_client_states_until_told_to_stop() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 9, 1, 9.0
+        deposit, 2, 2, 2.0
+        deposit, 5, 3, 5.0";
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = PaymentEngine::new();
+        engine.process_all(parse_transactions(Box::new(str_buf)).await?).await.into_result()?;
+        assert_eq!(engine.clients_len(), 3);
+
+        let mut visited = Vec::new();
+        let flow = engine.for_each_client_state(|view| {
+            visited.push(view.client);
+            ControlFlow::Continue(())
+        });
+        assert_eq!(flow, ControlFlow::Continue(()));
+        assert_eq!(visited, [2, 5, 9]);
+
+        // breaking at the second client never visits the third
+        let mut calls = 0;
+        let flow = engine.for_each_client_state(|view| {
+            calls += 1;
+            if view.client == 5 {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        });
+        assert_eq!(flow, ControlFlow::Break(()));
+        assert_eq!(calls, 2);
+        let empty = PaymentEngine::new();
+        assert_eq!(empty.for_each_client_state(|_| ControlFlow::Break(())), ControlFlow::Continue(()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_list_clients_in_first_seen_order() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        withdrawal, 7, 1, 1.0
+        deposit, 5, 2, 1.0
+        deposit, 2, 3, 1.0
+        deposit, 5, 4, 1.0
+        deposit, 9, 5, 1.0";
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = PaymentEngine::new().with_undo_history(10);
+        engine.process_all(parse_transactions(Box::new(str_buf)).await?).await.into_result()?;
+
+        assert_eq!(engine.client_ids(OutputOrder::ClientId), vec![2, 5, 9]);
+        assert_eq!(engine.client_ids(OutputOrder::FirstSeen), vec![5, 2, 9]);
+        let mut out = Vec::new();
+        engine.write_client_states(&mut out, None, OutputOrder::FirstSeen).unwrap();
+        let ids: Vec<&str> = std::str::from_utf8(&out)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').next().unwrap())
+            .collect();
+        assert_eq!(ids, vec!["5", "2", "9"]);
+
+        // undoing the deposit that created client 9 forgets it was seen
+        engine.undo_last().await?;
+        assert_eq!(engine.client_ids(OutputOrder::FirstSeen), vec![5, 2]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_hold_deposits_to_locked_accounts() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 5.0
+        deposit, 1, 2, 2.0
+        dispute, 1, 2
+        chargeback, 1, 2
+        deposit, 1, 3, 4.0";
+
+        // rejected by default
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = PaymentEngine::new();
+        let mut outcomes = Vec::new();
+        for txn in parse_transactions(Box::new(str_buf)).await? {
+            outcomes.push(engine.process_transaction(txn?).await?);
+        }
+        assert_eq!(outcomes[4], ProcessOutcome::Ignored(IgnoreReason::AccountLocked));
+        let client = engine.clients[&1];
+        assert_eq!((client.available.to_f64(), client.held.to_f64(), client.total.to_f64()), (5.0, 0.0, 5.0));
+        assert!(engine.transactions.get(3).await?.is_none());
+
+        // held with the policy on
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = PaymentEngine::new().with_locked_deposit_policy(LockedDepositPolicy::Hold);
+        let mut outcomes = Vec::new();
+        for txn in parse_transactions(Box::new(str_buf)).await? {
+            outcomes.push(engine.process_transaction(txn?).await?);
+        }
+        assert_eq!(outcomes[4], ProcessOutcome::Applied);
+        let client = engine.clients[&1];
+        assert!(client.locked);
+        assert_eq!((client.available.to_f64(), client.held.to_f64(), client.total.to_f64()), (5.0, 4.0, 9.0));
+        assert!(engine.transactions.get(3).await?.is_some());
+        assert!(engine.check_invariants().is_empty());
+
+        // unlocking releases the held deposits
+        assert!(engine.unlock(1)?);
+        let client = engine.clients[&1];
+        assert!(!client.locked);
+        assert_eq!(client.locked_by, None);
+        assert_eq!((client.available.to_f64(), client.held.to_f64(), client.total.to_f64()), (9.0, 0.0, 9.0));
+        assert!(!engine.unlock(1)?);
+        assert!(!engine.unlock(2)?);
+        Ok(())
+    }
+
+    const UNKNOWN_CLIENTS: &str = "type, client, tx, amount
+        deposit, 1, 1, 5.0
+        deposit, 2, 2, 3.0
+        deposit, 3, 3, 4.0
+        withdrawal, 4, 4, 1.0
+        dispute, 4, 1,
+        dispute, 1, 1,";
+
+    /// Returns the client and detail of every rejection of a batch.
+    fn rejected(batch: &BatchResult) -> Vec<(Option<ClientId>, &str)> {
+        batch
+            .rejections
+            .iter()
+            .map(|rejection| (rejection.record.client, rejection.record.detail.as_str()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn unknown_clients_are_reported_when_deposits_open_accounts() -> Result<(), PaymentError> {
+        let str_buf = stringreader::StringReader::new(UNKNOWN_CLIENTS);
+        let mut engine = PaymentEngine::new();
+        let batch = engine.process_all(parse_transactions(Box::new(str_buf)).await?).await;
+        assert_eq!(batch.applied, 4);
+        assert_eq!(
+            rejected(&batch),
+            [(Some(4), "unknown_client"), (Some(4), "client_mismatch")]
+        );
+        assert_eq!(engine.clients.len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_onboarded_clients_transact_from_the_initial_state_only() -> Result<(), PaymentError> {
+        let mut seeded = PaymentEngine::new();
+        seeded.process_transaction(Transaction::deposit(1, 100, 1.0)).await?;
+        let mut engine = PaymentEngine::new().with_config(EngineConfig {
+            client_creation: ClientCreationPolicy::FromInitialStateOnly,
+            onboarded_clients: [2].into_iter().collect(),
+            ..Default::default()
+        });
+        engine.load_clients_json(&seeded.clients_json()?)?;
+
+        let str_buf = stringreader::StringReader::new(UNKNOWN_CLIENTS);
+        let batch = engine.process_all(parse_transactions(Box::new(str_buf)).await?).await;
+        assert_eq!(batch.applied, 3);
+        assert_eq!(
+            rejected(&batch),
+            [
+                (Some(3), "unknown_client: not onboarded"),
+                (Some(4), "unknown_client: not onboarded"),
+                (Some(4), "unknown_client: not onboarded"),
+            ]
+        );
+        assert_eq!(engine.client_ids(OutputOrder::ClientId), [1, 2]);
+        let client = engine.clients[&1];
+        assert_eq!((client.available, client.held), (amount(1.0), amount(5.0)));
+        assert_eq!(engine.clients[&2].total, amount(3.0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chargebacks_leave_a_status_reason_until_unlocked() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount, reason
+        deposit, 1, 1, 5.0,
+        deposit, 1, 2, 2.0,
+        dispute, 1, 2,,
+        chargeback, 1, 2,, fraud
+        deposit, 2, 3, 1.0,";
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = PaymentEngine::new();
+        engine.process_all(parse_transactions(Box::new(str_buf)).await?).await;
+        let reason = engine.clients[&1].status_reason;
+        assert_eq!(reason.as_ref().map(StatusReason::as_str), Some("chargeback of tx 2: fraud"));
+        assert_eq!(engine.clients[&2].status_reason, None);
+
+        // kept through a clients dump
+        let json = engine.clients_json()?;
+        assert!(json.contains("\"status_reason\":\"chargeback of tx 2: fraud\""));
+        let mut restored = PaymentEngine::new();
+        restored.load_clients_json(&json)?;
+        assert_eq!(restored.clients[&1].status_reason, reason);
+        assert_eq!(restored.clients[&2].status_reason, None);
+
+        assert!(restored.unlock(1)?);
+        assert_eq!(restored.clients[&1].status_reason, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_hold_release_and_capture_escrow() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 10.0
+        hold, 1, 2, 4.0
+        hold, 1, 3, 7.0
+        hold, 1, 2, 1.0
+        release, 1, 2
+        release, 1, 2
+        hold, 1, 4, 3.0
+        capture, 2, 4
+        capture, 1, 4
+        capture, 1, 4
+        hold, 3, 5, 1.0";
+
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = PaymentEngine::new().with_undo_history(20);
+        let mut outcomes = Vec::new();
+        let mut balances = Vec::new();
+        for txn in parse_transactions(Box::new(str_buf)).await? {
+            outcomes.push(engine.process_transaction(txn?).await?);
+            let client = engine.clients[&1];
+            balances.push((client.available.to_f64(), client.held.to_f64(), client.total.to_f64()));
+        }
+
+        assert_eq!(
+            outcomes,
+            vec![
+                ProcessOutcome::Applied,
+                ProcessOutcome::Applied,
+                ProcessOutcome::Ignored(IgnoreReason::InsufficientFunds),
+                ProcessOutcome::Ignored(IgnoreReason::AlreadyHeld),
+                ProcessOutcome::Applied,
+                ProcessOutcome::Ignored(IgnoreReason::NotHeld),
+                ProcessOutcome::Applied,
+                ProcessOutcome::Ignored(IgnoreReason::ClientMismatch),
+                ProcessOutcome::Applied,
+                ProcessOutcome::Ignored(IgnoreReason::NotHeld),
+                ProcessOutcome::Ignored(IgnoreReason::UnknownClient),
+            ]
+        );
+        assert_eq!(balances[1], (6.0, 4.0, 10.0));
+        assert_eq!(balances[4], (10.0, 0.0, 10.0));
+        assert_eq!(balances[6], (7.0, 3.0, 10.0));
+        assert_eq!(balances[8], (7.0, 0.0, 7.0));
+        assert!(engine.escrow_holds.is_empty());
+        assert_eq!(
+            engine.transactions.get(4).await?.map(|txn| txn.r#type),
+            Some(TransactionType::Withdrawal)
+        );
+
+        // undoing the capture reopens the hold
+        engine.undo_last().await?;
+        assert!(engine.escrow_holds.contains_key(&4));
+        assert!(engine.transactions.get(4).await?.is_none());
+        assert_eq!(engine.clients[&1].held, 3.0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stale_holds_expire_with_the_timestamps_of_the_rows() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount, timestamp
+        deposit, 1, 1, 10.0, 100
+        deposit, 2, 2, 10.0, 100
+        hold, 1, 3, 4.0, 100
+        hold, 1, 4, 2.0, 130
+        hold, 2, 5, 1.0,
+        deposit, 2, 6, 1.0, 160
+        deposit, 2, 7, 1.0, 161
+        capture, 1, 3, , 162
+        capture, 1, 4, , 162";
+
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = PaymentEngine::new()
+            .with_config(EngineConfig {
+                hold_ttl_secs: Some(60),
+                ..Default::default()
+            })
+            .with_undo_history(10);
+        let mut outcomes = Vec::new();
+        for txn in parse_transactions(Box::new(str_buf)).await? {
+            outcomes.push(engine.process_transaction(txn?).await?);
+            if outcomes.len() == 6 {
+                // 60s after tx 3 isn't more than its ttl
+                assert_eq!(engine.clients[&1].held, 6.0);
+            }
+        }
+
+        // tx 3 expired with the row of tx 7, on another client's account, and can't be captured
+        assert_eq!(outcomes[7], ProcessOutcome::Ignored(IgnoreReason::NotHeld));
+        assert_eq!(outcomes[8], ProcessOutcome::Applied);
+        assert_eq!(engine.expired_holds(), 1);
+        let client = engine.clients[&1];
+        assert_eq!((client.available, client.held, client.total), (amount(8.0), Amount::ZERO, amount(8.0)));
+        // a hold without a timestamp never expires
+        assert!(engine.escrow_holds.contains_key(&5));
+        assert_eq!(engine.total_held(), 1.0);
+        assert!(engine.check_totals().is_none());
+        // the expiry can't be undone, nor what came before it: only the capture and tx 7 can
+        assert!(engine.undo_last().await?.is_some());
+        assert!(engine.undo_last().await?.is_some());
+        assert!(engine.undo_last().await?.is_none());
+        assert_eq!(engine.clients[&1].held, amount(2.0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_settle_and_cancel_pending_withdrawals() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 10.0
+        withdrawal_pending, 1, 2, 4.0
+        withdrawal_pending, 1, 3, 7.0
+        withdrawal_pending, 1, 2, 1.0
+        withdrawal_settle, 2, 2
+        withdrawal_settle, 1, 2
+        withdrawal_settle, 1, 2
+        withdrawal_cancel, 1, 2
+        withdrawal_pending, 1, 4, 3.0
+        withdrawal_cancel, 1, 4
+        withdrawal_settle, 1, 4
+        withdrawal_pending, 1, 5, 1.0";
+
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = PaymentEngine::new().with_undo_history(20);
+        let mut outcomes = Vec::new();
+        let mut balances = Vec::new();
+        for txn in parse_transactions(Box::new(str_buf)).await? {
+            outcomes.push(engine.process_transaction(txn?).await?);
+            let client = engine.clients[&1];
+            balances.push((client.available.to_f64(), client.held.to_f64(), client.total.to_f64()));
+        }
+
+        assert_eq!(
+            outcomes,
+            vec![
+                ProcessOutcome::Applied,
+                ProcessOutcome::Applied,
+                ProcessOutcome::Ignored(IgnoreReason::InsufficientFunds),
+                ProcessOutcome::Ignored(IgnoreReason::AlreadyPending),
+                ProcessOutcome::Ignored(IgnoreReason::ClientMismatch),
+                ProcessOutcome::Applied,
+                ProcessOutcome::Ignored(IgnoreReason::NotPending),
+                ProcessOutcome::Ignored(IgnoreReason::NotPending),
+                ProcessOutcome::Applied,
+                ProcessOutcome::Applied,
+                ProcessOutcome::Ignored(IgnoreReason::NotPending),
+                ProcessOutcome::Applied,
+            ]
+        );
+        assert_eq!(balances[1], (6.0, 4.0, 10.0));
+        assert_eq!(balances[5], (6.0, 0.0, 6.0));
+        assert_eq!(balances[8], (3.0, 3.0, 6.0));
+        assert_eq!(balances[9], (6.0, 0.0, 6.0));
+        assert_eq!(engine.clients[&1].pending_out, 1.0);
+        assert!(engine.check_invariants().is_empty());
+        assert_eq!(
+            engine.transactions.get(2).await?.map(|txn| txn.r#type),
+            Some(TransactionType::Withdrawal)
+        );
+        assert!(engine.transactions.get(4).await?.is_none());
+
+        // undoing the last pending withdrawal and the cancel brings back the one cancelled
+        engine.undo_last().await?;
+        engine.undo_last().await?;
+        assert!(engine.pending_withdrawals.contains_key(&4));
+        assert!(!engine.pending_withdrawals.contains_key(&5));
+        let client = engine.clients[&1];
+        assert_eq!((client.held.to_f64(), client.pending_out.to_f64()), (3.0, 3.0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pending_withdrawals_on_locked_accounts() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 10.0
+        deposit, 1, 2, 1.0
+        withdrawal_pending, 1, 3, 4.0
+        withdrawal_pending, 1, 4, 2.0
+        dispute, 1, 2
+        chargeback, 1, 2
+        withdrawal_pending, 1, 5, 1.0
+        withdrawal_settle, 1, 4
+        withdrawal_cancel, 1, 3";
+
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = PaymentEngine::new();
+        let mut outcomes = Vec::new();
+        for txn in parse_transactions(Box::new(str_buf)).await? {
+            outcomes.push(engine.process_transaction(txn?).await?);
+        }
+
+        assert_eq!(
+            outcomes[6..],
+            [
+                ProcessOutcome::Ignored(IgnoreReason::AccountLocked),
+                ProcessOutcome::Applied,
+                ProcessOutcome::Applied,
+            ]
+        );
+        let client = engine.clients[&1];
+        assert_eq!((client.available.to_f64(), client.held.to_f64(), client.total.to_f64()), (8.0, 0.0, 8.0));
+        assert_eq!(client.pending_out, 0.0);
+        assert!(engine.pending_withdrawals.is_empty());
+        assert!(engine.check_invariants().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn escrow_on_locked_accounts() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 10.0
+        deposit, 1, 2, 1.0
+        hold, 1, 3, 4.0
+        hold, 1, 4, 2.0
+        dispute, 1, 2
+        chargeback, 1, 2
+        hold, 1, 5, 1.0
+        capture, 1, 4
+        release, 1, 3";
+
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = PaymentEngine::new();
+        let mut outcomes = Vec::new();
+        for txn in parse_transactions(Box::new(str_buf)).await? {
+            outcomes.push(engine.process_transaction(txn?).await?);
+        }
+
+        assert_eq!(
+            outcomes[6..],
+            [
+                ProcessOutcome::Ignored(IgnoreReason::AccountLocked),
+                ProcessOutcome::Ignored(IgnoreReason::AccountLocked),
+                ProcessOutcome::Applied,
+            ]
+        );
+        let client = engine.clients[&1];
+        assert_eq!((client.available.to_f64(), client.held.to_f64(), client.total.to_f64()), (8.0, 2.0, 10.0));
+        assert!(engine.check_invariants().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_report_dispute_reasons() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount, reason
+        deposit, 1, 1, 1.0,
+        deposit, 1, 2, 2.0
+        deposit, 2, 3, 4.0
+        deposit, 3, 4, 8.0
+        dispute, 1, 1, , fraud
+        dispute, 1, 2
+        dispute, 2, 3, , product_not_received
+        chargeback, 2, 3, ,\"duplicate, confirmed\"
+        dispute, 3, 4, , duplicate
+        chargeback, 3, 4";
+
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = PaymentEngine::new();
+        engine.process_all(parse_transactions(Box::new(str_buf)).await?).await.into_result()?;
+
+        let mut out = Vec::new();
+        engine.write_open_disputes(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "tx,client,amount,reason,source,age_secs\n1,1,1.0000,fraud,,\n2,1,2.0000,,,\n"
+        );
+        // the chargeback's own reason wins over the dispute's
+        let mut out = Vec::new();
+        engine.write_locked_accounts(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,tx,amount,line,total,reason,tx_source
+2,3,4.0000,,0.0000,\"duplicate, confirmed\",
+3,4,8.0000,,0.0000,duplicate,
+"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_write_merchant_stats() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount, merchant
+        deposit, 1, 1, 10.0, acme
+        deposit, 1, 2, 20.0, acme
+        deposit, 2, 3, 5.0, acme
+        deposit, 2, 4, 8.0, globex
+        deposit, 3, 5, 1.0, initech
+        deposit, 3, 6, 3.0, initech
+        deposit, 3, 7, 4.0
+        dispute, 1, 1
+        chargeback, 1, 1
+        dispute, 2, 3
+        resolve, 2, 3
+        dispute, 3, 5
+        chargeback, 3, 5
+        dispute, 3, 6
+        dispute, 3, 7
+        chargeback, 3, 7";
+
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = PaymentEngine::new().with_undo_history(10);
+        engine.process_all(parse_transactions(Box::new(str_buf)).await?).await.into_result()?;
+
+        let rates: Vec<f64> = ["acme", "globex", "initech"]
+            .iter()
+            .map(|merchant| engine.merchants.get(merchant).unwrap().chargeback_rate())
+            .collect();
+        assert_eq!(rates, vec![1.0 / 3.0, 0.0, 0.5]);
+        // the stored deposits of a merchant share one copy of its name
+        let first = engine.stored(1).await?.and_then(|deposit| deposit.merchant);
+        let third = engine.stored(3).await?.and_then(|deposit| deposit.merchant);
+        assert!(matches!((first, third), (Some(first), Some(third)) if Arc::ptr_eq(&first, &third)));
+        let mut out = Vec::new();
+        engine.write_merchant_stats(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "merchant,deposits,deposit_volume,disputes,chargebacks,chargeback_volume,chargeback_rate
+acme,3,35.0000,2,1,10.0000,0.3333
+globex,1,8.0000,0,0,0.0000,0.0000
+initech,2,4.0000,2,1,1.0000,0.5000
+"
+        );
+
+        // undoing the last chargeback of initech takes it back out
+        engine.undo_last().await?;
+        engine.undo_last().await?;
+        engine.undo_last().await?;
+        let initech = engine.merchants.get("initech").unwrap();
+        assert_eq!((initech.disputes, initech.chargebacks), (1, 1));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn blocklisted_clients_keep_their_dispute_lifecycle() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 10.0
+        deposit, 2, 2, 5.0";
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = PaymentEngine::new();
+        engine.process_all(parse_transactions(Box::new(str_buf)).await?).await.into_result()?;
+
+        // the blocklist is updated after the first deposits were made
+        let blocked = &mut engine.config.blocked_clients;
+        blocked.insert(1, Some("mule account".to_owned()));
+        blocked.insert(3, None);
+        let csv = "type, client, tx, amount
+        deposit, 1, 3, 1.0
+        withdrawal, 1, 4, 1.0
+        deposit, 3, 5, 1.0
+        dispute, 1, 1
+        chargeback, 1, 1
+        withdrawal, 2, 6, 1.0";
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut outcomes = Vec::new();
+        for txn in parse_transactions(Box::new(str_buf)).await? {
+            outcomes.push(engine.process_transaction(txn?).await?);
+        }
+
+        assert_eq!(
+            outcomes,
+            vec![
+                ProcessOutcome::Ignored(IgnoreReason::Blocklisted),
+                ProcessOutcome::Ignored(IgnoreReason::Blocklisted),
+                ProcessOutcome::Ignored(IgnoreReason::Blocklisted),
+                ProcessOutcome::Applied,
+                ProcessOutcome::Applied,
+                ProcessOutcome::Applied,
+            ]
+        );
+        assert_eq!(
+            engine.describe_ignored(1, IgnoreReason::Blocklisted),
+            "blocklisted: mule account"
+        );
+        assert_eq!(engine.describe_ignored(3, IgnoreReason::Blocklisted), "blocklisted");
+        let client = engine.clients[&1];
+        assert!(client.locked);
+        assert_eq!(client.total, 0.0);
+        assert!(!engine.clients.contains_key(&3));
+
+        // nor can they take money out through a pending withdrawal
+        engine.config.blocked_clients.insert(2, None);
+        let pending = engine.process_transaction(Transaction::withdrawal_pending(2, 7, 4.0)).await?;
+        assert_eq!(pending, ProcessOutcome::Ignored(IgnoreReason::Blocklisted));
+        let settle = engine.process_transaction(Transaction::withdrawal_settle(2, 7)).await?;
+        assert_eq!(settle, ProcessOutcome::Ignored(IgnoreReason::NotPending));
+        // or through an escrow hold and its capture
+        let hold = engine.process_transaction(Transaction::hold(2, 8, 4.0)).await?;
+        assert_eq!(hold, ProcessOutcome::Ignored(IgnoreReason::Blocklisted));
+        let capture = engine.process_transaction(Transaction::capture(2, 8)).await?;
+        assert_eq!(capture, ProcessOutcome::Ignored(IgnoreReason::NotHeld));
+        assert_eq!(engine.clients[&2].total, 4.0);
+        assert_eq!(engine.clients[&2].held, 0.0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deposits_are_capped() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 9000.0
+        deposit, 1, 2, 1000.0
+        withdrawal, 1, 3, 100.0
+        dispute, 1, 1
+        resolve, 1, 1
+        deposit, 1, 4, 100.0001
+        deposit, 2, 5, 10000.0001
+        deposit, 3, 6, 25000.0";
+        let mut config = EngineConfig {
+            max_client_balance: Some(10000.0),
+            ..Default::default()
+        };
+        config.client_balance_caps.insert(3, None);
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = PaymentEngine::new().with_config(config);
+        let mut outcomes = Vec::new();
+        for txn in parse_transactions(Box::new(str_buf)).await? {
+            outcomes.push(engine.process_transaction(txn?).await?);
+        }
+
+        let capped = ProcessOutcome::Ignored(IgnoreReason::BalanceCapExceeded);
+        assert_eq!(
+            outcomes,
+            vec![
+                ProcessOutcome::Applied,
+                ProcessOutcome::Applied,
+                ProcessOutcome::Applied,
+                ProcessOutcome::Applied,
+                ProcessOutcome::Applied,
+                capped,
+                capped,
+                ProcessOutcome::Applied,
+            ]
+        );
+        assert_eq!(engine.clients[&1].total, 9900.0);
+        assert_eq!(
+            engine.describe_ignored(1, IgnoreReason::BalanceCapExceeded),
+            "balance_cap_exceeded: headroom 100.0000"
+        );
+        assert_eq!(engine.clients[&3].total, 25000.0);
+        // a new client's capped deposit opens no account
+        assert!(!engine.clients.contains_key(&2));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_flag_or_reject_duplicate_deposits() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount, timestamp
+        deposit, 1, 1, 10.0, 1000
+        deposit, 1, 2, 10.0, 1003
+        deposit, 1, 3, 10.0, 1009
+        deposit, 1, 4, 10.5, 1010
+        deposit, 2, 5, 10.5, 1011
+        deposit, 2, 6, 10.5
+        deposit, 2, 7, 10.5, 1016";
+        let engine_with = |action| {
+            PaymentEngine::new().with_config(EngineConfig {
+                duplicate_deposits: Some(DuplicateDetection {
+                    window_secs: 5,
+                    action,
+                }),
+                ..Default::default()
+            })
+        };
+
+        // flagged: tx 3 is 6s after tx 2, tx 4 has another amount, tx 6 has no timestamp
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = engine_with(DuplicateAction::Flag).with_undo_history(10);
+        for txn in parse_transactions(Box::new(str_buf)).await? {
+            assert_eq!(engine.process_transaction(txn?).await?, ProcessOutcome::Applied);
+        }
+        assert_eq!(engine.clients[&1].total, 40.5);
+        let mut out = Vec::new();
+        engine.write_suspected_duplicates(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,original_tx,tx,amount,delta_secs\n1,1,2,10.0000,3\n2,5,7,10.5000,5\n"
+        );
+        engine.undo_last().await?;
+        assert_eq!(engine.suspected_duplicates.len(), 1);
+
+        // rejected: the rejected deposit isn't remembered, tx 3 is compared to tx 1
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = engine_with(DuplicateAction::Reject);
+        let mut outcomes = Vec::new();
+        for txn in parse_transactions(Box::new(str_buf)).await? {
+            outcomes.push(engine.process_transaction(txn?).await?);
+        }
+        let rejected = ProcessOutcome::Ignored(IgnoreReason::SuspectedDuplicate);
+        assert_eq!(outcomes[1], rejected);
+        assert_eq!(outcomes[2], ProcessOutcome::Applied);
+        assert_eq!(outcomes[6], rejected);
+        assert!(engine.suspected_duplicates.is_empty());
+        assert_eq!(engine.clients[&1].total, 30.5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chargebacks_debit_the_fee() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 10.0
+        deposit, 1, 2, 5.0
+        dispute, 1, 2
+        chargeback, 1, 2
+        deposit, 2, 3, 20.0
+        dispute, 2, 3
+        resolve, 2, 3
+        chargeback, 2, 3
+        chargeback, 2, 9";
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = PaymentEngine::new().with_config(EngineConfig {
+            chargeback_fee: Some(15.0),
+            ..Default::default()
+        });
+        engine.process_all(parse_transactions(Box::new(str_buf)).await?).await.into_result()?;
+
+        let client = engine.clients[&1];
+        assert!(client.locked);
+        assert_eq!((client.available.to_f64(), client.held.to_f64(), client.total.to_f64()), (-5.0, 0.0, -5.0));
+        // the rejected chargebacks of client 2 cost nothing
+        let client = engine.clients[&2];
+        assert_eq!((client.available.to_f64(), client.total.to_f64(), client.chargeback_fees.to_f64()), (20.0, 20.0, 0.0));
+        assert_eq!(engine.collected_chargeback_fees()?, amount(15.0));
+        assert!(engine.check_invariants().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deposits_pay_down_debt() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 10.0
+        dispute, 1, 1
+        chargeback, 1, 1
+        deposit, 1, 2, 4.0
+        deposit, 2, 3, 10.0
+        withdrawal, 2, 4, 8.0
+        dispute, 2, 3";
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = PaymentEngine::new()
+            .with_config(EngineConfig {
+                chargeback_fee: Some(15.0),
+                locked_deposits: LockedDepositPolicy::Hold,
+                ..Default::default()
+            })
+            .with_undo_history(10);
+        engine.process_all(parse_transactions(Box::new(str_buf)).await?).await.into_result()?;
+
+        // the deposit while locked pays down the fee rather than being held
+        let client = engine.clients[&1];
+        assert_eq!((client.debt.to_f64(), client.debt_repaid.to_f64()), (11.0, 4.0));
+        assert_eq!((client.available.to_f64(), client.held.to_f64(), client.total.to_f64()), (-11.0, 0.0, -11.0));
+        // disputing funds already withdrawn takes client 2's available below zero, but the funds
+        // are on hold, so it owes nothing
+        let client = engine.clients[&2];
+        assert_eq!((client.available.to_f64(), client.total.to_f64(), client.debt.to_f64()), (-8.0, 2.0, 0.0));
+        let mut out = Vec::new();
+        engine.write_debtors_report(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,debt,repaid,total,locked\n1,11.0000,4.0000,-11.0000,true\n"
+        );
+
+        assert!(engine.unlock(1)?);
+        let csv = "type, client, tx, amount
+        deposit, 1, 5, 6.0
+        deposit, 1, 6, 8.0
+        resolve, 2, 3";
+        let str_buf = stringreader::StringReader::new(csv);
+        engine.process_all(parse_transactions(Box::new(str_buf)).await?).await.into_result()?;
+
+        let client = engine.clients[&1];
+        assert_eq!((client.debt.to_f64(), client.debt_repaid.to_f64()), (0.0, 15.0));
+        assert_eq!((client.available.to_f64(), client.total.to_f64()), (3.0, 3.0));
+        // the resolve gives the disputed funds back, which isn't a repayment
+        let client = engine.clients[&2];
+        assert_eq!((client.debt.to_f64(), client.debt_repaid.to_f64()), (0.0, 0.0));
+        let mut out = Vec::new();
+        engine.write_debtors_report(&mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "client,debt,repaid,total,locked\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn representment_reverses_the_chargeback() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 10.0
+        deposit, 1, 2, 5.0
+        deposit, 1, 3, 2.0
+        dispute, 1, 2
+        chargeback, 1, 2
+        dispute, 1, 3
+        chargeback, 1, 3
+        representment, 1, 2
+        representment, 1, 2
+        representment, 1, 1";
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = PaymentEngine::new().with_undo_history(10);
+        let mut outcomes = Vec::new();
+        for txn in parse_transactions(Box::new(str_buf)).await? {
+            outcomes.push(engine.process_transaction(txn?).await?);
+        }
+
+        assert_eq!(
+            outcomes[7..],
+            [
+                ProcessOutcome::Applied,
+                ProcessOutcome::Ignored(IgnoreReason::NotChargedBack),
+                ProcessOutcome::Ignored(IgnoreReason::NotChargedBack),
+            ]
+        );
+        // the chargeback of tx 3 still keeps the account locked
+        let client = engine.clients[&1];
+        assert_eq!((client.available.to_f64(), client.held.to_f64(), client.total.to_f64()), (15.0, 0.0, 15.0));
+        assert_eq!(client.chargebacks, 1);
+        assert!(client.locked);
+
+        let str_buf = stringreader::StringReader::new("type,client,tx,amount\nrepresentment,1,3");
+        for txn in parse_transactions(Box::new(str_buf)).await? {
+            assert_eq!(engine.process_transaction(txn?).await?, ProcessOutcome::Applied);
+        }
+        let client = engine.clients[&1];
+        assert_eq!((client.available.to_f64(), client.total.to_f64(), client.chargebacks), (17.0, 17.0, 0));
+        assert!(!client.locked);
+        assert_eq!(client.locked_by, None);
+        assert!(engine.charged_back_transactions.is_empty());
+
+        // undoing the representment charges tx 3 back again
+        engine.undo_last().await?;
+        assert!(engine.clients[&1].locked);
+        assert!(engine.charged_back_transactions.contains_key(&3));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn representment_gives_back_only_what_the_chargeback_took() -> Result<(), PaymentError> {
+        // the chargeback takes 10 from a total of 2, and zeroes the 8 left below zero
+        let mut engine = PaymentEngine::new().with_undo_history(10);
+        for txn in [
+            Transaction::deposit(1, 1, 10.0),
+            Transaction::withdrawal(1, 2, 8.0),
+            Transaction::dispute(1, 1),
+            Transaction::chargeback(1, 1),
+            Transaction::representment(1, 1),
+        ] {
+            assert_eq!(engine.process_transaction(txn).await?, ProcessOutcome::Applied);
+        }
+        let client = engine.clients[&1];
+        assert_eq!((client.available.to_f64(), client.held.to_f64(), client.total.to_f64()), (2.0, 0.0, 2.0));
+        assert!(!client.locked);
+        assert!(engine.check_ledger().balances());
+
+        // undone, the chargeback's write-off is back for the next representment
+        engine.undo_last().await?;
+        assert_eq!(engine.clients[&1].total, 0.0);
+        engine.process_transaction(Transaction::representment(1, 1)).await?;
+        assert_eq!(engine.clients[&1].total, 2.0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn undone_chargebacks_take_their_reason_back() -> Result<(), PaymentError> {
+        let mut engine = PaymentEngine::new().with_undo_history(10);
+        for txn in [
+            Transaction::deposit(1, 1, 10.0),
+            Transaction::dispute(1, 1),
+            Transaction::chargeback(1, 1).with_reason("fraud"),
+            Transaction::representment(1, 1),
+        ] {
+            assert_eq!(engine.process_transaction(txn).await?, ProcessOutcome::Applied);
+        }
+        assert!(engine.chargeback_reasons.is_empty());
+
+        // undoing the representment puts the reason back, undoing the chargeback drops it
+        engine.undo_last().await?;
+        assert_eq!(engine.chargeback_reasons.get(&1).map(String::as_str), Some("fraud"));
+        engine.undo_last().await?;
+        assert!(engine.chargeback_reasons.is_empty());
+        // so charging it back anew without a reason leaves none
+        engine.process_transaction(Transaction::chargeback(1, 1)).await?;
+        assert!(engine.chargeback_reasons.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn memos_are_stored_up_to_the_cap() -> Result<(), PaymentError> {
+        let csv = "type,client,tx,amount,memo
+deposit,1,1,10.0,invoice 42
+deposit,1,2,5.0,refund of the duplicate invoice 42
+withdrawal,1,3,1.0,";
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = PaymentEngine::new().with_config(EngineConfig {
+            max_memo_len: 10,
+            ..Default::default()
+        });
+        engine.process_all(parse_transactions(Box::new(str_buf)).await?).await.into_result()?;
+
+        let memo = engine.transactions.get(1).await?.and_then(|txn| txn.memo);
+        assert_eq!(memo.as_deref(), Some("invoice 42"));
+        let memo = engine.transactions.get(2).await?.and_then(|txn| txn.memo);
+        assert_eq!(memo.as_deref(), Some("refund of [...]"));
+        let memo = engine.transactions.get(3).await?.and_then(|txn| txn.memo);
+        assert_eq!(memo, None);
+        Ok(())
+    }
+
+    async fn run_shortfall_scenario(
+        policy: DisputeShortfallPolicy,
+        rows: &str,
+    ) -> Result<(PaymentEngine, Vec<ProcessOutcome>), PaymentError> {
+        let csv = format!("type,client,tx,amount\ndeposit,1,1,100.0\nwithdrawal,1,2,90.0\ndispute,1,1\n{}", rows);
+        let str_buf = std::io::Cursor::new(csv);
+        let mut engine = PaymentEngine::new()
+            .with_config(EngineConfig {
+                dispute_shortfall: policy,
+                ..Default::default()
+            })
+            .with_undo_history(10);
+        let mut outcomes = Vec::new();
+        for txn in parse_transactions(Box::new(str_buf)).await? {
+            outcomes.push(engine.process_transaction(txn?).await?);
+        }
+        assert!(engine.check_invariants().is_empty());
+        Ok((engine, outcomes))
+    }
+
+    #[tokio::test]
+    async fn dispute_shortfall_policies() -> Result<(), PaymentError> {
+        use DisputeShortfallPolicy::{Allow, Freeze, HoldPartial};
+        let balances = |engine: &PaymentEngine| {
+            let client = engine.clients[&1];
+            (client.available.to_f64(), client.held.to_f64(), client.total.to_f64())
+        };
+
+        for (policy, disputed, charged_back) in [
+            (Allow, (-90.0, 100.0, 10.0), (0.0, 0.0, 0.0)),
+            (HoldPartial, (0.0, 10.0, 10.0), (0.0, 0.0, 0.0)),
+            (Freeze, (0.0, 10.0, 10.0), (-90.0, 0.0, -90.0)),
+        ] {
+            let (engine, _) = run_shortfall_scenario(policy, "").await?;
+            assert_eq!(balances(&engine), disputed, "{:?}", policy);
+            assert_eq!(engine.clients[&1].frozen, policy == Freeze);
+            assert_eq!(engine.open_dispute_held()?.to_f64(), disputed.1, "{:?}", policy);
+
+            let (engine, _) = run_shortfall_scenario(policy, "resolve,1,1").await?;
+            assert_eq!(balances(&engine), (10.0, 0.0, 10.0), "{:?}", policy);
+            assert_eq!(engine.clients[&1].dispute_shortfall, 0.0);
+            assert!(!engine.clients[&1].frozen);
+
+            let (engine, _) = run_shortfall_scenario(policy, "chargeback,1,1").await?;
+            assert_eq!(balances(&engine), charged_back, "{:?}", policy);
+            assert!(!engine.clients[&1].frozen);
+        }
+
+        // the partial hold writes the shortfall off, and a representment only gives back what was taken
+        let (engine, _) = run_shortfall_scenario(HoldPartial, "chargeback,1,1").await?;
+        assert_eq!(engine.clients[&1].shortfall_written_off, 90.0);
+        let (engine, _) = run_shortfall_scenario(HoldPartial, "chargeback,1,1\nrepresentment,1,1").await?;
+        assert_eq!(balances(&engine), (10.0, 0.0, 10.0));
+        assert_eq!(engine.clients[&1].shortfall_written_off, 0.0);
+
+        // a frozen client's deposits collect the shortfall, what is still missing becomes debt
+        let (mut engine, outcomes) =
+            run_shortfall_scenario(Freeze, "withdrawal,1,3,5.0\ndeposit,1,4,50.0").await?;
+        assert_eq!(outcomes[3], ProcessOutcome::Ignored(IgnoreReason::AccountFrozen));
+        assert_eq!(balances(&engine), (0.0, 60.0, 60.0));
+        assert_eq!(engine.dispute_shortfalls[&1], 40.0);
+        engine.undo_last().await?;
+        assert_eq!(balances(&engine), (0.0, 10.0, 10.0));
+        assert_eq!(engine.dispute_shortfalls[&1], 90.0);
+
+        let (engine, _) = run_shortfall_scenario(Freeze, "deposit,1,4,50.0\nchargeback,1,1").await?;
+        assert_eq!(balances(&engine), (-40.0, 0.0, -40.0));
+        assert_eq!(engine.clients[&1].debt, 40.0);
+        assert!(engine.clients[&1].locked);
+
+        let (engine, _) = run_shortfall_scenario(Freeze, "deposit,1,4,95.0").await?;
+        assert_eq!(balances(&engine), (5.0, 100.0, 105.0));
+        assert!(!engine.clients[&1].frozen);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn partial_holds_report_the_losses_of_their_chargebacks() -> Result<(), PaymentError> {
+        use DisputeShortfallPolicy::{Allow, HoldPartial};
+        let balances = |engine: &PaymentEngine| {
+            let client = engine.clients[&1];
+            (client.available.to_f64(), client.held.to_f64(), client.total.to_f64())
+        };
+        let losses = |engine: &PaymentEngine| {
+            let mut report = Vec::new();
+            engine.write_losses_report(&mut report).expect("writing to memory");
+            String::from_utf8(report).expect("the report is text")
+        };
+        // a deposit of 100 disputed after 60 of it was withdrawn
+        // either way the 60 withdrawn can't be taken back and is written off
+        let loss = "1,1,100.0000,40.0000,60.0000\n";
+        for (policy, disputed) in [(Allow, (-60.0, 100.0, 40.0)), (HoldPartial, (0.0, 40.0, 40.0))] {
+            let mut engine = PaymentEngine::new()
+                .with_config(EngineConfig {
+                    dispute_shortfall: policy,
+                    ..Default::default()
+                })
+                .with_undo_history(1);
+            for txn in [
+                Transaction::deposit(1, 1, 100.0),
+                Transaction::withdrawal(1, 2, 60.0),
+                Transaction::dispute(1, 1),
+            ] {
+                engine.process_transaction(txn).await?;
+            }
+            assert_eq!(balances(&engine), disputed, "{:?}", policy);
+
+            // a resolve gives back what was held, and nothing is lost
+            engine.process_transaction(Transaction::resolve(1, 1)).await?;
+            assert_eq!(balances(&engine), (40.0, 0.0, 40.0), "{:?}", policy);
+            assert_eq!(losses(&engine), format!("{}\n", LOSSES_HEADER));
+
+            // a chargeback takes the 40 left either way and reports the 60 it couldn't
+            engine.undo_last().await?;
+            engine.process_transaction(Transaction::chargeback(1, 1)).await?;
+            assert_eq!(balances(&engine), (0.0, 0.0, 0.0), "{:?}", policy);
+            assert_eq!(losses(&engine), format!("{}\n{}", LOSSES_HEADER, loss), "{:?}", policy);
+
+            engine.process_transaction(Transaction::representment(1, 1)).await?;
+            assert_eq!(losses(&engine), format!("{}\n", LOSSES_HEADER));
+        }
+
+        // a partial dispute expects only the amount it disputed
+        for policy in [Allow, HoldPartial] {
+            let mut engine = PaymentEngine::new().with_config(EngineConfig {
+                dispute_shortfall: policy,
+                dispute_amounts: DisputeAmountPolicy::UseAsPartial,
+                ..Default::default()
+            });
+            for txn in [
+                Transaction::deposit(1, 1, 100.0),
+                Transaction::withdrawal(1, 2, 60.0),
+                Transaction {
+                    amount: Some(80.0),
+                    ..Transaction::dispute(1, 1)
+                },
+                Transaction::chargeback(1, 1),
+            ] {
+                assert_eq!(engine.process_transaction(txn).await?, ProcessOutcome::Applied, "{:?}", policy);
+            }
+            assert_eq!(balances(&engine), (0.0, 0.0, 0.0), "{:?}", policy);
+            let loss = "1,1,80.0000,40.0000,40.0000\n";
+            assert_eq!(losses(&engine), format!("{}\n{}", LOSSES_HEADER, loss), "{:?}", policy);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resolves_and_chargebacks_never_take_held_below_zero() -> Result<(), PaymentError> {
+        for (clamp, settle, balances) in [
+            (false, Transaction::resolve(1, 1), (0.0, 4.0, 4.0)),
+            (false, Transaction::chargeback(1, 1), (0.0, 4.0, 4.0)),
+            (true, Transaction::resolve(1, 1), (4.0, 0.0, 4.0)),
+            (true, Transaction::chargeback(1, 1), (0.0, 0.0, 0.0)),
+        ] {
+            let (sender, mut receiver) = mpsc::channel(10);
+            let mut engine = PaymentEngine::new()
+                .with_config(EngineConfig {
+                    clamp_inconsistent_held: clamp,
+                    ..Default::default()
+                })
+                .with_warning_sink(sender);
+            // a state edited by hand, holding 6.0 less than its disputes
+            engine.load_clients_json("{\"1\":{\"held\":\"-6.0000\",\"total\":\"-6.0000\"}}")?;
+            engine.process_transaction(Transaction::deposit(1, 1, 10.0)).await?;
+            engine.process_transaction(Transaction::dispute(1, 1)).await?;
+            let outcome = engine.process_transaction(settle.clone()).await?;
+
+            let client = engine.clients[&1];
+            assert_eq!((client.available.to_f64(), client.held.to_f64(), client.total.to_f64()), balances);
+            assert_eq!(client.locked, clamp && settle.r#type == TransactionType::Chargeback);
+            let expected = if clamp {
+                ProcessOutcome::Applied
+            } else {
+                ProcessOutcome::Ignored(IgnoreReason::HeldBalanceInconsistent)
+            };
+            assert_eq!(outcome, expected);
+            engine.close_warnings();
+            let mut warnings = Vec::new();
+            while let Some(warning) = receiver.recv().await {
+                warnings.push(warning.to_string());
+            }
+            assert!(
+                warnings.contains(&"invariant violated: client 1: held 4.0000 < 10.0000 held by the dispute of tx 1".to_owned()),
+                "{:?}",
+                warnings
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dispute_amount_policies() -> Result<(), PaymentError> {
+        use DisputeAmountPolicy::{Ignore, UseAsPartial, Verify};
+        let engine_with = |policy| {
+            PaymentEngine::new().with_config(EngineConfig {
+                dispute_amounts: policy,
+                ..Default::default()
+            })
+        };
+        let with_amount = |txn: Transaction, amount: Option<f64>| Transaction { amount, ..txn };
+
+        // what a dispute of the 10.0 deposit holds, if it isn't rejected
+        for (policy, given, held) in [
+            (Ignore, None, Some(10.0)),
+            (Ignore, Some(10.0), Some(10.0)),
+            (Ignore, Some(4.0), Some(10.0)),
+            (Verify, None, Some(10.0)),
+            (Verify, Some(10.0), Some(10.0)),
+            (Verify, Some(4.0), None),
+            (UseAsPartial, None, Some(10.0)),
+            (UseAsPartial, Some(10.0), Some(10.0)),
+            (UseAsPartial, Some(4.0), Some(4.0)),
+            (UseAsPartial, Some(12.0), None),
+        ] {
+            let mut engine = engine_with(policy);
+            engine.process_transaction(Transaction::deposit(1, 1, 10.0)).await?;
+            let outcome = engine.process_transaction(with_amount(Transaction::dispute(1, 1), given)).await?;
+            match held {
+                Some(held) => {
+                    assert_eq!(outcome, ProcessOutcome::Applied, "{:?} {:?}", policy, given);
+                    assert_eq!(engine.clients[&1].held, held, "{:?} {:?}", policy, given);
+                }
+                None => {
+                    assert_eq!(outcome, ProcessOutcome::Ignored(IgnoreReason::AmountMismatch));
+                    assert_eq!(engine.clients[&1].held, 0.0);
+                }
+            }
+        }
+
+        // both amounts make it to the rejection report
+        let mut engine = engine_with(Verify);
+        engine.process_transaction(Transaction::deposit(1, 1, 10.0)).await?;
+        let dispute = with_amount(Transaction::dispute(1, 1), Some(4.0));
+        engine.process_transaction(dispute.clone()).await?;
+        assert_eq!(
+            engine.rejection(&dispute, IgnoreReason::AmountMismatch).detail,
+            "amount_mismatch: row says 4.0000, expected 10.0000"
+        );
+
+        // a resolve or chargeback is checked against what the partial dispute holds
+        let mut engine = engine_with(UseAsPartial);
+        engine.process_transaction(Transaction::deposit(1, 1, 10.0)).await?;
+        engine.process_transaction(with_amount(Transaction::dispute(1, 1), Some(4.0))).await?;
+        let resolve = with_amount(Transaction::resolve(1, 1), Some(10.0));
+        assert_eq!(
+            engine.process_transaction(resolve.clone()).await?,
+            ProcessOutcome::Ignored(IgnoreReason::AmountMismatch)
+        );
+        assert_eq!(
+            engine.rejection(&resolve, IgnoreReason::AmountMismatch).detail,
+            "amount_mismatch: row says 10.0000, expected 4.0000"
+        );
+        let chargeback = with_amount(Transaction::chargeback(1, 1), Some(4.0));
+        assert_eq!(engine.process_transaction(chargeback).await?, ProcessOutcome::Applied);
+        assert_eq!((engine.clients[&1].available, engine.clients[&1].total), (amount(6.0), amount(6.0)));
+        engine.process_transaction(Transaction::representment(1, 1)).await?;
+        assert_eq!(engine.clients[&1].total, 10.0);
+
+        // without an amount, or under Ignore, the whole dispute is settled
+        for (policy, given) in [(Verify, None), (UseAsPartial, None), (Ignore, Some(3.0))] {
+            let mut engine = engine_with(policy);
+            engine.process_transaction(Transaction::deposit(1, 1, 10.0)).await?;
+            engine.process_transaction(Transaction::dispute(1, 1)).await?;
+            let resolve = with_amount(Transaction::resolve(1, 1), given);
+            assert_eq!(engine.process_transaction(resolve).await?, ProcessOutcome::Applied, "{:?}", policy);
+            assert_eq!(engine.clients[&1].available, 10.0);
+        }
+
+        // under Ignore, not even an amount out of range is looked at
+        let mut engine = engine_with(Ignore);
+        engine.process_transaction(Transaction::deposit(1, 1, 10.0)).await?;
+        for txn in [Transaction::dispute(1, 1), Transaction::chargeback(1, 1)] {
+            let outcome = engine.process_transaction(with_amount(txn, Some(1e20))).await?;
+            assert_eq!(outcome, ProcessOutcome::Applied);
+        }
+        assert_eq!(engine.clients[&1].total, 0.0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn every_pair_of_operations_keeps_total_in_line() -> Result<(), PaymentError> {
+        // amounts with odd decimals, and some with more than four that the engine rounds
+        let operations = [
+            Transaction::deposit(1, 10, 0.00015),
+            Transaction::withdrawal(1, 11, 4.99995),
+            Transaction::dispute(1, 1),
+            Transaction::resolve(1, 2),
+            Transaction::chargeback(1, 2),
+            Transaction::representment(1, 2),
+            Transaction::hold(1, 12, 0.3333),
+            Transaction::release(1, 4),
+            Transaction::capture(1, 4),
+        ];
+        for policy in [DisputeShortfallPolicy::Allow, DisputeShortfallPolicy::HoldPartial, DisputeShortfallPolicy::Freeze] {
+            for first in &operations {
+                for second in &operations {
+                    let mut engine = PaymentEngine::new().with_config(EngineConfig {
+                        dispute_shortfall: policy,
+                        chargeback_fee: Some(0.35),
+                        ..Default::default()
+                    });
+                    for txn in [
+                        Transaction::deposit(1, 1, 10.1234),
+                        Transaction::deposit(1, 2, 3.3333),
+                        Transaction::withdrawal(1, 3, 1.0001),
+                        Transaction::hold(1, 4, 2.5),
+                        Transaction::dispute(1, 2),
+                        first.clone(),
+                        second.clone(),
+                    ] {
+                        engine.process_transaction(txn).await?;
+                    }
+                    let pair = format!("{:?} then {:?} under {:?}", first.r#type, second.r#type, policy);
+                    assert_eq!(engine.check_invariants(), [], "{}", pair);
+                    assert_eq!(engine.check_totals(), None, "{}", pair);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn overflowing_balances_stop_processing() -> Result<(), PaymentError> {
+        let mut engine = PaymentEngine::new();
+        engine.process_transaction(Transaction::deposit(1, 1, 9e14)).await?;
+        let before = engine.clients[&1];
+
+        let result = engine.process_transaction(Transaction::deposit(1, 2, 9e14)).await;
+        assert!(matches!(result, Err(PaymentError::AmountOutOfRange(_))), "{:?}", result);
+        let result = engine.process_transaction(Transaction::deposit(1, 3, f64::INFINITY)).await;
+        assert!(matches!(result, Err(PaymentError::AmountOutOfRange(_))), "{:?}", result);
+        assert_eq!(engine.clients[&1], before);
+
+        // amounts are kept to four decimal places, rounded half to even by default
+        engine.process_transaction(Transaction::deposit(2, 4, 0.00025)).await?;
+        engine.process_transaction(Transaction::deposit(2, 5, 0.1)).await?;
+        engine.process_transaction(Transaction::deposit(2, 6, 0.2)).await?;
+        assert_eq!(engine.clients[&2].total, amount(0.3002));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn warnings_a_slow_consumer_misses_are_counted() -> Result<(), PaymentError> {
+        let (sender, mut receiver) = mpsc::channel(2);
+        let mut engine = PaymentEngine::new().with_warning_sink(sender);
+
+        // the channel fills up with the first two warnings, the next two are dropped
+        for tx in 1..=4 {
+            engine.process_transaction(Transaction::withdrawal(1, tx, 1.0)).await?;
+        }
+        assert_eq!(engine.dropped_warnings(), 2);
+        let first = receiver.recv().await.unwrap();
+        assert_eq!(
+            first,
+            EngineWarning::Rejected(engine.rejection(&Transaction::withdrawal(1, 1, 1.0), IgnoreReason::UnknownClient))
+        );
+        assert_eq!(first.to_string(), "tx 1 of client 1 ignored: unknown_client");
+
+        // applied transactions warn about nothing, the room made by the consumer takes the next one
+        engine.process_transaction(Transaction::deposit(1, 5, 1.0)).await?;
+        engine.process_transaction(Transaction::withdrawal(1, 6, 5.0)).await?;
+        let err = PaymentError::CsvParseError("invalid amount".to_owned());
+        engine.warn(EngineWarning::Rejected(RejectionRecord::unparseable(8, &err)));
+        assert_eq!(engine.dropped_warnings(), 3);
+
+        engine.close_warnings();
+        let mut rest = Vec::new();
+        while let Some(warning) = receiver.recv().await {
+            rest.push(warning.to_string());
+        }
+        assert_eq!(rest, ["tx 2 of client 1 ignored: unknown_client", "tx 6 of client 1 ignored: insufficient_funds"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn batches_aggregate_outcomes_and_stop_when_asked() -> Result<(), PaymentError> {
+        // line 4 doesn't parse, the withdrawal on line 5 overdraws
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 2.0
+        deposit, 1, 2, 1.0
+        deposit, 1, 3, abc
+        withdrawal, 1, 4, 5.0
+        withdrawal, 1, 5, 1.5
+        dispute, 1, 9";
+        let batch = |config: EngineConfig| async move {
+            let str_buf = stringreader::StringReader::new(csv);
+            let mut engine = PaymentEngine::new().with_config(config);
+            let result = engine.process_all(parse_transactions(Box::new(str_buf)).await?).await;
+            Ok::<_, PaymentError>((engine, result))
+        };
+
+        let (engine, result) = batch(EngineConfig::default()).await?;
+        assert_eq!((result.applied, result.ignored, result.errors, result.rows()), (3, 2, 1, 6));
+        assert_eq!(
+            result.rejections.iter().map(|rejection| (rejection.row, rejection.record.tx, rejection.record.reason)).collect::<Vec<_>>(),
+            [
+                (4, Some(4), RejectionReason::Ignored(IgnoreReason::InsufficientFunds)),
+                (6, Some(9), RejectionReason::Ignored(IgnoreReason::UnknownTransaction))
+            ]
+        );
+        assert!(result.error.is_none());
+        assert_eq!(engine.clients[&1].total, 1.5);
+
+        // fail-fast stops at the parse error, counting it
+        let (engine, result) = batch(EngineConfig {
+            fail_fast: true,
+            ..Default::default()
+        })
+        .await?;
+        assert_eq!((result.applied, result.ignored, result.errors), (2, 0, 1));
+        assert!(matches!(result.error, Some(PaymentError::CsvParseError(_))));
+        assert_eq!(engine.clients[&1].total, 3.0);
+
+        // failing on ignored transactions stops at the overdraw instead
+        let (engine, result) = batch(EngineConfig {
+            fail_on_ignore: true,
+            ..Default::default()
+        })
+        .await?;
+        assert_eq!((result.applied, result.ignored, result.errors), (2, 1, 1));
+        let err = result.into_result().unwrap_err();
+        assert_eq!(err.to_string(), "Ignored transaction: row 4: tx 4 of client 1: insufficient_funds");
+        assert_eq!(engine.clients[&1].total, 3.0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn batches_of_parsed_records_report_their_lines() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 2.0
+        deposit, 1, 2, abc
+        withdrawal, 1, 3, 5.0
+        withdrawal, 1, 4, 1.5
+        dispute, 1, 9";
+        let lines = |batch: &BatchResult| {
+            batch.rejections.iter().map(|rejection| (rejection.row, rejection.record.line)).collect::<Vec<_>>()
+        };
+        for chunk_size in [None, Some(1), Some(64)] {
+            let (_, records) = parse_records(Box::new(stringreader::StringReader::new(csv))).await?;
+            let mut engine = PaymentEngine::new();
+            let batch = match chunk_size {
+                Some(chunk_size) => engine.process_all_chunked(records, chunk_size).await,
+                None => engine.process_all(records).await,
+            };
+            assert_eq!((batch.applied, batch.ignored, batch.errors), (2, 2, 1), "chunks of {:?}", chunk_size);
+            assert_eq!(lines(&batch), [(3, Some(4)), (5, Some(6))], "chunks of {:?}", chunk_size);
+            assert_eq!(engine.source_of(1).await?, Some(SourceRef::line(2)));
+        }
+
+        let (_, records) = parse_records(Box::new(stringreader::StringReader::new(csv))).await?;
+        let mut engine = PaymentEngine::new().with_config(EngineConfig {
+            fail_on_ignore: true,
+            ..Default::default()
+        });
+        let err = engine.process_all_chunked(records, 64).await.into_result().unwrap_err();
+        assert_eq!(err.to_string(), "Ignored transaction: line 4: tx 3 of client 1: insufficient_funds");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn streams_are_processed_like_batches() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 2.0
+        withdrawal, 1, 2, 5.0
+        withdrawal, 1, 3, 0.5";
+        let str_buf = stringreader::StringReader::new(csv);
+        let rows: Vec<_> = parse_transactions(Box::new(str_buf)).await?.collect();
+        let (sender, receiver) = mpsc::channel(1);
+        let producer = tokio::spawn(async move {
+            for txn in rows {
+                sender.send(txn).await.expect("engine stopped receiving");
+            }
+        });
+
+        let mut engine = PaymentEngine::new();
+        let result = engine.process_stream(receiver).await.into_result()?;
+        producer.await.expect("producer panicked");
+        assert_eq!((result.applied, result.ignored, result.errors), (2, 1, 0));
+        assert_eq!(engine.clients[&1].total, 1.5);
+        Ok(())
+    }
+
+    /// Returns 100k generated rows, with a dispute of another client's deposit every 1000 rows,
+    /// so that some chunks run row by row.
+    fn generated_rows() -> Vec<Transaction> {
+        TransactionGenerator::new(7, 500)
+            .take(100_000)
+            .enumerate()
+            .map(|(index, txn)| match index % 1000 {
+                999 => Transaction::dispute(txn.client % 500 + 1, txn.tx.saturating_sub(1)),
+                _ => txn,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn chunks_are_processed_like_rows_one_by_one() -> Result<(), PaymentError> {
+        let rows = generated_rows();
+        let mut expected = PaymentEngine::new();
+        let mut outcomes = Vec::with_capacity(rows.len());
+        for txn in rows.clone() {
+            outcomes.push(expected.process_transaction(txn).await.map_err(|err| err.to_string()));
+        }
+        let mismatch = Ok(ProcessOutcome::Ignored(IgnoreReason::ClientMismatch));
+        assert!(outcomes.contains(&mismatch));
+
+        for chunk_size in [1, 64, 1000] {
+            let mut engine = PaymentEngine::new();
+            let mut chunked = Vec::with_capacity(rows.len());
+            for chunk in rows.chunks(chunk_size) {
+                let mut chunk = chunk.to_vec();
+                let chunk_outcomes = engine.process_chunk(&mut chunk).await;
+                assert!(chunk.is_empty());
+                chunked.extend(chunk_outcomes.into_iter().map(|outcome| outcome.map_err(|err| err.to_string())));
+            }
+            assert_eq!(chunked, outcomes, "chunks of {}", chunk_size);
+            assert_eq!(engine.clients, expected.clients);
+            assert_eq!(engine.client_ids(OutputOrder::FirstSeen), expected.client_ids(OutputOrder::FirstSeen));
+            assert_eq!(engine.open_disputes().collect::<Vec<_>>(), expected.open_disputes().collect::<Vec<_>>());
+            assert_eq!(engine.check_totals(), None);
+        }
+
+        // idempotency keys are shared by all clients, so the first row with the key wins
+        let rows = [
+            Transaction::deposit(1, 3, 1.0),
+            Transaction::deposit(2, 4, 2.0).with_idempotency_key("k"),
+            Transaction::deposit(1, 5, 5.0).with_idempotency_key("k"),
+        ];
+        let mut engine = PaymentEngine::new();
+        for txn in [Transaction::deposit(1, 1, 1.0), Transaction::deposit(2, 2, 1.0)] {
+            engine.process_transaction(txn).await?;
+        }
+        let outcomes = engine.process_chunk(&mut rows.to_vec()).await.into_iter().collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            outcomes,
+            [
+                ProcessOutcome::Applied,
+                ProcessOutcome::Applied,
+                ProcessOutcome::Ignored(IgnoreReason::DuplicateIdempotencyKey)
+            ]
+        );
+        assert_eq!((engine.clients[&1].total, engine.clients[&2].total), (amount(2.0), amount(3.0)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chunked_streams_are_processed_like_streams() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 2.0
+        deposit, 2, 2, 1.0
+        withdrawal, 1, 3, 5.0
+        deposit, x, 4, 1.0
+        withdrawal, 2, 5, 0.5
+        dispute, 1, 2,
+        withdrawal, 1, 6, 0.5";
+        let stream = |rows: Vec<Result<Transaction, PaymentError>>| {
+            let (sender, receiver) = mpsc::channel(rows.len());
+            for txn in rows {
+                sender.try_send(txn).expect("channel has room");
+            }
+            receiver
+        };
+        let str_buf = stringreader::StringReader::new(csv);
+        let rows: Vec<_> = parse_transactions(Box::new(str_buf)).await?.collect();
+        let mut expected = PaymentEngine::new();
+        let expected_batch = expected.process_all(rows).await;
+
+        let str_buf = stringreader::StringReader::new(csv);
+        let rows: Vec<_> = parse_transactions(Box::new(str_buf)).await?.collect();
+        let mut engine = PaymentEngine::new();
+        let batch = engine.process_stream_chunked(stream(rows), 4).await;
+        assert_eq!((batch.applied, batch.ignored, batch.errors), (4, 2, 1));
+        assert_eq!(batch.rejections, expected_batch.rejections);
+        assert_eq!(batch.rejections.iter().map(|rejection| rejection.row).collect::<Vec<_>>(), [3, 6]);
+        assert_eq!(engine.clients, expected.clients);
+        Ok(())
+    }
+
+    fn stream_rows() -> Vec<Transaction> {
+        (1..=10u32)
+            .map(|tx| match tx % 3 {
+                0 => Transaction::withdrawal(1, tx, 1.5),
+                _ => Transaction::deposit(tx as ClientId % 2 + 1, tx, 1.0),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn cancelled_streams_stop_between_transactions() -> Result<(), PaymentError> {
+        const CANCEL_AFTER: usize = 4;
+        let (sender, receiver) = mpsc::channel(1);
+        let token = CancellationToken::new();
+        let producer = tokio::spawn({
+            let token = token.clone();
+            async move {
+                for (sent, txn) in stream_rows().into_iter().enumerate() {
+                    if sent == CANCEL_AFTER {
+                        // once there's room again, the engine has taken every row sent so far
+                        drop(sender.reserve().await);
+                        token.cancel();
+                    }
+                    if sender.send(Ok(txn)).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        let mut engine = PaymentEngine::new();
+        let result = engine.process_stream_with_cancel(receiver, token).await;
+        assert!(result.cancelled);
+        assert_eq!(result.rows(), CANCEL_AFTER);
+        assert!(result.error.is_none());
+
+        let mut expected = PaymentEngine::new();
+        expected.process_all(stream_rows().into_iter().take(CANCEL_AFTER).map(Ok)).await.into_result()?;
+        assert_eq!(engine.state_digest(), expected.state_digest());
+        assert_eq!(engine.transactions.len(), expected.transactions.len());
+        producer.abort();
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_streams_time_out() -> Result<(), PaymentError> {
+        let (sender, receiver) = mpsc::channel(1);
+        let producer = tokio::spawn(async move {
+            for txn in stream_rows() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                if sender.send(Ok(txn)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut engine = PaymentEngine::new();
+        let result = engine.process_stream_with_timeout(receiver, Duration::from_millis(120)).await;
+        // rows arrive at 50 and 100ms, the third would come after the timeout
+        assert!(result.cancelled);
+        assert_eq!(result.applied, 2);
+        assert_eq!(engine.clients[&1].total, 1.0);
+        assert_eq!(engine.clients[&2].total, 1.0);
+        producer.abort();
+
+        // a stream ending in time isn't cancelled
+        let (sender, receiver) = mpsc::channel(10);
+        for txn in stream_rows() {
+            sender.send(Ok(txn)).await.expect("channel has room");
+        }
+        drop(sender);
+        let result = PaymentEngine::new().process_stream_with_timeout(receiver, Duration::from_millis(120)).await;
+        assert!(!result.cancelled);
+        assert_eq!(result.rows(), 10);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn clients_round_trip_through_json() -> Result<(), PaymentError> {
+        let mut engine = PaymentEngine::new();
+        for txn in [
+            Transaction::deposit(2, 1, 10.0),
+            Transaction::withdrawal(2, 2, 4.0),
+            Transaction::dispute(2, 1),
+            Transaction::chargeback(2, 1),
+            Transaction::deposit(1, 3, 1.23456),
+        ] {
+            engine.process_transaction(txn).await?;
+        }
+
+        let json = engine.clients_json()?;
+        assert!(
+            json.starts_with("{\"1\":{\"available\":\"1.2346\",\"held\":\"0.0000\",\"total\":\"1.2346\",\"locked\":false,"),
+            "{}",
+            json
+        );
+        assert!(json.contains("\"2\":{\"available\":\"0.0000\",\"held\":\"0.0000\",\"total\":\"0.0000\",\"locked\":true,"), "{}", json);
+        assert!(json.contains("\"debt\":\"0.0000\""), "{}", json);
+
+        let mut restored = PaymentEngine::new();
+        restored.load_clients_json(&json)?;
+        assert_eq!(restored.clients[&2], engine.clients[&2]);
+        assert_eq!(restored.clients[&2].locked_by.map(|cause| cause.tx), Some(1));
+        assert_eq!(restored.clients[&1].total, 1.2346);
+        assert_eq!(restored.clients_json()?, json);
+
+        // fields left out take their defaults, an invalid amount loads nothing
+        restored.load_clients_json("{\"7\":{\"available\":\"5.0000\",\"total\":\"5.0000\"}}")?;
+        assert_eq!(restored.clients[&7].available, 5.0);
+        assert!(!restored.clients[&7].locked);
+        let err = restored
+            .load_clients_json("{\"8\":{\"total\":\"0\"},\"9\":{\"total\":\"lots\"}}")
+            .unwrap_err();
+        assert!(matches!(err, PaymentError::JsonError(_)), "{}", err);
+        assert!(!restored.clients.contains_key(&8));
+        Ok(())
+    }
+
+    async fn region(transactions: Vec<Transaction>, config: EngineConfig) -> Result<PaymentEngine, PaymentError> {
+        let mut engine = PaymentEngine::new().with_config(config);
+        engine.process_all(transactions.into_iter().map(Ok)).await.into_result()?;
+        Ok(engine)
+    }
+
+    #[tokio::test]
+    async fn disjoint_states_merge() -> Result<(), PaymentError> {
+        let east = region(
+            vec![Transaction::deposit(1, 1, 10.0), Transaction::dispute(1, 1)],
+            EngineConfig::default(),
+        )
+        .await?;
+        let west = region(
+            vec![
+                Transaction::deposit(3, 2, 5.0),
+                Transaction::deposit(2, 3, 4.0),
+                Transaction::dispute(2, 3),
+                Transaction::chargeback(2, 3),
+            ],
+            EngineConfig::default(),
+        )
+        .await?;
+
+        let mut merged = east.merge(west).await.unwrap();
+        assert_eq!(merged.client_ids(OutputOrder::FirstSeen), [1, 3, 2]);
+        assert_eq!(merged.clients[&1].held, 10.0);
+        assert_eq!(merged.clients[&3].available, 5.0);
+        assert!(merged.clients[&2].locked);
+        assert_eq!(merged.transactions.len(), 3);
+        assert!(merged.charged_back_transactions.contains_key(&3));
+
+        // the dispute opened before the merge can still be settled against the merged store
+        merged.process_transaction(Transaction::resolve(1, 1)).await?;
+        assert_eq!(merged.clients[&1].available, 10.0);
+        assert!(merged.disputed_transactions.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shared_clients_are_rejected_or_summed() -> Result<(), PaymentError> {
+        let states = |config: EngineConfig| async move {
+            let east = region(vec![Transaction::deposit(1, 1, 10.0), Transaction::deposit(2, 2, 1.0)], config.clone()).await?;
+            let west = region(
+                vec![
+                    Transaction::deposit(1, 3, 4.0),
+                    Transaction::deposit(2, 4, 2.0),
+                    Transaction::dispute(2, 4),
+                    Transaction::chargeback(2, 4),
+                ],
+                config,
+            )
+            .await?;
+            Ok::<_, PaymentError>((east, west))
+        };
+
+        let (east, west) = states(EngineConfig::default()).await?;
+        let err = east.merge(west).await.err().expect("the merge should fail");
+        assert!(matches!(err, MergeError::SharedClients(ref clients) if clients == &[1, 2]), "{}", err);
+        assert_eq!(err.to_string(), "clients on both sides: 1, 2");
+
+        let config = EngineConfig {
+            client_merge: ClientMergePolicy::Sum,
+            ..Default::default()
+        };
+        let (east, west) = states(config).await?;
+        let merged = east.merge(west).await.unwrap();
+        assert_eq!(merged.clients[&1].available, 14.0);
+        assert_eq!(merged.clients[&1].total, 14.0);
+        assert!(!merged.clients[&1].locked);
+        assert_eq!(merged.clients[&2].total, 1.0);
+        assert_eq!(merged.clients[&2].chargebacks, 1);
+        assert!(merged.clients[&2].locked, "locked on either side locks the merged account");
+        assert_eq!(merged.client_ids(OutputOrder::FirstSeen), [1, 2]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn colliding_tx_ids_fail_the_merge() -> Result<(), PaymentError> {
+        let east = region(
+            vec![Transaction::deposit(1, 1, 1.0), Transaction::deposit(1, 2, 1.0), Transaction::hold(1, 5, 0.5)],
+            EngineConfig::default(),
+        )
+        .await?;
+        let west = region(
+            vec![Transaction::deposit(2, 5, 1.0), Transaction::deposit(2, 3, 1.0), Transaction::deposit(2, 2, 1.0)],
+            EngineConfig::default(),
+        )
+        .await?;
+
+        let err = east.merge(west).await.err().expect("the merge should fail");
+        assert!(matches!(err, MergeError::DuplicateTransactions(ref txs) if txs == &[2, 5]), "{}", err);
+        assert_eq!(err.to_string(), "tx ids on both sides: 2, 5");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn aggregates_follow_the_accounts() -> Result<(), PaymentError> {
+        // the rows of the disputes fixture, plus a second client with an open dispute
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 10.0
+        deposit, 1, 2, 5.0
+        dispute, 1, 1
+        resolve, 1, 1
+        dispute, 1, 2
+        chargeback, 1, 2
+        deposit, 2, 3, 4.0
+        deposit, 2, 4, 1.5
+        dispute, 2, 4";
+        let str_buf = stringreader::StringReader::new(csv);
+        let transactions = parse_transactions(Box::new(str_buf)).await?;
+        let mut engine = PaymentEngine::new().with_undo_history(1);
+        engine.process_all(transactions).await.into_result()?;
+
+        assert_eq!(engine.total_available(), 14.0);
+        assert_eq!(engine.total_held(), 1.5);
+        assert_eq!(engine.total_funds(), 15.5);
+        assert_eq!(engine.locked_count(), 1);
+        let client_1 = engine.sum_for_clients(&[1, 1, 9]);
+        assert_eq!((client_1.total, client_1.locked), (10.0, 1));
+        assert_eq!(engine.sum_for_clients(&[1, 2]).total, engine.total_funds());
+        assert_eq!(engine.check_totals(), None);
+
+        // undoing and unlocking keep the running totals in line
+        engine.undo_last().await?;
+        assert_eq!(engine.total_held(), 0.0);
+        assert!(engine.unlock(1)?);
+        assert_eq!(engine.locked_count(), 0);
+        assert_eq!(engine.check_totals(), None);
+
+        // accounts changed behind the engine's back show up as drift
+        let client = engine.clients.get_mut(&2).unwrap();
+        client.available = client.available.checked_add(amount(1.0))?;
+        let drift = engine.check_totals().unwrap();
+        assert_eq!(drift.actual.available, drift.tracked.available + 1.0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn every_flow_of_money_is_in_the_ledger() -> Result<(), PaymentError> {
+        let mut engine = PaymentEngine::new()
+            .with_config(EngineConfig {
+                chargeback_fee: Some(2.0),
+                allow_corrections: true,
+                ..EngineConfig::default()
+            })
+            .with_undo_history(1);
+        for txn in [
+            Transaction::deposit(1, 1, 10.0),
+            Transaction::withdrawal(1, 2, 3.0),
+            Transaction::hold(1, 3, 1.0),
+            Transaction::capture(1, 3),
+            Transaction::withdrawal_pending(1, 4, 2.0),
+            Transaction::withdrawal_settle(1, 4),
+            Transaction::deposit(2, 5, 5.0),
+            Transaction::withdrawal(2, 6, 4.0),
+            // the chargeback zeroes the overdrawn account before taking its fee
+            Transaction::dispute(2, 5),
+            Transaction::chargeback(2, 5),
+            Transaction::representment(2, 5),
+            Transaction::deposit(1, 7, 1.0),
+        ] {
+            engine.process_transaction(txn).await?;
+        }
+        engine.undo_last().await?;
+        engine.apply_correction(1, 6.0, 0.0, "reconciled by hand")?;
+        engine.apply_accrual(0.1)?;
+
+        let ledger = engine.check_ledger();
+        assert!(ledger.balances());
+        assert_eq!(
+            ledger.to_string(),
+            "deposits 15.0000 - withdrawals 10.0000 - chargebacks 4.0000 - fees 2.0000 + adjustments 6.6000 + \
+             opening 0.0000 = 5.6000, accounts total 5.6000, delta 0.0000"
+        );
+
+        // a loaded state is where the next run's ledger opens
+        let mut next = PaymentEngine::new();
+        next.load_clients_json(&engine.clients_json()?)?;
+        next.load_clients_json(&engine.clients_json()?)?;
+        next.process_transaction(Transaction::withdrawal(1, 8, 0.5)).await?;
+        let ledger = next.check_ledger();
+        assert!(ledger.balances());
+        assert_eq!((ledger.flows.opening, ledger.flows.withdrawals), (56_000, 5_000));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn exported_transactions_carry_their_lifecycle_state() -> Result<(), PaymentError> {
+        let csv = "type,client,tx,amount,memo
+        deposit,1,1,10.0,\"salary, march\"
+        deposit,1,2,5.0
+        dispute,1,1
+        resolve,1,1
+        dispute,1,2
+        chargeback,1,2
+        deposit,2,4,3.0
+        withdrawal,2,3,1.25
+        dispute,2,4";
+        let (_, records) = parse_records(Box::new(stringreader::StringReader::new(csv))).await?;
+        let mut engine = PaymentEngine::new();
+        for record in records {
+            let txn = record.transaction?;
+            engine.process_transaction_at(txn, Some(record.line)).await?;
+        }
+        let mut export = Vec::new();
+        engine.export_transactions(&mut export).await?;
+        let export = String::from_utf8(export).expect("the export is text");
+        assert_eq!(
+            export,
+            "# retained: deposits and withdrawals\n\
+             tx,client,type,amount,state,source,memo\n\
+             1,1,deposit,10.0000,resolved,2,\"salary, march\"\n\
+             2,1,deposit,5.0000,charged_back,3,\n\
+             3,2,withdrawal,1.2500,settled,9,\n\
+             4,2,deposit,3.0000,disputed,8,\n"
+        );
+
+        // past its first line the export is an input, reading back as the retained transactions
+        let rows = export.split_once('\n').map(|(_, rows)| rows.to_owned()).unwrap_or_default();
+        let transactions = parse_transactions(Box::new(std::io::Cursor::new(rows.into_bytes()))).await?;
+        let mut retained = Vec::new();
+        for tx in 1..=4 {
+            // where a row was read from isn't a column
+            retained.extend(engine.transactions.get(tx).await?.map(|txn| Transaction { source: None, ..txn }));
+        }
+        assert_eq!(transactions.collect::<Result<Vec<_>, _>>()?, retained);
+
+        // an undone resolve takes the transaction back to disputed
+        let mut engine = PaymentEngine::new().with_undo_history(1);
+        engine.process_transaction(Transaction::deposit(1, 1, 1.0)).await?;
+        engine.process_transaction(Transaction::dispute(1, 1)).await?;
+        engine.process_transaction(Transaction::resolve(1, 1)).await?;
+        assert_eq!(engine.transaction_state(1), TransactionState::Resolved);
+        engine.undo_last().await?;
+        assert_eq!(engine.transaction_state(1), TransactionState::Disputed);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn the_export_tells_what_was_retained() -> Result<(), PaymentError> {
+        let cases = [
+            (DisputeSupport::None, DustPolicy::Apply, "# retained: nothing, dispute support is off\n"),
+            (
+                DisputeSupport::Full,
+                DustPolicy::ApplyButDontStore,
+                "# retained: deposits and withdrawals of at least 0.0100\n",
+            ),
+        ];
+        for (dispute_support, dust, retained) in cases {
+            let mut engine = PaymentEngine::new().with_config(EngineConfig {
+                dispute_support,
+                dust,
+                min_amount: Some(0.01),
+                ..EngineConfig::default()
+            });
+            engine.process_transaction(Transaction::deposit(1, 1, 0.001)).await?;
+            let mut export = Vec::new();
+            engine.export_transactions(&mut export).await?;
+            let export = String::from_utf8(export).expect("the export is text");
+            assert_eq!(export, format!("{}{}\n", retained, EXPORTED_TRANSACTIONS_HEADER));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn a_handler_moving_unrecorded_money_unbalances_the_ledger() -> Result<(), PaymentError> {
+        let mut engine = PaymentEngine::new().with_deposit_skew(amount(0.0001));
+        for txn in [
+            Transaction::deposit(1, 1, 10.0),
+            Transaction::deposit(2, 2, 5.0),
+            Transaction::withdrawal(1, 3, 2.0),
+            Transaction::deposit(1, 4, 1.0),
+        ] {
+            engine.process_transaction(txn).await?;
+        }
+        // the accounts agree with the running totals, only the ledger sees what happened
+        assert_eq!(engine.check_totals(), None);
+        let ledger = engine.check_ledger();
+        assert!(!ledger.balances());
+        assert_eq!(ledger.delta(), 3);
+        assert!(ledger.to_string().ends_with("= 14.0000, accounts total 14.0003, delta 0.0003"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn state_digest_is_pinned() -> Result<(), PaymentError> {
+        let mut engine = PaymentEngine::new();
+        assert_eq!(
+            engine.state_digest(),
+            // the digest of the header line alone
+            "316cc80d31ff5e36b6fc358f3edc842bd38a0bf36e8e0e4fe8658cb87ff60abb"
+        );
+        for txn in [
+            Transaction::deposit(2, 1, 10.0),
+            Transaction::deposit(1, 2, 5.0),
+            Transaction::withdrawal(1, 3, 1.25),
+        ] {
+            engine.process_transaction(txn).await?;
+        }
+        assert_eq!(
+            engine.state_digest(),
+            "051cdff79659d74bf0ab332983e53ec1adad577b1bfd95a0a63aa07d3567d4a1"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn size_limits_stop_processing() -> Result<(), PaymentError> {
+        let config = EngineConfig {
+            max_clients: Some(3),
+            ..Default::default()
+        };
+        let mut engine = PaymentEngine::new().with_config(config);
+        let deposits = (1..=5u32).map(|id| Ok(Transaction::deposit(id as ClientId, id, 1.0)));
+        let result = engine.process_all(deposits).await;
+        assert_eq!(result.applied, 3);
+        assert!(matches!(
+            result.error,
+            Some(PaymentError::LimitExceeded { which: Limit::Clients, limit: 3 })
+        ));
+        assert_eq!(engine.client_ids(OutputOrder::ClientId), [1, 2, 3]);
+        // known clients can still deposit
+        engine.process_transaction(Transaction::deposit(2, 6, 1.0)).await?;
+        assert_eq!(engine.clients[&2].total, 2.0);
+
+        let config = EngineConfig {
+            max_stored_transactions: Some(4),
+            ..Default::default()
+        };
+        let mut engine = PaymentEngine::new().with_config(config);
+        let rows = (1..=10).map(|tx| {
+            Ok(match tx {
+                3 => Transaction::withdrawal(1, tx, 0.5),
+                _ => Transaction::deposit(1, tx, 1.0),
+            })
+        });
+        let result = engine.process_all(rows).await;
+        assert_eq!(result.applied, 4);
+        let err = result.error.unwrap();
+        assert_eq!(err.to_string(), "Limit exceeded: more than 4 stored transactions");
+        assert_eq!(engine.transactions.len(), 4);
+        assert_eq!(engine.clients[&1].total, 2.5);
+        // a full store doesn't stop a withdrawal that is ignored anyway
+        let outcome = engine.process_transaction(Transaction::withdrawal(1, 11, 100.0)).await?;
+        assert_eq!(outcome, ProcessOutcome::Ignored(IgnoreReason::InsufficientFunds));
+        assert_eq!(engine.transactions.len(), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn corrections_set_balances_and_keep_a_receipt() -> Result<(), PaymentError> {
+        let config = EngineConfig {
+            allow_corrections: true,
+            ..Default::default()
+        };
+        let mut engine = PaymentEngine::new().with_config(config).with_undo_history(4);
+        engine.process_transaction(Transaction::deposit(1, 1, 10.0)).await?;
+        engine.process_transaction(Transaction::dispute(1, 1)).await?;
+        engine.process_transaction(Transaction::deposit(2, 2, 3.0)).await?;
+
+        let receipt = engine.apply_correction(1, 4.0, 2.5, "double-counted fee, ticket 812")?;
+        assert_eq!(receipt.sequence, 1);
+        assert_eq!(receipt.client, 1);
+        assert_eq!(
+            receipt.old,
+            Balances {
+                available: amount(0.0),
+                held: amount(10.0),
+                total: amount(10.0)
+            }
+        );
+        assert_eq!(
+            receipt.new,
+            Balances {
+                available: amount(4.0),
+                held: amount(2.5),
+                total: amount(6.5)
+            }
+        );
+        assert_eq!(receipt.reason, "double-counted fee, ticket 812");
+        assert_eq!(engine.clients[&1].total, 6.5);
+        assert_eq!(engine.total_funds(), 9.5);
+        assert!(engine.check_totals().is_none());
+        // the deposit of client 2 can't be undone past the correction
+        assert!(engine.undo_last().await?.is_none());
+
+        let receipt = engine.apply_correction(2, -1.0, 0.0, "overdraft the old engine missed")?;
+        assert_eq!((receipt.sequence, receipt.new.total.to_f64()), (2, -1.0));
+        assert_eq!(engine.corrections().len(), 2);
+
+        for (client, held, reason) in [(1, -0.5, "negative"), (1, 1.0, " "), (9, 1.0, "unknown client")] {
+            let err = engine.apply_correction(client, 1.0, held, reason).unwrap_err();
+            assert!(matches!(err, PaymentError::CorrectionRefused(_)), "{}", err);
+        }
+        assert_eq!(engine.clients[&1].total, 6.5);
+        assert_eq!(engine.corrections().len(), 2);
+        Ok(())
+    }
+
+    /// Clients 1 to 3 with amounts whose 1% interest is a whole amount, a tie to round up and a
+    /// tie to round down to even, and client 4 locked by a chargeback.
+    async fn accrual_engine(rounding: Rounding) -> Result<PaymentEngine, PaymentError> {
+        let config = EngineConfig {
+            rounding,
+            ..Default::default()
+        };
+        let mut engine = PaymentEngine::new().with_config(config).with_undo_history(4);
+        for txn in [
+            Transaction::deposit(1, 1, 100.0),
+            Transaction::deposit(2, 2, 0.015),
+            Transaction::deposit(3, 3, 0.005),
+            Transaction::deposit(4, 4, 20.0),
+            Transaction::deposit(4, 5, 30.0),
+            Transaction::dispute(4, 5),
+            Transaction::chargeback(4, 5),
+        ] {
+            engine.process_transaction(txn).await?;
+        }
+        Ok(engine)
+    }
+
+    #[tokio::test]
+    async fn accruals_pay_interest_to_unlocked_accounts() -> Result<(), PaymentError> {
+        let mut engine = accrual_engine(Rounding::HalfEven).await?;
+        let report = engine.apply_accrual(0.01)?;
+        let paid: Vec<(ClientId, u64, f64)> = report
+            .entries
+            .iter()
+            .map(|entry| (entry.client, entry.sequence, entry.interest.to_f64()))
+            .collect();
+        // client 3's 0.00005 rounds to even, i.e. to nothing, and client 4 is locked
+        assert_eq!(paid, [(1, 1, 1.0), (2, 2, 0.0002)]);
+        assert_eq!(report.total_interest, amount(1.0002));
+        assert_eq!(report.entries[1].available_before, amount(0.015));
+        assert_eq!((engine.clients[&1].available, engine.clients[&1].total), (amount(101.0), amount(101.0)));
+        assert_eq!(engine.clients[&3].available, 0.005);
+        assert_eq!(engine.clients[&4].available, 20.0);
+        assert!(engine.check_totals().is_none());
+        // the deposits can't be undone past the accrual
+        assert!(engine.undo_last().await?.is_none());
+
+        // entries keep their sequence across accruals
+        let report = engine.apply_accrual(0.5)?;
+        assert_eq!(report.entries.iter().map(|entry| entry.sequence).collect::<Vec<_>>(), [3, 4, 5]);
+        assert_eq!(engine.accruals().len(), 5);
+        assert_eq!(engine.interest_paid()?, amount(51.5103));
+        assert_eq!(format!("{:.4}", PaymentEngine::new().interest_paid()?.to_f64()), "0.0000");
+
+        for rate in [f64::NAN, f64::INFINITY, -1.5] {
+            let err = engine.apply_accrual(rate).unwrap_err();
+            assert!(matches!(err, PaymentError::AmountOutOfRange(_)), "{}", err);
+        }
+        assert_eq!(engine.accruals().len(), 5);
+
+        // ties go up under half-up rounding, and truncation drops them
+        let mut engine = accrual_engine(Rounding::HalfUp).await?;
+        let report = engine.apply_accrual(0.01)?;
+        assert_eq!(report.total_interest, amount(1.0003));
+        assert_eq!(engine.clients[&3].available, 0.0051);
+        let mut engine = accrual_engine(Rounding::Truncate).await?;
+        assert_eq!(engine.apply_accrual(0.01)?.total_interest, amount(1.0001));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn accrue_rows_pay_interest_and_are_reported() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 100.0
+        deposit, 2, 2, 0.015
+        accrue, 0, 3, 0.01
+        accrue, 0, 4,
+        deposit, 3, 5, 10.0
+        accrue, 0, 6, 0.5";
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = PaymentEngine::new().with_undo_history(4);
+        let mut outcomes = Vec::new();
+        for txn in parse_transactions(Box::new(str_buf)).await? {
+            outcomes.push(engine.process_transaction(txn?).await?);
+        }
+
+        let missing = ProcessOutcome::Ignored(IgnoreReason::MissingAmount);
+        assert_eq!(
+            outcomes[2..],
+            [ProcessOutcome::Applied, missing, ProcessOutcome::Applied, ProcessOutcome::Applied]
+        );
+        // no account is opened for client 0, and the accrual can't be undone
+        assert!(!engine.clients.contains_key(&0));
+        assert!(engine.undo_last().await?.is_none());
+        assert_eq!(engine.clients[&1].available, 151.5);
+        assert!(engine.check_totals().is_none());
+        let mut out = Vec::new();
+        engine.write_accruals(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "sequence,client,rate,available_before,interest
+1,1,0.01,100.0000,1.0000
+2,2,0.01,0.0150,0.0002
+3,1,0.5,101.0000,50.5000
+4,2,0.5,0.0152,0.0076
+5,3,0.5,10.0000,5.0000
+"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn repeated_idempotency_keys_are_rejected() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount, idempotency_key
+        deposit, 1, 1, 10.0, k1
+        deposit, 1, 2, 10.0, k1
+        withdrawal, 1, 3, 50.0, k2
+        withdrawal, 1, 4, 5.0, k2
+        deposit, 1, 5, 1.0,
+        deposit, 1, 6, 1.0,
+        deposit, 2, 7, 3.0, k3
+        deposit, 2, 8, 3.0, k4
+        deposit, 2, 9, 3.0, k1";
+
+        let config = EngineConfig {
+            max_idempotency_keys: 2,
+            ..Default::default()
+        };
+        let mut engine = PaymentEngine::new().with_config(config);
+        let mut outcomes = Vec::new();
+        for txn in parse_transactions(Box::new(stringreader::StringReader::new(csv))).await? {
+            let txn = txn?;
+            let outcome = engine.process_transaction(txn.clone()).await?;
+            if let ProcessOutcome::Ignored(reason) = outcome {
+                let rejection = engine.rejection(&txn, reason);
+                assert_eq!(rejection.idempotency_key, txn.idempotency_key);
+            }
+            outcomes.push(outcome);
+        }
+        let duplicate = ProcessOutcome::Ignored(IgnoreReason::DuplicateIdempotencyKey);
+        assert_eq!(
+            outcomes,
+            [
+                ProcessOutcome::Applied,
+                duplicate,
+                // a rejected transaction doesn't use up its key
+                ProcessOutcome::Ignored(IgnoreReason::InsufficientFunds),
+                ProcessOutcome::Applied,
+                // rows without a key aren't deduplicated
+                ProcessOutcome::Applied,
+                ProcessOutcome::Applied,
+                ProcessOutcome::Applied,
+                ProcessOutcome::Applied,
+                // only the last 2 keys are remembered, k1 was forgotten
+                ProcessOutcome::Applied,
+            ]
+        );
+        assert_eq!((engine.clients[&1].total, engine.clients[&2].total), (amount(7.0), amount(9.0)));
+
+        // files without the column are processed as before
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 10.0
+        deposit, 1, 2, 10.0";
+        let mut engine = PaymentEngine::new();
+        let transactions = parse_transactions(Box::new(stringreader::StringReader::new(csv))).await?;
+        let batch = engine.process_all(transactions).await;
+        assert_eq!((batch.applied, engine.clients[&1].total.to_f64()), (2, 20.0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn undone_transactions_give_their_idempotency_key_back() -> Result<(), PaymentError> {
+        let mut engine = PaymentEngine::new().with_undo_history(2);
+        let deposit = |tx| Transaction::deposit(1, tx, 2.0).with_idempotency_key("k1");
+        engine.process_transaction(deposit(1)).await?;
+        engine.undo_last().await?;
+        assert_eq!(engine.process_transaction(deposit(2)).await?, ProcessOutcome::Applied);
+        assert_eq!(
+            engine.process_transaction(deposit(3)).await?,
+            ProcessOutcome::Ignored(IgnoreReason::DuplicateIdempotencyKey)
+        );
+        assert_eq!(engine.clients[&1].total, 2.0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn corrections_are_disabled_by_default() -> Result<(), PaymentError> {
+        let mut engine = PaymentEngine::new();
+        engine.process_transaction(Transaction::deposit(1, 1, 10.0)).await?;
+        let err = engine.apply_correction(1, 0.0, 0.0, "zero it").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Correction refused: corrections are disabled, see EngineConfig::allow_corrections"
+        );
+        assert_eq!(engine.clients[&1].total, 10.0);
+        assert!(engine.corrections().is_empty());
         Ok(())
     }
 }
@@ -0,0 +1,215 @@
+//! Preprocessing stages run on the transactions between the parser and the engine, e.g. to
+//! filter clients, rewrite tx ids or add synthetic rows, without changing the engine.
+
+use crate::{errors::PaymentError, filter::ClientFilter, types::Transaction};
+use std::collections::VecDeque;
+
+/// What a stage does with a transaction.
+#[derive(Debug)]
+pub enum StageOutput {
+    /// Pass the transaction on, changed or not.
+    Keep(Transaction),
+    /// Leave the transaction out.
+    Drop,
+    /// Pass these transactions on instead, in order, e.g. the transaction followed by a fee.
+    Replace(Vec<Transaction>),
+    /// The transaction can't go through the stage. It reaches the engine as a row that failed
+    /// to parse, and the later stages don't see it.
+    Reject(PaymentError),
+}
+
+/// A step of a `Pipeline`. Closures taking a transaction and returning a `StageOutput` are
+/// stages too.
+pub trait TransactionStage {
+    fn apply(&mut self, txn: Transaction) -> StageOutput;
+}
+
+impl<F: FnMut(Transaction) -> StageOutput> TransactionStage for F {
+    fn apply(&mut self, txn: Transaction) -> StageOutput {
+        self(txn)
+    }
+}
+
+/// Keeps the transactions of the clients in the filter.
+impl TransactionStage for ClientFilter {
+    fn apply(&mut self, txn: Transaction) -> StageOutput {
+        match self.contains(txn.client) {
+            true => StageOutput::Keep(txn),
+            false => StageOutput::Drop,
+        }
+    }
+}
+
+/// Adds a fixed offset to the tx id of every transaction, for merging feeds whose tx id ranges
+/// collide. Disputes, resolves, releases and the like are offset too, so they still refer to
+/// the same transaction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TxOffset(pub u32);
+
+impl TxOffset {
+    /// Returns the transaction with its tx id offset.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::CsvParseError` if the offset id doesn't fit in a tx id.
+    pub fn remap(&self, mut txn: Transaction) -> Result<Transaction, PaymentError> {
+        txn.tx = txn.tx.checked_add(self.0).ok_or_else(|| {
+            PaymentError::CsvParseError(format!("tx {} is out of range once offset by {}", txn.tx, self.0))
+        })?;
+        Ok(txn)
+    }
+}
+
+impl TransactionStage for TxOffset {
+    fn apply(&mut self, txn: Transaction) -> StageOutput {
+        match self.remap(txn) {
+            Ok(txn) => StageOutput::Keep(txn),
+            Err(err) => StageOutput::Reject(err),
+        }
+    }
+}
+
+/// Stages run one after the other on every transaction, as an adapter of the parser's
+/// iterator:
+///
+/// ```ignore
+/// let pipeline = Pipeline::new().with_stage(filter).with_stage(TxOffset(1_000_000));
+/// engine.process_all(pipeline.wrap(parse_transactions(input).await?)).await;
+/// ```
+#[derive(Default)]
+pub struct Pipeline {
+    stages: Vec<Box<dyn TransactionStage>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a stage after the ones already in the pipeline.
+    pub fn with_stage(mut self, stage: impl TransactionStage + 'static) -> Self {
+        self.stages.push(Box::new(stage));
+        self
+    }
+
+    /// Runs a transaction through every stage, returning what comes out of the last one.
+    pub fn apply(&mut self, txn: Transaction) -> Vec<Result<Transaction, PaymentError>> {
+        let mut rows = vec![Ok(txn)];
+        for stage in &mut self.stages {
+            let mut next = Vec::with_capacity(rows.len());
+            for row in rows {
+                match row.map(|txn| stage.apply(txn)) {
+                    Ok(StageOutput::Keep(txn)) => next.push(Ok(txn)),
+                    Ok(StageOutput::Drop) => {}
+                    Ok(StageOutput::Replace(txns)) => next.extend(txns.into_iter().map(Ok)),
+                    Ok(StageOutput::Reject(err)) | Err(err) => next.push(Err(err)),
+                }
+            }
+            rows = next;
+        }
+        rows
+    }
+
+    /// Runs the pipeline over parsed rows, lazily. Rows that failed to parse go through
+    /// untouched.
+    pub fn wrap<I>(self, rows: I) -> Piped<I::IntoIter>
+    where
+        I: IntoIterator<Item = Result<Transaction, PaymentError>>,
+    {
+        Piped {
+            pipeline: self,
+            rows: rows.into_iter(),
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+/// The rows coming out of a `Pipeline`, see `Pipeline::wrap`.
+pub struct Piped<I> {
+    pipeline: Pipeline,
+    rows: I,
+    /// What the stages made of the last transaction, not yet taken.
+    pending: VecDeque<Result<Transaction, PaymentError>>,
+}
+
+impl<I: Iterator<Item = Result<Transaction, PaymentError>>> Iterator for Piped<I> {
+    type Item = Result<Transaction, PaymentError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(row) = self.pending.pop_front() {
+                return Some(row);
+            }
+            match self.rows.next()? {
+                Ok(txn) => self.pending.extend(self.pipeline.apply(txn)),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        errors::PaymentError,
+        filter::ClientFilter,
+        parser::parse_transactions,
+        payment_engine::PaymentEngine,
+        pipeline::{Pipeline, StageOutput, TxOffset},
+        store::TransactionStore,
+        types::{Transaction, TransactionType},
+    };
+
+    #[tokio::test]
+    async fn engine_sees_the_transformed_stream() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 10.0
+        deposit, 2, 2, 10.0
+        withdrawal, 1, 3, 4.0
+        deposit, 1, x, 1.0
+        dispute, 1, 1
+        withdrawal, 2, 4, 1.0";
+        let str_buf = stringreader::StringReader::new(csv);
+
+        // a 0.5 fee after every withdrawal, under a tx id of its own
+        let mut fee_tx = 900;
+        let fees = move |txn: Transaction| match txn.r#type {
+            TransactionType::Withdrawal => {
+                fee_tx += 1;
+                let fee = Transaction::withdrawal(txn.client, fee_tx, 0.5);
+                StageOutput::Replace(vec![txn, fee])
+            }
+            _ => StageOutput::Keep(txn),
+        };
+        let pipeline = Pipeline::new()
+            .with_stage(ClientFilter::parse("1")?)
+            .with_stage(TxOffset(1000))
+            .with_stage(fees);
+
+        let mut engine = PaymentEngine::new();
+        let batch = engine
+            .process_all(pipeline.wrap(parse_transactions(Box::new(str_buf)).await?))
+            .await;
+        assert_eq!((batch.applied, batch.ignored, batch.errors), (4, 0, 1));
+        assert!(!engine.clients.contains_key(&2));
+        let client = engine.clients[&1];
+        assert_eq!((client.available.to_f64(), client.held.to_f64()), (-4.5, 10.0));
+
+        let mut ids = engine.transactions.tx_ids();
+        ids.sort();
+        assert_eq!(ids, [901, 1001, 1003]);
+        assert!(engine.disputed_transactions.contains_key(&1001));
+        Ok(())
+    }
+
+    #[test]
+    fn offset_ids_out_of_range_are_rejected() {
+        let mut pipeline = Pipeline::new().with_stage(TxOffset(10)).with_stage(|_| StageOutput::Drop);
+        let rows = pipeline.apply(Transaction::deposit(1, u32::MAX - 5, 1.0));
+        assert_eq!(rows.len(), 1);
+        let err = rows.into_iter().next().unwrap().unwrap_err();
+        assert!(err.to_string().contains("tx 4294967290 is out of range"), "{}", err);
+
+        assert!(pipeline.apply(Transaction::deposit(1, 7, 1.0)).is_empty());
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    io::{self, Read, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Wraps a reader and counts the bytes read through it.
+///
+/// The count is shared through a `ByteCounter` handle so it can be observed while the reader
+/// itself is owned by the parser.
+pub struct CountingReader<R> {
+    inner: R,
+    counter: ByteCounter,
+}
+
+impl<R: Read> CountingReader<R> {
+    pub fn new(inner: R) -> Self {
+        CountingReader {
+            inner,
+            counter: ByteCounter::default(),
+        }
+    }
+
+    /// Wraps a reader adding to an existing count, so several inputs can share one.
+    pub fn with_counter(inner: R, counter: ByteCounter) -> Self {
+        CountingReader { inner, counter }
+    }
+
+    /// Returns a handle on the number of bytes read so far.
+    pub fn counter(&self) -> ByteCounter {
+        self.counter.clone()
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.counter.0.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+/// Shared count of the bytes consumed by a `CountingReader`.
+#[derive(Clone, Default)]
+pub struct ByteCounter(Arc<AtomicU64>);
+
+impl ByteCounter {
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Prints periodic progress lines to stderr during long runs.
+///
+/// The clock is only checked every `CHECK_EVERY` rows to keep the per-row cost negligible.
+pub struct ProgressReporter {
+    bytes: Option<ByteCounter>,
+    total_bytes: Option<u64>,
+    interval: Duration,
+    started: Instant,
+    last_report: Instant,
+    rows: u64,
+}
+
+const CHECK_EVERY: u64 = 1024;
+
+impl ProgressReporter {
+    /// Creates a reporter printing at most once per `interval`.
+    ///
+    /// `bytes` and `total_bytes` enable the percent complete figure, which is only meaningful
+    /// when the input is a regular file of known size.
+    pub fn new(bytes: Option<ByteCounter>, total_bytes: Option<u64>, interval: Duration) -> Self {
+        let now = Instant::now();
+        ProgressReporter {
+            bytes,
+            total_bytes,
+            interval,
+            started: now,
+            last_report: now,
+            rows: 0,
+        }
+    }
+
+    /// Records one more processed row, printing a progress line if the interval has elapsed.
+    pub fn tick(&mut self) {
+        self.rows += 1;
+        if self.rows.is_multiple_of(CHECK_EVERY) && self.last_report.elapsed() >= self.interval {
+            self.last_report = Instant::now();
+            let _ = writeln!(io::stderr().lock(), "{}", self.line());
+        }
+    }
+
+    /// Returns the current progress line, e.g. `progress: 2048 rows, 1024 rows/s, 12.5%`.
+    pub fn line(&self) -> String {
+        let seconds = self.started.elapsed().as_secs_f64();
+        let rate = if seconds > 0.0 {
+            self.rows as f64 / seconds
+        } else {
+            0.0
+        };
+        let mut line = format!("progress: {} rows, {:.0} rows/s", self.rows, rate);
+        if let (Some(bytes), Some(total)) = (&self.bytes, self.total_bytes) {
+            if total > 0 {
+                let percent = (bytes.get() as f64 / total as f64 * 100.0).min(100.0);
+                line.push_str(&format!(", {:.1}%", percent));
+            }
+        }
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::progress::{CountingReader, ProgressReporter};
+    use std::{io::Read, time::Duration};
+
+    #[test]
+    fn counting_reader_counts_bytes_consumed() {
+        let data = "type, client, tx, amount\ndeposit, 1, 1, 1.0\n";
+        let mut reader = CountingReader::new(stringreader::StringReader::new(data));
+        let counter = reader.counter();
+
+        let mut buf = [0u8; 10];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(counter.get(), 10);
+
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(counter.get(), data.len() as u64);
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+        assert_eq!(counter.get(), data.len() as u64);
+    }
+
+    #[test]
+    fn progress_line_includes_percent_for_known_size() {
+        let data = "0123456789";
+        let mut reader = CountingReader::new(stringreader::StringReader::new(data));
+        let mut progress =
+            ProgressReporter::new(Some(reader.counter()), Some(20), Duration::from_secs(60));
+        let mut buf = [0u8; 5];
+        reader.read_exact(&mut buf).unwrap();
+        progress.tick();
+
+        let line = progress.line();
+        assert!(line.starts_with("progress: 1 rows, "));
+        assert!(line.ends_with(", 25.0%"));
+        assert!(!ProgressReporter::new(None, None, Duration::ZERO)
+            .line()
+            .contains('%'));
+    }
+}
@@ -0,0 +1,470 @@
+//! Transactions read as length-delimited protobuf messages, for `--input-format proto`.
+//!
+//! The messages are the `Transaction` of `proto/transaction.proto`. Their few fields are decoded
+//! here straight from the protobuf wire format.
+
+use crate::{
+    errors::PaymentError,
+    parser::ParsedRecord,
+    types::{ClientId, Transaction, TransactionType},
+};
+use csv::StringRecord;
+use std::io::{self, BufRead, BufReader, Read};
+
+/// Columns of the raw records of protobuf inputs, as kept for the quarantine.
+pub const PROTO_COLUMNS: &str = "type,client,tx,amount,timestamp";
+
+/// Largest message accepted. A length prefix above it is taken for a malformed one rather than
+/// allocated.
+pub const MAX_MESSAGE_BYTES: u64 = 64 * 1024;
+
+/// The `TransactionType` values of the schema.
+const TRANSACTION_TYPES: [(i32, TransactionType); 13] = [
+    (1, TransactionType::Deposit),
+    (2, TransactionType::Withdrawal),
+    (3, TransactionType::Dispute),
+    (4, TransactionType::Resolve),
+    (5, TransactionType::Chargeback),
+    (6, TransactionType::Representment),
+    (7, TransactionType::Hold),
+    (8, TransactionType::Release),
+    (9, TransactionType::Capture),
+    (10, TransactionType::WithdrawalPending),
+    (11, TransactionType::WithdrawalSettle),
+    (12, TransactionType::WithdrawalCancel),
+    (13, TransactionType::Accrue),
+];
+
+/// Wire types of the protobuf encoding.
+const VARINT: u64 = 0;
+const FIXED64: u64 = 1;
+const LENGTH_DELIMITED: u64 = 2;
+const FIXED32: u64 = 5;
+
+/// A `Transaction` message as encoded, before it is checked.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProtoTransaction {
+    /// A `TransactionType` value of the schema, possibly one this build doesn't know.
+    pub r#type: i32,
+    pub client: u32,
+    pub tx: u32,
+    /// Decimal amount, empty for transactions without one.
+    pub amount: String,
+    pub timestamp: Option<u64>,
+}
+
+impl ProtoTransaction {
+    /// Decodes a message, skipping the fields it doesn't know.
+    ///
+    /// # Errors
+    ///
+    /// Returns why the bytes aren't a valid message.
+    pub fn decode(mut buf: &[u8]) -> Result<Self, String> {
+        let mut message = ProtoTransaction::default();
+        while !buf.is_empty() {
+            let key = read_varint(&mut buf)?;
+            let (field, wire_type) = (key >> 3, key & 7);
+            match (field, wire_type) {
+                (1, VARINT) => message.r#type = read_varint(&mut buf)? as i32,
+                (2, VARINT) => message.client = read_varint(&mut buf)? as u32,
+                (3, VARINT) => message.tx = read_varint(&mut buf)? as u32,
+                (4, LENGTH_DELIMITED) => {
+                    let bytes = read_bytes(&mut buf)?;
+                    message.amount = String::from_utf8(bytes.to_vec())
+                        .map_err(|_| "the amount isn't valid UTF-8".to_owned())?;
+                }
+                (5, VARINT) => message.timestamp = Some(read_varint(&mut buf)?),
+                (1..=5, _) => return Err(format!("field {} has wire type {}", field, wire_type)),
+                (_, VARINT) => {
+                    read_varint(&mut buf)?;
+                }
+                (_, FIXED64) => {
+                    take(&mut buf, 8)?;
+                }
+                (_, LENGTH_DELIMITED) => {
+                    read_bytes(&mut buf)?;
+                }
+                (_, FIXED32) => {
+                    take(&mut buf, 4)?;
+                }
+                (_, _) => return Err(format!("unsupported wire type {}", wire_type)),
+            }
+        }
+        Ok(message)
+    }
+
+    /// Encodes the message, leaving out the fields at their default value like proto3 does.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let varint_field = |buf: &mut Vec<u8>, field: u64, value: u64| {
+            write_varint(buf, field << 3 | VARINT);
+            write_varint(buf, value);
+        };
+        if self.r#type != 0 {
+            // negative enum values are encoded on ten bytes, as protobuf does
+            varint_field(&mut buf, 1, self.r#type as i64 as u64);
+        }
+        if self.client != 0 {
+            varint_field(&mut buf, 2, self.client.into());
+        }
+        if self.tx != 0 {
+            varint_field(&mut buf, 3, self.tx.into());
+        }
+        if !self.amount.is_empty() {
+            write_varint(&mut buf, 4 << 3 | LENGTH_DELIMITED);
+            write_varint(&mut buf, self.amount.len() as u64);
+            buf.extend_from_slice(self.amount.as_bytes());
+        }
+        if let Some(timestamp) = self.timestamp {
+            varint_field(&mut buf, 5, timestamp);
+        }
+        buf
+    }
+
+    /// Encodes the message preceded by its length, as messages are streamed.
+    pub fn encode_length_delimited(&self) -> Vec<u8> {
+        let message = self.encode();
+        let mut buf = Vec::with_capacity(message.len() + 2);
+        write_varint(&mut buf, message.len() as u64);
+        buf.extend_from_slice(&message);
+        buf
+    }
+
+    /// Returns the fields as a raw record under `PROTO_COLUMNS`.
+    fn record(&self) -> StringRecord {
+        let r#type = TRANSACTION_TYPES
+            .iter()
+            .find(|(value, _)| *value == self.r#type)
+            .map_or_else(|| self.r#type.to_string(), |(_, r#type)| r#type.as_str().to_owned());
+        StringRecord::from(vec![
+            r#type,
+            self.client.to_string(),
+            self.tx.to_string(),
+            self.amount.clone(),
+            self.timestamp.map(|timestamp| timestamp.to_string()).unwrap_or_default(),
+        ])
+    }
+}
+
+impl From<&Transaction> for ProtoTransaction {
+    // client ids are already `u32` with wide-client-ids
+    #[allow(clippy::useless_conversion)]
+    fn from(txn: &Transaction) -> Self {
+        ProtoTransaction {
+            r#type: TRANSACTION_TYPES
+                .iter()
+                .find(|(_, r#type)| *r#type == txn.r#type)
+                .map_or(0, |(value, _)| *value),
+            client: txn.client.into(),
+            tx: txn.tx,
+            amount: txn.amount.map(|amount| amount.to_string()).unwrap_or_default(),
+            timestamp: txn.timestamp,
+        }
+    }
+}
+
+impl TryFrom<ProtoTransaction> for Transaction {
+    type Error = String;
+
+    /// Checks a message like the CSV parser checks a row.
+    fn try_from(message: ProtoTransaction) -> Result<Self, String> {
+        let r#type = TRANSACTION_TYPES
+            .iter()
+            .find(|(value, _)| *value == message.r#type)
+            .map(|(_, r#type)| *r#type)
+            .ok_or_else(|| format!("unknown transaction type {}", message.r#type))?;
+        let client = ClientId::try_from(message.client).map_err(|_| {
+            format!(
+                "client id {} is out of range, the largest supported is {}",
+                message.client,
+                ClientId::MAX
+            )
+        })?;
+        let amount = match message.amount.trim() {
+            "" => None,
+            amount => Some(
+                amount
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|amount| amount.is_finite())
+                    .ok_or_else(|| format!("invalid amount '{}'", amount))?,
+            ),
+        };
+        Ok(Transaction {
+            timestamp: message.timestamp,
+            ..Transaction::new(r#type, client, message.tx, amount)
+        })
+    }
+}
+
+/// Reads length-delimited `Transaction` messages, yielding them with their index in the stream
+/// (from 1). After a message that doesn't decode comes the next one, but a malformed length
+/// prefix or a truncated message ends the stream, as the messages after it can't be found.
+pub struct ProtoReader<R> {
+    reader: BufReader<R>,
+    index: u64,
+    done: bool,
+    /// The bytes of the last message, if it failed to decode.
+    undecoded: Vec<u8>,
+}
+
+impl<R: Read> ProtoReader<R> {
+    pub fn new(reader: R) -> Self {
+        ProtoReader {
+            reader: BufReader::new(reader),
+            index: 0,
+            done: false,
+            undecoded: Vec::new(),
+        }
+    }
+
+    /// Takes the bytes of the last message read if it failed to decode, empty if its framing
+    /// was broken.
+    pub fn undecoded(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.undecoded)
+    }
+
+    /// Reads the next message, `None` at the end of the stream. Errors in the framing of the
+    /// messages end the stream.
+    fn next_message(&mut self) -> Option<Result<ProtoTransaction, String>> {
+        let framed = match read_length_prefix(&mut self.reader) {
+            Ok(None) => return None,
+            Ok(Some(len)) if len > MAX_MESSAGE_BYTES => Err(format!(
+                "malformed length prefix: {} bytes is over the {} bytes of the largest message",
+                len, MAX_MESSAGE_BYTES
+            )),
+            Ok(Some(len)) => {
+                let mut message = vec![0; len as usize];
+                match self.reader.read_exact(&mut message) {
+                    Ok(()) => Ok(message),
+                    Err(err) => Err(format!("truncated message of {} bytes: {}", len, err)),
+                }
+            }
+            Err(reason) => Err(reason),
+        };
+        match framed {
+            Ok(message) => {
+                let decoded = ProtoTransaction::decode(&message);
+                if decoded.is_err() {
+                    self.undecoded = message;
+                }
+                Some(decoded)
+            }
+            Err(reason) => {
+                self.done = true;
+                Some(Err(reason))
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for ProtoReader<R> {
+    type Item = (u64, Result<ProtoTransaction, PaymentError>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let message = self.next_message()?;
+        self.index += 1;
+        let index = self.index;
+        let message = message.map_err(|reason| PaymentError::DecodeError { index, reason });
+        Some((self.index, message))
+    }
+}
+
+/// Parses length-delimited `Transaction` messages from a reader, lazily like
+/// `parser::parse_transactions`. Invalid messages are `PaymentError::DecodeError`s with their
+/// index in the stream.
+pub fn parse_transactions_proto<R: Read>(
+    reader: R,
+) -> impl Iterator<Item = Result<Transaction, PaymentError>> {
+    ProtoReader::new(reader)
+        .map(|(index, message)| message.and_then(|message| checked(index, message)))
+}
+
+/// Parses length-delimited `Transaction` messages like `parser::parse_records` parses CSV rows:
+/// the records' line is the index of their message, and their raw fields are under
+/// `PROTO_COLUMNS`, returned as the header.
+pub fn parse_records_proto(
+    reader: Box<dyn Read>,
+) -> (StringRecord, Box<dyn Iterator<Item = ParsedRecord>>) {
+    let mut messages = ProtoReader::new(reader);
+    let records = std::iter::from_fn(move || {
+        let (index, message) = messages.next()?;
+        Some(match message {
+            Ok(message) => ParsedRecord {
+                line: index,
+                raw: message.record(),
+                undecoded: None,
+                source: None,
+                transaction: checked(index, message),
+            },
+            Err(err) => ParsedRecord::undecodable_message(index, &messages.undecoded(), err),
+        })
+    });
+    (StringRecord::from(PROTO_COLUMNS.split(',').collect::<Vec<_>>()), Box::new(records))
+}
+
+fn checked(index: u64, message: ProtoTransaction) -> Result<Transaction, PaymentError> {
+    Transaction::try_from(message).map_err(|reason| PaymentError::DecodeError { index, reason })
+}
+
+/// Reads the varint length of the next message, `None` at the end of the stream.
+fn read_length_prefix<R: BufRead>(reader: &mut R) -> Result<Option<u64>, String> {
+    let mut value = 0u64;
+    for shift in (0..70).step_by(7) {
+        let mut byte = [0];
+        match reader.read(&mut byte) {
+            Ok(0) if shift == 0 => return Ok(None),
+            Ok(0) => return Err("malformed length prefix: the stream ends within it".to_owned()),
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(format!("unreadable length prefix: {}", err)),
+        }
+        value |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+    }
+    Err("malformed length prefix: longer than ten bytes".to_owned())
+}
+
+fn read_varint(buf: &mut &[u8]) -> Result<u64, String> {
+    let mut value = 0u64;
+    for shift in (0..70).step_by(7) {
+        let (&byte, rest) = buf.split_first().ok_or("the message ends within a varint")?;
+        *buf = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("varint longer than ten bytes".to_owned())
+}
+
+fn read_bytes<'a>(buf: &mut &'a [u8]) -> Result<&'a [u8], String> {
+    let len = read_varint(buf)?;
+    take(buf, usize::try_from(len).unwrap_or(usize::MAX))
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8], String> {
+    if buf.len() < len {
+        return Err(format!("a field of {} bytes goes past the end of the message", len));
+    }
+    let (taken, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(taken)
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        amount::amount,
+        errors::PaymentError,
+        payment_engine::PaymentEngine,
+        proto::{parse_records_proto, parse_transactions_proto, ProtoTransaction},
+        types::{Transaction, TransactionType},
+    };
+
+    fn message(r#type: i32, client: u32, tx: u32, amount: &str) -> ProtoTransaction {
+        ProtoTransaction {
+            r#type,
+            client,
+            tx,
+            amount: amount.to_owned(),
+            timestamp: None,
+        }
+    }
+
+    fn stream(messages: &[ProtoTransaction]) -> Vec<u8> {
+        messages.iter().flat_map(ProtoTransaction::encode_length_delimited).collect()
+    }
+
+    #[tokio::test]
+    async fn can_process_protobuf_messages() -> Result<(), PaymentError> {
+        let mut deposit = message(1, 1, 1, "10.1234");
+        deposit.timestamp = Some(1_700_000_000);
+        let mut with_unknown_field = message(1, 2, 2, "5.0").encode();
+        // field 15, a fixed64 the engine doesn't know of
+        with_unknown_field.extend([15 << 3 | 1, 1, 2, 3, 4, 5, 6, 7, 8]);
+        let mut bytes = stream(&[deposit]);
+        bytes.push(with_unknown_field.len() as u8);
+        bytes.extend(&with_unknown_field);
+        let withdrawal = message(2, 1, 3, "0.1234");
+        bytes.extend(stream(&[withdrawal, message(3, 2, 2, ""), message(5, 2, 2, "")]));
+
+        let transactions: Vec<Transaction> =
+            parse_transactions_proto(bytes.as_slice()).collect::<Result<_, _>>()?;
+        assert_eq!(transactions.len(), 5);
+        assert_eq!(transactions[0].timestamp, Some(1_700_000_000));
+        assert_eq!(transactions[3], Transaction::dispute(2, 2));
+
+        let mut engine = PaymentEngine::new();
+        let transactions = parse_transactions_proto(bytes.as_slice());
+        engine.process_all(Box::new(transactions)).await.into_result()?;
+        let client = engine.client_view(1).expect("client 1 has an account");
+        assert_eq!((client.available, client.total), (amount(10.0), amount(10.0)));
+        let client = engine.client_view(2).expect("client 2 has an account");
+        assert!(client.is_locked());
+        assert_eq!(client.total, amount(0.0));
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_messages_are_decode_errors() {
+        let unknown_type = message(42, 1, 2, "1.0");
+        let mut bytes = stream(&[message(1, 1, 1, "1.0"), unknown_type, message(1, 1, 3, "abc")]);
+        // the wire type of the client field is wrong
+        bytes.extend([2, 2 << 3 | 2, 0]);
+        bytes.extend(stream(&[message(-1, 1, 4, "1.0"), message(1, 1, 5, "2.0")]));
+        // a length prefix of eleven bytes, after which nothing can be read
+        bytes.extend([0xff; 11]);
+        bytes.extend(stream(&[message(1, 1, 6, "3.0")]));
+
+        let errors: Vec<String> = parse_transactions_proto(bytes.as_slice())
+            .map(|result| result.map_or_else(|err| err.to_string(), |txn| format!("tx {}", txn.tx)))
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "tx 1",
+                "Decode error: message 2: unknown transaction type 42",
+                "Decode error: message 3: invalid amount 'abc'",
+                "Decode error: message 4: field 2 has wire type 2",
+                "Decode error: message 5: unknown transaction type -1",
+                "tx 5",
+                "Decode error: message 7: malformed length prefix: longer than ten bytes",
+            ]
+        );
+
+        // a message cut short ends the stream too
+        let mut bytes = stream(&[message(1, 1, 1, "1.0")]);
+        bytes.extend([20, 1 << 3]);
+        let results: Vec<_> = parse_transactions_proto(bytes.as_slice()).collect();
+        assert_eq!(results.len(), 2);
+        assert!(matches!(results[1], Err(PaymentError::DecodeError { index: 2, .. })));
+    }
+
+    #[test]
+    fn records_keep_the_fields_of_their_message() {
+        let bytes = stream(&[message(4, 3, 9, ""), message(0, 3, 10, "1.0")]);
+        let (header, records) = parse_records_proto(Box::new(std::io::Cursor::new(bytes)));
+        let columns: Vec<&str> = header.iter().collect();
+        assert_eq!(columns, ["type", "client", "tx", "amount", "timestamp"]);
+        let records: Vec<_> = records.collect();
+        assert_eq!(records[0].raw.iter().collect::<Vec<_>>(), ["resolve", "3", "9", "", ""]);
+        let resolve = records[0].transaction.as_ref().map(|txn| txn.r#type);
+        assert_eq!(resolve.ok(), Some(TransactionType::Resolve));
+        assert_eq!((records[1].line, records[1].raw.get(0)), (2, Some("0")));
+        assert!(records[1].transaction.is_err());
+    }
+}
@@ -0,0 +1,204 @@
+use crate::errors::PaymentError;
+use csv::{ByteRecord, StringRecord};
+use std::{
+    fmt::Display,
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+/// Writes rows that failed to parse or were rejected by the engine to a CSV for reprocessing.
+///
+/// Rows are written exactly as they appeared in the input: fields are untrimmed and joined with
+/// commas, only fields that needed quoting in the first place (containing a comma, quote or
+/// line break) are quoted again. Rows that aren't valid UTF-8 are written byte for byte, so the
+/// quarantine holds what was actually received. Two columns are appended, `line` and `reason`.
+/// In multi-file runs the line is given as `path:line`, and every row keeps the columns of its
+/// own file: the rows of a file whose header differs from the one before it follow that file's
+/// own header.
+///
+/// Since the parser ignores unknown columns, the quarantine file can be fed back to the engine
+/// once the offending rows have been fixed, split at its headers if it has several.
+pub struct QuarantineWriter<W: Write> {
+    w: W,
+    rows: u64,
+    /// The input header the rows are written under.
+    header: StringRecord,
+}
+
+impl QuarantineWriter<BufWriter<File>> {
+    /// Creates (or truncates) the quarantine file at `path` and writes its header.
+    pub fn create(path: &str, raw_headers: &StringRecord) -> Result<Self, PaymentError> {
+        let file = File::create(path).map_err(|err| PaymentError::file(path, err))?;
+        QuarantineWriter::new(BufWriter::new(file), raw_headers)
+    }
+}
+
+impl<W: Write> QuarantineWriter<W> {
+    /// Wraps a writer, writing the input's header followed by the `line` and `reason` columns.
+    pub fn new(w: W, raw_headers: &StringRecord) -> Result<Self, PaymentError> {
+        let mut writer = QuarantineWriter {
+            w,
+            rows: 0,
+            header: raw_headers.clone(),
+        };
+        writer.write_row(raw_headers.as_byte_record(), &["line", "reason"])?;
+        Ok(writer)
+    }
+
+    /// Starts the rows of another input, writing its header first if it differs from the one
+    /// the rows so far are under.
+    pub fn start_source(&mut self, raw_headers: &StringRecord) -> Result<(), PaymentError> {
+        if *raw_headers != self.header {
+            self.write_row(raw_headers.as_byte_record(), &["line", "reason"])?;
+            self.header = raw_headers.clone();
+        }
+        Ok(())
+    }
+
+    /// Appends a raw input row along with its line number (or `path:line` location) and the
+    /// reason it was quarantined.
+    pub fn quarantine(
+        &mut self,
+        raw: &ByteRecord,
+        line: impl Display,
+        reason: &str,
+    ) -> Result<(), PaymentError> {
+        self.write_row(raw, &[&line.to_string(), reason])?;
+        self.rows += 1;
+        Ok(())
+    }
+
+    /// Returns the number of rows quarantined so far.
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    /// Flushes the rows written so far.
+    pub fn flush(&mut self) -> Result<(), PaymentError> {
+        self.w
+            .flush()
+            .map_err(|err| PaymentError::IoError(err.to_string()))
+    }
+
+    fn write_row(&mut self, raw: &ByteRecord, extra: &[&str]) -> Result<(), PaymentError> {
+        let mut line = Vec::new();
+        for field in raw.iter().chain(extra.iter().map(|field| field.as_bytes())) {
+            if !line.is_empty() {
+                line.push(b',');
+            }
+            push_field(&mut line, field);
+        }
+        line.push(b'\n');
+        self.w
+            .write_all(&line)
+            .map_err(|err| PaymentError::IoError(err.to_string()))
+    }
+}
+
+fn push_field(line: &mut Vec<u8>, field: &[u8]) {
+    if field.iter().any(|byte| matches!(byte, b',' | b'"' | b'\n' | b'\r')) {
+        line.push(b'"');
+        for &byte in field {
+            if byte == b'"' {
+                line.push(b'"');
+            }
+            line.push(byte);
+        }
+        line.push(b'"');
+    } else {
+        line.extend_from_slice(field);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        errors::PaymentError,
+        parser::parse_records,
+        payment_engine::PaymentEngine,
+        quarantine::QuarantineWriter,
+        types::{ProcessOutcome, TransactionType},
+    };
+
+    async fn quarantine_input(csv: &str) -> Result<String, PaymentError> {
+        let output = quarantine_bytes(csv.as_bytes()).await?;
+        Ok(String::from_utf8(output).expect("quarantine output should be utf-8"))
+    }
+
+    async fn quarantine_bytes(csv: &[u8]) -> Result<Vec<u8>, PaymentError> {
+        let str_buf = std::io::Cursor::new(csv.to_vec());
+        let (headers, records) = parse_records(Box::new(str_buf)).await?;
+        let mut engine = PaymentEngine::new();
+        let mut quarantine = QuarantineWriter::new(Vec::new(), &headers)?;
+
+        for record in records {
+            match record.transaction {
+                Ok(txn) => {
+                    if let ProcessOutcome::Ignored(reason) = engine.process_transaction(txn).await? {
+                        quarantine.quarantine(record.raw.as_byte_record(), record.line, reason.as_str())?;
+                    }
+                }
+                Err(_) => quarantine.quarantine(record.raw_bytes(), record.line, "parse_error")?,
+            }
+        }
+        Ok(quarantine.w)
+    }
+
+    #[tokio::test]
+    async fn can_quarantine_raw_rows_verbatim() -> Result<(), PaymentError> {
+        let csv = "type,  client, tx, amount
+deposit, 1, 1, 1.0
+withdrawal,   1,  2,   5.0
+deposit, 1, 3, not-a-number
+dispute, 2, 1";
+        let output = quarantine_input(csv).await?;
+
+        assert_eq!(
+            output,
+            "type,  client, tx, amount,line,reason
+withdrawal,   1,  2,   5.0,3,insufficient_funds
+deposit, 1, 3, not-a-number,4,parse_error
+dispute, 2, 1,5,client_mismatch
+"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn rows_that_are_not_utf8_are_quarantined_byte_for_byte() -> Result<(), PaymentError> {
+        let csv = b"type, client, tx, amount, memo\ndeposit, 1, 1, 1.0, caf\xe9\ndeposit, 1, 2, 2.0,\"a,\xff\"\n";
+        let output = quarantine_bytes(csv).await?;
+        assert_eq!(
+            output,
+            b"type, client, tx, amount, memo,line,reason\n\
+              deposit, 1, 1, 1.0, caf\xe9,2,parse_error\n\
+              deposit, 1, 2, 2.0,\"a,\xff\",3,parse_error\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_refeed_fixed_quarantine_file() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+deposit, 1, 1, 1.0
+deposit, 1, 2, 2.O";
+        let output = quarantine_input(csv).await?;
+        assert_eq!(
+            output,
+            "type, client, tx, amount,line,reason\ndeposit, 1, 2, 2.O,3,parse_error\n"
+        );
+
+        // upstream fixes the amount, the extra columns are ignored on the way back in
+        let fixed = output.replace("2.O,3,parse_error", "2.0,3,parse_error");
+        let str_buf = std::io::Cursor::new(fixed);
+        let (_, records) = parse_records(Box::new(str_buf)).await?;
+        let mut engine = PaymentEngine::new();
+        for record in records {
+            let txn = record.transaction?;
+            assert_eq!(txn.r#type, TransactionType::Deposit);
+            assert_eq!(engine.process_transaction(txn).await?, ProcessOutcome::Applied);
+        }
+        assert_eq!(engine.clients.get(&1).map(|client| client.total.to_f64()), Some(2.0));
+        Ok(())
+    }
+}
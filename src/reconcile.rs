@@ -0,0 +1,276 @@
+use crate::{
+    amount::Amount,
+    config::EngineConfig,
+    diff::{diff_states, ClientDiff, DiffKind, FieldChange},
+    errors::PaymentError,
+    parser::parse_transactions,
+    payment_engine::PaymentEngine,
+    report::{OutputOrder, Rounding},
+    types::{ClientId, Transaction, TransactionType},
+};
+use std::{
+    collections::HashSet,
+    io::{self, Read, Write},
+};
+
+/// Most open disputes of a client searched for outcomes. Clients with more are reported as
+/// unreconcilable rather than searched for ever.
+pub const MAX_SEARCHED_DISPUTES: usize = 12;
+
+/// Outcome of reconciling a transaction log with lost dispute outcomes against a claimed state.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reconciliation {
+    /// Clients whose computed state already matches the claimed one.
+    pub matched: usize,
+    /// Resolves and chargebacks of open disputes that bring the computed state to the claimed
+    /// one, ordered by client and tx id.
+    pub proposed: Vec<Transaction>,
+    /// Clients that no outcome of their open disputes reconciles. In each diff the computed
+    /// state is the "before" side and the claimed state the "after", so the diff is the residual.
+    pub unreconcilable: Vec<ClientDiff>,
+}
+
+impl Reconciliation {
+    /// Returns `true` if the proposed operations reconcile every client.
+    pub fn is_reconciled(&self) -> bool {
+        self.unreconcilable.is_empty()
+    }
+
+    /// Writes the proposed operations as a transactions CSV, to be reviewed and appended to
+    /// the log.
+    pub fn write_proposed<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "type,client,tx,amount")?;
+        for txn in &self.proposed {
+            writeln!(w, "{},{},{},", txn.r#type.as_str(), txn.client, txn.tx)?;
+        }
+        w.flush()
+    }
+}
+
+/// Processes the transactions and looks for the fewest resolves and chargebacks of the disputes
+/// they leave open that would give the accounts of `claimed_state`, an account states report.
+///
+/// Outcomes are searched per client from the amounts each dispute holds, then replayed on the
+/// engine: a client is only reconciled if the replay gives exactly its claimed balances and
+/// lock, compared at the report's four decimal places.
+pub async fn reconcile(
+    transactions: Box<dyn Read>,
+    mut claimed_state: impl Read,
+) -> Result<Reconciliation, PaymentError> {
+    let mut engine = PaymentEngine::new().with_config(EngineConfig {
+        fail_fast: true,
+        ..Default::default()
+    });
+    engine.process_all(parse_transactions(transactions).await?).await.into_result()?;
+
+    let mut claimed = Vec::new();
+    claimed_state
+        .read_to_end(&mut claimed)
+        .map_err(|err| PaymentError::IoError(err.to_string()))?;
+    let gaps = diff_states(computed_state(&engine)?.as_slice(), claimed.as_slice())?;
+    let unmatched = gaps
+        .iter()
+        .filter(|gap| !matches!(gap.kind, DiffKind::Added(_)))
+        .count();
+
+    let mut proposed = Vec::new();
+    for gap in &gaps {
+        if let Some(outcomes) = close_gap(&engine, gap)? {
+            proposed.extend(outcomes);
+        }
+    }
+    for txn in &proposed {
+        engine.process_transaction(txn.clone()).await?;
+    }
+
+    // a client whose outcomes don't replay as computed keeps none of them
+    let residual: HashSet<ClientId> = diff_states(computed_state(&engine)?.as_slice(), claimed.as_slice())?
+        .iter()
+        .map(|gap| gap.client)
+        .collect();
+    proposed.retain(|txn| !residual.contains(&txn.client));
+    Ok(Reconciliation {
+        matched: engine.clients.len() - unmatched,
+        proposed,
+        unreconcilable: gaps.into_iter().filter(|gap| residual.contains(&gap.client)).collect(),
+    })
+}
+
+fn computed_state(engine: &PaymentEngine) -> Result<Vec<u8>, PaymentError> {
+    let mut computed = Vec::new();
+    engine
+        .write_client_states(&mut computed, None, OutputOrder::default())
+        .map_err(|err| PaymentError::IoError(err.to_string()))?;
+    Ok(computed)
+}
+
+/// Returns the fewest resolves and chargebacks of the client's open disputes that close the
+/// gap, if any.
+fn close_gap(engine: &PaymentEngine, gap: &ClientDiff) -> Result<Option<Vec<Transaction>>, PaymentError> {
+    let DiffKind::Changed(changes) = &gap.kind else {
+        return Ok(None);
+    };
+    let delta = |before: f64, after: f64| -> Result<i128, PaymentError> {
+        let before = Amount::from_f64(before, Rounding::HalfEven)?;
+        let after = Amount::from_f64(after, Rounding::HalfEven)?;
+        Ok(after.units() as i128 - before.units() as i128)
+    };
+    let locked = engine.clients.get(&gap.client).is_some_and(|client| client.locked);
+    let (mut available, mut held, mut total, mut lock) = (0, 0, 0, (locked, locked));
+    for change in changes {
+        match *change {
+            FieldChange::Available { before, after } => available = delta(before, after)?,
+            FieldChange::Held { before, after } => held = delta(before, after)?,
+            FieldChange::Total { before, after } => total = delta(before, after)?,
+            FieldChange::Locked { before, after } => lock = (before, after),
+        }
+    }
+    // a resolve moves its amount from held to available, a chargeback takes it out of held and
+    // total and locks the account, which nothing here unlocks
+    let chargeback = match lock {
+        (true, false) => return Ok(None),
+        (true, true) => None,
+        (false, after) => Some(after),
+    };
+    if held != -available + total {
+        return Ok(None);
+    }
+
+    let mut disputes = Vec::new();
+    for (tx, txn) in &engine.disputed_transactions {
+        if txn.client == gap.client {
+            let amount = txn.amount.map(|amount| Amount::from_f64(amount, Rounding::HalfEven)).transpose()?;
+            disputes.push((*tx, amount.unwrap_or_default().units() as i128));
+        }
+    }
+    if disputes.len() > MAX_SEARCHED_DISPUTES {
+        return Ok(None);
+    }
+    disputes.sort();
+
+    let target = Outcomes {
+        resolved: available,
+        charged: -total,
+        chargeback,
+    };
+    let mut picks = Vec::new();
+    for count in 1..=disputes.len() {
+        if pick(&disputes, 0, count, (0, 0), &target, &mut picks) {
+            let outcomes = picks
+                .iter()
+                .map(|&(tx, kind)| match kind {
+                    TransactionType::Resolve => Transaction::resolve(gap.client, tx),
+                    _ => Transaction::chargeback(gap.client, tx),
+                })
+                .collect();
+            return Ok(Some(outcomes));
+        }
+    }
+    Ok(None)
+}
+
+/// What the outcomes of a client's disputes must add up to, in amount units.
+struct Outcomes {
+    /// The amount of the resolved disputes.
+    resolved: i128,
+    /// The amount of the charged back disputes.
+    charged: i128,
+    /// Whether there must be a chargeback, if it matters.
+    chargeback: Option<bool>,
+}
+
+/// Picks `count` more disputes from `start` on, each resolved or charged back, so the outcomes
+/// add up to `target` with the `sums` (resolved, charged) so far.
+fn pick(
+    disputes: &[(u32, i128)],
+    start: usize,
+    count: usize,
+    sums: (i128, i128),
+    target: &Outcomes,
+    picks: &mut Vec<(u32, TransactionType)>,
+) -> bool {
+    if count == 0 {
+        let charged_back = picks.iter().any(|(_, kind)| *kind == TransactionType::Chargeback);
+        return sums == (target.resolved, target.charged)
+            && target.chargeback.is_none_or(|chargeback| chargeback == charged_back);
+    }
+    for index in start..disputes.len() {
+        let (tx, held) = disputes[index];
+        for (kind, sums) in [
+            (TransactionType::Resolve, (sums.0 + held, sums.1)),
+            (TransactionType::Chargeback, (sums.0, sums.1 + held)),
+        ] {
+            picks.push((tx, kind));
+            if pick(disputes, index + 1, count - 1, sums, target, picks) {
+                return true;
+            }
+            picks.pop();
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{errors::PaymentError, reconcile::reconcile, types::Transaction};
+
+    /// Client 1's resolve of tx 2 and client 2's chargeback of tx 3 are missing from the log.
+    const TRUNCATED: &str = "type, client, tx, amount
+        deposit, 1, 1, 10.0
+        deposit, 1, 2, 5.0
+        dispute, 1, 1
+        dispute, 1, 2
+        deposit, 2, 3, 4.0
+        deposit, 2, 4, 1.0
+        dispute, 2, 3
+        dispute, 2, 4
+        resolve, 2, 4";
+
+    #[tokio::test]
+    async fn proposes_the_lost_outcomes_back() -> Result<(), PaymentError> {
+        let claimed = "client,available,held,total,locked
+1,5.0000,10.0000,15.0000,false
+2,1.0000,0.0000,1.0000,true
+";
+        let str_buf = stringreader::StringReader::new(TRUNCATED);
+        let reconciliation = reconcile(Box::new(str_buf), claimed.as_bytes()).await?;
+
+        assert!(reconciliation.is_reconciled());
+        assert_eq!(reconciliation.matched, 0);
+        assert_eq!(
+            reconciliation.proposed,
+            [Transaction::resolve(1, 2), Transaction::chargeback(2, 3)]
+        );
+        let mut csv = Vec::new();
+        reconciliation.write_proposed(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "type,client,tx,amount\nresolve,1,2,\nchargeback,2,3,\n"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reports_the_residual_of_unreconcilable_clients() -> Result<(), PaymentError> {
+        // no outcome of client 1's disputes moves 7, and taking 4 from client 2 would lock it
+        let claimed = "client,available,held,total,locked
+1,7.0000,8.0000,15.0000,false
+2,1.0000,0.0000,1.0000,false
+";
+        let str_buf = stringreader::StringReader::new(TRUNCATED);
+        let reconciliation = reconcile(Box::new(str_buf), claimed.as_bytes()).await?;
+
+        assert!(!reconciliation.is_reconciled());
+        assert!(reconciliation.proposed.is_empty());
+        assert_eq!(reconciliation.matched, 0);
+        let residuals: Vec<_> = reconciliation.unreconcilable.iter().map(|gap| gap.to_string()).collect();
+        assert_eq!(
+            residuals,
+            [
+                "~ client 1: available 0.0000 -> 7.0000 (+7.0000), held 15.0000 -> 8.0000 (-7.0000)",
+                "~ client 2: held 4.0000 -> 0.0000 (-4.0000), total 5.0000 -> 1.0000 (-4.0000)",
+            ]
+        );
+        Ok(())
+    }
+}
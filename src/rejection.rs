@@ -0,0 +1,341 @@
+//! Rejected rows in the one shape shared by the rejections report, the quarantine, the warnings
+//! channel and the errors of strict runs.
+
+use crate::{
+    errors::PaymentError,
+    types::{ClientId, IgnoreReason, Transaction, TransactionType},
+};
+use csv::{ReaderBuilder, Trim, WriterBuilder};
+use serde::{de::IntoDeserializer, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    fmt,
+    fs::File,
+    io::{BufWriter, Read, Write},
+};
+
+/// Header line of the rejections report in CSV.
+pub const REJECTIONS_HEADER: &str = "source,line,client,tx,type,amount,reason,detail,idempotency_key,memo";
+
+/// Why a row was rejected: the reason the engine ignored it, or a parse error for a row that
+/// never reached the engine.
+///
+/// Serialized as the reason's name, `parse_error` or an `IgnoreReason::as_str`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RejectionReason {
+    Ignored(IgnoreReason),
+    ParseError,
+}
+
+impl RejectionReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RejectionReason::Ignored(reason) => reason.as_str(),
+            RejectionReason::ParseError => "parse_error",
+        }
+    }
+}
+
+impl From<IgnoreReason> for RejectionReason {
+    fn from(reason: IgnoreReason) -> Self {
+        RejectionReason::Ignored(reason)
+    }
+}
+
+impl fmt::Display for RejectionReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for RejectionReason {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for RejectionReason {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        match name.as_str() {
+            "parse_error" => Ok(RejectionReason::ParseError),
+            name => IgnoreReason::deserialize(name.into_deserializer()).map(RejectionReason::Ignored),
+        }
+    }
+}
+
+/// A row that was rejected, by the engine or because it failed to parse.
+///
+/// Serialized with the `REJECTIONS_HEADER` columns, or as a JSON object with the same keys,
+/// `idempotency_key` and `memo` only when set.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RejectionRecord {
+    /// Input file of the row, in multi-file runs.
+    pub source: Option<String>,
+    /// Line of the row in its input (the header is line 1), when the row came from one.
+    pub line: Option<u64>,
+    /// Client of the transaction. Like `tx` and `txn_type`, `None` for rows that failed to parse.
+    pub client: Option<ClientId>,
+    pub tx: Option<u32>,
+    #[serde(rename = "type")]
+    pub txn_type: Option<TransactionType>,
+    pub amount: Option<f64>,
+    pub reason: RejectionReason,
+    /// The reason with its context (`blocklisted: mule account`), or the parse error.
+    pub detail: String,
+    /// Idempotency key of the transaction, if it has one. Left out of the JSON without one, as
+    /// in the reports of releases before it was added.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+    /// Memo of the transaction as it was read, if it has one. Left out of the JSON without one,
+    /// like `idempotency_key`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memo: Option<String>,
+}
+
+impl RejectionRecord {
+    /// Returns the record of a transaction ignored for `reason`, see `PaymentEngine::rejection`
+    /// for the engine's own detail.
+    pub fn ignored(txn: &Transaction, reason: IgnoreReason, detail: String) -> Self {
+        RejectionRecord {
+            source: None,
+            line: None,
+            client: Some(txn.client),
+            tx: Some(txn.tx),
+            txn_type: Some(txn.r#type),
+            amount: txn.amount,
+            reason: RejectionReason::Ignored(reason),
+            detail,
+            idempotency_key: txn.idempotency_key.clone(),
+            memo: txn.memo.clone(),
+        }
+    }
+
+    /// Returns the record of the row at `line` that failed to parse with `err`.
+    pub fn unparseable(line: u64, err: &PaymentError) -> Self {
+        RejectionRecord {
+            source: None,
+            line: Some(line),
+            client: None,
+            tx: None,
+            txn_type: None,
+            amount: None,
+            reason: RejectionReason::ParseError,
+            detail: err.to_string(),
+            idempotency_key: None,
+            memo: None,
+        }
+    }
+
+    pub fn with_line(mut self, line: Option<u64>) -> Self {
+        self.line = line;
+        self
+    }
+
+    pub fn with_source(mut self, source: impl Into<String>) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    /// Returns where the row is: `path:line` in multi-file runs, the line otherwise, or an
+    /// empty string for rows that didn't come from an input.
+    pub fn location(&self) -> String {
+        match (&self.source, self.line) {
+            (Some(source), Some(line)) => format!("{}:{}", source, line),
+            (None, Some(line)) => line.to_string(),
+            (Some(source), None) => source.clone(),
+            (None, None) => String::new(),
+        }
+    }
+
+    /// Returns the error a strict run stops with on this rejection, found `at` a row of the
+    /// input (`line 7`, `row 6`...).
+    pub fn to_error(&self, at: impl fmt::Display) -> PaymentError {
+        PaymentError::IgnoredTransaction(format!("{}: {}", at, self))
+    }
+}
+
+impl fmt::Display for RejectionRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.client, self.tx) {
+            (Some(client), Some(tx)) => write!(f, "tx {} of client {}: {}", tx, client, self.detail),
+            _ => write!(f, "{}", self.detail),
+        }
+    }
+}
+
+/// Writes rejection records as they happen, as CSV under `REJECTIONS_HEADER` or as JSON lines.
+pub struct RejectionWriter<W: Write> {
+    output: Output<W>,
+    rows: u64,
+}
+
+enum Output<W: Write> {
+    Csv(Box<csv::Writer<W>>),
+    JsonLines(W),
+}
+
+impl RejectionWriter<BufWriter<File>> {
+    /// Creates (or truncates) the rejections report at `path`, written as JSON lines if the
+    /// path ends with `.jsonl` and as CSV otherwise.
+    pub fn create(path: &str) -> Result<Self, PaymentError> {
+        let file = File::create(path).map_err(|err| PaymentError::file(path, err))?;
+        let w = BufWriter::new(file);
+        if path.ends_with(".jsonl") {
+            Ok(RejectionWriter::json_lines(w))
+        } else {
+            RejectionWriter::csv(w)
+        }
+    }
+}
+
+impl<W: Write> RejectionWriter<W> {
+    /// Wraps a writer, writing the CSV header.
+    pub fn csv(mut w: W) -> Result<Self, PaymentError> {
+        writeln!(w, "{}", REJECTIONS_HEADER).map_err(|err| PaymentError::IoError(err.to_string()))?;
+        let writer = WriterBuilder::new().has_headers(false).from_writer(w);
+        Ok(RejectionWriter {
+            output: Output::Csv(Box::new(writer)),
+            rows: 0,
+        })
+    }
+
+    /// Wraps a writer, writing one JSON object per line.
+    pub fn json_lines(w: W) -> Self {
+        RejectionWriter {
+            output: Output::JsonLines(w),
+            rows: 0,
+        }
+    }
+
+    pub fn write(&mut self, record: &RejectionRecord) -> Result<(), PaymentError> {
+        match &mut self.output {
+            // as a tuple, so that every row has the idempotency key and memo columns the JSON may
+            // leave out
+            Output::Csv(writer) => writer
+                .serialize((
+                    &record.source,
+                    record.line,
+                    record.client,
+                    record.tx,
+                    record.txn_type,
+                    record.amount,
+                    record.reason,
+                    &record.detail,
+                    &record.idempotency_key,
+                    &record.memo,
+                ))
+                .map_err(|err| PaymentError::IoError(err.to_string()))?,
+            Output::JsonLines(w) => serde_json::to_writer(&mut *w, record)
+                .map_err(|err| PaymentError::IoError(err.to_string()))
+                .and_then(|_| writeln!(w).map_err(|err| PaymentError::IoError(err.to_string())))?,
+        }
+        self.rows += 1;
+        Ok(())
+    }
+
+    /// Returns the number of records written so far.
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    /// Flushes the records written so far.
+    pub fn flush(&mut self) -> Result<(), PaymentError> {
+        match &mut self.output {
+            Output::Csv(writer) => writer.flush(),
+            Output::JsonLines(w) => w.flush(),
+        }
+        .map_err(|err| PaymentError::IoError(err.to_string()))
+    }
+}
+
+/// Reads a rejections report written as CSV.
+///
+/// # Errors
+///
+/// Returns a `PaymentError::CsvParseError` if a row isn't a valid rejection record.
+pub fn read_rejections<R: Read>(r: R) -> Result<Vec<RejectionRecord>, PaymentError> {
+    ReaderBuilder::new()
+        .trim(Trim::All)
+        .from_reader(r)
+        .deserialize()
+        .map(|record| record.map_err(|err| PaymentError::CsvParseError(err.to_string())))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        errors::PaymentError,
+        parser::parse_records,
+        payment_engine::PaymentEngine,
+        rejection::{read_rejections, Output, RejectionReason, RejectionRecord, RejectionWriter},
+        types::{IgnoreReason, ProcessOutcome, TransactionType},
+        warnings::EngineWarning,
+    };
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn reports_and_warnings_carry_the_same_records() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+deposit, 1, 1, 1.0
+withdrawal, 1, 2, 5.0
+deposit, 1, 3, abc
+dispute, 2, 1";
+        let (sender, mut receiver) = mpsc::channel(16);
+        let mut engine = PaymentEngine::new().with_warning_sink(sender);
+        let mut report = RejectionWriter::csv(Vec::new())?;
+
+        let (_, records) = parse_records(Box::new(std::io::Cursor::new(csv))).await?;
+        for record in records {
+            match record.transaction {
+                Ok(txn) => {
+                    let rejected = txn.clone();
+                    let outcome = engine.process_transaction_at(txn, Some(record.line)).await?;
+                    if let ProcessOutcome::Ignored(reason) = outcome {
+                        report.write(&engine.rejection(&rejected, reason).with_line(Some(record.line)))?;
+                    }
+                }
+                Err(err) => {
+                    let rejection = RejectionRecord::unparseable(record.line, &err);
+                    report.write(&rejection)?;
+                    engine.warn(EngineWarning::Rejected(rejection));
+                }
+            }
+        }
+        engine.close_warnings();
+        let mut warned = Vec::new();
+        while let Some(EngineWarning::Rejected(rejection)) = receiver.recv().await {
+            warned.push(rejection);
+        }
+
+        let Output::Csv(writer) = report.output else {
+            unreachable!("the report is written as CSV")
+        };
+        let reported = read_rejections(writer.into_inner().unwrap().as_slice())?;
+        assert_eq!(reported, warned);
+        assert_eq!(
+            reported.iter().map(|rejection| (rejection.line, rejection.reason)).collect::<Vec<_>>(),
+            [
+                (Some(3), RejectionReason::Ignored(IgnoreReason::InsufficientFunds)),
+                (Some(4), RejectionReason::ParseError),
+                (Some(5), RejectionReason::Ignored(IgnoreReason::ClientMismatch))
+            ]
+        );
+        assert_eq!(reported[0].txn_type, Some(TransactionType::Withdrawal));
+        assert_eq!(reported[0].amount, Some(5.0));
+        assert_eq!(reported[2].to_string(), "tx 1 of client 2: client_mismatch (tx 1 at line 2)");
+        Ok(())
+    }
+
+    #[test]
+    fn reasons_serialize_by_name() {
+        for (reason, name) in [
+            (RejectionReason::ParseError, "\"parse_error\""),
+            (RejectionReason::Ignored(IgnoreReason::DustAmount), "\"dust_amount\""),
+        ] {
+            assert_eq!(serde_json::to_string(&reason).ok().as_deref(), Some(name));
+            assert_eq!(serde_json::from_str::<RejectionReason>(name).ok(), Some(reason));
+        }
+        assert!(serde_json::from_str::<RejectionReason>("\"no_such_reason\"").is_err());
+    }
+}
@@ -0,0 +1,325 @@
+use crate::{
+    errors::PaymentError,
+    parser::{open_input, parse_transactions},
+    payment_engine::{write_client_row, PaymentEngine, CLIENT_STATES_HEADER},
+    types::{ClientId, ProcessOutcome, Transaction},
+};
+use std::io::{BufRead, Cursor, Write};
+
+/// Header synthesized in front of a bare row so it can go through `parse_transactions`.
+const ROW_HEADER: &str = "type,client,tx,amount";
+/// Number of applied transactions `.undo` can revert.
+const UNDO_DEPTH: usize = 1000;
+
+/// One line of REPL input.
+#[derive(Debug, PartialEq)]
+pub enum ReplCommand {
+    /// A bare CSV row, e.g. `deposit, 1, 1, 1.0`, to parse and apply.
+    Row(String),
+    /// `.client <id>`: show one client's state.
+    Client(ClientId),
+    /// `.summary`: show the number of clients and the sum of their balances.
+    Summary,
+    /// `.dump`: show every client's state, sorted by id.
+    Dump,
+    /// `.undo`: revert the most recently applied transaction.
+    Undo,
+    /// `.load <file.csv>`: apply every transaction of a CSV file (with its header).
+    Load(String),
+    /// `.help`: list the commands.
+    Help,
+    /// `.quit`: leave the REPL.
+    Quit,
+}
+
+impl ReplCommand {
+    /// Parses a line of input, returns `None` for blank lines.
+    pub fn parse(line: &str) -> Result<Option<Self>, PaymentError> {
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(None);
+        }
+        let Some(command) = line.strip_prefix('.') else {
+            return Ok(Some(ReplCommand::Row(line.to_owned())));
+        };
+
+        let invalid = |msg: String| PaymentError::InvalidCliArgument(msg);
+        let (name, argument) = match command.split_once(char::is_whitespace) {
+            Some((name, argument)) => (name, Some(argument.trim())),
+            None => (command, None),
+        };
+        let command = match (name, argument) {
+            ("client", Some(id)) => ReplCommand::Client(
+                id.parse()
+                    .map_err(|_| invalid(format!("invalid client id '{}'", id)))?,
+            ),
+            ("load", Some(path)) => ReplCommand::Load(path.to_owned()),
+            ("summary", None) => ReplCommand::Summary,
+            ("dump", None) => ReplCommand::Dump,
+            ("undo", None) => ReplCommand::Undo,
+            ("help", None) => ReplCommand::Help,
+            ("quit" | "exit", None) => ReplCommand::Quit,
+            ("client" | "load", None) => return Err(invalid(format!(".{} expects an argument", name))),
+            ("summary" | "dump" | "undo" | "help" | "quit" | "exit", Some(_)) => {
+                return Err(invalid(format!(".{} takes no argument", name)))
+            }
+            _ => return Err(invalid(format!("unknown command '.{}', try .help", name))),
+        };
+        Ok(Some(command))
+    }
+}
+
+/// An engine driven one line at a time, for exploring how transactions affect balances.
+pub struct Repl {
+    engine: PaymentEngine,
+    applied: u64,
+    ignored: u64,
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        Repl {
+            engine: PaymentEngine::new().with_undo_history(UNDO_DEPTH),
+            applied: 0,
+            ignored: 0,
+        }
+    }
+
+    /// Executes a command, writing its output to `out`. Returns `false` once asked to quit.
+    ///
+    /// Invalid rows and failing commands are reported on `out` (a `.load` stops at the first
+    /// malformed row, keeping the rows before it); only I/O errors on `out` and storage errors
+    /// are returned.
+    pub async fn execute<W: Write>(
+        &mut self,
+        command: ReplCommand,
+        mut out: W,
+    ) -> Result<bool, PaymentError> {
+        match command {
+            ReplCommand::Row(row) => match parse_row(&row).await {
+                Ok(txn) => {
+                    let outcome = self.apply(txn).await?;
+                    match outcome {
+                        ProcessOutcome::Applied => writeln!(out, "applied"),
+                        ProcessOutcome::Ignored(reason) => {
+                            writeln!(out, "ignored: {}", reason.as_str())
+                        }
+                    }
+                    .map_err(output_error)?;
+                }
+                Err(err) => writeln!(out, "error: {}", err).map_err(output_error)?,
+            },
+            ReplCommand::Client(id) => match self.engine.clients.get(&id) {
+                Some(client) => writeln!(out, "{}", CLIENT_STATES_HEADER)
+                    .and_then(|_| write_client_row(&mut out, id, client))
+                    .map_err(output_error)?,
+                None => writeln!(out, "unknown client {}", id).map_err(output_error)?,
+            },
+            ReplCommand::Summary => {
+                writeln!(
+                    out,
+                    "clients: {} ({} locked), transactions applied: {}, ignored: {}\n\
+                     available: {:.4}, held: {:.4}, total: {:.4}",
+                    self.engine.clients.len(),
+                    self.engine.locked_count(),
+                    self.applied,
+                    self.ignored,
+                    self.engine.total_available(),
+                    self.engine.total_held(),
+                    self.engine.total_funds()
+                )
+                .map_err(output_error)?;
+            }
+            ReplCommand::Dump => {
+                let mut ids: Vec<&ClientId> = self.engine.clients.keys().collect();
+                ids.sort();
+                writeln!(out, "{}", CLIENT_STATES_HEADER).map_err(output_error)?;
+                for id in ids {
+                    write_client_row(&mut out, *id, &self.engine.clients[id]).map_err(output_error)?;
+                }
+            }
+            ReplCommand::Undo => match self.engine.undo_last().await? {
+                Some(txn) => {
+                    self.applied -= 1;
+                    writeln!(
+                        out,
+                        "undid {}, client {}, tx {}",
+                        txn.r#type.as_str(),
+                        txn.client,
+                        txn.tx
+                    )
+                    .map_err(output_error)?
+                }
+                None => writeln!(out, "nothing to undo").map_err(output_error)?,
+            },
+            ReplCommand::Load(path) => match self.load(&path).await {
+                Ok((applied, ignored)) => writeln!(
+                    out,
+                    "loaded {}: {} applied, {} ignored",
+                    path, applied, ignored
+                )
+                .map_err(output_error)?,
+                Err(err) => writeln!(out, "error: {}", err).map_err(output_error)?,
+            },
+            ReplCommand::Help => writeln!(
+                out,
+                "<type>,<client>,<tx>[,<amount>]  apply a transaction\n\
+                 .client <id>                     show a client\n\
+                 .summary                         show totals over all clients\n\
+                 .dump                            show every client\n\
+                 .undo                            revert the last applied transaction\n\
+                 .load <file.csv>                 apply a transactions file\n\
+                 .quit                            leave"
+            )
+            .map_err(output_error)?,
+            ReplCommand::Quit => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    async fn apply(&mut self, txn: Transaction) -> Result<ProcessOutcome, PaymentError> {
+        let outcome = self.engine.process_transaction(txn).await?;
+        match outcome {
+            ProcessOutcome::Applied => self.applied += 1,
+            ProcessOutcome::Ignored(_) => self.ignored += 1,
+        }
+        Ok(outcome)
+    }
+
+    /// Applies a transactions file, stopping at the first row that can't be parsed.
+    async fn load(&mut self, path: &str) -> Result<(u64, u64), PaymentError> {
+        let (mut applied, mut ignored) = (0, 0);
+        for txn in parse_transactions(open_input(path)?).await? {
+            match self.apply(txn?).await? {
+                ProcessOutcome::Applied => applied += 1,
+                ProcessOutcome::Ignored(_) => ignored += 1,
+            }
+        }
+        Ok((applied, ignored))
+    }
+}
+
+/// Reads commands from `input` until it ends or `.quit` is entered, writing the transcript to
+/// `out`. The `prompt`, if any, is written before reading each line.
+pub async fn run<R: BufRead, W: Write>(
+    input: R,
+    mut out: W,
+    prompt: Option<&str>,
+) -> Result<(), PaymentError> {
+    let mut repl = Repl::new();
+    let mut lines = input.lines();
+    loop {
+        if let Some(prompt) = prompt {
+            write!(out, "{}", prompt)
+                .and_then(|_| out.flush())
+                .map_err(output_error)?;
+        }
+        let Some(line) = lines.next() else {
+            return Ok(()); // end of input
+        };
+        let line = line.map_err(|err| PaymentError::IoError(err.to_string()))?;
+        let keep_going = match ReplCommand::parse(&line) {
+            Ok(Some(command)) => repl.execute(command, &mut out).await?,
+            Ok(None) => true,
+            Err(err) => {
+                writeln!(out, "error: {}", err).map_err(output_error)?;
+                true
+            }
+        };
+        if !keep_going {
+            return Ok(());
+        }
+    }
+}
+
+/// Parses a bare row by synthesizing the header in front of it.
+async fn parse_row(row: &str) -> Result<Transaction, PaymentError> {
+    let csv = format!("{}\n{}\n", ROW_HEADER, row);
+    parse_transactions(Box::new(Cursor::new(csv)))
+        .await?
+        .next()
+        .unwrap_or_else(|| Err(PaymentError::CsvParseError("empty row".to_owned())))
+}
+
+/// Returns the error of a failed write to the transcript.
+fn output_error(err: std::io::Error) -> PaymentError {
+    PaymentError::output("REPL output", err)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        errors::PaymentError,
+        repl::{run, ReplCommand},
+    };
+
+    #[test]
+    fn can_parse_commands() {
+        assert_eq!(ReplCommand::parse("  ").unwrap(), None);
+        assert_eq!(
+            ReplCommand::parse("deposit, 1, 1, 1.0").unwrap(),
+            Some(ReplCommand::Row("deposit, 1, 1, 1.0".to_owned()))
+        );
+        assert_eq!(
+            ReplCommand::parse(".client 7").unwrap(),
+            Some(ReplCommand::Client(7))
+        );
+        assert_eq!(
+            ReplCommand::parse(".load  some file.csv ").unwrap(),
+            Some(ReplCommand::Load("some file.csv".to_owned()))
+        );
+        assert_eq!(ReplCommand::parse(".summary").unwrap(), Some(ReplCommand::Summary));
+        assert_eq!(ReplCommand::parse(".dump").unwrap(), Some(ReplCommand::Dump));
+        assert_eq!(ReplCommand::parse(".undo").unwrap(), Some(ReplCommand::Undo));
+        assert_eq!(ReplCommand::parse(".quit").unwrap(), Some(ReplCommand::Quit));
+
+        for invalid in [".client", ".client x", ".load", ".dump all", ".frobnicate"] {
+            assert!(ReplCommand::parse(invalid).is_err(), "'{}' should be rejected", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn can_run_scripted_session() -> Result<(), PaymentError> {
+        let script = "deposit, 1, 1, 1.0
+deposit, 2, 2, 2.0
+withdrawal, 1, 3, 5.0
+dispute, 2, 2
+transfer, 1, 4, 1.0
+.client 2
+.client 3
+.undo
+.dump
+.summary
+.quit
+deposit, 1, 5, 1.0
+";
+        let mut transcript = Vec::new();
+        run(script.as_bytes(), &mut transcript, None).await?;
+
+        assert_eq!(
+            String::from_utf8(transcript).unwrap(),
+            "applied
+applied
+ignored: insufficient_funds
+applied
+error: CSV parse error: CSV deserialize error: record 1 (line: 2, byte: 22): unknown variant `transfer`, expected one of `deposit`, `withdrawal`, `dispute`, `resolve`, `chargeback`, `hold`, `release`, `capture`, `representment`, `withdrawal_pending`, `withdrawal_settle`, `withdrawal_cancel`, `accrue`
+client,available,held,total,locked
+2,0.0000,2.0000,2.0000,false
+unknown client 3
+undid dispute, client 2, tx 2
+client,available,held,total,locked
+1,1.0000,0.0000,1.0000,false
+2,2.0000,0.0000,2.0000,false
+clients: 2 (0 locked), transactions applied: 2, ignored: 1
+available: 3.0000, held: 0.0000, total: 3.0000
+"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,1102 @@
+use crate::{
+    amount::{self, Amount},
+    errors::PaymentError,
+    filter::ClientFilter,
+    payment_engine::{PaymentEngine, CLIENT_STATES_HEADER},
+    store::{ClientStore, TransactionStore},
+    types::{Client, ClientId},
+};
+#[cfg(feature = "msgpack")]
+use crate::msgpack;
+use std::{
+    borrow::Cow,
+    io::{self, BufWriter, Write},
+    ops::ControlFlow,
+    thread,
+};
+
+/// Columns appended to the client states CSV by the extended report.
+pub const EXTENDED_COLUMNS: &str = "open_disputes,disputes,chargebacks,open_dispute_held,status_reason";
+
+/// Format of the account states report.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ReportFormat {
+    /// `client,available,held,total,locked` rows, the engine's historical output.
+    #[default]
+    Csv,
+    /// A JSON array with one object per client.
+    Json,
+    /// An aligned text table for people.
+    Table,
+    /// A MessagePack array with one map per client, see `msgpack`. Needs the `msgpack` feature.
+    Msgpack,
+}
+
+impl ReportFormat {
+    /// Parses a format name as given on the command line (`csv`, `json`, `table` or `msgpack`).
+    pub fn parse(name: &str) -> Result<Self, PaymentError> {
+        match name {
+            "csv" => Ok(ReportFormat::Csv),
+            "json" => Ok(ReportFormat::Json),
+            "table" => Ok(ReportFormat::Table),
+            "msgpack" if cfg!(feature = "msgpack") => Ok(ReportFormat::Msgpack),
+            "msgpack" => Err(PaymentError::InvalidCliArgument(
+                "MessagePack reports need the engine built with --features msgpack".to_owned(),
+            )),
+            _ => Err(PaymentError::InvalidCliArgument(format!(
+                "unknown report format '{}', expected csv, json, table or msgpack",
+                name
+            ))),
+        }
+    }
+
+    /// Returns the file extension used for reports in this format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ReportFormat::Csv => "csv",
+            ReportFormat::Json => "json",
+            ReportFormat::Table => "txt",
+            ReportFormat::Msgpack => "msgpack",
+        }
+    }
+}
+
+/// Order in which clients are listed in the account states report.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutputOrder {
+    /// Ascending client id.
+    #[default]
+    ClientId,
+    /// The order the input created the accounts in, i.e. of each client's first deposit.
+    FirstSeen,
+    /// Whatever order the client map iterates in, which saves the sort on huge reports.
+    Unordered,
+}
+
+impl OutputOrder {
+    /// Parses an order name as given on the command line (`client-id`, `first-seen` or
+    /// `unordered`).
+    pub fn parse(name: &str) -> Result<Self, PaymentError> {
+        match name {
+            "client-id" => Ok(OutputOrder::ClientId),
+            "first-seen" => Ok(OutputOrder::FirstSeen),
+            "unordered" => Ok(OutputOrder::Unordered),
+            _ => Err(PaymentError::InvalidCliArgument(format!(
+                "unknown output order '{}', expected client-id, first-seen or unordered",
+                name
+            ))),
+        }
+    }
+}
+
+/// How an amount is cut down to the report's number of decimal places.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Rounding {
+    /// Ties go away from zero: `0.00005` is `0.0001` at four places.
+    HalfUp,
+    /// Ties go to the even digit: `0.00005` is `0.0000` and `0.00015` is `0.0002`.
+    #[default]
+    HalfEven,
+    /// Extra digits are dropped. Note that a held amount of `0.00005` then reads `0.0000` at
+    /// four places, so a non-zero held amount can look like nothing is held.
+    Truncate,
+}
+
+impl Rounding {
+    /// Parses a rounding mode as given on the command line (`half-up`, `half-even` or
+    /// `truncate`).
+    pub fn parse(name: &str) -> Result<Self, PaymentError> {
+        match name {
+            "half-up" => Ok(Rounding::HalfUp),
+            "half-even" => Ok(Rounding::HalfEven),
+            "truncate" => Ok(Rounding::Truncate),
+            _ => Err(PaymentError::InvalidCliArgument(format!(
+                "unknown rounding mode '{}', expected half-up, half-even or truncate",
+                name
+            ))),
+        }
+    }
+}
+
+/// Largest number of decimal places amounts can be reported with.
+pub const MAX_PRECISION: u8 = 8;
+
+/// Decimal places an amount is first rendered with, before being rounded to the report's
+/// precision. Rounding this shorter form rather than the exact binary value means ties are
+/// decided on the decimal amount the input meant (`2.675` is a tie, although the closest
+/// `f64` is slightly below it) and float noise like `0.30000000000000004` is ignored.
+const EXACT_DECIMALS: usize = 10;
+
+/// Capacity of the buffer reports are written through, so that millions of rows take a few
+/// thousand writes.
+const OUTPUT_BUFFER_BYTES: usize = 1 << 20;
+
+/// Rows of a CSV report each thread formats at a time, when there are enough of them to be
+/// formatted in parallel.
+const ROWS_PER_THREAD: usize = 1 << 14;
+
+/// How amounts are presented in the report. Only the output is affected: the engine keeps
+/// computing with `amount::SCALE` decimal places.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutputOptions {
+    /// Number of decimal places, at most `MAX_PRECISION`. With `0` there's no decimal point.
+    pub precision: u8,
+    pub rounding: Rounding,
+    /// Decimal separator of the amounts in CSV reports, `.` or `,`. JSON and tables always
+    /// use `.`.
+    pub decimal_separator: char,
+    /// Field delimiter of CSV reports.
+    pub delimiter: char,
+}
+
+impl Default for OutputOptions {
+    fn default() -> Self {
+        OutputOptions {
+            precision: 4,
+            rounding: Rounding::default(),
+            decimal_separator: '.',
+            delimiter: ',',
+        }
+    }
+}
+
+impl OutputOptions {
+    /// Checks that CSV reports written with these options can be read back.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::InvalidCliArgument` if the decimal separator isn't `.` or `,`,
+    /// if the delimiter isn't an ASCII punctuation or whitespace character other than `.`, `-`
+    /// and `"`, or if both are the same character.
+    pub fn validate(&self) -> Result<(), PaymentError> {
+        let invalid = |msg: String| Err(PaymentError::InvalidCliArgument(msg));
+        if !matches!(self.decimal_separator, '.' | ',') {
+            return invalid(format!(
+                "decimal separator must be '.' or ',', got '{}'",
+                self.decimal_separator
+            ));
+        }
+        let delimiter = self.delimiter;
+        if !(delimiter.is_ascii_punctuation() || delimiter.is_ascii_whitespace())
+            || matches!(delimiter, '.' | '-' | '"')
+        {
+            return invalid(format!("'{}' can't be used as the field delimiter", delimiter));
+        }
+        if delimiter == self.decimal_separator {
+            return invalid(format!(
+                "the decimal separator and the field delimiter are both '{}', pick another \
+                 delimiter such as ';'",
+                delimiter
+            ));
+        }
+        Ok(())
+    }
+
+    /// Formats an amount with `precision` decimal places, rounded according to `rounding`, with
+    /// a `.` decimal separator.
+    ///
+    /// Amounts that round to zero are written without a sign.
+    pub fn format(&self, amount: f64) -> String {
+        self.render(amount, '.')
+    }
+
+    /// Formats an amount for a CSV report, like `format` but with `decimal_separator`.
+    pub fn format_csv(&self, amount: f64) -> String {
+        self.render(amount, self.decimal_separator)
+    }
+
+    /// Formats an amount like `format`, from its fixed-point units rather than an `f64`, so every
+    /// digit is exact however large the amount.
+    pub fn format_amount(&self, amount: Amount) -> String {
+        let shown = amount.to_string();
+        let exact = format!(
+            "{}{}",
+            shown.trim_start_matches('-'),
+            "0".repeat(EXACT_DECIMALS - amount::SCALE as usize)
+        );
+        self.round(&exact, amount.is_negative(), '.')
+    }
+
+    /// Whether `format_csv` writes `amount` as its `Display` does, so that it can be written
+    /// without going through `f64`. That is the case at `SCALE` decimal places with a `.`
+    /// separator below `2^50` units, where the `f64` is within a fraction of a unit of the
+    /// amount and rounds back to it; truncating could give the unit below.
+    fn writes_exactly(&self, amount: Amount) -> bool {
+        self.precision as u32 == amount::SCALE
+            && self.decimal_separator == '.'
+            && self.rounding != Rounding::Truncate
+            && amount.units().unsigned_abs() < 1 << 50
+    }
+
+    fn render(&self, amount: f64, decimal_separator: char) -> String {
+        let exact = format!("{:.*}", EXACT_DECIMALS, amount.abs());
+        self.round(&exact, amount.is_sign_negative(), decimal_separator)
+    }
+
+    /// Rounds `exact`, an absolute amount written with `EXACT_DECIMALS` decimal places, to
+    /// `precision` places, signed if `negative` and still not zero.
+    fn round(&self, exact: &str, negative: bool, decimal_separator: char) -> String {
+        let precision = self.precision.min(MAX_PRECISION) as usize;
+        let (integer, fraction) = exact.split_at(exact.len() - EXACT_DECIMALS - 1);
+        let (kept, dropped) = fraction[1..].split_at(precision);
+
+        let mut digits: Vec<u8> = integer.bytes().chain(kept.bytes()).collect();
+        let (first_dropped, rest) = (dropped.as_bytes()[0], &dropped[1..]);
+        let round_up = match self.rounding {
+            Rounding::Truncate => false,
+            Rounding::HalfUp => first_dropped >= b'5',
+            Rounding::HalfEven => match first_dropped.cmp(&b'5') {
+                std::cmp::Ordering::Less => false,
+                std::cmp::Ordering::Greater => true,
+                std::cmp::Ordering::Equal => {
+                    rest.bytes().any(|digit| digit != b'0')
+                        || digits.last().is_some_and(|digit| (digit - b'0') % 2 == 1)
+                }
+            },
+        };
+        if round_up {
+            match digits.iter().rposition(|digit| *digit != b'9') {
+                Some(i) => {
+                    digits[i] += 1;
+                    digits[i + 1..].fill(b'0');
+                }
+                None => {
+                    digits.fill(b'0');
+                    digits.insert(0, b'1');
+                }
+            }
+        }
+
+        let negative = negative && digits.iter().any(|digit| *digit != b'0');
+        let (integer, fraction) = digits.split_at(digits.len() - precision);
+        let mut formatted = String::with_capacity(digits.len() + 2);
+        if negative {
+            formatted.push('-');
+        }
+        formatted.extend(integer.iter().map(|digit| *digit as char));
+        if precision > 0 {
+            formatted.push(decimal_separator);
+            formatted.extend(fraction.iter().map(|digit| *digit as char));
+        }
+        formatted
+    }
+}
+
+/// What the account states report contains and how it is written.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReportOptions<'a> {
+    pub format: ReportFormat,
+    pub order: OutputOrder,
+    /// Precision, rounding and separators of the amounts.
+    pub output: OutputOptions,
+    /// Also report the dispute counters of each client.
+    pub extended: bool,
+    /// Only report these clients.
+    pub clients: Option<&'a ClientFilter>,
+    /// Show at most this many clients in a table, followed by a count of the others.
+    pub max_rows: Option<usize>,
+}
+
+/// Writes the account states report of every client (restricted to `options.clients`).
+pub fn write_report<S: TransactionStore, C: ClientStore, W: Write>(
+    engine: &PaymentEngine<S, C>,
+    w: W,
+    options: &ReportOptions,
+) -> io::Result<()> {
+    // the CSV rows are formatted in parallel and the table and MessagePack lengths come first, so
+    // only JSON is written as the clients are visited
+    let collect = || {
+        let mut clients: Vec<(ClientId, &Client)> = Vec::new();
+        let _ = engine.for_each_client(options.order, options.clients, |id, client| {
+            clients.push((id, client));
+            ControlFlow::<()>::Continue(())
+        });
+        clients
+    };
+
+    let mut w = BufWriter::with_capacity(OUTPUT_BUFFER_BYTES, w);
+    match options.format {
+        ReportFormat::Csv => {
+            write_csv_header(&mut w, options)?;
+            let clients = collect();
+            let threads = thread::available_parallelism().map_or(1, |threads| threads.get());
+            write_csv_rows(&mut w, &clients, options, threads)?;
+        }
+        ReportFormat::Json => {
+            write!(w, "[")?;
+            let mut first = true;
+            let written = engine.for_each_client(options.order, options.clients, |id, client| {
+                let object = write!(w, "{}\n  ", if first { "" } else { "," })
+                    .and_then(|()| write_json_object(&mut w, id, client, options));
+                first = false;
+                match object {
+                    Ok(()) => ControlFlow::Continue(()),
+                    Err(err) => ControlFlow::Break(err),
+                }
+            });
+            if let ControlFlow::Break(err) = written {
+                return Err(err);
+            }
+            writeln!(w, "\n]")?;
+        }
+        ReportFormat::Table => {
+            let clients = collect();
+            write_table(&mut w, &clients, options)?;
+        }
+        ReportFormat::Msgpack => {
+            let clients = collect();
+            write_msgpack(&mut w, &clients, options)?;
+        }
+    }
+    w.flush()
+}
+
+/// Writes a single client's report, e.g. for a per-client file: the CSV header and the row, the
+/// JSON object alone, a one-row table or a one-map MessagePack array.
+pub fn write_client_report<W: Write>(
+    mut w: W,
+    client_id: ClientId,
+    client: &Client,
+    options: &ReportOptions,
+) -> io::Result<()> {
+    match options.format {
+        ReportFormat::Csv => {
+            write_csv_header(&mut w, options)?;
+            write_csv_row(&mut w, client_id, client, options)
+        }
+        ReportFormat::Json => {
+            write_json_object(&mut w, client_id, client, options)?;
+            writeln!(w)
+        }
+        ReportFormat::Table => write_table(w, &[(client_id, client)], options),
+        ReportFormat::Msgpack => write_msgpack(w, &[(client_id, client)], options),
+    }
+}
+
+pub(crate) fn write_csv_header<W: Write>(mut w: W, options: &ReportOptions) -> io::Result<()> {
+    let mut columns: Vec<&str> = CLIENT_STATES_HEADER.split(',').collect();
+    if options.extended {
+        columns.extend(EXTENDED_COLUMNS.split(','));
+    }
+    writeln!(w, "{}", columns.join(&options.output.delimiter.to_string()))
+}
+
+/// Writes one client's row of the client states CSV, with the extended columns if asked.
+pub fn write_csv_row<W: Write>(
+    mut w: W,
+    client_id: ClientId,
+    client: &Client,
+    options: &ReportOptions,
+) -> io::Result<()> {
+    let mut row = Vec::new();
+    push_csv_row(&mut row, client_id, client, options);
+    w.write_all(&row)
+}
+
+/// Writes the rows of `clients`, in order. Large reports are formatted by up to `threads`
+/// threads, each into its own buffer, and the buffers written in turn.
+fn write_csv_rows<W: Write>(
+    mut w: W,
+    clients: &[(ClientId, &Client)],
+    options: &ReportOptions,
+    threads: usize,
+) -> io::Result<()> {
+    if threads < 2 || clients.len() < 2 * ROWS_PER_THREAD {
+        let mut row = Vec::new();
+        for &(id, client) in clients {
+            row.clear();
+            push_csv_row(&mut row, id, client, options);
+            w.write_all(&row)?;
+        }
+        return Ok(());
+    }
+    for batch in clients.chunks(threads * ROWS_PER_THREAD) {
+        let formatted: Vec<Vec<u8>> = thread::scope(|scope| {
+            let workers: Vec<_> = batch
+                .chunks(ROWS_PER_THREAD)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        let mut rows = Vec::new();
+                        for &(id, client) in chunk {
+                            push_csv_row(&mut rows, id, client, options);
+                        }
+                        rows
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().expect("formatting rows doesn't panic"))
+                .collect()
+        });
+        for rows in formatted {
+            w.write_all(&rows)?;
+        }
+    }
+    Ok(())
+}
+
+/// Appends one client's row of the client states CSV, formatting the amounts without going
+/// through `f64` when that gives the same bytes.
+fn push_csv_row(out: &mut Vec<u8>, client_id: ClientId, client: &Client, options: &ReportOptions) {
+    let mut delimiter = [0; 4];
+    let delimiter = options.output.delimiter.encode_utf8(&mut delimiter).as_bytes();
+    let output = &options.output;
+    let push_amount = |out: &mut Vec<u8>, amount: Amount| {
+        out.extend_from_slice(delimiter);
+        if output.writes_exactly(amount) {
+            amount.write_to(out);
+        } else {
+            out.extend_from_slice(output.format_csv(amount.to_f64()).as_bytes());
+        }
+    };
+    let push_count = |out: &mut Vec<u8>, count: u32| {
+        out.extend_from_slice(delimiter);
+        amount::push_digits(out, count.into());
+    };
+
+    amount::push_digits(out, client_id.into());
+    push_amount(out, client.available);
+    push_amount(out, client.held);
+    push_amount(out, client.total);
+    out.extend_from_slice(delimiter);
+    out.extend_from_slice(if client.locked { b"true" } else { b"false" });
+    if options.extended {
+        push_count(out, client.open_disputes);
+        push_count(out, client.disputes);
+        push_count(out, client.chargebacks);
+        push_amount(out, client.open_dispute_held);
+        out.extend_from_slice(delimiter);
+        if let Some(reason) = client.status_reason {
+            let reason = reason.as_str();
+            if reason.contains([options.output.delimiter, '"', '\n', '\r']) {
+                out.extend_from_slice(format!("\"{}\"", reason.replace('"', "\"\"")).as_bytes());
+            } else {
+                out.extend_from_slice(reason.as_bytes());
+            }
+        }
+    }
+    out.push(b'\n');
+}
+
+fn write_json_object<W: Write>(
+    mut w: W,
+    client_id: ClientId,
+    client: &Client,
+    options: &ReportOptions,
+) -> io::Result<()> {
+    let amount = |amount: Amount| options.output.format(amount.to_f64());
+    write!(
+        w,
+        "{{\"client\":{},\"available\":{},\"held\":{},\"total\":{},\"locked\":{}",
+        client_id,
+        amount(client.available),
+        amount(client.held),
+        amount(client.total),
+        client.locked
+    )?;
+    if options.extended {
+        write!(
+            w,
+            ",\"open_disputes\":{},\"disputes\":{},\"chargebacks\":{},\"open_dispute_held\":{},\"status_reason\":{}",
+            client.open_disputes,
+            client.disputes,
+            client.chargebacks,
+            amount(client.open_dispute_held),
+            serde_json::to_string(&client.status_reason).map_err(io::Error::other)?
+        )?;
+    }
+    write!(w, "}}")
+}
+
+/// Writes the clients as a MessagePack array of maps.
+#[cfg(feature = "msgpack")]
+fn write_msgpack<W: Write>(
+    mut w: W,
+    clients: &[(ClientId, &Client)],
+    options: &ReportOptions,
+) -> io::Result<()> {
+    let mut out = Vec::new();
+    msgpack::push_array_len(&mut out, clients.len());
+    for &(id, client) in clients {
+        msgpack::push_client(&mut out, id, client, options);
+    }
+    w.write_all(&out)
+}
+
+#[cfg(not(feature = "msgpack"))]
+fn write_msgpack<W: Write>(
+    _: W,
+    _: &[(ClientId, &Client)],
+    _: &ReportOptions,
+) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "MessagePack reports need the engine built with --features msgpack",
+    ))
+}
+
+/// Writes the clients as a table with a header, right-aligning every column but `locked` and
+/// `status_reason`.
+fn write_table<W: Write>(
+    mut w: W,
+    clients: &[(ClientId, &Client)],
+    options: &ReportOptions,
+) -> io::Result<()> {
+    let mut headers: Vec<&str> = CLIENT_STATES_HEADER.split(',').collect();
+    if options.extended {
+        headers.extend(EXTENDED_COLUMNS.split(','));
+    }
+    let amount = |amount: Amount| thousands(&options.output.format(amount.to_f64()));
+    let shown = options.max_rows.unwrap_or(clients.len()).min(clients.len());
+    let rows: Vec<Vec<String>> = clients[..shown]
+        .iter()
+        .map(|(id, client)| {
+            let mut row = vec![
+                id.to_string(),
+                amount(client.available),
+                amount(client.held),
+                amount(client.total),
+                client.locked.to_string(),
+            ];
+            if options.extended {
+                row.extend([
+                    thousands(&client.open_disputes.to_string()),
+                    thousands(&client.disputes.to_string()),
+                    thousands(&client.chargebacks.to_string()),
+                    amount(client.open_dispute_held),
+                    client.status_reason.map(|reason| reason.to_string()).unwrap_or_default(),
+                ]);
+            }
+            row
+        })
+        .collect();
+
+    let widths: Vec<usize> = headers
+        .iter()
+        .enumerate()
+        .map(|(i, header)| rows.iter().map(|row| row[i].len()).fold(header.len(), usize::max))
+        .collect();
+    let locked_column = 4;
+    let reason_column = options.extended.then(|| headers.len() - 1);
+    let write_row = |w: &mut W, cells: &[&str]| {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .enumerate()
+            .map(|(i, (cell, width))| match i {
+                i if i == locked_column || Some(i) == reason_column => {
+                    format!("{:<width$}", cell, width = width)
+                }
+                _ => format!("{:>width$}", cell, width = width),
+            })
+            .collect();
+        writeln!(w, "{}", line.join(" | ").trim_end())
+    };
+
+    write_row(&mut w, &headers)?;
+    let rule: Vec<String> = widths.iter().map(|width| "-".repeat(*width)).collect();
+    writeln!(w, "{}", rule.join("-+-"))?;
+    for row in &rows {
+        write_row(&mut w, &row.iter().map(String::as_str).collect::<Vec<_>>())?;
+    }
+    if shown < clients.len() {
+        let more = clients.len() - shown;
+        writeln!(w, "\u{2026}and {more} more {}", if more == 1 { "client" } else { "clients" })?;
+    }
+    Ok(())
+}
+
+/// Inserts a comma between each group of three digits of the integer part of a number.
+fn thousands(number: &str) -> String {
+    let (sign, unsigned) = match number.strip_prefix('-') {
+        Some(unsigned) => ("-", unsigned),
+        None => ("", number),
+    };
+    let (integer, fraction) = match unsigned.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (unsigned, None),
+    };
+
+    let mut grouped = String::with_capacity(number.len() + integer.len() / 3);
+    grouped.push_str(sign);
+    for (i, digit) in integer.chars().enumerate() {
+        if i > 0 && (integer.len() - i).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    if let Some(fraction) = fraction {
+        grouped.push('.');
+        grouped.push_str(fraction);
+    }
+    grouped
+}
+
+/// Quotes a free text field of a report if it contains a delimiter, quote or line break.
+pub(crate) fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        amount::Amount,
+        errors::PaymentError,
+        parser::parse_transactions,
+        payment_engine::PaymentEngine,
+        filter::ClientFilter,
+        report::{
+            push_csv_row, thousands, write_csv_rows, write_report, OutputOptions, OutputOrder,
+            ReportFormat, ReportOptions, Rounding, ROWS_PER_THREAD,
+        },
+        types::{Client, ClientId, StatusReason},
+    };
+    use std::fmt::Write;
+
+    /// One client with a resolved dispute, a charged back one and one still open.
+    async fn disputed_engine() -> Result<PaymentEngine, PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 10.0
+        deposit, 1, 2, 5.0
+        deposit, 1, 3, 2.5
+        dispute, 1, 1
+        resolve, 1, 1
+        dispute, 1, 3
+        dispute, 1, 2
+        dispute, 1, 2
+        chargeback, 1, 2";
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = PaymentEngine::new();
+        engine.process_all(parse_transactions(Box::new(str_buf)).await?).await.into_result()?;
+        Ok(engine)
+    }
+
+    fn render(engine: &PaymentEngine, options: ReportOptions) -> String {
+        let mut out = Vec::new();
+        write_report(engine, &mut out, &options).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[tokio::test]
+    async fn can_report_dispute_counters() -> Result<(), PaymentError> {
+        let engine = disputed_engine().await?;
+        let client = engine.clients[&1];
+        assert_eq!(client.open_disputes, 1);
+        assert_eq!(client.disputes, 3);
+        assert_eq!(client.chargebacks, 1);
+        assert_eq!(client.open_dispute_held, 2.5);
+
+        // the default format is untouched
+        assert_eq!(
+            render(&engine, ReportOptions::default()),
+            "client,available,held,total,locked\n1,10.0000,2.5000,12.5000,true\n"
+        );
+        assert_eq!(
+            render(
+                &engine,
+                ReportOptions {
+                    extended: true,
+                    ..Default::default()
+                }
+            ),
+            "client,available,held,total,locked,open_disputes,disputes,chargebacks,open_dispute_held,status_reason
+1,10.0000,2.5000,12.5000,true,1,3,1,2.5000,chargeback of tx 2
+"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pending_withdrawals_are_reported_as_held() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 10.0
+        deposit, 1, 2, 2.5
+        dispute, 1, 2
+        withdrawal_pending, 1, 3, 4.0";
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = PaymentEngine::new();
+        engine.process_all(parse_transactions(Box::new(str_buf)).await?).await.into_result()?;
+        assert_eq!(engine.clients[&1].pending_out, 4.0);
+
+        // held counts the pending withdrawal along with the dispute, but open_dispute_held doesn't
+        assert_eq!(
+            render(
+                &engine,
+                ReportOptions {
+                    extended: true,
+                    ..Default::default()
+                }
+            ),
+            "client,available,held,total,locked,open_disputes,disputes,chargebacks,open_dispute_held,status_reason
+1,6.0000,6.5000,12.5000,false,1,1,0,2.5000,
+"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_report_as_json() -> Result<(), PaymentError> {
+        let engine = disputed_engine().await?;
+        assert_eq!(
+            render(
+                &engine,
+                ReportOptions {
+                    format: ReportFormat::Json,
+                    ..Default::default()
+                }
+            ),
+            "[\n  {\"client\":1,\"available\":10.0000,\"held\":2.5000,\"total\":12.5000,\"locked\":true}\n]\n"
+        );
+        assert_eq!(
+            render(
+                &engine,
+                ReportOptions {
+                    format: ReportFormat::Json,
+                    extended: true,
+                    ..Default::default()
+                }
+            ),
+            "[\n  {\"client\":1,\"available\":10.0000,\"held\":2.5000,\"total\":12.5000,\"locked\":true,\
+             \"open_disputes\":1,\"disputes\":3,\"chargebacks\":1,\"open_dispute_held\":2.5000,\
+             \"status_reason\":\"chargeback of tx 2\"}\n]\n"
+        );
+        assert_eq!(
+            render(
+                &PaymentEngine::new(),
+                ReportOptions {
+                    format: ReportFormat::Json,
+                    ..Default::default()
+                }
+            ),
+            "[\n]\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn can_round_amounts() {
+        let format = |precision, rounding, amount| OutputOptions {
+            precision,
+            rounding,
+            ..Default::default()
+        }
+        .format(amount);
+        let cases = [
+            // amount, precision, half-up, half-even, truncate
+            (0.00005, 4, "0.0001", "0.0000", "0.0000"),
+            (0.00015, 4, "0.0002", "0.0002", "0.0001"),
+            (2.675, 2, "2.68", "2.68", "2.67"),
+            (2.665, 2, "2.67", "2.66", "2.66"),
+            (2.6651, 2, "2.67", "2.67", "2.66"),
+            (-1.005, 2, "-1.01", "-1.00", "-1.00"),
+            (-0.00004, 4, "0.0000", "0.0000", "0.0000"),
+            (9.995, 2, "10.00", "10.00", "9.99"),
+            (0.5, 0, "1", "0", "0"),
+            (1.5, 0, "2", "2", "1"),
+            (0.1 + 0.2, 6, "0.300000", "0.300000", "0.300000"),
+            (1.23456789, 8, "1.23456789", "1.23456789", "1.23456789"),
+        ];
+        for (amount, precision, half_up, half_even, truncate) in cases {
+            assert_eq!(format(precision, Rounding::HalfUp, amount), half_up, "{} half-up", amount);
+            assert_eq!(format(precision, Rounding::HalfEven, amount), half_even, "{} half-even", amount);
+            assert_eq!(format(precision, Rounding::Truncate, amount), truncate, "{} truncate", amount);
+        }
+        // the default matches the historical four places
+        assert_eq!(OutputOptions::default().format(1.5), "1.5000");
+    }
+
+    #[test]
+    fn can_round_fixed_point_amounts() {
+        let format = |precision, rounding, units| OutputOptions {
+            precision,
+            rounding,
+            ..Default::default()
+        }
+        .format_amount(Amount::from_units(units).unwrap());
+        let cases = [
+            // units, precision, half-up, half-even, truncate
+            (5, 3, "0.001", "0.000", "0.000"),
+            (-12_340_050, 2, "-1234.01", "-1234.00", "-1234.00"),
+            (-1, 2, "0.00", "0.00", "0.00"),
+            (25_000, 0, "3", "2", "2"),
+            (1, 8, "0.00010000", "0.00010000", "0.00010000"),
+            // past what an f64 holds to the unit
+            (9_000_000_000_000_000_001, 4, "900000000000000.0001", "900000000000000.0001", "900000000000000.0001"),
+            (-9_000_000_000_000_000_050, 2, "-900000000000000.01", "-900000000000000.00", "-900000000000000.00"),
+        ];
+        for (units, precision, half_up, half_even, truncate) in cases {
+            assert_eq!(format(precision, Rounding::HalfUp, units), half_up, "{} half-up", units);
+            assert_eq!(format(precision, Rounding::HalfEven, units), half_even, "{} half-even", units);
+            assert_eq!(format(precision, Rounding::Truncate, units), truncate, "{} truncate", units);
+        }
+    }
+
+    #[tokio::test]
+    async fn can_report_with_precision() -> Result<(), PaymentError> {
+        let engine = disputed_engine().await?;
+        let options = ReportOptions {
+            output: OutputOptions {
+                precision: 0,
+                rounding: Rounding::HalfUp,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            render(&engine, options),
+            "client,available,held,total,locked\n1,10,3,13,true\n"
+        );
+        assert_eq!(
+            render(
+                &engine,
+                ReportOptions {
+                    format: ReportFormat::Json,
+                    output: OutputOptions {
+                        precision: 2,
+                        rounding: Rounding::Truncate,
+                        ..Default::default()
+                    },
+                    ..options
+                }
+            ),
+            "[\n  {\"client\":1,\"available\":10.00,\"held\":2.50,\"total\":12.50,\"locked\":true}\n]\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn can_group_thousands() {
+        assert_eq!(thousands("0.0000"), "0.0000");
+        assert_eq!(thousands("999"), "999");
+        assert_eq!(thousands("1000"), "1,000");
+        assert_eq!(thousands("-1234567.5000"), "-1,234,567.5000");
+        assert_eq!(thousands("123456.0001"), "123,456.0001");
+    }
+
+    #[tokio::test]
+    async fn can_report_as_table() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 3, 1, 1234567.5
+        deposit, 1, 2, 10.0
+        deposit, 2, 3, 2.25
+        dispute, 1, 2
+        deposit, 2, 4, 1000.0
+        dispute, 2, 4
+        chargeback, 2, 4";
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut engine = PaymentEngine::new();
+        engine.process_all(parse_transactions(Box::new(str_buf)).await?).await.into_result()?;
+
+        let table = ReportOptions {
+            format: ReportFormat::Table,
+            ..Default::default()
+        };
+        assert_eq!(
+            render(&engine, table),
+            "client |      available |    held |          total | locked
+-------+----------------+---------+----------------+-------
+     1 |         0.0000 | 10.0000 |        10.0000 | false
+     2 |         2.2500 |  0.0000 |         2.2500 | true
+     3 | 1,234,567.5000 |  0.0000 | 1,234,567.5000 | false
+"
+        );
+        assert_eq!(
+            render(
+                &engine,
+                ReportOptions {
+                    extended: true,
+                    max_rows: Some(2),
+                    ..table
+                }
+            ),
+            "client | available |    held |   total | locked | open_disputes | disputes | chargebacks | open_dispute_held | status_reason
+-------+-----------+---------+---------+--------+---------------+----------+-------------+-------------------+-------------------
+     1 |    0.0000 | 10.0000 | 10.0000 | false  |             1 |        1 |           0 |           10.0000 |
+     2 |    2.2500 |  0.0000 |  2.2500 | true   |             0 |        1 |           1 |            0.0000 | chargeback of tx 4
+\u{2026}and 1 more client
+"
+        );
+        let shortest = ReportOptions {
+            max_rows: Some(1),
+            ..table
+        };
+        assert!(render(&engine, shortest).ends_with("\u{2026}and 2 more clients\n"));
+        Ok(())
+    }
+
+    /// Returns `count` clients with balances spread over the whole range of amounts, edge
+    /// values first, from a fixed seed.
+    fn sample_clients(count: usize) -> Vec<(ClientId, Client)> {
+        let edges = [0, 1, -1, 9999, -9999, (1 << 50) - 1, -(1 << 50) + 1, 1 << 50, -(1 << 50)];
+        let mut edges = edges.into_iter().chain([i64::MAX, -i64::MAX]);
+        let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+        let mut units = move || {
+            edges.next().unwrap_or_else(|| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                // mostly everyday balances, some of them huge
+                let magnitude = if state.is_multiple_of(8) { 63 } else { 36 };
+                (state as i64 >> (64 - magnitude)).max(-i64::MAX)
+            })
+        };
+        (0..count)
+            .map(|i| {
+                let mut client = Client::new();
+                client.available = Amount::from_units(units()).unwrap();
+                client.held = Amount::from_units(units()).unwrap();
+                client.total = Amount::from_units(units()).unwrap();
+                client.open_dispute_held = Amount::from_units(units()).unwrap();
+                client.locked = i % 3 == 0;
+                client.open_disputes = i as u32 % 7;
+                client.disputes = i as u32;
+                client.chargebacks = u32::MAX - i as u32;
+                ((i % ClientId::MAX as usize) as ClientId, client)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fast_rows_match_formatting_every_amount() {
+        // how rows were written before amounts could skip `f64`
+        let naive_row = |client_id: ClientId, client: &Client, options: &ReportOptions| {
+            let amount = |amount: Amount| options.output.format_csv(amount.to_f64());
+            let d = options.output.delimiter;
+            let mut row = format!(
+                "{}{d}{}{d}{}{d}{}{d}{}",
+                client_id,
+                amount(client.available),
+                amount(client.held),
+                amount(client.total),
+                client.locked
+            );
+            if options.extended {
+                let _ = write!(
+                    row,
+                    "{d}{}{d}{}{d}{}{d}{}{d}{}",
+                    client.open_disputes,
+                    client.disputes,
+                    client.chargebacks,
+                    amount(client.open_dispute_held),
+                    client.status_reason.as_ref().map_or("", StatusReason::as_str)
+                );
+            }
+            row + "\n"
+        };
+        let outputs = [
+            OutputOptions::default(),
+            OutputOptions {
+                rounding: Rounding::HalfUp,
+                ..Default::default()
+            },
+            OutputOptions {
+                rounding: Rounding::Truncate,
+                ..Default::default()
+            },
+            OutputOptions {
+                precision: 2,
+                ..Default::default()
+            },
+            OutputOptions {
+                decimal_separator: ',',
+                delimiter: ';',
+                ..Default::default()
+            },
+        ];
+        let clients = sample_clients(2000);
+        for output in outputs {
+            for extended in [false, true] {
+                let options = ReportOptions {
+                    output,
+                    extended,
+                    ..Default::default()
+                };
+                for (id, client) in &clients {
+                    let mut row = Vec::new();
+                    push_csv_row(&mut row, *id, client, &options);
+                    assert_eq!(String::from_utf8(row).unwrap(), naive_row(*id, client, &options));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rows_formatted_in_parallel_keep_their_order() {
+        let clients = sample_clients(5 * ROWS_PER_THREAD / 2);
+        let clients: Vec<(ClientId, &Client)> = clients.iter().map(|(id, client)| (*id, client)).collect();
+        let options = ReportOptions {
+            extended: true,
+            ..Default::default()
+        };
+        let write = |threads| {
+            let mut out = Vec::new();
+            write_csv_rows(&mut out, &clients, &options, threads).unwrap();
+            out
+        };
+        let sequential = write(1);
+        assert_eq!(sequential.iter().filter(|byte| **byte == b'\n').count(), clients.len());
+        assert_eq!(write(2), sequential);
+        assert_eq!(write(4), sequential);
+    }
+
+    #[tokio::test]
+    async fn every_format_reports_the_same_clients_in_the_same_order() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 3, 1, 3.0
+        deposit, 1, 2, 1.0
+        deposit, 4, 3, 4.0
+        deposit, 2, 4, 2.0
+        dispute, 4, 3";
+        let mut engine = PaymentEngine::new();
+        let str_buf = stringreader::StringReader::new(csv);
+        engine.process_all(parse_transactions(Box::new(str_buf)).await?).await.into_result()?;
+        let filter = ClientFilter::parse("1,3-4")?;
+        let options = |format| ReportOptions {
+            format,
+            order: OutputOrder::FirstSeen,
+            clients: Some(&filter),
+            ..Default::default()
+        };
+        assert_eq!(
+            render(&engine, options(ReportFormat::Csv)),
+            "client,available,held,total,locked
+3,3.0000,0.0000,3.0000,false
+1,1.0000,0.0000,1.0000,false
+4,0.0000,4.0000,4.0000,false
+"
+        );
+        assert_eq!(
+            render(&engine, options(ReportFormat::Json)),
+            "[
+  {\"client\":3,\"available\":3.0000,\"held\":0.0000,\"total\":3.0000,\"locked\":false},
+  {\"client\":1,\"available\":1.0000,\"held\":0.0000,\"total\":1.0000,\"locked\":false},
+  {\"client\":4,\"available\":0.0000,\"held\":4.0000,\"total\":4.0000,\"locked\":false}
+]
+"
+        );
+        assert_eq!(
+            render(&engine, options(ReportFormat::Table)),
+            "client | available |   held |  total | locked
+-------+-----------+--------+--------+-------
+     3 |    3.0000 | 0.0000 | 3.0000 | false
+     1 |    1.0000 | 0.0000 | 1.0000 | false
+     4 |    0.0000 | 4.0000 | 4.0000 | false
+"
+        );
+        #[cfg(feature = "msgpack")]
+        {
+            let mut expected = vec![0x93];
+            for id in [3, 1, 4] {
+                let client = &engine.clients[&id];
+                crate::msgpack::push_client(&mut expected, id, client, &options(ReportFormat::Msgpack));
+            }
+            let mut out = Vec::new();
+            write_report(&engine, &mut out, &options(ReportFormat::Msgpack)).unwrap();
+            assert_eq!(out, expected);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,336 @@
+use crate::{
+    errors::PaymentError,
+    payment_engine::PaymentEngine,
+    report::{write_csv_header, write_csv_row, OutputOptions, ReportOptions},
+    types::{Client, ClientId, ProcessOutcome, Transaction, TransactionType},
+};
+use std::io::{self, Write};
+use tokio::sync::{mpsc, Mutex};
+
+/// How a shard's clients are rendered when streamed by `SharedPaymentEngine::finish_shard`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StreamOptions {
+    /// Send a shard's rows ordered by client id. Rows of different shards stay interleaved.
+    pub sort_within_shard: bool,
+    /// Append a `shard` column with the index of the shard the client lives in.
+    pub tag_shard: bool,
+    /// Append the extended dispute counter columns.
+    pub extended: bool,
+    /// Precision, rounding and separators of the amounts and columns.
+    pub output: OutputOptions,
+}
+
+impl StreamOptions {
+    fn report_options(&self) -> ReportOptions<'static> {
+        ReportOptions {
+            extended: self.extended,
+            output: self.output,
+            ..Default::default()
+        }
+    }
+}
+
+/// A `PaymentEngine` that can be shared between tasks ingesting transactions concurrently.
+///
+/// Clients are partitioned across a fixed number of shards keyed by client id, each shard being
+/// an independent `PaymentEngine` behind a `tokio::sync::Mutex`. Transactions for different
+/// shards are processed in parallel, while transactions for the same client are serialized by
+/// its shard's lock.
+///
+/// Per-client ordering is guaranteed as long as the caller awaits each `process` call for a
+/// client before submitting that client's next transaction. Transactions for one client that
+/// are submitted concurrently are applied in whichever order they acquire the lock.
+///
+/// Because each shard only stores its own clients' transactions, a dispute, resolve or
+/// chargeback naming a different client than the referenced transaction is ignored as
+/// `UnknownTransaction` rather than `ClientMismatch`, and duplicate tx ids are only detected
+/// within a shard.
+pub struct SharedPaymentEngine {
+    shards: Vec<Mutex<PaymentEngine>>,
+}
+
+impl SharedPaymentEngine {
+    /// Creates an engine with the given number of shards (at least one).
+    pub fn new(shard_count: usize) -> Self {
+        SharedPaymentEngine {
+            shards: (0..shard_count.max(1))
+                .map(|_| Mutex::new(PaymentEngine::new()))
+                .collect(),
+        }
+    }
+
+    /// Returns the number of shards clients are partitioned across.
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Returns the index of the shard owning `client`.
+    pub fn shard_of(&self, client: ClientId) -> usize {
+        client as usize % self.shards.len()
+    }
+
+    fn shard_for(&self, client: ClientId) -> &Mutex<PaymentEngine> {
+        &self.shards[self.shard_of(client)]
+    }
+
+    /// Processes a transaction on the shard owning its client. An `accrue` row is for every
+    /// client, so it is processed on every shard, one after the other: transactions processed
+    /// concurrently may come before it on some shards and after it on others.
+    ///
+    /// See `PaymentEngine::process_transaction` for the processing rules.
+    pub async fn process(&self, txn: Transaction) -> Result<ProcessOutcome, PaymentError> {
+        if txn.r#type == TransactionType::Accrue {
+            let mut outcome = ProcessOutcome::Applied;
+            for shard in &self.shards {
+                outcome = shard.lock().await.process_transaction(txn.clone()).await?;
+            }
+            return Ok(outcome);
+        }
+        let mut engine = self.shard_for(txn.client).lock().await;
+        engine.process_transaction(txn).await
+    }
+
+    /// Returns every client's state, sorted by client id.
+    ///
+    /// Shards are locked one at a time, so transactions processed concurrently with the
+    /// snapshot may be reflected for some shards and not for others. Each client's own state
+    /// is always consistent, but the report as a whole is not a point-in-time view unless
+    /// ingestion is paused while it is taken.
+    pub async fn snapshot_all(&self) -> Vec<(ClientId, Client)> {
+        let mut clients = Vec::new();
+        for shard in &self.shards {
+            let engine = shard.lock().await;
+            clients.extend(engine.clients.iter().map(|(id, client)| (*id, *client)));
+        }
+        clients.sort_by_key(|(id, _)| *id);
+        clients
+    }
+
+    /// Sends the report rows of one shard's clients to `rows`, returning the number sent.
+    ///
+    /// Meant to be called by whoever feeds a shard once its input is exhausted, so its clients
+    /// are reported without waiting for the other shards. Each message is one complete CSV line,
+    /// so rows of concurrently finishing shards interleave but are never torn; the
+    /// `write_streamed_report` task on the other end of the channel writes them out.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::IoError` if the receiving end of the channel was dropped.
+    pub async fn finish_shard(
+        &self,
+        shard: usize,
+        rows: &mpsc::Sender<String>,
+        options: StreamOptions,
+    ) -> Result<usize, PaymentError> {
+        let clients: Vec<(ClientId, Client)> = {
+            let engine = self.shards[shard].lock().await;
+            engine.clients.iter().map(|(id, client)| (*id, *client)).collect()
+        };
+        send_rows(shard, clients, rows, options).await
+    }
+}
+
+/// Sends the report rows of `clients`, those of `shard`, to `rows`, one complete CSV line per
+/// message, and returns the number sent. See `SharedPaymentEngine::finish_shard`.
+pub(crate) async fn send_rows(
+    shard: usize,
+    mut clients: Vec<(ClientId, Client)>,
+    rows: &mpsc::Sender<String>,
+    options: StreamOptions,
+) -> Result<usize, PaymentError> {
+    if options.sort_within_shard {
+        clients.sort_by_key(|(id, _)| *id);
+    }
+    let report_options = options.report_options();
+    for (id, client) in &clients {
+        let mut row = Vec::new();
+        write_csv_row(&mut row, *id, client, &report_options).map_err(|err| PaymentError::IoError(err.to_string()))?;
+        if options.tag_shard {
+            row.pop(); // the newline, the shard goes after the last column
+            writeln!(row, "{}{}", options.output.delimiter, shard)
+                .map_err(|err| PaymentError::IoError(err.to_string()))?;
+        }
+        let row = String::from_utf8(row).expect("report rows are UTF-8");
+        rows.send(row)
+            .await
+            .map_err(|_| PaymentError::IoError("streamed report writer has stopped".to_owned()))?;
+    }
+    Ok(clients.len())
+}
+
+/// Writes the report header, then every row received from `finish_shard` calls as it arrives,
+/// until all senders are dropped. Returns the number of rows written.
+///
+/// `w` is flushed after each row so downstream consumers see shards' clients as soon as they
+/// are final. The set of rows is the same as the batch report's, only their order differs.
+pub async fn write_streamed_report<W: Write>(
+    mut rows: mpsc::Receiver<String>,
+    mut w: W,
+    options: StreamOptions,
+) -> io::Result<usize> {
+    let mut header = Vec::new();
+    write_csv_header(&mut header, &options.report_options())?;
+    if options.tag_shard {
+        header.pop();
+        writeln!(header, "{}shard", options.output.delimiter)?;
+    }
+    w.write_all(&header)?;
+    w.flush()?;
+
+    let mut written = 0;
+    while let Some(row) = rows.recv().await {
+        w.write_all(row.as_bytes())?;
+        w.flush()?;
+        written += 1;
+    }
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        errors::PaymentError,
+        payment_engine::PaymentEngine,
+        report::OutputOrder,
+        shared_engine::{write_streamed_report, SharedPaymentEngine, StreamOptions},
+        types::{ClientId, IgnoreReason, ProcessOutcome, Transaction},
+    };
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+
+    #[tokio::test]
+    async fn can_process_transactions_across_shards() -> Result<(), PaymentError> {
+        let engine = SharedPaymentEngine::new(4);
+
+        engine.process(Transaction::deposit(1, 1, 2.0)).await?;
+        engine.process(Transaction::deposit(2, 2, 3.0)).await?;
+        let outcome = engine.process(Transaction::dispute(1, 1)).await?;
+        assert_eq!(outcome, ProcessOutcome::Applied);
+
+        // tx 2 lives in client 2's shard, so it is unknown to client 1's shard
+        let outcome = engine.process(Transaction::dispute(1, 2)).await?;
+        assert_eq!(outcome, ProcessOutcome::Ignored(IgnoreReason::UnknownTransaction));
+
+        let clients = engine.snapshot_all().await;
+        assert_eq!(clients.len(), 2);
+        assert_eq!(clients[0].0, 1);
+        assert_eq!(clients[0].1.held, 2.0);
+        assert_eq!(clients[1].0, 2);
+        assert_eq!(clients[1].1.available, 3.0);
+
+        // an accrual pays the clients of every shard
+        assert_eq!(engine.process(Transaction::accrue(3, 0.5)).await?, ProcessOutcome::Applied);
+        let clients = engine.snapshot_all().await;
+        assert_eq!(clients[0].1.available, 0.0);
+        assert_eq!(clients[1].1.available, 4.5);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn can_process_interleaved_transactions_from_concurrent_tasks() -> Result<(), PaymentError>
+    {
+        const TASKS: u32 = 8;
+        const CLIENTS: u32 = 16;
+        let engine = Arc::new(SharedPaymentEngine::new(3));
+
+        let mut handles = Vec::new();
+        for task in 0..TASKS {
+            let engine = Arc::clone(&engine);
+            handles.push(tokio::spawn(async move {
+                // every task touches every client, disputing its own deposits once made
+                for id in 0..CLIENTS {
+                    let client = id as ClientId;
+                    let tx = task * 1000 + id;
+                    engine.process(Transaction::deposit(client, tx, 1.0)).await?;
+                    tokio::task::yield_now().await;
+                    if client.is_multiple_of(2) {
+                        let outcome = engine.process(Transaction::dispute(client, tx)).await?;
+                        assert_eq!(outcome, ProcessOutcome::Applied);
+                    }
+                }
+                Ok::<(), PaymentError>(())
+            }));
+        }
+        for handle in handles {
+            handle.await.expect("task panicked")?;
+        }
+
+        let clients = engine.snapshot_all().await;
+        assert_eq!(clients.len(), CLIENTS as usize);
+        for (id, client) in clients {
+            assert_eq!(client.total, TASKS as f64);
+            if id % 2 == 0 {
+                assert_eq!(client.held, TASKS as f64);
+                assert_eq!(client.available, 0.0);
+            } else {
+                assert_eq!(client.held, 0.0);
+                assert_eq!(client.available, TASKS as f64);
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn streamed_rows_match_batch_report() -> Result<(), PaymentError> {
+        const SHARDS: usize = 3;
+        let engine = Arc::new(SharedPaymentEngine::new(SHARDS));
+        let mut batch = PaymentEngine::new();
+        for id in 0..20u32 {
+            let deposit = Transaction::deposit(id as ClientId, id, id as f64);
+            engine.process(deposit.clone()).await?;
+            batch.process_transaction(deposit).await?;
+        }
+        let dispute = Transaction::dispute(4, 4);
+        engine.process(dispute.clone()).await?;
+        batch.process_transaction(dispute).await?;
+
+        let options = StreamOptions {
+            sort_within_shard: true,
+            tag_shard: true,
+            ..Default::default()
+        };
+        let (sender, receiver) = mpsc::channel(4);
+        let writer = tokio::spawn(async move {
+            let mut out = Vec::new();
+            let written = write_streamed_report(receiver, &mut out, options).await.unwrap();
+            (written, String::from_utf8(out).unwrap())
+        });
+        let mut finishers = Vec::new();
+        for shard in 0..SHARDS {
+            let (engine, sender) = (Arc::clone(&engine), sender.clone());
+            finishers.push(tokio::spawn(async move {
+                engine.finish_shard(shard, &sender, options).await
+            }));
+        }
+        drop(sender);
+        let mut sent = 0;
+        for finisher in finishers {
+            sent += finisher.await.expect("task panicked")?;
+        }
+        let (written, streamed) = writer.await.expect("task panicked");
+        assert_eq!((sent, written), (20, 20));
+
+        let mut lines = streamed.lines();
+        assert_eq!(lines.next(), Some("client,available,held,total,locked,shard"));
+        let mut last_in_shard = [None; SHARDS];
+        let mut rows = Vec::new();
+        for line in lines {
+            let (row, shard) = line.rsplit_once(',').unwrap();
+            let shard: usize = shard.parse().unwrap();
+            let id: ClientId = row.split(',').next().unwrap().parse().unwrap();
+            assert_eq!(engine.shard_of(id), shard);
+            assert!(last_in_shard[shard] < Some(id), "shard {} isn't sorted", shard);
+            last_in_shard[shard] = Some(id);
+            rows.push(format!("{}\n", row));
+        }
+        rows.sort();
+
+        let mut report = Vec::new();
+        batch.write_client_states(&mut report, None, OutputOrder::Unordered).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        let mut expected: Vec<String> = report.lines().skip(1).map(|line| format!("{}\n", line)).collect();
+        expected.sort();
+        assert_eq!(rows, expected);
+        Ok(())
+    }
+}
@@ -0,0 +1,333 @@
+//! Seeded simulations for soak tests: transactions generated on the fly from a seed are fed
+//! straight into an engine, whose invariants are checked along the way.
+
+use crate::{
+    errors::PaymentError,
+    invariants,
+    payment_engine::PaymentEngine,
+    progress::ProgressReporter,
+    store::TransactionStore,
+    types::{ClientId, ProcessOutcome, Transaction},
+};
+use serde::Serialize;
+use std::collections::VecDeque;
+
+/// Rows between two checks of every account, by default.
+pub const DEFAULT_CHECK_EVERY: u64 = 1_000_000;
+
+/// Rows kept in the reproduction of a failure, by default.
+pub const DEFAULT_REPRO_WINDOW: usize = 1000;
+
+/// Recent deposits and open disputes the generator keeps to dispute, resolve and charge back.
+const POOL_SIZE: usize = 1024;
+
+/// What a simulation generates and how often it checks the accounts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SimulationConfig {
+    pub seed: u64,
+    /// Number of rows generated.
+    pub rows: u64,
+    /// Number of clients, whose ids go from 1 to `clients`.
+    pub clients: ClientId,
+    /// Check every account each time this many rows were processed, and after the last row.
+    pub check_every: u64,
+    /// At most this many rows in the reproduction of a failure.
+    pub repro_window: usize,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        SimulationConfig {
+            seed: 0,
+            rows: 1_000_000,
+            clients: 1000,
+            check_every: DEFAULT_CHECK_EVERY,
+            repro_window: DEFAULT_REPRO_WINDOW,
+        }
+    }
+}
+
+/// Transactions generated from a seed: deposits and withdrawals of random amounts, with
+/// disputes of recent deposits, and resolves and chargebacks of open disputes.
+///
+/// The rows only depend on the seed and the number of clients, not on how an engine takes them,
+/// so a simulation can be replayed row for row.
+pub struct TransactionGenerator {
+    state: u64,
+    clients: u64,
+    next_tx: u32,
+    deposits: VecDeque<(ClientId, u32)>,
+    disputes: VecDeque<(ClientId, u32)>,
+}
+
+impl TransactionGenerator {
+    pub fn new(seed: u64, clients: ClientId) -> Self {
+        TransactionGenerator {
+            state: seed,
+            clients: u64::from(clients).max(1),
+            next_tx: 1,
+            deposits: VecDeque::with_capacity(POOL_SIZE),
+            disputes: VecDeque::with_capacity(POOL_SIZE),
+        }
+    }
+
+    fn next_random(&mut self) -> u64 {
+        self.state = self.state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        self.state >> 16
+    }
+}
+
+/// Takes the entry of a pool a random number points to.
+fn pick(pool: &mut VecDeque<(ClientId, u32)>, random: u64) -> Option<(ClientId, u32)> {
+    if pool.is_empty() {
+        return None;
+    }
+    pool.swap_remove_back((random % pool.len() as u64) as usize)
+}
+
+/// Adds an entry to a pool, dropping the oldest one if it is full.
+fn keep(pool: &mut VecDeque<(ClientId, u32)>, entry: (ClientId, u32)) {
+    if pool.len() == POOL_SIZE {
+        pool.pop_front();
+    }
+    pool.push_back(entry);
+}
+
+impl Iterator for TransactionGenerator {
+    type Item = Transaction;
+
+    /// Returns the next row, `None` once the tx ids run out.
+    fn next(&mut self) -> Option<Transaction> {
+        // chargebacks are rare, as each of them locks an account for good
+        let roll = self.next_random() % 1000;
+        let random = self.next_random();
+        let settled = match roll {
+            850..=929 => pick(&mut self.deposits, random).map(|(client, tx)| {
+                keep(&mut self.disputes, (client, tx));
+                Transaction::dispute(client, tx)
+            }),
+            930..=998 => pick(&mut self.disputes, random).map(|(client, tx)| {
+                Transaction::resolve(client, tx)
+            }),
+            999 => pick(&mut self.disputes, random).map(|(client, tx)| {
+                Transaction::chargeback(client, tx)
+            }),
+            _ => None,
+        };
+        if settled.is_some() {
+            return settled;
+        }
+
+        let tx = self.next_tx;
+        self.next_tx = self.next_tx.checked_add(1)?;
+        let client = (self.next_random() % self.clients + 1) as ClientId;
+        // up to 1000.0000, a whole number of ten-thousandths
+        let amount = (self.next_random() % 10_000_000 + 1) as f64 / 1e4;
+        if (550..850).contains(&roll) {
+            Some(Transaction::withdrawal(client, tx, amount))
+        } else {
+            keep(&mut self.deposits, (client, tx));
+            Some(Transaction::deposit(client, tx, amount))
+        }
+    }
+}
+
+/// How a simulation ended.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulationOutcome {
+    /// Rows processed, all of them unless the simulation failed.
+    pub rows: u64,
+    /// Rows the engine applied.
+    pub applied: u64,
+    /// `PaymentEngine::state_digest` of the final accounts.
+    pub digest: String,
+    pub failure: Option<SimulationFailure>,
+}
+
+/// An invariant violation found by a simulation, with what it takes to reproduce it.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SimulationFailure {
+    pub seed: u64,
+    /// Index of the first row after which the violation shows, from 1.
+    pub row: u64,
+    pub violations: Vec<String>,
+    /// The last rows up to `row` of the clients in violation, at most `repro_window` of them.
+    pub window: Vec<Transaction>,
+}
+
+/// Runs a simulation on the engine `new_engine` returns, printing progress to `progress` if
+/// given.
+///
+/// Every account is checked each `check_every` rows. A violation stops the simulation, which
+/// then replays the rows since the last clean check on a new engine, checking the client of
+/// every row, to find the first row breaking an invariant; the reproduction window only keeps
+/// the rows of the clients in violation. The final digest is of the accounts when the
+/// simulation stopped.
+///
+/// # Errors
+///
+/// Returns the errors of `PaymentEngine::process_transaction`.
+pub async fn simulate<S: TransactionStore>(
+    config: &SimulationConfig,
+    new_engine: impl Fn() -> PaymentEngine<S>,
+    mut progress: Option<&mut ProgressReporter>,
+) -> Result<SimulationOutcome, PaymentError> {
+    let mut engine = new_engine();
+    let check_every = config.check_every.max(1);
+    let mut rows: u64 = 0;
+    let mut applied = 0;
+    let mut last_clean = 0;
+    for txn in TransactionGenerator::new(config.seed, config.clients).take(config.rows as usize) {
+        rows += 1;
+        if engine.process_transaction(txn).await? == ProcessOutcome::Applied {
+            applied += 1;
+        }
+        if let Some(progress) = progress.as_deref_mut() {
+            progress.tick();
+        }
+        if rows.is_multiple_of(check_every) || rows == config.rows {
+            let violations = account_violations(&engine);
+            if !violations.is_empty() {
+                let failure = reproduce(config, new_engine, last_clean, rows, violations).await?;
+                return Ok(SimulationOutcome {
+                    rows,
+                    applied,
+                    digest: engine.state_digest(),
+                    failure: Some(failure),
+                });
+            }
+            last_clean = rows;
+        }
+    }
+    Ok(SimulationOutcome {
+        rows,
+        applied,
+        digest: engine.state_digest(),
+        failure: None,
+    })
+}
+
+/// Returns the violations of every account and of the running totals.
+fn account_violations<S: TransactionStore>(engine: &PaymentEngine<S>) -> Vec<String> {
+    let mut violations: Vec<String> =
+        engine.check_invariants().iter().map(ToString::to_string).collect();
+    violations.extend(engine.check_totals().map(|drift| drift.to_string()));
+    violations
+}
+
+/// Finds the first row after `last_clean` breaking an invariant of its client, and the window
+/// of rows reproducing it. Violations not tied to a row's client, e.g. of the running totals,
+/// are reported at `found_at` with the last rows of every client.
+async fn reproduce<S: TransactionStore>(
+    config: &SimulationConfig,
+    new_engine: impl Fn() -> PaymentEngine<S>,
+    last_clean: u64,
+    found_at: u64,
+    violations: Vec<String>,
+) -> Result<SimulationFailure, PaymentError> {
+    let mut engine = new_engine();
+    let mut first = None;
+    for (row, txn) in (1..=found_at).zip(TransactionGenerator::new(config.seed, config.clients)) {
+        let client = txn.client;
+        engine.process_transaction(txn).await?;
+        if row > last_clean {
+            let account = engine.clients.get(&client);
+            let broken = account.map(|account| invariants::check_client(client, account));
+            if let Some(broken) = broken.filter(|broken| !broken.is_empty()) {
+                first = Some((row, client, broken));
+                break;
+            }
+        }
+    }
+
+    let (row, client, violations) = match first {
+        Some((row, client, broken)) => {
+            (row, Some(client), broken.iter().map(ToString::to_string).collect())
+        }
+        None => (found_at, None, violations),
+    };
+    let mut window = VecDeque::with_capacity(config.repro_window);
+    let generated = TransactionGenerator::new(config.seed, config.clients).take(row as usize);
+    for txn in generated.filter(|txn| client.is_none_or(|client| txn.client == client)) {
+        if window.len() == config.repro_window {
+            window.pop_front();
+        }
+        window.push_back(txn);
+    }
+    Ok(SimulationFailure {
+        seed: config.seed,
+        row,
+        violations,
+        window: window.into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        errors::PaymentError,
+        payment_engine::PaymentEngine,
+        simulate::{simulate, SimulationConfig, TransactionGenerator},
+        types::TransactionType,
+    };
+
+    #[tokio::test]
+    async fn simulations_are_deterministic() -> Result<(), PaymentError> {
+        let config = SimulationConfig {
+            seed: 42,
+            rows: 10_000,
+            clients: 50,
+            check_every: 1000,
+            ..Default::default()
+        };
+        let outcome = simulate(&config, PaymentEngine::new, None).await?;
+        assert_eq!(outcome.failure, None);
+        assert_eq!(outcome.rows, 10_000);
+        assert!(0 < outcome.applied && outcome.applied < outcome.rows);
+
+        let again = simulate(&config, PaymentEngine::new, None).await?;
+        assert_eq!(again, outcome);
+        let other_seed = SimulationConfig { seed: 43, ..config };
+        assert_ne!(simulate(&other_seed, PaymentEngine::new, None).await?.digest, outcome.digest);
+
+        // every kind of row shows up
+        let rows: Vec<_> = TransactionGenerator::new(42, 50).take(10_000).collect();
+        for r#type in [
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+            TransactionType::Chargeback,
+        ] {
+            assert!(rows.iter().any(|txn| txn.r#type == r#type), "{:?}", r#type);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn violations_are_reproduced_from_their_first_row() -> Result<(), PaymentError> {
+        let config = SimulationConfig {
+            seed: 7,
+            rows: 5000,
+            clients: 20,
+            check_every: 1000,
+            repro_window: 3,
+        };
+        // client 5 starts out with a total that doesn't add up
+        let corrupt_engine = || {
+            let mut engine = PaymentEngine::new();
+            let json = r#"{"5":{"available":"1.0000","held":"0.0000","total":"2.0000"}}"#;
+            engine.load_clients_json(json).expect("a valid clients dump");
+            engine
+        };
+        let outcome = simulate(&config, corrupt_engine, None).await?;
+        assert_eq!(outcome.rows, 1000);
+
+        let failure = outcome.failure.expect("the corrupt account is found");
+        let mut generated = TransactionGenerator::new(7, 20);
+        let first_row = generated.position(|txn| txn.client == 5).unwrap() + 1;
+        assert_eq!((failure.seed, failure.row), (7, first_row as u64));
+        assert_eq!(failure.violations.len(), 1);
+        assert!(failure.window.len() <= 3);
+        assert!(failure.window.iter().all(|txn| txn.client == 5));
+        Ok(())
+    }
+}
@@ -0,0 +1,221 @@
+use crate::{
+    errors::PaymentError,
+    report::OutputOptions,
+    types::{deserialize_client_id, deserialize_locked, ClientId, StatusReason},
+};
+use csv::{ReaderBuilder, StringRecord, Trim};
+use serde::Deserialize;
+use std::io::Read;
+
+/// One row of an account states report, as written by `write_client_states`.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct AccountRecord {
+    #[serde(deserialize_with = "deserialize_client_id")]
+    pub client: ClientId,
+    pub available: f64,
+    pub held: f64,
+    pub total: f64,
+    /// Also read from the `1`/`0`, `yes`/`no` and `y`/`n` of legacy close files, in any case.
+    #[serde(deserialize_with = "deserialize_locked")]
+    pub locked: bool,
+    /// The `status_reason` column of extended reports, if the report has one and the row a
+    /// reason.
+    #[serde(default)]
+    pub status_reason: Option<StatusReason>,
+}
+
+/// Reads every row of an account states report.
+///
+/// Fields are trimmed, so reports written with spaces after the commas are accepted too.
+pub fn read_account_records<R: Read>(r: R) -> Result<Vec<AccountRecord>, PaymentError> {
+    read_account_records_with(r, &OutputOptions::default())
+}
+
+/// Reads every row of an account states report written with the decimal separator and field
+/// delimiter of `output`, so reports localized with `--decimal-separator` and `--delimiter`
+/// can be read back.
+pub fn read_account_records_with<R: Read>(
+    r: R,
+    output: &OutputOptions,
+) -> Result<Vec<AccountRecord>, PaymentError> {
+    output.validate()?;
+    let parse_error = |err: csv::Error| PaymentError::CsvParseError(err.to_string());
+    let mut reader = ReaderBuilder::new()
+        .trim(Trim::All)
+        .delimiter(output.delimiter as u8)
+        .from_reader(r);
+    let headers = reader.headers().map_err(parse_error)?.clone();
+    reader
+        .records()
+        .map(|result| {
+            let record = result.map_err(parse_error)?;
+            let record = if output.decimal_separator == '.' {
+                record
+            } else {
+                record
+                    .iter()
+                    .map(|field| field.replace(output.decimal_separator, "."))
+                    .collect::<StringRecord>()
+            };
+            record.deserialize(Some(&headers)).map_err(parse_error)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        amount::amount,
+        report::{write_csv_header, write_csv_row, OutputOptions, ReportOptions},
+        payment_engine::PaymentEngine,
+        state::{read_account_records, read_account_records_with, AccountRecord},
+        types::{Client, StatusReason},
+    };
+
+    #[test]
+    fn can_read_account_states_report() {
+        let csv = "client, available, held, total, locked
+1, 1.5, 0.0, 1.5, false
+2,0.0000,2.0000,2.0000,true
+";
+        let records = read_account_records(csv.as_bytes()).unwrap();
+        assert_eq!(
+            records,
+            vec![
+                AccountRecord {
+                    client: 1,
+                    available: 1.5,
+                    held: 0.0,
+                    total: 1.5,
+                    locked: false,
+                    status_reason: None
+                },
+                AccountRecord {
+                    client: 2,
+                    available: 0.0,
+                    held: 2.0,
+                    total: 2.0,
+                    locked: true,
+                    status_reason: None
+                },
+            ]
+        );
+        assert!(read_account_records("client,available,held,total,locked\n1,x,0,0,false\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn can_read_status_reasons_of_extended_reports() {
+        let options = ReportOptions {
+            extended: true,
+            ..Default::default()
+        };
+        let locked = Client {
+            locked: true,
+            status_reason: Some(StatusReason::new("chargeback of tx 3: fraud, \"friendly\"")),
+            ..Default::default()
+        };
+        let mut report = Vec::new();
+        write_csv_header(&mut report, &options).unwrap();
+        write_csv_row(&mut report, 1, &locked, &options).unwrap();
+        write_csv_row(&mut report, 2, &Client::default(), &options).unwrap();
+        assert_eq!(
+            String::from_utf8(report.clone()).unwrap(),
+            "client,available,held,total,locked,open_disputes,disputes,chargebacks,open_dispute_held,status_reason
+1,0.0000,0.0000,0.0000,true,0,0,0,0.0000,\"chargeback of tx 3: fraud, \"\"friendly\"\"\"
+2,0.0000,0.0000,0.0000,false,0,0,0,0.0000,
+"
+        );
+        let reasons: Vec<Option<StatusReason>> = read_account_records(report.as_slice())
+            .unwrap()
+            .into_iter()
+            .map(|record| record.status_reason)
+            .collect();
+        assert_eq!(reasons, [locked.status_reason, None]);
+    }
+
+    #[test]
+    fn legacy_locked_flags_are_read_as_booleans() {
+        let spellings = [
+            ("true", true),
+            ("TRUE", true),
+            ("1", true),
+            ("yes", true),
+            ("Yes", true),
+            ("y", true),
+            ("Y", true),
+            ("false", false),
+            ("False", false),
+            ("0", false),
+            ("no", false),
+            ("NO", false),
+            ("n", false),
+            ("N", false),
+        ];
+        for (spelling, locked) in spellings {
+            let csv = format!("client,available,held,total,locked\n1,0,0,0,{}\n", spelling);
+            let records = read_account_records(csv.as_bytes()).unwrap();
+            assert_eq!(records[0].locked, locked, "{}", spelling);
+
+            // the clients JSON of --initial-state takes them too
+            let json = format!("{{\"1\":{{\"locked\":\"{}\"}}}}", spelling);
+            let mut engine = PaymentEngine::new();
+            engine.load_clients_json(&json).unwrap();
+            assert_eq!(engine.clients[&1].locked, locked, "{}", spelling);
+            let canonical = if locked { "\"locked\":true" } else { "\"locked\":false" };
+            assert!(engine.clients_json().unwrap().contains(canonical));
+        }
+
+        for rejected in ["maybe", "2"] {
+            let csv = format!("client,available,held,total,locked\n1,0,0,0,false\n2,0,0,0,{}\n", rejected);
+            let err = read_account_records(csv.as_bytes()).unwrap_err().to_string();
+            assert!(err.contains("line: 3"), "{}", err);
+            assert!(err.contains(&format!("invalid locked flag `{}`", rejected)), "{}", err);
+        }
+    }
+
+    #[test]
+    fn can_read_back_localized_report() {
+        let output = OutputOptions {
+            decimal_separator: ',',
+            delimiter: ';',
+            ..Default::default()
+        };
+        let options = ReportOptions {
+            output,
+            ..Default::default()
+        };
+        let client = Client {
+            available: amount(1234.5),
+            held: amount(0.25),
+            total: amount(1234.75),
+            ..Default::default()
+        };
+        let mut report = Vec::new();
+        write_csv_header(&mut report, &options).unwrap();
+        write_csv_row(&mut report, 7, &client, &options).unwrap();
+        assert_eq!(
+            String::from_utf8(report.clone()).unwrap(),
+            "client;available;held;total;locked\n7;1234,5000;0,2500;1234,7500;false\n"
+        );
+
+        let records = read_account_records_with(report.as_slice(), &output).unwrap();
+        assert_eq!(
+            records,
+            vec![AccountRecord {
+                client: 7,
+                available: 1234.5,
+                held: 0.25,
+                total: 1234.75,
+                locked: false,
+                status_reason: None
+            }]
+        );
+        // The default reader doesn't split on semicolons
+        assert!(read_account_records(report.as_slice()).is_err());
+        let clashing = OutputOptions {
+            delimiter: ',',
+            ..output
+        };
+        assert!(read_account_records_with(report.as_slice(), &clashing).is_err());
+    }
+}
@@ -0,0 +1,371 @@
+//! Daily account statements: for each day a client had applied transactions, the balances the
+//! day opened with, the day's transactions and the balances it closed with.
+
+use crate::{
+    amount::Amount,
+    corrections::Balances,
+    errors::PaymentError,
+    filter::ClientFilter,
+    parser::parse_records,
+    payment_engine::PaymentEngine,
+    store::TransactionStore,
+    types::{ClientId, ClientView, ProcessOutcome, TransactionType},
+};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{self, Read, Write},
+};
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+/// Offset from UTC of the days statements are cut into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct UtcOffset {
+    seconds: i32,
+}
+
+impl UtcOffset {
+    pub const UTC: UtcOffset = UtcOffset { seconds: 0 };
+
+    /// Parses an offset as given on the command line: `UTC`, or `+hh:mm` / `-hh:mm` up to
+    /// 23:59 either way.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::InvalidCliArgument` for anything else.
+    pub fn parse(value: &str) -> Result<Self, PaymentError> {
+        let invalid = || {
+            PaymentError::InvalidCliArgument(format!(
+                "invalid UTC offset '{}', expected UTC, +hh:mm or -hh:mm",
+                value
+            ))
+        };
+        if value == "UTC" {
+            return Ok(UtcOffset::UTC);
+        }
+        let (sign, hours_minutes) = match value.split_at_checked(1) {
+            Some(("+", rest)) => (1, rest),
+            Some(("-", rest)) => (-1, rest),
+            _ => return Err(invalid()),
+        };
+        let (hours, minutes) = hours_minutes.split_once(':').ok_or_else(invalid)?;
+        let two_digits = |field: &str| {
+            (field.len() == 2 && field.bytes().all(|byte| byte.is_ascii_digit()))
+                .then(|| field.parse::<i32>().ok())
+                .flatten()
+        };
+        match (two_digits(hours), two_digits(minutes)) {
+            (Some(hours), Some(minutes)) if hours < 24 && minutes < 60 => Ok(UtcOffset {
+                seconds: sign * (hours * 3600 + minutes * 60),
+            }),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// Returns the offset in seconds, negative west of UTC.
+    pub fn seconds(self) -> i32 {
+        self.seconds
+    }
+
+    /// Returns the day of `timestamp` at this offset, as a number of days since 1970-01-01.
+    pub fn day_of(self, timestamp: u64) -> i64 {
+        (timestamp as i64 + i64::from(self.seconds)).div_euclid(SECONDS_PER_DAY)
+    }
+
+    /// Returns the time of day of `timestamp` at this offset, in seconds since midnight.
+    fn time_of(self, timestamp: u64) -> i64 {
+        (timestamp as i64 + i64::from(self.seconds)).rem_euclid(SECONDS_PER_DAY)
+    }
+}
+
+/// Writes `UTC`, or `UTC+hh:mm` / `UTC-hh:mm`.
+impl fmt::Display for UtcOffset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.seconds == 0 {
+            return write!(f, "UTC");
+        }
+        let sign = if self.seconds < 0 { '-' } else { '+' };
+        let minutes = self.seconds.unsigned_abs() / 60;
+        write!(f, "UTC{}{:02}:{:02}", sign, minutes / 60, minutes % 60)
+    }
+}
+
+/// A transaction applied to a client's account, with how it changed the balances.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatementEntry {
+    /// Line of the row in the input (the header is line 1).
+    pub line: u64,
+    /// When the transaction happened, its own timestamp or, for a row without one, that of the
+    /// row before it.
+    pub timestamp: u64,
+    pub r#type: TransactionType,
+    pub tx: u32,
+    /// Changes of the balances, negative for a decrease.
+    pub change: Balances,
+    /// Memo of the transaction, cut like the engine cuts those it stores.
+    pub memo: Option<String>,
+}
+
+/// The statement of a client for a day.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    pub client: ClientId,
+    /// The day, as a number of days since 1970-01-01 at `utc_offset`.
+    pub day: i64,
+    pub utc_offset: UtcOffset,
+    /// The balances at the end of the client's previous statement, or before its first
+    /// transaction.
+    pub opening: Balances,
+    /// The day's transactions, in input order.
+    pub entries: Vec<StatementEntry>,
+    /// `opening` plus the changes of `entries`.
+    pub closing: Balances,
+}
+
+impl Statement {
+    /// Returns the day as `yyyy-mm-dd`.
+    pub fn date(&self) -> String {
+        let (year, month, day) = civil_from_days(self.day);
+        format!("{:04}-{:02}-{:02}", year, month, day)
+    }
+
+    /// Writes the statement as a section of text ending with a blank line.
+    pub fn write_to<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "Statement of client {} for {} ({})", self.client, self.date(), self.utc_offset)?;
+        writeln!(w, "Opening balance: {}", BalancesText(&self.opening))?;
+        for entry in &self.entries {
+            let time = self.utc_offset.time_of(entry.timestamp);
+            write!(
+                w,
+                "  {:02}:{:02}:{:02}  {:<14} tx {:<10} available {:>12}  held {:>12}",
+                time / 3600,
+                time / 60 % 60,
+                time % 60,
+                entry.r#type.as_str(),
+                entry.tx,
+                Signed(entry.change.available),
+                Signed(entry.change.held)
+            )?;
+            if let Some(memo) = &entry.memo {
+                write!(w, "  {}", memo)?;
+            }
+            writeln!(w)?;
+        }
+        writeln!(w, "Closing balance: {}", BalancesText(&self.closing))?;
+        writeln!(w)
+    }
+}
+
+struct BalancesText<'a>(&'a Balances);
+
+impl fmt::Display for BalancesText<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "available {}, held {}, total {}", self.0.available, self.0.held, self.0.total)
+    }
+}
+
+/// An amount change, with a `+` for increases.
+struct Signed(Amount);
+
+impl fmt::Display for Signed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.0.is_negative() { "" } else { "+" };
+        f.pad(&format!("{}{}", sign, self.0))
+    }
+}
+
+/// Processes `input` with `engine` and returns the daily statements of the clients in
+/// `clients`, or of every client, ordered by client then day. Days without an applied
+/// transaction of the client get no statement.
+///
+/// Transactions are dated by their `timestamp` column. Rows without one, typically disputes and
+/// their settlements, are dated like the row before them.
+///
+/// # Errors
+///
+/// Returns the first row that fails to parse, since statements missing a row would be wrong,
+/// or a `PaymentError::CsvParseError` for an applied row without a timestamp before any row
+/// with one.
+pub async fn statements<S: TransactionStore>(
+    mut engine: PaymentEngine<S>,
+    input: Box<dyn Read>,
+    clients: Option<&ClientFilter>,
+    utc_offset: UtcOffset,
+) -> Result<Vec<Statement>, PaymentError> {
+    let (_, records) = parse_records(input).await?;
+    let mut openings: BTreeMap<ClientId, Balances> = BTreeMap::new();
+    let mut entries: BTreeMap<ClientId, Vec<StatementEntry>> = BTreeMap::new();
+    let mut last_timestamp = None;
+    for record in records {
+        let txn = record.transaction?;
+        last_timestamp = txn.timestamp.or(last_timestamp);
+        let client = txn.client;
+        let before = balances_of(engine.client_view(client), client);
+        let (r#type, tx) = (txn.r#type, txn.tx);
+        let mut memo = txn.memo.clone();
+        if let Some(memo) = memo.as_mut() {
+            engine.config().cap_memo(memo);
+        }
+        if engine.process_transaction(txn).await? != ProcessOutcome::Applied
+            || !clients.is_none_or(|clients| clients.contains(client))
+        {
+            continue;
+        }
+        let timestamp = last_timestamp.ok_or_else(|| {
+            PaymentError::CsvParseError(format!(
+                "line {}: the row has no timestamp, and no row before it has one",
+                record.line
+            ))
+        })?;
+        let after = balances_of(engine.client_view(client), client);
+        openings.entry(client).or_insert(before);
+        entries.entry(client).or_default().push(StatementEntry {
+            line: record.line,
+            timestamp,
+            r#type,
+            tx,
+            change: Balances {
+                available: after.available.checked_sub(before.available)?,
+                held: after.held.checked_sub(before.held)?,
+                total: after.total.checked_sub(before.total)?,
+            },
+            memo,
+        });
+    }
+
+    let mut statements = Vec::new();
+    for (client, entries) in entries {
+        let mut days: BTreeMap<i64, Vec<StatementEntry>> = BTreeMap::new();
+        for entry in entries {
+            days.entry(utc_offset.day_of(entry.timestamp)).or_default().push(entry);
+        }
+        let mut opening = openings[&client];
+        for (day, entries) in days {
+            let mut closing = opening;
+            for entry in &entries {
+                closing = Balances {
+                    available: closing.available.checked_add(entry.change.available)?,
+                    held: closing.held.checked_add(entry.change.held)?,
+                    total: closing.total.checked_add(entry.change.total)?,
+                };
+            }
+            statements.push(Statement {
+                client,
+                day,
+                utc_offset,
+                opening,
+                entries,
+                closing,
+            });
+            opening = closing;
+        }
+    }
+    Ok(statements)
+}
+
+fn balances_of(view: Option<ClientView>, client: ClientId) -> Balances {
+    let view = view.unwrap_or_else(|| ClientView::empty(client));
+    Balances {
+        available: view.available,
+        held: view.held,
+        total: view.total,
+    }
+}
+
+/// Returns the year, month and day of a number of days since 1970-01-01, in the proleptic
+/// Gregorian calendar (Howard Hinnant's `civil_from_days`).
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        amount::{amount, Amount},
+        errors::PaymentError,
+        payment_engine::PaymentEngine,
+        statements::{civil_from_days, statements, UtcOffset},
+        types::TransactionType,
+    };
+
+    // 2023-11-14 22:13:20 UTC, then the next day
+    const TWO_DAYS: &str = "type,client,tx,amount,timestamp,memo
+deposit,1,1,100.0,1700000000
+deposit,2,2,5.0,1700000100
+withdrawal,1,3,30.0,1700003600,atm
+dispute,1,1,,
+deposit,1,4,12.5,1700040000
+resolve,1,1,,1700045000
+withdrawal,1,5,500.0,1700046000
+";
+
+    #[tokio::test]
+    async fn statements_chain_their_opening_and_closing_balances() -> Result<(), PaymentError> {
+        let input = Box::new(std::io::Cursor::new(TWO_DAYS));
+        let clients = crate::filter::ClientFilter::parse("1").unwrap();
+        let daily = statements(PaymentEngine::new(), input, Some(&clients), UtcOffset::UTC).await?;
+
+        let days: Vec<_> = daily.iter().map(|statement| (statement.client, statement.date())).collect();
+        assert_eq!(days, [(1, "2023-11-14".to_owned()), (1, "2023-11-15".to_owned())]);
+        let (first, second) = (&daily[0], &daily[1]);
+        assert_eq!(first.opening.total, 0.0);
+        // the dispute without a timestamp is dated like the withdrawal before it
+        let types: Vec<_> = first.entries.iter().map(|entry| entry.r#type).collect();
+        assert_eq!(types, [TransactionType::Deposit, TransactionType::Withdrawal, TransactionType::Dispute]);
+        assert_eq!((first.closing.available, first.closing.held, first.closing.total), (amount(-30.0), amount(100.0), amount(70.0)));
+        assert_eq!(second.opening, first.closing);
+        // the rejected withdrawal isn't on the statement
+        assert_eq!(second.entries.len(), 2);
+        assert_eq!((second.closing.available, second.closing.held, second.closing.total), (amount(82.5), Amount::ZERO, amount(82.5)));
+
+        let mut out = Vec::new();
+        first.write_to(&mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("Statement of client 1 for 2023-11-14 (UTC)\nOpening balance: available 0.0000, held 0.0000, total 0.0000\n"), "{}", text);
+        // with its memo, if it has one
+        assert!(text.contains("  22:13:20  deposit        tx 1          available    +100.0000  held      +0.0000\n"), "{}", text);
+        assert!(text.contains("  23:13:20  withdrawal     tx 3          available     -30.0000  held      +0.0000  atm\n"), "{}", text);
+        assert!(text.ends_with("Closing balance: available -30.0000, held 100.0000, total 70.0000\n\n"), "{}", text);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn days_follow_the_utc_offset() -> Result<(), PaymentError> {
+        // two hours ahead, both of client 1's days fall on the 15th
+        let offset = UtcOffset::parse("+02:00")?;
+        let input = Box::new(std::io::Cursor::new(TWO_DAYS));
+        let daily = statements(PaymentEngine::new(), input, None, offset).await?;
+        let days: Vec<_> = daily.iter().map(|statement| (statement.client, statement.date())).collect();
+        assert_eq!(days, [(1, "2023-11-15".to_owned()), (2, "2023-11-15".to_owned())]);
+        assert_eq!(daily[0].entries.len(), 5);
+        assert_eq!(offset.to_string(), "UTC+02:00");
+        assert_eq!(UtcOffset::parse("-05:30")?.seconds(), -19_800);
+        for invalid in ["2", "+2:00", "+24:00", "-05:60", "UTC+1"] {
+            assert!(UtcOffset::parse(invalid).is_err(), "{}", invalid);
+        }
+
+        let undated = "type,client,tx,amount\ndeposit,1,1,1.0\n";
+        let input = Box::new(std::io::Cursor::new(undated));
+        assert!(statements(PaymentEngine::new(), input, None, offset).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn days_since_the_epoch_are_calendar_dates() {
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(-1), (1969, 12, 31));
+        assert_eq!(civil_from_days(19_675), (2023, 11, 14));
+        assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+        assert_eq!(civil_from_days(11_017), (2000, 3, 1));
+    }
+}
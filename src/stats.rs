@@ -0,0 +1,430 @@
+use crate::{
+    parser::EmptyInput,
+    invariants::Ledger,
+    tiered_store::TieredStoreStats,
+    types::{IgnoreReason, ProcessOutcome, TransactionType},
+};
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+/// Counters and timings of a single run, reported at the end of processing.
+#[derive(Debug, Clone)]
+pub struct RunStats {
+    started: Instant,
+    /// Rows successfully deserialized by the parser.
+    pub rows_parsed: u64,
+    /// Rows the parser failed to deserialize.
+    pub parse_errors: u64,
+    /// How the inputs were empty, when none of them had a single row.
+    pub empty_input: Option<EmptyInput>,
+    /// Transactions that changed the engine state.
+    pub rows_applied: u64,
+    /// Transactions the engine ignored.
+    pub rows_rejected: u64,
+    /// Transactions the engine ignored, by transaction type and reason, in declaration order
+    /// of both. Only the combinations that occurred are present.
+    pub rejections: BTreeMap<(TransactionType, IgnoreReason), u64>,
+    /// Transactions skipped because their client is outside the input filter.
+    pub rows_filtered: u64,
+    /// Client accounts that failed the invariant check at the end of the run.
+    pub invariant_violations: u64,
+    /// Wall-clock duration of the run, set by `finish`.
+    pub elapsed: Duration,
+    /// Peak resident set size in KiB, where the platform exposes it.
+    pub peak_rss_kib: Option<u64>,
+    /// Eviction statistics when the tiered transaction store is in use.
+    pub tiered_store: Option<TieredStoreStats>,
+    /// Disputes neither resolved nor charged back at the end of the run.
+    pub open_disputes: u64,
+    /// Amount held by the open disputes.
+    pub open_dispute_held: f64,
+    /// Deposits applied but flagged as suspected duplicates.
+    pub suspected_duplicates: u64,
+    /// Chargeback fees debited from the clients.
+    pub collected_chargeback_fees: f64,
+    /// Interest paid by `--accrue`.
+    pub interest_paid: f64,
+    /// Deposits and withdrawals whose tx id is lower than one seen before them, when checked.
+    pub out_of_order_tx: u64,
+    /// Warnings dropped because their consumer fell behind, in `--follow` mode.
+    pub dropped_warnings: u64,
+    /// Deposits and withdrawals applied without being stored, when dispute support is off.
+    pub rows_not_retained: u64,
+    /// Deposits and withdrawals below the minimum amount, applied or rejected.
+    pub dust_transactions: u64,
+    /// Transactions whose processing panicked and was caught, under `--max-panics`.
+    pub caught_panics: u64,
+    /// Store lookups and removals that failed and succeeded when tried again.
+    pub storage_retries: u64,
+    /// The money flows of the run checked against the accounts, set at the end of the run.
+    pub ledger: Option<Ledger>,
+    /// Available funds of all clients at the end of the run.
+    pub total_available: f64,
+    /// Funds held across all clients at the end of the run.
+    pub total_held: f64,
+    /// Total funds of all clients at the end of the run.
+    pub total_funds: f64,
+    /// Accounts locked at the end of the run.
+    pub locked_accounts: u64,
+    /// `PaymentEngine::state_digest` of the final account states, when asked for.
+    pub checksum: Option<String>,
+}
+
+impl RunStats {
+    /// Starts collecting statistics, the run's clock starts now.
+    pub fn start() -> Self {
+        RunStats {
+            started: Instant::now(),
+            rows_parsed: 0,
+            parse_errors: 0,
+            empty_input: None,
+            rows_applied: 0,
+            rows_rejected: 0,
+            rejections: BTreeMap::new(),
+            rows_filtered: 0,
+            invariant_violations: 0,
+            elapsed: Duration::ZERO,
+            peak_rss_kib: None,
+            tiered_store: None,
+            open_disputes: 0,
+            open_dispute_held: 0.0,
+            suspected_duplicates: 0,
+            collected_chargeback_fees: 0.0,
+            interest_paid: 0.0,
+            out_of_order_tx: 0,
+            dropped_warnings: 0,
+            rows_not_retained: 0,
+            dust_transactions: 0,
+            caught_panics: 0,
+            storage_retries: 0,
+            ledger: None,
+            total_available: 0.0,
+            total_held: 0.0,
+            total_funds: 0.0,
+            locked_accounts: 0,
+            checksum: None,
+        }
+    }
+
+    /// Records the result of parsing one row.
+    pub fn record_parsed<T, E>(&mut self, row: &Result<T, E>) {
+        match row {
+            Ok(_) => self.rows_parsed += 1,
+            Err(_) => self.parse_errors += 1,
+        }
+    }
+
+    /// Records the outcome of processing one transaction of the given type.
+    pub fn record_outcome(&mut self, r#type: TransactionType, outcome: &ProcessOutcome) {
+        match outcome {
+            ProcessOutcome::Applied => self.rows_applied += 1,
+            ProcessOutcome::Ignored(reason) => {
+                self.rows_rejected += 1;
+                *self.rejections.entry((r#type, *reason)).or_default() += 1;
+            }
+        }
+    }
+
+    /// Returns the number of transactions of a type ignored for a reason.
+    pub fn rejected(&self, r#type: TransactionType, reason: IgnoreReason) -> u64 {
+        self.rejections.get(&(r#type, reason)).copied().unwrap_or(0)
+    }
+
+    /// Stops the clock and samples the peak memory usage.
+    pub fn finish(&mut self) {
+        self.elapsed = self.started.elapsed();
+        self.peak_rss_kib = peak_rss_kib();
+    }
+
+    /// Returns the number of parsed rows per second of wall-clock time.
+    pub fn rows_per_second(&self) -> f64 {
+        let seconds = self.elapsed.as_secs_f64();
+        if seconds > 0.0 {
+            (self.rows_parsed + self.parse_errors) as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+
+    /// Writes a human-readable summary, one figure per line.
+    pub fn write_summary<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "rows parsed: {}", self.rows_parsed)?;
+        writeln!(w, "parse errors: {}", self.parse_errors)?;
+        writeln!(w, "rows applied: {}", self.rows_applied)?;
+        writeln!(w, "rows rejected: {}", self.rows_rejected)?;
+        self.write_rejections(&mut w)?;
+        if self.rows_parsed == 0 {
+            match self.empty_input {
+                Some(empty) => writeln!(w, "no transactions processed: {}", empty)?,
+                None => writeln!(w, "no transactions processed")?,
+            }
+        }
+        if self.rows_filtered > 0 {
+            writeln!(w, "rows filtered: {}", self.rows_filtered)?;
+        }
+        if self.invariant_violations > 0 {
+            writeln!(w, "invariant violations: {}", self.invariant_violations)?;
+        }
+        writeln!(
+            w,
+            "open disputes: {} ({:.4} held)",
+            self.open_disputes, self.open_dispute_held
+        )?;
+        if self.suspected_duplicates > 0 {
+            writeln!(w, "suspected duplicates: {}", self.suspected_duplicates)?;
+        }
+        if self.collected_chargeback_fees != 0.0 {
+            writeln!(w, "collected chargeback fees: {:.4}", self.collected_chargeback_fees)?;
+        }
+        if self.interest_paid != 0.0 {
+            writeln!(w, "interest paid: {:.4}", self.interest_paid)?;
+        }
+        if self.out_of_order_tx > 0 {
+            writeln!(w, "out of order tx ids: {}", self.out_of_order_tx)?;
+        }
+        if self.dropped_warnings > 0 {
+            writeln!(w, "dropped warnings: {}", self.dropped_warnings)?;
+        }
+        if self.rows_not_retained > 0 {
+            writeln!(w, "rows not retained: {} (dispute support off)", self.rows_not_retained)?;
+        }
+        if self.dust_transactions > 0 {
+            writeln!(w, "dust: {}", self.dust_transactions)?;
+        }
+        let above_limit: u64 = self
+            .rejections
+            .iter()
+            .filter(|((_, reason), _)| *reason == IgnoreReason::AmountAboveLimit)
+            .map(|(_, count)| count)
+            .sum();
+        if above_limit > 0 {
+            writeln!(w, "amounts above limit: {} (rejected)", above_limit)?;
+        }
+        if self.caught_panics > 0 {
+            writeln!(w, "internal errors: {} (transactions that panicked, skipped)", self.caught_panics)?;
+        }
+        if self.storage_retries > 0 {
+            writeln!(w, "degraded: {} storage operations failed and succeeded on retry", self.storage_retries)?;
+        }
+        writeln!(
+            w,
+            "client funds: {:.4} ({:.4} available, {:.4} held)",
+            self.total_funds, self.total_available, self.total_held
+        )?;
+        if let Some(ledger) = &self.ledger {
+            writeln!(w, "ledger: {}", ledger)?;
+        }
+        if self.locked_accounts > 0 {
+            writeln!(w, "locked accounts: {}", self.locked_accounts)?;
+        }
+        writeln!(w, "duration: {:.3}s", self.elapsed.as_secs_f64())?;
+        writeln!(w, "throughput: {:.0} rows/s", self.rows_per_second())?;
+        if let Some(kib) = self.peak_rss_kib {
+            writeln!(w, "peak rss: {} KiB", kib)?;
+        }
+        if let Some(store) = self.tiered_store {
+            writeln!(
+                w,
+                "tiered store: {} evictions, {} spilled writes, {} faults",
+                store.evictions, store.spilled_writes, store.faults
+            )?;
+        }
+        // last, so CI can pick it with `tail -n 1`
+        if let Some(checksum) = &self.checksum {
+            writeln!(w, "checksum: sha256:{}", checksum)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the rejections as a table aligned on its columns, under the rejected row count.
+    fn write_rejections<W: Write>(&self, mut w: W) -> io::Result<()> {
+        if self.rejections.is_empty() {
+            return Ok(());
+        }
+        let keys = self.rejections.keys();
+        let type_width = keys.clone().map(|(r#type, _)| r#type.as_str().len()).fold("type".len(), usize::max);
+        let reason_width = keys.map(|(_, reason)| reason.as_str().len()).fold("reason".len(), usize::max);
+        let count_width = self
+            .rejections
+            .values()
+            .map(|count| count.to_string().len())
+            .fold("count".len(), usize::max);
+        writeln!(
+            w,
+            "  {:<type_width$}  {:<reason_width$}  {:>count_width$}",
+            "type", "reason", "count"
+        )?;
+        for ((r#type, reason), count) in &self.rejections {
+            writeln!(
+                w,
+                "  {:<type_width$}  {:<reason_width$}  {:>count_width$}",
+                r#type.as_str(),
+                reason.as_str(),
+                count
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Returns the rejections as a JSON object of transaction types, each an object of the
+    /// reasons its transactions were ignored for with their counts.
+    fn rejections_json(&self) -> String {
+        let mut by_type: BTreeMap<TransactionType, Vec<String>> = BTreeMap::new();
+        for ((r#type, reason), count) in &self.rejections {
+            by_type
+                .entry(*r#type)
+                .or_default()
+                .push(format!("\"{}\":{}", reason.as_str(), count));
+        }
+        let types: Vec<String> = by_type
+            .into_iter()
+            .map(|(r#type, reasons)| format!("\"{}\":{{{}}}", r#type.as_str(), reasons.join(",")))
+            .collect();
+        format!("{{{}}}", types.join(","))
+    }
+
+    /// Returns the statistics as a JSON object.
+    ///
+    /// The keys and their order are stable, optional figures are `null` when unavailable.
+    pub fn to_json(&self) -> String {
+        let peak_rss_kib = self
+            .peak_rss_kib
+            .map(|kib| kib.to_string())
+            .unwrap_or_else(|| "null".to_owned());
+        let tiered_store = self
+            .tiered_store
+            .map(|store| {
+                format!(
+                    "{{\"evictions\":{},\"spilled_writes\":{},\"faults\":{}}}",
+                    store.evictions, store.spilled_writes, store.faults
+                )
+            })
+            .unwrap_or_else(|| "null".to_owned());
+        let empty_input = self
+            .empty_input
+            .map(|empty| format!("\"{}\"", empty.as_str()))
+            .unwrap_or_else(|| "null".to_owned());
+        let ledger = self
+            .ledger
+            .map(|ledger| ledger.to_json())
+            .unwrap_or_else(|| "null".to_owned());
+        let checksum = self
+            .checksum
+            .as_ref()
+            .map(|checksum| format!("\"{}\"", checksum))
+            .unwrap_or_else(|| "null".to_owned());
+        format!(
+            "{{\"rows_parsed\":{},\"parse_errors\":{},\"empty_input\":{},\"rows_applied\":{},\"rows_rejected\":{},\"elapsed_seconds\":{:.6},\"rows_per_second\":{:.3},\"peak_rss_kib\":{},\"tiered_store\":{},\"invariant_violations\":{},\"ledger\":{},\"rows_filtered\":{},\"open_disputes\":{},\"open_dispute_held\":{:.4},\"suspected_duplicates\":{},\"collected_chargeback_fees\":{:.4},\"interest_paid\":{:.4},\"out_of_order_tx\":{},\"dropped_warnings\":{},\"rows_not_retained\":{},\"dust_transactions\":{},\"caught_panics\":{},\"storage_retries\":{},\"total_available\":{:.4},\"total_held\":{:.4},\"total_funds\":{:.4},\"locked_accounts\":{},\"rejections\":{},\"checksum\":{}}}",
+            self.rows_parsed,
+            self.parse_errors,
+            empty_input,
+            self.rows_applied,
+            self.rows_rejected,
+            self.elapsed.as_secs_f64(),
+            self.rows_per_second(),
+            peak_rss_kib,
+            tiered_store,
+            self.invariant_violations,
+            ledger,
+            self.rows_filtered,
+            self.open_disputes,
+            self.open_dispute_held,
+            self.suspected_duplicates,
+            self.collected_chargeback_fees,
+            self.interest_paid,
+            self.out_of_order_tx,
+            self.dropped_warnings,
+            self.rows_not_retained,
+            self.dust_transactions,
+            self.caught_panics,
+            self.storage_retries,
+            self.total_available,
+            self.total_held,
+            self.total_funds,
+            self.locked_accounts,
+            self.rejections_json(),
+            checksum
+        )
+    }
+}
+
+/// Reads the peak resident set size from `/proc/self/status`, available on Linux only.
+fn peak_rss_kib() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))
+        .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        errors::PaymentError, parser::parse_transactions, payment_engine::PaymentEngine,
+        stats::RunStats, tiered_store::TieredStoreStats,
+        types::{IgnoreReason, TransactionType},
+    };
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn can_count_parsed_applied_and_rejected_rows() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 1.0
+        deposit, 2, 2, 2.0
+        deposit, 1, 3, 2.0
+        withdrawal, 1, 4, 1.5
+        withdrawal, 2, 5, 3.0
+        dispute, 1, 3
+        resolve, 2, 3";
+        let str_buf = stringreader::StringReader::new(csv);
+        let transactions = parse_transactions(Box::new(str_buf)).await?;
+        let mut engine = PaymentEngine::new();
+        let mut stats = RunStats::start();
+
+        for txn in transactions {
+            stats.record_parsed(&txn);
+            let txn = txn?;
+            let r#type = txn.r#type;
+            let outcome = engine.process_transaction(txn).await?;
+            stats.record_outcome(r#type, &outcome);
+        }
+        stats.finish();
+
+        assert_eq!(stats.rows_parsed, 7);
+        assert_eq!(stats.parse_errors, 0);
+        assert_eq!(stats.rows_applied, 5);
+        assert_eq!(stats.rows_rejected, 2);
+        assert_eq!(stats.rejected(TransactionType::Withdrawal, IgnoreReason::InsufficientFunds), 1);
+        assert_eq!(stats.rejected(TransactionType::Resolve, IgnoreReason::ClientMismatch), 1);
+        assert_eq!(stats.rejected(TransactionType::Dispute, IgnoreReason::ClientMismatch), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn json_schema_is_stable() {
+        let mut stats = RunStats::start();
+        stats.rows_parsed = 3;
+        stats.parse_errors = 1;
+        stats.rows_applied = 2;
+        stats.rows_rejected = 1;
+        stats.elapsed = Duration::from_secs(2);
+
+        assert_eq!(
+            stats.to_json(),
+            "{\"rows_parsed\":3,\"parse_errors\":1,\"empty_input\":null,\"rows_applied\":2,\"rows_rejected\":1,\"elapsed_seconds\":2.000000,\"rows_per_second\":2.000,\"peak_rss_kib\":null,\"tiered_store\":null,\"invariant_violations\":0,\"ledger\":null,\"rows_filtered\":0,\"open_disputes\":0,\"open_dispute_held\":0.0000,\"suspected_duplicates\":0,\"collected_chargeback_fees\":0.0000,\"interest_paid\":0.0000,\"out_of_order_tx\":0,\"dropped_warnings\":0,\"rows_not_retained\":0,\"dust_transactions\":0,\"caught_panics\":0,\"storage_retries\":0,\"total_available\":0.0000,\"total_held\":0.0000,\"total_funds\":0.0000,\"locked_accounts\":0,\"rejections\":{},\"checksum\":null}"
+        );
+
+        stats.peak_rss_kib = Some(1024);
+        stats.tiered_store = Some(TieredStoreStats {
+            evictions: 5,
+            spilled_writes: 4,
+            faults: 1,
+        });
+        assert!(stats.to_json().ends_with(
+            "\"peak_rss_kib\":1024,\"tiered_store\":{\"evictions\":5,\"spilled_writes\":4,\"faults\":1},\"invariant_violations\":0,\"ledger\":null,\"rows_filtered\":0,\"open_disputes\":0,\"open_dispute_held\":0.0000,\"suspected_duplicates\":0,\"collected_chargeback_fees\":0.0000,\"interest_paid\":0.0000,\"out_of_order_tx\":0,\"dropped_warnings\":0,\"rows_not_retained\":0,\"dust_transactions\":0,\"caught_panics\":0,\"storage_retries\":0,\"total_available\":0.0000,\"total_held\":0.0000,\"total_funds\":0.0000,\"locked_accounts\":0,\"rejections\":{},\"checksum\":null}"
+        ));
+    }
+}
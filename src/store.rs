@@ -0,0 +1,296 @@
+//! Storage abstractions for the transactions the engine retains and for client accounts.
+//!
+//! Store operations are asynchronous so that disk or network backed stores can do their I/O
+//! without blocking the runtime, while the in-memory stores return ready futures and cost
+//! nothing extra on the hot path. The engine works on the accounts in its own map, loading an
+//! account from its `ClientStore` the first time a transaction refers to it and writing back
+//! the accounts its transactions change.
+
+use crate::{
+    errors::PaymentError,
+    types::{Client, ClientId, Transaction},
+};
+use std::{
+    collections::HashMap,
+    future::{ready, Future},
+};
+
+/// Storage for the transactions the engine keeps around so later disputes can refer to them.
+///
+/// Lookups take `&mut self` because some implementations (e.g. the tiered store) update
+/// their recency bookkeeping or fault records back in from disk on reads.
+pub trait TransactionStore {
+    /// Stores a transaction under its `tx` id, replacing any previous entry with the same id.
+    fn insert(
+        &mut self,
+        txn: Transaction,
+    ) -> impl Future<Output = Result<(), PaymentError>> + Send;
+
+    /// Returns a copy of the stored transaction with the given id, if present.
+    fn get(
+        &mut self,
+        tx: u32,
+    ) -> impl Future<Output = Result<Option<Transaction>, PaymentError>> + Send;
+
+    /// Removes the transaction with the given id, if present. Used to revert an insertion.
+    fn remove(&mut self, tx: u32) -> impl Future<Output = Result<(), PaymentError>> + Send;
+
+    /// Returns the number of stored transactions.
+    fn len(&self) -> usize;
+
+    /// Returns the ids of every stored transaction, in no particular order.
+    fn tx_ids(&self) -> Vec<u32>;
+
+    /// Returns `true` if no transactions are stored.
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Storage for client accounts outliving the engine, e.g. a database the accounts of earlier
+/// runs are in.
+///
+/// The engine looks an account up before the first transaction of a client it has no account
+/// for, and writes an account back after every transaction that changed it, and removes it when
+/// undoing the transaction that opened it. Accounts changed outside of transactions, e.g. by
+/// `PaymentEngine::load_clients_json`, `unlock` or `apply_correction`, are written by
+/// `PaymentEngine::save_clients`.
+pub trait ClientStore {
+    /// Set by stores that keep nothing, so the engine doesn't track the accounts to write back.
+    const KEEPS_ACCOUNTS: bool = true;
+
+    /// Returns a copy of the account of `id`, if present.
+    fn get(&mut self, id: ClientId) -> impl Future<Output = Result<Option<Client>, PaymentError>> + Send;
+
+    /// Stores the account of `id`, replacing any previous one.
+    fn insert(&mut self, id: ClientId, client: Client) -> impl Future<Output = Result<(), PaymentError>> + Send;
+
+    /// Removes the account of `id`, if present.
+    fn remove(&mut self, id: ClientId) -> impl Future<Output = Result<(), PaymentError>> + Send;
+}
+
+/// Keeps no account beyond the engine's own map, which holds every account of the run. This is
+/// the default client store.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct InMemoryClientStore;
+
+impl ClientStore for InMemoryClientStore {
+    const KEEPS_ACCOUNTS: bool = false;
+
+    fn get(&mut self, _id: ClientId) -> impl Future<Output = Result<Option<Client>, PaymentError>> + Send {
+        ready(Ok(None))
+    }
+
+    fn insert(&mut self, _id: ClientId, _client: Client) -> impl Future<Output = Result<(), PaymentError>> + Send {
+        ready(Ok(()))
+    }
+
+    fn remove(&mut self, _id: ClientId) -> impl Future<Output = Result<(), PaymentError>> + Send {
+        ready(Ok(()))
+    }
+}
+
+/// Keeps every stored transaction in a `HashMap`. This is the default store.
+#[derive(Default)]
+pub struct InMemoryTransactionStore {
+    transactions: HashMap<u32, Transaction>,
+}
+
+impl InMemoryTransactionStore {
+    pub fn new() -> Self {
+        InMemoryTransactionStore {
+            transactions: HashMap::new(),
+        }
+    }
+}
+
+impl TransactionStore for InMemoryTransactionStore {
+    fn insert(
+        &mut self,
+        txn: Transaction,
+    ) -> impl Future<Output = Result<(), PaymentError>> + Send {
+        self.transactions.insert(txn.tx, txn);
+        ready(Ok(()))
+    }
+
+    fn get(
+        &mut self,
+        tx: u32,
+    ) -> impl Future<Output = Result<Option<Transaction>, PaymentError>> + Send {
+        ready(Ok(self.transactions.get(&tx).cloned()))
+    }
+
+    fn remove(&mut self, tx: u32) -> impl Future<Output = Result<(), PaymentError>> + Send {
+        self.transactions.remove(&tx);
+        ready(Ok(()))
+    }
+
+    fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    fn tx_ids(&self) -> Vec<u32> {
+        self.transactions.keys().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        errors::PaymentError,
+        parser::parse_transactions,
+        payment_engine::PaymentEngine,
+        store::{ClientStore, InMemoryTransactionStore, TransactionStore},
+        types::{Client, ClientId, ProcessOutcome, Transaction},
+    };
+    use std::{
+        collections::HashMap,
+        future::Future,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    /// A store that takes a while to answer, like a remote backend would.
+    struct SlowStore {
+        inner: InMemoryTransactionStore,
+        delay: Duration,
+    }
+
+    impl TransactionStore for SlowStore {
+        fn insert(
+            &mut self,
+            txn: Transaction,
+        ) -> impl Future<Output = Result<(), PaymentError>> + Send {
+            let delay = self.delay;
+            let inserted = self.inner.insert(txn);
+            async move {
+                tokio::time::sleep(delay).await;
+                inserted.await
+            }
+        }
+
+        fn get(
+            &mut self,
+            tx: u32,
+        ) -> impl Future<Output = Result<Option<Transaction>, PaymentError>> + Send {
+            let delay = self.delay;
+            let found = self.inner.get(tx);
+            async move {
+                tokio::time::sleep(delay).await;
+                found.await
+            }
+        }
+
+        fn remove(&mut self, tx: u32) -> impl Future<Output = Result<(), PaymentError>> + Send {
+            self.inner.remove(tx)
+        }
+
+        fn len(&self) -> usize {
+            self.inner.len()
+        }
+
+        fn tx_ids(&self) -> Vec<u32> {
+            self.inner.tx_ids()
+        }
+    }
+
+    /// A client store that takes a while to answer, its accounts shared with the test.
+    #[derive(Clone)]
+    struct SlowClientStore {
+        accounts: Arc<Mutex<HashMap<ClientId, Client>>>,
+        delay: Duration,
+    }
+
+    impl ClientStore for SlowClientStore {
+        fn get(&mut self, id: ClientId) -> impl Future<Output = Result<Option<Client>, PaymentError>> + Send {
+            let found = self.accounts.lock().unwrap().get(&id).copied();
+            let delay = self.delay;
+            async move {
+                tokio::time::sleep(delay).await;
+                Ok(found)
+            }
+        }
+
+        fn insert(&mut self, id: ClientId, client: Client) -> impl Future<Output = Result<(), PaymentError>> + Send {
+            self.accounts.lock().unwrap().insert(id, client);
+            let delay = self.delay;
+            async move {
+                tokio::time::sleep(delay).await;
+                Ok(())
+            }
+        }
+
+        fn remove(&mut self, id: ClientId) -> impl Future<Output = Result<(), PaymentError>> + Send {
+            self.accounts.lock().unwrap().remove(&id);
+            std::future::ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn engine_awaits_the_store_instead_of_blocking() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 1.0
+        deposit, 1, 2, 2.0
+        dispute, 1, 1";
+        let str_buf = stringreader::StringReader::new(csv);
+        let transactions = parse_transactions(Box::new(str_buf)).await?;
+        let mut engine = PaymentEngine::with_store(SlowStore {
+            inner: InMemoryTransactionStore::new(),
+            delay: Duration::from_millis(20),
+        });
+
+        // on this single threaded runtime the ticker only runs while the engine yields
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let ticker = {
+            let ticks = Arc::clone(&ticks);
+            tokio::spawn(async move {
+                loop {
+                    ticks.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+            })
+        };
+
+        engine.process_all(transactions).await.into_result()?;
+        ticker.abort();
+
+        assert!(ticks.load(Ordering::SeqCst) > 0);
+        let client = engine.clients.get(&1).expect("client 1 should exist");
+        assert_eq!(client.held, 1.0);
+        assert_eq!(client.available, 2.0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn engine_loads_and_writes_back_accounts_through_the_client_store() -> Result<(), PaymentError> {
+        let clients = SlowClientStore {
+            accounts: Arc::new(Mutex::new(HashMap::new())),
+            delay: Duration::from_millis(5),
+        };
+        let mut engine = PaymentEngine::with_stores(InMemoryTransactionStore::new(), clients.clone());
+        engine.process_transaction(Transaction::deposit(1, 1, 10.0)).await?;
+        engine.process_transaction(Transaction::withdrawal(1, 2, 3.0)).await?;
+        // a rejected row changes nothing, so nothing is written
+        let overdraft = engine.process_transaction(Transaction::withdrawal(1, 3, 50.0)).await?;
+        assert!(matches!(overdraft, ProcessOutcome::Ignored(_)));
+        assert_eq!(clients.accounts.lock().unwrap()[&1].available, 7.0);
+
+        // a later run picks the account up where the first left it
+        let mut engine = PaymentEngine::with_stores(InMemoryTransactionStore::new(), clients.clone())
+            .with_undo_history(1);
+        engine.process_transaction(Transaction::deposit(1, 4, 3.0)).await?;
+        assert_eq!(engine.clients[&1].available, 10.0);
+        assert_eq!(clients.accounts.lock().unwrap()[&1].available, 10.0);
+        assert!(engine.check_ledger().balances());
+
+        // undoing the deposit that opened an account removes it from the store
+        engine.process_transaction(Transaction::deposit(2, 5, 1.0)).await?;
+        assert!(clients.accounts.lock().unwrap().contains_key(&2));
+        engine.undo_last().await?;
+        assert!(!clients.accounts.lock().unwrap().contains_key(&2));
+        Ok(())
+    }
+}
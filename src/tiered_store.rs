@@ -0,0 +1,500 @@
+use crate::{
+    errors::PaymentError,
+    store::TransactionStore,
+    types::{SourceRef, Transaction, TransactionType},
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{File, OpenOptions},
+    future::Future,
+    io::{Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+static SPILL_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Spilled records are kept in memory until this many bytes are waiting, then appended to the
+/// spill file at once.
+const SPILL_BUFFER: usize = 8 * 1024;
+
+/// Eviction statistics of a `TieredTransactionStore`, used to tune the in-memory capacity.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct TieredStoreStats {
+    /// Number of records moved out of memory because the capacity was reached.
+    pub evictions: u64,
+    /// Number of records appended to the spill file (records already on disk are not rewritten).
+    pub spilled_writes: u64,
+    /// Number of lookups that had to read a spilled record back from disk.
+    pub faults: u64,
+}
+
+struct HotEntry {
+    txn: Transaction,
+    last_used: u64,
+    on_disk: bool,
+}
+
+/// A transaction store with a hard ceiling on the number of records held in memory.
+///
+/// The most recently used `capacity` transactions stay in RAM. Older ones are appended to a
+/// spill file in the temp directory, with an in-memory map from tx id to file offset, and are
+/// transparently faulted back in on lookup. Stored transactions are never mutated, so a record
+/// that was faulted in and evicted again reuses its existing copy on disk.
+///
+/// Spill reads and writes run on tokio's blocking thread pool, so a slow disk doesn't stall
+/// the runtime; lookups of records still in memory don't touch the file and are ready at once.
+/// The store must thus be used from within a tokio runtime. The spill file is removed when the
+/// store is dropped.
+pub struct TieredTransactionStore {
+    capacity: usize,
+    hot: HashMap<u32, HotEntry>,
+    recency: BTreeMap<u64, u32>,
+    tick: u64,
+    spilled: HashMap<u32, (u64, usize)>,
+    spill_path: PathBuf,
+    /// The spill file, opened for reading and appending, shared with the blocking tasks.
+    spill_file: Arc<Mutex<File>>,
+    /// Spilled records not yet appended to the file, starting at offset `written`.
+    unwritten: Vec<u8>,
+    written: u64,
+    stats: TieredStoreStats,
+}
+
+impl TieredTransactionStore {
+    /// Creates a store keeping at most `capacity` transactions in memory (at least one).
+    pub fn new(capacity: usize) -> Result<Self, PaymentError> {
+        let spill_path = std::env::temp_dir().join(format!(
+            "payment-engine-{}-{}.spill",
+            std::process::id(),
+            SPILL_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let spill_file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create_new(true)
+            .open(&spill_path)
+            .map_err(|err| PaymentError::StorageError(err.to_string()))?;
+
+        Ok(TieredTransactionStore {
+            capacity: capacity.max(1),
+            hot: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            spilled: HashMap::new(),
+            spill_path,
+            spill_file: Arc::new(Mutex::new(spill_file)),
+            unwritten: Vec::new(),
+            written: 0,
+            stats: TieredStoreStats::default(),
+        })
+    }
+
+    /// Returns the eviction statistics gathered so far.
+    pub fn stats(&self) -> TieredStoreStats {
+        self.stats
+    }
+
+    /// Returns the number of transactions currently held in memory.
+    pub fn resident_len(&self) -> usize {
+        self.hot.len()
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn make_hot(&mut self, txn: Transaction, on_disk: bool) {
+        let last_used = self.next_tick();
+        let tx = txn.tx;
+        if let Some(previous) = self.hot.insert(
+            tx,
+            HotEntry {
+                txn,
+                last_used,
+                on_disk,
+            },
+        ) {
+            self.recency.remove(&previous.last_used);
+        }
+        self.recency.insert(last_used, tx);
+
+        while self.hot.len() > self.capacity {
+            self.evict_coldest();
+        }
+    }
+
+    /// Moves the least recently used record out of memory, queueing it for the spill file unless
+    /// it is already there.
+    fn evict_coldest(&mut self) {
+        if let Some((_, tx)) = self.recency.pop_first() {
+            if let Some(entry) = self.hot.remove(&tx) {
+                if !entry.on_disk {
+                    let record = encode_record(&entry.txn);
+                    let offset = self.written + self.unwritten.len() as u64;
+                    self.spilled.insert(tx, (offset, record.len()));
+                    self.unwritten.extend_from_slice(record.as_bytes());
+                    self.stats.spilled_writes += 1;
+                }
+                self.stats.evictions += 1;
+            }
+        }
+    }
+
+    /// Appends the queued records to the spill file once enough of them are waiting.
+    async fn write_spilled(&mut self) -> Result<(), PaymentError> {
+        if self.unwritten.len() < SPILL_BUFFER {
+            return Ok(());
+        }
+        let records = std::mem::take(&mut self.unwritten);
+        let written = records.len() as u64;
+        let spill_file = Arc::clone(&self.spill_file);
+        blocking(move || lock(&spill_file)?.write_all(&records)).await?;
+        self.written += written;
+        Ok(())
+    }
+
+    async fn read_spilled(&mut self, offset: u64, len: usize) -> Result<Transaction, PaymentError> {
+        let record = match offset.checked_sub(self.written) {
+            Some(queued) => {
+                let start = queued as usize;
+                self.unwritten.get(start..start + len).map(<[u8]>::to_vec).unwrap_or_default()
+            }
+            None => {
+                let spill_file = Arc::clone(&self.spill_file);
+                blocking(move || {
+                    let mut file = lock(&spill_file)?;
+                    let mut record = vec![0; len];
+                    file.seek(SeekFrom::Start(offset))?;
+                    file.read_exact(&mut record)?;
+                    Ok(record)
+                })
+                .await?
+            }
+        };
+        let line = String::from_utf8(record)
+            .map_err(|err| PaymentError::StorageError(err.to_string()))?;
+        decode_record(&line)
+    }
+}
+
+/// Runs spill file I/O on the blocking thread pool.
+async fn blocking<T, F>(io: F) -> Result<T, PaymentError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> std::io::Result<T> + Send + 'static,
+{
+    match tokio::task::spawn_blocking(io).await {
+        Ok(result) => result.map_err(|err| PaymentError::StorageError(err.to_string())),
+        Err(err) => Err(PaymentError::StorageError(err.to_string())),
+    }
+}
+
+fn lock(spill_file: &Mutex<File>) -> std::io::Result<std::sync::MutexGuard<'_, File>> {
+    spill_file
+        .lock()
+        .map_err(|_| std::io::Error::other("spill file poisoned by a panicked write"))
+}
+
+impl TransactionStore for TieredTransactionStore {
+    fn insert(
+        &mut self,
+        txn: Transaction,
+    ) -> impl Future<Output = Result<(), PaymentError>> + Send {
+        // a replaced record's old copy on disk is stale, forget about it
+        self.spilled.remove(&txn.tx);
+        self.make_hot(txn, false);
+        self.write_spilled()
+    }
+
+    fn get(
+        &mut self,
+        tx: u32,
+    ) -> impl Future<Output = Result<Option<Transaction>, PaymentError>> + Send {
+        self.lookup(tx)
+    }
+
+    fn remove(&mut self, tx: u32) -> impl Future<Output = Result<(), PaymentError>> + Send {
+        if let Some(entry) = self.hot.remove(&tx) {
+            self.recency.remove(&entry.last_used);
+        }
+        // the record's bytes stay in the spill file, unreferenced
+        self.spilled.remove(&tx);
+        std::future::ready(Ok(()))
+    }
+
+    fn len(&self) -> usize {
+        self.spilled.len() + self.hot.values().filter(|entry| !entry.on_disk).count()
+    }
+
+    fn tx_ids(&self) -> Vec<u32> {
+        // faulted in records are also in `spilled`, only count them once
+        let hot = self.hot.iter().filter(|(_, entry)| !entry.on_disk).map(|(tx, _)| *tx);
+        self.spilled.keys().copied().chain(hot).collect()
+    }
+}
+
+impl TieredTransactionStore {
+    async fn lookup(&mut self, tx: u32) -> Result<Option<Transaction>, PaymentError> {
+        let last_used = self.next_tick();
+        if let Some(entry) = self.hot.get_mut(&tx) {
+            self.recency.remove(&entry.last_used);
+            entry.last_used = last_used;
+            self.recency.insert(last_used, tx);
+            return Ok(Some(entry.txn.clone()));
+        }
+
+        if let Some(&(offset, len)) = self.spilled.get(&tx) {
+            let txn = self.read_spilled(offset, len).await?;
+            self.stats.faults += 1;
+            self.make_hot(txn.clone(), true);
+            self.write_spilled().await?;
+            return Ok(Some(txn));
+        }
+
+        Ok(None)
+    }
+}
+
+impl Drop for TieredTransactionStore {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.spill_path);
+    }
+}
+
+/// Only stored deposits and withdrawals are spilled, so the dispute reason isn't kept, and
+/// neither is the memo, which nothing reads back. The source's file, the merchant and the
+/// idempotency key may contain commas, so they are written with their length in bytes first, as
+/// `len:text`.
+fn encode_record(txn: &Transaction) -> String {
+    let amount = txn.amount.map(|amount| amount.to_string()).unwrap_or_default();
+    let timestamp = txn.timestamp.map(|timestamp| timestamp.to_string()).unwrap_or_default();
+    let line = txn.source.as_ref().map(|source| source.line.to_string()).unwrap_or_default();
+    let file = txn.source.as_ref().and_then(|source| source.file.as_deref());
+    format!(
+        "{},{},{},{},{},{},{},{},{}\n",
+        txn.r#type.as_str(),
+        txn.client,
+        txn.tx,
+        amount,
+        timestamp,
+        line,
+        encode_text(file),
+        encode_text(txn.merchant.as_deref()),
+        encode_text(txn.idempotency_key.as_deref())
+    )
+}
+
+/// Writes an optional free-form field of a spill record, empty when there is none.
+fn encode_text(text: Option<&str>) -> String {
+    text.map(|text| format!("{}:{}", text.len(), text)).unwrap_or_default()
+}
+
+/// Reads a free-form field written by `encode_text` at the start of `rest`, returning it with
+/// what follows it.
+fn decode_text(rest: &str) -> Option<(Option<String>, &str)> {
+    if rest.is_empty() || rest.starts_with(',') {
+        return Some((None, rest));
+    }
+    let (len, text) = rest.split_once(':')?;
+    let len: usize = len.parse().ok()?;
+    let value = text.get(..len)?;
+    Some((Some(value.to_owned()), &text[len..]))
+}
+
+fn decode_record(line: &str) -> Result<Transaction, PaymentError> {
+    let corrupt = || PaymentError::StorageError(format!("corrupt spill record: {}", line.trim_end()));
+    let mut fields = line.strip_suffix('\n').unwrap_or(line).splitn(7, ',');
+    let r#type = match fields.next() {
+        Some("deposit") => TransactionType::Deposit,
+        Some("withdrawal") => TransactionType::Withdrawal,
+        Some("dispute") => TransactionType::Dispute,
+        Some("resolve") => TransactionType::Resolve,
+        Some("chargeback") => TransactionType::Chargeback,
+        Some("hold") => TransactionType::Hold,
+        Some("release") => TransactionType::Release,
+        Some("capture") => TransactionType::Capture,
+        Some("representment") => TransactionType::Representment,
+        Some("withdrawal_pending") => TransactionType::WithdrawalPending,
+        Some("withdrawal_settle") => TransactionType::WithdrawalSettle,
+        Some("withdrawal_cancel") => TransactionType::WithdrawalCancel,
+        Some("accrue") => TransactionType::Accrue,
+        _ => return Err(corrupt()),
+    };
+    let client = fields.next().and_then(|f| f.parse().ok()).ok_or_else(corrupt)?;
+    let tx = fields.next().and_then(|f| f.parse().ok()).ok_or_else(corrupt)?;
+    let amount = match fields.next() {
+        Some("") | None => None,
+        Some(f) => Some(f.parse().map_err(|_| corrupt())?),
+    };
+    let timestamp = match fields.next() {
+        Some("") | None => None,
+        Some(f) => Some(f.parse().map_err(|_| corrupt())?),
+    };
+    let source_line: Option<u64> = match fields.next() {
+        Some("") | None => None,
+        Some(f) => Some(f.parse().map_err(|_| corrupt())?),
+    };
+    let (file, rest) = decode_text(fields.next().unwrap_or_default()).ok_or_else(corrupt)?;
+    let (merchant, rest) = match rest.strip_prefix(',') {
+        Some(rest) => decode_text(rest).ok_or_else(corrupt)?,
+        None => (None, rest),
+    };
+    let (idempotency_key, rest) = match rest.strip_prefix(',') {
+        Some(rest) => decode_text(rest).ok_or_else(corrupt)?,
+        None => (None, rest),
+    };
+    if !rest.is_empty() {
+        return Err(corrupt());
+    }
+    Ok(Transaction {
+        r#type,
+        client,
+        tx,
+        amount,
+        reason: None,
+        merchant: merchant.map(Arc::from),
+        timestamp,
+        memo: None,
+        idempotency_key,
+        source: source_line.map(|line| SourceRef {
+            file: file.map(Arc::from),
+            line,
+        }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        errors::PaymentError,
+        parser::parse_transactions,
+        payment_engine::PaymentEngine,
+        store::TransactionStore,
+        tiered_store::{decode_record, encode_record, TieredTransactionStore},
+        types::{SourceRef, Transaction},
+    };
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn can_spill_and_fault_in_transactions() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 1.5
+        deposit, 1, 2, 2.0
+        deposit, 1, 3, 3.0";
+        let str_buf = stringreader::StringReader::new(csv);
+        let mut store = TieredTransactionStore::new(1)?;
+
+        for txn in parse_transactions(Box::new(str_buf)).await? {
+            store.insert(txn?).await?;
+        }
+
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.resident_len(), 1);
+        assert_eq!(store.stats().evictions, 2);
+
+        let spilled = store.get(1).await?.expect("spilled transaction should be found");
+        assert_eq!(spilled.amount, Some(1.5));
+        assert_eq!(store.stats().faults, 1);
+        assert!(store.get(42).await?.is_none());
+
+        // faulting in tx 1 evicted tx 3, evicting records already on disk must not rewrite them
+        assert_eq!(store.stats().spilled_writes, 3);
+        store.get(2).await?;
+        store.get(1).await?;
+        assert_eq!(store.stats().spilled_writes, 3);
+        assert_eq!(store.len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn faults_in_records_written_to_the_spill_file() -> Result<(), PaymentError> {
+        let mut store = TieredTransactionStore::new(10)?;
+        for tx in 1..=1_000 {
+            store.insert(Transaction::deposit(1, tx, f64::from(tx))).await?;
+        }
+        assert!(store.written > 0, "enough records to fill the spill buffer");
+        for tx in (1..=1_000).rev() {
+            let txn = store.get(tx).await?.expect("every record is found");
+            assert_eq!(txn.amount, Some(f64::from(tx)));
+        }
+        assert_eq!(store.len(), 1_000);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_resolve_dispute_against_spilled_deposit() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 1.0
+        deposit, 2, 2, 2.0
+        deposit, 1, 3, 2.0
+        dispute, 1, 1
+        resolve, 1, 1
+        dispute, 2, 2";
+        let str_buf = stringreader::StringReader::new(csv);
+        let transactions = parse_transactions(Box::new(str_buf)).await?;
+        let mut engine = PaymentEngine::with_store(TieredTransactionStore::new(1)?);
+
+        engine.process_all(transactions).await.into_result()?;
+
+        let client = engine.clients.get(&1).expect("client 1 should exist");
+        assert_eq!(client.available, 3.0);
+        assert_eq!(client.held, 0.0);
+
+        let client = engine.clients.get(&2).expect("client 2 should exist");
+        assert_eq!(client.available, 0.0);
+        assert_eq!(client.held, 2.0);
+
+        assert!(engine.transactions.stats().faults >= 2);
+        Ok(())
+    }
+
+    #[test]
+    fn spill_records_keep_the_merchant_and_timestamp() -> Result<(), PaymentError> {
+        let mut txn = Transaction::deposit(1, 7, 2.5)
+            .with_merchant("Acme, Inc ")
+            .with_timestamp(1700000000);
+        let decoded = decode_record(&encode_record(&txn))?;
+        assert_eq!((decoded.tx, decoded.amount), (7, Some(2.5)));
+        assert_eq!(decoded.timestamp, Some(1700000000));
+        assert_eq!(decoded.merchant.as_deref(), Some("Acme, Inc "));
+
+        txn.merchant = None;
+        assert_eq!(decode_record(&encode_record(&txn))?.merchant, None);
+        Ok(())
+    }
+
+    #[test]
+    fn spill_records_keep_the_idempotency_key() -> Result<(), PaymentError> {
+        let txn = Transaction::deposit(1, 7, 2.5).with_idempotency_key("batch 3, row 1");
+        let decoded = decode_record(&encode_record(&txn))?;
+        assert_eq!(decoded.idempotency_key.as_deref(), Some("batch 3, row 1"));
+
+        let txn = Transaction::deposit(1, 7, 2.5).with_merchant("Acme").with_idempotency_key("k");
+        let decoded = decode_record(&encode_record(&txn))?;
+        assert_eq!((decoded.merchant.as_deref(), decoded.idempotency_key.as_deref()), (Some("Acme"), Some("k")));
+        assert_eq!(decode_record(&encode_record(&Transaction::deposit(1, 7, 2.5)))?.idempotency_key, None);
+        Ok(())
+    }
+
+    #[test]
+    fn spill_records_keep_the_source() -> Result<(), PaymentError> {
+        let mut txn = Transaction::deposit(1, 7, 2.5).with_merchant("Acme, Inc");
+        txn.source = Some(SourceRef {
+            file: Some(Arc::from("day 1, east.csv")),
+            line: 12,
+        });
+        let decoded = decode_record(&encode_record(&txn))?;
+        assert_eq!(decoded.source, txn.source);
+        assert_eq!(decoded.merchant.as_deref(), Some("Acme, Inc"));
+
+        txn.source = Some(SourceRef::line(3));
+        assert_eq!(decode_record(&encode_record(&txn))?.source, Some(SourceRef::line(3)));
+        assert!(decode_record("deposit,1,7,2.5,,3,9:short,\n").is_err());
+        Ok(())
+    }
+}
@@ -1,7 +1,60 @@
-use serde::Deserialize;
+use crate::{amount::Amount, errors::PaymentError};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, sync::Arc};
+
+/// Client id of the transactions and accounts, 16 bits wide unless the crate is built with the
+/// `wide-client-ids` feature.
+#[cfg(not(feature = "wide-client-ids"))]
+pub type ClientId = u16;
+
+/// Client id of the transactions and accounts, 32 bits wide since the crate is built with the
+/// `wide-client-ids` feature.
+#[cfg(feature = "wide-client-ids")]
+pub type ClientId = u32;
+
+/// Deserializes a client id, rejecting ids that don't fit in `ClientId` with an error naming the
+/// largest one supported.
+pub(crate) fn deserialize_client_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<ClientId, D::Error> {
+    let id = u64::deserialize(deserializer)?;
+    ClientId::try_from(id).map_err(|_| {
+        D::Error::custom(format!(
+            "client id {} is out of range, the largest supported is {}",
+            id,
+            ClientId::MAX
+        ))
+    })
+}
+
+/// A locked flag as found in state files, canonical or in the spelling of a legacy system.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LockedFlag {
+    Bool(bool),
+    Number(u64),
+    Text(String),
+}
+
+/// Deserializes a locked flag, accepting `true`/`false`, `1`/`0`, `yes`/`no` and `y`/`n` in any
+/// case, as legacy close files spell it, and rejecting anything else. The flag is always written
+/// back as `true` or `false`.
+pub(crate) fn deserialize_locked<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    let text = match LockedFlag::deserialize(deserializer)? {
+        LockedFlag::Bool(locked) => return Ok(locked),
+        LockedFlag::Number(number) => number.to_string(),
+        LockedFlag::Text(text) => text,
+    };
+    match text.trim().to_ascii_lowercase().as_str() {
+        "true" | "1" | "yes" | "y" => Ok(true),
+        "false" | "0" | "no" | "n" => Ok(false),
+        _ => Err(D::Error::custom(format!(
+            "invalid locked flag `{}`, expected true/false, 1/0, yes/no or y/n",
+            text
+        ))),
+    }
+}
 
 /// Represents the different types of transactions in the payment engine.
-#[derive(Deserialize, Debug, PartialEq)]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionType {
     Deposit,
@@ -9,32 +62,930 @@ pub enum TransactionType {
     Dispute,
     Resolve,
     Chargeback,
+    /// Escrow hold: moves an amount from available to held, without referring to a prior
+    /// transaction.
+    Hold,
+    /// Moves the funds of the hold with the same tx id back to available.
+    Release,
+    /// Turns the hold with the same tx id into a withdrawal of the held funds.
+    Capture,
+    /// Reverses the chargeback of the transaction with the same tx id, after the merchant won
+    /// the representment.
+    Representment,
+    /// Withdrawal awaiting settlement: moves an amount from available to held, as pending out,
+    /// without referring to a prior transaction.
+    #[serde(rename = "withdrawal_pending")]
+    WithdrawalPending,
+    /// Turns the pending withdrawal with the same tx id into a withdrawal of its funds.
+    #[serde(rename = "withdrawal_settle")]
+    WithdrawalSettle,
+    /// Moves the funds of the pending withdrawal with the same tx id back to available.
+    #[serde(rename = "withdrawal_cancel")]
+    WithdrawalCancel,
+    /// Pays interest at the rate given as its amount to every account, like
+    /// `PaymentEngine::apply_accrual`. Its client is `0`, as it is for all of them.
+    Accrue,
+}
+
+impl TransactionType {
+    /// Returns the name used for this type in the CSV input.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Hold => "hold",
+            TransactionType::Release => "release",
+            TransactionType::Capture => "capture",
+            TransactionType::Representment => "representment",
+            TransactionType::WithdrawalPending => "withdrawal_pending",
+            TransactionType::WithdrawalSettle => "withdrawal_settle",
+            TransactionType::WithdrawalCancel => "withdrawal_cancel",
+            TransactionType::Accrue => "accrue",
+        }
+    }
+
+    /// Returns the type named `name` in the CSV input, the inverse of `as_str`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "deposit" => TransactionType::Deposit,
+            "withdrawal" => TransactionType::Withdrawal,
+            "dispute" => TransactionType::Dispute,
+            "resolve" => TransactionType::Resolve,
+            "chargeback" => TransactionType::Chargeback,
+            "hold" => TransactionType::Hold,
+            "release" => TransactionType::Release,
+            "capture" => TransactionType::Capture,
+            "representment" => TransactionType::Representment,
+            "withdrawal_pending" => TransactionType::WithdrawalPending,
+            "withdrawal_settle" => TransactionType::WithdrawalSettle,
+            "withdrawal_cancel" => TransactionType::WithdrawalCancel,
+            "accrue" => TransactionType::Accrue,
+            _ => return None,
+        })
+    }
 }
 
 /// Represents a transaction in the payment engine.
-#[derive(Deserialize, Debug)]
+///
+/// Serialized with the `type,client,tx,amount,reason,merchant,timestamp,memo,idempotency_key`
+/// columns, so transactions built in code can be written out as input CSV.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct Transaction {
     pub r#type: TransactionType,
-    pub client: u16,
+    #[serde(deserialize_with = "deserialize_client_id")]
+    pub client: ClientId,
     pub tx: u32,
     pub amount: Option<f64>,
+    /// Reason code of a dispute or chargeback (`fraud`, `duplicate`...), from the optional
+    /// `reason` column.
+    #[serde(default)]
+    pub reason: Option<String>,
+    /// Merchant a deposit was made through, from the optional `merchant` column. Stored
+    /// deposits share the name interned by the engine's merchant table.
+    #[serde(default)]
+    pub merchant: Option<Arc<str>>,
+    /// When the transaction happened, in seconds since the Unix epoch, from the optional
+    /// `timestamp` column.
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    /// Free-text note from the optional `memo` column, carried along for reconciliation but
+    /// ignored for accounting. The engine caps its length before storing the transaction.
+    #[serde(default)]
+    pub memo: Option<String>,
+    /// Key the upstream feed gives a logical transaction, kept when a retry resends it under a
+    /// new tx id, from the optional `idempotency_key` column. The engine rejects a transaction
+    /// whose key it has seen applied. Transactions without one aren't deduplicated.
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    /// Where the transaction was read from, set by the engine from the source the caller gives
+    /// so the stored transaction keeps it. Not a column of the input.
+    #[serde(skip)]
+    pub source: Option<SourceRef>,
+}
+
+impl Transaction {
+    /// Creates a deposit of `amount` to `client`'s account.
+    pub fn deposit(client: ClientId, tx: u32, amount: f64) -> Self {
+        Transaction::new(TransactionType::Deposit, client, tx, Some(amount))
+    }
+
+    /// Creates a withdrawal of `amount` from `client`'s account.
+    pub fn withdrawal(client: ClientId, tx: u32, amount: f64) -> Self {
+        Transaction::new(TransactionType::Withdrawal, client, tx, Some(amount))
+    }
+
+    /// Creates a dispute of `client`'s transaction `tx`.
+    pub fn dispute(client: ClientId, tx: u32) -> Self {
+        Transaction::new(TransactionType::Dispute, client, tx, None)
+    }
+
+    /// Creates a resolve of the dispute of `client`'s transaction `tx`.
+    pub fn resolve(client: ClientId, tx: u32) -> Self {
+        Transaction::new(TransactionType::Resolve, client, tx, None)
+    }
+
+    /// Creates a chargeback of the disputed transaction `tx` of `client`.
+    pub fn chargeback(client: ClientId, tx: u32) -> Self {
+        Transaction::new(TransactionType::Chargeback, client, tx, None)
+    }
+
+    /// Creates a representment of the charged back transaction `tx` of `client`.
+    pub fn representment(client: ClientId, tx: u32) -> Self {
+        Transaction::new(TransactionType::Representment, client, tx, None)
+    }
+
+    /// Creates an escrow hold of `amount` on `client`'s account, identified by `tx`.
+    pub fn hold(client: ClientId, tx: u32, amount: f64) -> Self {
+        Transaction::new(TransactionType::Hold, client, tx, Some(amount))
+    }
+
+    /// Creates a release of the escrow hold `tx` of `client`.
+    pub fn release(client: ClientId, tx: u32) -> Self {
+        Transaction::new(TransactionType::Release, client, tx, None)
+    }
+
+    /// Creates a capture of the escrow hold `tx` of `client`.
+    pub fn capture(client: ClientId, tx: u32) -> Self {
+        Transaction::new(TransactionType::Capture, client, tx, None)
+    }
+
+    /// Creates a pending withdrawal of `amount` from `client`'s account, identified by `tx`.
+    pub fn withdrawal_pending(client: ClientId, tx: u32, amount: f64) -> Self {
+        Transaction::new(TransactionType::WithdrawalPending, client, tx, Some(amount))
+    }
+
+    /// Creates a settlement of the pending withdrawal `tx` of `client`.
+    pub fn withdrawal_settle(client: ClientId, tx: u32) -> Self {
+        Transaction::new(TransactionType::WithdrawalSettle, client, tx, None)
+    }
+
+    /// Creates a cancellation of the pending withdrawal `tx` of `client`.
+    pub fn withdrawal_cancel(client: ClientId, tx: u32) -> Self {
+        Transaction::new(TransactionType::WithdrawalCancel, client, tx, None)
+    }
+
+    /// Creates an accrual of interest at `rate` for every client, identified by `tx`.
+    pub fn accrue(tx: u32, rate: f64) -> Self {
+        Transaction::new(TransactionType::Accrue, 0, tx, Some(rate))
+    }
+
+    /// Sets the reason code of a dispute or chargeback.
+    pub fn with_reason(mut self, reason: impl Into<String>) -> Self {
+        self.reason = Some(reason.into());
+        self
+    }
+
+    /// Sets the merchant a deposit was made through.
+    pub fn with_merchant(mut self, merchant: impl Into<Arc<str>>) -> Self {
+        self.merchant = Some(merchant.into());
+        self
+    }
+
+    /// Sets when the transaction happened, in seconds since the Unix epoch.
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Sets the free-text memo of the transaction.
+    pub fn with_memo(mut self, memo: impl Into<String>) -> Self {
+        self.memo = Some(memo.into());
+        self
+    }
+
+    /// Sets the idempotency key of the transaction.
+    pub fn with_idempotency_key(mut self, key: impl Into<String>) -> Self {
+        self.idempotency_key = Some(key.into());
+        self
+    }
+
+    pub(crate) fn new(r#type: TransactionType, client: ClientId, tx: u32, amount: Option<f64>) -> Self {
+        Transaction {
+            r#type,
+            client,
+            tx,
+            amount,
+            reason: None,
+            merchant: None,
+            timestamp: None,
+            memo: None,
+            idempotency_key: None,
+            source: None,
+        }
+    }
 }
 
 /// Represents a client's account within the payment engine.
+///
+/// Serialized with its amounts as strings with four decimal places, like the reports.
+/// Fields missing when deserializing take their default value.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Client {
-    pub available: f64,
-    pub held: f64,
-    pub total: f64,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    #[serde(deserialize_with = "deserialize_locked")]
     pub locked: bool,
+    /// Number of disputes currently open on the client's transactions.
+    pub open_disputes: u32,
+    /// Number of disputes ever opened, whatever their outcome.
+    pub disputes: u32,
+    /// Number of disputes that ended in a chargeback.
+    pub chargebacks: u32,
+    /// Amount held by the currently open disputes.
+    pub open_dispute_held: Amount,
+    /// The chargeback that locked the account, if it is locked.
+    pub locked_by: Option<LockCause>,
+    /// Amount deposited while the account was locked, held until it is unlocked. Always zero
+    /// unless the engine uses `LockedDepositPolicy::Hold`.
+    pub held_while_locked: Amount,
+    /// Part of `held` set aside for pending withdrawals, until they are settled or cancelled.
+    pub pending_out: Amount,
+    /// Chargeback fees debited from the account.
+    pub chargeback_fees: Amount,
+    /// What the client owes, i.e. how far `total` is below zero. Funds on hold count, so an
+    /// account whose available balance is negative only because of a hold owes nothing.
+    pub debt: Amount,
+    /// Deposits that went to paying down the debt.
+    pub debt_repaid: Amount,
+    /// The client's last applied deposit with a timestamp, kept when duplicate detection is on.
+    /// Not serialized.
+    #[serde(skip)]
+    pub last_deposit: Option<LastDeposit>,
+    /// Set while disputes under `DisputeShortfallPolicy::Freeze` have a shortfall to collect:
+    /// withdrawals are refused and deposits go to the disputes' holds first.
+    pub frozen: bool,
+    /// Disputed amounts the open disputes couldn't hold for lack of available funds.
+    pub dispute_shortfall: Amount,
+    /// Shortfalls written off by chargebacks under `DisputeShortfallPolicy::HoldPartial`.
+    pub shortfall_written_off: Amount,
+    /// Why the account is locked, frozen or blocklisted, for support tooling: set by the
+    /// chargeback that locked it, the dispute that froze it or the blocklist, cleared once it is
+    /// unlocked or unfrozen. Left out of the JSON dump when unset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status_reason: Option<StatusReason>,
+}
+
+/// Whether an account takes transactions.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountStatus {
+    #[default]
+    Active,
+    /// Refuses withdrawals while disputes have a shortfall to collect (see `Client::frozen`).
+    Frozen,
+    /// Locked by a chargeback.
+    Locked,
+}
+
+impl AccountStatus {
+    /// Returns the snake_case name of this status, as in the JSON dump.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccountStatus::Active => "active",
+            AccountStatus::Frozen => "frozen",
+            AccountStatus::Locked => "locked",
+        }
+    }
+}
+
+/// Where a stored deposit or withdrawal is in its dispute lifecycle, see
+/// `PaymentEngine::transaction_state`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionState {
+    /// Never disputed.
+    Settled,
+    /// Under an open dispute.
+    Disputed,
+    /// Disputed, then resolved, or charged back and won back by a representment.
+    Resolved,
+    /// Charged back.
+    ChargedBack,
+}
+
+impl TransactionState {
+    /// Returns the snake_case name used for this state in reports.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionState::Settled => "settled",
+            TransactionState::Disputed => "disputed",
+            TransactionState::Resolved => "resolved",
+            TransactionState::ChargedBack => "charged_back",
+        }
+    }
+}
+
+/// A copy of a client's balances and status, which callers can keep while the engine goes on
+/// processing, unlike a `&Client` borrowed from the engine.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClientView {
+    pub client: ClientId,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    pub status: AccountStatus,
+}
+
+impl ClientView {
+    /// Returns the view of a client without an account, whose balances are all zero.
+    pub fn empty(client: ClientId) -> Self {
+        Client::new().view(client)
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.status == AccountStatus::Locked
+    }
+}
+
+/// A copy of a dispute still open, i.e. neither resolved nor charged back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisputeView {
+    /// Id of the disputed transaction.
+    pub tx: u32,
+    pub client: ClientId,
+    /// Amount disputed, less than the transaction's own for a partial dispute.
+    pub amount: f64,
+    /// Part of `amount` actually held, short of it when the client's available funds didn't
+    /// cover the dispute under `DisputeShortfallPolicy::HoldPartial` or `Freeze`.
+    pub held: f64,
+    /// When the dispute was opened, from the dispute row's `timestamp` column.
+    pub timestamp: Option<u64>,
+    /// Reason code given by the dispute row.
+    pub reason: Option<String>,
+    /// Where the disputed transaction was read from, when the caller provided it.
+    pub source: Option<SourceRef>,
+}
+
+/// Where a transaction was read from: the line of its input row and, in a multi-file run, the
+/// file of the row.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceRef {
+    /// Path of the input file, `None` for a single input.
+    pub file: Option<Arc<str>>,
+    /// Line number of the row in its input (the header is line 1).
+    pub line: u64,
+}
+
+impl SourceRef {
+    /// Returns the source of the row at `line` of the only input.
+    pub fn line(line: u64) -> Self {
+        SourceRef { file: None, line }
+    }
+
+    /// Returns where the row is as a report column: `path:line`, or only the line for a single
+    /// input.
+    pub fn location(&self) -> String {
+        match &self.file {
+            Some(file) => format!("{}:{}", file, self.line),
+            None => self.line.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for SourceRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}", file, self.line),
+            None => write!(f, "line {}", self.line),
+        }
+    }
+}
+
+/// The chargeback that locked an account.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LockCause {
+    /// Id of the charged back transaction.
+    pub tx: u32,
+    /// Amount charged back.
+    pub amount: Amount,
+    /// Line of the chargeback row in the input, when the caller provided it.
+    pub line: Option<u64>,
+}
+
+/// Longest `StatusReason`, in bytes.
+pub const MAX_STATUS_REASON_LEN: usize = 64;
+
+/// Why an account is locked, frozen or blocklisted, e.g. `chargeback of tx 12: fraud`. Kept
+/// inline, so `Client` stays `Copy`: longer reasons are cut to `MAX_STATUS_REASON_LEN` bytes, at
+/// a character boundary.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct StatusReason {
+    len: u8,
+    bytes: [u8; MAX_STATUS_REASON_LEN],
+}
+
+impl StatusReason {
+    pub fn new(reason: &str) -> Self {
+        let mut len = reason.len().min(MAX_STATUS_REASON_LEN);
+        while !reason.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut bytes = [0; MAX_STATUS_REASON_LEN];
+        bytes[..len].copy_from_slice(&reason.as_bytes()[..len]);
+        StatusReason { len: len as u8, bytes }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // only ever cut at a character boundary
+        std::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or_default()
+    }
+}
+
+impl fmt::Debug for StatusReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for StatusReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for StatusReason {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for StatusReason {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(StatusReason::new(&String::deserialize(deserializer)?))
+    }
 }
 
 impl Client {
     pub fn new() -> Self {
         Client {
-            available: 0.0,
-            held: 0.0,
-            total: 0.0,
+            available: Amount::ZERO,
+            held: Amount::ZERO,
+            total: Amount::ZERO,
             locked: false,
+            open_disputes: 0,
+            disputes: 0,
+            chargebacks: 0,
+            open_dispute_held: Amount::ZERO,
+            locked_by: None,
+            held_while_locked: Amount::ZERO,
+            pending_out: Amount::ZERO,
+            chargeback_fees: Amount::ZERO,
+            debt: Amount::ZERO,
+            debt_repaid: Amount::ZERO,
+            last_deposit: None,
+            frozen: false,
+            dispute_shortfall: Amount::ZERO,
+            shortfall_written_off: Amount::ZERO,
+            status_reason: None,
+        }
+    }
+}
+
+impl Client {
+    /// Returns the account's status, `Locked` taking precedence over `Frozen`.
+    pub fn status(&self) -> AccountStatus {
+        if self.locked {
+            AccountStatus::Locked
+        } else if self.frozen {
+            AccountStatus::Frozen
+        } else {
+            AccountStatus::Active
+        }
+    }
+
+    /// Returns a copy of the account's balances and status, as the account of `client`.
+    pub fn view(&self, client: ClientId) -> ClientView {
+        ClientView {
+            client,
+            available: self.available,
+            held: self.held,
+            total: self.total,
+            status: self.status(),
         }
     }
+
+    /// Brings `debt` in line with `total` after a balance change, returning by how much the
+    /// debt went down.
+    pub(crate) fn settle_debt(&mut self) -> Amount {
+        let owed = (-self.total).max(Amount::ZERO);
+        // both are non-negative, so the difference can't overflow
+        let paid_down = self.debt.checked_sub(owed).unwrap_or_default().max(Amount::ZERO);
+        self.debt = owed;
+        paid_down
+    }
+
+    /// Adds another account of the same client to this one, for merged engine states: balances
+    /// and counters are summed and the account is locked or frozen if either side was.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `PaymentError::AmountOutOfRange` if a summed balance is out of range, in
+    /// which case the account may be partly merged.
+    pub(crate) fn absorb(&mut self, other: Client) -> Result<(), PaymentError> {
+        self.available = self.available.checked_add(other.available)?;
+        self.held = self.held.checked_add(other.held)?;
+        self.total = self.total.checked_add(other.total)?;
+        self.locked |= other.locked;
+        self.open_disputes += other.open_disputes;
+        self.disputes += other.disputes;
+        self.chargebacks += other.chargebacks;
+        self.open_dispute_held = self.open_dispute_held.checked_add(other.open_dispute_held)?;
+        self.locked_by = self.locked_by.or(other.locked_by);
+        self.held_while_locked = self.held_while_locked.checked_add(other.held_while_locked)?;
+        self.pending_out = self.pending_out.checked_add(other.pending_out)?;
+        self.chargeback_fees = self.chargeback_fees.checked_add(other.chargeback_fees)?;
+        self.debt_repaid = self.debt_repaid.checked_add(other.debt_repaid)?;
+        self.settle_debt();
+        self.last_deposit = match (self.last_deposit, other.last_deposit) {
+            (Some(ours), Some(theirs)) if theirs.timestamp > ours.timestamp => Some(theirs),
+            (ours, theirs) => ours.or(theirs),
+        };
+        self.frozen |= other.frozen;
+        self.status_reason = self.status_reason.or(other.status_reason);
+        self.dispute_shortfall = self.dispute_shortfall.checked_add(other.dispute_shortfall)?;
+        self.shortfall_written_off = self.shortfall_written_off.checked_add(other.shortfall_written_off)?;
+        Ok(())
+    }
+
+    /// Unlocks the account, releasing to available the deposits held while it was locked.
+    pub(crate) fn unlock(&mut self) -> Result<(), PaymentError> {
+        let available = self.available.checked_add(self.held_while_locked)?;
+        self.held = self.held.checked_sub(self.held_while_locked)?;
+        self.available = available;
+        self.held_while_locked = Amount::ZERO;
+        let repaid = self.settle_debt();
+        self.debt_repaid = self.debt_repaid.checked_add(repaid)?;
+        self.locked = false;
+        self.locked_by = None;
+        self.status_reason = None;
+        Ok(())
+    }
+
+    /// Takes a settled dispute's shortfall off the outstanding ones, unfreezing the account
+    /// once none is left.
+    pub(crate) fn release_shortfall(&mut self, shortfall: Amount) -> Result<(), PaymentError> {
+        self.dispute_shortfall = self.dispute_shortfall.checked_sub(shortfall)?;
+        if !self.dispute_shortfall.is_positive() {
+            self.dispute_shortfall = Amount::ZERO;
+            if self.frozen && !self.locked {
+                self.status_reason = None;
+            }
+            self.frozen = false;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A timestamped deposit, remembered to spot a second identical deposit shortly after.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LastDeposit {
+    pub tx: u32,
+    pub amount: Amount,
+    pub timestamp: u64,
+}
+
+/// A deposit that repeats the amount of the client's previous deposit within the duplicate
+/// detection window.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SuspectedDuplicate {
+    pub client: ClientId,
+    /// The earlier deposit.
+    pub original_tx: u32,
+    /// The deposit suspected to duplicate it.
+    pub tx: u32,
+    pub amount: Amount,
+    /// Seconds between the two deposits.
+    pub delta_secs: u64,
+}
+
+/// What the engine does with a deposit to a locked account.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LockedDepositPolicy {
+    /// The deposit is ignored as `IgnoreReason::AccountLocked`.
+    #[default]
+    Reject,
+    /// The deposit is accepted but credited to `held` and `total` rather than `available`,
+    /// until `PaymentEngine::unlock` releases it.
+    Hold,
+}
+
+impl LockedDepositPolicy {
+    /// Parses a policy name as given on the command line (`reject` or `hold`).
+    pub fn parse(name: &str) -> Result<Self, PaymentError> {
+        match name {
+            "reject" => Ok(LockedDepositPolicy::Reject),
+            "hold" => Ok(LockedDepositPolicy::Hold),
+            _ => Err(PaymentError::InvalidCliArgument(format!(
+                "unknown locked deposit policy '{}', expected reject or hold",
+                name
+            ))),
+        }
+    }
+}
+
+/// Result of processing a single transaction.
+///
+/// Serialized as `"applied"` or `{"ignored": "<reason>"}`, a stable format for logs and services
+/// embedding the engine.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum ProcessOutcome {
+    /// The transaction changed the engine state.
+    Applied,
+    /// The transaction was skipped and left the engine state untouched.
+    Ignored(IgnoreReason),
+}
+
+/// Why a transaction was skipped by the engine.
+///
+/// Every reason has one name, from `as_str`, used by serde and in every report, and a numeric
+/// code, from `as_code`, for compact storage. Both are stable: reasons may be added, but never
+/// renamed or renumbered.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum IgnoreReason {
+    /// The client's account is locked.
+    AccountLocked,
+    /// A deposit or withdrawal without an amount.
+    MissingAmount,
+    /// The client doesn't have enough available funds for the withdrawal.
+    InsufficientFunds,
+    /// A withdrawal for a client that has no account yet.
+    UnknownClient,
+    /// The referenced transaction is not stored.
+    UnknownTransaction,
+    /// The referenced transaction belongs to another client.
+    ClientMismatch,
+    /// A resolve or chargeback for a transaction that is not under dispute.
+    NotDisputed,
+    /// A dispute for a transaction that is already under dispute.
+    AlreadyDisputed,
+    /// A release or capture for a tx id that has no open escrow hold.
+    NotHeld,
+    /// An escrow hold reusing the tx id of a hold that is still open.
+    AlreadyHeld,
+    /// A deposit or withdrawal for a client on the engine's blocklist.
+    Blocklisted,
+    /// A deposit that would take the client's total over its balance cap.
+    BalanceCapExceeded,
+    /// A deposit repeating the client's previous one, rejected by duplicate detection.
+    SuspectedDuplicate,
+    /// A representment for a transaction that wasn't charged back.
+    NotChargedBack,
+    /// A withdrawal from an account frozen until a dispute's shortfall is collected.
+    AccountFrozen,
+    /// A dispute beyond the cap on open disputes, of the client or of the engine.
+    TooManyOpenDisputes,
+    /// A dispute, resolve or chargeback whose amount disagrees with the transaction or the
+    /// dispute it refers to, under `EngineConfig::dispute_amounts`.
+    AmountMismatch,
+    /// A dispute, resolve, chargeback or representment while `EngineConfig::dispute_support`
+    /// is `DisputeSupport::None`.
+    DisputesDisabled,
+    /// A deposit or withdrawal reusing the tx id of one from an earlier input file. Only
+    /// multi-file runs check for it, the engine itself never returns it.
+    DuplicateTransaction,
+    /// A deposit or withdrawal whose tx id is in the engine's journal, as an earlier run already
+    /// applied it.
+    AlreadyProcessed,
+    /// A resolve or chargeback of a dispute holding more than the client's held balance, which
+    /// would take it below zero.
+    HeldBalanceInconsistent,
+    /// A deposit or withdrawal below `EngineConfig::min_amount`, under `DustPolicy::Reject`.
+    DustAmount,
+    /// A transaction whose processing panicked, under `EngineConfig::max_panics`. What it
+    /// changed was restored.
+    InternalError,
+    /// A withdrawal settle or cancel for a tx id that has no pending withdrawal.
+    NotPending,
+    /// A pending withdrawal reusing the tx id of one that is still pending.
+    AlreadyPending,
+    /// A transaction whose idempotency key is that of one already applied, e.g. an upstream
+    /// retry under a new tx id.
+    DuplicateIdempotencyKey,
+    /// A deposit or withdrawal above `EngineConfig::max_transaction_amount`.
+    AmountAboveLimit,
+}
+
+impl IgnoreReason {
+    /// Returns the snake_case name used for this reason in reports.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IgnoreReason::AccountLocked => "account_locked",
+            IgnoreReason::MissingAmount => "missing_amount",
+            IgnoreReason::InsufficientFunds => "insufficient_funds",
+            IgnoreReason::UnknownClient => "unknown_client",
+            IgnoreReason::UnknownTransaction => "unknown_transaction",
+            IgnoreReason::ClientMismatch => "client_mismatch",
+            IgnoreReason::NotDisputed => "not_disputed",
+            IgnoreReason::AlreadyDisputed => "already_disputed",
+            IgnoreReason::NotHeld => "not_held",
+            IgnoreReason::AlreadyHeld => "already_held",
+            IgnoreReason::Blocklisted => "blocklisted",
+            IgnoreReason::BalanceCapExceeded => "balance_cap_exceeded",
+            IgnoreReason::SuspectedDuplicate => "suspected_duplicate",
+            IgnoreReason::NotChargedBack => "not_charged_back",
+            IgnoreReason::AccountFrozen => "account_frozen",
+            IgnoreReason::TooManyOpenDisputes => "too_many_open_disputes",
+            IgnoreReason::AmountMismatch => "amount_mismatch",
+            IgnoreReason::DisputesDisabled => "disputes_disabled",
+            IgnoreReason::DuplicateTransaction => "duplicate_transaction",
+            IgnoreReason::AlreadyProcessed => "already_processed",
+            IgnoreReason::HeldBalanceInconsistent => "held_balance_inconsistent",
+            IgnoreReason::DustAmount => "dust_amount",
+            IgnoreReason::InternalError => "internal_error",
+            IgnoreReason::NotPending => "not_pending",
+            IgnoreReason::AlreadyPending => "already_pending",
+            IgnoreReason::DuplicateIdempotencyKey => "duplicate_idempotency_key",
+            IgnoreReason::AmountAboveLimit => "amount_above_limit",
+        }
+    }
+
+    /// Returns the numeric code of this reason, for compact storage.
+    pub fn as_code(&self) -> u16 {
+        match self {
+            IgnoreReason::AccountLocked => 1,
+            IgnoreReason::MissingAmount => 2,
+            IgnoreReason::InsufficientFunds => 3,
+            IgnoreReason::UnknownClient => 4,
+            IgnoreReason::UnknownTransaction => 5,
+            IgnoreReason::ClientMismatch => 6,
+            IgnoreReason::NotDisputed => 7,
+            IgnoreReason::AlreadyDisputed => 8,
+            IgnoreReason::NotHeld => 9,
+            IgnoreReason::AlreadyHeld => 10,
+            IgnoreReason::Blocklisted => 11,
+            IgnoreReason::BalanceCapExceeded => 12,
+            IgnoreReason::SuspectedDuplicate => 13,
+            IgnoreReason::NotChargedBack => 14,
+            IgnoreReason::AccountFrozen => 15,
+            IgnoreReason::TooManyOpenDisputes => 16,
+            IgnoreReason::AmountMismatch => 17,
+            IgnoreReason::DisputesDisabled => 18,
+            IgnoreReason::DuplicateTransaction => 19,
+            IgnoreReason::AlreadyProcessed => 20,
+            IgnoreReason::HeldBalanceInconsistent => 21,
+            IgnoreReason::DustAmount => 22,
+            IgnoreReason::InternalError => 23,
+            IgnoreReason::NotPending => 24,
+            IgnoreReason::AlreadyPending => 25,
+            IgnoreReason::DuplicateIdempotencyKey => 26,
+            IgnoreReason::AmountAboveLimit => 27,
+        }
+    }
+
+    /// Returns the reason with the numeric `code` of `as_code`, or `None` for an unknown code,
+    /// e.g. one of a newer release.
+    pub fn from_code(code: u16) -> Option<Self> {
+        Some(match code {
+            1 => IgnoreReason::AccountLocked,
+            2 => IgnoreReason::MissingAmount,
+            3 => IgnoreReason::InsufficientFunds,
+            4 => IgnoreReason::UnknownClient,
+            5 => IgnoreReason::UnknownTransaction,
+            6 => IgnoreReason::ClientMismatch,
+            7 => IgnoreReason::NotDisputed,
+            8 => IgnoreReason::AlreadyDisputed,
+            9 => IgnoreReason::NotHeld,
+            10 => IgnoreReason::AlreadyHeld,
+            11 => IgnoreReason::Blocklisted,
+            12 => IgnoreReason::BalanceCapExceeded,
+            13 => IgnoreReason::SuspectedDuplicate,
+            14 => IgnoreReason::NotChargedBack,
+            15 => IgnoreReason::AccountFrozen,
+            16 => IgnoreReason::TooManyOpenDisputes,
+            17 => IgnoreReason::AmountMismatch,
+            18 => IgnoreReason::DisputesDisabled,
+            19 => IgnoreReason::DuplicateTransaction,
+            20 => IgnoreReason::AlreadyProcessed,
+            21 => IgnoreReason::HeldBalanceInconsistent,
+            22 => IgnoreReason::DustAmount,
+            23 => IgnoreReason::InternalError,
+            24 => IgnoreReason::NotPending,
+            25 => IgnoreReason::AlreadyPending,
+            26 => IgnoreReason::DuplicateIdempotencyKey,
+            27 => IgnoreReason::AmountAboveLimit,
+            _ => return None,
+        })
+    }
+}
+
+/// Tells `PaymentEngine::process_until` whether to keep going after looking at a transaction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Until {
+    /// Process this transaction and keep going.
+    Continue,
+    /// Process this transaction, then stop.
+    StopAfter,
+    /// Stop without processing this transaction.
+    StopBefore,
+}
+
+/// Where an as-of run stops: at the first transaction carrying the `tx` id, in stream order.
+///
+/// Tx ids aren't guaranteed to be ordered, so this is about position in the input, not about
+/// id values: transactions with higher ids seen earlier are processed, lower ones seen later
+/// are not. Disputes, resolves and chargebacks carry the id of the transaction they refer to,
+/// so the first row with the id is normally the deposit or withdrawal itself.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AsOfTx {
+    pub tx: u32,
+    /// Whether the transaction with the id is itself processed before stopping.
+    pub inclusive: bool,
+}
+
+impl AsOfTx {
+    /// Returns the stop decision for the next transaction of the stream.
+    pub fn until(&self, txn: &Transaction) -> Until {
+        match (txn.tx == self.tx, self.inclusive) {
+            (false, _) => Until::Continue,
+            (true, true) => Until::StopAfter,
+            (true, false) => Until::StopBefore,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::{IgnoreReason, ProcessOutcome, StatusReason, MAX_STATUS_REASON_LEN};
+
+    #[test]
+    fn status_reasons_are_cut_at_a_character_boundary() {
+        assert_eq!(StatusReason::new("chargeback of tx 1").as_str(), "chargeback of tx 1");
+        // the 64th byte falls inside the last `é`
+        let long = format!("{}é", "a".repeat(MAX_STATUS_REASON_LEN - 1));
+        assert_eq!(StatusReason::new(&long).as_str(), &long[..MAX_STATUS_REASON_LEN - 1]);
+        let json = serde_json::to_string(&StatusReason::new("blocklisted: fraud")).unwrap();
+        assert_eq!(json, "\"blocklisted: fraud\"");
+        assert_eq!(
+            serde_json::from_str::<StatusReason>(&json).ok(),
+            Some(StatusReason::new("blocklisted: fraud"))
+        );
+    }
+
+    #[test]
+    fn reasons_keep_their_names_and_codes() {
+        use IgnoreReason::*;
+        let pinned = [
+            (AccountLocked, 1, "account_locked"),
+            (MissingAmount, 2, "missing_amount"),
+            (InsufficientFunds, 3, "insufficient_funds"),
+            (UnknownClient, 4, "unknown_client"),
+            (UnknownTransaction, 5, "unknown_transaction"),
+            (ClientMismatch, 6, "client_mismatch"),
+            (NotDisputed, 7, "not_disputed"),
+            (AlreadyDisputed, 8, "already_disputed"),
+            (NotHeld, 9, "not_held"),
+            (AlreadyHeld, 10, "already_held"),
+            (Blocklisted, 11, "blocklisted"),
+            (BalanceCapExceeded, 12, "balance_cap_exceeded"),
+            (SuspectedDuplicate, 13, "suspected_duplicate"),
+            (NotChargedBack, 14, "not_charged_back"),
+            (AccountFrozen, 15, "account_frozen"),
+            (TooManyOpenDisputes, 16, "too_many_open_disputes"),
+            (AmountMismatch, 17, "amount_mismatch"),
+            (DisputesDisabled, 18, "disputes_disabled"),
+            (DuplicateTransaction, 19, "duplicate_transaction"),
+            (AlreadyProcessed, 20, "already_processed"),
+            (HeldBalanceInconsistent, 21, "held_balance_inconsistent"),
+            (DustAmount, 22, "dust_amount"),
+            (InternalError, 23, "internal_error"),
+            (NotPending, 24, "not_pending"),
+            (AlreadyPending, 25, "already_pending"),
+            (DuplicateIdempotencyKey, 26, "duplicate_idempotency_key"),
+            (AmountAboveLimit, 27, "amount_above_limit"),
+        ];
+        for (reason, code, name) in pinned {
+            assert_eq!((reason.as_code(), reason.as_str()), (code, name));
+            assert_eq!(IgnoreReason::from_code(code), Some(reason));
+            let json = format!("\"{}\"", name);
+            assert_eq!(serde_json::to_string(&reason).ok(), Some(json.clone()));
+            assert_eq!(serde_json::from_str::<IgnoreReason>(&json).ok(), Some(reason));
+        }
+        // every code belongs to a pinned reason
+        let known = (0..=u16::MAX).filter_map(IgnoreReason::from_code).count();
+        assert_eq!(known, pinned.len());
+    }
+
+    #[test]
+    fn outcomes_serialize_with_the_names_of_their_reasons() {
+        let outcomes = [ProcessOutcome::Applied, ProcessOutcome::Ignored(IgnoreReason::AccountLocked)];
+        let json = serde_json::to_string(&outcomes).expect("outcomes serialize to JSON");
+        assert_eq!(json, "[\"applied\",{\"ignored\":\"account_locked\"}]");
+        let parsed: Vec<ProcessOutcome> = serde_json::from_str(&json).expect("outcomes deserialize");
+        assert_eq!(parsed, outcomes);
+        assert!(serde_json::from_str::<ProcessOutcome>("{\"ignored\":\"no_such_reason\"}").is_err());
+    }
 }
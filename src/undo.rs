@@ -0,0 +1,81 @@
+use crate::{
+    amount::Amount,
+    invariants::MoneyFlows,
+    types::{Client, Transaction},
+};
+use std::collections::VecDeque;
+
+/// What an applied transaction changed, so it can be reverted.
+///
+/// Rather than inverse deltas, the entry keeps the prior value of everything the transaction
+/// touched: restoring it is exact, and also covers the chargeback clamp and account lock.
+pub(crate) struct UndoEntry {
+    /// The applied transaction.
+    pub txn: Transaction,
+    /// The client's account before the transaction, `None` if the transaction opened it.
+    pub client: Option<Client>,
+    /// For deposits and withdrawals, the entry the store held under the same id beforehand.
+    pub stored: Option<Option<Transaction>>,
+    /// For disputes, resolves and chargebacks, the dispute recorded under the same id beforehand.
+    pub disputed: Option<Option<Transaction>>,
+    /// For holds, releases and captures, the escrow hold recorded under the same id beforehand.
+    pub escrowed: Option<Option<Transaction>>,
+    /// For pending withdrawals, settles and cancels, the pending withdrawal recorded under the
+    /// same id beforehand.
+    pub pending: Option<Option<Transaction>>,
+    /// For chargebacks and representments, the chargeback recorded under the same id
+    /// beforehand.
+    pub charged_back: Option<Option<Transaction>>,
+    /// For resolves and representments, whether the transaction was resolved beforehand.
+    pub resolved: Option<bool>,
+    /// The dispute shortfalls the transaction may change, by tx id, as they were beforehand.
+    pub shortfalls: Vec<(u32, Option<Amount>)>,
+    /// For chargebacks and representments, the write-off recorded under the same id beforehand.
+    pub written_off: Option<Option<Amount>>,
+    /// For chargebacks and representments, the chargeback reason recorded under the same id
+    /// beforehand.
+    pub chargeback_reason: Option<Option<String>>,
+    /// The engine's count of dust amounts beforehand.
+    pub dust: u64,
+    /// The engine's money flows beforehand.
+    pub flows: MoneyFlows,
+}
+
+/// The most recent undo entries, at most `depth` of them, the oldest being dropped first.
+pub(crate) struct UndoHistory {
+    depth: usize,
+    entries: VecDeque<UndoEntry>,
+}
+
+impl UndoHistory {
+    pub fn new(depth: usize) -> Self {
+        UndoHistory {
+            depth,
+            entries: VecDeque::with_capacity(depth),
+        }
+    }
+
+    /// Returns `true` if entries are recorded at all.
+    pub fn is_enabled(&self) -> bool {
+        self.depth > 0
+    }
+
+    pub fn push(&mut self, entry: UndoEntry) {
+        if !self.is_enabled() {
+            return;
+        }
+        if self.entries.len() == self.depth {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    pub fn pop(&mut self) -> Option<UndoEntry> {
+        self.entries.pop_back()
+    }
+
+    /// Forgets every entry, keeping the depth.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
@@ -0,0 +1,148 @@
+use crate::{
+    amount,
+    errors::PaymentError,
+    report::csv_field,
+    types::{Transaction, TransactionType},
+};
+use std::{
+    collections::HashSet,
+    fmt,
+    io::{self, Write},
+};
+
+/// Header line of the validation findings report.
+pub const FINDINGS_HEADER: &str = "line,finding";
+
+/// Decimal places amounts may have in the input.
+pub const AMOUNT_DECIMALS: i32 = amount::SCALE as i32;
+
+/// A problem the validation pass found in a row.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    /// Line of the row, as `path:line` in multi-file runs.
+    pub line: String,
+    pub problem: String,
+}
+
+impl Finding {
+    /// Writes the finding as a row of the report, under `FINDINGS_HEADER`.
+    pub fn write_row<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "{},{}", csv_field(&self.line), csv_field(&self.problem))
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.problem)
+    }
+}
+
+/// Checks the rows of an input without applying them, for all-or-nothing runs.
+///
+/// A row is invalid when it doesn't parse, has an amount with more than `AMOUNT_DECIMALS`
+/// decimal places, reuses the tx id of an earlier deposit or withdrawal, or refers to a
+/// transaction (or escrow hold, or pending withdrawal) that no earlier row created. Account
+/// balances aren't tracked, so rows the engine would merely ignore, like an overdrawing
+/// withdrawal, are valid.
+#[derive(Debug, Default)]
+pub struct Validator {
+    transactions: HashSet<u32>,
+    holds: HashSet<u32>,
+    pending_withdrawals: HashSet<u32>,
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checks the next row of the input, found at `line`, returning what is wrong with it, if
+    /// anything.
+    pub fn check(
+        &mut self,
+        row: &Result<Transaction, PaymentError>,
+        line: impl fmt::Display,
+    ) -> Option<Finding> {
+        let problem = match row {
+            Ok(txn) => self.problem(txn)?,
+            Err(err) => err.to_string(),
+        };
+        Some(Finding {
+            line: line.to_string(),
+            problem,
+        })
+    }
+
+    fn problem(&mut self, txn: &Transaction) -> Option<String> {
+        // an accrual's amount is a rate, of any precision
+        if let Some(amount) = txn.amount.filter(|_| txn.r#type != TransactionType::Accrue) {
+            let scaled = amount * 10f64.powi(AMOUNT_DECIMALS);
+            if (scaled - scaled.round()).abs() > 1e-6 {
+                return Some(format!(
+                    "amount {} has more than {} decimal places",
+                    amount, AMOUNT_DECIMALS
+                ));
+            }
+        }
+        match txn.r#type {
+            TransactionType::Deposit | TransactionType::Withdrawal => {
+                (!self.transactions.insert(txn.tx)).then(|| format!("duplicate tx {}", txn.tx))
+            }
+            TransactionType::Hold => {
+                self.holds.insert(txn.tx);
+                None
+            }
+            TransactionType::Dispute
+            | TransactionType::Resolve
+            | TransactionType::Chargeback
+            | TransactionType::Representment => (!self.transactions.contains(&txn.tx))
+                .then(|| format!("{} of unknown tx {}", txn.r#type.as_str(), txn.tx)),
+            TransactionType::Release | TransactionType::Capture => (!self.holds.contains(&txn.tx))
+                .then(|| format!("{} of unknown hold {}", txn.r#type.as_str(), txn.tx)),
+            TransactionType::WithdrawalPending => {
+                self.pending_withdrawals.insert(txn.tx);
+                None
+            }
+            TransactionType::WithdrawalSettle | TransactionType::WithdrawalCancel => (!self
+                .pending_withdrawals
+                .contains(&txn.tx))
+            .then(|| format!("{} of unknown pending withdrawal {}", txn.r#type.as_str(), txn.tx)),
+            TransactionType::Accrue => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{errors::PaymentError, parser::parse_records, validate::Validator};
+
+    #[tokio::test]
+    async fn finds_invalid_rows() -> Result<(), PaymentError> {
+        let csv = "type, client, tx, amount
+        deposit, 1, 1, 1.0
+        deposit, 1, 2, 1.00001
+        deposit, 2, 1, 3.0
+        dispute, 1, 9
+        withdrawal, 1, 3, abc
+        release, 1, 4
+        hold, 1, 4, 0.5
+        capture, 1, 4
+        chargeback, 1, 1
+        withdrawal, 1, 5, 5.0";
+        let str_buf = stringreader::StringReader::new(csv);
+        let (_, records) = parse_records(Box::new(str_buf)).await?;
+
+        let mut validator = Validator::new();
+        let findings: Vec<String> = records
+            .filter_map(|record| validator.check(&record.transaction, record.line))
+            .map(|finding| finding.to_string())
+            .collect();
+        assert_eq!(findings.len(), 5);
+        assert_eq!(findings[0], "line 3: amount 1.00001 has more than 4 decimal places");
+        assert_eq!(findings[1], "line 4: duplicate tx 1");
+        assert_eq!(findings[2], "line 5: dispute of unknown tx 9");
+        assert!(findings[3].starts_with("line 6: CSV parse error"), "{}", findings[3]);
+        assert_eq!(findings[4], "line 7: release of unknown hold 4");
+        Ok(())
+    }
+}
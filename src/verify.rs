@@ -0,0 +1,150 @@
+use crate::{
+    config::EngineConfig,
+    diff::{diff_states, ClientDiff, DiffKind, FieldChange},
+    errors::PaymentError,
+    parser::parse_transactions,
+    payment_engine::PaymentEngine,
+    report::OutputOrder,
+};
+use std::{fmt, io::Read};
+
+/// Outcome of reconciling a transaction log against a claimed final state.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerificationReport {
+    /// Clients whose claimed state matches the computed one.
+    pub matched: usize,
+    /// Clients whose claimed and computed states differ, or that are missing from either side.
+    /// In each diff the claimed state is the "before" side and the computed state the "after".
+    pub mismatches: Vec<ClientDiff>,
+}
+
+impl VerificationReport {
+    /// Returns `true` if every client reconciles.
+    pub fn is_reconciled(&self) -> bool {
+        self.mismatches.is_empty()
+    }
+}
+
+/// Processes the transactions and compares the resulting account states with `expected_state`,
+/// an account states report as written by `write_client_states`.
+///
+/// The comparison is done at the report's precision of four decimal places, so computed
+/// balances are formatted exactly as the engine would output them before being compared.
+pub async fn verify(
+    transactions: Box<dyn Read>,
+    expected_state: impl Read,
+) -> Result<VerificationReport, PaymentError> {
+    // a row that can't be parsed makes the log unverifiable
+    let mut engine = PaymentEngine::new().with_config(EngineConfig {
+        fail_fast: true,
+        ..Default::default()
+    });
+    engine.process_all(parse_transactions(transactions).await?).await.into_result()?;
+
+    let mut computed = Vec::new();
+    engine
+        .write_client_states(&mut computed, None, OutputOrder::default())
+        .map_err(|err| PaymentError::IoError(err.to_string()))?;
+    let mismatches = diff_states(expected_state, computed.as_slice())?;
+
+    // every computed client is either matched, changed or missing from the claimed state
+    let unmatched = mismatches
+        .iter()
+        .filter(|diff| !matches!(diff.kind, DiffKind::Removed(_)))
+        .count();
+    Ok(VerificationReport {
+        matched: engine.clients.len() - unmatched,
+        mismatches,
+    })
+}
+
+impl fmt::Display for VerificationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "matched clients: {}", self.matched)?;
+        writeln!(f, "mismatched clients: {}", self.mismatches.len())?;
+        for diff in &self.mismatches {
+            match &diff.kind {
+                DiffKind::Added(_) => {
+                    writeln!(f, "client {}: missing from the claimed state", diff.client)?
+                }
+                DiffKind::Removed(_) => {
+                    writeln!(f, "client {}: missing from the computed state", diff.client)?
+                }
+                DiffKind::Changed(changes) => {
+                    for change in changes {
+                        let (field, claimed, computed) = match change {
+                            FieldChange::Available { before, after } => {
+                                ("available", format!("{:.4}", before), format!("{:.4}", after))
+                            }
+                            FieldChange::Held { before, after } => {
+                                ("held", format!("{:.4}", before), format!("{:.4}", after))
+                            }
+                            FieldChange::Total { before, after } => {
+                                ("total", format!("{:.4}", before), format!("{:.4}", after))
+                            }
+                            FieldChange::Locked { before, after } => {
+                                ("locked", before.to_string(), after.to_string())
+                            }
+                        };
+                        writeln!(
+                            f,
+                            "client {}: {} claimed {}, computed {}",
+                            diff.client, field, claimed, computed
+                        )?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{errors::PaymentError, verify::verify};
+
+    const TRANSACTIONS: &str = "type, client, tx, amount
+        deposit, 1, 1, 1.0
+        deposit, 2, 2, 2.0
+        deposit, 1, 3, 2.0
+        withdrawal, 1, 4, 1.5
+        withdrawal, 2, 5, 3.0";
+
+    #[tokio::test]
+    async fn can_verify_matching_state() -> Result<(), PaymentError> {
+        let claimed = "client,available,held,total,locked
+2,2.0000,0.0000,2.0000,false
+1,1.5000,0.0000,1.5000,false
+";
+        let str_buf = stringreader::StringReader::new(TRANSACTIONS);
+        let report = verify(Box::new(str_buf), claimed.as_bytes()).await?;
+
+        assert!(report.is_reconciled());
+        assert_eq!(report.matched, 2);
+        assert_eq!(report.to_string(), "matched clients: 2\nmismatched clients: 0\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn can_detect_omitted_withdrawal() -> Result<(), PaymentError> {
+        // the claimed state forgot the 1.5 withdrawal of client 1
+        let claimed = "client,available,held,total,locked
+1,3.0000,0.0000,3.0000,false
+2,2.0000,0.0000,2.0000,false
+";
+        let str_buf = stringreader::StringReader::new(TRANSACTIONS);
+        let report = verify(Box::new(str_buf), claimed.as_bytes()).await?;
+
+        assert!(!report.is_reconciled());
+        assert_eq!(report.matched, 1);
+        assert_eq!(
+            report.to_string(),
+            "matched clients: 1
+mismatched clients: 1
+client 1: available claimed 3.0000, computed 1.5000
+client 1: total claimed 3.0000, computed 1.5000
+"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,68 @@
+//! Warnings emitted while transactions are processed, for long-running ingestion that must not
+//! keep them until the end of the run.
+
+use crate::{invariants::InvariantViolation, rejection::RejectionRecord};
+use std::fmt;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+/// Default capacity of the warning channel of `--follow` mode.
+pub const DEFAULT_WARNING_CHANNEL_CAPACITY: usize = 1024;
+
+/// Something worth a warning that happened while processing the input.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EngineWarning {
+    /// A transaction was ignored by the engine, or a row that failed to parse was skipped.
+    Rejected(RejectionRecord),
+    /// A client account failed an invariant after a transaction was applied to it.
+    InvariantViolated(InvariantViolation),
+    /// The number of open disputes reached 90% of `EngineConfig::max_open_disputes`, or the
+    /// cap itself.
+    OpenDisputesNearCap { open: usize, cap: usize },
+}
+
+impl fmt::Display for EngineWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EngineWarning::Rejected(rejection) => match (rejection.client, rejection.tx) {
+                (Some(client), Some(tx)) => {
+                    write!(f, "tx {} of client {} ignored: {}", tx, client, rejection.detail)
+                }
+                _ => write!(f, "line {} skipped: {}", rejection.location(), rejection.detail),
+            },
+            EngineWarning::InvariantViolated(violation) => write!(f, "invariant violated: {}", violation),
+            EngineWarning::OpenDisputesNearCap { open, cap } => {
+                write!(f, "{} disputes open, new ones are rejected from {}", open, cap)
+            }
+        }
+    }
+}
+
+/// Sending half of a warning channel, set with `PaymentEngine::with_warning_sink`.
+///
+/// Sending never waits for the consumer: a warning that doesn't fit in the channel, or that
+/// nobody receives any more, is dropped and counted instead, so a slow consumer can't hold up
+/// processing.
+#[derive(Debug)]
+pub struct WarningSink {
+    sender: mpsc::Sender<EngineWarning>,
+    dropped: u64,
+}
+
+impl WarningSink {
+    pub fn new(sender: mpsc::Sender<EngineWarning>) -> Self {
+        WarningSink { sender, dropped: 0 }
+    }
+
+    /// Sends a warning if the channel has room for it, or counts it as dropped.
+    pub fn send(&mut self, warning: EngineWarning) {
+        match self.sender.try_send(warning) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_) | TrySendError::Closed(_)) => self.dropped += 1,
+        }
+    }
+
+    /// Returns the number of warnings dropped so far.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
@@ -0,0 +1,279 @@
+//! Live read access to the accounts of an engine that is still ingesting, e.g. for dashboards.
+
+use crate::{
+    errors::PaymentError,
+    payment_engine::PaymentEngine,
+    store::{InMemoryTransactionStore, TransactionStore},
+    types::{ClientId, ClientView, ProcessOutcome, Transaction, TransactionType},
+};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::watch;
+
+/// Every client's balances at one point of the ingestion, sorted by client id.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientTable {
+    /// Transactions applied before the table was taken.
+    pub applied: u64,
+    clients: Vec<ClientView>,
+}
+
+impl ClientTable {
+    pub fn get(&self, client: ClientId) -> Option<&ClientView> {
+        self.clients
+            .binary_search_by_key(&client, |view| view.client)
+            .ok()
+            .map(|index| &self.clients[index])
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ClientView> {
+        self.clients.iter()
+    }
+}
+
+/// A `PaymentEngine` publishing its accounts as it processes transactions, for readers on
+/// other tasks or threads (see `WatchableEngine::reader`):
+///
+/// - after each applied transaction, the balances of its client go to the client's
+///   `tokio::sync::watch` channel, created the first time the client changes or is subscribed to;
+/// - every `refresh_every` applied transactions, a `ClientTable` of all the clients replaces the
+///   previous snapshot.
+///
+/// Readers only clone the latest value out of a channel, so they never wait for a transaction
+/// to be processed, and the engine only waits for those clones.
+pub struct WatchableEngine<S: TransactionStore = InMemoryTransactionStore> {
+    engine: PaymentEngine<S>,
+    refresh_every: u64,
+    applied: u64,
+    watchers: Arc<Mutex<HashMap<ClientId, watch::Sender<ClientView>>>>,
+    snapshot: watch::Sender<Arc<ClientTable>>,
+}
+
+/// The reading half of a `WatchableEngine`, cheap to clone and send to other tasks.
+#[derive(Clone)]
+pub struct EngineReader {
+    watchers: Arc<Mutex<HashMap<ClientId, watch::Sender<ClientView>>>>,
+    snapshot: watch::Receiver<Arc<ClientTable>>,
+}
+
+impl<S: TransactionStore> WatchableEngine<S> {
+    /// Wraps an engine, refreshing the snapshot every `refresh_every` applied transactions (at
+    /// least one). The first snapshot is taken right away.
+    pub fn new(engine: PaymentEngine<S>, refresh_every: usize) -> Self {
+        let (snapshot, _) = watch::channel(Arc::new(ClientTable::default()));
+        let watchable = WatchableEngine {
+            engine,
+            refresh_every: refresh_every.max(1) as u64,
+            applied: 0,
+            watchers: Arc::new(Mutex::new(HashMap::new())),
+            snapshot,
+        };
+        watchable.refresh();
+        watchable
+    }
+
+    /// Processes a transaction, then publishes its client's balances if it was applied. An
+    /// `accrue` row is for every client, and a row may expire escrow holds of other clients (see
+    /// `EngineConfig::hold_ttl_secs`), so these republish every watched client that changed and
+    /// refresh the snapshot right away.
+    ///
+    /// See `PaymentEngine::process_transaction` for the processing rules and errors.
+    pub async fn process(&mut self, txn: Transaction) -> Result<ProcessOutcome, PaymentError> {
+        let client = txn.client;
+        let accrue = txn.r#type == TransactionType::Accrue;
+        let expired = self.engine.expired_holds();
+        let outcome = self.engine.process_transaction(txn).await?;
+        let applied = outcome == ProcessOutcome::Applied;
+        if applied {
+            self.applied += 1;
+        }
+        let everyone = (applied && accrue) || self.engine.expired_holds() != expired;
+        if everyone {
+            for (client, watcher) in self.watchers().iter() {
+                if let Some(view) = self.engine.client_view(*client) {
+                    watcher.send_if_modified(|seen| std::mem::replace(seen, view) != view);
+                }
+            }
+        }
+        if applied && !accrue {
+            let view = self.engine.client_view(client).unwrap_or_else(|| ClientView::empty(client));
+            self.watchers()
+                .entry(client)
+                .or_insert_with(|| watch::Sender::new(view))
+                .send_replace(view);
+        }
+        if everyone || (applied && self.applied.is_multiple_of(self.refresh_every)) {
+            self.refresh();
+        }
+        Ok(outcome)
+    }
+
+    /// Replaces the snapshot with the current balances, e.g. at the end of the input.
+    pub fn refresh(&self) {
+        self.snapshot.send_replace(Arc::new(ClientTable {
+            applied: self.applied,
+            clients: self.engine.all_client_views(),
+        }));
+    }
+
+    /// Returns a handle to read the accounts from other tasks.
+    pub fn reader(&self) -> EngineReader {
+        EngineReader {
+            watchers: self.watchers.clone(),
+            snapshot: self.snapshot.subscribe(),
+        }
+    }
+
+    /// See `EngineReader::subscribe`.
+    pub fn subscribe(&self, client: ClientId) -> watch::Receiver<ClientView> {
+        self.reader().subscribe(client)
+    }
+
+    /// See `EngineReader::read_snapshot`.
+    pub fn read_snapshot(&self) -> Arc<ClientTable> {
+        self.snapshot.borrow().clone()
+    }
+
+    pub fn engine(&self) -> &PaymentEngine<S> {
+        &self.engine
+    }
+
+    /// Returns the engine, once the ingestion is over.
+    pub fn into_inner(self) -> PaymentEngine<S> {
+        self.engine
+    }
+
+    fn watchers(&self) -> std::sync::MutexGuard<'_, HashMap<ClientId, watch::Sender<ClientView>>> {
+        // the map is left consistent whatever panicked while holding it
+        self.watchers.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl EngineReader {
+    /// Returns a receiver of a client's balances, which changes after every transaction applied
+    /// to the client. A client without an account yet reads as zero until its first one.
+    pub fn subscribe(&self, client: ClientId) -> watch::Receiver<ClientView> {
+        let mut watchers = self.watchers.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        watchers
+            .entry(client)
+            .or_insert_with(|| {
+                // the client hasn't changed since the engine was wrapped, so any snapshot has it
+                let snapshot = self.read_snapshot();
+                let view = snapshot.get(client).copied();
+                watch::Sender::new(view.unwrap_or_else(|| ClientView::empty(client)))
+            })
+            .subscribe()
+    }
+
+    /// Returns the latest snapshot of every client's balances.
+    pub fn read_snapshot(&self) -> Arc<ClientTable> {
+        self.snapshot.borrow().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        amount::amount,
+        config::EngineConfig,
+        errors::PaymentError,
+        payment_engine::PaymentEngine,
+        types::{AccountStatus, ClientView, Transaction},
+        watchable::WatchableEngine,
+    };
+    use std::time::Duration;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn readers_see_balances_while_ingestion_continues() -> Result<(), PaymentError> {
+        let mut engine = WatchableEngine::new(PaymentEngine::new(), 3);
+        let reader = engine.reader();
+        let mut balances = reader.subscribe(1);
+        assert_eq!(*balances.borrow(), ClientView::empty(1));
+
+        let watcher = tokio::spawn(async move {
+            while balances.borrow_and_update().available != amount(2.5) {
+                balances.changed().await.expect("the engine is still running");
+            }
+            *balances.borrow()
+        });
+
+        engine.process(Transaction::deposit(1, 1, 2.5)).await?;
+        let seen = tokio::time::timeout(Duration::from_secs(5), watcher)
+            .await
+            .expect("the reader saw the deposit")
+            .expect("the reader didn't panic");
+        assert_eq!(
+            (seen.available, seen.total, seen.status),
+            (amount(2.5), amount(2.5), AccountStatus::Active)
+        );
+
+        // ingestion goes on, with the snapshot refreshed every third applied transaction
+        assert!(reader.read_snapshot().is_empty());
+        engine.process(Transaction::deposit(2, 2, 1.0)).await?;
+        engine.process(Transaction::withdrawal(1, 3, 5.0)).await?;
+        assert!(reader.read_snapshot().is_empty());
+        engine.process(Transaction::withdrawal(1, 4, 0.5)).await?;
+
+        let snapshot = reader.read_snapshot();
+        assert_eq!(snapshot.applied, 3);
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot.get(1).map(|view| view.available), Some(amount(2.0)));
+        assert_eq!(snapshot.get(3), None);
+        assert_eq!(engine.subscribe(2).borrow().total, amount(1.0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn accruals_republish_every_watched_client() -> Result<(), PaymentError> {
+        let mut engine = WatchableEngine::new(PaymentEngine::new(), 10);
+        engine.process(Transaction::deposit(1, 1, 100.0)).await?;
+        engine.process(Transaction::deposit(2, 2, 50.0)).await?;
+        let mut first = engine.subscribe(1);
+        let mut second = engine.subscribe(2);
+        first.borrow_and_update();
+        second.borrow_and_update();
+
+        engine.process(Transaction::accrue(3, 0.01)).await?;
+        assert!(first.has_changed().expect("the engine is still running"));
+        assert_eq!(first.borrow().total, amount(101.0));
+        assert_eq!(second.borrow().total, amount(50.5));
+        // the snapshot is refreshed without waiting for `refresh_every`
+        let snapshot = engine.read_snapshot();
+        assert_eq!(snapshot.applied, 3);
+        assert_eq!(snapshot.get(2).map(|view| view.total), Some(amount(50.5)));
+        // client 0 only stands for "every client", it gets no view of its own
+        assert!(!engine.watchers().contains_key(&0));
+        assert_eq!(snapshot.get(0), None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn expired_holds_republish_their_clients() -> Result<(), PaymentError> {
+        let config = EngineConfig {
+            hold_ttl_secs: Some(10),
+            ..Default::default()
+        };
+        let mut engine = WatchableEngine::new(PaymentEngine::new().with_config(config), 10);
+        engine.process(Transaction::deposit(1, 1, 5.0).with_timestamp(0)).await?;
+        engine.process(Transaction::hold(1, 2, 5.0).with_timestamp(0)).await?;
+        let mut balances = engine.subscribe(1);
+        assert_eq!(balances.borrow_and_update().held, amount(5.0));
+
+        // another client's row expires the hold
+        engine.process(Transaction::deposit(2, 3, 1.0).with_timestamp(11)).await?;
+        assert!(balances.has_changed().expect("the engine is still running"));
+        assert_eq!(balances.borrow().available, amount(5.0));
+        assert_eq!(engine.read_snapshot().get(1).map(|view| view.held), Some(amount(0.0)));
+        Ok(())
+    }
+}
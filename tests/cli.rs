@@ -0,0 +1,1266 @@
+//! Runs the binary against the fixtures in `tests/fixtures` and checks its exit code contract.
+
+use std::{
+    io::Write,
+    process::{Command, Output, Stdio},
+};
+
+fn fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_payment-engine"))
+        .args(args)
+        .output()
+        .expect("failed to run the payment-engine binary")
+}
+
+#[test]
+fn clean_run_exits_with_zero() {
+    let output = run(&[&fixture("clean.csv"), "--fail-on-reject"]);
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("client,available,held,total,locked\n"));
+    assert!(stdout.contains("1,1.5000,0.0000,1.5000,false\n"));
+}
+
+#[test]
+fn hard_errors_exit_with_one() {
+    assert_eq!(run(&[]).status.code(), Some(1));
+    let output = run(&[&fixture("missing.csv")]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("fixtures/missing.csv: No such file"), "{}", stderr);
+
+    let output = run(&[&fixture("")]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is a directory"));
+
+    let output = run(&[&fixture("malformed.csv")]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("CSV parse error"));
+}
+
+#[test]
+fn rejected_transactions_exit_with_two_only_when_asked() {
+    assert_eq!(run(&[&fixture("rejected.csv")]).status.code(), Some(0));
+
+    let output = run(&[&fixture("rejected.csv"), "--fail-on-reject"]);
+    assert_eq!(output.status.code(), Some(2));
+    // the report is still written in full
+    assert!(String::from_utf8_lossy(&output.stdout).contains("2,2.0000,0.0000,2.0000,false\n"));
+}
+
+#[test]
+fn empty_inputs_are_reported_and_exit_with_five_only_when_asked() {
+    let shapes = [
+        ("empty.csv", Some("input is empty (0 bytes)")),
+        ("header_only.csv", Some("input has a header but no rows")),
+        ("clean.csv", None),
+    ];
+    for (name, note) in shapes {
+        for fail_on_empty in [false, true] {
+            let path = fixture(name);
+            let mut args = vec![path.as_str()];
+            if fail_on_empty {
+                args.push("--fail-on-empty");
+            }
+            let output = run(&args);
+            let code = if fail_on_empty && note.is_some() { 5 } else { 0 };
+            assert_eq!(output.status.code(), Some(code), "{} {:?}", name, args);
+            // downstream parsers always get the header
+            assert!(String::from_utf8_lossy(&output.stdout).starts_with("client,available,held,total,locked\n"));
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            match note {
+                Some(note) => {
+                    assert!(stderr.contains(&format!("no transactions processed: {}\n", note)), "{}", stderr);
+                    assert_eq!(
+                        stderr.contains(&format!("--fail-on-empty: no transactions processed, {}\n", note)),
+                        fail_on_empty,
+                        "{}",
+                        stderr
+                    );
+                }
+                None => assert!(!stderr.contains("no transactions processed"), "{}", stderr),
+            }
+        }
+    }
+
+    // a header in any of the inputs makes them header-only
+    let output = run(&[&fixture("empty.csv"), &fixture("header_only.csv"), "--fail-on-empty", "--errors", "json"]);
+    assert_eq!(output.status.code(), Some(5));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("\"kind\":\"EmptyInput\""), "{}", stderr);
+    assert!(stderr.contains("input has a header but no rows"), "{}", stderr);
+}
+
+#[test]
+fn invariant_violations_exit_with_three() {
+    // a state whose held balance is below zero, as left by a manual edit
+    let state = fixture("invariant_violation_state.json");
+    let output = run(&[&fixture("invariant_violation.csv"), "--initial-state", &state]);
+    assert_eq!(output.status.code(), Some(3));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("invariant violated: client 1"));
+}
+
+#[test]
+fn lenient_run_quarantines_poison_rows() {
+    let quarantine = std::env::temp_dir().join(format!("quarantine-{}.csv", std::process::id()));
+    let output = run(&[
+        &fixture("malformed.csv"),
+        "--lenient",
+        "--quarantine",
+        quarantine.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+
+    let contents = std::fs::read_to_string(&quarantine).unwrap();
+    std::fs::remove_file(&quarantine).unwrap();
+    let mut lines = contents.lines();
+    assert_eq!(lines.next(), Some("type, client, tx, amount,line,reason"));
+    assert!(lines.next().unwrap().starts_with("transfer, 1, 2, 1.0,3,"));
+    assert_eq!(lines.next(), None);
+}
+
+#[test]
+fn client_filter_restricts_processing_and_report() {
+    let output = run(&[
+        &fixture("three_clients.csv"),
+        "--clients",
+        "2-3",
+        "--filter-input",
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut rows: Vec<&str> = stdout.lines().skip(1).collect();
+    rows.sort();
+    assert_eq!(
+        rows,
+        vec!["2,0.0000,20.0000,20.0000,false", "3,30.0000,0.0000,30.0000,false"]
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains("rows filtered: 2"));
+}
+
+#[test]
+fn diff_exits_with_two_when_reports_differ() {
+    let same = run(&["diff", &fixture("accounts_before.csv"), &fixture("accounts_before.csv")]);
+    assert_eq!(same.status.code(), Some(0));
+    assert!(same.stdout.is_empty());
+
+    let changed = run(&["diff", &fixture("accounts_before.csv"), &fixture("accounts_after.csv")]);
+    assert_eq!(changed.status.code(), Some(2));
+    assert_eq!(
+        String::from_utf8_lossy(&changed.stdout),
+        "~ client 2: available 2.0000 -> 0.0000 (-2.0000), total 2.0000 -> 0.0000 (-2.0000), locked false -> true\n"
+    );
+}
+
+#[test]
+fn verify_exits_with_two_when_claimed_state_mismatches() {
+    let matching = run(&["verify", &fixture("clean.csv"), "--against", &fixture("accounts_before.csv")]);
+    assert_eq!(matching.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&matching.stdout).starts_with("matched clients: 2\n"));
+
+    let mismatching = run(&["verify", &fixture("clean.csv"), "--against", &fixture("accounts_after.csv")]);
+    assert_eq!(mismatching.status.code(), Some(2));
+}
+
+#[test]
+fn reconcile_proposes_the_lost_resolve() {
+    let full = run(&[&fixture("disputes.csv")]);
+    assert_eq!(full.status.code(), Some(0));
+    let pid = std::process::id();
+    let state = std::env::temp_dir().join(format!("reconcile-state-{}.csv", pid));
+    std::fs::write(&state, &full.stdout).unwrap();
+
+    // the log lost its resolve of tx 1
+    let log = std::fs::read_to_string(fixture("disputes.csv")).unwrap();
+    let truncated = std::env::temp_dir().join(format!("reconcile-log-{}.csv", pid));
+    std::fs::write(&truncated, log.replace("resolve, 1, 1\n", "")).unwrap();
+    let output = run(&["reconcile", truncated.to_str().unwrap(), "--against", state.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "type,client,tx,amount\nresolve,1,1,\n");
+
+    // replaying the proposal gives the claimed state back
+    let proposed = std::env::temp_dir().join(format!("reconcile-proposed-{}.csv", pid));
+    std::fs::write(&proposed, &output.stdout).unwrap();
+    let replayed = run(&[truncated.to_str().unwrap(), proposed.to_str().unwrap()]);
+    assert_eq!(replayed.stdout, full.stdout);
+
+    std::fs::write(&state, "client,available,held,total,locked\n1,3.0000,0.0000,3.0000,true\n").unwrap();
+    let output = run(&["reconcile", truncated.to_str().unwrap(), "--against", state.to_str().unwrap()]);
+    for path in [&state, &truncated, &proposed] {
+        std::fs::remove_file(path).unwrap();
+    }
+    assert_eq!(output.status.code(), Some(2));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "type,client,tx,amount\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("~ client 1: available 0.0000 -> 3.0000"), "{}", stderr);
+}
+
+#[test]
+fn runs_without_dispute_support_report_the_rows_not_retained() {
+    let output = run(&[&fixture("clean.csv"), "--dispute-support", "none"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("1,1.5000,0.0000,1.5000,false\n"));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("rows not retained: 4 (dispute support off)\n"), "{}", stderr);
+
+    let output = run(&[&fixture("disputes.csv"), "--dispute-support", "none"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("disputes_disabled"), "{}", stderr);
+}
+
+#[test]
+fn dust_is_counted_and_kept_out_of_disputes() {
+    let output = run(&[&fixture("dust.csv"), "--min-amount", "0.01", "--dust", "apply-but-dont-store"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "client,available,held,total,locked\n1,10.0001,0.0000,10.0001,false\n2,0.0000,0.0000,0.0000,false\n"
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("dust: 2\n"), "{}", stderr);
+    assert!(stderr.contains("  dispute  unknown_transaction      1\n"), "{}", stderr);
+
+    let output = run(&[&fixture("dust.csv"), "--min-amount", "0.01"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("  deposit  dust_amount              2\n"), "{}", stderr);
+}
+
+#[test]
+fn as_of_run_stops_before_the_chargeback() {
+    let stdout = |output: Output| String::from_utf8_lossy(&output.stdout).into_owned();
+
+    let full = run(&["process", &fixture("as_of.csv")]);
+    assert_eq!(full.status.code(), Some(0));
+    assert!(stdout(full).contains("1,6.0000,0.0000,6.0000,true\n"));
+
+    // tx 3 is the last row before the chargeback of tx 1
+    let as_of = run(&["process", &fixture("as_of.csv"), "--as-of-tx", "3"]);
+    assert!(stdout(as_of).contains("1,6.0000,10.0000,16.0000,false\n"));
+
+    let before = run(&["process", &fixture("as_of.csv"), "--as-of-tx", "3", "--as-of-exclusive"]);
+    assert!(stdout(before).contains("1,5.0000,10.0000,15.0000,false\n"));
+}
+
+#[test]
+fn extended_output_reports_dispute_counters() {
+    let csv = run(&[&fixture("disputes.csv"), "--extended-output"]);
+    assert_eq!(
+        String::from_utf8_lossy(&csv.stdout),
+        "client,available,held,total,locked,open_disputes,disputes,chargebacks,open_dispute_held,\
+         status_reason\n1,10.0000,0.0000,10.0000,true,0,2,1,0.0000,chargeback of tx 2\n"
+    );
+
+    let json = run(&[&fixture("disputes.csv"), "--extended-output", "--format", "json"]);
+    assert_eq!(
+        String::from_utf8_lossy(&json.stdout),
+        "[\n  {\"client\":1,\"available\":10.0000,\"held\":0.0000,\"total\":10.0000,\"locked\":true,\
+         \"open_disputes\":0,\"disputes\":2,\"chargebacks\":1,\"open_dispute_held\":0.0000,\
+         \"status_reason\":\"chargeback of tx 2\"}\n]\n"
+    );
+}
+
+#[test]
+fn disputes_report_lists_open_disputes() {
+    let path = std::env::temp_dir().join(format!("payment-engine-disputes-{}.csv", std::process::id()));
+    let output = run(&[&fixture("open_disputes.csv"), "--disputes-report", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("open disputes: 2 (15.0000 held)\n"));
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        // the disputed deposits are on lines 2 and 3
+        "tx,client,amount,reason,source,age_secs\n1,1,10.0000,,2,\n2,2,5.0000,,3,\n"
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn summary_totals_the_client_funds() {
+    let output = run(&[&fixture("disputes.csv")]);
+    assert_eq!(output.status.code(), Some(0));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("client funds: 10.0000 (10.0000 available, 0.0000 held)\n"), "{}", stderr);
+    assert!(stderr.contains("locked accounts: 1\n"), "{}", stderr);
+}
+
+#[test]
+fn summary_breaks_rejections_down_by_type_and_reason() {
+    let path = std::env::temp_dir().join(format!("payment-engine-rejections-{}.json", std::process::id()));
+    let output = run(&[&fixture("rejections.csv"), "--stats-json", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(
+            "rows rejected: 8
+  type        reason               count
+  deposit     missing_amount           1
+  withdrawal  account_locked           1
+  withdrawal  insufficient_funds       2
+  withdrawal  unknown_client           1
+  dispute     unknown_transaction      1
+  dispute     client_mismatch          1
+  resolve     not_disputed             1
+"
+        ),
+        "{}",
+        stderr
+    );
+    assert!(std::fs::read_to_string(&path).unwrap().ends_with(
+        "\"rejections\":{\"deposit\":{\"missing_amount\":1},\"withdrawal\":{\"account_locked\":1,\"insufficient_funds\":2,\"unknown_client\":1},\"dispute\":{\"unknown_transaction\":1,\"client_mismatch\":1},\"resolve\":{\"not_disputed\":1}},\"checksum\":null}\n"
+    ));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn rejections_report_lists_every_rejected_row() {
+    let csv = std::env::temp_dir().join(format!("payment-engine-rejected-{}.csv", std::process::id()));
+    let output = run(&[&fixture("rejections.csv"), "--rejections-report", csv.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    let report = std::fs::read_to_string(&csv).unwrap();
+    std::fs::remove_file(&csv).unwrap();
+    let rows: Vec<&str> = report.lines().collect();
+    assert_eq!(rows.len(), 9);
+    assert_eq!(rows[0], "source,line,client,tx,type,amount,reason,detail,idempotency_key,memo");
+    assert_eq!(rows[1], ",4,1,3,withdrawal,50.0,insufficient_funds,insufficient_funds,,");
+    assert_eq!(rows[8], ",13,1,7,deposit,,missing_amount,missing_amount,,");
+
+    let jsonl = csv.with_extension("jsonl");
+    let output = run(&[
+        &fixture("rejections.csv"),
+        &fixture("clean.csv"),
+        "--lenient",
+        "--rejections-report",
+        jsonl.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    let report = std::fs::read_to_string(&jsonl).unwrap();
+    std::fs::remove_file(&jsonl).unwrap();
+    // clean.csv reuses tx ids of the first file
+    let duplicate = report.lines().find(|row| row.contains("duplicate_transaction")).unwrap();
+    assert!(duplicate.starts_with(&format!("{{\"source\":\"{}\",\"line\":2,", fixture("clean.csv"))), "{}", duplicate);
+}
+
+#[test]
+fn checksum_is_the_last_stderr_line() {
+    let output = run(&[&fixture("disputes.csv"), "--checksum", "--format", "json"]);
+    assert_eq!(output.status.code(), Some(0));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    // the digest of the canonical rows, whatever the output format
+    assert_eq!(
+        stderr.lines().last(),
+        Some("checksum: sha256:bc43eb48e68e5435551d0e83b054fcad5dad548a30332b65cfdf3bad060e4f68")
+    );
+}
+
+#[test]
+fn locked_report_names_the_chargeback() {
+    let path = std::env::temp_dir().join(format!("payment-engine-locked-{}.csv", std::process::id()));
+    let output = run(&[&fixture("disputes.csv"), "--locked-report", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    // the chargeback of tx 2 is on line 7, the deposit it charges back on line 3
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "client,tx,amount,line,total,reason,tx_source\n1,2,5.0000,7,10.0000,,3\n"
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn amounts_above_the_limit_are_quarantined_and_counted() {
+    let quarantine = std::env::temp_dir().join(format!("quarantine-limit-{}.csv", std::process::id()));
+    let output = run(&[
+        &fixture("three_clients.csv"),
+        "--max-transaction-amount",
+        "20",
+        "--quarantine",
+        quarantine.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    // the dispute of the deposit at the limit goes through
+    assert!(String::from_utf8_lossy(&output.stderr).contains("amounts above limit: 1 (rejected)\n"));
+    let contents = std::fs::read_to_string(&quarantine).unwrap();
+    std::fs::remove_file(&quarantine).unwrap();
+    assert_eq!(
+        contents,
+        "type, client, tx, amount,line,reason
+deposit, 3, 3, 30.0,4,amount_above_limit: 30.0000 is above the limit of 20.0000
+"
+    );
+}
+
+#[test]
+fn exported_transactions_show_how_their_disputes_ended() {
+    let path = std::env::temp_dir().join(format!("payment-engine-export-{}.csv", std::process::id()));
+    let output = run(&[&fixture("disputes.csv"), "--export-transactions", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "# retained: deposits and withdrawals\ntx,client,type,amount,state,source,memo\n\
+         1,1,deposit,10.0000,resolved,2,\n2,1,deposit,5.0000,charged_back,3,\n"
+    );
+
+    // dispute support off retains nothing
+    let output = run(&[
+        &fixture("disputes.csv"),
+        "--dispute-support",
+        "none",
+        "--export-transactions",
+        path.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "# retained: nothing, dispute support is off\ntx,client,type,amount,state,source,memo\n"
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn tx_offset_applies_to_disputes_too() {
+    let path = std::env::temp_dir().join(format!("payment-engine-offset-{}.csv", std::process::id()));
+    let output = run(&[
+        &fixture("disputes.csv"),
+        "--tx-offset",
+        "1000",
+        "--locked-report",
+        path.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "client,tx,amount,line,total,reason,tx_source\n1,1002,5.0000,7,10.0000,,3\n"
+    );
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn tx_offsets_can_differ_per_file() {
+    // feed_b reuses tx 2 of feed_a, which only its own offset moves out of the way
+    let output = run(&[&fixture("feed_a.csv"), &fixture("feed_b.csv"), "--tx-offset", "0,100"]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "client,available,held,total,locked\n1,9.0000,0.0000,9.0000,false\n2,10.0000,0.0000,10.0000,false\n"
+    );
+
+    let output = run(&[&fixture("feed_a.csv"), &fixture("feed_b.csv"), "--tx-offset", "0,100,200"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--tx-offset gives 3 offsets for 2 transactions files"));
+}
+
+#[test]
+fn table_format_renders_aligned_columns() {
+    let output = run(&[&fixture("three_clients.csv"), "--format", "table", "--max-rows", "2"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "client | available |    held |   total | locked
+-------+-----------+---------+---------+-------
+     1 |    5.0000 |  0.0000 |  5.0000 | false
+     2 |    0.0000 | 20.0000 | 20.0000 | false
+\u{2026}and 1 more client
+"
+    );
+}
+
+#[test]
+fn blocklisted_clients_are_quarantined_with_the_reason() {
+    let quarantine = std::env::temp_dir().join(format!("blocklist-{}.csv", std::process::id()));
+    let output = run(&[
+        &fixture("three_clients.csv"),
+        "--blocklist",
+        &fixture("blocklist.csv"),
+        "--quarantine",
+        quarantine.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("\n1,"));
+
+    let contents = std::fs::read_to_string(&quarantine).unwrap();
+    std::fs::remove_file(&quarantine).unwrap();
+    assert_eq!(
+        contents,
+        "type, client, tx, amount,line,reason
+deposit, 1, 1, 10.0,2,blocklisted: mule account
+withdrawal, 1, 4, 5.0,6,blocklisted: mule account
+"
+    );
+}
+
+#[test]
+fn memos_are_carried_into_the_reports() {
+    let quarantine = std::env::temp_dir().join(format!("memos-{}.csv", std::process::id()));
+    let rejections = std::env::temp_dir().join(format!("memos-rejections-{}.csv", std::process::id()));
+    let export = std::env::temp_dir().join(format!("memos-export-{}.csv", std::process::id()));
+    let output = run(&[
+        &fixture("memos.csv"),
+        "--max-memo-len",
+        "4",
+        "--quarantine",
+        quarantine.to_str().unwrap(),
+        "--rejections-report",
+        rejections.to_str().unwrap(),
+        "--export-transactions",
+        export.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+
+    // rejected rows keep their memo verbatim, stored transactions have it cut at the cap
+    let contents = std::fs::read_to_string(&rejections).unwrap();
+    std::fs::remove_file(&rejections).unwrap();
+    assert_eq!(
+        contents.lines().nth(1),
+        Some(",3,1,2,withdrawal,50.0,insufficient_funds,insufficient_funds,,\"rent, april \"\"late\"\"\"")
+    );
+    let contents = std::fs::read_to_string(&export).unwrap();
+    std::fs::remove_file(&export).unwrap();
+    assert!(
+        contents.contains("\n1,1,deposit,10.0000,settled,2,payr[...]\n3,1,withdrawal,4.0000,settled,4,\n"),
+        "{}",
+        contents
+    );
+
+    let contents = std::fs::read_to_string(&quarantine).unwrap();
+    std::fs::remove_file(&quarantine).unwrap();
+    assert_eq!(
+        contents,
+        "type, client, tx, amount, memo,line,reason
+withdrawal, 1, 2, 50.0,\"rent, april \"\"late\"\"\",3,insufficient_funds
+"
+    );
+}
+
+#[test]
+fn ordering_by_timestamp_matches_the_presorted_input() {
+    let presorted = run(&[&fixture("timestamps_sorted.csv"), "--fail-on-reject"]);
+    assert_eq!(presorted.status.code(), Some(0));
+
+    let shuffled = run(&[&fixture("timestamps_shuffled.csv"), "--fail-on-reject"]);
+    assert_eq!(shuffled.status.code(), Some(2));
+
+    for chunk_rows in ["100", "2"] {
+        let output = run(&[
+            &fixture("timestamps_shuffled.csv"),
+            "--fail-on-reject",
+            "--order-by",
+            "timestamp",
+            "--sort-chunk-rows",
+            chunk_rows,
+        ]);
+        assert_eq!(output.status.code(), Some(0));
+        assert_eq!(output.stdout, presorted.stdout);
+    }
+}
+
+#[test]
+fn tx_ids_reused_across_files_are_rejected_and_attributed() {
+    let quarantine = std::env::temp_dir().join(format!("feeds-{}.csv", std::process::id()));
+    let feed_a = fixture("feed_a.csv");
+    let feed_b = fixture("feed_b.csv");
+    let output = run(&[&feed_a, &feed_b, "--quarantine", quarantine.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "client,available,held,total,locked
+1,9.0000,0.0000,9.0000,false
+2,5.0000,0.0000,5.0000,false
+"
+    );
+
+    let contents = std::fs::read_to_string(&quarantine).unwrap();
+    std::fs::remove_file(&quarantine).unwrap();
+    assert_eq!(
+        contents,
+        format!(
+            "type, client, tx, amount,line,reason
+deposit, 2, 2, 5.0,{}:3,duplicate_transaction: tx 2 already at {}:3
+",
+            feed_b, feed_a
+        )
+    );
+}
+
+#[test]
+fn quarantined_rows_follow_the_header_of_their_file() {
+    let dir = std::env::temp_dir();
+    let pid = std::process::id();
+    let (first, second, third) = (
+        dir.join(format!("headers-a-{}.csv", pid)),
+        dir.join(format!("headers-b-{}.csv", pid)),
+        dir.join(format!("headers-c-{}.csv", pid)),
+    );
+    let quarantine = dir.join(format!("headers-quarantine-{}.csv", pid));
+    std::fs::write(&first, "type,client,tx,amount\nwithdrawal,1,1,5.0\n").unwrap();
+    std::fs::write(&second, "type,client,tx,amount,memo\nwithdrawal,2,2,5.0,refund\n").unwrap();
+    std::fs::write(&third, "type,client,tx,amount,memo\nwithdrawal,3,3,5.0,fee\n").unwrap();
+    let paths = [&first, &second, &third].map(|path| path.to_str().unwrap().to_owned());
+    let output = run(&[&paths[0], &paths[1], &paths[2], "--quarantine", quarantine.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+
+    let contents = std::fs::read_to_string(&quarantine).unwrap();
+    for path in [&first, &second, &third, &quarantine] {
+        std::fs::remove_file(path).unwrap();
+    }
+    // the third file's rows go under the second file's header, which is the same
+    assert_eq!(
+        contents,
+        format!(
+            "type,client,tx,amount,line,reason
+withdrawal,1,1,5.0,{}:2,unknown_client
+type,client,tx,amount,memo,line,reason
+withdrawal,2,2,5.0,refund,{}:2,unknown_client
+withdrawal,3,3,5.0,fee,{}:2,unknown_client
+",
+            paths[0], paths[1], paths[2]
+        )
+    );
+}
+
+#[test]
+fn chunked_runs_report_like_row_by_row_runs() {
+    let quarantine = std::env::temp_dir().join(format!("chunks-{}.csv", std::process::id()));
+    let run_chunked = |inputs: &[String], chunk_size: Option<&str>| {
+        let mut args: Vec<&str> = inputs.iter().map(String::as_str).collect();
+        args.extend(["--lenient", "--quarantine", quarantine.to_str().unwrap()]);
+        if let Some(chunk_size) = chunk_size {
+            args.extend(["--chunk-size", chunk_size]);
+        }
+        let output = run(&args);
+        assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+        let contents = std::fs::read_to_string(&quarantine).unwrap();
+        (String::from_utf8_lossy(&output.stdout).into_owned(), contents)
+    };
+
+    for inputs in [vec![fixture("chunks.csv")], vec![fixture("feed_a.csv"), fixture("feed_b.csv")]] {
+        let (accounts, quarantined) = run_chunked(&inputs, None);
+        for chunk_size in ["2", "5", "64"] {
+            assert_eq!(run_chunked(&inputs, Some(chunk_size)), (accounts.clone(), quarantined.clone()));
+        }
+    }
+    std::fs::remove_file(&quarantine).unwrap();
+
+    let (accounts, quarantined) = run_chunked(&[fixture("chunks.csv")], Some("64"));
+    assert_eq!(
+        accounts,
+        "client,available,held,total,locked
+1,5.0000,0.0000,5.0000,false
+2,0.5000,0.0000,0.5000,false
+3,2.0000,0.0000,2.0000,false
+"
+    );
+    // the rejected rows come in input order, the unparseable one included
+    let lines: Vec<&str> = quarantined.lines().map(|line| line.split(',').nth(4).unwrap_or_default()).collect();
+    assert_eq!(lines, ["line", "4", "6", "10", "12"]);
+    std::fs::remove_file(&quarantine).unwrap();
+}
+
+#[test]
+fn out_of_order_tx_ids_warn_or_abort() {
+    let report = std::env::temp_dir().join(format!("tx-order-{}.csv", std::process::id()));
+    let output = run(&[
+        &fixture("out_of_order.csv"),
+        "--check-tx-order",
+        "--tx-order-report",
+        report.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    // the dispute and resolve of tx 1 are not out of order, the withdrawal is
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("warning: line 6: tx 3 comes after tx 5"), "{}", stderr);
+    assert!(stderr.contains("out of order tx ids: 1"), "{}", stderr);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("1,12.0000,0.0000,12.0000,false"));
+
+    let contents = std::fs::read_to_string(&report).unwrap();
+    std::fs::remove_file(&report).unwrap();
+    assert_eq!(contents, "line,tx,max_tx\n6,3,5\n");
+
+    let output = run(&[&fixture("out_of_order.csv"), "--strict-ordering"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("line 6: tx 3 comes after tx 5"));
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn two_pass_applies_nothing_when_a_row_is_invalid() {
+    let output = run(&[&fixture("clean.csv"), "--two-pass"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, run(&[&fixture("clean.csv")]).stdout);
+
+    let findings = std::env::temp_dir().join(format!("findings-{}.csv", std::process::id()));
+    let output = run(&[
+        &fixture("dangling_dispute.csv"),
+        "--two-pass",
+        "--lenient",
+        "--findings-report",
+        findings.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(4));
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("validation failed: 1 invalid rows"));
+
+    let contents = std::fs::read_to_string(&findings).unwrap();
+    std::fs::remove_file(&findings).unwrap();
+    assert_eq!(contents, "line,finding\n4,dispute of unknown tx 7\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn two_pass_reads_a_named_pipe_once() {
+    let fifo = std::env::temp_dir().join(format!("payment-engine-two-pass-{}.fifo", std::process::id()));
+    let status = Command::new("mkfifo").arg(&fifo).status().expect("failed to run mkfifo");
+    assert!(status.success());
+
+    // the pipe can only be read once, the validation pass must keep a copy for the second pass
+    let writer = {
+        let fifo = fifo.clone();
+        std::thread::spawn(move || std::fs::write(fifo, std::fs::read(fixture("clean.csv")).unwrap()))
+    };
+    let output = run(&[fifo.to_str().unwrap(), "--two-pass"]);
+    writer.join().unwrap().unwrap();
+    std::fs::remove_file(&fifo).unwrap();
+
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, run(&[&fixture("clean.csv")]).stdout);
+}
+
+#[test]
+fn two_pass_reads_stdin_once() {
+    let run_on_stdin = |name: &str| {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_payment-engine"))
+            .args(["-", "--two-pass", "--lenient"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("failed to run the payment-engine binary");
+        child.stdin.take().unwrap().write_all(&std::fs::read(fixture(name)).unwrap()).unwrap();
+        child.wait_with_output().unwrap()
+    };
+
+    // stdin is copied to a temp file during the validation pass, and applied from the copy
+    let output = run_on_stdin("clean.csv");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, run(&[&fixture("clean.csv")]).stdout);
+
+    let output = run_on_stdin("dangling_dispute.csv");
+    assert_eq!(output.status.code(), Some(4));
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("invalid row: line 4: dispute of unknown tx 7"));
+}
+
+#[test]
+fn strict_engine_aborts_on_the_first_rejected_transaction() {
+    let output = run(&[&fixture("clean.csv"), "--strict-engine"]);
+    assert_eq!(output.status.code(), Some(0));
+
+    let quarantine = std::env::temp_dir().join(format!("strict-{}.csv", std::process::id()));
+    let stats = std::env::temp_dir().join(format!("strict-stats-{}.json", std::process::id()));
+    let rejections = std::env::temp_dir().join(format!("strict-rejections-{}.csv", std::process::id()));
+    let output = run(&[
+        &fixture("rejected.csv"),
+        "--strict-engine",
+        "--quarantine",
+        quarantine.to_str().unwrap(),
+        "--stats-json",
+        stats.to_str().unwrap(),
+        "--rejections-report",
+        rejections.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Ignored transaction: line 6: tx 5 of client 2: insufficient_funds"),
+        "{}",
+        stderr
+    );
+    // the rows applied before the stop are reported
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n2,2.0000,0.0000,2.0000,false\n"
+    );
+
+    // the row that stopped the run is quarantined
+    let contents = std::fs::read_to_string(&quarantine).unwrap();
+    std::fs::remove_file(&quarantine).unwrap();
+    assert_eq!(contents.lines().count(), 2, "{}", contents);
+    assert!(contents.contains("insufficient_funds"));
+
+    // and so are the statistics and the rejection
+    let json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&stats).unwrap()).unwrap();
+    std::fs::remove_file(&stats).unwrap();
+    assert_eq!((json["rows_applied"].as_u64(), json["rows_rejected"].as_u64()), (Some(4), Some(1)));
+    let contents = std::fs::read_to_string(&rejections).unwrap();
+    std::fs::remove_file(&rejections).unwrap();
+    assert_eq!(contents.lines().count(), 2, "{}", contents);
+}
+
+#[test]
+fn errors_json_writes_one_diagnostic_per_line() {
+    let diagnostics = |output: &Output| -> Vec<serde_json::Value> {
+        String::from_utf8_lossy(&output.stderr)
+            .lines()
+            .map(|line| {
+                let value: serde_json::Value = serde_json::from_str(line).expect("a JSON line");
+                // every key of the schema, and no other
+                let keys: Vec<&str> = value.as_object().unwrap().keys().map(String::as_str).collect();
+                let mut expected = ["client", "kind", "level", "line", "message", "reason", "schema", "source", "tx"];
+                expected.sort();
+                assert_eq!(keys, expected, "{}", line);
+                serde_json::from_value::<payment_engine::diagnostics::Diagnostic>(value.clone())
+                    .expect("a diagnostic");
+                value
+            })
+            .collect()
+    };
+
+    // the row that doesn't parse is skipped, the overdrawing withdrawal ignored
+    let output = run(&[&fixture("diagnostics.csv"), "--lenient", "--errors", "json"]);
+    assert_eq!(output.status.code(), Some(0));
+    let warnings = diagnostics(&output);
+    assert_eq!(warnings.len(), 2, "{:?}", warnings);
+    let fields = |value: &serde_json::Value| {
+        ["schema", "level", "kind", "reason", "line", "client", "tx"].map(|key| value[key].to_string())
+    };
+    assert_eq!(
+        fields(&warnings[0]),
+        ["1", "\"warning\"", "\"CsvParseError\"", "\"parse_error\"", "3", "null", "null"]
+    );
+    assert_eq!(
+        fields(&warnings[1]),
+        ["1", "\"warning\"", "\"IgnoredTransaction\"", "\"insufficient_funds\"", "4", "1", "3"]
+    );
+    assert!(warnings.iter().all(|warning| warning["source"].is_null()), "{:?}", warnings);
+
+    // in multi-file runs, every row's diagnostic says which file it's from
+    let input = fixture("diagnostics.csv");
+    let output = run(&[&input, &fixture("header_only.csv"), "--lenient", "--errors", "json"]);
+    assert_eq!(output.status.code(), Some(0));
+    let warnings = diagnostics(&output);
+    assert_eq!(warnings.len(), 2, "{:?}", warnings);
+    assert!(warnings.iter().all(|warning| warning["source"].as_str() == Some(&input)), "{:?}", warnings);
+    assert_eq!((warnings[1]["line"].as_u64(), warnings[1]["tx"].as_u64()), (Some(4), Some(3)));
+
+    // without --lenient the row stops the run
+    let output = run(&[&fixture("diagnostics.csv"), "--errors", "json"]);
+    assert_eq!(output.status.code(), Some(1));
+    let errors = diagnostics(&output);
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    assert_eq!((errors[0]["level"].as_str(), errors[0]["kind"].as_str()), (Some("error"), Some("CsvParseError")));
+    assert_eq!((errors[0]["line"].as_u64(), errors[0]["source"].as_str()), (Some(3), None));
+    let output = run(&[&input, &fixture("header_only.csv"), "--errors", "json"]);
+    let errors = diagnostics(&output);
+    assert_eq!((errors[0]["line"].as_u64(), errors[0]["source"].as_str()), (Some(3), Some(input.as_str())));
+
+    // so do invalid arguments
+    let errors = diagnostics(&run(&["--errors", "json"]));
+    assert_eq!(errors[0]["kind"].as_str(), Some("InvalidCliArgument"));
+}
+
+#[test]
+fn inverting_a_batch_backs_it_out() {
+    let output = run(&["invert", &fixture("rejected.csv")]);
+    assert_eq!(output.status.code(), Some(0));
+    let inverse = std::env::temp_dir().join(format!("inverse-{}.csv", std::process::id()));
+    std::fs::write(&inverse, &output.stdout).unwrap();
+
+    // the batch followed by its inverse leaves every client at zero
+    let output = run(&[&fixture("rejected.csv"), inverse.to_str().unwrap()]);
+    std::fs::remove_file(&inverse).unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "client,available,held,total,locked\n1,0.0000,0.0000,0.0000,false\n2,0.0000,0.0000,0.0000,false\n"
+    );
+
+    // disputed deposits stay, and so do their disputes
+    let output = run(&["invert", &fixture("open_disputes.csv")]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("\nwithdrawal,3,4,2.0,"));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(stderr.lines().count(), 6, "{}", stderr);
+    assert!(stderr.contains("not backed out: line 5: dispute of tx 2 by client 2"), "{}", stderr);
+}
+
+#[test]
+fn inspecting_a_dump_answers_each_query() {
+    let dump = std::env::temp_dir().join(format!("inspect-{}.json", std::process::id()));
+    let dump = dump.to_str().unwrap();
+    let output = run(&[&fixture("inspect.csv"), "--dump-clients-json", dump]);
+    assert_eq!(output.status.code(), Some(0));
+
+    let inspect = |query: &[&str]| {
+        let output = run(&[&["inspect", dump], query].concat());
+        assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+    // the dump the run wrote is read back whole, the queries themselves are tested in `inspect`
+    assert_eq!(
+        inspect(&[]),
+        "client,available,held,total,locked,open_disputes,disputes,chargebacks,open_dispute_held,status_reason
+1,10.0000,0.0000,10.0000,true,0,1,1,0.0000,chargeback of tx 2
+2,0.0000,20.0000,20.0000,false,1,1,0,20.0000,
+3,22.5000,0.0000,22.5000,false,0,0,0,0.0000,
+"
+    );
+    assert_eq!(
+        inspect(&["--client", "2", "--format", "json"]),
+        "[\n  {\"client\":2,\"available\":0.0000,\"held\":20.0000,\"total\":20.0000,\"locked\":false,\
+         \"open_disputes\":1,\"disputes\":1,\"chargebacks\":0,\"open_dispute_held\":20.0000,\"status_reason\":null}\n]\n"
+    );
+
+    // a single query at a time, from a clients dump that exists
+    let transactions = fixture("inspect.csv");
+    let failures = [
+        (vec!["inspect", dump, "--locked", "--summary"], "inspect answers one of --client"),
+        (vec!["inspect", dump, "--client", "x"], "invalid client id or range 'x'"),
+        (vec!["inspect", "missing.json"], "File error: missing.json"),
+        (vec!["inspect", &transactions], "JSON error"),
+    ];
+    for (args, error) in &failures {
+        let output = run(args);
+        assert_eq!(output.status.code(), Some(1), "{:?}", args);
+        assert!(output.stdout.is_empty(), "{:?}", args);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains(error), "{:?}: {}", args, stderr);
+    }
+    std::fs::remove_file(dump).unwrap();
+}
+
+#[test]
+fn disputes_are_validated_against_last_months_deposit_index() {
+    let index = std::env::temp_dir().join(format!("deposits-{}.idx", std::process::id()));
+    let index = index.to_str().unwrap();
+    let output = run(&[&fixture("deposits_last_month.csv"), "--emit-deposit-index", index]);
+    assert_eq!(output.status.code(), Some(0));
+
+    let output = run(&["validate", &fixture("disputes_only.csv"), "--deposit-index", index]);
+    std::fs::remove_file(index).unwrap();
+    assert_eq!(output.status.code(), Some(4));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "line,finding\n\
+         4,\"dispute of tx 2 by client 1, deposited by client 2\"\n\
+         6,dispute of unknown tx 3\n\
+         7,resolve of unknown tx 99\n\
+         8,deposit tx 6 doesn't refer to a deposit\n"
+    );
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "7 rows checked against 4 deposits: 4 findings\n");
+
+    // anything but a deposit index is a hard error
+    let output = run(&["validate", &fixture("disputes_only.csv"), "--deposit-index", &fixture("disputes_only.csv")]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn simulations_with_the_same_seed_end_in_the_same_state() {
+    let simulate = |seed| run(&["simulate", "--seed", seed, "--rows", "10000", "--clients", "100"]);
+    let output = simulate("42");
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    assert!(stdout.starts_with("seed: 42\nrows: 10000 ("), "{}", stdout);
+    assert!(stdout.contains("\nchecksum: sha256:"), "{}", stdout);
+
+    assert_eq!(String::from_utf8_lossy(&simulate("42").stdout), stdout);
+    let other_seed = String::from_utf8_lossy(&simulate("43").stdout).into_owned();
+    assert_ne!(other_seed.lines().last(), stdout.lines().last());
+}
+
+#[cfg(feature = "proto")]
+#[test]
+fn protobuf_inputs_give_the_report_of_their_csv() {
+    use payment_engine::{proto::ProtoTransaction, types::Transaction};
+
+    let transactions = [
+        Transaction::deposit(1, 1, 1.0),
+        Transaction::deposit(2, 2, 2.0),
+        Transaction::deposit(1, 3, 2.0),
+        Transaction::withdrawal(1, 4, 1.5),
+    ];
+    let mut bytes: Vec<u8> = transactions
+        .iter()
+        .flat_map(|txn| ProtoTransaction::from(txn).encode_length_delimited())
+        .collect();
+    let input = std::env::temp_dir().join(format!("clean-{}.bin", std::process::id()));
+    std::fs::write(&input, &bytes).unwrap();
+    let output = run(&[input.to_str().unwrap(), "--input-format", "proto", "--fail-on-reject"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, run(&[&fixture("clean.csv")]).stdout);
+
+    // an unknown transaction type is a malformed row
+    bytes.extend([2, 1 << 3, 42]);
+    std::fs::write(&input, &bytes).unwrap();
+    let output = run(&[input.to_str().unwrap(), "--input-format", "proto"]);
+    std::fs::remove_file(&input).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Decode error: message 5: unknown transaction type 42"), "{}", stderr);
+}
+
+#[cfg(feature = "msgpack")]
+#[test]
+fn msgpack_batches_give_the_report_of_their_csv() {
+    use payment_engine::{msgpack::write_transactions_msgpack, types::Transaction};
+
+    let transactions = [
+        Transaction::deposit(1, 1, 1.0),
+        Transaction::deposit(2, 2, 2.0),
+        Transaction::deposit(1, 3, 2.0),
+        Transaction::withdrawal(1, 4, 1.5),
+    ];
+    let mut bytes = Vec::new();
+    write_transactions_msgpack(&mut bytes, &transactions).unwrap();
+    let input = std::env::temp_dir().join(format!("clean-{}.msgpack", std::process::id()));
+    std::fs::write(&input, &bytes).unwrap();
+    let output = run(&[input.to_str().unwrap(), "--input-format", "msgpack", "--fail-on-reject"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, run(&[&fixture("clean.csv")]).stdout);
+
+    let output = run(&[input.to_str().unwrap(), "--input-format", "msgpack", "--format", "msgpack"]);
+    std::fs::remove_file(&input).unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    // an array of two maps, the first of them client 1's
+    assert_eq!(&output.stdout[..10], b"\x92\x85\xa6client\x01");
+}
+
+#[test]
+fn parallel_files_give_the_report_of_the_sequential_run() {
+    let regions = [fixture("region_eu.csv"), fixture("region_us.csv"), fixture("region_apac.csv")];
+    let sequential = run(&[&regions[0], &regions[1], &regions[2]]);
+    assert_eq!(sequential.status.code(), Some(0));
+    let output = run(&["process", "--parallel-files", &regions[0], &regions[1], &regions[2]]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(output.stdout, sequential.stdout);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("\n20,7.0000,0.0000,7.0000,true\n"));
+
+    // streamed, each file's rows come as it is processed, the same rows in another order
+    let streamed = run(&[
+        "process",
+        "--parallel-files",
+        &regions[0],
+        &regions[1],
+        &regions[2],
+        "--stream-report",
+        "--sort-within-shard",
+        "--tag-shard",
+    ]);
+    assert_eq!(streamed.status.code(), Some(0));
+    let stdout = String::from_utf8_lossy(&streamed.stdout);
+    let mut lines = stdout.lines();
+    assert_eq!(lines.next(), Some("client,available,held,total,locked,shard"));
+    let mut last_in_file = [None; 3];
+    let mut rows = Vec::new();
+    for line in lines {
+        let (row, shard) = line.rsplit_once(',').unwrap();
+        let (shard, client): (usize, u32) = (shard.parse().unwrap(), row.split(',').next().unwrap().parse().unwrap());
+        assert!(last_in_file[shard] < Some(client), "file {} isn't sorted: {}", shard, stdout);
+        last_in_file[shard] = Some(client);
+        rows.push(row.to_owned());
+    }
+    rows.sort();
+    let sequential = String::from_utf8_lossy(&sequential.stdout);
+    let mut expected: Vec<String> = sequential.lines().skip(1).map(str::to_owned).collect();
+    expected.sort();
+    assert_eq!(rows, expected);
+    assert!(last_in_file.iter().all(Option::is_some));
+
+    // client 11 also has an account in the US file
+    let input = std::env::temp_dir().join(format!("region-overlap-{}.csv", std::process::id()));
+    std::fs::write(&input, "type,client,tx,amount\ndeposit,30,301,1.0\ndeposit,11,302,1.0\n").unwrap();
+    let output = run(&["process", "--parallel-files", &regions[0], &regions[1], input.to_str().unwrap()]);
+    std::fs::remove_file(&input).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("can't be merged with the files before it: clients on both sides: 11"), "{}", stderr);
+}
+
+#[test]
+fn amounts_in_minor_units_give_the_report_of_their_decimal_form() {
+    let major = run(&[&fixture("major_units.csv")]);
+    assert_eq!(major.status.code(), Some(0));
+    assert!(!String::from_utf8_lossy(&major.stderr).contains("minor units"));
+    let minor = run(&[&fixture("minor_units.csv"), "--amount-unit", "minor:4"]);
+    assert_eq!(minor.status.code(), Some(0));
+    assert_eq!(minor.stdout, major.stdout);
+    assert!(String::from_utf8_lossy(&minor.stdout).contains("\n4,251.7500,0.0000,251.7500,false\n"));
+
+    // read as decimal amounts, the minor units would inflate every balance by 10^4
+    let output = run(&[&fixture("minor_units.csv")]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("\n4,2517500.0000,0.0000,2517500.0000,false\n"));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("warning: 10 of the 10 amounts up to line 11 are whole numbers"), "{}", stderr);
+    assert!(stderr.contains("may be in minor units (see --amount-unit)"), "{}", stderr);
+}
+
+#[test]
+fn statements_are_written_per_client_and_day() {
+    let output = run(&["statements", &fixture("timestamps_sorted.csv"), "--client", "1", "--utc-offset", "-23:00"]);
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("Statement of client 1 for 2023-11-13 (UTC-23:00)\n"), "{}", stdout);
+    assert!(stdout.contains("  23:13:50  dispute        tx 3          available      -2.0000  held      +2.0000\n"));
+    assert!(stdout.ends_with("Closing balance: available 2.0000, held 2.0000, total 4.0000\n\n"), "{}", stdout);
+    assert!(!stdout.contains("client 2"));
+
+    let output = run(&["statements", &fixture("timestamps_sorted.csv")]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn a_file_submitted_twice_with_a_journal_is_applied_once() {
+    let temp = |name: &str| std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+    let (journal, state) = (temp("applied.journal"), temp("journaled-state.json"));
+    let submit = || {
+        let mut args = vec![fixture("clean.csv"), "--journal".to_owned(), journal.to_str().unwrap().to_owned()];
+        if state.exists() {
+            args.extend(["--initial-state".to_owned(), state.to_str().unwrap().to_owned()]);
+        }
+        args.extend(["--dump-clients-json".to_owned(), state.to_str().unwrap().to_owned()]);
+        run(&args.iter().map(String::as_str).collect::<Vec<_>>())
+    };
+    let first = submit();
+    assert_eq!(first.status.code(), Some(0));
+    assert_eq!(first.stdout, run(&[&fixture("clean.csv")]).stdout);
+
+    let second = submit();
+    std::fs::remove_file(&journal).unwrap();
+    std::fs::remove_file(&state).unwrap();
+    assert_eq!(second.status.code(), Some(0));
+    assert_eq!(second.stdout, first.stdout);
+    let stderr = String::from_utf8_lossy(&second.stderr);
+    assert!(stderr.contains("already_processed"), "{}", stderr);
+}
+
+#[test]
+fn clients_dump_can_seed_a_later_run() {
+    let dump = std::env::temp_dir().join(format!("clients-{}.json", std::process::id()));
+    let output = run(&[&fixture("clean.csv"), "--dump-clients-json", dump.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    let json = std::fs::read_to_string(&dump).unwrap();
+    assert!(json.starts_with("{\"1\":{\"available\":\"1.5000\""), "{}", json);
+
+    // the next day's file starts from the dumped balances
+    let input = std::env::temp_dir().join(format!("next-day-{}.csv", std::process::id()));
+    std::fs::write(&input, "type,client,tx,amount\ndeposit,2,10,1.0\nwithdrawal,1,11,1.5\n").unwrap();
+    let output = run(&[input.to_str().unwrap(), "--initial-state", dump.to_str().unwrap()]);
+    std::fs::remove_file(&input).unwrap();
+    std::fs::remove_file(&dump).unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "client,available,held,total,locked\n1,0.0000,0.0000,0.0000,false\n2,3.0000,0.0000,3.0000,false\n"
+    );
+}
+
+#[test]
+fn dry_run_leaves_the_state_untouched() {
+    let dump = std::env::temp_dir().join(format!("dry-run-{}.json", std::process::id()));
+    let output = run(&[&fixture("clean.csv"), "--dump-clients-json", dump.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    let seeded = std::fs::read(&dump).unwrap();
+
+    let input = std::env::temp_dir().join(format!("dry-run-corrections-{}.csv", std::process::id()));
+    std::fs::write(&input, "type,client,tx,amount\nwithdrawal,1,11,1.5\ndeposit,3,12,4.0\n").unwrap();
+    let state = dump.to_str().unwrap();
+    let output = run(&[input.to_str().unwrap(), "--initial-state", state, "--dump-clients-json", state, "--dry-run"]);
+    std::fs::remove_file(&input).unwrap();
+    let after = std::fs::read(&dump).unwrap();
+    std::fs::remove_file(&dump).unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(after, seeded);
+
+    // the report is the one the run would write, the changes go to stderr
+    assert!(String::from_utf8_lossy(&output.stdout).contains("1,0.0000,0.0000,0.0000,false\n"));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("left as it was"), "{}", stderr);
+    assert!(stderr.contains(
+        "dry run: accounts of 2 clients would change\n\
+         ~ client 1: available 1.5000 -> 0.0000 (-1.5000), total 1.5000 -> 0.0000 (-1.5000)\n\
+         + client 3: 4.0000,0.0000,4.0000,false\n"
+    ), "{}", stderr);
+}
+
+#[test]
+fn size_limits_stop_the_run_after_reporting_the_rows_applied() {
+    let path = std::env::temp_dir().join(format!("payment-engine-limits-{}.csv", std::process::id()));
+    let mut csv = String::from("type,client,tx,amount\n");
+    for tx in 1..=50 {
+        csv += &format!("deposit,{},{},1.0\n", tx % 10 + 1, tx);
+    }
+    std::fs::write(&path, csv).unwrap();
+
+    // tx 4 would create a fourth client, so only tx 1 to 3 are applied
+    let output = run(&[path.to_str().unwrap(), "--max-clients", "3"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Limit exceeded: more than 3 clients"));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "client,available,held,total,locked\n2,1.0000,0.0000,1.0000,false\n3,1.0000,0.0000,1.0000,false\n4,1.0000,0.0000,1.0000,false\n"
+    );
+
+    let output = run(&[path.to_str().unwrap(), "--max-transactions", "12"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Limit exceeded: more than 12 stored transactions"));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // tx 1 to 12 went to clients 2 to 10, 1, 2 and 3
+    assert!(stdout.contains("\n1,1.0000,0.0000,1.0000,false\n2,2.0000,0.0000,2.0000,false\n3,2.0000,"), "{}", stdout);
+    assert!(stdout.contains("\n4,1.0000,"), "{}", stdout);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn inputs_over_their_limits_fail_even_when_lenient() {
+    let output = run(&[&fixture("clean.csv"), "--max-records", "3", "--lenient"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Input limit exceeded: more than 3 records at line 5"), "{}", stderr);
+
+    let output = run(&[&fixture("clean.csv"), "--max-field-bytes", "8"]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("more than 8 bytes in a field at line 5"), "{}", stderr);
+}
+
+#[test]
+fn resolves_of_an_inconsistent_state_are_rejected_unless_clamped() {
+    let temp = |name: &str| std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+    let (input, state) = (temp("inconsistent.csv"), temp("inconsistent-state.json"));
+    std::fs::write(&input, "type,client,tx,amount\ndeposit,1,1,10.0\ndispute,1,1,\nresolve,1,1,\n").unwrap();
+    // held by 6.0 less than the account's disputes, as left by a manual edit
+    std::fs::write(&state, "{\"1\":{\"held\":\"-6.0000\",\"total\":\"-6.0000\"}}").unwrap();
+    let (input, state) = (input.to_str().unwrap(), state.to_str().unwrap());
+    let rejected = run(&[input, "--initial-state", state, "--errors", "json"]);
+    let clamped = run(&[input, "--initial-state", state, "--clamp-inconsistent-held"]);
+    std::fs::remove_file(input).unwrap();
+    std::fs::remove_file(state).unwrap();
+
+    assert_eq!(String::from_utf8_lossy(&rejected.stdout), "client,available,held,total,locked\n1,0.0000,4.0000,4.0000,false\n");
+    let stderr = String::from_utf8_lossy(&rejected.stderr);
+    assert!(stderr.contains("\"reason\":\"held_balance_inconsistent\",\"source\":null,\"line\":4"), "{}", stderr);
+    assert!(stderr.contains("\"tx\":1,\"message\":\"invariant violated: client 1: held 4.0000 < 10.0000"), "{}", stderr);
+
+    assert_eq!(clamped.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&clamped.stdout), "client,available,held,total,locked\n1,4.0000,0.0000,4.0000,false\n");
+    assert!(!String::from_utf8_lossy(&clamped.stderr).contains("held_balance_inconsistent"));
+}
+
+#[test]
+fn accruals_pay_interest_on_available_balances() {
+    let output = run(&[&fixture("three_clients.csv"), "--accrue", "0.01"]);
+    assert_eq!(output.status.code(), Some(0));
+    // client 2's funds are all held by the dispute
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "client,available,held,total,locked\n\
+         1,5.0500,0.0000,5.0500,false\n\
+         2,0.0000,20.0000,20.0000,false\n\
+         3,30.3000,0.0000,30.3000,false\n"
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("interest paid: 0.3500\n"), "{}", stderr);
+}
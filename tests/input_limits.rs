@@ -0,0 +1,157 @@
+//! Feeds the parser inputs far larger than its limits and checks that they fail before the
+//! memory grows with them, counting the bytes allocated by the test's thread.
+
+use payment_engine::{
+    errors::{InputLimit, PaymentError},
+    parser::{parse_records_with, ParseOptions},
+};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    io::Read,
+};
+
+/// Largest growth of the memory allowed while reading one input.
+const MAX_PEAK_BYTES: usize = 16 * 1024 * 1024;
+
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+    static PEAK: Cell<usize> = const { Cell::new(0) };
+}
+
+fn count(grow: usize, shrink: usize) {
+    // the thread's counters may already be gone while it exits
+    let _ = ALLOCATED.try_with(|allocated| {
+        let now = (allocated.get() + grow).saturating_sub(shrink);
+        allocated.set(now);
+        let _ = PEAK.try_with(|peak| peak.set(peak.get().max(now)));
+    });
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count(layout.size(), 0);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        count(0, layout.size());
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count(new_size, layout.size());
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// An input of `start` followed by `pattern` repeated until `len` bytes, made as it is read.
+struct Generated {
+    start: &'static [u8],
+    pattern: &'static [u8],
+    at: u64,
+    len: u64,
+}
+
+impl Read for Generated {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut read = 0;
+        while read < buf.len() && self.at < self.len {
+            let at = self.at as usize;
+            buf[read] = match self.start.get(at) {
+                Some(&byte) => byte,
+                None => self.pattern[(at - self.start.len()) % self.pattern.len()],
+            };
+            read += 1;
+            self.at += 1;
+        }
+        Ok(read)
+    }
+}
+
+/// Reads `input` within `options` and returns its last row's error with the growth of the
+/// memory meanwhile.
+async fn last_error(input: Generated, options: ParseOptions) -> (Option<PaymentError>, usize) {
+    let before = ALLOCATED.with(Cell::get);
+    PEAK.with(|peak| peak.set(before));
+    let last = match parse_records_with(Box::new(input), &options).await {
+        Ok((_, records)) => records.last().and_then(|record| record.transaction.err()),
+        Err(err) => Some(err),
+    };
+    (last, PEAK.with(Cell::get) - before)
+}
+
+#[tokio::test]
+async fn a_field_of_a_gigabyte_fails_at_the_field_limit() {
+    let input = Generated {
+        start: b"type,client,tx,amount,memo\ndeposit,1,1,1.0,",
+        pattern: b"a",
+        at: 0,
+        len: 1 << 30,
+    };
+    let (last, peak) = last_error(input, ParseOptions::default()).await;
+    assert!(
+        matches!(
+            last,
+            Some(PaymentError::InputLimitExceeded { which: InputLimit::FieldBytes, at_line: 2, .. })
+        ),
+        "{:?}",
+        last
+    );
+    assert!(peak < MAX_PEAK_BYTES, "{} bytes allocated", peak);
+}
+
+#[tokio::test]
+async fn a_record_of_endless_fields_fails_at_the_record_limit() {
+    let input = Generated {
+        start: b"type,client,tx,amount\ndeposit,1,1,1.0",
+        pattern: b",a",
+        at: 0,
+        len: 1 << 30,
+    };
+    // the CSV reader keeps the bounds of every field besides their bytes, so a record of one-byte
+    // fields takes several times its length
+    let mut options = ParseOptions::default();
+    options.limits.max_record_bytes = 1024 * 1024;
+    let (last, peak) = last_error(input, options).await;
+    assert!(
+        matches!(
+            last,
+            Some(PaymentError::InputLimitExceeded { which: InputLimit::RecordBytes, at_line: 2, .. })
+        ),
+        "{:?}",
+        last
+    );
+    assert!(peak < MAX_PEAK_BYTES, "{} bytes allocated", peak);
+}
+
+#[tokio::test]
+async fn endless_empty_lines_fail_at_the_record_limit() {
+    let input = Generated {
+        start: b"type,client,tx,amount\n",
+        pattern: b"\n",
+        at: 0,
+        len: u64::MAX,
+    };
+    let mut options = ParseOptions::default();
+    options.limits.max_records = 1_000_000;
+    let (last, peak) = last_error(input, options).await;
+    assert!(
+        matches!(
+            last,
+            Some(PaymentError::InputLimitExceeded {
+                which: InputLimit::Records,
+                limit: 1_000_000,
+                at_line: 1_000_002,
+            })
+        ),
+        "{:?}",
+        last
+    );
+    assert!(peak < MAX_PEAK_BYTES, "{} bytes allocated", peak);
+}